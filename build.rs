@@ -0,0 +1,28 @@
+//! Records the commit the crate is built from for its build info
+//!
+//! Packagers building from a source archive without git can set `UDPFORWARDER_GIT_HASH`.
+
+use std::{path::Path, process::Command};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=UDPFORWARDER_GIT_HASH");
+    if std::env::var_os("UDPFORWARDER_GIT_HASH").is_some() {
+        return;
+    }
+
+    // Commits move the branch HEAD points to, which changes the index or the refs
+    for path in [".git/HEAD", ".git/index", ".git/refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=UDPFORWARDER_GIT_HASH={}", hash.trim());
+    }
+}
@@ -0,0 +1,61 @@
+/*
+ * C API of udpforwarder
+ *
+ * Build the library with the `capi` feature, e.g.
+ * cargo rustc --release --features capi --crate-type cdylib
+ */
+
+#ifndef UDPFORWARDER_H
+#define UDPFORWARDER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Forwarder running on its own thread */
+typedef struct udpforwarder udpforwarder;
+
+/* Counters of a running forwarder */
+typedef struct {
+    uint64_t received_packets;
+    uint64_t received_bytes;
+    /* Packets and bytes sent to the targets, summed over all targets */
+    uint64_t sent_packets;
+    uint64_t sent_bytes;
+    uint64_t send_errors;
+    uint64_t looped_packets;
+    uint64_t expired_packets;
+    uint64_t filtered_packets;
+    uint64_t relayed_packets;
+} udpforwarder_stats_t;
+
+/*
+ * Start forwarding from `listener`, e.g. "224.10.10.10:4000/192.168.1.10",
+ * to the `num_targets` addresses in `targets`, e.g. "10.1.1.10:4000".
+ *
+ * Returns NULL on failure, udpforwarder_last_error() tells why.
+ */
+udpforwarder *udpforwarder_start(const char *listener, const char *const *targets,
+                                 size_t num_targets);
+
+/*
+ * Stop forwarding and free the forwarder. Does nothing for NULL.
+ *
+ * Returns 0 if the forwarder ran without failing and -1 otherwise.
+ */
+int udpforwarder_stop(udpforwarder *forwarder);
+
+/* Write the counters of the forwarder to `stats`, returns 0 on success */
+int udpforwarder_stats(const udpforwarder *forwarder, udpforwarder_stats_t *stats);
+
+/* Error of the last failed call on the calling thread, or NULL */
+const char *udpforwarder_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* UDPFORWARDER_H */
@@ -0,0 +1,367 @@
+//! Admin socket
+//!
+//! Operators adjust the targets of a running forwarder with line commands through a
+//! Unix socket or a UDP port, without HTTP tooling, e.g.
+//! `echo "add 10.0.0.5:9000" | nc -U /run/udpforwarder.sock`:
+//!
+//! | Command               | Effect                                  |
+//! |-----------------------|-----------------------------------------|
+//! | `add addr[,addr...]`  | add the targets                         |
+//! | `remove addr`         | remove a target                         |
+//! | `list`                | list the targets with their counters    |
+//! | `help`                | list the commands                       |
+//!
+//! Replies start with a line of `ok` or `error: ` and the reason, followed by the
+//! listed targets. Over UDP, each datagram holds a single command, so that a spoofed
+//! datagram cannot turn a short request into a long reply. The changes are applied to
+//! the [SharedState] like those of the control API, so the forwarding loop picks up the
+//! complete new set of targets with its next packet. There is no authentication,
+//! restrict the socket by its file permissions, UDP ports are only bound to loopback
+//! addresses.
+
+use std::{
+    fmt::{self, Write as _},
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, atomic::Ordering},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::state::SharedState;
+
+/// Largest datagram of commands taken over UDP
+const MAX_DATAGRAM: usize = 4096;
+/// Time a client of the Unix socket may stay connected
+#[cfg(unix)]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Help listing the commands
+const HELP: &str = "add addr[,addr...]  add targets\n\
+                    remove addr         remove a target\n\
+                    list                list the targets with their counters\n";
+
+/// Address of the admin socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AdminAddr {
+    /// UDP port on a loopback address, e.g. `127.0.0.1:9999`
+    Udp(SocketAddr),
+    /// Path of a Unix stream socket
+    Unix(PathBuf),
+}
+
+impl FromStr for AdminAddr {
+    type Err = ();
+
+    /// Parse a loopback socket address, or take anything else as path
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<SocketAddr>() {
+            Ok(addr) if addr.ip().is_loopback() => Ok(Self::Udp(addr)),
+            Ok(_) => Err(()),
+            Err(_) if !s.is_empty() => Ok(Self::Unix(s.into())),
+            Err(_) => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for AdminAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Udp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Serve the admin socket at `addr` for the forwarder of `state` on a thread of its own
+///
+/// A stale Unix socket left by an earlier run is replaced, UDP ports are refused on
+/// other than loopback addresses.
+pub fn serve(addr: &AdminAddr, state: Arc<SharedState>) -> Result<JoinHandle<()>, io::Error> {
+    match addr {
+        AdminAddr::Udp(addr) if !addr.ip().is_loopback() => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the admin socket takes commands without authentication, give a loopback address",
+        )),
+        AdminAddr::Udp(addr) => serve_udp(UdpSocket::bind(addr)?, state),
+        #[cfg(unix)]
+        AdminAddr::Unix(path) => {
+            use std::os::unix::fs::FileTypeExt;
+
+            if std::fs::symlink_metadata(path)
+                .is_ok_and(|metadata| metadata.file_type().is_socket())
+            {
+                std::fs::remove_file(path)?;
+            }
+            serve_unix(std::os::unix::net::UnixListener::bind(path)?, state)
+        }
+        #[cfg(not(unix))]
+        AdminAddr::Unix(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix sockets are only available on Unix, give an address instead",
+        )),
+    }
+}
+
+/// Answer the command of each datagram arriving at `socket`
+fn serve_udp(socket: UdpSocket, state: Arc<SharedState>) -> Result<JoinHandle<()>, io::Error> {
+    thread::Builder::new()
+        .name("admin".to_owned())
+        .spawn(move || {
+            let mut buffer = vec![0; MAX_DATAGRAM];
+            loop {
+                let (num_bytes, client) = match socket.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    // Reported for earlier replies to clients gone on some systems
+                    Err(_) => continue,
+                };
+                let reply = match std::str::from_utf8(&buffer[..num_bytes]) {
+                    Ok(command) if command.trim().lines().count() > 1 => {
+                        "error: send a single command per datagram\n".to_owned()
+                    }
+                    Ok(command) if command.trim().is_empty() => continue,
+                    Ok(command) => handle(&state, command),
+                    Err(_) => "error: command is not UTF-8\n".to_owned(),
+                };
+                let _ = socket.send_to(reply.as_bytes(), client);
+            }
+        })
+}
+
+/// Answer the commands of each client of `listener` line by line, until the client
+/// closes the connection or [CLIENT_TIMEOUT] has passed
+#[cfg(unix)]
+fn serve_unix(
+    listener: std::os::unix::net::UnixListener,
+    state: Arc<SharedState>,
+) -> Result<JoinHandle<()>, io::Error> {
+    thread::Builder::new()
+        .name("admin".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                // Errors of single connections only affect that client
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let deadline = Instant::now() + CLIENT_TIMEOUT;
+                let _ = stream
+                    .set_write_timeout(Some(CLIENT_TIMEOUT))
+                    .and_then(|()| stream.try_clone())
+                    .and_then(|reader| {
+                        let reader = DeadlineReader {
+                            stream: reader,
+                            deadline,
+                        };
+                        for command in BufReader::new(reader).lines() {
+                            let command = command?;
+                            if !command.trim().is_empty() {
+                                stream.write_all(handle(&state, &command).as_bytes())?;
+                            }
+                        }
+                        Ok(())
+                    });
+            }
+        })
+}
+
+/// Reader of a client connection failing once its deadline has passed, so that a client
+/// trickling bytes cannot hold the admin socket
+#[cfg(unix)]
+struct DeadlineReader {
+    stream: std::os::unix::net::UnixStream,
+    deadline: Instant,
+}
+
+#[cfg(unix)]
+impl io::Read for DeadlineReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        io::Read::read(&mut self.stream, buf)
+    }
+}
+
+/// Apply a single command, returning the reply
+fn handle(state: &SharedState, command: &str) -> String {
+    let (name, argument) = command
+        .trim()
+        .split_once(char::is_whitespace)
+        .map_or((command.trim(), ""), |(name, argument)| {
+            (name, argument.trim())
+        });
+
+    match (name, argument) {
+        ("add", addrs) => {
+            let addrs: Result<Vec<SocketAddr>, _> = addrs
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|addr| !addr.is_empty())
+                .map(str::parse)
+                .collect();
+            match addrs {
+                Ok(addrs) if !addrs.is_empty() => {
+                    for addr in addrs {
+                        state.add_target(addr);
+                    }
+                    "ok\n".to_owned()
+                }
+                Ok(_) => "error: no target address given\n".to_owned(),
+                Err(e) => format!("error: invalid target address: {e}\n"),
+            }
+        }
+        ("remove", addr) => match addr.parse() {
+            Ok(addr) if state.remove_target(addr) => "ok\n".to_owned(),
+            Ok(addr) => format!("error: no target {addr}\n"),
+            Err(e) => format!("error: invalid target address: {e}\n"),
+        },
+        ("list", "") => {
+            let mut reply = "ok\n".to_owned();
+            for target in state.targets() {
+                let _ = writeln!(
+                    reply,
+                    "{} sent {} packets ({} bytes), {} send errors",
+                    target.addr,
+                    target.sent.packets(),
+                    target.sent.bytes(),
+                    target.send_errors.load(Ordering::Relaxed)
+                );
+            }
+            reply
+        }
+        ("help", "") => format!("ok\n{HELP}"),
+        _ => format!("error: unknown command `{}`, try help\n", command.trim()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn commands_applied() {
+        let state = SharedState::new(None, &["10.1.1.1:4000".parse().unwrap()]);
+
+        assert_eq!("ok\n", handle(&state, "add 10.1.1.2:4000, 10.1.1.3:4000"));
+        assert_eq!("ok\n", handle(&state, " remove  10.1.1.1:4000 "));
+        assert_eq!(
+            "ok\n10.1.1.2:4000 sent 0 packets (0 bytes), 0 send errors\n\
+             10.1.1.3:4000 sent 0 packets (0 bytes), 0 send errors\n",
+            handle(&state, "list")
+        );
+        assert_eq!(
+            "error: no target 10.1.1.1:4000\n",
+            handle(&state, "remove 10.1.1.1:4000")
+        );
+        assert_eq!(
+            "error: invalid target address: invalid socket address syntax\n",
+            handle(&state, "add 10.1.1.4")
+        );
+        assert_eq!(
+            "error: unknown command `flush`, try help\n",
+            handle(&state, "flush")
+        );
+    }
+
+    #[test]
+    fn served_over_udp() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let state = Arc::new(SharedState::new(None, &[]));
+        serve_udp(socket, Arc::clone(&state)).unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buffer = [0; 256];
+        let mut request = |command: &[u8]| {
+            client.send_to(command, addr).unwrap();
+            let num_bytes = client.recv(&mut buffer).unwrap();
+            String::from_utf8(buffer[..num_bytes].to_vec()).unwrap()
+        };
+        assert_eq!("ok\n", request(b"add 10.1.1.2:4000\n"));
+        assert_eq!(
+            "ok\n10.1.1.2:4000 sent 0 packets (0 bytes), 0 send errors\n",
+            request(b"list")
+        );
+        assert_eq!(
+            "error: send a single command per datagram\n",
+            request(b"remove 10.1.1.2:4000\nlist\n")
+        );
+        assert_eq!(1, state.targets().len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn served_over_unix_socket() {
+        use std::{io::Read, os::unix::net::UnixStream};
+
+        let path = std::env::temp_dir().join(format!("udpforwarder-admin-{}", std::process::id()));
+        let state = Arc::new(SharedState::new(None, &["10.1.1.1:4000".parse().unwrap()]));
+        serve(&AdminAddr::Unix(path.clone()), Arc::clone(&state)).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"remove 10.1.1.1:4000\n").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!("ok\n", reply);
+        assert!(state.targets().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn trickling_client_disconnected() {
+        use std::{io::Read, os::unix::net::UnixStream, time::Instant};
+
+        let path =
+            std::env::temp_dir().join(format!("udpforwarder-admin-trickle-{}", std::process::id()));
+        let state = Arc::new(SharedState::new(None, &[]));
+        serve(&AdminAddr::Unix(path.clone()), state).unwrap();
+
+        let start = Instant::now();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let mut reply = Vec::new();
+        while start.elapsed() < CLIENT_TIMEOUT * 2 {
+            // Never completes a line, each byte well within the read timeout
+            if stream.write_all(b"l").is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(200));
+            stream
+                .set_read_timeout(Some(Duration::from_millis(1)))
+                .unwrap();
+            if stream.read_to_end(&mut reply).is_ok() {
+                break;
+            }
+        }
+        assert!(start.elapsed() < CLIENT_TIMEOUT * 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn only_loopback_udp_addresses() {
+        assert_eq!(
+            Ok(AdminAddr::Udp(SocketAddr::from(([127, 0, 0, 1], 9999)))),
+            "127.0.0.1:9999".parse()
+        );
+        assert_eq!(
+            Ok(AdminAddr::Udp(SocketAddr::from((
+                std::net::Ipv6Addr::LOCALHOST,
+                9999
+            )))),
+            "[::1]:9999".parse()
+        );
+        assert_eq!(Err(()), "0.0.0.0:9999".parse::<AdminAddr>());
+        assert_eq!(Err(()), "10.1.1.1:9999".parse::<AdminAddr>());
+        assert_eq!(
+            Ok(AdminAddr::Unix("/run/udpforwarder.sock".into())),
+            "/run/udpforwarder.sock".parse()
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! AES for SRT encryption
+//!
+//! SRT encrypts payloads with AES in counter mode and wraps the stream keys with
+//! the AES key wrap (RFC 3394). Both come from the constant-time implementations of
+//! the RustCrypto crates, this only picks the one for the negotiated key length.
+
+use aes::{
+    Aes128, Aes192, Aes256,
+    cipher::{InnerIvInit, KeyInit, StreamCipher},
+};
+use aes_kw::Kek;
+use ctr::{Ctr128BE, CtrCore};
+
+/// AES in counter mode with the round keys expanded from a 128-, 192- or 256-bit key
+pub(crate) enum Ctr {
+    Aes128(Aes128),
+    Aes192(Aes192),
+    Aes256(Aes256),
+}
+
+impl Ctr {
+    /// Expand `key`, `None` unless it is 16, 24 or 32 bytes long
+    pub(crate) fn new(key: &[u8]) -> Option<Self> {
+        match key.len() {
+            16 => Some(Self::Aes128(Aes128::new(key.into()))),
+            24 => Some(Self::Aes192(Aes192::new(key.into()))),
+            32 => Some(Self::Aes256(Aes256::new(key.into()))),
+            _ => None,
+        }
+    }
+
+    /// Encrypt or decrypt `data`, starting with the counter block `iv`
+    pub(crate) fn apply(&self, iv: [u8; 16], data: &mut [u8]) {
+        let iv = iv.into();
+        match self {
+            Self::Aes128(aes) => {
+                Ctr128BE::<Aes128>::from_core(CtrCore::inner_iv_init(aes.clone(), &iv))
+                    .apply_keystream(data)
+            }
+            Self::Aes192(aes) => {
+                Ctr128BE::<Aes192>::from_core(CtrCore::inner_iv_init(aes.clone(), &iv))
+                    .apply_keystream(data)
+            }
+            Self::Aes256(aes) => {
+                Ctr128BE::<Aes256>::from_core(CtrCore::inner_iv_init(aes.clone(), &iv))
+                    .apply_keystream(data)
+            }
+        }
+    }
+}
+
+/// Wrap `key` with the key-encrypting key `kek`, `None` unless `kek` is 16, 24 or 32
+/// bytes long and `key` a multiple of 8 bytes of at least 16 bytes
+pub(crate) fn wrap(kek: &[u8], key: &[u8]) -> Option<Vec<u8>> {
+    match kek.len() {
+        16 => Kek::<Aes128>::try_from(kek).and_then(|kek| kek.wrap_vec(key)),
+        24 => Kek::<Aes192>::try_from(kek).and_then(|kek| kek.wrap_vec(key)),
+        _ => Kek::<Aes256>::try_from(kek).and_then(|kek| kek.wrap_vec(key)),
+    }
+    .ok()
+}
+
+/// Unwrap a key wrapped with the key-encrypting key `kek`, `None` if the integrity
+/// check fails, e.g. because the key-encrypting key is wrong
+pub(crate) fn unwrap(kek: &[u8], wrapped: &[u8]) -> Option<Vec<u8>> {
+    match kek.len() {
+        16 => Kek::<Aes128>::try_from(kek).and_then(|kek| kek.unwrap_vec(wrapped)),
+        24 => Kek::<Aes192>::try_from(kek).and_then(|kek| kek.unwrap_vec(wrapped)),
+        _ => Kek::<Aes256>::try_from(kek).and_then(|kek| kek.unwrap_vec(wrapped)),
+    }
+    .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn known_blocks_encrypted() {
+        // NIST SP 800-38A, appendix F.5
+        let iv: [u8; 16] = bytes("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff")
+            .try_into()
+            .unwrap();
+        let plaintext = bytes("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+        for (key, ciphertext) in [
+            (
+                "2b7e151628aed2a6abf7158809cf4f3c",
+                "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff",
+            ),
+            (
+                "8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b",
+                "1abc932417521ca24f2b0459fe7e6e0b090339ec0aa6faefd5ccc2c6f4ce8e94",
+            ),
+            (
+                "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+                "601ec313775789a5b7a7f504bbf3d228f443e3ca4d62b59aca84e990cacaf5c5",
+            ),
+        ] {
+            let ctr = Ctr::new(&bytes(key)).unwrap();
+            let mut data = plaintext.clone();
+            ctr.apply(iv, &mut data);
+            assert_eq!(bytes(ciphertext), data);
+            ctr.apply(iv, &mut data);
+            assert_eq!(plaintext, data);
+        }
+    }
+
+    #[test]
+    fn keys_wrapped() {
+        // RFC 3394, section 4.1
+        let kek = bytes("000102030405060708090a0b0c0d0e0f");
+        let key = bytes("00112233445566778899aabbccddeeff");
+        let wrapped = bytes("1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5");
+
+        assert_eq!(Some(wrapped.clone()), wrap(&kek, &key));
+        assert_eq!(Some(key), unwrap(&kek, &wrapped));
+        assert_eq!(None, unwrap(&[0; 16], &wrapped));
+    }
+
+    #[test]
+    fn invalid_lengths_refused() {
+        assert!(Ctr::new(&[0; 8]).is_none());
+        assert_eq!(None, wrap(&[0; 20], &[0; 16]));
+        assert_eq!(None, wrap(&[0; 16], &[0; 12]));
+    }
+}
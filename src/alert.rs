@@ -0,0 +1,112 @@
+//! Alerts on the packet rate of the listener
+//!
+//! The rate of each stats interval is checked against an expected envelope, so that a
+//! dead encoder or a runaway sender is noticed at the relay. Alerts are raised when the
+//! rate leaves the envelope and once more when it returns, not on every interval.
+
+use std::fmt;
+
+/// Expected envelope of the packet rate, each bound optional
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RateThresholds {
+    /// Packets per second the rate should not fall below
+    pub min_pps: Option<f64>,
+    /// Packets per second the rate should not exceed
+    pub max_pps: Option<f64>,
+}
+
+impl RateThresholds {
+    /// Check the rate of an interval, returning an alert if its level changed
+    pub(crate) fn check(&self, pps: f64, level: &mut RateLevel) -> Option<RateAlert> {
+        let (new_level, alert) = match (self.min_pps, self.max_pps) {
+            (Some(min_pps), _) if pps < min_pps => {
+                (RateLevel::Low, RateAlert::BelowMin { pps, min_pps })
+            }
+            (_, Some(max_pps)) if pps > max_pps => {
+                (RateLevel::High, RateAlert::AboveMax { pps, max_pps })
+            }
+            _ => (RateLevel::Normal, RateAlert::Recovered { pps }),
+        };
+        if new_level == std::mem::replace(level, new_level) {
+            return None;
+        }
+        Some(alert)
+    }
+}
+
+/// Position of the rate relative to the envelope
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum RateLevel {
+    #[default]
+    Normal,
+    Low,
+    High,
+}
+
+/// Change of the packet rate relative to the envelope
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum RateAlert {
+    /// The rate fell below the minimum
+    BelowMin { pps: f64, min_pps: f64 },
+    /// The rate exceeded the maximum
+    AboveMax { pps: f64, max_pps: f64 },
+    /// The rate returned into the envelope
+    Recovered { pps: f64 },
+}
+
+impl fmt::Display for RateAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BelowMin { pps, min_pps } => {
+                write!(f, "rate {pps:.1} pps below the minimum of {min_pps} pps")
+            }
+            Self::AboveMax { pps, max_pps } => {
+                write!(f, "rate {pps:.1} pps above the maximum of {max_pps} pps")
+            }
+            Self::Recovered { pps } => write!(f, "rate back to {pps:.1} pps"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn alerts_on_changes_only() {
+        let thresholds = RateThresholds {
+            min_pps: Some(10.0),
+            max_pps: Some(1000.0),
+        };
+        let mut level = RateLevel::default();
+
+        let alerts: Vec<_> = [50.0, 0.0, 0.0, 2000.0, 500.0, 600.0]
+            .into_iter()
+            .map(|pps| thresholds.check(pps, &mut level))
+            .collect();
+        assert_eq!(
+            vec![
+                None,
+                Some(RateAlert::BelowMin {
+                    pps: 0.0,
+                    min_pps: 10.0
+                }),
+                None,
+                Some(RateAlert::AboveMax {
+                    pps: 2000.0,
+                    max_pps: 1000.0
+                }),
+                Some(RateAlert::Recovered { pps: 500.0 }),
+                None,
+            ],
+            alerts
+        );
+        assert_eq!(
+            "rate 0.0 pps below the minimum of 10 pps",
+            alerts[1].unwrap().to_string()
+        );
+    }
+}
@@ -0,0 +1,610 @@
+//! AMT gateway
+//!
+//! Pulls a multicast group across networks that only route unicast, like most of the
+//! internet, with Automatic Multicast Tunneling (RFC 7450). The gateway discovers the
+//! relay, requests membership and reports the group as IGMPv3 or MLDv2 report
+//! encapsulated in UDP. The relay then tunnels the multicast packets to the gateway,
+//! which unwraps them so the forwarder can re-emit them locally.
+//!
+//! Membership is refreshed at the query interval of the relay while receiving. Only
+//! the group and sources of the listener are joined, any-source or source-specific.
+
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ListenerSpec,
+    sink::checksum,
+    source::{Source, copy_truncated, udp_datagram},
+    tools::is_timeout,
+};
+
+/// UDP port of AMT relays
+pub const AMT_PORT: u16 = 2268;
+/// Anycast address to discover the nearest public AMT relay at (RFC 7450)
+pub const DISCOVERY_ADDR: Ipv4Addr = Ipv4Addr::new(192, 52, 193, 1);
+
+/// Time to wait for an answer of the relay before repeating the message
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Attempts to get an answer of the relay while setting up
+const ATTEMPTS: usize = 3;
+/// Query interval of IGMP and MLD if the relay does not tell
+const DEFAULT_QUERY_INTERVAL: Duration = Duration::from_secs(125);
+/// Largest message expected from the relay
+const MAX_MESSAGE: usize = 65536;
+
+/// AMT message types
+const RELAY_DISCOVERY: u8 = 1;
+const RELAY_ADVERTISEMENT: u8 = 2;
+const REQUEST: u8 = 3;
+const MEMBERSHIP_QUERY: u8 = 4;
+const MEMBERSHIP_UPDATE: u8 = 5;
+const MULTICAST_DATA: u8 = 6;
+const TEARDOWN: u8 = 7;
+
+/// Flag of membership queries followed by the address of the gateway as seen by the relay
+const GATEWAY_ADDRESS_FLAG: u8 = 0x01;
+/// Flag of requests for MLD instead of IGMP
+const MLD_FLAG: u8 = 0x01;
+
+/// IGMP and ICMPv6 message types
+const IGMP_QUERY: u8 = 0x11;
+const IGMPV3_REPORT: u8 = 0x22;
+const MLD_QUERY: u8 = 130;
+const MLDV2_REPORT: u8 = 143;
+/// Group record types of reports
+const MODE_IS_INCLUDE: u8 = 1;
+const MODE_IS_EXCLUDE: u8 = 2;
+/// Destinations of IGMPv3 and MLDv2 reports
+const IGMPV3_ROUTERS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 22);
+const MLDV2_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x16);
+/// IP protocol numbers
+const IGMP: u8 = 2;
+const ICMPV6: u8 = 58;
+
+/// Gateway receiving a multicast group through an AMT relay
+pub struct AmtSource {
+    socket: UdpSocket,
+    relay: SocketAddr,
+    group: SocketAddr,
+    sources: Vec<IpAddr>,
+    /// Nonce of the current request, echoed by the relay
+    nonce: [u8; 4],
+    /// MAC of the last query, authenticating updates and teardown
+    response_mac: [u8; 6],
+    /// Port and address of the gateway as seen by the relay, if it told
+    gateway: Option<[u8; 18]>,
+    query_interval: Duration,
+    next_request: Instant,
+    read_timeout: Option<Duration>,
+    nonblocking: bool,
+    message: Vec<u8>,
+}
+
+impl AmtSource {
+    /// Join the multicast group of `listener_spec` through `relay`, given as host with
+    /// optional port
+    ///
+    /// Fails if the listener is no multicast group or the relay does not answer.
+    pub fn open(listener_spec: &ListenerSpec, relay: &str) -> Result<Self, io::Error> {
+        let (group, sources) = match listener_spec {
+            ListenerSpec::MulticastV4 {
+                multicast_group,
+                sources,
+                ..
+            } => (
+                SocketAddr::V4(*multicast_group),
+                sources.iter().copied().map(IpAddr::V4).collect(),
+            ),
+            ListenerSpec::MulticastV6 {
+                multicast_group,
+                sources,
+                ..
+            } => (
+                SocketAddr::V6(*multicast_group),
+                sources.iter().copied().map(IpAddr::V6).collect(),
+            ),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{listener_spec} is no multicast group to receive through AMT"),
+                ));
+            }
+        };
+        let relay = resolve_relay(relay)?;
+        let local_addr = match relay {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+
+        let mut source = Self {
+            socket: UdpSocket::bind(local_addr)?,
+            relay,
+            group,
+            sources,
+            nonce: random_nonce(),
+            response_mac: [0; 6],
+            gateway: None,
+            query_interval: DEFAULT_QUERY_INTERVAL,
+            next_request: Instant::now(),
+            read_timeout: None,
+            nonblocking: false,
+            message: vec![0; MAX_MESSAGE],
+        };
+        source.discover()?;
+        source.request_membership()?;
+        Ok(source)
+    }
+
+    /// Address of the relay tunneling the group
+    pub fn relay(&self) -> SocketAddr {
+        self.relay
+    }
+
+    /// Multicast group received
+    pub fn group(&self) -> SocketAddr {
+        self.group
+    }
+
+    /// Learn the unicast address of the relay, which may have been an anycast address
+    fn discover(&mut self) -> Result<(), io::Error> {
+        let nonce = random_nonce();
+        let advertisement = self.exchange(&relay_discovery(nonce), |message| {
+            message.first() == Some(&RELAY_ADVERTISEMENT) && message.get(4..8) == Some(&nonce)
+        })?;
+        let ip = match advertisement.len() {
+            12 => IpAddr::V4(Ipv4Addr::from(
+                <[u8; 4]>::try_from(&advertisement[8..]).unwrap(),
+            )),
+            24 => IpAddr::V6(Ipv6Addr::from(
+                <[u8; 16]>::try_from(&advertisement[8..]).unwrap(),
+            )),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid advertisement of AMT relay {}", self.relay),
+                ));
+            }
+        };
+        self.relay = SocketAddr::new(ip, self.relay.port());
+        Ok(())
+    }
+
+    /// Request a query of the relay and answer it with the membership of the group
+    fn request_membership(&mut self) -> Result<(), io::Error> {
+        self.nonce = random_nonce();
+        let nonce = self.nonce;
+        let request = request(nonce, self.group.is_ipv6());
+        let query = self.exchange(&request, |message| {
+            message.first() == Some(&MEMBERSHIP_QUERY) && message.get(8..12) == Some(&nonce)
+        })?;
+        self.answer(&query)
+    }
+
+    /// Send an update with the membership of the group in response to `query`
+    fn answer(&mut self, query: &[u8]) -> Result<(), io::Error> {
+        let Some(query) = Query::parse(query) else {
+            return Ok(());
+        };
+        if query.nonce != self.nonce {
+            return Ok(());
+        }
+        self.response_mac = query.response_mac;
+        self.gateway = query.gateway.or(self.gateway);
+        self.query_interval = query.interval;
+        self.next_request = Instant::now() + self.query_interval;
+
+        let report = membership_report(self.group.ip(), &self.sources);
+        let update = membership_update(self.response_mac, self.nonce, &report);
+        self.socket.send_to(&update, self.relay)?;
+        Ok(())
+    }
+
+    /// Send `message` to the relay until it answers with a message passing `accept`
+    fn exchange(
+        &mut self,
+        message: &[u8],
+        accept: impl Fn(&[u8]) -> bool,
+    ) -> Result<Vec<u8>, io::Error> {
+        self.socket.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+        for _ in 0..ATTEMPTS {
+            self.socket.send_to(message, self.relay)?;
+            let deadline = Instant::now() + RESPONSE_TIMEOUT;
+            while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+                self.socket
+                    .set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+                let (len, from) = match self.socket.recv_from(&mut self.message) {
+                    Ok(received) => received,
+                    Err(e) if is_timeout(&e) => break,
+                    Err(e) => return Err(e),
+                };
+                let answer = &self.message[..len];
+                if from.ip() == self.relay.ip() && accept(answer) {
+                    return Ok(answer.to_vec());
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("AMT relay {} did not answer", self.relay),
+        ))
+    }
+}
+
+impl Source for AmtSource {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let now = Instant::now();
+            if now >= self.next_request {
+                self.nonce = random_nonce();
+                self.socket
+                    .send_to(&request(self.nonce, self.group.is_ipv6()), self.relay)?;
+                self.next_request = now + self.query_interval;
+            }
+            // Wake up in time to refresh the membership
+            let wait = deadline
+                .map_or(self.query_interval, |deadline| {
+                    deadline.saturating_duration_since(now)
+                })
+                .min(self.next_request - now)
+                .max(Duration::from_millis(1));
+            self.socket.set_read_timeout(Some(wait))?;
+
+            let (len, from) = match self.socket.recv_from(&mut self.message) {
+                Ok(received) => received,
+                Err(e) if self.nonblocking => return Err(e),
+                Err(e) if is_timeout(&e) => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(e);
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if from.ip() != self.relay.ip() {
+                continue;
+            }
+            match self.message[..len].first() {
+                Some(&MEMBERSHIP_QUERY) => {
+                    let query = self.message[..len].to_vec();
+                    self.answer(&query)?;
+                }
+                Some(&MULTICAST_DATA) => {
+                    let Some((source, destination, payload)) =
+                        self.message.get(2..len).and_then(udp_datagram)
+                    else {
+                        continue;
+                    };
+                    let wanted_source =
+                        self.sources.is_empty() || self.sources.contains(&source.ip());
+                    if destination == self.group && wanted_source {
+                        return Ok(Some((copy_truncated(payload, buffer), source)));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        self.nonblocking = nonblocking;
+        self.socket.set_nonblocking(nonblocking)
+    }
+}
+
+/// Leaves the group right away instead of letting the relay time it out
+impl Drop for AmtSource {
+    fn drop(&mut self) {
+        if let Some(gateway) = self.gateway {
+            let teardown = teardown(self.response_mac, self.nonce, gateway);
+            let _ = self.socket.send_to(&teardown, self.relay);
+        }
+    }
+}
+
+/// Membership query of the relay
+#[derive(Debug, PartialEq, Eq)]
+struct Query {
+    response_mac: [u8; 6],
+    nonce: [u8; 4],
+    /// Interval to refresh the membership at, as told by the querier
+    interval: Duration,
+    gateway: Option<[u8; 18]>,
+}
+
+impl Query {
+    /// Parse a membership query message with its encapsulated IGMPv3 or MLDv2 query
+    fn parse(message: &[u8]) -> Option<Self> {
+        if message.len() < 12 || message[0] != MEMBERSHIP_QUERY {
+            return None;
+        }
+        let (ip, gateway) = match message[1] & GATEWAY_ADDRESS_FLAG {
+            0 => (&message[12..], None),
+            _ => {
+                let (ip, gateway) =
+                    message[12..].split_at_checked(message.len().checked_sub(30)?)?;
+                (ip, Some(gateway.try_into().ok()?))
+            }
+        };
+
+        Some(Self {
+            response_mac: message[2..8].try_into().ok()?,
+            nonce: message[8..12].try_into().ok()?,
+            interval: query_interval(ip).unwrap_or(DEFAULT_QUERY_INTERVAL),
+            gateway,
+        })
+    }
+}
+
+/// Query interval of an IGMPv3 or MLDv2 general query in an IP packet
+fn query_interval(packet: &[u8]) -> Option<Duration> {
+    let qqic = match packet.first()? >> 4 {
+        4 => {
+            let igmp = packet.get(usize::from(packet[0] & 0x0f) * 4..)?;
+            (igmp.len() >= 12 && igmp[0] == IGMP_QUERY).then(|| igmp[9])?
+        }
+        6 => {
+            let mut next_header = *packet.get(6)?;
+            let mut offset = 40;
+            // Queries carry the router alert option in a hop-by-hop header
+            if next_header == 0 {
+                next_header = *packet.get(offset)?;
+                offset += (usize::from(*packet.get(offset + 1)?) + 1) * 8;
+            }
+            let mld = packet.get(offset..)?;
+            (next_header == ICMPV6 && mld.len() >= 28 && mld[0] == MLD_QUERY).then(|| mld[25])?
+        }
+        _ => return None,
+    };
+    // Codes from 128 on are a floating point number (RFC 3376, section 4.1.7)
+    let seconds = match qqic {
+        0 => return None,
+        1..128 => u64::from(qqic),
+        _ => u64::from((qqic & 0x0f) | 0x10) << (((qqic >> 4) & 0x07) + 3),
+    };
+    Some(Duration::from_secs(seconds))
+}
+
+fn relay_discovery(nonce: [u8; 4]) -> [u8; 8] {
+    let mut message = [RELAY_DISCOVERY, 0, 0, 0, 0, 0, 0, 0];
+    message[4..].copy_from_slice(&nonce);
+    message
+}
+
+fn request(nonce: [u8; 4], mld: bool) -> [u8; 8] {
+    let flags = match mld {
+        true => MLD_FLAG,
+        false => 0,
+    };
+    let mut message = [REQUEST, flags, 0, 0, 0, 0, 0, 0];
+    message[4..].copy_from_slice(&nonce);
+    message
+}
+
+fn membership_update(response_mac: [u8; 6], nonce: [u8; 4], report: &[u8]) -> Vec<u8> {
+    let mut message = vec![MEMBERSHIP_UPDATE, 0];
+    message.extend_from_slice(&response_mac);
+    message.extend_from_slice(&nonce);
+    message.extend_from_slice(report);
+    message
+}
+
+fn teardown(response_mac: [u8; 6], nonce: [u8; 4], gateway: [u8; 18]) -> Vec<u8> {
+    let mut message = vec![TEARDOWN, 0];
+    message.extend_from_slice(&response_mac);
+    message.extend_from_slice(&nonce);
+    message.extend_from_slice(&gateway);
+    message
+}
+
+/// IGMPv3 or MLDv2 report of the membership in `group`, in an IP packet
+///
+/// Any source is received without `sources`. The packet is sent from the unspecified
+/// address, as the relay knows the gateway by its tunnel.
+fn membership_report(group: IpAddr, sources: &[IpAddr]) -> Vec<u8> {
+    let record_type = match sources.is_empty() {
+        true => MODE_IS_EXCLUDE,
+        false => MODE_IS_INCLUDE,
+    };
+    let (report_type, group) = match group {
+        IpAddr::V4(group) => (IGMPV3_REPORT, group.octets().to_vec()),
+        IpAddr::V6(group) => (MLDV2_REPORT, group.octets().to_vec()),
+    };
+    let mut report = vec![report_type, 0, 0, 0, 0, 0, 0, 1];
+    report.extend_from_slice(&[record_type, 0]);
+    report.extend_from_slice(&(sources.len() as u16).to_be_bytes());
+    report.extend_from_slice(&group);
+    for source in sources {
+        match source {
+            IpAddr::V4(source) => report.extend_from_slice(&source.octets()),
+            IpAddr::V6(source) => report.extend_from_slice(&source.octets()),
+        }
+    }
+
+    let mut packet = Vec::with_capacity(48 + report.len());
+    if report_type == IGMPV3_REPORT {
+        let sum = checksum(&[&report]);
+        report[2..4].copy_from_slice(&sum.to_be_bytes());
+        // Header with router alert option
+        packet.extend_from_slice(&[0x46, 0xc0]);
+        packet.extend_from_slice(&(24 + report.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0, 1, IGMP, 0, 0]);
+        packet.extend_from_slice(&Ipv4Addr::UNSPECIFIED.octets());
+        packet.extend_from_slice(&IGMPV3_ROUTERS.octets());
+        packet.extend_from_slice(&[0x94, 0x04, 0, 0]);
+        let sum = checksum(&[&packet]);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    } else {
+        let len = report.len() as u32;
+        let pseudo_header = [
+            &Ipv6Addr::UNSPECIFIED.octets()[..],
+            &MLDV2_ROUTERS.octets(),
+            &len.to_be_bytes(),
+            &[0, 0, 0, ICMPV6],
+        ]
+        .concat();
+        let sum = checksum(&[&pseudo_header, &report]);
+        report[2..4].copy_from_slice(&sum.to_be_bytes());
+        packet.extend_from_slice(&[0x60, 0, 0, 0]);
+        packet.extend_from_slice(&(8 + report.len() as u16).to_be_bytes());
+        // Hop-by-hop header with router alert option follows
+        packet.extend_from_slice(&[0, 1]);
+        packet.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        packet.extend_from_slice(&MLDV2_ROUTERS.octets());
+        packet.extend_from_slice(&[ICMPV6, 0, 5, 2, 0, 0, 1, 0]);
+    }
+    packet.extend_from_slice(&report);
+    packet
+}
+
+/// Address of the relay given as host with optional port
+fn resolve_relay(relay: &str) -> Result<SocketAddr, io::Error> {
+    if let Ok(ip) = relay.trim_start_matches('[').trim_end_matches(']').parse() {
+        return Ok(SocketAddr::new(ip, AMT_PORT));
+    }
+    let mut addrs = match relay.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => relay.to_socket_addrs()?,
+        _ => (relay, AMT_PORT).to_socket_addrs()?,
+    };
+    addrs.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("AMT relay {relay} has no address"),
+        )
+    })
+}
+
+fn random_nonce() -> [u8; 4] {
+    (RandomState::new().build_hasher().finish() as u32).to_be_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+    use crate::sink::ip_packet;
+
+    /// IPv4 packet with an IGMPv3 general query with the given QQIC
+    fn igmp_query(qqic: u8) -> Vec<u8> {
+        let mut packet = vec![0x45, 0xc0, 0, 32, 0, 0, 0, 0, 1, IGMP, 0, 0];
+        packet.extend_from_slice(&[10, 0, 0, 1, 224, 0, 0, 1]);
+        packet.extend_from_slice(&[IGMP_QUERY, 100, 0, 0, 0, 0, 0, 0, 0, qqic, 0, 0]);
+        packet
+    }
+
+    #[test]
+    fn membership_report_built() {
+        let sources = [IpAddr::V4(Ipv4Addr::new(10, 1, 1, 1))];
+        let packet = membership_report("232.1.1.1".parse().unwrap(), &sources);
+
+        assert_eq!(24 + 8 + 8 + 4, packet.len());
+        assert_eq!(
+            [IGMPV3_ROUTERS.octets(), [0x94, 0x04, 0, 0]].concat(),
+            packet[16..24]
+        );
+        assert_eq!(
+            [MODE_IS_INCLUDE, 0, 0, 1, 232, 1, 1, 1, 10, 1, 1, 1],
+            packet[32..]
+        );
+        // Checksums verify to zero
+        assert_eq!(0xffff, checksum(&[&packet[..24]]));
+        assert_eq!(0xffff, checksum(&[&packet[24..]]));
+
+        let packet = membership_report("ff3e::1".parse().unwrap(), &[]);
+        assert_eq!(40 + 8 + 8 + 20, packet.len());
+        assert_eq!([MLDV2_REPORT, MODE_IS_EXCLUDE], [packet[48], packet[56]]);
+
+        // Query intervals from 128 seconds on are encoded with exponent and mantissa
+        assert_eq!(
+            Some(Duration::from_secs(125)),
+            query_interval(&igmp_query(125))
+        );
+        assert_eq!(
+            Some(Duration::from_secs(136)),
+            query_interval(&igmp_query(0x81))
+        );
+        assert_eq!(None, query_interval(&igmp_query(0)));
+    }
+
+    #[test]
+    fn group_received_through_relay() {
+        let relay = UdpSocket::bind("127.0.0.1:0").unwrap();
+        relay
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let group: SocketAddr = "232.1.1.1:5004".parse().unwrap();
+        let sender: SocketAddr = "10.1.1.1:6000".parse().unwrap();
+        let gateway = [0x42; 18];
+
+        let relay = thread::spawn(move || {
+            let mut buffer = [0; 1500];
+            let mut recv = || {
+                let (len, from) = relay.recv_from(&mut buffer).unwrap();
+                (buffer[..len].to_vec(), from)
+            };
+
+            let (discovery, gateway_addr) = recv();
+            assert_eq!(RELAY_DISCOVERY, discovery[0]);
+            let mut advertisement = vec![RELAY_ADVERTISEMENT, 0, 0, 0];
+            advertisement.extend_from_slice(&discovery[4..8]);
+            advertisement.extend_from_slice(&[127, 0, 0, 1]);
+            relay.send_to(&advertisement, gateway_addr).unwrap();
+
+            let (request, _) = recv();
+            assert_eq!([REQUEST, 0], request[..2]);
+            let mut query = vec![MEMBERSHIP_QUERY, GATEWAY_ADDRESS_FLAG, 1, 2, 3, 4, 5, 6];
+            query.extend_from_slice(&request[4..8]);
+            query.extend_from_slice(&igmp_query(60));
+            query.extend_from_slice(&gateway);
+            relay.send_to(&query, gateway_addr).unwrap();
+
+            let (update, _) = recv();
+            assert_eq!([MEMBERSHIP_UPDATE, 0, 1, 2, 3, 4, 5, 6], update[..8]);
+            assert_eq!(request[4..8], update[8..12]);
+            assert_eq!(membership_report(group.ip(), &[sender.ip()]), update[12..]);
+
+            // Packets of other groups and sources are left out
+            let other_group = "232.2.2.2:5004".parse().unwrap();
+            let other_sender = "10.2.2.2:6000".parse().unwrap();
+            for (from, to) in [
+                (sender, other_group),
+                (other_sender, group),
+                (sender, group),
+            ] {
+                let mut data = vec![MULTICAST_DATA, 0];
+                data.extend_from_slice(&ip_packet(from, to, b"payload"));
+                relay.send_to(&data, gateway_addr).unwrap();
+            }
+
+            let (teardown, _) = recv();
+            assert_eq!([TEARDOWN, 0, 1, 2, 3, 4, 5, 6], teardown[..8]);
+            assert_eq!(gateway, teardown[12..]);
+        });
+
+        let listener_spec = "10.1.1.1@232.1.1.1:5004".parse().unwrap();
+        let mut source = AmtSource::open(&listener_spec, &relay_addr.to_string()).unwrap();
+        source
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(Duration::from_secs(60), source.query_interval);
+
+        let mut buffer = [0; 1500];
+        assert_eq!(Some((7, sender)), source.recv(&mut buffer).unwrap());
+        assert_eq!(b"payload", &buffer[..7]);
+
+        drop(source);
+        relay.join().unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+//! Standard base64 encoding (RFC 4648)
+//!
+//! The JSON API of etcd carries keys and values base64-encoded.
+
+/// Alphabet of the standard encoding
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode `data` with padding
+pub(crate) fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for idx in 0..4 {
+            match idx <= chunk.len() {
+                true => encoded.push(ALPHABET[(bits >> (18 - idx * 6) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode `encoded`, with or without padding
+pub(crate) fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+
+    for chunk in encoded.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (idx, byte) in chunk.iter().enumerate() {
+            let value = ALPHABET.iter().position(|c| c == byte)? as u32;
+            bits |= value << (18 - idx * 6);
+        }
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn known_encodings() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"/udpforwarder/targets", "L3VkcGZvcndhcmRlci90YXJnZXRz"),
+        ] {
+            assert_eq!(encoded, encode(data));
+            assert_eq!(Some(data.to_vec()), decode(encoded));
+        }
+        assert_eq!(None, decode("Z"));
+        assert_eq!(None, decode("Zm9*"));
+    }
+}
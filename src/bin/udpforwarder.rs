@@ -1,63 +1,1275 @@
 //! UDP forwarder
 
-use udpforwarder::{ParseArgsError, forward, parse_args};
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
 
-fn main() {
+#[cfg(feature = "pcap")]
+use std::net::{Ipv4Addr, SocketAddr};
+
+#[cfg(all(feature = "dpdk", target_os = "linux"))]
+use udpforwarder::dpdk;
+#[cfg(feature = "pcap")]
+use udpforwarder::sink::PcapSink;
+use udpforwarder::{
+    Args, Command, Forwarder, ListenerBuilder, ListenerSpec, Options, ParseArgsError,
+    ShutdownHandle, admin, amt, check, daemon,
+    diagnostics::{self, CapabilityReport},
+    discovery,
+    geoip::GeoIp,
+    history::{self, PacketSummary},
+    hooks::DumpTrigger,
+    hub, iperf,
+    kcp::{KcpSink, KcpSource},
+    log::{LogLevel, RateLimiter, Verdict, label_prefix},
+    mdns,
+    notify::{Event, ExecHook, Webhook},
+    parse_command,
+    reload::{self, Reload, Reloader},
+    render_help, render_manpage, rendezvous,
+    rist::RistSink,
+    sandbox, sap, session, signal,
+    source::Source,
+    srt::SrtSink,
+    stun,
+    summary::ExitSummary,
+    tools, tui, turn,
+};
+#[cfg(target_os = "linux")]
+use udpforwarder::{capture, netns, timestamp, xdp};
+#[cfg(feature = "control")]
+use udpforwarder::{control, diagnostics::Setup, metrics};
+
+fn main() -> ExitCode {
     // Parse and handle arguments
-    let args = match parse_args(std::env::args().skip(1)) {
-        Ok(args) => args,
+    let command_line: Vec<String> = std::env::args().skip(1).collect();
+    let command = match parse_command(command_line.clone()) {
+        Ok(command) => command,
         Err(e) => {
             match e {
                 ParseArgsError::Help => {
-                    println!("{HELP}");
+                    println!("{}", render_help());
+                    return ExitCode::SUCCESS;
                 }
                 ParseArgsError::MissingArgs => {
                     eprintln!("Missing arguments\n");
-                    println!("{HELP}");
+                    println!("{}", render_help());
                 }
-                ParseArgsError::ListenerSpec => {
-                    eprintln!("Failed to parse the listener specification");
+                ParseArgsError::ListenerSpec(e) => {
+                    eprintln!("Failed to parse the listener specification: {e}");
                 }
                 ParseArgsError::ForwardSpec(e) => {
-                    eprintln!("Failed to parse the listener specification: {e}");
+                    eprintln!("Failed to parse the forward address specification: {e}");
+                }
+                ParseArgsError::TurnSpec(spec) => {
+                    eprintln!("Failed to parse the TURN target specification {spec}");
+                }
+                ParseArgsError::SrtSpec(spec) => {
+                    eprintln!("Failed to parse the SRT target specification {spec}");
+                }
+                ParseArgsError::RistSpec(spec) => {
+                    eprintln!("Failed to parse the RIST target specification {spec}");
+                }
+                ParseArgsError::KcpSpec(spec) => {
+                    eprintln!("Failed to parse the KCP target specification {spec}");
+                }
+                ParseArgsError::DiscoverySpec(spec) => {
+                    eprintln!("Failed to parse the service discovery specification {spec}");
+                }
+                ParseArgsError::MirrorSpec(spec) => {
+                    eprintln!("Failed to parse the mirror target specification {spec}");
+                }
+                ParseArgsError::RuleSpec(spec) => {
+                    eprintln!("Failed to parse the forwarding rule {spec}");
+                }
+                ParseArgsError::SocatSpec(e) => {
+                    eprintln!("Failed to parse the socat address {e}");
+                }
+                ParseArgsError::TargetGroup(e) => {
+                    eprintln!("Failed to parse target group: {e}");
+                }
+                ParseArgsError::TargetsFile(path, e) => {
+                    eprintln!("Failed to read targets file {}: {e}", path.display());
+                }
+                ParseArgsError::TargetsStdin(e) => {
+                    eprintln!("Failed to read targets from stdin: {e}");
+                }
+                ParseArgsError::UnknownOption(option) => {
+                    eprintln!("Unknown option {option}\n");
+                    println!("{}", render_help());
+                }
+                ParseArgsError::InvalidValue(option) => {
+                    eprintln!("Missing or invalid value for option {option}");
+                }
+                ParseArgsError::ZeroValue(option) => {
+                    eprintln!("Value of option {option} must be greater than zero");
+                }
+                ParseArgsError::UnexpectedArg(arg) => {
+                    eprintln!("Unexpected argument {arg}");
+                }
+                ParseArgsError::Config(e) => {
+                    eprintln!("Invalid config file {e}");
                 }
             }
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
-    // Forward from listening socket to forward addresses
-    if let Err(e) = forward(args.listener_spec, &args.forward_addrs) {
-        eprintln!("Failed to forward: {e}");
+    match command {
+        Command::Run(args) => {
+            if args.options.daemon
+                && let Err(e) = daemon::daemonize(args.options.log_file.as_deref())
+            {
+                eprintln!("Failed to daemonize: {e}");
+                return ExitCode::FAILURE;
+            }
+            if let Some(pidfile) = &args.options.pidfile
+                && let Err(e) = daemon::write_pidfile(pidfile)
+            {
+                eprintln!("Failed to write PID file {}: {e}", pidfile.display());
+                return ExitCode::FAILURE;
+            }
+
+            let geoip = match args.options.geoip_databases.as_slice() {
+                [] => None,
+                paths => match GeoIp::open(paths) {
+                    Ok(geoip) => Some(geoip),
+                    Err(e) => {
+                        eprintln!("Failed to load GeoIP database: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                },
+            };
+
+            let mdns_service = match &args.options.mdns {
+                Some(mdns) => match mdns.service(&args.listener_spec) {
+                    Ok(service) => Some(service),
+                    Err(e) => {
+                        eprintln!("Failed to advertise via mDNS: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                },
+                None => None,
+            };
+
+            if args.options.on_event.is_some() && (args.options.seccomp || args.options.landlock) {
+                eprintln!("--on-event runs commands, which --seccomp and --landlock forbid");
+                return ExitCode::FAILURE;
+            }
+            if args.options.webhook.is_some() && args.options.seccomp {
+                eprintln!("--webhook connects to the URL, which --seccomp forbids");
+                return ExitCode::FAILURE;
+            }
+            if args.options.control_addr.is_some() && !cfg!(feature = "control") {
+                eprintln!(
+                    "--control-addr needs the HTTP control API, built without the control feature"
+                );
+                return ExitCode::FAILURE;
+            }
+            if args.options.metrics_addr.is_some() && !cfg!(feature = "control") {
+                eprintln!(
+                    "--metrics-addr needs the HTTP server, built without the control feature"
+                );
+                return ExitCode::FAILURE;
+            }
+            if args.options.pcap.is_some() && !cfg!(feature = "pcap") {
+                eprintln!("--pcap writes captures, built without the pcap feature");
+                return ExitCode::FAILURE;
+            }
+            if args.options.watch_config && args.options.config.is_none() {
+                eprintln!("--watch-config requires --config");
+                return ExitCode::FAILURE;
+            }
+            if args.options.watch_config
+                && (args.options.seccomp || args.options.landlock || args.options.chroot.is_some())
+            {
+                eprintln!(
+                    "--watch-config reads the config file, which --seccomp, --landlock and \
+                     --chroot forbid"
+                );
+                return ExitCode::FAILURE;
+            }
+            if args.options.retry.is_some() && args.options.seccomp {
+                eprintln!("--retry-on-error binds new sockets, which --seccomp forbids");
+                return ExitCode::FAILURE;
+            }
+            if args.options.state_file.is_some() && args.options.seccomp {
+                eprintln!("--state-file writes the hub peers to a file, which --seccomp forbids");
+                return ExitCode::FAILURE;
+            }
+            if args.options.target_queue.is_some() && args.options.seccomp {
+                eprintln!("--target-queue starts a thread per target, which --seccomp forbids");
+                return ExitCode::FAILURE;
+            }
+            if args.options.reply_path && args.options.seccomp {
+                eprintln!("--reply-path starts a thread per client, which --seccomp forbids");
+                return ExitCode::FAILURE;
+            }
+            if !args.discovery.is_empty() && args.options.seccomp {
+                eprintln!(
+                    "Service discovery resolves names and reads files, which --seccomp forbids"
+                );
+                return ExitCode::FAILURE;
+            }
+            if args.options.verbose && args.options.log_level == Some(LogLevel::Error) {
+                eprintln!("--verbose logs new sources, which --quiet hides");
+                return ExitCode::FAILURE;
+            }
+            let log = Log::new(
+                args.options.log_level.unwrap_or_default(),
+                &args.options.labels,
+            );
+            let notifier = Notifier {
+                on_event: args.options.on_event.clone(),
+                webhook: args.options.webhook.clone(),
+                log: log.clone(),
+            };
+
+            let port = args.listener_spec.port();
+            #[cfg(feature = "pcap")]
+            let listener_addr = args.listener_spec.addr();
+            // Kept to open the source anew after transient failures
+            let source_options = args.options.clone();
+            // Kept to tell the changes of reloads
+            let initial_args = args.clone();
+            // Created before dropping privileges, written once forwarding stopped
+            let mut summary_out = match args.options.summary.as_deref() {
+                Some(path) if path == Path::new("-") => {
+                    Some(Box::new(io::stdout()) as Box<dyn Write>)
+                }
+                Some(path) => match File::create(path) {
+                    Ok(file) => Some(Box::new(file) as Box<dyn Write>),
+                    Err(e) => {
+                        eprintln!("Failed to create summary file {}: {e}", path.display());
+                        return ExitCode::FAILURE;
+                    }
+                },
+                None => None,
+            };
+            let forwarder = open_forwarder(&args, &log);
+            let mut forwarder = match forwarder {
+                Ok(mut forwarder) => {
+                    {
+                        let log = log.clone();
+                        forwarder = forwarder.on_send_error(move |addr, e| {
+                            log.warn(format!("Failed to send to {addr}: {e}"));
+                        });
+                    }
+                    if !args.target_groups.is_empty() {
+                        forwarder = forwarder.with_target_groups(args.target_groups);
+                    }
+                    if let Some(hop_limit) = args.options.hop_limit {
+                        forwarder = forwarder.with_hop_limit(hop_limit);
+                    }
+                    if let Some(one_way_delay) = args.options.one_way_delay {
+                        forwarder = forwarder.with_one_way_delay(one_way_delay);
+                    }
+                    if let Some(keepalive) = args.options.keepalive {
+                        forwarder = forwarder.with_keepalive(keepalive);
+                    }
+                    if let Some(heartbeat) = args.options.heartbeat {
+                        forwarder = forwarder.with_heartbeat(heartbeat);
+                    }
+                    if let Some(interval) = args.options.stats_interval {
+                        forwarder = forwarder.with_stats_interval(interval);
+                    }
+                    if let Some(thresholds) = args.options.rate_thresholds {
+                        let notifier = notifier.clone();
+                        let log = log.clone();
+                        forwarder =
+                            forwarder
+                                .with_rate_alerts(thresholds)
+                                .on_rate_alert(move |alert| {
+                                    log.warn(format!("Rate alert: {alert}"));
+                                    notifier.notify(Event::Rate(alert));
+                                });
+                    }
+                    if let Some(alarm) = args.options.silence_alarm {
+                        let notifier = notifier.clone();
+                        let log = log.clone();
+                        forwarder = forwarder.with_silence_alarm(alarm).on_stream_state_change(
+                            move |state| {
+                                log.warn(format!("Stream {state}"));
+                                notifier.notify(Event::Stream(state));
+                            },
+                        );
+                    }
+                    let capacity = args
+                        .options
+                        .packet_history
+                        .unwrap_or(history::DEFAULT_CAPACITY);
+                    if capacity > 0 {
+                        let log = log.clone();
+                        forwarder = forwarder.with_packet_history(capacity).on_packet_history(
+                            move |trigger, summaries| {
+                                log.warn(format_packet_history(trigger, summaries));
+                            },
+                        );
+                    }
+                    if notifier.is_enabled() {
+                        let notifier = notifier.clone();
+                        forwarder = forwarder.on_target_state_change(move |addr, state| {
+                            notifier.notify(Event::target(addr, state));
+                        });
+                    }
+                    if args.options.hub {
+                        forwarder = forwarder
+                            .with_hub(args.options.peer_expiry.unwrap_or(hub::DEFAULT_PEER_EXPIRY));
+                    }
+                    if let Some(geoip) = geoip {
+                        forwarder = forwarder.with_geoip(geoip);
+                    }
+                    if args.options.verbose {
+                        forwarder = forwarder.with_verbose();
+                    }
+                    if !args.options.labels.is_empty() {
+                        forwarder = forwarder.with_labels(args.options.labels.clone());
+                    }
+                    if args.options.inspect_dns {
+                        forwarder = forwarder.with_dns_inspection();
+                    }
+                    if let Some(field) = args.options.sequence {
+                        forwarder = forwarder.with_sequence(field);
+                    }
+                    if let Some(syslog) = args.options.syslog {
+                        forwarder = forwarder.with_syslog(syslog);
+                    }
+                    if let Some(gelf) = args.options.gelf {
+                        forwarder = forwarder.with_gelf_chunking(gelf);
+                    }
+                    #[cfg(feature = "pcap")]
+                    if let Some(path) = &args.options.pcap {
+                        let destination = listener_addr
+                            .or(forwarder.state().listener_addr())
+                            .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+                        match PcapSink::create(path, destination) {
+                            Ok(sink) => forwarder = forwarder.with_sink(sink),
+                            Err(e) => {
+                                eprintln!("Failed to create capture {}: {e}", path.display());
+                                return ExitCode::FAILURE;
+                            }
+                        }
+                    }
+                    forwarder
+                }
+                Err(e) => {
+                    eprintln!("Failed to set up forwarding: {e}");
+                    print_bind_error_hint(port, &e);
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            // Stops all rules on SIGINT or SIGTERM
+            let shutdown = ShutdownHandle::new();
+
+            // Bound before dropping privileges like the listener of the first rule
+            let mut rule_forwarders = Vec::new();
+            for rule in &args.rules {
+                let forwarder =
+                    match Forwarder::new(rule.listener_spec.clone(), rule.forward_addrs.clone()) {
+                        Ok(forwarder) => forwarder,
+                        Err(e) => {
+                            eprintln!(
+                                "Failed to set up forwarding from {}: {e}",
+                                rule.listener_spec
+                            );
+                            print_bind_error_hint(rule.listener_spec.port(), &e);
+                            return ExitCode::FAILURE;
+                        }
+                    };
+                let mut forwarder = {
+                    let log = log.clone();
+                    forwarder.on_send_error(move |addr, e| {
+                        log.warn(format!("Failed to send to {addr}: {e}"));
+                    })
+                };
+                if args.options.verbose {
+                    forwarder = forwarder.with_verbose();
+                }
+                if !args.options.labels.is_empty() {
+                    forwarder = forwarder.with_labels(args.options.labels.clone());
+                }
+                rule_forwarders.push((
+                    rule.listener_spec.clone(),
+                    forwarder.with_shutdown(shutdown.clone()),
+                ));
+            }
+
+            if let Some(path) = &args.options.state_file {
+                match forwarder.persist_peers(path) {
+                    Ok(restored) => log.info(format!(
+                        "Restored {restored} hub peers from {}",
+                        path.display()
+                    )),
+                    Err(e) => {
+                        eprintln!("Failed to persist hub peers to {}: {e}", path.display());
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            if !args.options.stun_servers.is_empty() {
+                let servers = match stun::resolve(&args.options.stun_servers) {
+                    Ok(servers) => servers,
+                    Err(e) => {
+                        eprintln!("Failed to resolve STUN servers: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                };
+                // Only informative, forwarding works without knowing the public address
+                for result in forwarder.discover_public_addrs(&servers, stun::TIMEOUT) {
+                    match result {
+                        Ok(mapping) => log.info(format!(
+                            "Sender {} is reachable at {} ({})",
+                            mapping.local, mapping.public, mapping.nat
+                        )),
+                        Err(e) => log.warn(format!("Failed to discover public address: {e}")),
+                    }
+                }
+            }
+
+            if let Some(spec) = &args.options.rendezvous {
+                match forwarder.rendezvous(spec, rendezvous::TIMEOUT) {
+                    Ok(peer) => log.info(format!("Direct path to peer {peer} open")),
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to reach peer of session {} via {}: {e}",
+                            spec.session, spec.server
+                        );
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            for target in &args.turn_targets {
+                match forwarder.add_turn_target(target, turn::TIMEOUT) {
+                    Ok(relayed_addr) => log.info(format!(
+                        "Relaying to {} through TURN relay {relayed_addr}",
+                        target.peer
+                    )),
+                    Err(e) => {
+                        eprintln!("Failed to allocate TURN relay at {}: {e}", target.server);
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            for target in &args.srt_targets {
+                match SrtSink::connect(target, forwarder.state()) {
+                    Ok(sink) => {
+                        log.info(format!("Sending to {sink}"));
+                        forwarder = forwarder.with_sink(sink);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to connect to SRT receiver {target:?}: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            for target in &args.rist_targets {
+                match RistSink::connect(target, forwarder.state()) {
+                    Ok(sink) => {
+                        log.info(format!("Sending to {sink}"));
+                        forwarder = forwarder.with_sink(sink);
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to send to RIST receiver {}:{}: {e}",
+                            target.host, target.port
+                        );
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            for target in &args.kcp_targets {
+                match KcpSink::connect(target, forwarder.state()) {
+                    Ok(sink) => {
+                        log.info(format!("Sending to {sink}"));
+                        forwarder = forwarder.with_sink(sink);
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to send to KCP receiver {}:{}: {e}",
+                            target.host, target.port
+                        );
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            for registry in args.discovery {
+                let name = registry.to_string();
+                match discovery::watch(registry, forwarder.state()) {
+                    Ok(_) => log.info(format!("Following targets of {name}")),
+                    Err(e) => {
+                        eprintln!("Failed to watch {name}: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            if let Some(service) = mdns_service {
+                let instance = service.instance.clone();
+                match mdns::advertise(service) {
+                    Ok(_) => log.info(format!("Advertising {instance} via mDNS")),
+                    Err(e) => {
+                        eprintln!("Failed to advertise via mDNS: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            // The arguments of the run subcommand are parsed again on reloads
+            let run_args = match command_line.first().map(String::as_str) {
+                Some("run") => command_line[1..].to_vec(),
+                _ => command_line,
+            };
+            match Reloader::new(run_args, &initial_args, forwarder.state()) {
+                Ok(reloader) => {
+                    if args.options.watch_config
+                        && let Some(path) = initial_args.options.config.clone()
+                    {
+                        let reload_log = log.clone();
+                        let watched = reload::watch(
+                            path.clone(),
+                            reloader.clone(),
+                            move |reload| match reload {
+                                Ok(reload) => log_reload(&reload_log, &reload),
+                                Err(e) => reload_log
+                                    .warn(format!("Not reloading {}: {e}", path.display())),
+                            },
+                        );
+                        if let Err(e) = watched {
+                            eprintln!("Failed to watch the config file: {e}");
+                            return ExitCode::FAILURE;
+                        }
+                    }
+                    let reload_log = log.clone();
+                    let seccomp = args.options.seccomp;
+                    if let Err(e) = signal::reload_on_hangup(move || {
+                        // Parsing the arguments again reads the config and targets files
+                        if seccomp {
+                            reload_log.warn(
+                                "Not reloading on SIGHUP, which --seccomp forbids".to_owned(),
+                            );
+                            return;
+                        }
+                        reload_log.info("Reloading on SIGHUP".to_owned());
+                        match reloader.reload() {
+                            Ok(reload) => log_reload(&reload_log, &reload),
+                            Err(e) => reload_log.warn(format!("Not reloading: {e}")),
+                        }
+                    }) {
+                        log.warn(format!("Not reloading on SIGHUP: {e}"));
+                    }
+                }
+                Err(e) if args.options.watch_config => {
+                    eprintln!("Failed to watch the config file: {e}");
+                    return ExitCode::FAILURE;
+                }
+                Err(e) => log.warn(format!("Not reloading on SIGHUP: {e}")),
+            }
+
+            #[cfg(feature = "control")]
+            if let Some(control_addr) = args.options.control_addr {
+                forwarder.state().set_setup(Setup {
+                    options: args.applied_options,
+                    listener_spec: Some(args.listener_spec.clone()),
+                    socket: forwarder
+                        .socket()
+                        .and_then(|socket| socket.try_clone().ok()),
+                });
+                if let Err(e) = control::serve(control_addr, forwarder.state()) {
+                    eprintln!("Failed to serve control API on {control_addr}: {e}");
+                    return ExitCode::FAILURE;
+                }
+            }
+            #[cfg(feature = "control")]
+            if let Some(metrics_addr) = args.options.metrics_addr
+                && let Err(e) = metrics::serve(metrics_addr, forwarder.state())
+            {
+                eprintln!("Failed to serve metrics on {metrics_addr}: {e}");
+                return ExitCode::FAILURE;
+            }
+            if let Some(admin_socket) = &args.options.admin_socket
+                && let Err(e) = admin::serve(admin_socket, forwarder.state())
+            {
+                eprintln!("Failed to serve admin socket on {admin_socket}: {e}");
+                return ExitCode::FAILURE;
+            }
+
+            let signal_log = log.clone();
+            if let Err(e) = signal::watch(forwarder.state(), move |paused| match paused {
+                true => signal_log.info("Forwarding paused".to_owned()),
+                false => signal_log.info("Forwarding resumed".to_owned()),
+            }) {
+                log.warn(format!("Not pausing on signals: {e}"));
+            }
+            if let Err(e) = signal::shutdown_on_termination(shutdown.clone()) {
+                log.warn(format!("Not stopping gracefully on signals: {e}"));
+            }
+
+            if args.options.tui
+                && let Err(e) = tui::spawn(forwarder.state(), Duration::from_secs(1))
+            {
+                eprintln!("Failed to start status display: {e}");
+                return ExitCode::FAILURE;
+            }
+
+            // Drop privileges once the sockets are bound
+            if (args.options.user.is_some()
+                || args.options.group.is_some()
+                || args.options.chroot.is_some())
+                && let Err(e) = daemon::drop_privileges(
+                    args.options.user.as_deref(),
+                    args.options.group.as_deref(),
+                    args.options.chroot.as_deref(),
+                )
+            {
+                eprintln!("Failed to drop privileges: {e}");
+                return ExitCode::FAILURE;
+            }
+
+            if args.options.landlock {
+                let allowed_paths: Vec<_> = [&args.options.log_file, &args.options.pidfile]
+                    .into_iter()
+                    .flatten()
+                    .map(PathBuf::as_path)
+                    .collect();
+                let replaced_paths: Vec<_> = args
+                    .options
+                    .state_file
+                    .iter()
+                    .map(PathBuf::as_path)
+                    .collect();
+                match sandbox::restrict_filesystem(&allowed_paths, &replaced_paths) {
+                    Ok(()) => {}
+                    // Best effort on kernels without Landlock
+                    Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                        log.warn(format!("Not restricting filesystem access: {e}"));
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to restrict filesystem access: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            // Further rules forward on threads of their own, failing without stopping others.
+            // Started before the seccomp filter, which forbids starting threads, and after
+            // Landlock, which only restricts threads started afterwards
+            let mut rule_threads = Vec::new();
+            for (listener_spec, forwarder) in rule_forwarders {
+                let log = log.clone();
+                let spawned = thread::Builder::new()
+                    .name("rule".to_owned())
+                    .spawn(move || match forwarder.run() {
+                        Ok(stats) => {
+                            log.info(format!("Forwarding from {listener_spec} finished: {stats}"))
+                        }
+                        Err(e) => log.warn(format!(
+                            "Failed to forward from {listener_spec}: {e}, stopped: {}",
+                            e.stats
+                        )),
+                    });
+                match spawned {
+                    Ok(thread) => rule_threads.push(thread),
+                    Err(e) => {
+                        eprintln!("Failed to start forwarding rule: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+
+            if args.options.seccomp
+                && let Err(e) = sandbox::install_seccomp_filter()
+            {
+                eprintln!("Failed to install seccomp filter: {e}");
+                return ExitCode::FAILURE;
+            }
+
+            // Forward from listening socket to forward addresses
+            let forwarder = forwarder.with_shutdown(shutdown.clone());
+            let state = forwarder.state();
+            let result = match args.options.retry {
+                Some(policy) => forwarder.run_supervised(
+                    policy,
+                    || {
+                        open_source(
+                            &args.listener_spec,
+                            args.listener_vrf.as_deref(),
+                            &source_options,
+                            &log,
+                        )
+                    },
+                    |e, backoff| {
+                        log.warn(format!("Forwarding failed: {e}, retrying in {backoff:?}"))
+                    },
+                ),
+                None => forwarder.run(),
+            };
+            if let Some(out) = &mut summary_out {
+                let summary = ExitSummary::new(&result, &state).with_shutdown(&shutdown);
+                if let Err(e) = out.write_all(summary.to_json().as_bytes()) {
+                    log.warn(format!("Failed to write summary: {e}"));
+                }
+            }
+            match result {
+                Ok(stats) => {
+                    match shutdown.is_shutdown() {
+                        true => log.info(format!("Forwarding stopped: {stats}")),
+                        false => log.info(format!("Forwarding finished: {stats}")),
+                    }
+                    for thread in rule_threads {
+                        let _ = thread.join();
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Failed to forward: {e}");
+                    eprintln!("Forwarding stopped: {}", e.stats);
+                    // Deliver before exiting, which would cut short a webhook in the background
+                    notifier.deliver(&Event::Fatal(e.error.to_string()));
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Command::Check { args, check_caps } => {
+            if check_caps {
+                println!("{}", CapabilityReport::probe());
+            }
+
+            let port = args.listener_spec.port();
+            if let Err(e) = check(args.listener_spec, &args.forward_addrs) {
+                eprintln!("Check failed: {e}");
+                print_bind_error_hint(port, &e);
+                return ExitCode::FAILURE;
+            }
+            println!("Listener and {} target(s) OK", args.forward_addrs.len());
+            for rule in args.rules {
+                let port = rule.listener_spec.port();
+                if let Err(e) = check(rule.listener_spec.clone(), &rule.forward_addrs) {
+                    eprintln!("Check of rule {} failed: {e}", rule.listener_spec);
+                    print_bind_error_hint(port, &e);
+                    return ExitCode::FAILURE;
+                }
+                println!(
+                    "Listener {} and {} target(s) OK",
+                    rule.listener_spec,
+                    rule.forward_addrs.len()
+                );
+            }
+        }
+        Command::Stats {
+            listener_spec,
+            interval,
+        } => {
+            let result = tools::stats(listener_spec, interval, |sample| {
+                let secs = sample.interval.as_secs_f64();
+                match sample.jitter {
+                    Some(jitter) => println!(
+                        "{:.0} packets/s {:.0} bytes/s, {jitter}",
+                        sample.packets as f64 / secs,
+                        sample.bytes as f64 / secs
+                    ),
+                    None => println!(
+                        "{:.0} packets/s {:.0} bytes/s",
+                        sample.packets as f64 / secs,
+                        sample.bytes as f64 / secs
+                    ),
+                }
+            });
+            if let Err(e) = result {
+                eprintln!("Failed to receive: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::Bench {
+            target,
+            count,
+            size,
+        } => match tools::bench(target, count, size) {
+            Ok(report) => {
+                let secs = report.elapsed.as_secs_f64();
+                println!(
+                    "Sent {} packets ({} bytes) in {secs:.3}s: {:.0} packets/s {:.1} Mbit/s",
+                    report.packets,
+                    report.bytes,
+                    report.packets as f64 / secs,
+                    report.bytes as f64 * 8.0 / secs / 1e6
+                );
+            }
+            Err(e) => {
+                eprintln!("Failed to send: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        Command::Selftest { count, size } => match tools::selftest(count, size) {
+            Ok(report) => {
+                let secs = report.elapsed.as_secs_f64();
+                println!(
+                    "Forwarded {} packets ({} bytes) in {secs:.3}s: {:.0} packets/s {:.1} Mbit/s, {} lost",
+                    report.packets,
+                    report.bytes,
+                    report.packets as f64 / secs,
+                    report.bytes as f64 * 8.0 / secs / 1e6,
+                    report.lost
+                );
+                println!(
+                    "Latency p50 {:?} p90 {:?} p99 {:?} max {:?}",
+                    report.latency_p50, report.latency_p90, report.latency_p99, report.latency_max
+                );
+            }
+            Err(e) => {
+                eprintln!("Self-test failed: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        Command::IperfServer(listener_spec) => {
+            let result = iperf::server(listener_spec, |source, report| {
+                println!("{source}: {}", format_stream_report(&report));
+            });
+            if let Err(e) = result {
+                eprintln!("Failed to receive: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::IperfClient {
+            target,
+            bandwidth,
+            duration,
+            len,
+        } => match iperf::client(target, bandwidth, duration, len) {
+            Ok(report) => {
+                println!(
+                    "Sent {} datagrams ({} bytes) in {:.2}s",
+                    report.packets,
+                    report.bytes,
+                    report.duration.as_secs_f64()
+                );
+                match report.server {
+                    Some(server) => println!("Server report: {}", format_stream_report(&server)),
+                    None => eprintln!("No report received from {target}"),
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to send: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        Command::Echo(listener_spec) => {
+            if let Err(e) = tools::echo(listener_spec) {
+                eprintln!("Failed to echo: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::ProbeMcast {
+            listener_spec,
+            timeout,
+        } => match tools::probe(listener_spec, timeout) {
+            Ok(Some((source, num_bytes))) => {
+                println!("Received {num_bytes} bytes from {source}");
+            }
+            Ok(None) => {
+                eprintln!("No packet received within {}s", timeout.as_secs_f64());
+                return ExitCode::FAILURE;
+            }
+            Err(e) => {
+                eprintln!("Failed to receive: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        Command::Rendezvous {
+            listener_spec,
+            expiry,
+        } => {
+            let result = rendezvous::serve(listener_spec, expiry, |session, first, second| {
+                println!("Introduced {first} and {second} in session {session}");
+            });
+            if let Err(e) = result {
+                eprintln!("Failed to serve rendezvous: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::SapRelay(relay) => {
+            if let Err(e) = sap::relay(&relay, |event| println!("{event}")) {
+                eprintln!("Failed to relay SAP sessions: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        #[cfg(feature = "control")]
+        Command::Diag(control_addr) => match control::fetch_diag(control_addr) {
+            Ok(diag) => print!("{diag}"),
+            Err(e) => {
+                eprintln!("Failed to fetch diagnostics from {control_addr}: {e}");
+                return ExitCode::FAILURE;
+            }
+        },
+        #[cfg(not(feature = "control"))]
+        Command::Diag(_) => {
+            eprintln!("Cannot fetch diagnostics, built without the control feature");
+            return ExitCode::FAILURE;
+        }
+        Command::GenerateManpage => {
+            print!("{}", render_manpage());
+        }
     }
+
+    ExitCode::SUCCESS
 }
 
-const HELP: &str = r#"UDP forwarder
+/// List the most recent packets before `trigger`, one per line
+fn format_packet_history(trigger: DumpTrigger, summaries: &[PacketSummary]) -> String {
+    let mut history = format!("Last {} packets before {trigger}:", summaries.len());
+    for summary in summaries {
+        history.push_str(&format!("\n  {summary}"));
+    }
+    history
+}
 
-usage: udpforwarder [listener_spec] [target_addr] [...target_addr]
+/// Summarize what arrived of an iperf stream
+fn format_stream_report(report: &iperf::StreamReport) -> String {
+    format!(
+        "{} bytes in {:.2}s, {:.2} Mbit/s, jitter {:.3} ms, lost {}/{} ({:.2}%), {} out of order",
+        report.bytes,
+        report.duration.as_secs_f64(),
+        report.bandwidth() / 1e6,
+        report.jitter.as_secs_f64() * 1e3,
+        report.lost,
+        report.packets + report.lost,
+        report.loss_percent(),
+        report.out_of_order
+    )
+}
 
-examples:
+/// Bind the listener, or receive its packets as given by `--dpdk`, `--amt`, `--kcp`, `--capture`
+/// or `--xdp`, and the senders
+fn open_forwarder(args: &Args, log: &Log) -> Result<Forwarder, io::Error> {
+    let options = &args.options;
+    let source = open_source(
+        &args.listener_spec,
+        args.listener_vrf.as_deref(),
+        options,
+        log,
+    )?;
+    let forward_addrs = args.forward_addrs.clone();
+    let mut forwarder = match &options.target_netns {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            Forwarder::from_source_and_network(source, forward_addrs, netns::Netns::new(name))?
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "network namespaces are only available on Linux",
+            ));
+        }
+        None => Forwarder::from_source(source, forward_addrs)?,
+    };
+    for (addr, vrf) in &args.target_vrfs {
+        forwarder = forwarder.with_target_vrf(*addr, vrf.clone())?;
+    }
+    for (addr, snaplen) in &args.mirrors {
+        forwarder = forwarder.with_mirror(*addr, *snaplen);
+    }
+    if let Some(mark) = options.fwmark {
+        forwarder = forwarder.with_fwmark(mark)?;
+    }
+    if let Some(capacity) = options.target_queue {
+        forwarder = forwarder.with_target_queues(capacity);
+    }
+    if options.reply_path {
+        forwarder = forwarder.with_reply_path(
+            options
+                .session_timeout
+                .unwrap_or(session::DEFAULT_SESSION_TIMEOUT),
+        )?;
+    }
+    if !options.no_udp_checksum {
+        return Ok(forwarder);
+    }
+    log.warn(
+        "Sending to IPv4 targets without UDP checksums, receivers cannot detect corrupted packets"
+            .to_owned(),
+    );
+    forwarder.without_udp_checksums()
+}
 
-  Forward incoming IPV4 unicast stream to IPv4 localhost
+/// Source of the packets to forward, the listener socket unless receiving otherwise
+fn open_source(
+    listener_spec: &ListenerSpec,
+    listener_vrf: Option<&str>,
+    options: &Options,
+    log: &Log,
+) -> Result<Box<dyn Source>, io::Error> {
+    if let Some(name) = &options.listener_netns {
+        let options = Options {
+            listener_netns: None,
+            ..options.clone()
+        };
+        #[cfg(target_os = "linux")]
+        return netns::Netns::new(name)
+            .enter(|| open_source(listener_spec, listener_vrf, &options, log));
+        #[cfg(not(target_os = "linux"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "cannot open the listener in network namespace {name}, only available on Linux"
+            ),
+        ));
+    }
+    let receives_otherwise = options.dpdk.is_some()
+        || options.amt.is_some()
+        || options.capture.is_some()
+        || options.xdp.is_some();
+    if listener_vrf.is_some() && receives_otherwise {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a listener VRF only applies to the listener socket, not to --dpdk, --amt, \
+             --capture or --xdp",
+        ));
+    }
+    if let Some(port) = &options.dpdk {
+        #[cfg(all(feature = "dpdk", target_os = "linux"))]
+        {
+            let source = dpdk::DpdkSource::open(listener_spec, port, &options.dpdk_eal_args)?;
+            log.info(format!("Receiving {} from DPDK port {port}", source.flow()));
+            return Ok(Box::new(source));
+        }
+        #[cfg(not(all(feature = "dpdk", target_os = "linux")))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("cannot receive from DPDK port {port}, built without the dpdk feature"),
+        ));
+    }
+    if let Some(relay) = &options.amt {
+        if options.capture.is_some() || options.xdp.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--amt excludes --capture and --xdp",
+            ));
+        }
+        let source = amt::AmtSource::open(listener_spec, relay)?;
+        log.info(format!(
+            "Receiving {} through AMT relay {}",
+            source.group(),
+            source.relay()
+        ));
+        return Ok(Box::new(source));
+    }
+    if options.capture.is_none() && options.xdp.is_none() {
+        let mut builder = ListenerBuilder::new(listener_spec);
+        if let Some(vrf) = listener_vrf {
+            builder = builder.device(vrf);
+        }
+        if let Some(size) = options.recv_buffer {
+            builder = builder.recv_buffer_size(size);
+        }
+        if let Some(size) = options.send_buffer {
+            builder = builder.send_buffer_size(size);
+        }
+        let listener = builder.bind()?;
+        if options.kcp {
+            return Ok(Box::new(KcpSource::new(listener)));
+        }
+        let Some(timestamps) = &options.rx_timestamps else {
+            return Ok(Box::new(listener));
+        };
+        #[cfg(target_os = "linux")]
+        return Ok(Box::new(timestamp::TimestampingSocket::new(
+            listener, timestamps,
+        )?));
+        #[cfg(not(target_os = "linux"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{timestamps} receive timestamps are only supported on Linux"),
+        ));
+    }
+    if options.rx_timestamps.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--rx-timestamps only applies to the listener socket, not to --capture or --xdp",
+        ));
+    }
+    if options.kcp {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--kcp only applies to the listener socket, not to --capture or --xdp",
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    match (&options.capture, &options.xdp) {
+        (Some(interface), None) => {
+            let Some(mut flow) = capture::Flow::to_listener(listener_spec) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot capture for a socket passed by systemd",
+                ));
+            };
+            if let Some(source) = options.capture_source {
+                flow = flow.from_source(source);
+            }
+            let source = capture::CaptureSource::open(interface, flow)?;
+            log.info(format!("Capturing {flow} on {interface}"));
+            Ok(Box::new(source))
+        }
+        (None, Some(interface)) => {
+            let queue = options.xdp_queue.unwrap_or_default();
+            let source = xdp::XdpSource::open(listener_spec.clone(), interface, queue)?;
+            log.info(format!(
+                "Steering {} from queue {queue} of {interface} with XDP",
+                source.flow()
+            ));
+            Ok(Box::new(source))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--capture and --xdp exclude each other",
+        )),
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = listener_spec;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "capturing and XDP are only supported on Linux",
+        ))
+    }
+}
+
+/// Command and webhook to notify of significant events, each optional
+#[derive(Clone)]
+struct Notifier {
+    on_event: Option<ExecHook>,
+    webhook: Option<Webhook>,
+    log: Log,
+}
+
+impl Notifier {
+    fn is_enabled(&self) -> bool {
+        self.on_event.is_some() || self.webhook.is_some()
+    }
+
+    /// Run the command and post `event` to the webhook in the background
+    fn notify(&self, event: Event) {
+        self.run(&event);
+        if let Some(webhook) = self.webhook.clone() {
+            let log = self.log.clone();
+            let posted = thread::Builder::new()
+                .name("webhook".to_owned())
+                .spawn(move || post(&webhook, &event, &log));
+            if let Err(e) = posted {
+                self.log
+                    .warn(format!("Failed to start webhook thread: {e}"));
+            }
+        }
+    }
 
-    udpforwarder 10.1.1.10:4000 127.0.0.1:4001
+    /// Run the command and post `event` to the webhook, waiting for the post
+    fn deliver(&self, event: &Event) {
+        self.run(event);
+        if let Some(webhook) = &self.webhook {
+            post(webhook, event, &self.log);
+        }
+    }
 
-  Forward incoming IPv4 unicast stream to IPv4 and IPv6 localhost
+    fn run(&self, event: &Event) {
+        if let Some(on_event) = &self.on_event
+            && let Err(e) = on_event.run(event)
+        {
+            self.log
+                .warn(format!("Failed to run --on-event command for {event}: {e}"));
+        }
+    }
+}
 
-    udpforwarder 10.1.1.10:4000 127.0.0.1:4001 [::1]:4002
+fn post(webhook: &Webhook, event: &Event, log: &Log) {
+    if let Err(e) = webhook.send(event) {
+        log.warn(format!(
+            "Failed to post {event} to webhook {}: {e}",
+            webhook.host
+        ));
+    }
+}
 
-  Subscribe to IPv4 multicast group on any interface and forward to remote address
+/// Log the targets added and removed by a reload, and the changes it could not apply
+fn log_reload(log: &Log, reload: &Reload) {
+    for addr in &reload.added {
+        log.info(format!("Reloaded config, forwarding to {addr}"));
+    }
+    for addr in &reload.removed {
+        log.info(format!("Reloaded config, no longer forwarding to {addr}"));
+    }
+    if !reload.needs_restart.is_empty() {
+        log.warn(format!(
+            "Changes of {} take effect after a restart",
+            reload.needs_restart.join(", ")
+        ));
+    }
+}
 
-    udpforwarder 224.10.10.10:4000 10.1.1.11:4000
+/// Messages of the forwarder up to a level, limited in their repetitions
+#[derive(Clone)]
+struct Log {
+    level: LogLevel,
+    limiter: Arc<Mutex<RateLimiter>>,
+    /// Labels of the listener starting each line
+    prefix: String,
+}
 
-  Subscribe to IPv4 multicast group specifying the local address of the interface to use
-  and forward to local port
+impl Log {
+    fn new(level: LogLevel, labels: &[(String, String)]) -> Self {
+        Self {
+            level,
+            limiter: Arc::new(Mutex::new(RateLimiter::default())),
+            prefix: label_prefix(labels),
+        }
+    }
 
-    udpforwarder 224.10.10.10:4000 127.0.0.1:4001
+    fn warn(&self, message: String) {
+        self.log(LogLevel::Warn, message);
+    }
 
-  Subscribe to IPv6 multicast group specifying the ID of the interface to use
-  and forward to local port
+    fn info(&self, message: String) {
+        self.log(LogLevel::Info, message);
+    }
 
-    udpforwarder [ff05::1]:4000 [::1]:4001
+    /// Log warnings to stderr and everything else to stdout, unless filtered
+    fn log(&self, level: LogLevel, message: String) {
+        if level > self.level {
+            return;
+        }
+        let verdict = self
+            .limiter
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .check(&message, Instant::now());
+        let message = match verdict {
+            Verdict::Suppress => return,
+            Verdict::Log { suppressed: 0 } => message,
+            Verdict::Log { suppressed } => {
+                format!("{message} ({suppressed} repetitions suppressed)")
+            }
+        };
+        match level {
+            LogLevel::Error | LogLevel::Warn => eprintln!("{}{message}", self.prefix),
+            LogLevel::Info => println!("{}{message}", self.prefix),
+        }
+    }
+}
 
-"#;
+/// Print guidance if binding the listener failed due to missing privileges
+fn print_bind_error_hint(port: Option<u16>, e: &io::Error) {
+    if let Some(hint) = port.and_then(|port| diagnostics::bind_error_hint(port, e)) {
+        eprintln!("{hint}");
+    }
+}
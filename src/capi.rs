@@ -0,0 +1,267 @@
+//! C API
+//!
+//! Lets C and C++ applications embed the forwarder instead of running the binary.
+//! The forwarder runs on a thread of its own between [udpforwarder_start] and
+//! [udpforwarder_stop]. The declarations are in `include/udpforwarder.h`.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, c_char, c_int},
+    net::SocketAddr,
+    ptr,
+    sync::{Arc, atomic::Ordering},
+};
+
+use crate::{Forwarder, ListenerSpec, forwarding::Background, state::SharedState};
+
+/// Forwarder running on its own thread, opaque to C
+pub struct Handle {
+    state: Arc<SharedState>,
+    background: Background,
+}
+
+/// Counters of a running forwarder
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub received_packets: u64,
+    pub received_bytes: u64,
+    /// Packets and bytes sent to the targets, summed over all targets
+    pub sent_packets: u64,
+    pub sent_bytes: u64,
+    pub send_errors: u64,
+    pub looped_packets: u64,
+    pub expired_packets: u64,
+    pub filtered_packets: u64,
+    pub relayed_packets: u64,
+}
+
+thread_local! {
+    /// Error of the last failed call on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Remember `error` as the error of the last failed call
+fn set_last_error(error: impl ToString) {
+    let error = CString::new(error.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(error));
+}
+
+/// Read a C string argument, `what` names it in errors
+///
+/// # Safety
+///
+/// `s` must be null or point to a null-terminated string.
+unsafe fn read_str<'a>(s: *const c_char, what: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{what} is null"));
+    }
+    // SAFETY: Not null and null-terminated as guaranteed by the caller
+    let s = unsafe { CStr::from_ptr(s) };
+    s.to_str().map_err(|_| format!("{what} is no valid UTF-8"))
+}
+
+/// Start forwarding from `listener` to the `num_targets` addresses in `targets`
+///
+/// Returns null if the arguments are invalid or the sockets cannot be set up,
+/// [udpforwarder_last_error] tells why.
+///
+/// # Safety
+///
+/// `listener` must point to a null-terminated string and `targets` to `num_targets`
+/// pointers to null-terminated strings. `targets` may be null if `num_targets` is 0.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn udpforwarder_start(
+    listener: *const c_char,
+    targets: *const *const c_char,
+    num_targets: usize,
+) -> *mut Handle {
+    // SAFETY: Guaranteed by the caller
+    let args = unsafe { read_args(listener, targets, num_targets) };
+    let (listener_spec, forward_addrs) = match args {
+        Ok(args) => args,
+        Err(e) => {
+            set_last_error(e);
+            return ptr::null_mut();
+        }
+    };
+
+    let forwarder = match Forwarder::new(listener_spec, forward_addrs) {
+        Ok(forwarder) => forwarder,
+        Err(e) => {
+            set_last_error(format!("failed to set up forwarder: {e}"));
+            return ptr::null_mut();
+        }
+    };
+    let state = forwarder.state();
+    match Background::spawn(forwarder) {
+        Ok(background) => Box::into_raw(Box::new(Handle { state, background })),
+        Err(e) => {
+            set_last_error(format!("failed to spawn forwarding thread: {e}"));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Parse the arguments of [udpforwarder_start]
+///
+/// # Safety
+///
+/// See [udpforwarder_start].
+unsafe fn read_args(
+    listener: *const c_char,
+    targets: *const *const c_char,
+    num_targets: usize,
+) -> Result<(ListenerSpec, Vec<SocketAddr>), String> {
+    // SAFETY: Guaranteed by the caller
+    let listener = unsafe { read_str(listener, "listener") }?;
+    let listener_spec: ListenerSpec = listener
+        .parse()
+        .map_err(|e| format!("invalid listener: {e}"))?;
+
+    if targets.is_null() && num_targets > 0 {
+        return Err("targets is null".to_owned());
+    }
+    let mut forward_addrs = Vec::with_capacity(num_targets);
+    for idx in 0..num_targets {
+        // SAFETY: `targets` holds `num_targets` valid pointers as guaranteed by the caller
+        let target = unsafe { read_str(*targets.add(idx), "target") }?;
+        let addr = target
+            .parse()
+            .map_err(|e| format!("invalid target {target}: {e}"))?;
+        forward_addrs.push(addr);
+    }
+
+    Ok((listener_spec, forward_addrs))
+}
+
+/// Stop forwarding and free the forwarder
+///
+/// Returns 0 if the forwarder ran without failing and -1 otherwise,
+/// [udpforwarder_last_error] tells why. Does nothing for null.
+///
+/// # Safety
+///
+/// `handle` must be null or returned by [udpforwarder_start] and not be stopped yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn udpforwarder_stop(handle: *mut Handle) -> c_int {
+    if handle.is_null() {
+        return 0;
+    }
+    // SAFETY: Returned by `udpforwarder_start` and not freed yet as guaranteed by the caller
+    let handle = unsafe { Box::from_raw(handle) };
+
+    match handle.background.stop() {
+        Ok(_) => 0,
+        Err(e) => {
+            set_last_error(format!("forwarding failed: {e}"));
+            -1
+        }
+    }
+}
+
+/// Write the counters of the forwarder to `stats`
+///
+/// Returns 0 on success and -1 if an argument is null.
+///
+/// # Safety
+///
+/// `handle` must be null or a running forwarder returned by [udpforwarder_start],
+/// `stats` must be null or valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn udpforwarder_stats(handle: *const Handle, stats: *mut Stats) -> c_int {
+    if handle.is_null() || stats.is_null() {
+        set_last_error("handle or stats is null");
+        return -1;
+    }
+    // SAFETY: Valid as guaranteed by the caller
+    let (handle, stats) = unsafe { (&*handle, &mut *stats) };
+
+    let state = &handle.state;
+    *stats = Stats {
+        received_packets: state.received().packets(),
+        received_bytes: state.received().bytes(),
+        looped_packets: state.looped().packets(),
+        expired_packets: state.expired().packets(),
+        filtered_packets: state.filtered().packets(),
+        relayed_packets: state.relayed().packets(),
+        ..Stats::default()
+    };
+    for target in state.targets() {
+        stats.sent_packets += target.sent.packets();
+        stats.sent_bytes += target.sent.bytes();
+        stats.send_errors += target.send_errors.load(Ordering::Relaxed);
+    }
+
+    0
+}
+
+/// Error of the last failed call on the calling thread, or null
+///
+/// The string is valid until the next failing call on the thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn udpforwarder_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| match &*last_error.borrow() {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::UdpSocket, thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn started_and_stopped() {
+        let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+        target
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let listener_addr = "127.0.0.1:4320";
+        let listener = CString::new(listener_addr).unwrap();
+        let target_addr = CString::new(target.local_addr().unwrap().to_string()).unwrap();
+        let targets = [target_addr.as_ptr()];
+
+        // SAFETY: Valid strings and array
+        let handle = unsafe { udpforwarder_start(listener.as_ptr(), targets.as_ptr(), 1) };
+        assert!(!handle.is_null());
+
+        target.send_to(b"hello", listener_addr).unwrap();
+        let mut buffer = [0; 8];
+        assert_eq!(5, target.recv(&mut buffer).unwrap());
+
+        // The forwarder counts after sending
+        let mut stats = Stats::default();
+        for _ in 0..100 {
+            // SAFETY: Running handle and valid stats
+            assert_eq!(0, unsafe { udpforwarder_stats(handle, &mut stats) });
+            if stats.sent_packets > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, stats.received_packets);
+        assert_eq!(1, stats.sent_packets);
+
+        // SAFETY: Running handle
+        assert_eq!(0, unsafe { udpforwarder_stop(handle) });
+    }
+
+    #[test]
+    fn invalid_listener_explained() {
+        let listener = CString::new("10.1.1.10").unwrap();
+
+        // SAFETY: Valid string without targets
+        let handle = unsafe { udpforwarder_start(listener.as_ptr(), ptr::null(), 0) };
+        assert!(handle.is_null());
+
+        // SAFETY: Null-terminated string of the failed call
+        let error = unsafe { CStr::from_ptr(udpforwarder_last_error()) };
+        assert_eq!(
+            "invalid listener: 10.1.1.10 is no valid socket address",
+            error.to_str().unwrap()
+        );
+    }
+}
@@ -0,0 +1,490 @@
+//! Listening through a packet socket
+//!
+//! A [CaptureSource] observes the UDP packets of a flow on a network interface with an
+//! `AF_PACKET` socket instead of binding the port. This lets the forwarder mirror traffic
+//! to analysis tools while another process owns the port and keeps consuming it. A BPF
+//! filter attached to the socket passes only the packets of the flow, so the kernel
+//! drops everything else before it reaches the forwarder. Only available on Linux and
+//! requires `CAP_NET_RAW`.
+
+#![cfg(target_os = "linux")]
+
+use std::{
+    ffi::{CString, c_int, c_uint, c_void},
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+use crate::{
+    ListenerSpec, sockopt,
+    source::{Source, copy_truncated, udp_payload},
+};
+
+const AF_PACKET: c_int = 17;
+const SOCK_DGRAM: c_int = 2;
+const SOCK_CLOEXEC: c_int = 0o2000000;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const SO_RCVTIMEO: c_int = 20;
+const SO_ATTACH_FILTER: c_int = 26;
+const MSG_DONTWAIT: c_int = 0x40;
+/// Packet type of packets sent by this host
+const PACKET_OUTGOING: u8 = 4;
+
+/// `struct sockaddr_ll`
+#[repr(C)]
+#[derive(Default)]
+struct SockaddrLl {
+    sll_family: u16,
+    sll_protocol: [u8; 2],
+    sll_ifindex: c_int,
+    sll_hatype: u16,
+    sll_pkttype: u8,
+    sll_halen: u8,
+    sll_addr: [u8; 8],
+}
+
+/// `struct timeval`
+#[repr(C)]
+struct Timeval {
+    tv_sec: i64,
+    tv_usec: i64,
+}
+
+/// `struct sock_filter`, an instruction of classic BPF
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// `struct sock_fprog`
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+unsafe extern "C" {
+    fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+    fn bind(fd: c_int, addr: *const c_void, len: u32) -> c_int;
+    fn recvfrom(
+        fd: c_int,
+        buffer: *mut c_void,
+        len: usize,
+        flags: c_int,
+        addr: *mut c_void,
+        addr_len: *mut u32,
+    ) -> isize;
+    fn if_nametoindex(name: *const std::ffi::c_char) -> c_uint;
+}
+
+/// UDP flow to capture, unspecified addresses and port `0` matching any
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flow {
+    /// Address the packets are sent to
+    pub destination: SocketAddr,
+    /// Address the packets are sent from
+    pub source: Option<SocketAddr>,
+}
+
+impl Flow {
+    /// Flow of the packets a listener with `listener_spec` would receive
+    ///
+    /// Returns `None` for sockets passed by systemd, whose address is unknown.
+    pub fn to_listener(listener_spec: &ListenerSpec) -> Option<Self> {
+        let destination = match listener_spec {
+            ListenerSpec::Unicast(addr) => *addr,
+            ListenerSpec::MulticastV4 {
+                multicast_group, ..
+            } => SocketAddr::V4(*multicast_group),
+            ListenerSpec::MulticastV6 {
+                multicast_group, ..
+            } => SocketAddr::V6(*multicast_group),
+            ListenerSpec::Systemd(_) => return None,
+        };
+
+        Some(Self {
+            destination,
+            source: None,
+        })
+    }
+
+    /// Restrict the flow to packets sent from `source`
+    pub fn from_source(mut self, source: SocketAddr) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Whether a packet from `source` to `destination` belongs to the flow
+    pub fn matches(&self, source: SocketAddr, destination: SocketAddr) -> bool {
+        let matches = |expected: SocketAddr, actual: SocketAddr| {
+            (expected.ip().is_unspecified() || expected.ip() == actual.ip())
+                && (expected.port() == 0 || expected.port() == actual.port())
+        };
+        self.destination.is_ipv4() == destination.is_ipv4()
+            && matches(self.destination, destination)
+            && self.source.is_none_or(|expected| matches(expected, source))
+    }
+
+    /// BPF program passing the UDP packets of the flow, starting at the IP header
+    ///
+    /// Fragments are rejected as only the first one carries the UDP header.
+    fn filter(&self) -> Vec<SockFilter> {
+        let mut filter = Filter::default();
+        let source = self.source.unwrap_or(match self.destination {
+            SocketAddr::V4(_) => SocketAddr::from(([0; 4], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        });
+
+        match (self.destination.ip(), source.ip()) {
+            (IpAddr::V4(destination_ip), IpAddr::V4(source_ip)) => {
+                filter.push(LDB_ABS, 0);
+                filter.push(AND, 0xf0);
+                filter.expect(0x40);
+                filter.push(LDB_ABS, 9);
+                filter.expect(UDP);
+                filter.push(LDH_ABS, 6);
+                filter.reject_any(0x1fff);
+                if !destination_ip.is_unspecified() {
+                    filter.push(LD_ABS, 16);
+                    filter.expect(destination_ip.to_bits());
+                }
+                if !source_ip.is_unspecified() {
+                    filter.push(LD_ABS, 12);
+                    filter.expect(source_ip.to_bits());
+                }
+                // Ports follow the header of variable length
+                filter.push(LDXB_MSH, 0);
+                filter.expect_port(LDH_IND, 2, self.destination.port());
+                filter.expect_port(LDH_IND, 0, source.port());
+            }
+            (destination_ip, source_ip) => {
+                filter.push(LDB_ABS, 0);
+                filter.push(AND, 0xf0);
+                filter.expect(0x60);
+                filter.push(LDB_ABS, 6);
+                filter.expect(UDP);
+                for (ip, offset) in [(destination_ip, 24), (source_ip, 8)] {
+                    if let IpAddr::V6(ip) = ip
+                        && !ip.is_unspecified()
+                    {
+                        for (idx, word) in ip.octets().as_chunks::<4>().0.iter().enumerate() {
+                            filter.push(LD_ABS, offset + 4 * idx as u32);
+                            filter.expect(u32::from_be_bytes(*word));
+                        }
+                    }
+                }
+                filter.expect_port(LDH_ABS, 42, self.destination.port());
+                filter.expect_port(LDH_ABS, 40, source.port());
+            }
+        }
+
+        filter.finish()
+    }
+}
+
+impl fmt::Display for Flow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.source {
+            Some(source) => write!(f, "{source} -> {}", self.destination),
+            None => write!(f, "* -> {}", self.destination),
+        }
+    }
+}
+
+const LD_ABS: u16 = 0x20;
+const LDH_ABS: u16 = 0x28;
+const LDB_ABS: u16 = 0x30;
+const LDH_IND: u16 = 0x48;
+const LDXB_MSH: u16 = 0xb1;
+const AND: u16 = 0x54;
+const JEQ: u16 = 0x15;
+const JSET: u16 = 0x45;
+const RET: u16 = 0x06;
+const UDP: u32 = 17;
+/// Number of bytes of a passing packet to capture, i.e. all of it
+const CAPTURE_LEN: u32 = 0x40000;
+
+/// Builder of a BPF program whose checks all jump to a final rejection
+#[derive(Default)]
+struct Filter {
+    instructions: Vec<SockFilter>,
+    /// Indices of the jumps to the rejection, to be resolved once its index is known
+    rejections: Vec<usize>,
+}
+
+impl Filter {
+    fn push(&mut self, code: u16, k: u32) {
+        self.instructions.push(SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        });
+    }
+
+    /// Continue if the accumulator equals `k`, reject otherwise
+    fn expect(&mut self, k: u32) {
+        self.rejections.push(self.instructions.len());
+        self.push(JEQ, k);
+    }
+
+    /// Load the port at `offset` with `code` and expect it to be `port`, unless `0`
+    fn expect_port(&mut self, code: u16, offset: u32, port: u16) {
+        if port != 0 {
+            self.push(code, offset);
+            self.expect(port.into());
+        }
+    }
+
+    /// Reject if any of the bits of `k` are set in the accumulator
+    fn reject_any(&mut self, k: u32) {
+        self.rejections.push(self.instructions.len());
+        self.push(JSET, k);
+    }
+
+    fn finish(mut self) -> Vec<SockFilter> {
+        self.push(RET, CAPTURE_LEN);
+        let reject = self.instructions.len();
+        self.push(RET, 0);
+
+        for idx in self.rejections {
+            let distance = (reject - idx - 1) as u8;
+            let instruction = &mut self.instructions[idx];
+            match instruction.code {
+                JSET => instruction.jt = distance,
+                _ => instruction.jf = distance,
+            }
+        }
+        self.instructions
+    }
+}
+
+/// Index of the network interface called `interface`
+pub(crate) fn interface_index(interface: &str) -> Result<c_int, io::Error> {
+    let name = CString::new(interface)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface"))?;
+    // SAFETY: `name` is a valid C string
+    match unsafe { if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        ifindex => Ok(ifindex as c_int),
+    }
+}
+
+/// Source receiving the packets of a UDP flow on an interface through a packet socket
+#[derive(Debug)]
+pub struct CaptureSource {
+    fd: OwnedFd,
+    flow: Flow,
+    nonblocking: bool,
+    /// Buffer for the IP packets
+    packet: Vec<u8>,
+}
+
+impl CaptureSource {
+    /// Capture the packets of `flow` arriving on `interface`, `any` for all interfaces
+    pub fn open(interface: &str, flow: Flow) -> Result<Self, io::Error> {
+        if let Some(source) = flow.source
+            && source.is_ipv4() != flow.destination.is_ipv4()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source and destination of different IP versions",
+            ));
+        }
+        let ifindex = match interface {
+            "any" => 0,
+            interface => interface_index(interface)?,
+        };
+
+        // Receive nothing until the filter is attached
+        // SAFETY: Plain syscall without memory arguments
+        let fd = unsafe { socket(AF_PACKET, SOCK_DGRAM | SOCK_CLOEXEC, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a new socket owned by nothing else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let filter = flow.filter();
+        let program = SockFprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr(),
+        };
+        sockopt::set_struct(&fd, sockopt::SOL_SOCKET, SO_ATTACH_FILTER, &program)?;
+
+        let protocol = match flow.destination {
+            SocketAddr::V4(_) => ETH_P_IP,
+            SocketAddr::V6(_) => ETH_P_IPV6,
+        };
+        let addr = SockaddrLl {
+            sll_family: AF_PACKET as u16,
+            sll_protocol: protocol.to_be_bytes(),
+            sll_ifindex: ifindex,
+            ..SockaddrLl::default()
+        };
+        // SAFETY: The address is valid for the given length
+        let result = unsafe {
+            bind(
+                fd.as_raw_fd(),
+                &addr as *const SockaddrLl as *const c_void,
+                size_of::<SockaddrLl>() as u32,
+            )
+        };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            fd,
+            flow,
+            nonblocking: false,
+            packet: vec![0; CAPTURE_LEN as usize],
+        })
+    }
+
+    /// Flow the source captures
+    pub fn flow(&self) -> &Flow {
+        &self.flow
+    }
+}
+
+impl Source for CaptureSource {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        let flags = match self.nonblocking {
+            true => MSG_DONTWAIT,
+            false => 0,
+        };
+        loop {
+            let mut addr = SockaddrLl::default();
+            let mut addr_len = size_of::<SockaddrLl>() as u32;
+            // SAFETY: Buffer and address are valid for their lengths
+            let len = unsafe {
+                recvfrom(
+                    self.fd.as_raw_fd(),
+                    self.packet.as_mut_ptr() as *mut c_void,
+                    self.packet.len(),
+                    flags,
+                    &mut addr as *mut SockaddrLl as *mut c_void,
+                    &mut addr_len,
+                )
+            };
+            let Ok(len) = usize::try_from(len) else {
+                return Err(io::Error::last_os_error());
+            };
+
+            // Packets sent by this host to the flow, e.g. when forwarding to it
+            if addr.sll_pkttype == PACKET_OUTGOING {
+                continue;
+            }
+            if let Some((source, payload)) = self.packet.get(..len).and_then(udp_payload) {
+                return Ok(Some((copy_truncated(payload, buffer), source)));
+            }
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        let timeout = timeout.unwrap_or_default();
+        let timeval = Timeval {
+            tv_sec: timeout.as_secs() as i64,
+            tv_usec: timeout.subsec_micros() as i64,
+        };
+        sockopt::set_struct(&self.fd, sockopt::SOL_SOCKET, SO_RCVTIMEO, &timeval)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run `filter` on `packet` like the kernel, returning the number of bytes to pass
+    fn run(filter: &[SockFilter], packet: &[u8]) -> u32 {
+        let (mut a, mut x, mut pc) = (0u32, 0u32, 0);
+        let load = |offset: u32, len: usize| {
+            let bytes = packet.get(offset as usize..offset as usize + len)?;
+            Some(bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as u32))
+        };
+        loop {
+            let SockFilter { code, jt, jf, k } = filter[pc];
+            pc += 1;
+            match code {
+                LD_ABS => a = load(k, 4).unwrap_or_default(),
+                LDH_ABS => a = load(k, 2).unwrap_or_default(),
+                LDB_ABS => a = load(k, 1).unwrap_or_default(),
+                LDH_IND => a = load(x + k, 2).unwrap_or_default(),
+                LDXB_MSH => x = (load(k, 1).unwrap_or_default() & 0xf) * 4,
+                AND => a &= k,
+                JEQ => pc += if a == k { jt } else { jf } as usize,
+                JSET => pc += if a & k != 0 { jt } else { jf } as usize,
+                RET => return k,
+                _ => panic!("unexpected instruction {code:#x}"),
+            }
+        }
+    }
+
+    /// IPv4 packet with a UDP header from `source` to `destination`
+    fn ipv4_packet(source: &str, destination: &str) -> Vec<u8> {
+        let (source, destination): (SocketAddr, SocketAddr) =
+            (source.parse().unwrap(), destination.parse().unwrap());
+        let (IpAddr::V4(src), IpAddr::V4(dst)) = (source.ip(), destination.ip()) else {
+            panic!("IPv4 addresses expected");
+        };
+        let mut packet = vec![0x45, 0, 0, 33, 0, 0, 0x40, 0, 64, 17, 0, 0];
+        packet.extend_from_slice(&src.octets());
+        packet.extend_from_slice(&dst.octets());
+        packet.extend_from_slice(&source.port().to_be_bytes());
+        packet.extend_from_slice(&destination.port().to_be_bytes());
+        packet.extend_from_slice(&[0, 13, 0, 0]);
+        packet.extend_from_slice(b"hello");
+        packet
+    }
+
+    #[test]
+    fn filter_matches_flow() {
+        let flow = Flow::to_listener(&"10.1.1.10:4000".parse().unwrap())
+            .unwrap()
+            .from_source("10.1.1.20:5000".parse().unwrap());
+        let filter = flow.filter();
+
+        let packet = ipv4_packet("10.1.1.20:5000", "10.1.1.10:4000");
+        assert_eq!(CAPTURE_LEN, run(&filter, &packet));
+        let (source, payload) = udp_payload(&packet).unwrap();
+        assert_eq!("10.1.1.20:5000".parse::<SocketAddr>().unwrap(), source);
+        assert_eq!(b"hello", payload);
+
+        for (source, destination) in [
+            ("10.1.1.20:5000", "10.1.1.10:4001"),
+            ("10.1.1.21:5000", "10.1.1.10:4000"),
+            ("10.1.1.20:5001", "10.1.1.10:4000"),
+        ] {
+            assert_eq!(0, run(&filter, &ipv4_packet(source, destination)));
+        }
+
+        // Fragments are rejected
+        let mut fragment = ipv4_packet("10.1.1.20:5000", "10.1.1.10:4000");
+        fragment[6..8].copy_from_slice(&[0x20, 0x10]);
+        assert_eq!(0, run(&filter, &fragment));
+    }
+
+    #[test]
+    fn filter_matches_any_source() {
+        let flow = Flow::to_listener(&"0.0.0.0:4000".parse().unwrap()).unwrap();
+        let filter = flow.filter();
+
+        let packet = ipv4_packet("10.1.1.20:5000", "10.1.1.10:4000");
+        assert_eq!(CAPTURE_LEN, run(&filter, &packet));
+        let packet = ipv4_packet("10.1.1.20:5000", "10.1.1.10:4001");
+        assert_eq!(0, run(&filter, &packet));
+    }
+}
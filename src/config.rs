@@ -0,0 +1,1560 @@
+//! Config files
+//!
+//! Instead of on the command line, the listener, targets and options can be given in
+//! a file in a subset of TOML, without dates and multi-line strings, or in JSON or YAML
+//! for files ending in `.json`, `.yaml` or `.yml`, as generated by deployment tooling.
+//! Keys are the long options without the leading dashes, e.g.
+//!
+//! ```toml
+//! listener = "0.0.0.0:4000"
+//! targets = ["10.1.1.10:5000", "@targets.txt"]
+//! stats-interval = "1s"
+//! alert-min-pps = 100
+//! hub = true
+//! ```
+//!
+//! Related keys can be grouped in sections instead, with keys named after the section,
+//! and targets with options of their own as well as further listeners, see
+//! [crate::Rule], get named tables:
+//!
+//! ```toml
+//! [listener]
+//! addr = "0.0.0.0:4000"
+//!
+//! [buffers]
+//! recv = "4M"
+//! target-queue = 1024
+//!
+//! [logging]
+//! level = "warn"
+//! file = "/var/log/udpforwarder.log"
+//!
+//! [target.recorder]
+//! addr = "10.1.1.10:5000"
+//! vrf = "blue"
+//!
+//! [target.monitor]
+//! addr = "10.1.1.11:5000"
+//! snap = 128
+//!
+//! [rule.audio]
+//! listener = "0.0.0.0:4002"
+//! targets = ["10.1.1.10:5002"]
+//! ```
+//!
+//! Large deployments can be composed from fragments with `include = ["targets.d/*.toml"]`,
+//! relative to the including file, which add to the targets and lists of the including
+//! file. `${NAME}` in strings is replaced by the environment variable `NAME`, or by the
+//! default of `${NAME:-default}` if it is unset, to template a file per environment.
+//!
+//! The files are checked against these keys as a whole before anything is applied, so
+//! a typo is reported with its file and line instead of silently falling back to the
+//! default.
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::args::{OPTIONS, Rule};
+
+/// Keys which must not be set together, with the reason
+const CONFLICTS: &[(&str, &str, &str)] = &[
+    ("capture", "xdp", "receiving excludes each other"),
+    ("dpdk", "capture", "receiving excludes each other"),
+    ("dpdk", "xdp", "receiving excludes each other"),
+    (
+        "rx-timestamps",
+        "capture",
+        "only the listener socket has timestamps",
+    ),
+    (
+        "rx-timestamps",
+        "xdp",
+        "only the listener socket has timestamps",
+    ),
+    (
+        "syslog",
+        "syslog-rewrite-hostname",
+        "both set the syslog mode",
+    ),
+    ("stamp-delay", "measure-delay", "both set the delay role"),
+    ("quiet", "log-level", "both set the log level"),
+    ("quiet", "verbose", "quiet hides the verbose messages"),
+    (
+        "hop-limit",
+        "strip-hop-limit",
+        "both set the hop-limit mode",
+    ),
+    ("on-event", "seccomp", "seccomp forbids running commands"),
+    ("on-event", "landlock", "landlock forbids running commands"),
+    ("webhook", "seccomp", "seccomp forbids connecting"),
+    (
+        "retry-on-error",
+        "seccomp",
+        "seccomp forbids binding sockets",
+    ),
+    (
+        "retry-backoff",
+        "seccomp",
+        "seccomp forbids binding sockets",
+    ),
+];
+
+/// Keys of the sections, with the key each stands for
+const SECTION_KEYS: &[(&str, &str)] = &[
+    ("listener.addr", "listener"),
+    ("listener.netns", "listener-netns"),
+    ("buffers.recv", "recv-buffer"),
+    ("buffers.send", "send-buffer"),
+    ("buffers.target-queue", "target-queue"),
+    ("logging.level", "log-level"),
+    ("logging.file", "log-file"),
+    ("logging.quiet", "quiet"),
+    ("logging.verbose", "verbose"),
+    ("logging.syslog", "syslog"),
+    ("logging.syslog-rewrite-hostname", "syslog-rewrite-hostname"),
+    ("logging.gelf", "gelf"),
+    ("logging.gelf-chunk-size", "gelf-chunk-size"),
+];
+/// Keys of the named tables of targets and rules
+const TARGET_KEYS: &[&str] = &["addr", "vrf", "snap"];
+const RULE_KEYS: &[&str] = &["listener", "targets"];
+/// Keys of options which can be given multiple times, taking an array
+const REPEATED: &[&str] = &["stun", "geoip", "label"];
+/// Depth of includes within includes at most, to stop include cycles
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// Listener, targets and options read from a config file
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    pub(crate) listener: Option<String>,
+    pub(crate) targets: Vec<String>,
+    /// Further listeners with their targets
+    pub(crate) rules: Vec<Rule>,
+    /// Options in the order of the files
+    pub(crate) options: Vec<ConfigOption>,
+}
+
+/// Option set in a config file, as on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ConfigOption {
+    /// Long option including the leading dashes
+    pub(crate) name: String,
+    /// Value of the option, unless it is a flag
+    pub(crate) value: Option<String>,
+    /// File the option is set in
+    pub(crate) path: PathBuf,
+    pub(crate) line: usize,
+}
+
+impl ConfigOption {
+    /// Error at the location of the option
+    pub(crate) fn error(&self, message: String) -> ConfigError {
+        ConfigError {
+            path: self.path.clone(),
+            line: Some(self.line),
+            message,
+        }
+    }
+}
+
+impl Config {
+    /// Read and check the config file at `path` and the files it includes
+    ///
+    /// `${NAME}` in strings is replaced by the environment variable `NAME`, or by
+    /// `default` for `${NAME:-default}` if it is unset.
+    pub(crate) fn load(path: &Path) -> Result<Self, ConfigError> {
+        let mut entries = Vec::new();
+        read_entries(path, &|name| std::env::var(name).ok(), 0, &mut entries)?;
+        Self::from_entries(&entries)
+    }
+
+    /// Check `entries` against the keys, reporting the first error with its location
+    fn from_entries(entries: &[Entry]) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        let mut seen: HashMap<&str, &Entry> = HashMap::new();
+        // Named tables of targets and rules in the order of their first key
+        let mut tables: Vec<NamedTable> = Vec::new();
+        for entry in entries {
+            if let Some((section, name, key)) = split_named_table(&entry.key) {
+                let table = match tables
+                    .iter_mut()
+                    .find(|table| table.section == section && table.name == name)
+                {
+                    Some(table) => table,
+                    None => {
+                        tables.push(NamedTable {
+                            section,
+                            name,
+                            entries: Vec::new(),
+                        });
+                        tables.last_mut().expect("just pushed")
+                    }
+                };
+                if let Some((_, other)) = table.entries.iter().find(|(other, _)| *other == key) {
+                    return Err(entry.error(format!(
+                        "`{}` already set {}",
+                        entry.key,
+                        entry.location_of(other)
+                    )));
+                }
+                table.entries.push((key, entry));
+                continue;
+            }
+
+            let key = canonical(&entry.key);
+            let Some(kind) = kind(key) else {
+                let message = match suggestion(&entry.key) {
+                    Some(key) => format!("unknown key `{}`, did you mean `{key}`?", entry.key),
+                    None => format!("unknown key `{}`", entry.key),
+                };
+                return Err(entry.error(message));
+            };
+            // Fragments can add to lists, but not set the same option twice
+            if let Some(other) = seen.insert(key, entry)
+                && (other.path == entry.path || kind != Kind::Values)
+            {
+                return Err(entry.error(format!(
+                    "`{}` already set {}",
+                    entry.key,
+                    entry.location_of(other)
+                )));
+            }
+
+            let option = |value| ConfigOption {
+                name: format!("--{key}"),
+                value,
+                path: entry.path.clone(),
+                line: entry.line,
+            };
+            match (kind, key) {
+                (Kind::Flag, _) => match entry.value {
+                    Value::Boolean(true) => config.options.push(option(None)),
+                    Value::Boolean(false) => {}
+                    _ => return Err(entry.mismatch("true or false")),
+                },
+                (Kind::Value, "listener") => {
+                    let value = entry
+                        .value
+                        .scalar()
+                        .ok_or_else(|| entry.mismatch("a string"))?;
+                    config.listener = Some(value);
+                }
+                (Kind::Value, _) => {
+                    let value = entry
+                        .value
+                        .scalar()
+                        .ok_or_else(|| entry.mismatch("a string or number"))?;
+                    config.options.push(option(Some(value)));
+                }
+                (Kind::Values, key) => {
+                    let values = entry
+                        .value
+                        .scalars()
+                        .ok_or_else(|| entry.mismatch("a string or an array of strings"))?;
+                    match key {
+                        "targets" => config.targets.extend(values),
+                        _ => config
+                            .options
+                            .extend(values.into_iter().map(|value| option(Some(value)))),
+                    }
+                }
+            }
+        }
+
+        for table in &tables {
+            match table.section {
+                "target" => config.targets.push(table.target()?),
+                _ => config.rules.push(table.rule()?),
+            }
+        }
+
+        for (first, second, reason) in CONFLICTS {
+            let set = |key: &str| {
+                entries.iter().position(|entry| {
+                    canonical(&entry.key) == key && entry.value != Value::Boolean(false)
+                })
+            };
+            if let (Some(first), Some(second)) = (set(first), set(second)) {
+                let (entry, other) = (&entries[first.max(second)], &entries[first.min(second)]);
+                return Err(entry.error(format!(
+                    "`{}` conflicts with `{}` {}, {reason}",
+                    entry.key,
+                    other.key,
+                    entry.location_of(other)
+                )));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Named table of a target or rule like `[target.recorder]`
+struct NamedTable<'a> {
+    /// `target` or `rule`
+    section: &'a str,
+    name: &'a str,
+    /// Entries by their key within the table
+    entries: Vec<(&'a str, &'a Entry)>,
+}
+
+impl NamedTable<'_> {
+    /// Entry of `key`, if set
+    fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find_map(|(other, entry)| (*other == key).then_some(*entry))
+    }
+
+    /// Entry of `key`, failing at the first entry of the table if it is not set
+    fn require(&self, key: &str) -> Result<&Entry, ConfigError> {
+        self.get(key).ok_or_else(|| {
+            self.entries[0]
+                .1
+                .error(format!("`{}.{}` lacks `{key}`", self.section, self.name))
+        })
+    }
+
+    /// Check that only `keys` are set
+    fn check_keys(&self, keys: &[&str]) -> Result<(), ConfigError> {
+        match self.entries.iter().find(|(key, _)| !keys.contains(key)) {
+            Some((_, entry)) => Err(entry.error(format!(
+                "unknown key `{}`, a {} takes `{}`",
+                entry.key,
+                self.section,
+                keys.join("`, `")
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Target as given on the command line, like `10.1.1.10:5000%blue?snap=128`
+    fn target(&self) -> Result<String, ConfigError> {
+        self.check_keys(TARGET_KEYS)?;
+        let addr = self.require("addr")?;
+        let Value::String(mut target) = addr.value.clone() else {
+            return Err(addr.mismatch("a string"));
+        };
+        if let Some(vrf) = self.get("vrf") {
+            match &vrf.value {
+                Value::String(name) if !name.is_empty() && !name.contains([']', ':', '/']) => {
+                    target.push('%');
+                    target.push_str(name);
+                }
+                _ => return Err(vrf.mismatch("the name of a VRF device")),
+            }
+        }
+        if let Some(snap) = self.get("snap") {
+            match snap.value {
+                Value::Integer(snaplen) if snaplen > 0 => {
+                    target.push_str(&format!("?snap={snaplen}"))
+                }
+                Value::Integer(_) => {
+                    return Err(snap.error(format!("`{}` must be positive", snap.key)));
+                }
+                _ => return Err(snap.mismatch("a positive integer")),
+            }
+        }
+        Ok(target)
+    }
+
+    fn rule(&self) -> Result<Rule, ConfigError> {
+        self.check_keys(RULE_KEYS)?;
+        let listener = self.require("listener")?;
+        let listener_spec = match &listener.value {
+            Value::String(spec) => spec
+                .parse()
+                .map_err(|e| listener.error(format!("invalid listener `{spec}`: {e}")))?,
+            _ => return Err(listener.mismatch("a string")),
+        };
+        let targets = self.require("targets")?;
+        let forward_addrs = targets
+            .value
+            .scalars()
+            .ok_or_else(|| targets.mismatch("a string or an array of strings"))?
+            .iter()
+            .map(|target| {
+                target
+                    .parse()
+                    .map_err(|e| targets.error(format!("invalid target `{target}`: {e}")))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Rule {
+            listener_spec,
+            forward_addrs,
+        })
+    }
+}
+
+/// Section, name and key of a key in a named table like `target.recorder.addr`
+fn split_named_table(key: &str) -> Option<(&str, &str, &str)> {
+    let (section, rest) = key.split_once('.')?;
+    let (name, key) = rest.rsplit_once('.')?;
+    matches!(section, "target" | "rule").then_some((section, name, key))
+}
+
+/// Key a key within a section stands for, the key itself if it is in none
+fn canonical(key: &str) -> &str {
+    SECTION_KEYS
+        .iter()
+        .find_map(|(section_key, option)| (*section_key == key).then_some(*option))
+        .unwrap_or(key)
+}
+
+/// Read the entries of the file at `path` and of the files it includes, in order
+fn read_entries(
+    path: &Path,
+    env: &dyn Fn(&str) -> Option<String>,
+    depth: usize,
+    entries: &mut Vec<Entry>,
+) -> Result<(), ConfigError> {
+    let content = fs::read_to_string(path).map_err(|e| ConfigError {
+        path: path.to_owned(),
+        line: None,
+        message: e.to_string(),
+    })?;
+    for entry in parse_entries(&content, path, env)? {
+        if entry.key != "include" {
+            entries.push(entry);
+            continue;
+        }
+        if depth == MAX_INCLUDE_DEPTH {
+            return Err(entry.error("includes nested too deeply".to_owned()));
+        }
+        let patterns = entry
+            .value
+            .scalars()
+            .ok_or_else(|| entry.mismatch("a string or an array of strings"))?;
+        // Included paths are relative to the including file
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for pattern in patterns {
+            let files = expand(&dir.join(&pattern))
+                .map_err(|e| entry.error(format!("failed to include {pattern}: {e}")))?;
+            for file in files {
+                read_entries(&file, env, depth + 1, entries)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse the entries of a single file at `path`, interpolating environment variables
+fn parse_entries(
+    content: &str,
+    path: &Path,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<Vec<Entry>, ConfigError> {
+    let error = |(line, message)| ConfigError {
+        path: path.to_owned(),
+        line: Some(line),
+        message,
+    };
+    let entries = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => Parser::new(content).json_entries(),
+        Some("yaml" | "yml") => yaml_entries(content),
+        _ => Parser::new(content).entries(),
+    };
+    let mut entries = entries.map_err(error)?;
+
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for entry in &mut entries {
+        if let Some(line) = seen.insert(entry.key.clone(), entry.line) {
+            return Err(error((
+                entry.line,
+                format!("`{}` already set on line {line}", entry.key),
+            )));
+        }
+        entry.path = path.to_owned();
+        entry
+            .value
+            .interpolate(env)
+            .map_err(|message| error((entry.line, message)))?;
+    }
+    Ok(entries)
+}
+
+/// Files matching `pattern`, with `*` and `?` in the file name, sorted by name
+///
+/// A pattern without wildcards names a file which must exist.
+fn expand(pattern: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    let Some(name) = pattern.file_name().and_then(|name| name.to_str()) else {
+        return Ok(vec![pattern.to_owned()]);
+    };
+    if !name.contains(['*', '?']) {
+        return fs::metadata(pattern).map(|_| vec![pattern.to_owned()]);
+    }
+
+    let dir = pattern.parent().unwrap_or(Path::new(""));
+    let listed = match dir.as_os_str().is_empty() {
+        true => fs::read_dir("."),
+        false => fs::read_dir(dir),
+    };
+    let mut files = Vec::new();
+    for entry in listed? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_str()
+            .is_some_and(|file_name| matches_wildcards(name, file_name))
+            && entry.file_type()?.is_file()
+        {
+            files.push(dir.join(entry.file_name()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Check if `name` matches `pattern` with `*` for any characters and `?` for a single one
+fn matches_wildcards(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => {
+            let rest = &pattern[1..];
+            name.char_indices()
+                .map(|(idx, _)| idx)
+                .chain([name.len()])
+                .any(|idx| matches_wildcards(rest, &name[idx..]))
+        }
+        Some(c) => {
+            let mut chars = name.chars();
+            chars.next().is_some_and(|first| c == '?' || c == first)
+                && matches_wildcards(&pattern[c.len_utf8()..], chars.as_str())
+        }
+    }
+}
+
+/// Values a key takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Boolean of an option without value
+    Flag,
+    /// String or number
+    Value,
+    /// Array of strings or numbers, or a single one
+    Values,
+}
+
+/// Values `key` takes, if it is known
+fn kind(key: &str) -> Option<Kind> {
+    match key {
+        "listener" => return Some(Kind::Value),
+        "targets" => return Some(Kind::Values),
+        key if REPEATED.contains(&key) => return Some(Kind::Values),
+        _ => {}
+    }
+    OPTIONS.iter().find_map(|(option, _)| {
+        let (name, placeholder) = option.split_once(' ').unwrap_or((option, ""));
+        match name.strip_prefix("--")? {
+            // Config files don't nest
+            "config" => None,
+            name if name != key => None,
+            _ if placeholder.is_empty() => Some(Kind::Flag),
+            _ => Some(Kind::Value),
+        }
+    })
+}
+
+/// Known key closest to the unknown `key`, if any is close enough to be a typo
+fn suggestion(key: &str) -> Option<&'static str> {
+    let keys = OPTIONS
+        .iter()
+        .filter_map(|(option, _)| option.split(' ').next()?.strip_prefix("--"))
+        .chain(["listener", "targets", "include"])
+        .chain(SECTION_KEYS.iter().map(|(section_key, _)| *section_key));
+    keys.map(|known| (edit_distance(key, known), known))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known)| known)
+}
+
+/// Number of inserted, removed or replaced characters to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let replaced = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Boolean or number written without quotes
+fn keyword(word: &str) -> Option<Value> {
+    match word {
+        "true" => return Some(Value::Boolean(true)),
+        "false" => return Some(Value::Boolean(false)),
+        _ => {}
+    }
+    let number = word.replace('_', "");
+    if let Ok(n) = number.parse() {
+        return Some(Value::Integer(n));
+    }
+    match number.parse() {
+        Ok(n) if word.contains(|c: char| c.is_ascii_digit()) => Some(Value::Float(n)),
+        _ => None,
+    }
+}
+
+/// Entries of a YAML document of nested mappings, scalars and lists of scalars
+///
+/// Nested mappings are taken as tables. Anchors, tags, multi-line scalars and
+/// multiple documents are not supported.
+fn yaml_entries(content: &str) -> Result<Vec<Entry>, (usize, String)> {
+    // Indentation, text and line of the lines with content
+    let mut lines = Vec::new();
+    for (idx, line) in content.lines().enumerate() {
+        let text = strip_yaml_comment(line).trim_end();
+        let content = text.trim_start_matches(' ');
+        if content.is_empty() || (idx == 0 || lines.is_empty()) && content == "---" {
+            continue;
+        }
+        if content.starts_with('\t') {
+            return Err((idx + 1, "tabs are not allowed for indentation".to_owned()));
+        }
+        lines.push((text.len() - content.len(), content, idx + 1));
+    }
+
+    let mut entries = Vec::new();
+    // Indentation and key of the mappings the current line is nested in
+    let mut tables: Vec<(usize, String)> = Vec::new();
+    let mut lines = lines.into_iter().peekable();
+    while let Some((indent, text, line)) = lines.next() {
+        while tables
+            .last()
+            .is_some_and(|(table_indent, _)| indent <= *table_indent)
+        {
+            tables.pop();
+        }
+        let Some((key, rest)) = split_yaml_key(text) else {
+            return Err((line, "expected `key: value`".to_owned()));
+        };
+        let key = yaml_scalar(key, line)?
+            .and_then(|key| key.scalar())
+            .ok_or((line, "expected a string as key".to_owned()))?;
+        let key = match tables.last() {
+            Some((_, table)) => format!("{table}.{key}"),
+            None => key,
+        };
+
+        let value = match rest {
+            "" => match lines.peek() {
+                // Items of a list may be indented like the key
+                Some((next_indent, next, _)) if *next_indent >= indent && next.starts_with('-') => {
+                    let item_indent = *next_indent;
+                    let mut values = Vec::new();
+                    while let Some((_, item, item_line)) =
+                        lines.next_if(|(next_indent, next, _)| {
+                            *next_indent == item_indent && next.starts_with('-')
+                        })
+                    {
+                        let item = item[1..].trim_start();
+                        match yaml_value(item, item_line)? {
+                            Some(
+                                value @ (Value::String(_)
+                                | Value::Integer(_)
+                                | Value::Float(_)
+                                | Value::Boolean(_)),
+                            ) => values.push(value),
+                            _ => {
+                                return Err((
+                                    item_line,
+                                    "expected a scalar as list item".to_owned(),
+                                ));
+                            }
+                        }
+                    }
+                    Some(Value::Array(values))
+                }
+                Some((next_indent, _, _)) if *next_indent > indent => {
+                    tables.push((indent, key));
+                    continue;
+                }
+                _ => None,
+            },
+            rest => yaml_value(rest, line)?,
+        };
+        // Null leaves the key unset
+        if let Some(value) = value {
+            entries.push(Entry {
+                key,
+                value,
+                path: PathBuf::new(),
+                line,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Part of `line` before a comment
+fn strip_yaml_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (idx, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open && previous != '\\' => quote = None,
+            (None, '#') if previous == ' ' || previous == '\t' => return &line[..idx],
+            _ => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+/// Key and the rest after `key:`, if the line is a mapping entry
+fn split_yaml_key(text: &str) -> Option<(&str, &str)> {
+    let end = match text.chars().next()? {
+        quote @ ('"' | '\'') => text[1..].find(quote)? + 2,
+        _ => 0,
+    };
+    let colon = end + text[end..].find(':')?;
+    let rest = &text[colon + 1..];
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    Some((text[..colon].trim_end(), rest.trim_start()))
+}
+
+/// Scalar or flow list of scalars, `None` for null
+fn yaml_value(text: &str, line: usize) -> Result<Option<Value>, (usize, String)> {
+    let Some(items) = text.strip_prefix('[') else {
+        return yaml_scalar(text, line);
+    };
+    let items = items
+        .strip_suffix(']')
+        .ok_or((line, "expected `]` at the end of the list".to_owned()))?;
+    let mut values = Vec::new();
+    for item in split_yaml_items(items) {
+        match yaml_scalar(item.trim(), line)? {
+            Some(value) => values.push(value),
+            None if item.trim().is_empty() && values.is_empty() => {}
+            None => return Err((line, "expected a scalar as list item".to_owned())),
+        }
+    }
+    Ok(Some(Value::Array(values)))
+}
+
+/// Items of a flow list separated by commas outside quotes
+fn split_yaml_items(items: &str) -> Vec<&str> {
+    let mut split = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (idx, c) in items.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            (None, ',') => {
+                split.push(&items[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    split.push(&items[start..]);
+    split
+}
+
+/// Quoted or plain scalar, `None` for null
+fn yaml_scalar(text: &str, line: usize) -> Result<Option<Value>, (usize, String)> {
+    match text {
+        "" | "~" | "null" => Ok(None),
+        _ if text.starts_with(['"', '\'']) => {
+            let mut parser = Parser::new(text);
+            let s = match text.starts_with('"') {
+                true => parser.basic_string(),
+                false => parser.literal_string(),
+            }
+            .map_err(|(_, message)| (line, message))?;
+            match parser.pos == text.len() {
+                true => Ok(Some(Value::String(s))),
+                false => Err((line, "unexpected text after the quoted string".to_owned())),
+            }
+        }
+        _ if text.starts_with(['{', '&', '*', '!', '|', '>']) => {
+            Err((line, format!("unsupported YAML `{text}`")))
+        }
+        _ => Ok(Some(
+            keyword(text).unwrap_or_else(|| Value::String(text.to_owned())),
+        )),
+    }
+}
+
+/// Invalid config file, with the line of the error if it was read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{line}: {}", self.path.display(), self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Value in a config file
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// String or number as given on the command line
+    fn scalar(&self) -> Option<String> {
+        match self {
+            Value::String(s) => Some(s.clone()),
+            Value::Integer(n) => Some(n.to_string()),
+            Value::Float(n) => Some(n.to_string()),
+            Value::Boolean(_) | Value::Array(_) => None,
+        }
+    }
+
+    /// Strings or numbers of an array, or a single one
+    fn scalars(&self) -> Option<Vec<String>> {
+        match self {
+            Value::Array(values) => values.iter().map(Value::scalar).collect(),
+            value => Some(vec![value.scalar()?]),
+        }
+    }
+
+    /// Replace `${NAME}` and `${NAME:-default}` in strings with environment variables
+    fn interpolate(&mut self, env: &dyn Fn(&str) -> Option<String>) -> Result<(), String> {
+        match self {
+            Value::String(s) => {
+                let mut interpolated = String::new();
+                let mut rest = s.as_str();
+                while let Some(start) = rest.find("${") {
+                    interpolated.push_str(&rest[..start]);
+                    let Some(end) = rest[start..].find('}') else {
+                        return Err("unterminated `${` in string".to_owned());
+                    };
+                    let reference = &rest[start + 2..start + end];
+                    let (name, default) = match reference.split_once(":-") {
+                        Some((name, default)) => (name, Some(default)),
+                        None => (reference, None),
+                    };
+                    match env(name).or_else(|| default.map(str::to_owned)) {
+                        Some(value) => interpolated.push_str(&value),
+                        None => return Err(format!("environment variable `{name}` is not set")),
+                    }
+                    rest = &rest[start + end + 1..];
+                }
+                interpolated.push_str(rest);
+                *s = interpolated;
+                Ok(())
+            }
+            Value::Array(values) => values
+                .iter_mut()
+                .try_for_each(|value| value.interpolate(env)),
+            Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => Ok(()),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Float(_) => "a float",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+/// Key set in a config file, with the file and line it is set on
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    /// Key prefixed with the table it is in, separated by dots
+    key: String,
+    value: Value,
+    path: PathBuf,
+    line: usize,
+}
+
+impl Entry {
+    fn error(&self, message: String) -> ConfigError {
+        ConfigError {
+            path: self.path.clone(),
+            line: Some(self.line),
+            message,
+        }
+    }
+
+    /// Type mismatch of the value
+    fn mismatch(&self, expected: &str) -> ConfigError {
+        self.error(format!(
+            "`{}` expects {expected}, found {}",
+            self.key,
+            self.value.type_name()
+        ))
+    }
+
+    /// Location of `other` as seen from this entry, omitting the file if it is the same
+    fn location_of(&self, other: &Entry) -> String {
+        match self.path == other.path {
+            true => format!("on line {}", other.line),
+            false => format!("in {}:{}", other.path.display(), other.line),
+        }
+    }
+}
+
+/// Parser of the TOML subset, tracking the line for errors
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            pos: 0,
+            line: 1,
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> (usize, String) {
+        (self.line, message.into())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), (usize, String)> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.bump();
+                Ok(())
+            }
+            Some(c) => Err(self.error(format!("expected `{expected}`, found `{c}`"))),
+            None => Err(self.error(format!("expected `{expected}` at the end"))),
+        }
+    }
+
+    /// Skip spaces and tabs within the line
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    /// Skip whitespace, line breaks and comments
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => {
+                    self.bump();
+                }
+                Some('#') => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.bump();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Require the line to end after optional spaces and a comment
+    fn end_of_line(&mut self) -> Result<(), (usize, String)> {
+        self.skip_space();
+        match self.peek() {
+            None | Some('\n' | '\r' | '#') => Ok(()),
+            Some(c) => Err(self.error(format!("unexpected `{c}` at the end of the line"))),
+        }
+    }
+
+    fn entries(mut self) -> Result<Vec<Entry>, (usize, String)> {
+        let mut entries = Vec::new();
+        let mut table = String::new();
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => return Ok(entries),
+                Some('[') => {
+                    self.bump();
+                    self.skip_space();
+                    table = self.key()?;
+                    self.skip_space();
+                    self.expect(']')?;
+                    self.end_of_line()?;
+                }
+                Some(_) => {
+                    let line = self.line;
+                    let key = self.key()?;
+                    self.skip_space();
+                    self.expect('=')?;
+                    self.skip_space();
+                    let value = self.value()?;
+                    self.end_of_line()?;
+                    let key = match table.is_empty() {
+                        true => key,
+                        false => format!("{table}.{key}"),
+                    };
+                    entries.push(Entry {
+                        key,
+                        value,
+                        path: PathBuf::new(),
+                        line,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Bare or quoted key, with dotted parts joined by dots
+    fn key(&mut self) -> Result<String, (usize, String)> {
+        let mut key = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => key.push_str(&self.basic_string()?),
+                Some('\'') => key.push_str(&self.literal_string()?),
+                _ => {
+                    let start = self.pos;
+                    while self
+                        .peek()
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    {
+                        self.bump();
+                    }
+                    if start == self.pos {
+                        return Err(self.error("expected a key"));
+                    }
+                    key.push_str(&self.input[start..self.pos]);
+                }
+            }
+            self.skip_space();
+            if self.peek() != Some('.') {
+                return Ok(key);
+            }
+            self.bump();
+            self.skip_space();
+            key.push('.');
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, (usize, String)> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some(_) => {
+                let word = self.word();
+                keyword(word).ok_or_else(|| {
+                    self.error(format!("invalid value `{word}`, strings need quotes"))
+                })
+            }
+            None => Err(self.error("expected a value at the end")),
+        }
+    }
+
+    /// Unquoted boolean or number
+    fn word(&mut self) -> &'a str {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '_' | '.'))
+        {
+            self.bump();
+        }
+        &self.input[start..self.pos]
+    }
+
+    /// Skip whitespace and line breaks, which is all JSON allows between tokens
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+            self.bump();
+        }
+    }
+
+    /// Entries of a JSON object, with nested objects as tables
+    fn json_entries(mut self) -> Result<Vec<Entry>, (usize, String)> {
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        self.expect('{')?;
+        self.json_object("", &mut entries)?;
+        self.skip_whitespace();
+        match self.peek() {
+            None => Ok(entries),
+            Some(c) => Err(self.error(format!("unexpected `{c}` after the object"))),
+        }
+    }
+
+    /// Members of an object after its `{`, with keys prefixed by `table`
+    fn json_object(
+        &mut self,
+        table: &str,
+        entries: &mut Vec<Entry>,
+    ) -> Result<(), (usize, String)> {
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(());
+        }
+        loop {
+            self.skip_whitespace();
+            let line = self.line;
+            let key = self.basic_string()?;
+            let key = match table.is_empty() {
+                true => key,
+                false => format!("{table}.{key}"),
+            };
+            self.skip_whitespace();
+            self.expect(':')?;
+            self.skip_whitespace();
+            match self.peek() {
+                Some('{') => {
+                    self.bump();
+                    self.json_object(&key, entries)?;
+                }
+                _ => {
+                    // Null leaves the key unset
+                    if let Some(value) = self.json_value()? {
+                        entries.push(Entry {
+                            key,
+                            value,
+                            path: PathBuf::new(),
+                            line,
+                        });
+                    }
+                }
+            }
+            self.skip_whitespace();
+            match self.bump() {
+                Some(',') => {}
+                Some('}') => return Ok(()),
+                _ => return Err(self.error("expected `,` or `}` in object")),
+            }
+        }
+    }
+
+    /// String, boolean, number or array of them, `None` for null
+    fn json_value(&mut self) -> Result<Option<Value>, (usize, String)> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(|s| Some(Value::String(s))),
+            Some('[') => {
+                self.bump();
+                let mut values = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if values.is_empty() && self.peek() == Some(']') {
+                        self.bump();
+                        return Ok(Some(Value::Array(values)));
+                    }
+                    match self.json_value()? {
+                        Some(value) => values.push(value),
+                        None => return Err(self.error("null in array")),
+                    }
+                    self.skip_whitespace();
+                    match self.bump() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Some(Value::Array(values))),
+                        _ => return Err(self.error("expected `,` or `]` in array")),
+                    }
+                }
+            }
+            Some('{') => Err(self.error("objects are only supported as values of keys")),
+            Some(_) => match self.word() {
+                "null" => Ok(None),
+                word => keyword(word)
+                    .map(Some)
+                    .ok_or_else(|| self.error(format!("invalid value `{word}`"))),
+            },
+            None => Err(self.error("expected a value at the end")),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, (usize, String)> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_blank();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(values)),
+                _ => return Err(self.error("expected `,` or `]` in array")),
+            }
+        }
+    }
+
+    /// String in double quotes with escapes
+    fn basic_string(&mut self) -> Result<String, (usize, String)> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let escaped = match self.bump() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some(c @ ('u' | 'U')) => {
+                            let len = if c == 'u' { 4 } else { 8 };
+                            let hex = self.input.get(self.pos..self.pos + len).unwrap_or("");
+                            let c = u32::from_str_radix(hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| self.error("invalid unicode escape"))?;
+                            self.pos += len;
+                            c
+                        }
+                        _ => return Err(self.error("invalid escape in string")),
+                    };
+                    s.push(escaped);
+                }
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    /// String in single quotes taken as is
+    fn literal_string(&mut self) -> Result<String, (usize, String)> {
+        self.expect('\'')?;
+        let start = self.pos;
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(self.input[start..self.pos - 1].to_owned()),
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, process};
+
+    use super::*;
+
+    /// Config of `content` in `test.toml`, with `HOST` set in the environment
+    fn parse(content: &str) -> Result<Config, ConfigError> {
+        parse_file(content, "test.toml")
+    }
+
+    /// Config of `content` in a file named `name`, with `HOST` set in the environment
+    fn parse_file(content: &str, name: &str) -> Result<Config, ConfigError> {
+        let env = |name: &str| (name == "HOST").then(|| "10.1.1.10".to_owned());
+        Config::from_entries(&parse_entries(content, Path::new(name), &env)?)
+    }
+
+    /// Options of `config` with their values and lines
+    fn options(config: &Config) -> Vec<(&str, Option<&str>, usize)> {
+        config
+            .options
+            .iter()
+            .map(|option| (option.name.as_str(), option.value.as_deref(), option.line))
+            .collect()
+    }
+
+    #[test]
+    fn options_read() {
+        let config = parse(
+            r#"
+# Relay of the camera streams
+listener = "0.0.0.0:4000"
+targets = [
+    "${HOST}:5000",  # Recorder
+    '@targets.txt',
+]
+stats-interval = "${STATS_INTERVAL:-1s}"
+alert-min-pps = 100
+hub = true
+verbose = false
+stun = ["stun1.example.com:3478", "stun2.example.com:3478"]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(Some("0.0.0.0:4000".to_owned()), config.listener);
+        assert_eq!(vec!["10.1.1.10:5000", "@targets.txt"], config.targets);
+        assert_eq!(
+            vec![
+                ("--stats-interval", Some("1s"), 8),
+                ("--alert-min-pps", Some("100"), 9),
+                ("--hub", None, 10),
+                ("--stun", Some("stun1.example.com:3478"), 12),
+                ("--stun", Some("stun2.example.com:3478"), 12),
+            ],
+            options(&config)
+        );
+    }
+
+    #[test]
+    fn sections_read() {
+        let config = parse(
+            r#"
+[listener]
+addr = "0.0.0.0:4000"
+
+[buffers]
+recv = "4M"
+
+[logging]
+level = "warn"
+
+[target.recorder]
+addr = "${HOST}:5000"
+vrf = "blue"
+
+[target.monitor]
+snap = 128
+addr = "10.1.1.11:5000"
+
+[rule.audio]
+listener = "0.0.0.0:4002"
+targets = ["10.1.1.10:5002", "10.1.1.11:5002"]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(Some("0.0.0.0:4000".to_owned()), config.listener);
+        assert_eq!(
+            vec!["10.1.1.10:5000%blue", "10.1.1.11:5000?snap=128"],
+            config.targets
+        );
+        assert_eq!(
+            vec![
+                ("--recv-buffer", Some("4M"), 6),
+                ("--log-level", Some("warn"), 9)
+            ],
+            options(&config)
+        );
+        assert_eq!(
+            vec![
+                "0.0.0.0:4002=>10.1.1.10:5002,10.1.1.11:5002"
+                    .parse::<Rule>()
+                    .unwrap()
+            ],
+            config.rules
+        );
+
+        let error = |content| parse(content).unwrap_err().to_string();
+        assert_eq!(
+            "test.toml:3: unknown key `target.cam.port`, a target takes `addr`, `vrf`, `snap`",
+            error("[target.cam]\naddr = \"10.1.1.10:5000\"\nport = 5000")
+        );
+        assert_eq!(
+            "test.toml:2: `target.cam` lacks `addr`",
+            error("[target.cam]\nvrf = \"blue\"")
+        );
+        assert_eq!(
+            "test.toml:3: `target.cam.snap` expects a positive integer, found a string",
+            error("[target.cam]\naddr = \"10.1.1.10:5000\"\nsnap = \"128\"")
+        );
+        assert_eq!(
+            "test.toml:3: invalid target `10.1.1.10`: invalid socket address syntax",
+            error("[rule.audio]\nlistener = \"0.0.0.0:4002\"\ntargets = \"10.1.1.10\"")
+        );
+        assert_eq!(
+            "test.toml:3: `listener.addr` already set on line 1",
+            error("listener = \"0.0.0.0:4000\"\n[listener]\naddr = \"0.0.0.0:4001\"")
+        );
+        assert_eq!(
+            "test.toml:2: `logging.verbose` conflicts with `quiet` on line 1, quiet hides the \
+             verbose messages",
+            error("quiet = true\nlogging.verbose = true")
+        );
+    }
+
+    #[test]
+    fn yaml_read() {
+        let config = parse_file(
+            r#"---
+# Relay of the camera streams
+listener: 0.0.0.0:4000
+targets:
+  - ${HOST}:5000  # Recorder
+  - '@targets.txt'
+stats-interval: "1s"
+alert-min-pps: 100
+hub: true
+stun: [stun1.example.com:3478, "stun2.example.com:3478"]
+"#,
+            "test.yaml",
+        )
+        .unwrap();
+
+        assert_eq!(Some("0.0.0.0:4000".to_owned()), config.listener);
+        assert_eq!(vec!["10.1.1.10:5000", "@targets.txt"], config.targets);
+        assert_eq!(
+            vec![
+                ("--stats-interval", Some("1s"), 7),
+                ("--alert-min-pps", Some("100"), 8),
+                ("--hub", None, 9),
+                ("--stun", Some("stun1.example.com:3478"), 10),
+                ("--stun", Some("stun2.example.com:3478"), 10),
+            ],
+            options(&config)
+        );
+
+        let error = parse_file("logging:\n  levl: warn\n", "test.yml").unwrap_err();
+        assert_eq!(
+            "test.yml:2: unknown key `logging.levl`, did you mean `logging.level`?",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn json_read() {
+        let config = parse_file(
+            r#"{
+    "listener": "0.0.0.0:4000",
+    "targets": ["${HOST}:5000", "@targets.txt"],
+    "stats-interval": "1s",
+    "alert-min-pps": 100,
+    "hub": true,
+    "pidfile": null
+}"#,
+            "test.json",
+        )
+        .unwrap();
+
+        assert_eq!(Some("0.0.0.0:4000".to_owned()), config.listener);
+        assert_eq!(vec!["10.1.1.10:5000", "@targets.txt"], config.targets);
+        assert_eq!(
+            vec![
+                ("--stats-interval", Some("1s"), 4),
+                ("--alert-min-pps", Some("100"), 5),
+                ("--hub", None, 6),
+            ],
+            options(&config)
+        );
+
+        let error = parse_file("{\n\"hub\": \"yes\"\n}", "test.json").unwrap_err();
+        assert_eq!(
+            "test.json:2: `hub` expects true or false, found a string",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn errors_located() {
+        let error = |content| parse(content).unwrap_err().to_string();
+
+        assert_eq!(
+            "test.toml:2: unknown key `stats-intervall`, did you mean `stats-interval`?",
+            error("hub = true\nstats-intervall = \"1s\"")
+        );
+        assert_eq!(
+            "test.toml:2: unknown key `metrics.port`",
+            error("[metrics]\nport = 1")
+        );
+        assert_eq!(
+            "test.toml:1: `hub` expects true or false, found a string",
+            error("hub = \"yes\"")
+        );
+        assert_eq!(
+            "test.toml:3: `xdp` conflicts with `capture` on line 1, receiving excludes each other",
+            error("capture = \"eth0\"\nhub = true\nxdp = \"eth1\"")
+        );
+        assert_eq!(
+            "test.toml:2: `hub` already set on line 1",
+            error("hub = true\nhub = false")
+        );
+        assert_eq!(
+            "test.toml:1: invalid value `eth0`, strings need quotes",
+            error("capture = eth0")
+        );
+        assert_eq!(
+            "test.toml:2: unterminated string",
+            error("\nuser = \"nobody")
+        );
+        assert_eq!(
+            "test.toml:1: environment variable `USER_NAME` is not set",
+            error("user = \"${USER_NAME}\"")
+        );
+    }
+
+    #[test]
+    fn fragments_included() {
+        let dir = env::temp_dir().join(format!("udpforwarder-config-{}", process::id()));
+        fs::create_dir_all(dir.join("targets.d")).unwrap();
+        fs::write(
+            dir.join("main.toml"),
+            "listener = \"0.0.0.0:4000\"\ninclude = [\"targets.d/*.toml\"]\nstun = \"stun.example.com:3478\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("targets.d/b.toml"),
+            "targets = [\"10.1.1.11:5000\"]\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("targets.d/a.toml"),
+            "targets = [\"10.1.1.10:5000\"]\nhub = true\n",
+        )
+        .unwrap();
+        fs::write(dir.join("targets.d/README"), "not a fragment").unwrap();
+
+        let config = Config::load(&dir.join("main.toml")).unwrap();
+        assert_eq!(vec!["10.1.1.10:5000", "10.1.1.11:5000"], config.targets);
+        assert_eq!(2, config.options.len());
+
+        // Options other than lists are set once across all files
+        fs::write(dir.join("targets.d/c.toml"), "\nhub = true\n").unwrap();
+        let error = Config::load(&dir.join("main.toml")).unwrap_err();
+        assert_eq!(Some(2), error.line);
+        assert!(error.path.ends_with("targets.d/c.toml"));
+        assert!(error.message.starts_with("`hub` already set in "));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wildcards_matched() {
+        assert!(matches_wildcards("*.toml", "cams.toml"));
+        assert!(matches_wildcards("cam?.toml", "cam1.toml"));
+        assert!(!matches_wildcards("*.toml", "cams.toml.bak"));
+        assert!(!matches_wildcards("cam?.toml", "cam12.toml"));
+    }
+
+    #[test]
+    fn typos_suggested() {
+        assert_eq!(Some("hub"), suggestion("hubb"));
+        assert_eq!(Some("targets"), suggestion("target"));
+        assert_eq!(Some("include"), suggestion("includes"));
+        assert_eq!(None, suggestion("bandwidth"));
+    }
+}
@@ -0,0 +1,743 @@
+//! HTTP control API
+//!
+//! Lets orchestration systems and scripts observe and manage a running forwarder
+//! with plain `curl`. Bind it to localhost, there is no authentication. Requests have
+//! to name the bound address, or `localhost` for a loopback address, as host, and
+//! browsers may only send them from the dashboard itself, so that other web pages can
+//! neither send them through the browser of an operator nor by rebinding their name.
+//!
+//! | Request                       | Effect                                                                      |
+//! |-------------------------------|-----------------------------------------------------------------------------|
+//! | `GET /`                       | web dashboard polling the endpoints below                                   |
+//! | `GET /status`                 | listener, uptime, paused flag and time, targets and public addresses        |
+//! | `GET /stats`                  | per-source and per-target counters, jitter, delay, rate alert, stream state |
+//! | `POST /targets`               | add the targets in the body                                                 |
+//! | `DELETE /targets/{addr}`      | remove a target                                                             |
+//! | `GET /groups`                 | target groups with their targets and enabled flag                           |
+//! | `POST /groups/{name}/enable`  | resume forwarding to the targets of a group                                 |
+//! | `POST /groups/{name}/disable` | stop forwarding to the targets of a group                                   |
+//! | `POST /pause`                 | stop forwarding, dropping received packets                                  |
+//! | `POST /resume`                | continue forwarding                                                         |
+//! | `GET /diag`                   | options, listener socket options, threads, status and stats for bug reports |
+
+use std::{
+    fmt::Write,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, atomic::Ordering},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{
+    alert::RateAlert,
+    diagnostics::{BuildInfo, SocketReport, thread_states},
+    http::{self, Request, Response},
+    json::{json_object, json_string},
+    state::SharedState,
+};
+
+/// Single-page dashboard for browsers
+const DASHBOARD: &str = include_str!("dashboard.html");
+/// Time to wait for the control API of another forwarder
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the control API on `addr` in new threads
+pub fn serve(addr: SocketAddr, state: Arc<SharedState>) -> Result<Vec<JoinHandle<()>>, io::Error> {
+    http::serve(addr, "control", move |request| {
+        match check_host(addr, request) {
+            Ok(()) => handle(&state, request),
+            Err(response) => response,
+        }
+    })
+}
+
+/// Refuse requests naming another host or sent by browsers from another origin
+fn check_host(addr: SocketAddr, request: &Request) -> Result<(), Response> {
+    if !request
+        .host
+        .as_deref()
+        .is_some_and(|host| is_bound_host(addr, host))
+    {
+        return Err(Response::text(
+            421,
+            "host does not match the address of the control API\n",
+        ));
+    }
+    if let Some(origin) = &request.origin
+        && !origin
+            .strip_prefix("http://")
+            .is_some_and(|host| is_bound_host(addr, host))
+    {
+        return Err(Response::text(403, "cross-origin requests are forbidden\n"));
+    }
+    Ok(())
+}
+
+/// Check if `host` with optional port names the address `addr` the API is bound to
+///
+/// Any address names an unspecified bound address, `localhost` a loopback one.
+fn is_bound_host(addr: SocketAddr, host: &str) -> bool {
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) if !host.ends_with(']') => (name, port.parse().ok()),
+        _ => (host, Some(80)),
+    };
+    let name = name
+        .strip_prefix('[')
+        .and_then(|name| name.strip_suffix(']'))
+        .unwrap_or(name);
+    let name_matches = match name.parse::<IpAddr>() {
+        Ok(ip) => ip == addr.ip() || addr.ip().is_unspecified(),
+        Err(_) => {
+            name.eq_ignore_ascii_case("localhost")
+                && (addr.ip().is_loopback() || addr.ip().is_unspecified())
+        }
+    };
+    name_matches && port == Some(addr.port())
+}
+
+/// Handle a single request of the control API
+fn handle(state: &SharedState, request: &Request) -> Response {
+    let method = request.method.as_str();
+    let path = request.path.trim_end_matches('/');
+
+    match (method, path) {
+        ("GET", "") => Response::html(200, DASHBOARD),
+        ("GET", "/status") => Response::json(200, status_json(state)),
+        ("GET", "/stats") => Response::json(200, stats_json(state)),
+        ("POST", "/targets") => add_targets(state, &request.body),
+        ("DELETE", path) if path.starts_with("/targets/") => {
+            match path["/targets/".len()..].parse() {
+                Ok(addr) if state.remove_target(addr) => Response::json(200, status_json(state)),
+                Ok(_) => Response::not_found(),
+                Err(_) => Response::text(400, "invalid target address\n"),
+            }
+        }
+        ("GET", "/groups") => Response::json(200, groups_json(state)),
+        ("POST", path) if path.starts_with("/groups/") => {
+            let (name, action) = path["/groups/".len()..]
+                .rsplit_once('/')
+                .unwrap_or_default();
+            let enabled = match action {
+                "enable" => true,
+                "disable" => false,
+                _ => return Response::not_found(),
+            };
+            match state.set_group_enabled(name, enabled) {
+                true => Response::json(200, groups_json(state)),
+                false => Response::not_found(),
+            }
+        }
+        ("POST", "/pause") => {
+            state.set_paused(true);
+            Response::json(200, status_json(state))
+        }
+        ("POST", "/resume") => {
+            state.set_paused(false);
+            Response::json(200, status_json(state))
+        }
+        ("GET", "/diag") => Response::json(200, diag_json(state)),
+        ("GET", "/info") => Response::json(200, info_json(state)),
+        (
+            _,
+            "" | "/status" | "/stats" | "/targets" | "/groups" | "/pause" | "/resume" | "/diag"
+            | "/info",
+        ) => Response::text(405, "method not allowed\n"),
+        _ => Response::not_found(),
+    }
+}
+
+/// Add the comma- or whitespace-separated targets of `body`
+fn add_targets(state: &SharedState, body: &[u8]) -> Response {
+    let Ok(body) = std::str::from_utf8(body) else {
+        return Response::text(400, "body is not UTF-8\n");
+    };
+
+    let addrs: Result<Vec<SocketAddr>, _> = body
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|addr| !addr.is_empty())
+        .map(str::parse)
+        .collect();
+
+    match addrs {
+        Ok(addrs) if !addrs.is_empty() => {
+            for addr in addrs {
+                state.add_target(addr);
+            }
+            Response::json(201, status_json(state))
+        }
+        Ok(_) => Response::text(400, "no target address given\n"),
+        Err(e) => Response::text(400, format!("invalid target address: {e}\n")),
+    }
+}
+
+/// Render listener, labels, uptime, paused flag and time, targets and public addresses
+/// as JSON
+fn status_json(state: &SharedState) -> String {
+    let listener = match state.listener_addr() {
+        Some(addr) => format!("\"{addr}\""),
+        None => "null".to_owned(),
+    };
+    let targets: Vec<String> = state
+        .targets()
+        .iter()
+        .map(|target| format!("\"{}\"", target.addr))
+        .collect();
+    let public_addrs: Vec<String> = state
+        .public_addrs()
+        .iter()
+        .map(|mapping| {
+            format!(
+                "{{\"local\":\"{}\",\"public\":\"{}\",\"nat\":\"{}\"}}",
+                mapping.local, mapping.public, mapping.nat
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"listener\":{listener},\"labels\":{},\"uptime_secs\":{:.3},\"paused\":{},\"paused_secs\":{:.3},\"targets\":[{}],\"public_addrs\":[{}]}}\n",
+        json_object(&state.labels()),
+        state.uptime().as_secs_f64(),
+        state.is_paused(),
+        state.paused_time().as_secs_f64(),
+        targets.join(","),
+        public_addrs.join(",")
+    )
+}
+
+/// Render the target groups with their targets and enabled flag as JSON
+fn groups_json(state: &SharedState) -> String {
+    let groups: Vec<String> = state
+        .groups()
+        .iter()
+        .map(|group| {
+            let targets: Vec<String> = group
+                .addrs
+                .iter()
+                .map(|addr| format!("\"{addr}\""))
+                .collect();
+            format!(
+                "{{\"name\":{},\"enabled\":{},\"targets\":[{}]}}",
+                json_string(&group.name),
+                group.enabled,
+                targets.join(",")
+            )
+        })
+        .collect();
+
+    format!("[{}]\n", groups.join(","))
+}
+
+/// Render the labels, received, looped, per-source and per-target counters, jitter,
+/// delay, the rate alert and the stream state as JSON
+///
+/// Sources carry their country and autonomous system if looked up with GeoIP
+/// and their DNS questions by type if inspected.
+fn stats_json(state: &SharedState) -> String {
+    let mut targets = String::new();
+    for (idx, target) in state.targets().iter().enumerate() {
+        if idx > 0 {
+            targets.push(',');
+        }
+        let _ = write!(
+            targets,
+            "{{\"addr\":\"{}\",\"sent_packets\":{},\"sent_bytes\":{},\"send_errors\":{},\
+             \"queue_dropped_packets\":{}}}",
+            target.addr,
+            target.sent.packets(),
+            target.sent.bytes(),
+            target.send_errors.load(Ordering::Relaxed),
+            target.queue_dropped.packets()
+        );
+    }
+
+    let mut sources = String::new();
+    for (idx, source) in state.sources().iter().enumerate() {
+        if idx > 0 {
+            sources.push(',');
+        }
+        let _ = write!(
+            sources,
+            "{{\"addr\":\"{}\",\"received_packets\":{},\"received_bytes\":{}",
+            source.addr,
+            source.received.packets(),
+            source.received.bytes()
+        );
+        if let Some(location) = &source.location {
+            if let Some(country) = &location.country {
+                let _ = write!(sources, ",\"country\":{}", json_string(country));
+            }
+            if let Some(asn) = location.asn {
+                let _ = write!(sources, ",\"asn\":{asn}");
+            }
+            if let Some(as_org) = &location.as_org {
+                let _ = write!(sources, ",\"as_org\":{}", json_string(as_org));
+            }
+        }
+        let dns = source.dns();
+        if !dns.by_type.is_empty() {
+            let by_type: Vec<String> = dns
+                .by_type
+                .iter()
+                .map(|(kind, count)| format!("\"{kind}\":{count}"))
+                .collect();
+            let _ = write!(sources, ",\"dns_questions\":{{{}}}", by_type.join(","));
+        }
+        if let Some(question) = &dns.last {
+            let _ = write!(
+                sources,
+                ",\"last_dns_question\":{}",
+                json_string(&question.name)
+            );
+        }
+        let sequence = &source.sequence;
+        let (gaps, lost, resets, reordered, duplicates) = (
+            sequence.gaps.load(Ordering::Relaxed),
+            sequence.lost.load(Ordering::Relaxed),
+            sequence.resets.load(Ordering::Relaxed),
+            sequence.reordered.load(Ordering::Relaxed),
+            sequence.duplicates.load(Ordering::Relaxed),
+        );
+        if gaps > 0 || resets > 0 || reordered > 0 || duplicates > 0 {
+            let _ = write!(
+                sources,
+                ",\"sequence_gaps\":{gaps},\"sequence_lost\":{lost},\"sequence_resets\":{resets},\
+                 \"reordered_packets\":{reordered},\"duplicate_packets\":{duplicates}"
+            );
+        }
+        sources.push('}');
+    }
+
+    // Gaps and delays of the last complete stats interval in microseconds
+    let jitter = match state.jitter() {
+        Some(jitter) => format!(
+            "{{\"gaps\":{},\"min_us\":{:.1},\"mean_us\":{:.1},\"max_us\":{:.1},\"p99_us\":{:.1}}}",
+            jitter.gaps,
+            micros(jitter.min),
+            micros(jitter.mean),
+            micros(jitter.max),
+            micros(jitter.p99)
+        ),
+        None => "null".to_owned(),
+    };
+    let delay = match state.delay() {
+        Some(delay) => format!(
+            "{{\"packets\":{},\"min_us\":{:.1},\"mean_us\":{:.1},\"max_us\":{:.1},\"variation_us\":{:.1},\"negative\":{}}}",
+            delay.packets,
+            micros(delay.min),
+            micros(delay.mean),
+            micros(delay.max),
+            micros(delay.variation),
+            delay.negative
+        ),
+        None => "null".to_owned(),
+    };
+    let rate_alert = match state.rate_alert() {
+        Some(RateAlert::BelowMin { pps, min_pps }) => {
+            format!("{{\"below_min_pps\":{min_pps},\"pps\":{pps:.1}}}")
+        }
+        Some(RateAlert::AboveMax { pps, max_pps }) => {
+            format!("{{\"above_max_pps\":{max_pps},\"pps\":{pps:.1}}}")
+        }
+        Some(RateAlert::Recovered { .. }) | None => "null".to_owned(),
+    };
+    let stream_state = match state.stream_state() {
+        Some(stream_state) => format!("\"{stream_state}\""),
+        None => "null".to_owned(),
+    };
+
+    format!(
+        "{{\"labels\":{},\"received_packets\":{},\"received_bytes\":{},\"looped_packets\":{},\"expired_packets\":{},\"filtered_packets\":{},\"relayed_packets\":{},\"paused_dropped_packets\":{},\"peers\":{},\"jitter\":{jitter},\"delay\":{delay},\"rate_alert\":{rate_alert},\"stream_state\":{stream_state},\"sources\":[{sources}],\"targets\":[{targets}]}}\n",
+        json_object(&state.labels()),
+        state.received().packets(),
+        state.received().bytes(),
+        state.looped().packets(),
+        state.expired().packets(),
+        state.filtered().packets(),
+        state.relayed().packets(),
+        state.paused_dropped().packets(),
+        state.num_peers()
+    )
+}
+
+/// Render the version, commit and features of the build as members of a JSON object
+fn build_json() -> String {
+    let info = BuildInfo::current();
+    let git_hash = match info.git_hash {
+        Some(git_hash) => json_string(git_hash),
+        None => "null".to_owned(),
+    };
+    let features: Vec<String> = info
+        .features
+        .iter()
+        .map(|feature| format!("\"{feature}\""))
+        .collect();
+    format!(
+        "\"version\":\"{}\",\"git_hash\":{git_hash},\"features\":[{}]",
+        info.version,
+        features.join(",")
+    )
+}
+
+/// Render the build, uptime and hash of the configuration as JSON
+///
+/// The hash is `null` until the setup of the forwarder is published.
+fn info_json(state: &SharedState) -> String {
+    let config_hash = match &*state.setup() {
+        Some(setup) => {
+            let targets: Vec<SocketAddr> =
+                state.targets().iter().map(|target| target.addr).collect();
+            format!("\"{}\"", setup.config_hash(&targets))
+        }
+        None => "null".to_owned(),
+    };
+    format!(
+        "{{{},\"uptime_secs\":{:.3},\"config_hash\":{config_hash}}}\n",
+        build_json(),
+        state.uptime().as_secs_f64()
+    )
+}
+
+/// Render a diagnostics snapshot as JSON
+///
+/// Combines the build, the options and listener socket options of the published setup,
+/// the threads of the process and the documents of `/status` and `/stats`.
+fn diag_json(state: &SharedState) -> String {
+    let (options, listener) = match &*state.setup() {
+        Some(setup) => {
+            let options: Vec<String> = setup
+                .options
+                .iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        Some(value) => json_string(value),
+                        None => "null".to_owned(),
+                    };
+                    format!("{{\"name\":{},\"value\":{value}}}", json_string(name))
+                })
+                .collect();
+            let listener = match &setup.socket {
+                Some(socket) => {
+                    socket_json(&SocketReport::probe(socket, setup.listener_spec.as_ref()))
+                }
+                None => "null".to_owned(),
+            };
+            (format!("[{}]", options.join(",")), listener)
+        }
+        None => ("null".to_owned(), "null".to_owned()),
+    };
+    let threads: Vec<String> = thread_states()
+        .iter()
+        .map(|thread| {
+            format!(
+                "{{\"name\":{},\"state\":\"{}\"}}",
+                json_string(&thread.name),
+                thread.state
+            )
+        })
+        .collect();
+
+    format!(
+        "{{{},\"options\":{options},\"listener\":{listener},\"threads\":[{}],\"status\":{},\"stats\":{}}}\n",
+        build_json(),
+        threads.join(","),
+        status_json(state).trim_end(),
+        stats_json(state).trim_end()
+    )
+}
+
+/// Render the socket options of the listener as JSON
+fn socket_json(report: &SocketReport) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_owned());
+    let groups: Vec<String> = report
+        .groups
+        .iter()
+        .map(|(group, interface)| {
+            format!(
+                "{{\"group\":\"{group}\",\"interface\":{}}}",
+                optional(interface.as_deref().map(json_string))
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"local_addr\":{},\"recv_buffer_size\":{},\"send_buffer_size\":{},\"ttl\":{},\"groups\":[{}]}}",
+        optional(report.local_addr.map(|addr| format!("\"{addr}\""))),
+        optional(report.recv_buffer_size.map(|size| size.to_string())),
+        optional(report.send_buffer_size.map(|size| size.to_string())),
+        optional(report.ttl.map(|ttl| ttl.to_string())),
+        groups.join(",")
+    )
+}
+
+/// Fetch a diagnostics snapshot from the control API served on `addr`
+pub fn fetch_diag(addr: SocketAddr) -> Result<String, io::Error> {
+    let response = http::request(
+        addr,
+        &addr.to_string(),
+        "GET",
+        "/diag",
+        &[],
+        &[],
+        FETCH_TIMEOUT,
+    )?;
+    if response.status != 200 {
+        return Err(io::Error::other(format!(
+            "control API responded with status {}",
+            response.status
+        )));
+    }
+    String::from_utf8(response.body)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "response is not UTF-8"))
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e6
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ListenerSpec, diagnostics::Setup, jitter::JitterStats, silence::StreamState,
+        state::TargetGroup,
+    };
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            query: None,
+            host: None,
+            origin: None,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn add_and_remove_targets() {
+        let state = SharedState::new(None, &["127.0.0.1:4001".parse().unwrap()]);
+
+        let response = handle(&state, &request("POST", "/targets", "127.0.0.1:4002\n"));
+        assert_eq!(201, response.status);
+        assert_eq!(2, state.targets().len());
+
+        let response = handle(&state, &request("DELETE", "/targets/127.0.0.1:4001", ""));
+        assert_eq!(200, response.status);
+        let response = handle(&state, &request("DELETE", "/targets/127.0.0.1:4001", ""));
+        assert_eq!(404, response.status);
+
+        let targets: Vec<_> = state.targets().iter().map(|target| target.addr).collect();
+        assert_eq!(
+            vec!["127.0.0.1:4002".parse::<SocketAddr>().unwrap()],
+            targets
+        );
+    }
+
+    #[test]
+    fn diagnostics_snapshot() {
+        let state = SharedState::new(None, &["127.0.0.1:4001".parse().unwrap()]);
+        let diag = |state: &SharedState| {
+            String::from_utf8(handle(state, &request("GET", "/diag", "")).body).unwrap()
+        };
+        assert!(diag(&state).contains("\"options\":null,\"listener\":null"));
+
+        let listener_spec: ListenerSpec = "127.0.0.1:0".parse().unwrap();
+        let socket = crate::ListenerBuilder::new(&listener_spec).bind().unwrap();
+        socket.set_ttl(4).unwrap();
+        state.set_setup(Setup {
+            options: vec![
+                ("--hub".to_owned(), None),
+                ("--ttl".to_owned(), Some("4".to_owned())),
+            ],
+            listener_spec: Some(listener_spec),
+            socket: Some(socket),
+        });
+        let diag = diag(&state);
+        assert!(diag.contains(
+            "\"options\":[{\"name\":\"--hub\",\"value\":null},{\"name\":\"--ttl\",\"value\":\"4\"}]"
+        ));
+        assert!(diag.contains("\"ttl\":4"));
+        assert!(diag.contains("\"targets\":[\"127.0.0.1:4001\"]"));
+        assert!(diag.contains("\"received_packets\":0"));
+    }
+
+    #[test]
+    fn build_and_config_info() {
+        let state = SharedState::new(None, &["127.0.0.1:4001".parse().unwrap()]);
+        let info = |state: &SharedState| {
+            String::from_utf8(handle(state, &request("GET", "/info", "")).body).unwrap()
+        };
+        let body = info(&state);
+        assert!(body.starts_with(&format!("{{\"version\":\"{}\"", env!("CARGO_PKG_VERSION"))));
+        assert!(body.contains("\"control\""));
+        assert!(body.contains("\"config_hash\":null"));
+
+        state.set_setup(Setup {
+            options: vec![("--hub".to_owned(), None)],
+            listener_spec: Some("127.0.0.1:4000".parse().unwrap()),
+            socket: None,
+        });
+        let hash = state
+            .setup()
+            .as_ref()
+            .unwrap()
+            .config_hash(&["127.0.0.1:4001".parse().unwrap()]);
+        assert!(info(&state).contains(&format!("\"config_hash\":\"{hash}\"")));
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let state = SharedState::new(None, &[]);
+
+        handle(&state, &request("POST", "/pause", ""));
+        assert!(state.is_paused());
+        let response = handle(&state, &request("POST", "/resume", ""));
+        assert!(!state.is_paused());
+        assert!(
+            String::from_utf8(response.body)
+                .unwrap()
+                .contains("\"paused\":false")
+        );
+    }
+
+    #[test]
+    fn disable_and_enable_groups() {
+        let state = SharedState::new(None, &["127.0.0.1:4001".parse().unwrap()]);
+        let mut group = TargetGroup::new("lab".to_owned());
+        group.addrs.push("127.0.0.1:4001".parse().unwrap());
+        state.add_group(group);
+
+        let response = handle(&state, &request("POST", "/groups/lab/disable", ""));
+        assert_eq!(200, response.status);
+        assert!(state.active_targets().is_empty());
+        let response = handle(&state, &request("POST", "/groups/lab/enable", ""));
+        assert!(
+            String::from_utf8(response.body)
+                .unwrap()
+                .contains("\"enabled\":true")
+        );
+        assert_eq!(1, state.active_targets().len());
+
+        assert_eq!(
+            404,
+            handle(&state, &request("POST", "/groups/qa/disable", "")).status
+        );
+        assert_eq!(
+            404,
+            handle(&state, &request("POST", "/groups/lab/toggle", "")).status
+        );
+    }
+
+    #[test]
+    fn invalid_requests_rejected() {
+        let state = SharedState::new(None, &[]);
+
+        assert_eq!(
+            400,
+            handle(&state, &request("POST", "/targets", "nonsense")).status
+        );
+        assert_eq!(405, handle(&state, &request("GET", "/pause", "")).status);
+        assert_eq!(404, handle(&state, &request("GET", "/unknown", "")).status);
+    }
+
+    #[test]
+    fn jitter_in_stats() {
+        let state = SharedState::new(None, &[]);
+        let body = |state: &SharedState| {
+            String::from_utf8(handle(state, &request("GET", "/stats", "")).body).unwrap()
+        };
+        assert!(body(&state).contains("\"jitter\":null,\"delay\":null,\"rate_alert\":null"));
+
+        state.set_jitter(JitterStats {
+            gaps: 9,
+            min: Duration::from_micros(900),
+            mean: Duration::from_millis(1),
+            max: Duration::from_micros(1500),
+            p99: Duration::from_micros(1500),
+        });
+        assert!(body(&state).contains(
+            "\"jitter\":{\"gaps\":9,\"min_us\":900.0,\"mean_us\":1000.0,\"max_us\":1500.0,\"p99_us\":1500.0}"
+        ));
+    }
+
+    #[test]
+    fn alerts_in_stats() {
+        let state = SharedState::new(None, &[]);
+        let body = |state: &SharedState| {
+            String::from_utf8(handle(state, &request("GET", "/stats", "")).body).unwrap()
+        };
+
+        state.set_rate_alert(RateAlert::BelowMin {
+            pps: 0.0,
+            min_pps: 25.0,
+        });
+        assert!(body(&state).contains("\"rate_alert\":{\"below_min_pps\":25,\"pps\":0.0}"));
+
+        state.set_rate_alert(RateAlert::Recovered { pps: 30.0 });
+        assert!(body(&state).contains("\"rate_alert\":null,\"stream_state\":null"));
+
+        state.set_stream_state(StreamState::Silent);
+        assert!(body(&state).contains("\"stream_state\":\"silent\""));
+
+        state.set_labels(vec![
+            ("tenant".to_owned(), "teamA".to_owned()),
+            ("stream".to_owned(), "cam01".to_owned()),
+        ]);
+        assert!(body(&state).starts_with(
+            "{\"labels\":{\"tenant\":\"teamA\",\"stream\":\"cam01\"},\"received_packets\":0"
+        ));
+    }
+
+    #[test]
+    fn dashboard_served() {
+        let state = SharedState::new(None, &[]);
+
+        let response = handle(&state, &request("GET", "/", ""));
+        assert_eq!(200, response.status);
+        assert!(response.content_type.starts_with("text/html"));
+        let page = String::from_utf8(response.body).unwrap();
+        assert!(page.contains("id=\"targets\"") && page.contains("id=\"sources\""));
+    }
+
+    #[test]
+    fn foreign_hosts_and_origins_rejected() {
+        let with = |host: Option<&str>, origin: Option<&str>| Request {
+            host: host.map(str::to_owned),
+            origin: origin.map(str::to_owned),
+            ..request("POST", "/pause", "")
+        };
+        let loopback: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+        assert!(check_host(loopback, &with(Some("127.0.0.1:8080"), None)).is_ok());
+        assert!(check_host(loopback, &with(Some("localhost:8080"), None)).is_ok());
+        assert!(
+            check_host(
+                loopback,
+                &with(Some("127.0.0.1:8080"), Some("http://127.0.0.1:8080"))
+            )
+            .is_ok()
+        );
+        for (host, origin, status) in [
+            (None, None, 421),
+            (Some("attacker.example:8080"), None, 421),
+            (Some("127.0.0.1:8081"), None, 421),
+            (Some("127.0.0.1"), None, 421),
+            (Some("localhost:8080"), Some("http://attacker.example"), 403),
+            (Some("localhost:8080"), Some("null"), 403),
+        ] {
+            assert_eq!(
+                status,
+                check_host(loopback, &with(host, origin))
+                    .unwrap_err()
+                    .status,
+                "{host:?} {origin:?}"
+            );
+        }
+
+        let any: SocketAddr = "[::]:80".parse().unwrap();
+        assert!(check_host(any, &with(Some("[2001:db8::1]"), None)).is_ok());
+        assert!(check_host(any, &with(Some("10.1.1.1:80"), None)).is_ok());
+        assert!(check_host(any, &with(Some("forwarder.example"), None)).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//! Process management
+//!
+//! Running as a classic daemon for init systems without systemd
+//! and dropping privileges after binding privileged ports,
+//! optionally confined to a new root directory.
+//! Only available on Unix, the required C functions are declared here
+//! to stay free of dependencies.
+
+use std::{fs, io, path::Path};
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::{c_char, c_int, c_void};
+
+    /// Leading fields of `struct passwd`, identical on all supported platforms
+    #[repr(C)]
+    pub struct Passwd {
+        pub pw_name: *const c_char,
+        pub pw_passwd: *const c_char,
+        pub pw_uid: u32,
+        pub pw_gid: u32,
+    }
+
+    /// Leading fields of `struct group`
+    #[repr(C)]
+    pub struct Group {
+        pub gr_name: *const c_char,
+        pub gr_passwd: *const c_char,
+        pub gr_gid: u32,
+    }
+
+    unsafe extern "C" {
+        pub fn fork() -> c_int;
+        pub fn setsid() -> c_int;
+        pub fn dup2(old_fd: c_int, new_fd: c_int) -> c_int;
+        pub fn getpwnam(name: *const c_char) -> *const Passwd;
+        pub fn getgrnam(name: *const c_char) -> *const Group;
+        pub fn setgroups(size: usize, list: *const c_void) -> c_int;
+        pub fn setgid(gid: u32) -> c_int;
+        pub fn setuid(uid: u32) -> c_int;
+    }
+}
+
+/// Fork into the background and detach from the terminal
+///
+/// The parent process exits. In the child, stdin is redirected from `/dev/null`
+/// and stdout/stderr are appended to `log_file` or discarded if not given.
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&Path>) -> Result<(), io::Error> {
+    use std::os::fd::AsRawFd;
+
+    // Open files before forking to report errors to the terminal
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    let output = match log_file {
+        Some(path) => fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?,
+        None => null.try_clone()?,
+    };
+
+    // SAFETY: The process is single-threaded at this point,
+    // so the child can safely continue running Rust code.
+    match unsafe { sys::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        _ => std::process::exit(0),
+    }
+
+    // SAFETY: Plain syscall without memory arguments
+    if unsafe { sys::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for (file, fd) in [(&null, 0), (&output, 1), (&output, 2)] {
+        // SAFETY: Both file descriptors are valid and owned by this process
+        if unsafe { sys::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_log_file: Option<&Path>) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "daemonizing is only available on Unix",
+    ))
+}
+
+/// Change the root directory and switch to the given user and group
+///
+/// User and group accept names or numeric IDs. Without a group, the primary group of the user is used.
+/// Supplementary groups are cleared. Names are resolved before changing the root directory,
+/// which usually lacks the user database.
+/// Call this after binding the sockets which require privileges.
+#[cfg(unix)]
+pub fn drop_privileges(
+    user: Option<&str>,
+    group: Option<&str>,
+    chroot_dir: Option<&Path>,
+) -> Result<(), io::Error> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.map(|(_, gid)| gid),
+    };
+
+    if let Some(dir) = chroot_dir {
+        std::os::unix::fs::chroot(dir)?;
+        std::env::set_current_dir("/")?;
+    }
+
+    if let Some(gid) = gid {
+        // SAFETY: An empty list is valid and the pointer is not dereferenced
+        if unsafe { sys::setgroups(0, std::ptr::null()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: Plain syscall without memory arguments
+        if unsafe { sys::setgid(gid) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if let Some((uid, _)) = user {
+        // SAFETY: Plain syscall without memory arguments
+        if unsafe { sys::setuid(uid) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(
+    _user: Option<&str>,
+    _group: Option<&str>,
+    _chroot_dir: Option<&Path>,
+) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "dropping privileges is only available on Unix",
+    ))
+}
+
+/// Look up the user ID and primary group ID of a user name or numeric ID
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(u32, u32), io::Error> {
+    let name = std::ffi::CString::new(user)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid user name"))?;
+
+    // SAFETY: `name` is a valid C string. The result points to static storage
+    // which is only read before any other call to `getpwnam`.
+    let passwd = unsafe { sys::getpwnam(name.as_ptr()) };
+    if !passwd.is_null() {
+        // SAFETY: Non-null results point to a valid `struct passwd`
+        let passwd = unsafe { &*passwd };
+        return Ok((passwd.pw_uid, passwd.pw_gid));
+    }
+
+    match user.parse() {
+        // Numeric IDs without an entry keep their ID as group
+        Ok(uid) => Ok((uid, uid)),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown user {user}"),
+        )),
+    }
+}
+
+/// Look up the group ID of a group name or numeric ID
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<u32, io::Error> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = std::ffi::CString::new(group)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid group name"))?;
+
+    // SAFETY: `name` is a valid C string. The result points to static storage
+    // which is only read before any other call to `getgrnam`.
+    let entry = unsafe { sys::getgrnam(name.as_ptr()) };
+    match entry.is_null() {
+        // SAFETY: Non-null results point to a valid `struct group`
+        false => Ok(unsafe { (*entry).gr_gid }),
+        true => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown group {group}"),
+        )),
+    }
+}
+
+/// Write the ID of the current process to `path`
+pub fn write_pidfile(path: &Path) -> Result<(), io::Error> {
+    fs::write(path, format!("{}\n", std::process::id()))
+}
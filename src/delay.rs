@@ -0,0 +1,193 @@
+//! One-way delay between paired forwarders
+//!
+//! A forwarder relaying to another one prefixes packets with a header carrying the
+//! time it sent them. The receiving forwarder strips the header again and measures
+//! the delay from the send time to the arrival, which shows asymmetric path problems
+//! that a round-trip time hides. The delay is only as accurate as the clocks of both
+//! hosts are synchronized, e.g. with NTP or PTP.
+//!
+//! The header is `UFT` followed by the send time as nanoseconds since the Unix epoch,
+//! eight bytes in network byte order.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+/// Magic bytes identifying the header
+const MAGIC: &[u8; 3] = b"UFT";
+
+/// Role of a forwarder in measuring the one-way delay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum OneWayDelay {
+    /// Prefix forwarded packets with their send time
+    Stamp,
+    /// Strip the send time of received packets and measure their delay
+    Measure,
+}
+
+/// Prefix `packet` with the send time `sent`, writing the result to `out`
+pub(crate) fn stamp(packet: &[u8], sent: SystemTime, out: &mut Vec<u8>) {
+    let nanos = sent
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    out.clear();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&nanos.to_be_bytes());
+    out.extend_from_slice(packet);
+}
+
+/// Send time and payload of a packet with header, `None` for packets without
+pub(crate) fn strip(packet: &[u8]) -> Option<(SystemTime, &[u8])> {
+    let (nanos, payload) = packet.strip_prefix(MAGIC)?.split_first_chunk()?;
+    let sent = SystemTime::UNIX_EPOCH + Duration::from_nanos(u64::from_be_bytes(*nanos));
+    Some((sent, payload))
+}
+
+/// One-way delay of the packets with send time during one stats interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DelayStats {
+    /// Number of packets measured
+    pub packets: u64,
+    pub min: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+    /// Mean difference between the delays of consecutive packets
+    pub variation: Duration,
+    /// Packets which arrived before they were sent, counted with zero delay
+    ///
+    /// Hints at clocks which are not synchronized.
+    pub negative: u64,
+}
+
+impl fmt::Display for DelayStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "delay min {:?} mean {:?} max {:?} variation {:?}",
+            self.min, self.mean, self.max, self.variation
+        )?;
+        if self.negative > 0 {
+            write!(f, ", {} packets before their send time", self.negative)?;
+        }
+        Ok(())
+    }
+}
+
+/// Delays of the packets of the current interval
+#[derive(Debug)]
+pub(crate) struct Delays {
+    /// Delay of the previous packet in nanoseconds
+    last: Option<u64>,
+    packets: u64,
+    sum: u128,
+    /// Number of consecutive pairs of packets and the sum of their differences
+    variations: u64,
+    variation_sum: u128,
+    min: u64,
+    max: u64,
+    negative: u64,
+}
+
+impl Delays {
+    pub(crate) fn new() -> Self {
+        Self {
+            last: None,
+            packets: 0,
+            sum: 0,
+            variations: 0,
+            variation_sum: 0,
+            min: u64::MAX,
+            max: 0,
+            negative: 0,
+        }
+    }
+
+    /// Count the delay of a packet sent at `sent` which arrived at `arrival`
+    pub(crate) fn add(&mut self, sent: SystemTime, arrival: SystemTime) {
+        let delay = match arrival.duration_since(sent) {
+            Ok(delay) => delay.as_nanos().min(u64::MAX as u128) as u64,
+            Err(_) => {
+                self.negative += 1;
+                0
+            }
+        };
+
+        if let Some(last) = self.last.replace(delay) {
+            self.variations += 1;
+            self.variation_sum += last.abs_diff(delay) as u128;
+        }
+        self.packets += 1;
+        self.sum += delay as u128;
+        self.min = self.min.min(delay);
+        self.max = self.max.max(delay);
+    }
+
+    /// Summarize the delays counted so far and start a new interval
+    ///
+    /// Returns `None` if no packet was measured.
+    pub(crate) fn take(&mut self) -> Option<DelayStats> {
+        if self.packets == 0 {
+            return None;
+        }
+
+        let stats = DelayStats {
+            packets: self.packets,
+            min: Duration::from_nanos(self.min),
+            mean: Duration::from_nanos((self.sum / self.packets as u128) as u64),
+            max: Duration::from_nanos(self.max),
+            variation: Duration::from_nanos(
+                self.variation_sum
+                    .checked_div(self.variations as u128)
+                    .unwrap_or_default() as u64,
+            ),
+            negative: self.negative,
+        };
+
+        *self = Self {
+            last: self.last,
+            ..Self::new()
+        };
+        Some(stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stamp_and_strip() {
+        let sent = SystemTime::UNIX_EPOCH + Duration::from_nanos(0x0102_0304_0506_0708);
+        let mut out = Vec::new();
+        stamp(b"data", sent, &mut out);
+        assert_eq!(b"UFT\x01\x02\x03\x04\x05\x06\x07\x08data", out.as_slice());
+
+        assert_eq!(Some((sent, &b"data"[..])), strip(&out));
+        assert_eq!(None, strip(b"data"));
+        assert_eq!(None, strip(b"UFT\x01"));
+    }
+
+    #[test]
+    fn delays_summarized() {
+        let sent = SystemTime::now();
+        let mut delays = Delays::new();
+        for millis in [10, 14, 12] {
+            delays.add(sent, sent + Duration::from_millis(millis));
+        }
+        delays.add(sent + Duration::from_secs(1), sent);
+
+        let stats = delays.take().unwrap();
+        assert_eq!(4, stats.packets);
+        assert_eq!(Duration::ZERO, stats.min);
+        assert_eq!(Duration::from_millis(9), stats.mean);
+        assert_eq!(Duration::from_millis(14), stats.max);
+        assert_eq!(Duration::from_millis(6), stats.variation);
+        assert_eq!(1, stats.negative);
+        assert_eq!(None, delays.take());
+    }
+}
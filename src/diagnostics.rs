@@ -0,0 +1,466 @@
+//! Diagnostics for common setup failures
+//!
+//! Binding ports below 1024 requires privileges which are often missing.
+//! Instead of a bare "permission denied", point users to the available options.
+//!
+//! For bug reports, the [Setup] of a running forwarder is published to its state,
+//! so that the control API can report the options and socket options in effect
+//! along with the [ThreadState]s of the process. The [BuildInfo] and the hash of the
+//! configuration let fleets of forwarders be audited for differing versions and setups.
+
+use std::{
+    fmt::{self, Write},
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+};
+
+use crate::{ListenerSpec, digest::sha1, sandbox};
+
+/// Optional cargo features with whether the crate was built with them
+const FEATURES: &[(&str, bool)] = &[
+    ("async", cfg!(feature = "async")),
+    ("capi", cfg!(feature = "capi")),
+    ("control", cfg!(feature = "control")),
+    ("dpdk", cfg!(feature = "dpdk")),
+    ("encryption", cfg!(feature = "encryption")),
+    ("mio", cfg!(feature = "mio")),
+    ("pcap", cfg!(feature = "pcap")),
+    ("python", cfg!(feature = "python")),
+    ("serde", cfg!(feature = "serde")),
+    ("tokio", cfg!(feature = "tokio")),
+    ("wepoll", cfg!(feature = "wepoll")),
+];
+
+/// Ports below this number are privileged on most systems
+const PRIVILEGED_PORT_LIMIT: u16 = 1024;
+
+/// Linux capabilities relevant for the forwarder with their bit in the capability sets
+const CAPABILITIES: &[(&str, u32)] = &[
+    ("CAP_SETGID", 6),
+    ("CAP_SETUID", 7),
+    ("CAP_NET_BIND_SERVICE", 10),
+    ("CAP_NET_ADMIN", 12),
+    ("CAP_NET_RAW", 13),
+    ("CAP_SYS_CHROOT", 18),
+];
+
+/// Actionable guidance for a failure to bind the given port, if it is a privilege issue
+pub fn bind_error_hint(port: u16, error: &io::Error) -> Option<String> {
+    if error.kind() != io::ErrorKind::PermissionDenied || port >= PRIVILEGED_PORT_LIMIT {
+        return None;
+    }
+
+    let binary = std::env::current_exe()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| "udpforwarder".to_owned());
+
+    Some(format!(
+        "Port {port} is privileged. Options to bind it:\n\
+         \x20 - grant the capability to the binary: sudo setcap cap_net_bind_service=+ep {binary}\n\
+         \x20 - start as root and drop privileges after binding with --user\n\
+         \x20 - let systemd bind the port and use socket activation with the listener `systemd`\n\
+         \x20 - lower the limit (Linux): sysctl net.ipv4.ip_unprivileged_port_start={port}"
+    ))
+}
+
+/// Effective privileges of the current process relevant for forwarding
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapabilityReport {
+    /// Whether the process runs as root
+    pub is_root: bool,
+    /// Relevant capabilities with whether they are effective, empty if unknown
+    pub capabilities: Vec<(&'static str, bool)>,
+    /// First unprivileged port, if known
+    pub unprivileged_port_start: Option<u16>,
+}
+
+impl CapabilityReport {
+    /// Probe the privileges of the current process
+    ///
+    /// Capabilities and the unprivileged port range are only available on Linux.
+    pub fn probe() -> Self {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+
+        let is_root = parse_status_field(&status, "Uid:")
+            .and_then(|uids| uids.split_whitespace().nth(1).map(str::to_owned))
+            .is_some_and(|euid| euid == "0");
+
+        let capabilities = parse_status_field(&status, "CapEff:")
+            .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+            .map(|caps| {
+                CAPABILITIES
+                    .iter()
+                    .map(|(name, bit)| (*name, caps & (1 << bit) != 0))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let unprivileged_port_start =
+            std::fs::read_to_string("/proc/sys/net/ipv4/ip_unprivileged_port_start")
+                .ok()
+                .and_then(|port| port.trim().parse().ok());
+
+        Self {
+            is_root,
+            capabilities,
+            unprivileged_port_start,
+        }
+    }
+
+    /// Check if ports below 1024 can be bound
+    pub fn can_bind_privileged_ports(&self) -> bool {
+        self.is_root
+            || self
+                .capabilities
+                .iter()
+                .any(|(name, effective)| *name == "CAP_NET_BIND_SERVICE" && *effective)
+            || self
+                .unprivileged_port_start
+                .is_some_and(|port| port < PRIVILEGED_PORT_LIMIT)
+    }
+}
+
+impl fmt::Display for CapabilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "root: {}", if self.is_root { "yes" } else { "no" })?;
+        for (name, effective) in &self.capabilities {
+            writeln!(f, "{name}: {}", if *effective { "yes" } else { "no" })?;
+        }
+        if let Some(port) = self.unprivileged_port_start {
+            writeln!(f, "unprivileged ports start at: {port}")?;
+        }
+        write!(
+            f,
+            "can bind ports below 1024: {}",
+            if self.can_bind_privileged_ports() {
+                "yes"
+            } else {
+                "no"
+            }
+        )
+    }
+}
+
+/// Setup of a running forwarder, reported by diagnostics snapshots
+#[derive(Debug, Default)]
+pub struct Setup {
+    /// Options as applied, from the config file first and the command line after
+    pub options: Vec<(String, Option<String>)>,
+    /// Specification the listener was set up from
+    pub listener_spec: Option<ListenerSpec>,
+    /// Duplicate of the listener socket to query the socket options in effect
+    pub socket: Option<UdpSocket>,
+}
+
+impl Setup {
+    /// SHA-1 of the listener, the options and `targets` in hex, equal for forwarders set
+    /// up alike
+    ///
+    /// Targets are sorted, so the order they were added in doesn't matter, but the hash
+    /// changes with targets added and removed at runtime.
+    pub fn config_hash(&self, targets: &[SocketAddr]) -> String {
+        let mut config = String::new();
+        if let Some(listener_spec) = &self.listener_spec {
+            let _ = writeln!(config, "listener {listener_spec}");
+        }
+        for (name, value) in &self.options {
+            let _ = writeln!(config, "{name} {}", value.as_deref().unwrap_or_default());
+        }
+        let mut targets = targets.to_vec();
+        targets.sort();
+        for target in targets {
+            let _ = writeln!(config, "target {target}");
+        }
+
+        sha1(config.as_bytes())
+            .iter()
+            .fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            })
+    }
+}
+
+/// Version, commit and features the crate was built with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Version of the crate
+    pub version: &'static str,
+    /// Abbreviated commit, if built from a git checkout
+    pub git_hash: Option<&'static str>,
+    /// Optional cargo features enabled
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    /// Info of the running build
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("UDPFORWARDER_GIT_HASH"),
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| *name)
+                .collect(),
+        }
+    }
+}
+
+/// Shows the version with the commit and the features
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "udpforwarder {}", self.version)?;
+        if let Some(git_hash) = self.git_hash {
+            write!(f, " ({git_hash})")?;
+        }
+        match self.features.is_empty() {
+            true => write!(f, ", no features"),
+            false => write!(f, ", features: {}", self.features.join(" ")),
+        }
+    }
+}
+
+/// Socket options of the listener in effect, as reported by the kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketReport {
+    /// Address the socket is bound to
+    pub local_addr: Option<SocketAddr>,
+    /// Size of the kernel receive buffer in bytes, only known on Linux
+    pub recv_buffer_size: Option<usize>,
+    /// Size of the kernel send buffer in bytes, only known on Linux
+    pub send_buffer_size: Option<usize>,
+    /// Time to live or unicast hop limit of sent packets
+    pub ttl: Option<u32>,
+    /// Multicast groups joined with the interface, `None` for any interface
+    pub groups: Vec<(IpAddr, Option<String>)>,
+}
+
+impl SocketReport {
+    /// Query the options of `socket`, set up from `listener_spec` if known
+    pub fn probe(socket: &UdpSocket, listener_spec: Option<&ListenerSpec>) -> Self {
+        let local_addr = socket.local_addr().ok();
+        let ttl = match local_addr {
+            Some(SocketAddr::V6(_)) => sockopt_int(socket, SocketOption::HopLimit),
+            _ => socket.ttl().ok(),
+        };
+        let groups = match listener_spec {
+            Some(ListenerSpec::MulticastV4 {
+                multicast_group,
+                local_addr,
+                interfaces,
+                ..
+            }) => joined_groups(
+                IpAddr::V4(*multicast_group.ip()),
+                (!local_addr.is_unspecified()).then(|| local_addr.to_string()),
+                interfaces,
+            ),
+            Some(ListenerSpec::MulticastV6 {
+                multicast_group,
+                interface_id,
+                interfaces,
+                ..
+            }) => joined_groups(
+                IpAddr::V6(*multicast_group.ip()),
+                (*interface_id != 0).then(|| interface_id.to_string()),
+                interfaces,
+            ),
+            _ => Vec::new(),
+        };
+
+        Self {
+            local_addr,
+            recv_buffer_size: sockopt_int(socket, SocketOption::RecvBuffer)
+                .map(|size| size as usize),
+            send_buffer_size: sockopt_int(socket, SocketOption::SendBuffer)
+                .map(|size| size as usize),
+            ttl,
+            groups,
+        }
+    }
+}
+
+/// Group joined on `interface` or on each of `interfaces` if any
+fn joined_groups(
+    group: IpAddr,
+    interface: Option<String>,
+    interfaces: &[String],
+) -> Vec<(IpAddr, Option<String>)> {
+    match interfaces.is_empty() {
+        true => vec![(group, interface)],
+        false => interfaces
+            .iter()
+            .map(|interface| (group, Some(interface.clone())))
+            .collect(),
+    }
+}
+
+/// Integer socket options reported
+enum SocketOption {
+    RecvBuffer,
+    SendBuffer,
+    HopLimit,
+}
+
+#[cfg(target_os = "linux")]
+fn sockopt_int(socket: &UdpSocket, option: SocketOption) -> Option<u32> {
+    use crate::sockopt::{
+        IPPROTO_IPV6, IPV6_UNICAST_HOPS, SO_RCVBUF, SO_SNDBUF, SOL_SOCKET, get_struct,
+    };
+
+    let (level, name) = match option {
+        SocketOption::RecvBuffer => (SOL_SOCKET, SO_RCVBUF),
+        SocketOption::SendBuffer => (SOL_SOCKET, SO_SNDBUF),
+        SocketOption::HopLimit => (IPPROTO_IPV6, IPV6_UNICAST_HOPS),
+    };
+    get_struct::<std::ffi::c_int>(socket, level, name)
+        .ok()
+        .and_then(|value| value.try_into().ok())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sockopt_int(_socket: &UdpSocket, _option: SocketOption) -> Option<u32> {
+    None
+}
+
+/// Name and scheduling state of a thread of the process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadState {
+    /// Name of the thread, e.g. `control` for the control API
+    pub name: String,
+    /// State like `running` or `sleeping`
+    pub state: &'static str,
+}
+
+/// Threads of the current process with their state
+///
+/// Read from `/proc/self/task`, so only available on Linux and empty elsewhere. Also
+/// empty once the seccomp filter forbids reading files.
+pub fn thread_states() -> Vec<ThreadState> {
+    if sandbox::is_seccomp_installed() {
+        return Vec::new();
+    }
+    let Ok(tasks) = std::fs::read_dir("/proc/self/task") else {
+        return Vec::new();
+    };
+    let mut tasks: Vec<(u64, String)> = tasks
+        .filter_map(|task| {
+            let task = task.ok()?;
+            let tid = task.file_name().to_str()?.parse().ok()?;
+            let stat = std::fs::read_to_string(task.path().join("stat")).ok()?;
+            Some((tid, stat))
+        })
+        .collect();
+    tasks.sort();
+
+    tasks
+        .iter()
+        .filter_map(|(_, stat)| parse_task_stat(stat))
+        .collect()
+}
+
+/// Name and state of a `/proc/<pid>/task/<tid>/stat` line
+///
+/// The name is in parentheses and may itself contain spaces and parentheses.
+fn parse_task_stat(stat: &str) -> Option<ThreadState> {
+    let (name, rest) = stat
+        .split_once('(')
+        .and_then(|(_, rest)| rest.rsplit_once(')'))?;
+    let state = match rest.trim_start().chars().next()? {
+        'R' => "running",
+        'S' => "sleeping",
+        'D' => "waiting",
+        'T' | 't' => "stopped",
+        'Z' => "zombie",
+        _ => "unknown",
+    };
+
+    Some(ThreadState {
+        name: name.to_owned(),
+        state,
+    })
+}
+
+/// Find the value of a `Name:\tvalue` line in `/proc/self/status`
+fn parse_status_field<'a>(status: &'a str, name: &str) -> Option<&'a str> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(name))
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_hash_of_setup() {
+        let setup = Setup {
+            options: vec![("--ttl".to_owned(), Some("4".to_owned()))],
+            listener_spec: Some("10.1.1.10:4000".parse().unwrap()),
+            socket: None,
+        };
+        let targets: Vec<SocketAddr> = vec![
+            "10.1.2.1:4000".parse().unwrap(),
+            "10.1.2.2:4000".parse().unwrap(),
+        ];
+        let hash = setup.config_hash(&targets);
+        assert_eq!(40, hash.len());
+        assert_eq!(hash, setup.config_hash(&[targets[1], targets[0]]));
+        assert_ne!(hash, setup.config_hash(&targets[..1]));
+
+        let other = Setup {
+            options: vec![("--ttl".to_owned(), Some("8".to_owned()))],
+            ..setup
+        };
+        assert_ne!(hash, other.config_hash(&targets));
+    }
+
+    #[test]
+    fn socket_options_reported() {
+        let listener_spec: ListenerSpec = "127.0.0.1:0".parse().unwrap();
+        let socket = crate::ListenerBuilder::new(&listener_spec).bind().unwrap();
+        socket.set_ttl(9).unwrap();
+
+        let report = SocketReport::probe(&socket, Some(&listener_spec));
+        assert_eq!(socket.local_addr().ok(), report.local_addr);
+        assert_eq!(Some(9), report.ttl);
+        assert!(report.groups.is_empty());
+        #[cfg(target_os = "linux")]
+        assert!(report.recv_buffer_size.is_some_and(|size| size > 0));
+    }
+
+    #[test]
+    fn thread_states_parsed() {
+        let (name, state) = ("udp (fwd) 2", "S");
+        let stat = format!("42 ({name}) {state} 1 42 42 0 -1");
+        assert_eq!(
+            Some(ThreadState {
+                name: name.to_owned(),
+                state: "sleeping"
+            }),
+            parse_task_stat(&stat)
+        );
+    }
+
+    #[test]
+    fn hint_only_for_denied_privileged_ports() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        let in_use = io::Error::from(io::ErrorKind::AddrInUse);
+
+        assert!(bind_error_hint(514, &denied).is_some());
+        assert!(bind_error_hint(5140, &denied).is_none());
+        assert!(bind_error_hint(514, &in_use).is_none());
+    }
+
+    #[test]
+    fn status_field_ok() {
+        let status = "Name:\tudpforwarder\nCapEff:\t0000000000000400\n";
+
+        assert_eq!(
+            Some("0000000000000400"),
+            parse_status_field(status, "CapEff:")
+        );
+        assert_eq!(None, parse_status_field(status, "CapPrm:"));
+    }
+}
@@ -0,0 +1,180 @@
+//! Message digests for TURN authentication and diagnostics
+//!
+//! TURN long-term credentials derive the key with MD5 and sign each request
+//! with HMAC-SHA1, and diagnostics identify the config file by its SHA-1. All are
+//! implemented here to keep the crate free of dependencies, they are not meant for
+//! anything else. The encryption of SRT relies on the RustCrypto crates instead.
+
+/// MD5 digest of `data` (RFC 1321)
+pub(crate) fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let constants: [u32; 64] =
+        std::array::from_fn(|idx| ((idx as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32);
+
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    for block in pad(data, false).chunks_exact(64) {
+        let words: [u32; 16] = words(block, u32::from_le_bytes);
+        let [mut a, mut b, mut c, mut d] = state;
+
+        for idx in 0..64 {
+            let (f, word) = match idx / 16 {
+                0 => ((b & c) | (!b & d), idx),
+                1 => ((d & b) | (!d & c), (5 * idx + 1) % 16),
+                2 => (b ^ c ^ d, (3 * idx + 5) % 16),
+                _ => (c ^ (b | !d), (7 * idx) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(constants[idx])
+                .wrapping_add(words[word])
+                .rotate_left(SHIFTS[idx / 16 * 4 + idx % 4]);
+            (a, d, c) = (d, c, b);
+            b = b.wrapping_add(rotated);
+        }
+
+        for (value, added) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (chunk, value) in digest.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    digest
+}
+
+/// SHA-1 digest of `data` (RFC 3174)
+pub(crate) fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [
+        0x6745_2301,
+        0xefcd_ab89,
+        0x98ba_dcfe,
+        0x1032_5476,
+        0xc3d2_e1f0,
+    ];
+    for block in pad(data, true).chunks_exact(64) {
+        let block_words: [u32; 16] = words(block, u32::from_be_bytes);
+        let mut words = [0u32; 80];
+        for idx in 0..80 {
+            words[idx] = match idx {
+                0..16 => block_words[idx],
+                _ => (words[idx - 3] ^ words[idx - 8] ^ words[idx - 14] ^ words[idx - 16])
+                    .rotate_left(1),
+            };
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+
+        for (idx, word) in words.iter().enumerate() {
+            let (f, k) = match idx / 20 {
+                0 => ((b & c) | (!b & d), 0x5a82_7999),
+                1 => (b ^ c ^ d, 0x6ed9_eba1),
+                2 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (chunk, value) in digest.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+/// HMAC-SHA1 of `data` with `key` (RFC 2104)
+pub(crate) fn hmac_sha1(key: &[u8], data: &[u8]) -> [u8; 20] {
+    const BLOCK_LEN: usize = 64;
+
+    let mut block_key = [0; BLOCK_LEN];
+    match key.len() > BLOCK_LEN {
+        true => block_key[..20].copy_from_slice(&sha1(key)),
+        false => block_key[..key.len()].copy_from_slice(key),
+    }
+
+    let mut inner: Vec<u8> = block_key.iter().map(|byte| byte ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block_key.iter().map(|byte| byte ^ 0x5c).collect();
+    outer.extend_from_slice(&sha1(&inner));
+
+    sha1(&outer)
+}
+
+/// Pad `data` to whole 64-byte blocks, ending with its length in bits
+///
+/// MD5 stores the length little-endian, SHA-1 big-endian.
+fn pad(data: &[u8], big_endian: bool) -> Vec<u8> {
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    match big_endian {
+        true => padded.extend_from_slice(&bit_len.to_be_bytes()),
+        false => padded.extend_from_slice(&bit_len.to_le_bytes()),
+    }
+    padded
+}
+
+/// Words of a 64-byte block, read with `from_bytes`
+fn words(block: &[u8], from_bytes: fn([u8; 4]) -> u32) -> [u32; 16] {
+    let mut words = [0; 16];
+    let (chunks, _) = block.as_chunks::<4>();
+    for (word, chunk) in words.iter_mut().zip(chunks) {
+        *word = from_bytes(*chunk);
+    }
+    words
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(digest: &[u8]) -> String {
+        digest.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    #[test]
+    fn md5_known_digests() {
+        assert_eq!("d41d8cd98f00b204e9800998ecf8427e", hex(&md5(b"")));
+        assert_eq!(
+            "9e107d9d372bb6826bd81d3542a419d6",
+            hex(&md5(b"The quick brown fox jumps over the lazy dog"))
+        );
+    }
+
+    #[test]
+    fn sha1_known_digests() {
+        assert_eq!("da39a3ee5e6b4b0d3255bfef95601890afd80709", hex(&sha1(b"")));
+        assert_eq!(
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+            hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            ))
+        );
+    }
+
+    #[test]
+    fn hmac_sha1_known_digest() {
+        assert_eq!(
+            "de7c9b85b8b78aa6bc8a7a36f70a90701c9db4d9",
+            hex(&hmac_sha1(
+                b"key",
+                b"The quick brown fox jumps over the lazy dog"
+            ))
+        );
+    }
+}
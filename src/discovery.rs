@@ -0,0 +1,1017 @@
+//! Discovery of targets from service registries
+//!
+//! A thread per registry watches it and keeps the targets of the forwarder in sync
+//! with the instances it lists, so the forwarder follows consumers scaling up and down.
+//! Targets given otherwise, e.g. on the command line, are left alone.
+
+use std::{
+    fmt,
+    hash::{BuildHasher, Hasher, RandomState},
+    io::{self, BufRead, BufReader},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::{Arc, atomic::Ordering},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    base64,
+    dns::{self, QueryType},
+    http,
+    json::Json,
+    state::SharedState,
+    tools::is_timeout,
+};
+
+/// Time to wait before retrying after a failure to query a registry
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Time to wait for a connection and for responses beyond blocking queries
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default port of the Consul HTTP API
+const CONSUL_DEFAULT_PORT: u16 = 8500;
+/// Time Consul holds a blocking query open when nothing changes
+const CONSUL_WAIT: Duration = Duration::from_secs(60);
+
+/// Default port of the etcd client API
+const ETCD_DEFAULT_PORT: u16 = 2379;
+/// Time without events after which the targets are read again and the watch reopened
+const ETCD_RESYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Resolver configuration of the pod
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// Cluster domain if the resolver configuration names none
+const K8S_DEFAULT_CLUSTER_DOMAIN: &str = "cluster.local";
+/// Interval of looking up the pods of a headless service
+const K8S_INTERVAL: Duration = Duration::from_secs(10);
+/// Interval of checking which address of a host name is reachable
+const HOST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Registry to discover targets from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Discovery {
+    /// Healthy instances of a Consul service, given as `consul://host:port/service[?query]`
+    ///
+    /// The query is passed on to the Consul health API, e.g. `tag=udp&dc=eu1`.
+    Consul {
+        /// Consul agent as `host` or `host:port`
+        server: String,
+        /// Name of the service
+        service: String,
+        /// Additional query parameters
+        query: Option<String>,
+    },
+    /// Target list stored in etcd, given as `etcd://host:port/key`
+    ///
+    /// The value of the key holds comma- or whitespace-separated addresses.
+    /// A key ending with `/` is a prefix, whose keys all contribute targets.
+    Etcd {
+        /// etcd server as `host` or `host:port`
+        server: String,
+        /// Key or prefix, starting with `/`
+        key: String,
+    },
+    /// Ready pods of a headless Kubernetes service, given as `k8s:namespace/service:port`
+    ///
+    /// The pods are looked up through the cluster DNS, which lists the address of
+    /// each ready pod for the name of a headless service.
+    Kubernetes {
+        /// Namespace of the service
+        namespace: String,
+        /// Name of the service
+        service: String,
+        /// Port the pods receive on
+        port: u16,
+    },
+    /// Address a host name resolves to, given as `host:port`
+    ///
+    /// Of the IPv6 and IPv4 addresses of the name, the first reachable one is used,
+    /// trying the families in turn like Happy Eyeballs. The choice is checked
+    /// periodically and falls back to the other family when sends keep failing.
+    Host {
+        /// Name of the host
+        host: String,
+        /// Port the host receives on
+        port: u16,
+    },
+}
+
+impl FromStr for Discovery {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(rest) = s.strip_prefix("k8s:") {
+            let (namespace, rest) = rest.split_once('/').ok_or(())?;
+            let (service, port) = rest.rsplit_once(':').ok_or(())?;
+            let valid_name = |name: &str| {
+                !name.is_empty()
+                    && name
+                        .bytes()
+                        .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+            };
+            if !valid_name(namespace) || !valid_name(service) {
+                return Err(());
+            }
+
+            return Ok(Self::Kubernetes {
+                namespace: namespace.to_owned(),
+                service: service.to_owned(),
+                port: port.parse().map_err(|_| ())?,
+            });
+        }
+
+        if let Some(rest) = s.strip_prefix("etcd://") {
+            let idx = rest.find('/').ok_or(())?;
+            let (server, key) = rest.split_at(idx);
+            if server.is_empty() || key.len() < 2 {
+                return Err(());
+            }
+
+            return Ok(Self::Etcd {
+                server: server.to_owned(),
+                key: key.to_owned(),
+            });
+        }
+
+        if let Some(rest) = s.strip_prefix("consul://") {
+            let (rest, query) = match rest.split_once('?') {
+                Some((rest, query)) => (rest, Some(query.to_owned())),
+                None => (rest, None),
+            };
+            let (server, service) = rest.split_once('/').ok_or(())?;
+            if server.is_empty() || service.is_empty() || service.contains('/') {
+                return Err(());
+            }
+
+            return Ok(Self::Consul {
+                server: server.to_owned(),
+                service: service.to_owned(),
+                query,
+            });
+        }
+
+        let (host, port) = s.rsplit_once(':').ok_or(())?;
+        if !is_host_name(host) {
+            return Err(());
+        }
+
+        Ok(Self::Host {
+            host: host.to_owned(),
+            port: port.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+/// Check that `name` is a host name, not an IP address or a typo of one
+///
+/// Top-level domains are never numeric, which rules out addresses like `10.1.1:4000`
+/// that the resolver would accept in their shorthand form.
+fn is_host_name(name: &str) -> bool {
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+    };
+
+    name.len() <= 253
+        && name.split('.').all(valid_label)
+        && name
+            .rsplit('.')
+            .next()
+            .is_some_and(|tld| !tld.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+impl fmt::Display for Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Consul {
+                server, service, ..
+            } => write!(f, "Consul service {service} at {server}"),
+            Self::Etcd { server, key } => write!(f, "etcd key {key} at {server}"),
+            Self::Kubernetes {
+                namespace, service, ..
+            } => write!(f, "Kubernetes service {namespace}/{service}"),
+            Self::Host { host, port } => write!(f, "host {host}:{port}"),
+        }
+    }
+}
+
+/// Watch the registry in a new thread, keeping the targets in `state` in sync
+///
+/// The address of the registry, or of the host, is resolved before returning, so that
+/// a sandbox installed afterwards does not need to permit name resolution.
+pub fn watch(discovery: Discovery, state: Arc<SharedState>) -> Result<JoinHandle<()>, io::Error> {
+    let name = discovery.to_string();
+    let mut watcher: Box<dyn Watcher> = match discovery {
+        Discovery::Consul {
+            server,
+            service,
+            query,
+        } => Box::new(ConsulWatcher {
+            addr: resolve(&server, CONSUL_DEFAULT_PORT)?,
+            server,
+            service,
+            query,
+            index: 0,
+        }),
+        Discovery::Etcd { server, key } => Box::new(EtcdWatcher {
+            addr: resolve(&server, ETCD_DEFAULT_PORT)?,
+            server,
+            range_end: key.ends_with('/').then(|| prefix_end(key.as_bytes())),
+            key: key.into_bytes(),
+            watch: None,
+        }),
+        Discovery::Kubernetes {
+            namespace,
+            service,
+            port,
+        } => Box::new(KubernetesWatcher::new(&namespace, &service, port)?),
+        Discovery::Host { host, port } => {
+            Box::new(HostWatcher::new(host, port, Arc::clone(&state))?)
+        }
+    };
+
+    thread::Builder::new()
+        .name("discovery".to_owned())
+        .spawn(move || {
+            let mut owned = Vec::new();
+            loop {
+                match watcher.next() {
+                    Ok(addrs) => sync(&state, &mut owned, &addrs),
+                    Err(e) => {
+                        state.record_error(format!("failed to watch {name}: {e}"));
+                        thread::sleep(RETRY_INTERVAL);
+                    }
+                }
+            }
+        })
+}
+
+/// Source of targets which may change over time
+trait Watcher: Send {
+    /// Wait until the targets may have changed and return all of them
+    fn next(&mut self) -> Result<Vec<SocketAddr>, io::Error>;
+}
+
+/// Add the targets of `addrs` and remove the ones added before which are no longer listed
+///
+/// `owned` holds the targets added by this watcher. Targets which existed before
+/// are not taken over, so they remain when the registry drops them.
+fn sync(state: &SharedState, owned: &mut Vec<SocketAddr>, addrs: &[SocketAddr]) {
+    owned.retain(|addr| match addrs.contains(addr) {
+        true => true,
+        false => {
+            state.remove_target(*addr);
+            false
+        }
+    });
+
+    for addr in addrs {
+        if !owned.contains(addr) && state.add_target(*addr) {
+            owned.push(*addr);
+        }
+    }
+}
+
+/// Resolve `server`, defaulting to `default_port`
+fn resolve(server: &str, default_port: u16) -> Result<SocketAddr, io::Error> {
+    let mut addrs = match server.to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => (server, default_port).to_socket_addrs()?,
+    };
+
+    addrs
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no address for {server}")))
+}
+
+/// Watches the healthy instances of a Consul service with blocking queries
+struct ConsulWatcher {
+    /// Resolved address of the Consul agent
+    addr: SocketAddr,
+    /// Consul agent as given, sent as `Host`
+    server: String,
+    /// Name of the service
+    service: String,
+    /// Additional query parameters
+    query: Option<String>,
+    /// Index of the last response, blocking until it changes
+    index: u64,
+}
+
+impl Watcher for ConsulWatcher {
+    fn next(&mut self) -> Result<Vec<SocketAddr>, io::Error> {
+        let mut path = format!(
+            "/v1/health/service/{}?passing=1&index={}&wait={}s",
+            self.service,
+            self.index,
+            CONSUL_WAIT.as_secs()
+        );
+        if let Some(query) = &self.query {
+            path.push('&');
+            path.push_str(query);
+        }
+
+        let response = http::request(
+            self.addr,
+            &self.server,
+            "GET",
+            &path,
+            &[],
+            &[],
+            CONSUL_WAIT + TIMEOUT,
+        )?;
+        if response.status != 200 {
+            return Err(io::Error::other(format!(
+                "Consul responded with status {}: {}",
+                response.status,
+                String::from_utf8_lossy(&response.body).trim()
+            )));
+        }
+
+        // Start over if the index went backwards, e.g. after a restore of Consul
+        let index = response
+            .header("x-consul-index")
+            .and_then(|index| index.parse().ok())
+            .unwrap_or(0);
+        self.index = match index < self.index {
+            true => 0,
+            false => index,
+        };
+
+        parse_consul_instances(&response.body)
+    }
+}
+
+/// Parse the addresses of the instances in a response of the Consul health API
+///
+/// Instances without an address of their own are reached at the address of their node.
+fn parse_consul_instances(body: &[u8]) -> Result<Vec<SocketAddr>, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+    let body = std::str::from_utf8(body).map_err(|_| invalid("response is not UTF-8"))?;
+    let json = Json::parse(body).ok_or_else(|| invalid("response is no valid JSON"))?;
+    let entries = json
+        .as_array()
+        .ok_or_else(|| invalid("response is no list of instances"))?;
+
+    let mut addrs = Vec::new();
+    for entry in entries {
+        let service = entry.get("Service");
+        let host = service
+            .and_then(|service| service.get("Address"))
+            .and_then(Json::as_str)
+            .filter(|address| !address.is_empty())
+            .or_else(|| {
+                entry
+                    .get("Node")
+                    .and_then(|node| node.get("Address"))
+                    .and_then(Json::as_str)
+            })
+            .ok_or_else(|| invalid("instance without address"))?;
+        let port = service
+            .and_then(|service| service.get("Port"))
+            .and_then(Json::as_u64)
+            .and_then(|port| u16::try_from(port).ok())
+            .ok_or_else(|| invalid("instance without port"))?;
+
+        let addr = resolve(host, port)?;
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    Ok(addrs)
+}
+
+/// Watches a key or prefix in etcd through its JSON API
+struct EtcdWatcher {
+    /// Resolved address of the etcd server
+    addr: SocketAddr,
+    /// etcd server as given, sent as `Host`
+    server: String,
+    /// Key or start of the prefix
+    key: Vec<u8>,
+    /// End of the prefix, if watching a prefix
+    range_end: Option<Vec<u8>>,
+    /// Open stream of watch events
+    watch: Option<BufReader<TcpStream>>,
+}
+
+impl Watcher for EtcdWatcher {
+    fn next(&mut self) -> Result<Vec<SocketAddr>, io::Error> {
+        // Wait for changes, or read again after reopening the watch
+        if let Some(watch) = &mut self.watch {
+            loop {
+                let mut line = String::new();
+                match watch.read_line(&mut line) {
+                    Ok(0) => {
+                        self.watch = None;
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e) if is_timeout(&e) => {
+                        self.watch = None;
+                        break;
+                    }
+                    Err(e) => {
+                        self.watch = None;
+                        return Err(e);
+                    }
+                }
+
+                let Some(result) = Json::parse(&line).and_then(|json| json.get("result").cloned())
+                else {
+                    continue;
+                };
+                if result.get("canceled") == Some(&Json::Bool(true)) {
+                    self.watch = None;
+                    break;
+                }
+                if result
+                    .get("events")
+                    .and_then(Json::as_array)
+                    .is_some_and(|events| !events.is_empty())
+                {
+                    break;
+                }
+            }
+        }
+
+        let (addrs, revision) = self.range()?;
+        if self.watch.is_none() {
+            self.watch = Some(self.open_watch(revision + 1)?);
+        }
+        Ok(addrs)
+    }
+}
+
+impl EtcdWatcher {
+    /// Read the targets of all keys and the revision they were read at
+    fn range(&self) -> Result<(Vec<SocketAddr>, u64), io::Error> {
+        let response = http::request(
+            self.addr,
+            &self.server,
+            "POST",
+            "/v3/kv/range",
+            &[("Content-Type", "application/json")],
+            self.key_range_json().as_bytes(),
+            TIMEOUT,
+        )?;
+        parse_etcd_range(response.status, &response.body)
+    }
+
+    /// Open a stream of changes of the keys since `revision`
+    fn open_watch(&self, revision: u64) -> Result<BufReader<TcpStream>, io::Error> {
+        let body = format!(
+            "{{\"create_request\":{},\"start_revision\":\"{revision}\"}}}}",
+            self.key_range_json().trim_end_matches('}')
+        );
+        let (response, reader) = http::stream_request(
+            self.addr,
+            &self.server,
+            "POST",
+            "/v3/watch",
+            &[("Content-Type", "application/json")],
+            body.as_bytes(),
+            TIMEOUT,
+        )?;
+        if response.status != 200 {
+            return Err(io::Error::other(format!(
+                "etcd responded with status {}",
+                response.status
+            )));
+        }
+
+        reader
+            .get_ref()
+            .set_read_timeout(Some(ETCD_RESYNC_INTERVAL))?;
+        Ok(reader)
+    }
+
+    /// Key and range end as JSON object
+    fn key_range_json(&self) -> String {
+        match &self.range_end {
+            Some(range_end) => format!(
+                "{{\"key\":\"{}\",\"range_end\":\"{}\"}}",
+                base64::encode(&self.key),
+                base64::encode(range_end)
+            ),
+            None => format!("{{\"key\":\"{}\"}}", base64::encode(&self.key)),
+        }
+    }
+}
+
+/// Parse the targets and revision of a range response of etcd
+fn parse_etcd_range(status: u16, body: &[u8]) -> Result<(Vec<SocketAddr>, u64), io::Error> {
+    let json = etcd_response(status, body)?;
+
+    let revision = json
+        .get("header")
+        .and_then(|header| header.get("revision"))
+        .and_then(etcd_number)
+        .unwrap_or(0);
+    let mut addrs = Vec::new();
+    for kv in json.get("kvs").and_then(Json::as_array).unwrap_or_default() {
+        let value = kv
+            .get("value")
+            .and_then(Json::as_str)
+            .and_then(base64::decode)
+            .unwrap_or_default();
+        let value = String::from_utf8_lossy(&value);
+        for addr in value
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|addr| !addr.is_empty())
+        {
+            let addr = addr.parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid target {addr} in etcd: {e}"),
+                )
+            })?;
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+
+    Ok((addrs, revision))
+}
+
+/// Parse a response of the etcd JSON API
+fn etcd_response(status: u16, body: &[u8]) -> Result<Json, io::Error> {
+    let body = String::from_utf8_lossy(body);
+    if status != 200 {
+        return Err(io::Error::other(format!(
+            "etcd responded with status {status}: {}",
+            body.trim()
+        )));
+    }
+
+    Json::parse(&body)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "etcd response is no valid JSON"))
+}
+
+/// 64-bit number of etcd, which the JSON API encodes as string
+fn etcd_number(json: &Json) -> Option<u64> {
+    match json {
+        Json::String(s) => s.parse().ok(),
+        json => json.as_u64(),
+    }
+}
+
+/// End of the key range starting with `prefix`, i.e. the prefix with its last byte incremented
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+
+    // All keys, the range end `\0`
+    vec![0]
+}
+
+/// Looks up the pods of a headless Kubernetes service through the cluster DNS
+///
+/// Queries are sent directly to the nameserver of the pod, so that lookups keep
+/// working in a sandbox which forbids reading the resolver configuration.
+struct KubernetesWatcher {
+    /// Socket connected to the nameserver
+    socket: UdpSocket,
+    /// Fully qualified name of the service
+    name: String,
+    /// Port the pods receive on
+    port: u16,
+    /// ID of the next query
+    next_id: u16,
+    /// Whether the pods were looked up before
+    looked_up: bool,
+}
+
+impl KubernetesWatcher {
+    /// Read the resolver configuration and connect to the nameserver
+    fn new(namespace: &str, service: &str, port: u16) -> Result<Self, io::Error> {
+        let content = std::fs::read_to_string(RESOLV_CONF)?;
+        let (nameserver, cluster_domain) = parse_resolv_conf(&content);
+        let nameserver = nameserver.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no nameserver in {RESOLV_CONF}"),
+            )
+        })?;
+
+        let bind_addr: SocketAddr = match nameserver {
+            IpAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            IpAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect((nameserver, 53))?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+
+        Ok(Self {
+            socket,
+            name: format!("{service}.{namespace}.svc.{cluster_domain}"),
+            port,
+            // Random IDs make spoofed responses harder to match
+            next_id: RandomState::new().build_hasher().finish() as u16,
+            looked_up: false,
+        })
+    }
+
+    /// Look up the addresses of records of type `kind`
+    fn lookup(&mut self, kind: QueryType) -> Result<Vec<IpAddr>, io::Error> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.socket.send(&dns::build_query(id, &self.name, kind))?;
+
+        let mut buf = vec![0; dns::EDNS_PAYLOAD_SIZE as usize];
+        loop {
+            let len = self.socket.recv(&mut buf)?;
+            match dns::parse_addresses(&buf[..len], id) {
+                Some(Ok(addrs)) => return Ok(addrs),
+                // No ready pods
+                Some(Err(dns::NXDOMAIN)) => return Ok(Vec::new()),
+                Some(Err(rcode)) => {
+                    return Err(io::Error::other(format!(
+                        "nameserver failed to look up {kind} of {} with code {rcode}",
+                        self.name
+                    )));
+                }
+                // Late response to an earlier query
+                None => continue,
+            }
+        }
+    }
+}
+
+impl Watcher for KubernetesWatcher {
+    fn next(&mut self) -> Result<Vec<SocketAddr>, io::Error> {
+        if self.looked_up {
+            thread::sleep(K8S_INTERVAL);
+        }
+        self.looked_up = true;
+
+        let mut addrs = Vec::new();
+        for kind in [QueryType::A, QueryType::AAAA] {
+            addrs.extend(
+                self.lookup(kind)?
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, self.port)),
+            );
+        }
+        Ok(addrs)
+    }
+}
+
+/// Picks the first reachable address of a host name, alternating between the families
+///
+/// The name is resolved once, so that a sandbox installed afterwards does not need to
+/// permit name resolution. Each check probes the addresses in order, moving the target
+/// in use to the end if all sends to it failed since the last check, e.g. because a
+/// broken IPv6 path lost its route.
+struct HostWatcher {
+    /// Name of the host, for errors
+    host: String,
+    /// Addresses of the name, alternating between IPv6 and IPv4
+    candidates: Vec<SocketAddr>,
+    /// State holding the counters of the target in use
+    state: Arc<SharedState>,
+    /// Target in use with its sent packets and failed sends at the last check
+    current: Option<(SocketAddr, u64, u64)>,
+    /// Whether the addresses were checked before
+    checked: bool,
+}
+
+impl HostWatcher {
+    /// Resolve `host`
+    fn new(host: String, port: u16, state: Arc<SharedState>) -> Result<Self, io::Error> {
+        let candidates = interleave_families((host.as_str(), port).to_socket_addrs()?.collect());
+        if candidates.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address for {host}"),
+            ));
+        }
+
+        Ok(Self {
+            host,
+            candidates,
+            state,
+            current: None,
+            checked: false,
+        })
+    }
+
+    /// Packets sent to and failed sends of a target so far
+    fn counters(&self, addr: SocketAddr) -> (u64, u64) {
+        self.state
+            .targets()
+            .iter()
+            .find(|target| target.addr == addr)
+            .map(|target| {
+                (
+                    target.sent.packets(),
+                    target.send_errors.load(Ordering::Relaxed),
+                )
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Watcher for HostWatcher {
+    fn next(&mut self) -> Result<Vec<SocketAddr>, io::Error> {
+        if self.checked {
+            thread::sleep(HOST_INTERVAL);
+        }
+        self.checked = true;
+
+        let failing = self.current.and_then(|(addr, sent, errors)| {
+            let (now_sent, now_errors) = self.counters(addr);
+            (now_sent == sent && now_errors > errors).then_some(addr)
+        });
+        let mut last_error = None;
+        for &addr in self
+            .candidates
+            .iter()
+            .filter(|addr| Some(**addr) != failing)
+            .chain(&failing)
+        {
+            match probe(addr) {
+                Ok(()) => {
+                    let (sent, errors) = self.counters(addr);
+                    self.current = Some((addr, sent, errors));
+                    return Ok(vec![addr]);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        let error = last_error.map(|e| e.to_string()).unwrap_or_default();
+        Err(io::Error::new(
+            io::ErrorKind::HostUnreachable,
+            format!("no address of {} is reachable: {error}", self.host),
+        ))
+    }
+}
+
+/// Order addresses alternating between the families, starting with the family of the first
+///
+/// The resolver sorts the addresses by preference, see RFC 6724, which is kept within
+/// each family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_is_ipv6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    preferred.reverse();
+    other.reverse();
+
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        for addr in [preferred.pop(), other.pop()].into_iter().flatten() {
+            if !interleaved.contains(&addr) {
+                interleaved.push(addr);
+            }
+        }
+    }
+    interleaved
+}
+
+/// Check that the host has a route and a source address to reach `addr`
+///
+/// Connecting a UDP socket sends nothing, yet fails if the family of `addr` is
+/// unusable, e.g. without IPv6 connectivity.
+fn probe(addr: SocketAddr) -> Result<(), io::Error> {
+    let bind_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    UdpSocket::bind(bind_addr)?.connect(addr)
+}
+
+/// Nameserver and cluster domain from the resolver configuration of a pod
+///
+/// Kubernetes lists `<namespace>.svc.<domain>`, `svc.<domain>` and `<domain>`
+/// as search domains, the cluster domain defaults to `cluster.local`.
+fn parse_resolv_conf(content: &str) -> (Option<IpAddr>, String) {
+    let mut nameserver = None;
+    let mut cluster_domain = None;
+
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") if nameserver.is_none() => {
+                nameserver = fields.next().and_then(|addr| addr.parse().ok());
+            }
+            Some("search") => {
+                cluster_domain = fields
+                    .find_map(|domain| domain.strip_prefix("svc."))
+                    .map(|domain| domain.trim_end_matches('.').to_owned());
+            }
+            _ => {}
+        }
+    }
+
+    (
+        nameserver,
+        cluster_domain.unwrap_or_else(|| K8S_DEFAULT_CLUSTER_DOMAIN.to_owned()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn consul_spec_parsed() {
+        let expected = Discovery::Consul {
+            server: "127.0.0.1:8500".to_owned(),
+            service: "consumer".to_owned(),
+            query: Some("tag=udp".to_owned()),
+        };
+
+        assert_eq!(
+            Ok(expected),
+            "consul://127.0.0.1:8500/consumer?tag=udp".parse()
+        );
+        assert!("consul://127.0.0.1:8500".parse::<Discovery>().is_err());
+        assert!("consul:///consumer".parse::<Discovery>().is_err());
+    }
+
+    #[test]
+    fn etcd_spec_parsed() {
+        let expected = Discovery::Etcd {
+            server: "127.0.0.1:2379".to_owned(),
+            key: "/udpforwarder/targets/".to_owned(),
+        };
+
+        assert_eq!(
+            Ok(expected),
+            "etcd://127.0.0.1:2379/udpforwarder/targets/".parse()
+        );
+        assert!("etcd://127.0.0.1:2379/".parse::<Discovery>().is_err());
+        assert_eq!(
+            b"/udpforwarder/targets0",
+            prefix_end(b"/udpforwarder/targets/").as_slice()
+        );
+    }
+
+    #[test]
+    fn kubernetes_spec_parsed() {
+        let expected = Discovery::Kubernetes {
+            namespace: "feeds".to_owned(),
+            service: "consumer".to_owned(),
+            port: 4000,
+        };
+
+        assert_eq!(Ok(expected), "k8s:feeds/consumer:4000".parse());
+        assert!("k8s:feeds/consumer".parse::<Discovery>().is_err());
+        assert!("k8s:feeds/con.sumer:4000".parse::<Discovery>().is_err());
+    }
+
+    #[test]
+    fn host_spec_parsed() {
+        let expected = Discovery::Host {
+            host: "graylog.example.com".to_owned(),
+            port: 12201,
+        };
+
+        assert_eq!(Ok(expected), "graylog.example.com:12201".parse());
+        assert!("graylog.example.com".parse::<Discovery>().is_err());
+        assert!("10.1.1:4000".parse::<Discovery>().is_err());
+        assert!("-graylog:12201".parse::<Discovery>().is_err());
+        assert!("graylog..example.com:12201".parse::<Discovery>().is_err());
+    }
+
+    #[test]
+    fn families_interleaved() {
+        let addrs: Vec<SocketAddr> = ["[2001:db8::1]:4000", "[2001:db8::2]:4000", "10.1.1.1:4000"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+
+        let expected = vec![addrs[0], addrs[2], addrs[1]];
+        assert_eq!(expected, interleave_families(addrs));
+    }
+
+    #[test]
+    fn failing_address_abandoned() {
+        let first: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let second: SocketAddr = "127.0.0.2:4001".parse().unwrap();
+        let state = Arc::new(SharedState::new(None, &[]));
+        let mut watcher = HostWatcher {
+            host: "localhost".to_owned(),
+            candidates: vec![first, second],
+            state: Arc::clone(&state),
+            current: None,
+            checked: false,
+        };
+        let mut owned = Vec::new();
+
+        let addrs = watcher.next().unwrap();
+        assert_eq!(vec![first], addrs);
+        sync(&state, &mut owned, &addrs);
+
+        // Sends failed without any succeeding since the last check
+        state.targets()[0]
+            .send_errors
+            .fetch_add(1, Ordering::Relaxed);
+        watcher.checked = false;
+        let addrs = watcher.next().unwrap();
+        assert_eq!(vec![second], addrs);
+        sync(&state, &mut owned, &addrs);
+
+        // Back to the preferred address once the other one fails as well
+        state.targets()[0]
+            .send_errors
+            .fetch_add(1, Ordering::Relaxed);
+        watcher.checked = false;
+        assert_eq!(vec![first], watcher.next().unwrap());
+    }
+
+    #[test]
+    fn resolv_conf_parsed() {
+        let content = "search feeds.svc.k8s.example svc.k8s.example k8s.example\n\
+            nameserver 10.96.0.10\n\
+            options ndots:5\n";
+
+        assert_eq!(
+            (
+                Some(IpAddr::from([10, 96, 0, 10])),
+                "k8s.example".to_owned()
+            ),
+            parse_resolv_conf(content)
+        );
+        assert_eq!(
+            (None, "cluster.local".to_owned()),
+            parse_resolv_conf("# empty\n")
+        );
+    }
+
+    #[test]
+    fn etcd_range_parsed() {
+        let body = format!(
+            r#"{{"header":{{"revision":"42"}},"kvs":[{{"key":"L2E=","value":"{}"}},{{"key":"L2I=","value":"{}"}}],"count":"2"}}"#,
+            base64::encode(b"10.1.1.11:4000, 10.1.1.12:4000"),
+            base64::encode(b"10.1.1.12:4000\n[::1]:4000\n"),
+        );
+
+        let (addrs, revision) = parse_etcd_range(200, body.as_bytes()).unwrap();
+        assert_eq!(42, revision);
+        assert_eq!(
+            vec![
+                "10.1.1.11:4000".parse::<SocketAddr>().unwrap(),
+                "10.1.1.12:4000".parse().unwrap(),
+                "[::1]:4000".parse().unwrap(),
+            ],
+            addrs
+        );
+
+        // Missing keys are no targets
+        let (addrs, _) = parse_etcd_range(200, br#"{"header":{"revision":7}}"#).unwrap();
+        assert!(addrs.is_empty());
+        assert!(parse_etcd_range(200, br#"{"kvs":[{"value":"eA=="}]}"#).is_err());
+    }
+
+    #[test]
+    fn consul_instances_parsed() {
+        let body = br#"[
+            {"Node": {"Address": "10.1.1.20"}, "Service": {"Address": "", "Port": 4000}},
+            {"Node": {"Address": "10.1.1.21"}, "Service": {"Address": "10.2.2.21", "Port": 4001}}
+        ]"#;
+
+        let expected: Vec<SocketAddr> = vec![
+            "10.1.1.20:4000".parse().unwrap(),
+            "10.2.2.21:4001".parse().unwrap(),
+        ];
+        assert_eq!(expected, parse_consul_instances(body).unwrap());
+    }
+
+    #[test]
+    fn sync_keeps_other_targets() {
+        let static_addr: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let discovered: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+        let state = SharedState::new(None, &[static_addr]);
+        let mut owned = Vec::new();
+
+        sync(&state, &mut owned, &[static_addr, discovered]);
+        assert_eq!(vec![discovered], owned);
+        assert_eq!(2, state.targets().len());
+
+        sync(&state, &mut owned, &[]);
+        let targets: Vec<_> = state.targets().iter().map(|target| target.addr).collect();
+        assert_eq!(vec![static_addr], targets);
+    }
+}
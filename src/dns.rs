@@ -0,0 +1,289 @@
+//! Inspection of relayed DNS traffic
+//!
+//! Decodes the question of DNS messages (RFC 1035), so that a forwarder relaying
+//! port-53 traffic shows which names are looked up by whom. Address lookups for
+//! target discovery are built and decoded here as well.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// Length of the DNS header
+const HEADER_LEN: usize = 12;
+/// Maximum number of compression pointers followed in a name
+const MAX_POINTERS: usize = 16;
+/// Size of responses over UDP advertised with EDNS
+pub(crate) const EDNS_PAYLOAD_SIZE: u16 = 4096;
+/// Response code of a name which does not exist
+pub(crate) const NXDOMAIN: u8 = 3;
+
+/// Question of a DNS message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Question {
+    /// Whether the message is a response
+    pub response: bool,
+    /// Name looked up, without trailing dot
+    pub name: String,
+    /// Type of the record looked up
+    pub kind: QueryType,
+}
+
+impl fmt::Display for Question {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.response {
+            true => "response",
+            false => "query",
+        };
+        write!(f, "{direction} {} {}", self.name, self.kind)
+    }
+}
+
+/// Type of a DNS record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct QueryType(pub u16);
+
+impl QueryType {
+    pub const A: Self = Self(1);
+    pub const AAAA: Self = Self(28);
+    /// Pseudo-record carrying EDNS options
+    const OPT: Self = Self(41);
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.0 {
+            1 => "A",
+            2 => "NS",
+            5 => "CNAME",
+            6 => "SOA",
+            12 => "PTR",
+            15 => "MX",
+            16 => "TXT",
+            28 => "AAAA",
+            33 => "SRV",
+            35 => "NAPTR",
+            43 => "DS",
+            48 => "DNSKEY",
+            64 => "SVCB",
+            65 => "HTTPS",
+            255 => "ANY",
+            257 => "CAA",
+            other => return write!(f, "TYPE{other}"),
+        };
+        f.write_str(name)
+    }
+}
+
+/// Questions of the DNS messages of a source
+#[derive(Debug, Default)]
+pub struct QueryStats {
+    /// Number of questions per type
+    pub by_type: BTreeMap<QueryType, u64>,
+    /// Most recent question asked
+    pub last: Option<Question>,
+}
+
+impl QueryStats {
+    /// Count a question
+    pub(crate) fn add(&mut self, question: Question) {
+        *self.by_type.entry(question.kind).or_default() += 1;
+        self.last = Some(question);
+    }
+}
+
+/// Decode the first question of a DNS message
+///
+/// Returns `None` for packets which are no DNS messages or carry no question.
+pub fn parse_question(packet: &[u8]) -> Option<Question> {
+    let header = packet.get(..HEADER_LEN)?;
+    let response = header[2] & 0x80 != 0;
+    let num_questions = u16::from_be_bytes([header[4], header[5]]);
+    if num_questions == 0 {
+        return None;
+    }
+
+    let (name, offset) = parse_name(packet, HEADER_LEN)?;
+    let kind = packet.get(offset..offset + 2)?;
+
+    Some(Question {
+        response,
+        name,
+        kind: QueryType(u16::from_be_bytes([kind[0], kind[1]])),
+    })
+}
+
+/// Build a recursive query for the records of type `kind` of `name`
+pub(crate) fn build_query(id: u16, name: &str, kind: QueryType) -> Vec<u8> {
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 17);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question and one additional record
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&kind.0.to_be_bytes());
+    query.extend_from_slice(&[0, 1]);
+
+    // OPT record permitting responses larger than 512 bytes
+    query.push(0);
+    query.extend_from_slice(&QueryType::OPT.0.to_be_bytes());
+    query.extend_from_slice(&EDNS_PAYLOAD_SIZE.to_be_bytes());
+    query.extend_from_slice(&[0; 6]);
+    query
+}
+
+/// Decode the addresses in the A and AAAA records of the response to query `id`
+///
+/// Returns `None` for packets which are no complete response to the query.
+/// Responses with an error code are an error, as are truncated ones.
+pub(crate) fn parse_addresses(packet: &[u8], id: u16) -> Option<Result<Vec<IpAddr>, u8>> {
+    let header = packet.get(..HEADER_LEN)?;
+    if header[..2] != id.to_be_bytes() || header[2] & 0x80 == 0 {
+        return None;
+    }
+    // Truncated responses miss records, report them like a server failure
+    if header[2] & 0x02 != 0 {
+        return Some(Err(2));
+    }
+    let rcode = header[3] & 0x0f;
+    if rcode != 0 {
+        return Some(Err(rcode));
+    }
+    let num_questions = u16::from_be_bytes([header[4], header[5]]);
+    let num_answers = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..num_questions {
+        offset = parse_name(packet, offset)?.1 + 4;
+    }
+
+    let mut addrs = Vec::new();
+    for _ in 0..num_answers {
+        offset = parse_name(packet, offset)?.1;
+        let record = packet.get(offset..offset + 10)?;
+        let kind = QueryType(u16::from_be_bytes([record[0], record[1]]));
+        let len = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let data = packet.get(offset + 10..offset + 10 + len)?;
+        offset += 10 + len;
+
+        match (kind, data.len()) {
+            (QueryType::A, 4) => {
+                addrs.push(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(data).ok()?)))
+            }
+            (QueryType::AAAA, 16) => {
+                addrs.push(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?)))
+            }
+            // CNAME records leading to the addresses
+            _ => {}
+        }
+    }
+
+    Some(Ok(addrs))
+}
+
+/// Decode the name at `offset`, returning it with the offset following it
+pub(crate) fn parse_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut num_pointers = 0;
+
+    loop {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => {
+                offset += 1;
+                break;
+            }
+            // Compression pointer to another name
+            0xc0.. => {
+                let low = *packet.get(offset + 1)? as usize;
+                end.get_or_insert(offset + 2);
+                num_pointers += 1;
+                if num_pointers > MAX_POINTERS {
+                    return None;
+                }
+                offset = (len & 0x3f) << 8 | low;
+            }
+            64.. => return None,
+            _ => {
+                let label = packet.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + len;
+            }
+        }
+    }
+
+    Some((labels.join("."), end.unwrap_or(offset)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Query for the AAAA record of example.com
+    const QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+        \x07example\x03com\x00\x00\x1c\x00\x01";
+
+    #[test]
+    fn query_decoded() {
+        let question = parse_question(QUERY).unwrap();
+
+        assert!(!question.response);
+        assert_eq!("example.com", question.name);
+        assert_eq!("query example.com AAAA", question.to_string());
+    }
+
+    #[test]
+    fn compressed_name_decoded() {
+        // Response asking for www plus a pointer to example.com behind the question
+        let packet = b"\x12\x34\x81\x80\x00\x01\x00\x00\x00\x00\x00\x00\
+            \x03www\xc0\x16\x00\x01\x00\x01\x07example\x03com\x00";
+        let question = parse_question(packet).unwrap();
+
+        assert!(question.response);
+        assert_eq!("www.example.com", question.name);
+        assert_eq!(QueryType(1), question.kind);
+    }
+
+    #[test]
+    fn addresses_decoded() {
+        let query = build_query(0x1234, "consumer.feeds.svc.cluster.local", QueryType::A);
+        assert_eq!(
+            "query consumer.feeds.svc.cluster.local A",
+            parse_question(&query).unwrap().to_string()
+        );
+        assert_eq!(None, parse_addresses(&query, 0x1234));
+
+        // Response with two A records pointing at the question name
+        let mut response = query[..query.len() - 11].to_vec();
+        response[2..8].copy_from_slice(&[0x81, 0x80, 0, 1, 0, 2]);
+        response[10..12].copy_from_slice(&[0, 0]);
+        for ip in [[10, 1, 1, 11], [10, 1, 1, 12]] {
+            response.extend_from_slice(b"\xc0\x0c\x00\x01\x00\x01\x00\x00\x00\x05\x00\x04");
+            response.extend_from_slice(&ip);
+        }
+
+        assert_eq!(
+            Some(Ok(vec![
+                IpAddr::from([10, 1, 1, 11]),
+                IpAddr::from([10, 1, 1, 12])
+            ])),
+            parse_addresses(&response, 0x1234)
+        );
+        assert_eq!(None, parse_addresses(&response, 0x4321));
+
+        response[3] = 0x83;
+        assert_eq!(Some(Err(NXDOMAIN)), parse_addresses(&response, 0x1234));
+    }
+
+    #[test]
+    fn non_dns_rejected() {
+        assert_eq!(None, parse_question(b"hello"));
+        assert_eq!(None, parse_question(&[0xff; 40]));
+    }
+}
@@ -0,0 +1,452 @@
+//! Receiving with DPDK
+//!
+//! With the `dpdk` feature, a [DpdkSource] polls a network port driven by a DPDK
+//! poll-mode driver instead of receiving through the network stack of the kernel,
+//! for multi-gigabit multicast feeds kernel sockets can't keep up with. The packets
+//! of the listener flow are forwarded by the usual forwarding logic, everything else
+//! arriving at the port is dropped. Sending to the targets stays on kernel sockets.
+//!
+//! DPDK 21.11 or later is loaded when opening the source, so that the binary runs
+//! without DPDK installed as long as it isn't used. The port has to be bound to a
+//! DPDK-compatible driver like `vfio-pci`, with hugepages set up.
+
+#![cfg(target_os = "linux")]
+
+use std::{
+    ffi::{CStr, CString, c_char, c_int, c_uint, c_void},
+    io,
+    net::SocketAddr,
+    ptr, slice,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ListenerSpec,
+    capture::Flow,
+    source::{Source, copy_truncated, udp_datagram},
+};
+
+const RTLD_NOW: c_int = 2;
+const RTLD_GLOBAL: c_int = 0x100;
+
+/// Packets fetched from the port at once
+const BURST: usize = 32;
+const NUM_MBUFS: c_uint = 8191;
+const MBUF_CACHE_SIZE: c_uint = 250;
+/// `RTE_MBUF_DEFAULT_BUF_SIZE`, room for a standard frame and the headroom
+const MBUF_BUF_SIZE: u16 = 2048 + 128;
+const RX_DESCRIPTORS: u16 = 1024;
+const TX_DESCRIPTORS: u16 = 512;
+/// Upper bound of the size of `struct rte_eth_conf` across DPDK versions
+const ETH_CONF_SIZE: usize = 8192;
+
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// Whether the EAL of DPDK was initialized, which can only happen once per process
+static EAL_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" {
+    fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlerror() -> *const c_char;
+}
+
+/// Start of `struct rte_mbuf` on 64-bit systems
+#[repr(C)]
+struct Mbuf {
+    buf_addr: *mut u8,
+    buf_iova: u64,
+    data_off: u16,
+    refcnt: u16,
+    nb_segs: u16,
+    port: u16,
+    ol_flags: u64,
+    packet_type: u32,
+    pkt_len: u32,
+    data_len: u16,
+}
+
+/// `struct rte_eth_fp_ops`, the fast-path functions of a port behind `rte_eth_rx_burst`
+#[repr(C, align(64))]
+struct FpOps {
+    rxq_data: *mut *mut c_void,
+    rxq_callbacks: *mut *mut c_void,
+    rx_pkt_burst: unsafe extern "C" fn(*mut c_void, *mut *mut Mbuf, u16) -> u16,
+    _rx_reserved: [usize; 5],
+    _tx: [usize; 8],
+}
+
+type PortFn = unsafe extern "C" fn(u16) -> c_int;
+
+/// Functions of the DPDK libraries
+struct Dpdk {
+    eal_init: unsafe extern "C" fn(c_int, *mut *mut c_char) -> c_int,
+    get_port_by_name: unsafe extern "C" fn(*const c_char, *mut u16) -> c_int,
+    socket_id: PortFn,
+    pktmbuf_pool_create:
+        unsafe extern "C" fn(*const c_char, c_uint, c_uint, u16, u16, c_int) -> *mut c_void,
+    pktmbuf_free_bulk: unsafe extern "C" fn(*mut *mut Mbuf, c_uint),
+    configure: unsafe extern "C" fn(u16, u16, u16, *const c_void) -> c_int,
+    rx_queue_setup:
+        unsafe extern "C" fn(u16, u16, u16, c_uint, *const c_void, *mut c_void) -> c_int,
+    tx_queue_setup: unsafe extern "C" fn(u16, u16, u16, c_uint, *const c_void) -> c_int,
+    start: PortFn,
+    stop: PortFn,
+    close: PortFn,
+    promiscuous_enable: PortFn,
+    allmulticast_enable: PortFn,
+    fp_ops: *const FpOps,
+}
+
+impl Dpdk {
+    /// Load the DPDK libraries, preferring the development symlinks
+    fn load() -> Result<Self, io::Error> {
+        for name in ["rte_eal", "rte_mempool", "rte_mbuf", "rte_ethdev"] {
+            let loaded = ["".to_owned()]
+                .into_iter()
+                .chain((22..=26).map(|abi| format!(".{abi}")))
+                .any(|version| {
+                    let Ok(filename) = CString::new(format!("lib{name}.so{version}")) else {
+                        return false;
+                    };
+                    // SAFETY: `filename` is a valid C string
+                    !unsafe { dlopen(filename.as_ptr(), RTLD_NOW | RTLD_GLOBAL) }.is_null()
+                });
+            if !loaded {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("failed to load lib{name}.so: {}", last_dl_error()),
+                ));
+            }
+        }
+
+        // SAFETY: The symbols have the signatures of DPDK 21.11 and later
+        unsafe {
+            Ok(Self {
+                eal_init: symbol(c"rte_eal_init")?,
+                get_port_by_name: symbol(c"rte_eth_dev_get_port_by_name")?,
+                socket_id: symbol(c"rte_eth_dev_socket_id")?,
+                pktmbuf_pool_create: symbol(c"rte_pktmbuf_pool_create")?,
+                pktmbuf_free_bulk: symbol(c"rte_pktmbuf_free_bulk")?,
+                configure: symbol(c"rte_eth_dev_configure")?,
+                rx_queue_setup: symbol(c"rte_eth_rx_queue_setup")?,
+                tx_queue_setup: symbol(c"rte_eth_tx_queue_setup")?,
+                start: symbol(c"rte_eth_dev_start")?,
+                stop: symbol(c"rte_eth_dev_stop")?,
+                close: symbol(c"rte_eth_dev_close")?,
+                promiscuous_enable: symbol(c"rte_eth_promiscuous_enable")?,
+                allmulticast_enable: symbol(c"rte_eth_allmulticast_enable")?,
+                fp_ops: symbol(c"rte_eth_fp_ops")?,
+            })
+        }
+    }
+}
+
+/// Look up `name` in the loaded libraries
+///
+/// # Safety
+///
+/// `T` must be a pointer of the type of the symbol.
+unsafe fn symbol<T>(name: &CStr) -> Result<T, io::Error> {
+    const RTLD_DEFAULT: *mut c_void = ptr::null_mut();
+
+    // SAFETY: `name` is a valid C string
+    let symbol = unsafe { dlsym(RTLD_DEFAULT, name.as_ptr()) };
+    if symbol.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("DPDK lacks {}", name.to_string_lossy()),
+        ));
+    }
+    // SAFETY: A pointer of the type of the symbol as guaranteed by the caller
+    Ok(unsafe { std::mem::transmute_copy(&symbol) })
+}
+
+fn last_dl_error() -> String {
+    // SAFETY: Null or a valid C string until the next call
+    match unsafe { dlerror() } {
+        error if error.is_null() => "unknown error".to_owned(),
+        // SAFETY: Not null
+        error => unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned(),
+    }
+}
+
+/// Turn the negative errno returned by DPDK functions into an error
+fn check(result: c_int, what: &str) -> Result<(), io::Error> {
+    match result {
+        0.. => Ok(()),
+        errno => {
+            let e = io::Error::from_raw_os_error(-errno);
+            Err(io::Error::new(e.kind(), format!("failed to {what}: {e}")))
+        }
+    }
+}
+
+/// Source polling the packets of a listener flow from a DPDK port
+///
+/// Stops and closes the port when dropped.
+pub struct DpdkSource {
+    dpdk: Dpdk,
+    port: u16,
+    flow: Flow,
+    /// Packets of the last burst, the ones from `next` on not handled yet
+    mbufs: [*mut Mbuf; BURST],
+    received: usize,
+    next: usize,
+    timeout: Option<Duration>,
+    nonblocking: bool,
+}
+
+// SAFETY: The port and its packets are only accessed through the source
+unsafe impl Send for DpdkSource {}
+
+impl DpdkSource {
+    /// Initialize DPDK with `eal_args` and receive the packets to `listener_spec` on `port`
+    ///
+    /// The port is named like the device, e.g. its PCI address `0000:01:00.0`. DPDK can
+    /// only be initialized once per process.
+    pub fn open(
+        listener_spec: &ListenerSpec,
+        port: &str,
+        eal_args: &[String],
+    ) -> Result<Self, io::Error> {
+        let Some(flow) = Flow::to_listener(listener_spec) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot receive for a socket passed by systemd",
+            ));
+        };
+        let dpdk = Dpdk::load()?;
+
+        if EAL_INITIALIZED.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "DPDK was initialized before",
+            ));
+        }
+        let args = ["udpforwarder"]
+            .into_iter()
+            .chain(eal_args.iter().map(String::as_str))
+            .map(CString::new)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid EAL argument"))?;
+        // DPDK may reorder the arguments, but keeps the strings
+        let mut argv: Vec<*mut c_char> = args.iter().map(|arg| arg.as_ptr().cast_mut()).collect();
+        // SAFETY: `argv` holds valid C strings which outlive the call
+        if unsafe { (dpdk.eal_init)(argv.len() as c_int, argv.as_mut_ptr()) } < 0 {
+            return Err(io::Error::other("failed to initialize the DPDK EAL"));
+        }
+
+        let name = CString::new(port)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid port"))?;
+        let mut port_id = 0;
+        // SAFETY: Valid C string and port ID to write to
+        let result = unsafe { (dpdk.get_port_by_name)(name.as_ptr(), &mut port_id) };
+        check(result, &format!("find DPDK port {port}"))?;
+        // SAFETY: The port exists
+        let socket_id = unsafe { (dpdk.socket_id)(port_id) };
+
+        // SAFETY: Valid C string, the pool is used for as long as the port
+        let pool = unsafe {
+            (dpdk.pktmbuf_pool_create)(
+                c"udpforwarder".as_ptr(),
+                NUM_MBUFS,
+                MBUF_CACHE_SIZE,
+                0,
+                MBUF_BUF_SIZE,
+                socket_id,
+            )
+        };
+        if pool.is_null() {
+            return Err(io::Error::other("failed to create the DPDK packet pool"));
+        }
+
+        // Default configuration with a single queue each, some drivers need a TX queue
+        let conf = vec![0u8; ETH_CONF_SIZE];
+        // SAFETY: The zeroed configuration is larger than `struct rte_eth_conf`
+        let result = unsafe { (dpdk.configure)(port_id, 1, 1, conf.as_ptr() as *const c_void) };
+        check(result, "configure DPDK port")?;
+        // SAFETY: Configured port and valid pool, default queue configurations
+        let result = unsafe {
+            (dpdk.rx_queue_setup)(
+                port_id,
+                0,
+                RX_DESCRIPTORS,
+                socket_id as c_uint,
+                ptr::null(),
+                pool,
+            )
+        };
+        check(result, "set up DPDK RX queue")?;
+        // SAFETY: Configured port, default queue configuration
+        let result = unsafe {
+            (dpdk.tx_queue_setup)(port_id, 0, TX_DESCRIPTORS, socket_id as c_uint, ptr::null())
+        };
+        check(result, "set up DPDK TX queue")?;
+
+        // SAFETY: Configured port
+        check(unsafe { (dpdk.start)(port_id) }, "start DPDK port")?;
+        // Nothing joins multicast groups on the port, unicast may target other MACs
+        // SAFETY: Started port
+        unsafe {
+            (dpdk.promiscuous_enable)(port_id);
+            (dpdk.allmulticast_enable)(port_id);
+        }
+
+        Ok(Self {
+            dpdk,
+            port: port_id,
+            flow,
+            mbufs: [ptr::null_mut(); BURST],
+            received: 0,
+            next: 0,
+            timeout: None,
+            nonblocking: false,
+        })
+    }
+
+    /// Flow the source receives
+    pub fn flow(&self) -> &Flow {
+        &self.flow
+    }
+
+    /// Fetch the next burst of packets, returning how many arrived
+    fn rx_burst(&mut self) -> usize {
+        // SAFETY: The fast-path functions of the started port, queue 0 is set up
+        let received = unsafe {
+            let ops = &*self.dpdk.fp_ops.add(self.port.into());
+            let queue = *ops.rxq_data;
+            (ops.rx_pkt_burst)(queue, self.mbufs.as_mut_ptr(), BURST as u16)
+        };
+        self.received = received.into();
+        self.next = 0;
+        self.received
+    }
+
+    /// Copy the payload of the next packet of the burst if it belongs to the flow
+    fn handle_next(&mut self, buffer: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let mbuf = self.mbufs[self.next];
+        self.next += 1;
+
+        // SAFETY: A packet of the burst, freed only after copying
+        let frame = unsafe {
+            let mbuf = &*mbuf;
+            slice::from_raw_parts(
+                mbuf.buf_addr.add(mbuf.data_off.into()),
+                mbuf.data_len.into(),
+            )
+        };
+        let received = ip_packet(frame)
+            .and_then(udp_datagram)
+            .filter(|(source, destination, _)| self.flow.matches(*source, *destination))
+            .map(|(source, _, payload)| (copy_truncated(payload, buffer), source));
+
+        let mut mbufs = [mbuf];
+        // SAFETY: Received from the port and not referenced anymore
+        unsafe { (self.dpdk.pktmbuf_free_bulk)(mbufs.as_mut_ptr(), 1) };
+        received
+    }
+}
+
+/// IP packet in an Ethernet frame, with at most one VLAN tag
+fn ip_packet(frame: &[u8]) -> Option<&[u8]> {
+    let mut offset = 12;
+    let ethertype = loop {
+        let ethertype = u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]);
+        match ethertype {
+            ETHERTYPE_VLAN if offset == 12 => offset += 4,
+            ethertype => break ethertype,
+        }
+    };
+    match ethertype {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => frame.get(offset + 2..),
+        _ => None,
+    }
+}
+
+impl Source for DpdkSource {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        let deadline = self
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        loop {
+            while self.next < self.received {
+                if let Some(received) = self.handle_next(buffer) {
+                    return Ok(Some(received));
+                }
+            }
+            if self.rx_burst() > 0 {
+                continue;
+            }
+            // Poll-mode drivers don't wake anyone up, so spin until the timeout
+            if self.nonblocking || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+impl Drop for DpdkSource {
+    fn drop(&mut self) {
+        let pending = &mut self.mbufs[self.next..self.received];
+        // SAFETY: Packets of the burst not freed yet, then the started port
+        unsafe {
+            (self.dpdk.pktmbuf_free_bulk)(pending.as_mut_ptr(), pending.len() as c_uint);
+            (self.dpdk.stop)(self.port);
+            (self.dpdk.close)(self.port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_matched_to_flow() {
+        let flow = Flow::to_listener(&"239.1.1.1:5000".parse().unwrap()).unwrap();
+        let frame = |vlan: bool, port: u8| {
+            let mut frame = vec![0x01, 0x00, 0x5e, 1, 1, 1, 2, 0, 0, 0, 0, 1];
+            if vlan {
+                frame.extend_from_slice(&[0x81, 0x00, 0, 10]);
+            }
+            frame.extend_from_slice(&[0x08, 0x00]);
+            frame.extend_from_slice(&[0x45, 0, 0, 33, 0, 0, 0x40, 0, 64, 17, 0, 0]);
+            frame.extend_from_slice(&[10, 1, 1, 20, 239, 1, 1, 1]);
+            frame.extend_from_slice(&[0x13, 0x88, 0x13, port, 0, 13, 0, 0]);
+            frame.extend_from_slice(b"hello");
+            frame
+        };
+        let matched = |frame: &[u8]| {
+            ip_packet(frame)
+                .and_then(udp_datagram)
+                .filter(|(source, destination, _)| flow.matches(*source, *destination))
+                .map(|(source, _, payload)| (source, payload.to_vec()))
+        };
+
+        let expected = Some(("10.1.1.20:5000".parse().unwrap(), b"hello".to_vec()));
+        assert_eq!(expected, matched(&frame(false, 0x88)));
+        assert_eq!(expected, matched(&frame(true, 0x88)));
+        assert_eq!(None, matched(&frame(false, 0x89)));
+
+        let mut arp = frame(false, 0x88);
+        arp[12..14].copy_from_slice(&[0x08, 0x06]);
+        assert_eq!(None, matched(&arp));
+    }
+}
@@ -0,0 +1,828 @@
+//! Waiting on several sockets at once
+//!
+//! An [EventLoop] forwards for several listeners on a single thread, instead of a
+//! thread per listener, and runs timers such as health checks and stats intervals
+//! in between. It waits until any of their sockets is readable through a [Poller],
+//! which uses epoll on Linux, `poll` on other Unix systems and `WSAPoll` on Windows,
+//! or the IOCP-based epoll emulation of wepoll with the `wepoll` feature. These need
+//! no dependencies and are the default. The opt-in `mio` feature is an alternative
+//! backend on Unix systems, waiting through mio with kqueue on the BSDs and macOS.
+
+use std::{
+    io,
+    net::UdpSocket,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{ForwardError, Forwarder, hooks::DumpTrigger, state::ForwardStats};
+
+/// Readiness notification for reading from sockets
+///
+/// Readiness is level-triggered, a socket is reported for as long as it is readable.
+pub struct Poller {
+    backend: Backend,
+    /// Buffer for the events of a single wait
+    events: Vec<backend::Event>,
+}
+
+impl Poller {
+    pub fn new() -> Result<Self, io::Error> {
+        Ok(Self {
+            backend: Backend::new()?,
+            events: Vec::new(),
+        })
+    }
+
+    /// Report `socket` with `token` once it is readable
+    pub fn register(&mut self, socket: &UdpSocket, token: usize) -> Result<(), io::Error> {
+        self.backend.register(socket, token)?;
+        self.events.push(backend::Event::default());
+        Ok(())
+    }
+
+    /// Stop reporting `socket`
+    pub fn deregister(&mut self, socket: &UdpSocket) -> Result<(), io::Error> {
+        self.backend.deregister(socket)?;
+        self.events.pop();
+        Ok(())
+    }
+
+    /// Wait until a socket is readable or `timeout` passed, collecting the tokens of
+    /// the readable sockets into `ready`
+    ///
+    /// Waits without limit if `timeout` is `None`. Returns with no tokens if interrupted.
+    pub fn wait(
+        &mut self,
+        ready: &mut Vec<usize>,
+        timeout: Option<Duration>,
+    ) -> Result<(), io::Error> {
+        ready.clear();
+        let timeout = match timeout {
+            // Round up, so that waiting doesn't end before the timeout
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        if self.events.is_empty() {
+            self.events.push(backend::Event::default());
+        }
+        match self.backend.wait(&mut self.events, timeout) {
+            Ok(num_events) => {
+                ready.extend(self.events[..num_events].iter().map(backend::Event::token));
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+use backend::Backend;
+
+#[cfg(all(target_os = "linux", not(feature = "mio")))]
+mod backend {
+    use std::{
+        ffi::c_int,
+        io,
+        net::UdpSocket,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        ptr,
+    };
+
+    const EPOLL_CLOEXEC: c_int = 0o2000000;
+    const EPOLL_CTL_ADD: c_int = 1;
+    const EPOLL_CTL_DEL: c_int = 2;
+    const EPOLLIN: u32 = 1;
+
+    /// `struct epoll_event`, packed on x86-64
+    #[repr(C)]
+    #[cfg_attr(target_arch = "x86_64", repr(packed))]
+    #[derive(Clone, Copy, Default)]
+    pub(super) struct Event {
+        events: u32,
+        data: u64,
+    }
+
+    impl Event {
+        pub(super) fn token(&self) -> usize {
+            self.data as usize
+        }
+    }
+
+    unsafe extern "C" {
+        fn epoll_create1(flags: c_int) -> c_int;
+        fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut Event) -> c_int;
+        fn epoll_wait(epfd: c_int, events: *mut Event, max_events: c_int, timeout: c_int) -> c_int;
+    }
+
+    /// Poller backed by epoll
+    pub(super) struct Backend {
+        epoll: OwnedFd,
+    }
+
+    impl Backend {
+        pub(super) fn new() -> Result<Self, io::Error> {
+            // SAFETY: Plain syscall without memory arguments
+            let fd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: `fd` is a new epoll instance owned by nothing else
+            let epoll = unsafe { OwnedFd::from_raw_fd(fd) };
+            Ok(Self { epoll })
+        }
+
+        pub(super) fn register(&self, socket: &UdpSocket, token: usize) -> Result<(), io::Error> {
+            let mut event = Event {
+                events: EPOLLIN,
+                data: token as u64,
+            };
+            // SAFETY: Valid event for the duration of the call
+            let result = unsafe {
+                epoll_ctl(
+                    self.epoll.as_raw_fd(),
+                    EPOLL_CTL_ADD,
+                    socket.as_raw_fd(),
+                    &mut event,
+                )
+            };
+            match result {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }
+
+        pub(super) fn deregister(&self, socket: &UdpSocket) -> Result<(), io::Error> {
+            // SAFETY: No event needed for deleting
+            let result = unsafe {
+                epoll_ctl(
+                    self.epoll.as_raw_fd(),
+                    EPOLL_CTL_DEL,
+                    socket.as_raw_fd(),
+                    ptr::null_mut(),
+                )
+            };
+            match result {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }
+
+        pub(super) fn wait(
+            &self,
+            events: &mut [Event],
+            timeout: c_int,
+        ) -> Result<usize, io::Error> {
+            // SAFETY: The events are valid for writes of their number
+            let result = unsafe {
+                epoll_wait(
+                    self.epoll.as_raw_fd(),
+                    events.as_mut_ptr(),
+                    events.len() as c_int,
+                    timeout,
+                )
+            };
+            match result {
+                -1 => Err(io::Error::last_os_error()),
+                num_events => Ok(num_events as usize),
+            }
+        }
+    }
+}
+
+#[cfg(all(windows, feature = "wepoll"))]
+mod backend {
+    use std::{
+        ffi::c_int,
+        io,
+        net::UdpSocket,
+        os::windows::io::{AsRawSocket, RawSocket},
+        ptr,
+    };
+
+    use wepoll_sys::{
+        EPOLL_CTL_ADD, EPOLL_CTL_DEL, EPOLLIN, HANDLE, epoll_close, epoll_create1, epoll_ctl,
+        epoll_data, epoll_event, epoll_wait,
+    };
+
+    #[repr(transparent)]
+    #[derive(Clone, Copy)]
+    pub(super) struct Event(epoll_event);
+
+    impl Default for Event {
+        fn default() -> Self {
+            Self(epoll_event {
+                events: 0,
+                data: epoll_data { u64: 0 },
+            })
+        }
+    }
+
+    impl Event {
+        pub(super) fn token(&self) -> usize {
+            // SAFETY: Registered with the token as `u64`
+            unsafe { self.0.data.u64 as usize }
+        }
+    }
+
+    /// Poller backed by wepoll, waiting on an I/O completion port
+    pub(super) struct Backend {
+        port: HANDLE,
+    }
+
+    // SAFETY: wepoll ports can be used from any thread
+    unsafe impl Send for Backend {}
+
+    impl Backend {
+        pub(super) fn new() -> Result<Self, io::Error> {
+            // SAFETY: Plain call without memory arguments
+            let port = unsafe { epoll_create1(0) };
+            if port.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self { port })
+        }
+
+        fn ctl(
+            &self,
+            op: u32,
+            socket: RawSocket,
+            event: *mut epoll_event,
+        ) -> Result<(), io::Error> {
+            // SAFETY: Open port and event valid for the duration of the call, if any
+            match unsafe { epoll_ctl(self.port, op as c_int, socket as usize, event) } {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }
+
+        pub(super) fn register(&self, socket: &UdpSocket, token: usize) -> Result<(), io::Error> {
+            let mut event = epoll_event {
+                events: EPOLLIN,
+                data: epoll_data { u64: token as u64 },
+            };
+            self.ctl(EPOLL_CTL_ADD, socket.as_raw_socket(), &mut event)
+        }
+
+        pub(super) fn deregister(&self, socket: &UdpSocket) -> Result<(), io::Error> {
+            self.ctl(EPOLL_CTL_DEL, socket.as_raw_socket(), ptr::null_mut())
+        }
+
+        pub(super) fn wait(
+            &self,
+            events: &mut [Event],
+            timeout: c_int,
+        ) -> Result<usize, io::Error> {
+            // SAFETY: `Event` is a transparent wrapper, valid for writes of their number
+            let result = unsafe {
+                epoll_wait(
+                    self.port,
+                    events.as_mut_ptr() as *mut epoll_event,
+                    events.len() as c_int,
+                    timeout,
+                )
+            };
+            match result {
+                -1 => Err(io::Error::last_os_error()),
+                num_events => Ok(num_events as usize),
+            }
+        }
+    }
+
+    impl Drop for Backend {
+        fn drop(&mut self) {
+            // SAFETY: Created in `new` and not closed before
+            unsafe { epoll_close(self.port) };
+        }
+    }
+}
+
+#[cfg(all(windows, not(feature = "wepoll")))]
+mod backend {
+    use std::{
+        ffi::{c_int, c_uint},
+        io,
+        net::UdpSocket,
+        os::windows::io::AsRawSocket,
+    };
+
+    /// `POLLRDNORM | POLLRDBAND`, `POLLIN` of winsock
+    const POLLIN: i16 = 0x0300;
+    const SOCKET_ERROR: c_int = -1;
+
+    /// `WSAPOLLFD` of a registered socket
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub(super) struct Event {
+        fd: usize,
+        events: i16,
+        revents: i16,
+    }
+
+    #[link(name = "ws2_32")]
+    unsafe extern "system" {
+        fn WSAPoll(fds: *mut Event, nfds: c_uint, timeout: c_int) -> c_int;
+        fn WSAGetLastError() -> c_int;
+    }
+
+    /// Poller backed by `WSAPoll`, scanning all sockets
+    pub(super) struct Backend {
+        fds: Vec<(Event, usize)>,
+        polled: Vec<Event>,
+    }
+
+    impl Event {
+        pub(super) fn token(&self) -> usize {
+            self.fd
+        }
+    }
+
+    impl Backend {
+        pub(super) fn new() -> Result<Self, io::Error> {
+            Ok(Self {
+                fds: Vec::new(),
+                polled: Vec::new(),
+            })
+        }
+
+        pub(super) fn register(
+            &mut self,
+            socket: &UdpSocket,
+            token: usize,
+        ) -> Result<(), io::Error> {
+            let event = Event {
+                fd: socket.as_raw_socket() as usize,
+                events: POLLIN,
+                revents: 0,
+            };
+            self.fds.push((event, token));
+            Ok(())
+        }
+
+        pub(super) fn deregister(&mut self, socket: &UdpSocket) -> Result<(), io::Error> {
+            self.fds
+                .retain(|(event, _)| event.fd != socket.as_raw_socket() as usize);
+            Ok(())
+        }
+
+        pub(super) fn wait(
+            &mut self,
+            events: &mut [Event],
+            timeout: c_int,
+        ) -> Result<usize, io::Error> {
+            self.polled.clear();
+            self.polled.extend(self.fds.iter().map(|(event, _)| *event));
+            // SAFETY: The sockets are valid for their number
+            let result =
+                unsafe { WSAPoll(self.polled.as_mut_ptr(), self.polled.len() as _, timeout) };
+            if result == SOCKET_ERROR {
+                // SAFETY: Plain call without arguments
+                return Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }));
+            }
+
+            // Report tokens in place of the sockets
+            let ready = self
+                .polled
+                .iter()
+                .zip(&self.fds)
+                .filter(|(polled, _)| polled.revents != 0)
+                .map(|(_, (event, token))| Event {
+                    fd: *token,
+                    ..*event
+                });
+            let mut num_events = 0;
+            for (slot, event) in events.iter_mut().zip(ready) {
+                *slot = event;
+                num_events += 1;
+            }
+            Ok(num_events)
+        }
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux"), not(feature = "mio")))]
+mod backend {
+    use std::{
+        ffi::c_int,
+        io,
+        net::UdpSocket,
+        os::fd::{AsRawFd, RawFd},
+    };
+
+    const POLLIN: i16 = 1;
+
+    /// `struct pollfd` of a registered socket
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    pub(super) struct Event {
+        fd: RawFd,
+        events: i16,
+        revents: i16,
+    }
+
+    unsafe extern "C" {
+        fn poll(fds: *mut Event, nfds: std::ffi::c_uint, timeout: c_int) -> c_int;
+    }
+
+    /// Poller backed by `poll`, scanning all sockets
+    pub(super) struct Backend {
+        fds: Vec<(Event, usize)>,
+        polled: Vec<Event>,
+    }
+
+    impl Event {
+        pub(super) fn token(&self) -> usize {
+            self.fd as usize
+        }
+    }
+
+    impl Backend {
+        pub(super) fn new() -> Result<Self, io::Error> {
+            Ok(Self {
+                fds: Vec::new(),
+                polled: Vec::new(),
+            })
+        }
+
+        pub(super) fn register(
+            &mut self,
+            socket: &UdpSocket,
+            token: usize,
+        ) -> Result<(), io::Error> {
+            let event = Event {
+                fd: socket.as_raw_fd(),
+                events: POLLIN,
+                revents: 0,
+            };
+            self.fds.push((event, token));
+            Ok(())
+        }
+
+        pub(super) fn deregister(&mut self, socket: &UdpSocket) -> Result<(), io::Error> {
+            self.fds.retain(|(event, _)| event.fd != socket.as_raw_fd());
+            Ok(())
+        }
+
+        pub(super) fn wait(
+            &mut self,
+            events: &mut [Event],
+            timeout: c_int,
+        ) -> Result<usize, io::Error> {
+            self.polled.clear();
+            self.polled.extend(self.fds.iter().map(|(event, _)| *event));
+            // SAFETY: The descriptors are valid for their number
+            let result = unsafe { poll(self.polled.as_mut_ptr(), self.polled.len() as _, timeout) };
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            // Report tokens in place of the descriptors
+            let ready = self
+                .polled
+                .iter()
+                .zip(&self.fds)
+                .filter(|(polled, _)| polled.revents != 0)
+                .map(|(_, (event, token))| Event {
+                    fd: *token as RawFd,
+                    ..*event
+                });
+            let mut num_events = 0;
+            for (slot, event) in events.iter_mut().zip(ready) {
+                *slot = event;
+                num_events += 1;
+            }
+            Ok(num_events)
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "mio"))]
+mod backend {
+    use std::{
+        ffi::c_int,
+        io,
+        net::UdpSocket,
+        os::fd::{AsRawFd, RawFd},
+        time::Duration,
+    };
+
+    use mio::{Events, Interest, Poll, Token, unix::SourceFd};
+
+    #[derive(Clone, Copy, Default)]
+    pub(super) struct Event(usize);
+
+    impl Event {
+        pub(super) fn token(&self) -> usize {
+            self.0
+        }
+    }
+
+    /// Poller backed by mio, the opt-in alternative to the default backends
+    ///
+    /// mio is edge-triggered. Sockets reported by a wait are registered again before
+    /// the next one, which reports them again while they are still readable.
+    pub(super) struct Backend {
+        poll: Poll,
+        events: Events,
+        /// Registered descriptors and their tokens
+        fds: Vec<(RawFd, usize)>,
+        /// Tokens reported by the last wait
+        reported: Vec<usize>,
+    }
+
+    impl Backend {
+        pub(super) fn new() -> Result<Self, io::Error> {
+            Ok(Self {
+                poll: Poll::new()?,
+                events: Events::with_capacity(0),
+                fds: Vec::new(),
+                reported: Vec::new(),
+            })
+        }
+
+        pub(super) fn register(
+            &mut self,
+            socket: &UdpSocket,
+            token: usize,
+        ) -> Result<(), io::Error> {
+            let fd = socket.as_raw_fd();
+            self.poll
+                .registry()
+                .register(&mut SourceFd(&fd), Token(token), Interest::READABLE)?;
+            self.fds.push((fd, token));
+            Ok(())
+        }
+
+        pub(super) fn deregister(&mut self, socket: &UdpSocket) -> Result<(), io::Error> {
+            let fd = socket.as_raw_fd();
+            self.poll.registry().deregister(&mut SourceFd(&fd))?;
+            self.fds.retain(|(registered, _)| *registered != fd);
+            Ok(())
+        }
+
+        pub(super) fn wait(
+            &mut self,
+            events: &mut [Event],
+            timeout: c_int,
+        ) -> Result<usize, io::Error> {
+            for token in self.reported.drain(..) {
+                if let Some((fd, _)) = self.fds.iter().find(|(_, registered)| *registered == token)
+                {
+                    self.poll.registry().reregister(
+                        &mut SourceFd(fd),
+                        Token(token),
+                        Interest::READABLE,
+                    )?;
+                }
+            }
+            if self.events.capacity() < events.len() {
+                self.events = Events::with_capacity(events.len());
+            }
+
+            let timeout = u64::try_from(timeout).ok().map(Duration::from_millis);
+            self.poll.poll(&mut self.events, timeout)?;
+            let mut num_events = 0;
+            for (slot, event) in events.iter_mut().zip(&self.events) {
+                *slot = Event(event.token().0);
+                self.reported.push(event.token().0);
+                num_events += 1;
+            }
+            Ok(num_events)
+        }
+    }
+}
+
+/// Several forwarders driven from a single thread
+///
+/// Each forwarder needs a socket as source. Keepalives and heartbeats are sent
+/// while waiting, as when running forwarders on threads of their own.
+pub struct EventLoop {
+    poller: Poller,
+    /// Forwarders by token, `None` once their source is exhausted
+    forwarders: Vec<Option<Forwarder>>,
+    timers: Vec<Timer>,
+}
+
+/// Callback run on the thread of the event loop on an interval
+struct Timer {
+    interval: Duration,
+    due: Instant,
+    callback: Box<dyn FnMut() + Send>,
+}
+
+impl EventLoop {
+    pub fn new() -> Result<Self, io::Error> {
+        Ok(Self {
+            poller: Poller::new()?,
+            forwarders: Vec::new(),
+            timers: Vec::new(),
+        })
+    }
+
+    /// Call `callback` every `interval` while running, first after one interval
+    ///
+    /// Callbacks run between forwarding, keep them short. Get the state of forwarders
+    /// to report or check through [Forwarder::state] before adding them.
+    pub fn every(&mut self, interval: Duration, callback: impl FnMut() + Send + 'static) {
+        self.timers.push(Timer {
+            interval,
+            due: Instant::now() + interval,
+            callback: Box::new(callback),
+        });
+    }
+
+    /// Forward for `forwarder` as well
+    pub fn add(&mut self, forwarder: Forwarder) -> Result<(), io::Error> {
+        match forwarder.socket() {
+            Some(socket) => self.poller.register(socket, self.forwarders.len())?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "forwarder without socket as source",
+                ));
+            }
+        }
+        self.forwarders.push(Some(forwarder));
+        Ok(())
+    }
+
+    /// Forward until receiving fails or the sources of all forwarders are exhausted
+    ///
+    /// Returns the statistics of the forwarders in the order they were added. The
+    /// error carries the statistics of the forwarder which failed.
+    pub fn run(self) -> Result<Vec<ForwardStats>, ForwardError> {
+        self.run_until(None)
+    }
+
+    /// Forward like [EventLoop::run] until `stop` is set, if given
+    pub fn run_until(
+        mut self,
+        stop: Option<&AtomicBool>,
+    ) -> Result<Vec<ForwardStats>, ForwardError> {
+        let started = Instant::now();
+        let poll_interval = self
+            .forwarders
+            .iter()
+            .flatten()
+            .filter_map(|forwarder| forwarder.poll_interval(stop.is_some()))
+            .min();
+
+        let mut stats = vec![None; self.forwarders.len()];
+        let mut ready = Vec::new();
+        let mut next_sweep = poll_interval.map(|interval| started + interval);
+        while self.forwarders.iter().any(Option::is_some) {
+            if stop.is_some_and(|stop| stop.load(Ordering::Acquire)) {
+                break;
+            }
+            let deadline = self
+                .timers
+                .iter()
+                .map(|timer| timer.due)
+                .chain(next_sweep)
+                .min();
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            self.poller.wait(&mut ready, timeout)?;
+
+            let now = Instant::now();
+            for timer in self.timers.iter_mut().filter(|timer| timer.due <= now) {
+                (timer.callback)();
+                timer.due = now + timer.interval;
+            }
+            // All forwarders are polled on the interval to send keepalives and heartbeats
+            if let (Some(interval), Some(sweep)) = (poll_interval, next_sweep)
+                && sweep <= now
+            {
+                ready.clear();
+                ready.extend(0..self.forwarders.len());
+                next_sweep = Some(now + interval);
+            }
+            for &token in &ready {
+                let Some(forwarder) = &mut self.forwarders[token] else {
+                    continue;
+                };
+                match forwarder.poll_once() {
+                    Ok(Some(_)) => (),
+                    Ok(None) => {
+                        stats[token] = Some(forwarder.state().forward_stats(started.elapsed()));
+                        if let Some(socket) = forwarder.socket() {
+                            self.poller.deregister(socket)?;
+                        }
+                        self.forwarders[token] = None;
+                    }
+                    Err(error) => {
+                        forwarder.dump_history(DumpTrigger::Failed);
+                        return Err(ForwardError {
+                            error,
+                            stats: Box::new(forwarder.state().forward_stats(started.elapsed())),
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(self
+            .forwarders
+            .iter()
+            .zip(stats)
+            .map(|(forwarder, stats)| match (forwarder, stats) {
+                (_, Some(stats)) => stats,
+                (Some(forwarder), None) => forwarder.state().forward_stats(started.elapsed()),
+                (None, None) => unreachable!("stats kept when removing forwarders"),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, atomic::AtomicUsize},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn readable_sockets_reported() {
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut poller = Poller::new().unwrap();
+        poller.register(&first, 7).unwrap();
+        poller.register(&second, 9).unwrap();
+
+        let mut ready = Vec::new();
+        poller
+            .wait(&mut ready, Some(Duration::from_millis(10)))
+            .unwrap();
+        assert!(ready.is_empty());
+
+        second
+            .send_to(b"hello", second.local_addr().unwrap())
+            .unwrap();
+        poller
+            .wait(&mut ready, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(vec![9], ready);
+
+        // Level-triggered until read
+        poller
+            .wait(&mut ready, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(vec![9], ready);
+        second.recv(&mut [0; 8]).unwrap();
+        poller.deregister(&first).unwrap();
+        poller
+            .wait(&mut ready, Some(Duration::from_millis(10)))
+            .unwrap();
+        assert!(ready.is_empty());
+    }
+
+    #[test]
+    fn forwarders_share_thread() {
+        let targets = [(); 2].map(|_| {
+            let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+            target
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            target
+        });
+        let mut event_loop = EventLoop::new().unwrap();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        {
+            let ticks = Arc::clone(&ticks);
+            event_loop.every(Duration::from_millis(5), move || {
+                ticks.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        let mut listener_addrs = Vec::new();
+        for target in &targets {
+            let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+            listener_addrs.push(listener.local_addr().unwrap());
+            let forwarder =
+                Forwarder::from_source(listener, vec![target.local_addr().unwrap()]).unwrap();
+            event_loop.add(forwarder).unwrap();
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let running = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || event_loop.run_until(Some(&stop)))
+        };
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for (idx, (listener_addr, target)) in listener_addrs.iter().zip(&targets).enumerate() {
+            sender.send_to(&[idx as u8; 4], listener_addr).unwrap();
+            let mut buffer = [0; 8];
+            assert_eq!(4, target.recv(&mut buffer).unwrap());
+            assert_eq!([idx as u8; 4], buffer[..4]);
+        }
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(ticks.load(Ordering::Relaxed) > 1);
+        stop.store(true, Ordering::Release);
+        let stats = running.join().unwrap().unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(2, stats.len());
+        assert!(stats.iter().all(|stats| stats.received.packets == 1));
+    }
+}
@@ -1,82 +1,2264 @@
 //! Forwarding
 
+#[cfg(any(feature = "capi", feature = "python"))]
+use std::thread::JoinHandle;
 use std::{
-    io,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    collections::HashMap,
+    fmt, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::ListenerSpec;
+use crate::{
+    Action, Gelf, Heartbeat, HopLimit, Keepalive, ListenerBuilder, ListenerSpec, Packet,
+    PacketHandler, Rendezvous, Rule, Syslog,
+    alert::{RateAlert, RateLevel, RateThresholds},
+    delay::{self, Delays, OneWayDelay},
+    dns,
+    gelf::Chunker,
+    geoip::GeoIp,
+    handler,
+    history::{PacketHistory, PacketSummary},
+    hooks::{DropReason, DumpTrigger, Hooks, TargetState},
+    hub::Peers,
+    jitter::Arrivals,
+    keepalive, log,
+    queue::TargetQueues,
+    rendezvous,
+    sequence::{SequenceField, Step as SequenceStep, Stream},
+    session::Sessions,
+    shutdown::ShutdownHandle,
+    silence::{SilenceAlarm, StreamState, StreamWatch},
+    sink::Sink,
+    socket::{Network, Socket, SystemNetwork},
+    source::Source as PacketSource,
+    state::{ForwardStats, MAX_SOURCES, SharedState, Source, Target, TargetGroup},
+    stun::{self, Mapping},
+    supervise::{self, RetryPolicy},
+    tools::is_timeout,
+    turn::{Relay, TurnTarget},
+};
 
 /// Forward from a listener to a set of forward addresses
-pub fn forward(listener_spec: ListenerSpec, forward_addrs: &[SocketAddr]) -> Result<(), io::Error> {
-    let listener: UdpSocket = listener_spec.try_into()?;
-    let senders = Senders::for_addresses(forward_addrs)?;
+///
+/// Returns the statistics of the forwarding once the source is exhausted.
+pub fn forward(
+    listener_spec: ListenerSpec,
+    forward_addrs: &[SocketAddr],
+) -> Result<ForwardStats, ForwardError> {
+    Forwarder::new(listener_spec, forward_addrs.to_vec())?.run()
+}
+
+/// Forward from a listener to a set of forward addresses until `shutdown` is shut down
+///
+/// Returns the statistics of the forwarding once stopped or once the source is exhausted.
+pub fn forward_with_shutdown(
+    listener_spec: ListenerSpec,
+    forward_addrs: &[SocketAddr],
+    shutdown: ShutdownHandle,
+) -> Result<ForwardStats, ForwardError> {
+    Forwarder::new(listener_spec, forward_addrs.to_vec())?
+        .with_shutdown(shutdown)
+        .run()
+}
 
-    const MTU: usize = 1500;
-    let mut buffer = [0; MTU];
+/// Forward according to all `rules` concurrently, each on a thread of its own
+///
+/// Every rule binds its own listener and senders, so that failing to bind or to forward
+/// only stops that rule. Returns the results of the rules in order once all stopped.
+pub fn forward_rules(rules: &[Rule]) -> Vec<Result<ForwardStats, ForwardError>> {
+    thread::scope(|scope| {
+        let threads: Vec<_> = rules
+            .iter()
+            .map(|rule| {
+                scope.spawn(|| {
+                    Forwarder::new(rule.listener_spec.clone(), rule.forward_addrs.clone())?.run()
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("forwarding thread panicked").into()))
+            })
+            .collect()
+    })
+}
+
+/// Forward from a listener to a set of forward addresses, passing packets through `handlers`
+///
+/// The handlers are applied in order to every packet before it is forwarded.
+pub fn forward_with_handlers(
+    listener_spec: ListenerSpec,
+    forward_addrs: &[SocketAddr],
+    handlers: Vec<Box<dyn PacketHandler>>,
+) -> Result<ForwardStats, ForwardError> {
+    let mut forwarder = Forwarder::new(listener_spec, forward_addrs.to_vec())?;
+    forwarder.handlers = handlers;
+    forwarder.run()
+}
 
-    loop {
-        let num_bytes = listener.recv(&mut buffer)?;
+/// Set up the listener and senders without forwarding
+///
+/// Surfaces binding errors and failures to join multicast groups.
+pub fn check(listener_spec: ListenerSpec, forward_addrs: &[SocketAddr]) -> Result<(), io::Error> {
+    Forwarder::new(listener_spec, forward_addrs.to_vec()).map(|_| ())
+}
 
-        for forward_addr in forward_addrs {
-            senders.send_to(&buffer[..num_bytes], forward_addr)?;
+/// Error forwarding stopped with, along with what was forwarded until then
+#[derive(Debug)]
+pub struct ForwardError {
+    pub error: io::Error,
+    /// Statistics up to the error, empty if setting up the forwarder failed
+    pub stats: Box<ForwardStats>,
+}
+
+impl fmt::Display for ForwardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for ForwardError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<io::Error> for ForwardError {
+    fn from(error: io::Error) -> Self {
+        Self {
+            error,
+            stats: Box::default(),
         }
     }
 }
 
-/// Set of IPv4/IPv6-bound [UdpSocket]s to use for sending
+impl From<ForwardError> for io::Error {
+    fn from(error: ForwardError) -> Self {
+        error.error
+    }
+}
+
+/// Forwarder with bound sockets, ready to run
+///
+/// Separating setup from running allows to act in between,
+/// for example to drop privileges once privileged ports are bound.
+pub struct Forwarder {
+    /// Source of the packets to forward, usually the listener socket
+    listener: Box<dyn PacketSource>,
+    /// Sockets sending to the forward addresses
+    senders: Senders,
+    /// Send queues of the targets, if each target is sent to through one
+    queues: Option<TargetQueues>,
+    /// Bytes of each packet sent to mirror targets, by address
+    snaplens: HashMap<SocketAddr, usize>,
+    /// Sessions of the clients whose replies are relayed back, if enabled
+    sessions: Option<Sessions>,
+    /// State shared with observers, e.g. the control API
+    state: Arc<SharedState>,
+    /// Handling of the hop-limit header, if any
+    hop_limit: Option<HopLimit>,
+    /// Role in measuring the one-way delay to or from another forwarder, if any
+    one_way_delay: Option<OneWayDelay>,
+    /// Peers to relay between in hub mode
+    hub: Option<Peers>,
+    /// File to checkpoint the hub peers to, if any
+    peers_file: Option<PathBuf>,
+    /// Peer behind NAT reached through a rendezvous server
+    peer: Option<SocketAddr>,
+    /// Relays on TURN servers to targets behind them
+    turn_relays: Vec<Relay>,
+    /// Keepalive sent while the stream is idle, if any
+    keepalive: Option<Keepalive>,
+    /// Heartbeat sent on an interval, if any
+    heartbeat: Option<Heartbeat>,
+    /// Databases to annotate sources with their country and autonomous system
+    geoip: Option<GeoIp>,
+    /// Log every new source
+    verbose: bool,
+    /// Labels starting the logged lines, empty without labels
+    log_prefix: String,
+    /// Decode the questions of DNS messages
+    inspect_dns: bool,
+    /// Position of the sequence number in the payload to follow per source, if any
+    sequence: Option<SequenceField>,
+    /// Re-framing of syslog messages, if any
+    syslog: Option<Syslog>,
+    /// Chunking of oversized GELF messages, if any
+    gelf: Option<Chunker>,
+    /// Chain of custom handlers applied to received packets
+    handlers: Vec<Box<dyn PacketHandler>>,
+    /// Outputs besides the targets
+    sinks: Vec<Box<dyn Sink>>,
+    /// Callbacks observing the forwarding
+    hooks: Hooks,
+    /// Interval to summarize the gaps between packets over
+    stats_interval: Duration,
+    /// Expected envelope of the packet rate of each stats interval, if checked
+    rate_thresholds: Option<RateThresholds>,
+    /// Thresholds of the dead-stream alarm, if enabled
+    silence_alarm: Option<SilenceAlarm>,
+    /// Summaries of the most recent packets, if kept
+    history: Option<PacketHistory>,
+    /// State of the forwarding loop between calls of [Forwarder::poll_once]
+    pump: Option<Pump>,
+    /// Request to stop forwarding, if it can be stopped
+    shutdown: Option<ShutdownHandle>,
+}
+
+/// Forwarder running on a thread of its own until stopped, for language bindings
+#[cfg(any(feature = "capi", feature = "python"))]
+pub(crate) struct Background {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Result<ForwardStats, ForwardError>>,
+}
+
+#[cfg(any(feature = "capi", feature = "python"))]
+impl Background {
+    /// Run `forwarder` on a new thread
+    pub(crate) fn spawn(forwarder: Forwarder) -> Result<Self, io::Error> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("udpforwarder".to_owned())
+                .spawn(move || forwarder.run_until(Some(&stop)))?
+        };
+
+        Ok(Self { stop, thread })
+    }
+
+    /// Stop forwarding and wait for the thread to finish
+    ///
+    /// Returns the error the forwarder stopped with before, if any.
+    pub(crate) fn stop(self) -> Result<ForwardStats, io::Error> {
+        self.stop.store(true, Ordering::Release);
+        let result = self
+            .thread
+            .join()
+            .map_err(|_| io::Error::other("forwarding thread panicked"))?;
+        Ok(result?)
+    }
+}
+
+/// Packets received per call of [Forwarder::poll_once] at most
+const POLL_BATCH: usize = 64;
+/// Interval to summarize the gaps between packets over by default
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(10);
+/// Interval to checkpoint changed hub peers at
+const PEERS_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// State of the forwarding loop kept between receives
+struct Pump {
+    buffer: Vec<u8>,
+    /// Buffer to build the payloads with a hop-limit header in
+    tagged: Vec<u8>,
+    /// Buffer to build the payloads with a send time in
+    stamped: Vec<u8>,
+    /// Copy of the received packet for the handlers to modify
+    handled: Packet,
+    /// Generation of the targets in the shared state
+    generation: u64,
+    targets: Vec<Arc<Target>>,
+    last_forwarded: Instant,
+    last_heartbeat: Instant,
+    /// Sources already published in the state, to count without locking
+    sources: HashMap<SocketAddr, Arc<Source>>,
+    /// Sequences followed for the sources already published
+    streams: HashMap<SocketAddr, Stream>,
+    /// Gaps between the packets of the current stats interval
+    arrivals: Arrivals,
+    /// One-way delay of the packets of the current stats interval
+    delays: Delays,
+    /// Packets received during the current stats interval
+    interval_packets: u64,
+    /// Start of the current stats interval
+    interval_started: Instant,
+    /// Position of the rate of the last complete stats interval relative to the envelope
+    rate_level: RateLevel,
+    /// Whether the stream is active or silent
+    stream_watch: StreamWatch,
+    last_checkpoint: Instant,
+}
+
+/// Outcome of a single receive of the forwarding loop
+enum Step {
+    /// A packet was received, whether forwarded or not
+    Received,
+    /// Nothing arrived in time
+    Idle,
+    /// The source is exhausted
+    Exhausted,
+}
+
+impl Forwarder {
+    /// Bind the listener and senders
+    pub fn new(
+        listener_spec: ListenerSpec,
+        forward_addrs: Vec<SocketAddr>,
+    ) -> Result<Self, io::Error> {
+        let listener = ListenerBuilder::new(&listener_spec).bind()?;
+        Self::from_source(listener, forward_addrs)
+    }
+
+    /// Forward the packets of `source` instead of a listener socket
+    ///
+    /// Sources which are no socket cannot be used in hub mode or for a rendezvous.
+    pub fn from_source(
+        source: impl PacketSource + 'static,
+        forward_addrs: Vec<SocketAddr>,
+    ) -> Result<Self, io::Error> {
+        Self::from_source_and_network(source, forward_addrs, SystemNetwork)
+    }
+
+    /// Forward the packets of `source` with senders bound in `network`
+    ///
+    /// With a [crate::socket::MemoryNetwork], the forwarding can be tested without real sockets.
+    pub fn from_source_and_network(
+        source: impl PacketSource + 'static,
+        forward_addrs: Vec<SocketAddr>,
+        network: impl Network + 'static,
+    ) -> Result<Self, io::Error> {
+        let senders = Senders::for_addresses(Box::new(network), &forward_addrs)?;
+        let listener_addr = source.socket().and_then(|socket| socket.local_addr().ok());
+        let state = SharedState::new(listener_addr, &forward_addrs);
+
+        Ok(Self {
+            listener: Box::new(source),
+            senders,
+            queues: None,
+            snaplens: HashMap::new(),
+            sessions: None,
+            state: Arc::new(state),
+            hop_limit: None,
+            one_way_delay: None,
+            hub: None,
+            peers_file: None,
+            peer: None,
+            turn_relays: Vec::new(),
+            keepalive: None,
+            heartbeat: None,
+            geoip: None,
+            verbose: false,
+            log_prefix: String::new(),
+            inspect_dns: false,
+            sequence: None,
+            syslog: None,
+            gelf: None,
+            handlers: Vec::new(),
+            sinks: Vec::new(),
+            hooks: Hooks::default(),
+            stats_interval: DEFAULT_STATS_INTERVAL,
+            rate_thresholds: None,
+            silence_alarm: None,
+            history: None,
+            pump: None,
+            shutdown: None,
+        })
+    }
+
+    /// Relay packets between all peers sending to the listener
+    ///
+    /// Relayed packets are sent from the listener socket, so peers behind NAT
+    /// receive them from the address they send to. Peers are forgotten after
+    /// not sending anything for `peer_expiry`.
+    pub fn with_hub(mut self, peer_expiry: Duration) -> Self {
+        self.hub = Some(Peers::new(peer_expiry));
+        self
+    }
+
+    /// Relay the replies of the targets back to the clients sending to the listener
+    ///
+    /// Like a NAT, each client gets a socket of its own per target to send from, and
+    /// datagrams the target sends back to it are relayed to the client from the listener
+    /// socket. Sessions expire after `timeout` without packets in either direction.
+    /// Fails if the source is no socket.
+    pub fn with_reply_path(mut self, timeout: Duration) -> Result<Self, io::Error> {
+        let listener = self.listener.socket().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "reply paths require a listener socket",
+            )
+        })?;
+        self.sessions = Some(Sessions::new(timeout, listener.try_clone()?));
+        Ok(self)
+    }
+
+    /// Stop forwarding once `shutdown` is shut down
+    ///
+    /// [Forwarder::run] and [Forwarder::run_supervised] then return the statistics of
+    /// the run within 100ms, after sending what is still queued, instead of forwarding
+    /// until the source fails.
+    pub fn with_shutdown(mut self, shutdown: ShutdownHandle) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Restore the hub peers checkpointed to `path` and keep checkpointing them there
+    ///
+    /// Peers are checkpointed every 10s while they change and once more when forwarding
+    /// stops, so that a restart keeps relaying between established peers. Peers which
+    /// expired in between are not restored. Returns the number of restored peers.
+    pub fn persist_peers(&mut self, path: impl Into<PathBuf>) -> Result<usize, io::Error> {
+        let Some(peers) = &mut self.hub else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only hub peers can be persisted",
+            ));
+        };
+        let path = path.into();
+        let restored = peers.restore(&path)?;
+        self.state.set_num_peers(peers.len());
+        // Create the file right away, e.g. before filesystem access is restricted
+        peers.checkpoint(&path)?;
+        self.peers_file = Some(path);
+
+        Ok(restored)
+    }
+
+    /// Open a direct path to the other forwarder of a session at a rendezvous server
+    ///
+    /// Blocks until the peer punched through or `timeout` passed and returns its address.
+    /// Afterwards, packets are relayed to the peer from the listener socket and packets
+    /// of the peer are forwarded to the targets. In hub mode, the peer joins the hub peers.
+    pub fn rendezvous(
+        &mut self,
+        rendezvous: &Rendezvous,
+        timeout: Duration,
+    ) -> Result<SocketAddr, io::Error> {
+        let socket = self.listener.socket().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "rendezvous needs a socket")
+        })?;
+        let peer = rendezvous::punch(socket, rendezvous, timeout)?;
+        if let Some(peers) = &mut self.hub {
+            peers.seen(peer, Instant::now());
+        }
+        self.peer = Some(peer);
+
+        Ok(peer)
+    }
+
+    /// Forward to a target through a relay allocated on its TURN server
+    ///
+    /// Blocks until the relay is allocated and returns the relayed address,
+    /// which the peer of the target receives packets from.
+    pub fn add_turn_target(
+        &mut self,
+        target: &TurnTarget,
+        timeout: Duration,
+    ) -> Result<SocketAddr, io::Error> {
+        let relay = Relay::allocate(target, timeout, self.state())?;
+        let relayed_addr = relay.relayed_addr();
+        self.turn_relays.push(relay);
+
+        Ok(relayed_addr)
+    }
+
+    /// Discover the public addresses of the senders with the given STUN servers
+    ///
+    /// Each sender asks the servers of its IP family. The results are published in the
+    /// shared state and returned per sender, senders without servers of their family are skipped.
+    pub fn discover_public_addrs(
+        &self,
+        servers: &[SocketAddr],
+        timeout: Duration,
+    ) -> Vec<Result<Mapping, io::Error>> {
+        let results: Vec<_> = [&self.senders.sender_v4, &self.senders.sender_v6]
+            .into_iter()
+            .flatten()
+            .filter_map(|sender| sender.udp_socket())
+            .filter_map(|sender| {
+                let is_ipv4 = sender.local_addr().ok()?.is_ipv4();
+                let servers: Vec<_> = servers
+                    .iter()
+                    .filter(|server| server.is_ipv4() == is_ipv4)
+                    .copied()
+                    .collect();
+                let local_addr = self.senders.source_addr(servers.first()?)?;
+                Some(stun::discover(sender, local_addr, &servers, timeout))
+            })
+            .collect();
+
+        self.state.set_public_addrs(
+            results
+                .iter()
+                .filter_map(|result| result.as_ref().ok())
+                .copied()
+                .collect(),
+        );
+        results
+    }
+
+    /// Send to IPv4 targets without UDP checksums, saving the time to compute them
+    ///
+    /// Receivers cannot detect corrupted payloads anymore, so this is only meant for
+    /// closed networks whose links detect corruption. IPv6 requires checksums, packets
+    /// to IPv6 targets keep them. Only available on Linux.
+    pub fn without_udp_checksums(mut self) -> Result<Self, io::Error> {
+        if !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "disabling UDP checksums is only available on Linux",
+            ));
+        }
+        self.senders.no_checksums = true;
+        for (addr, sender) in self.senders.all() {
+            if addr.is_ipv4() {
+                disable_checksums(sender)?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Mark packets sent to the targets with `mark`, for policy routing or firewall rules
+    ///
+    /// Sets `SO_MARK` on the senders, which requires `CAP_NET_ADMIN`. Only available on
+    /// Linux.
+    pub fn with_fwmark(mut self, mark: u32) -> Result<Self, io::Error> {
+        if !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "marking packets is only available on Linux",
+            ));
+        }
+        self.senders.mark = Some(mark);
+        for (_, sender) in self.senders.all() {
+            set_mark(sender, mark)?;
+        }
+        Ok(self)
+    }
+
+    /// Send to `addr` from within the Linux VRF `vrf`, to bridge streams between VRFs
+    ///
+    /// The target gets its own sender bound to the VRF device with `SO_BINDTODEVICE`,
+    /// so it is routed by the routing table of the VRF. Only available on Linux.
+    pub fn with_target_vrf(
+        mut self,
+        addr: SocketAddr,
+        vrf: impl Into<String>,
+    ) -> Result<Self, io::Error> {
+        if !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "binding to a VRF is only available on Linux",
+            ));
+        }
+        self.senders.vrfs.insert(addr, vrf.into());
+        self.senders.ensure_for(&addr)?;
+        Ok(self)
+    }
+
+    /// Send to each target from a socket of its own through a queue of `capacity` packets
+    ///
+    /// A worker thread per target takes the packets from its queue, so that a slow or
+    /// blocking target doesn't delay the others. Packets which don't fit into the full
+    /// queue of a target are dropped for it and counted in [Target::queue_dropped].
+    pub fn with_target_queues(mut self, capacity: usize) -> Self {
+        self.queues = Some(TargetQueues::new(capacity));
+        self
+    }
+
+    /// Send only the first `snaplen` bytes of each packet to `addr`
+    ///
+    /// Such a mirror target lets monitoring systems observe the headers of a high-bitrate
+    /// stream without receiving its full bandwidth. Keepalives and heartbeats are sent
+    /// complete. Applies to `addr` as well if it is added as target later.
+    pub fn with_mirror(mut self, addr: SocketAddr, snaplen: usize) -> Self {
+        self.snaplens.insert(addr, snaplen);
+        self
+    }
+
+    /// Recognize and maintain the hop-limit header of chained forwarders
+    pub fn with_hop_limit(mut self, hop_limit: HopLimit) -> Self {
+        self.hop_limit = Some(hop_limit);
+        self
+    }
+
+    /// Stamp forwarded packets with their send time or measure the delay of stamped packets
+    ///
+    /// The delay of the last complete stats interval is published in the shared state.
+    pub fn with_one_way_delay(mut self, one_way_delay: OneWayDelay) -> Self {
+        self.one_way_delay = Some(one_way_delay);
+        self
+    }
+
+    /// Send a keepalive to all targets whenever nothing was forwarded for its interval
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Send a heartbeat to all targets on its interval, regardless of traffic
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = Some(heartbeat);
+        self
+    }
+
+    /// Summarize the gaps between packets over `interval` instead of 10s
+    ///
+    /// The summary of the last complete interval is published in the shared state.
+    pub fn with_stats_interval(mut self, interval: Duration) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// Check the packet rate of every stats interval against `thresholds`
+    ///
+    /// Alerts are published in the shared state and reported to the hook set with
+    /// [Forwarder::on_rate_alert] when the rate leaves the envelope and when it returns.
+    /// Idle forwarders wake up to check the rate of intervals without packets.
+    pub fn with_rate_alerts(mut self, thresholds: RateThresholds) -> Self {
+        self.rate_thresholds = Some(thresholds);
+        self
+    }
+
+    /// Track the stream at the listener as active or silent with the thresholds of `alarm`
+    ///
+    /// The stream starts silent. Transitions are published in the shared state and
+    /// reported to the hook set with [Forwarder::on_stream_state_change].
+    pub fn with_silence_alarm(mut self, alarm: SilenceAlarm) -> Self {
+        self.silence_alarm = Some(alarm);
+        self.state.set_stream_state(StreamState::Silent);
+        self
+    }
+
+    /// Keep the time, source, size and first bytes of the last `capacity` packets
+    ///
+    /// They are reported to the hook set with [Forwarder::on_packet_history] when
+    /// forwarding fails or, with the dead-stream alarm, the stream turns silent.
+    pub fn with_packet_history(mut self, capacity: usize) -> Self {
+        self.history = (capacity > 0).then(|| PacketHistory::new(capacity));
+        self
+    }
+
+    /// Annotate sources with their country and autonomous system
+    ///
+    /// Each source is looked up once, when its first packet arrives.
+    pub fn with_geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// Log every new source to stdout, with its location if looked up
+    pub fn with_verbose(mut self) -> Self {
+        self.verbose = true;
+        self
+    }
+
+    /// Attribute the statistics and logged lines to a stream with labels like
+    /// `tenant=teamA`
+    ///
+    /// The labels are published in the shared state and start the lines logged in
+    /// verbose mode, see [log::label_prefix].
+    pub fn with_labels(mut self, labels: Vec<(String, String)>) -> Self {
+        self.log_prefix = log::label_prefix(&labels);
+        self.state.set_labels(labels);
+        self
+    }
+
+    /// Decode the question of every packet as DNS message
+    ///
+    /// Questions are counted per source and logged in verbose mode,
+    /// packets which are no DNS messages are forwarded unnoticed.
+    pub fn with_dns_inspection(mut self) -> Self {
+        self.inspect_dns = true;
+        self
+    }
+
+    /// Follow the sequence number at `field` of the packets of every source
+    ///
+    /// Gaps, resets, reordered and duplicate packets are counted per source and gaps
+    /// and resets logged in verbose mode. Packets too short to carry the field are
+    /// forwarded unnoticed.
+    pub fn with_sequence(mut self, field: SequenceField) -> Self {
+        self.sequence = Some(field);
+        self
+    }
+
+    /// Split datagrams into their syslog messages before forwarding
+    ///
+    /// Each message is forwarded as datagram of its own, with the hostname
+    /// replaced by the address of the source if configured.
+    pub fn with_syslog(mut self, syslog: Syslog) -> Self {
+        self.syslog = Some(syslog);
+        self
+    }
+
+    /// Forward messages larger than the GELF chunk size in GELF chunks
+    ///
+    /// Lets large log events reach Graylog over paths with a smaller MTU.
+    /// Datagrams up to the maximum UDP payload are received in this mode.
+    pub fn with_gelf_chunking(mut self, gelf: Gelf) -> Self {
+        self.gelf = Some(Chunker::new(gelf));
+        self
+    }
+
+    /// Append a handler to the chain filtering and transforming received packets
+    ///
+    /// Handlers run in the order they were added, after loop detection and
+    /// source tracking and before the packet is re-framed and forwarded.
+    /// Packets dropped by a handler are counted as filtered.
+    pub fn with_handler(mut self, handler: impl PacketHandler + 'static) -> Self {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Also deliver forwarded packets to `sink`
+    ///
+    /// Sinks receive every packet sent to the targets, after re-framing and chunking.
+    /// Keepalives and heartbeats are not delivered to sinks.
+    pub fn with_sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Call `hook` with the source and payload of every received packet
+    ///
+    /// Runs before the packet is checked and handled, so it also sees packets
+    /// which are dropped afterwards.
+    pub fn on_packet(mut self, hook: impl FnMut(SocketAddr, &[u8]) + Send + 'static) -> Self {
+        self.hooks.packet = Some(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` with the destination and the error whenever sending fails
+    ///
+    /// Covers targets, TURN relays, hub peers and the rendezvous peer.
+    pub fn on_send_error(
+        mut self,
+        hook: impl FnMut(SocketAddr, &io::Error) + Send + 'static,
+    ) -> Self {
+        self.hooks.send_error = Some(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` with the source, size and reason of every packet which is not forwarded
+    pub fn on_drop(
+        mut self,
+        hook: impl FnMut(SocketAddr, usize, DropReason) + Send + 'static,
+    ) -> Self {
+        self.hooks.drop = Some(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` whenever the packet rate leaves or returns into the expected envelope
+    ///
+    /// Only called with rate alerts enabled through [Forwarder::with_rate_alerts].
+    pub fn on_rate_alert(mut self, hook: impl FnMut(RateAlert) + Send + 'static) -> Self {
+        self.hooks.rate_alert = Some(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` whenever the stream turns active or silent
+    ///
+    /// Only called with the dead-stream alarm enabled through [Forwarder::with_silence_alarm].
+    pub fn on_stream_state_change(
+        mut self,
+        hook: impl FnMut(StreamState) + Send + 'static,
+    ) -> Self {
+        self.hooks.stream_state = Some(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` with the summaries of the most recent packets, oldest first, when
+    /// forwarding fails or the stream turns silent
+    ///
+    /// Only called with the history enabled through [Forwarder::with_packet_history].
+    pub fn on_packet_history(
+        mut self,
+        hook: impl FnMut(DumpTrigger, &[PacketSummary]) + Send + 'static,
+    ) -> Self {
+        self.hooks.history = Some(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` whenever a target becomes active or inactive
+    ///
+    /// Targets change when groups are toggled, through the control API or discovery.
+    /// Changes are reported when the forwarding loop picks them up with the next packet.
+    pub fn on_target_state_change(
+        mut self,
+        hook: impl FnMut(SocketAddr, TargetState) + Send + 'static,
+    ) -> Self {
+        self.hooks.target_state = Some(Box::new(hook));
+        self
+    }
+
+    /// Label targets as groups which can be disabled and enabled at runtime
+    ///
+    /// The targets of the groups must be among the targets given to [Forwarder::new].
+    pub fn with_target_groups(self, groups: Vec<TargetGroup>) -> Self {
+        for group in groups {
+            self.state.add_group(group);
+        }
+        self
+    }
+
+    /// Handle to the state shared with the forwarding loop
+    ///
+    /// Changes to the targets are picked up with the next packet.
+    pub fn state(&self) -> Arc<SharedState> {
+        Arc::clone(&self.state)
+    }
+
+    /// Forward packets until receiving fails or the source is exhausted
+    ///
+    /// Failures to send to a target are counted per target and don't stop forwarding.
+    /// Packets sent by this forwarder which reach the listener again, e.g. when relaying
+    /// between multicast groups on the same segment, are dropped to prevent packet storms.
+    /// Returns the statistics of the run, which the error carries as well.
+    pub fn run(self) -> Result<ForwardStats, ForwardError> {
+        self.run_until(None)
+    }
+
+    /// Forward packets like [Forwarder::run] until `stop` is set, if given
+    pub(crate) fn run_until(
+        mut self,
+        stop: Option<&AtomicBool>,
+    ) -> Result<ForwardStats, ForwardError> {
+        let started = Instant::now();
+        let result = self.forward_until(stop);
+        if result.is_err() {
+            self.dump_history(DumpTrigger::Failed);
+        }
+        self.checkpoint_peers();
+        self.flush_queues();
+        let stats = self.state.forward_stats(started.elapsed());
+
+        match result {
+            Ok(()) => Ok(stats),
+            Err(error) => Err(ForwardError {
+                error,
+                stats: Box::new(stats),
+            }),
+        }
+    }
+
+    /// Forward packets like [Forwarder::run], restarting after transient failures
+    ///
+    /// When receiving or sending fails because the network went away, see
+    /// [supervise::is_transient], the error is recorded and reported to `on_retry` with
+    /// the time until the restart. The listener is then replaced by the source `rebind`
+    /// opens and the senders are bound anew, while the sessions of reply paths stay
+    /// open. Fails with other errors, including those of
+    /// rebinding other than transient ones, which are retried as well. Not restarted
+    /// once shut down, see [Forwarder::with_shutdown].
+    pub fn run_supervised(
+        mut self,
+        policy: RetryPolicy,
+        mut rebind: impl FnMut() -> Result<Box<dyn PacketSource>, io::Error>,
+        mut on_retry: impl FnMut(&io::Error, Duration),
+    ) -> Result<ForwardStats, ForwardError> {
+        let started = Instant::now();
+        let mut backoff = policy.min_backoff;
+        let result = loop {
+            let run_started = Instant::now();
+            let error = match self.forward_until(None) {
+                Err(error) if supervise::is_transient(&error) => error,
+                result => break result,
+            };
+            // Only failures in quick succession wait longer
+            if run_started.elapsed() >= policy.max_backoff {
+                backoff = policy.min_backoff;
+            }
+            self.state.record_error(error.to_string());
+            if self.is_shut_down() {
+                break Ok(());
+            }
+            on_retry(&error, backoff);
+            thread::sleep(backoff);
+            backoff = policy.next_backoff(backoff);
+            if self.is_shut_down() {
+                break Ok(());
+            }
+
+            match rebind().and_then(|listener| self.replace_listener(listener)) {
+                Ok(()) => (),
+                Err(error) if supervise::is_transient(&error) => {
+                    self.state.record_error(error.to_string());
+                }
+                Err(error) => break Err(error),
+            }
+        };
+        if result.is_err() {
+            self.dump_history(DumpTrigger::Failed);
+        }
+        self.checkpoint_peers();
+        self.flush_queues();
+        let stats = self.state.forward_stats(started.elapsed());
+
+        match result {
+            Ok(()) => Ok(stats),
+            Err(error) => Err(ForwardError {
+                error,
+                stats: Box::new(stats),
+            }),
+        }
+    }
+
+    /// Forward from `listener` after a restart, binding the senders anew
+    ///
+    /// The sessions of reply paths stay open and relay replies from the new listener.
+    fn replace_listener(&mut self, listener: Box<dyn PacketSource>) -> Result<(), io::Error> {
+        if let Some(sessions) = &mut self.sessions
+            && let Some(socket) = listener.socket()
+        {
+            sessions.rebind(socket.try_clone()?);
+        }
+        self.listener = listener;
+        self.flush_queues();
+        self.senders.reset();
+        Ok(())
+    }
+
+    fn forward_until(&mut self, stop: Option<&AtomicBool>) -> Result<(), io::Error> {
+        let shutdown = self.shutdown.clone();
+        let stop = stop.or(shutdown.as_ref().map(ShutdownHandle::flag));
+        let poll_interval = self.poll_interval(stop.is_some());
+        if poll_interval.is_some() {
+            self.listener.set_read_timeout(poll_interval)?;
+        }
+
+        let mut pump = self.start()?;
+        loop {
+            if stop.is_some_and(|stop| stop.load(Ordering::Acquire)) {
+                return Ok(());
+            }
+            if let Step::Exhausted = self.step(&mut pump)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Check if forwarding was asked to stop through [Forwarder::with_shutdown]
+    fn is_shut_down(&self) -> bool {
+        self.shutdown
+            .as_ref()
+            .is_some_and(ShutdownHandle::is_shutdown)
+    }
+
+    /// Longest time to wait for packets while idle, if there is a limit
+    ///
+    /// Idle forwarders wake up to send keepalives and heartbeats in time, to check
+    /// the packet rate, to notice silence and to notice being stopped if they can be.
+    pub(crate) fn poll_interval(&self, stoppable: bool) -> Option<Duration> {
+        /// Longest time to notice being stopped while idle
+        const STOP_INTERVAL: Duration = Duration::from_millis(100);
+
+        let poll_interval = [
+            self.keepalive.as_ref().map(|keepalive| keepalive.interval),
+            self.heartbeat.as_ref().map(|heartbeat| heartbeat.interval),
+            self.rate_thresholds.map(|_| self.stats_interval),
+            self.silence_alarm.map(|alarm| alarm.silent_after),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .map(keepalive::poll_interval);
+        match stoppable {
+            false => poll_interval,
+            true => {
+                Some(poll_interval.map_or(STOP_INTERVAL, |interval| interval.min(STOP_INTERVAL)))
+            }
+        }
+    }
+
+    /// Forward the packets waiting at the listener without blocking
+    ///
+    /// Handles at most one batch of packets and returns how many were received,
+    /// zero if none was waiting, so that the forwarder can be driven from an existing
+    /// event loop instead of owning a thread. The listener is switched to non-blocking
+    /// on the first call, wait for it to become readable through [Forwarder::socket].
+    /// Keepalives and heartbeats are only sent while polling, so poll at least as often
+    /// as their interval. Returns `Ok(None)` once the source is exhausted.
+    pub fn poll_once(&mut self) -> Result<Option<usize>, io::Error> {
+        let mut pump = match self.pump.take() {
+            Some(pump) => pump,
+            None => {
+                self.listener.set_nonblocking(true)?;
+                self.start()?
+            }
+        };
+        let result = self.poll_batch(&mut pump);
+        self.pump = Some(pump);
+
+        result
+    }
+
+    /// Listener socket to wait on for readiness, if the source is one
+    pub fn socket(&self) -> Option<&UdpSocket> {
+        self.listener.socket()
+    }
+
+    fn poll_batch(&mut self, pump: &mut Pump) -> Result<Option<usize>, io::Error> {
+        for received in 0..POLL_BATCH {
+            match self.step(pump)? {
+                Step::Received => (),
+                Step::Idle => return Ok(Some(received)),
+                Step::Exhausted => return Ok(None),
+            }
+        }
+        Ok(Some(POLL_BATCH))
+    }
+
+    /// Set up the state of the forwarding loop
+    ///
+    /// Binds senders for targets added to the state since setting up the forwarder.
+    fn start(&mut self) -> Result<Pump, io::Error> {
+        const MTU: usize = 1500;
+        /// Largest payload of a UDP datagram over IPv4
+        const MAX_DATAGRAM: usize = 65_507;
+
+        let generation = self.state.generation();
+        let targets = self.state.active_targets();
+        self.prepare_targets(&targets)?;
+
+        Ok(Pump {
+            // Payloads to chunk are larger than the MTU by definition
+            buffer: match self.gelf {
+                None => vec![0; MTU],
+                Some(_) => vec![0; MAX_DATAGRAM],
+            },
+            tagged: Vec::with_capacity(MTU),
+            stamped: Vec::with_capacity(MTU),
+            handled: Packet {
+                source: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                payload: Vec::with_capacity(MTU),
+            },
+            generation,
+            targets,
+            last_forwarded: Instant::now(),
+            last_heartbeat: Instant::now(),
+            sources: HashMap::new(),
+            streams: HashMap::new(),
+            arrivals: Arrivals::new(),
+            delays: Delays::new(),
+            interval_packets: 0,
+            interval_started: Instant::now(),
+            rate_level: RateLevel::Normal,
+            stream_watch: StreamWatch::new(),
+            last_checkpoint: Instant::now(),
+        })
+    }
+
+    /// Bind the senders of `targets` and start the send queues of those without one
+    ///
+    /// Queues of targets which are no longer sent to are stopped.
+    fn prepare_targets(&mut self, targets: &[Arc<Target>]) -> Result<(), io::Error> {
+        for target in targets {
+            self.senders.ensure_for(&target.addr)?;
+        }
+        let Some(queues) = &mut self.queues else {
+            return Ok(());
+        };
+        queues.retain(targets);
+        for target in targets {
+            if !queues.contains(target) {
+                let (socket, _) = self.senders.bind_for(&target.addr)?;
+                queues.insert(Arc::clone(target), socket, Arc::clone(&self.state))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Report the failures of the send queues to the hooks
+    fn report_queue_errors(&mut self) {
+        if let Some(queues) = &self.queues {
+            for (addr, e) in queues.errors() {
+                self.hooks.send_error(addr, &e);
+            }
+        }
+    }
+
+    /// Wait until the packets queued for the targets are sent and stop the queues
+    ///
+    /// They are started anew with the next targets.
+    fn flush_queues(&mut self) {
+        if let Some(queues) = &mut self.queues {
+            queues.clear();
+        }
+        self.report_queue_errors();
+    }
+
+    /// Publish the summaries of the stats interval and check the packet rate
+    fn end_stats_interval(&mut self, pump: &mut Pump) {
+        if let Some(jitter) = pump.arrivals.take() {
+            self.state.set_jitter(jitter);
+        }
+        if let Some(delay) = pump.delays.take() {
+            self.state.set_delay(delay);
+        }
+        if let Some(thresholds) = &self.rate_thresholds {
+            let pps = pump.interval_packets as f64 / pump.interval_started.elapsed().as_secs_f64();
+            if let Some(alert) = thresholds.check(pps, &mut pump.rate_level) {
+                self.state.set_rate_alert(alert);
+                self.hooks.rate_alert(alert);
+            }
+        }
+        pump.interval_packets = 0;
+        pump.interval_started = Instant::now();
+    }
+
+    /// Checkpoint the hub peers if they changed, recording failures as errors
+    fn checkpoint_peers(&mut self) {
+        let (Some(peers), Some(path)) = (&mut self.hub, &self.peers_file) else {
+            return;
+        };
+        if peers.changed()
+            && let Err(e) = peers.checkpoint(path)
+        {
+            self.state.record_error(format!(
+                "failed to checkpoint peers to {}: {e}",
+                path.display()
+            ));
+        }
+    }
+
+    /// Publish and report a transition of the stream between active and silent
+    fn stream_state_changed(&mut self, state: StreamState) {
+        self.state.set_stream_state(state);
+        self.hooks.stream_state(state);
+        if state == StreamState::Silent {
+            self.dump_history(DumpTrigger::Silent);
+        }
+    }
+
+    /// Report the summaries of the most recent packets, if kept
+    pub(crate) fn dump_history(&mut self, trigger: DumpTrigger) {
+        if let Some(history) = &self.history {
+            self.hooks.history(trigger, &history.summaries());
+        }
+    }
+
+    /// Send due keepalives and heartbeats, then receive and forward one packet
+    fn step(&mut self, pump: &mut Pump) -> Result<Step, io::Error> {
+        self.report_queue_errors();
+        if let Some(keepalive) = &self.keepalive
+            && pump.last_forwarded.elapsed() >= keepalive.interval
+        {
+            let payload = keepalive.payload.clone();
+            self.send_to_all(&pump.targets, &payload, "keepalive");
+            pump.last_forwarded = Instant::now();
+        }
+        if let Some(heartbeat) = &self.heartbeat
+            && pump.last_heartbeat.elapsed() >= heartbeat.interval
+        {
+            let payload = heartbeat.payload.clone();
+            self.send_to_all(&pump.targets, &payload, "heartbeat");
+            pump.last_heartbeat = Instant::now();
+        }
+        if pump.interval_started.elapsed() >= self.stats_interval {
+            self.end_stats_interval(pump);
+        }
+        if let Some(alarm) = &self.silence_alarm
+            && let Some(state) = pump.stream_watch.check(alarm, Instant::now())
+        {
+            self.stream_state_changed(state);
+        }
+        if pump.last_checkpoint.elapsed() >= PEERS_CHECKPOINT_INTERVAL {
+            self.checkpoint_peers();
+            pump.last_checkpoint = Instant::now();
+        }
+
+        let (num_bytes, source) = match self.listener.recv(&mut pump.buffer) {
+            Ok(Some(received)) => received,
+            Ok(None) => return Ok(Step::Exhausted),
+            Err(e) if is_timeout(&e) => return Ok(Step::Idle),
+            // Signals interrupt receiving with a timeout despite `SA_RESTART`
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(Step::Idle),
+            Err(e) => return Err(e),
+        };
+        // Sources report at most the buffer length, but don't trust other implementations
+        let num_bytes = num_bytes.min(pump.buffer.len());
+        self.state.received().add(num_bytes);
+        if let Some(history) = &mut self.history {
+            history.push(PacketSummary::new(
+                SystemTime::now(),
+                source,
+                &pump.buffer[..num_bytes],
+            ));
+        }
+        pump.interval_packets += 1;
+        if let Some(alarm) = &self.silence_alarm
+            && let Some(state) = pump.stream_watch.packet(alarm, Instant::now())
+        {
+            self.stream_state_changed(state);
+        }
+        let arrival = self.listener.arrival().unwrap_or_else(SystemTime::now);
+        pump.arrivals.add(arrival);
+        let mut buffer = &pump.buffer[..num_bytes];
+        if self.one_way_delay == Some(OneWayDelay::Measure)
+            && let Some((sent, payload)) = delay::strip(buffer)
+        {
+            pump.delays.add(sent, arrival);
+            buffer = payload;
+        }
+        self.hooks.packet(source, buffer);
+
+        if self.senders.is_own(&source) {
+            self.state.looped().add(num_bytes);
+            self.hooks.drop(source, num_bytes, DropReason::Looped);
+            return Ok(Step::Received);
+        }
+
+        if !pump.sources.contains_key(&source)
+            && pump.sources.len() < MAX_SOURCES
+            && let Some(tracked) = self.track_source(source)
+        {
+            pump.sources.insert(source, tracked);
+        }
+        let tracked = pump.sources.get(&source);
+        if let Some(tracked) = tracked {
+            tracked.received.add(num_bytes);
+        }
+
+        if self.inspect_dns
+            && let Some(question) = dns::parse_question(buffer)
+        {
+            if self.verbose {
+                println!("{}DNS {question} from {source}", self.log_prefix);
+            }
+            if let Some(tracked) = tracked {
+                tracked.add_dns_question(question);
+            }
+        }
+
+        if let Some(field) = &self.sequence
+            && let Some(tracked) = tracked
+            && let Some(number) = field.read(buffer)
+        {
+            let stream = pump.streams.entry(source).or_default();
+            match stream.track(field, number, &tracked.sequence) {
+                SequenceStep::Gap(missing) if self.verbose => {
+                    println!(
+                        "{}{missing} packets missing before {number} from {source}",
+                        self.log_prefix
+                    );
+                }
+                SequenceStep::Reset if self.verbose => {
+                    println!("{}Sequence of {source} reset to {number}", self.log_prefix);
+                }
+                _ => {}
+            }
+        }
+
+        // Late punches of the peer
+        if self.peer == Some(source) && rendezvous::is_message(buffer) {
+            return Ok(Step::Received);
+        }
+
+        // Pick up changes of the targets
+        let current_generation = self.state.generation();
+        if current_generation != pump.generation {
+            pump.generation = current_generation;
+            let targets = self.state.active_targets();
+            self.prepare_targets(&targets)?;
+            if self.hooks.target_state.is_some() {
+                let addrs = |targets: &[Arc<Target>]| -> Vec<SocketAddr> {
+                    targets.iter().map(|target| target.addr).collect()
+                };
+                self.hooks
+                    .targets_changed(&addrs(&pump.targets), &addrs(&targets));
+            }
+            pump.targets = targets;
+        }
+
+        if self.state.is_paused() {
+            self.state.paused_dropped().add(num_bytes);
+            self.hooks.drop(source, num_bytes, DropReason::Paused);
+            return Ok(Step::Received);
+        }
+
+        let received = match self.handlers.is_empty() {
+            true => buffer,
+            false => {
+                let handled = &mut pump.handled;
+                handled.source = source;
+                handled.payload.clear();
+                handled.payload.extend_from_slice(buffer);
+                if handler::apply(&mut self.handlers, handled) == Action::Drop {
+                    self.state.filtered().add(num_bytes);
+                    self.hooks.drop(source, num_bytes, DropReason::Filtered);
+                    return Ok(Step::Received);
+                }
+                &handled.payload[..]
+            }
+        };
+
+        if let Some(peers) = &mut self.hub {
+            peers.seen(source, Instant::now());
+            self.state.set_num_peers(peers.len());
+        }
+
+        let targets = &pump.targets;
+        let tagged = &mut pump.tagged;
+        let stamped = &mut pump.stamped;
+        let mut forwarded = false;
+        match self.syslog {
+            None => {
+                forwarded = self.forward_message(targets, source, received, tagged, stamped);
+            }
+            Some(syslog) => {
+                for message in syslog.reframe(received, source.ip()) {
+                    forwarded |= self.forward_message(targets, source, &message, tagged, stamped);
+                }
+            }
+        }
+        if forwarded && self.keepalive.is_some() {
+            pump.last_forwarded = Instant::now();
+        }
+
+        Ok(Step::Received)
+    }
+
+    /// Forward a message to the targets, sinks, TURN relays, hub peers and the rendezvous peer
+    ///
+    /// Messages larger than the GELF chunk size are forwarded in chunks.
+    /// Returns `false` if the message was dropped because its hop limit was reached.
+    /// The headers are built in the buffers for tagged and stamped messages.
+    fn forward_message(
+        &mut self,
+        targets: &[Arc<Target>],
+        source: SocketAddr,
+        message: &[u8],
+        tagged: &mut Vec<u8>,
+        stamped: &mut Vec<u8>,
+    ) -> bool {
+        let packet = match self.hop_limit {
+            None => message,
+            Some(hop_limit) => match hop_limit.apply(message, tagged) {
+                Some(packet) => packet,
+                None => {
+                    self.state.expired().add(message.len());
+                    self.hooks.drop(source, message.len(), DropReason::Expired);
+                    return false;
+                }
+            },
+        };
+        let packet = match self.one_way_delay {
+            Some(OneWayDelay::Stamp) => {
+                delay::stamp(packet, SystemTime::now(), stamped);
+                stamped
+            }
+            _ => packet,
+        };
+
+        let chunks = match &mut self.gelf {
+            None => None,
+            Some(chunker) => chunker.chunk(packet),
+        };
+        match chunks {
+            None => self.fan_out(targets, source, packet),
+            Some(Ok(chunks)) => {
+                for chunk in chunks {
+                    self.fan_out(targets, source, &chunk);
+                }
+            }
+            Some(Err(num_chunks)) => {
+                self.state.record_error(format!(
+                    "dropped GELF message of {} bytes from {source}, it needs {num_chunks} chunks",
+                    packet.len()
+                ));
+                self.hooks.drop(source, packet.len(), DropReason::Oversized);
+            }
+        }
+
+        true
+    }
+
+    /// Send a packet to the targets, sinks, TURN relays, hub peers and the rendezvous peer
+    fn fan_out(&mut self, targets: &[Arc<Target>], source: SocketAddr, packet: &[u8]) {
+        for target in targets {
+            let packet = match self.snaplens.get(&target.addr) {
+                Some(&snaplen) => &packet[..packet.len().min(snaplen)],
+                None => packet,
+            };
+            if let Some(result) = self.send_in_session(source, target, packet) {
+                if let Err(e) = count(&self.state, target, result) {
+                    self.hooks.send_error(target.addr, &e);
+                }
+                continue;
+            }
+            if let Some(queues) = &self.queues
+                && queues.push(target, packet, None)
+            {
+                continue;
+            }
+            if let Err(e) = send(&self.senders, &self.state, target, packet) {
+                self.hooks.send_error(target.addr, &e);
+            }
+        }
+
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.send(source, packet) {
+                self.state
+                    .record_error(format!("failed to deliver to {sink}: {e}"));
+            }
+        }
+
+        for relay in &mut self.turn_relays {
+            match relay.send(packet) {
+                Ok(num_bytes) => self.state.relayed().add(num_bytes),
+                Err(e) => {
+                    self.state.record_error(format!(
+                        "failed to send to {} through TURN: {e}",
+                        relay.peer()
+                    ));
+                    self.hooks.send_error(relay.peer(), &e);
+                }
+            }
+        }
+
+        if let Some(peers) = &self.hub {
+            for peer in peers.others(source) {
+                match self.send_from_listener(packet, *peer) {
+                    Ok(num_bytes) => self.state.relayed().add(num_bytes),
+                    Err(e) => {
+                        self.state
+                            .record_error(format!("failed to relay to {peer}: {e}"));
+                        self.hooks.send_error(*peer, &e);
+                    }
+                }
+            }
+        }
+
+        // Hub mode relays to the peer already
+        if self.hub.is_none()
+            && let Some(peer) = self.peer
+            && peer != source
+        {
+            match self.send_from_listener(packet, peer) {
+                Ok(num_bytes) => self.state.relayed().add(num_bytes),
+                Err(e) => {
+                    self.state
+                        .record_error(format!("failed to relay to {peer}: {e}"));
+                    self.hooks.send_error(peer, &e);
+                }
+            }
+        }
+    }
+
+    /// Send a packet of `client` to `target` through their session, opening it if needed
+    ///
+    /// Returns `None` without reply paths or once no further sessions can be opened.
+    fn send_in_session(
+        &mut self,
+        client: SocketAddr,
+        target: &Target,
+        packet: &[u8],
+    ) -> Option<Result<usize, io::Error>> {
+        let sessions = self.sessions.as_mut()?;
+        if !sessions.contains(client, target.addr) {
+            for own_addr in sessions.expire() {
+                self.senders.forget(&own_addr);
+            }
+            if sessions.is_full() {
+                return None;
+            }
+            let opened = self
+                .senders
+                .bind_for(&target.addr)
+                .and_then(|(socket, own_addr)| {
+                    sessions.open(
+                        client,
+                        target.addr,
+                        socket,
+                        own_addr,
+                        Arc::clone(&self.state),
+                    )
+                });
+            if let Err(e) = opened {
+                return Some(Err(e));
+            }
+        }
+
+        sessions.send_to(client, target.addr, packet)
+    }
+
+    /// Send a packet to a peer from the listener socket
+    fn send_from_listener(&self, packet: &[u8], peer: SocketAddr) -> Result<usize, io::Error> {
+        match self.listener.socket() {
+            Some(socket) => socket.send_to(packet, peer),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "source is no socket",
+            )),
+        }
+    }
+
+    /// Publish a new source in the state, looking up its location
+    fn track_source(&self, addr: SocketAddr) -> Option<Arc<Source>> {
+        let location = self
+            .geoip
+            .as_ref()
+            .and_then(|geoip| geoip.lookup(addr.ip()));
+        if self.verbose {
+            match &location {
+                Some(location) => println!("{}New source {addr} ({location})", self.log_prefix),
+                None => println!("{}New source {addr}", self.log_prefix),
+            }
+        }
+
+        self.state.add_source(addr, location)
+    }
+
+    /// Send a keepalive or heartbeat to the targets, the rendezvous peer and TURN relays
+    ///
+    /// These are not counted as forwarded, `kind` names them in errors.
+    /// Targets with a send queue get them through it, to receive them from the same port
+    /// as the forwarded packets.
+    fn send_to_all(&mut self, targets: &[Arc<Target>], payload: &[u8], kind: &'static str) {
+        for target in targets {
+            if let Some(queues) = &self.queues
+                && queues.push(target, payload, Some(kind))
+            {
+                continue;
+            }
+            if let Err(e) = self.senders.send_to(payload, &target.addr) {
+                self.state
+                    .record_error(format!("failed to send {kind} to {}: {e}", target.addr));
+                self.hooks.send_error(target.addr, &e);
+            }
+        }
+
+        if let Some(peer) = self.peer
+            && let Err(e) = self.send_from_listener(payload, peer)
+        {
+            self.state
+                .record_error(format!("failed to send {kind} to {peer}: {e}"));
+            self.hooks.send_error(peer, &e);
+        }
+
+        for relay in &mut self.turn_relays {
+            if let Err(e) = relay.send(payload) {
+                self.state.record_error(format!(
+                    "failed to send {kind} to {} through TURN: {e}",
+                    relay.peer()
+                ));
+                self.hooks.send_error(relay.peer(), &e);
+            }
+        }
+    }
+}
+
+/// Send data to a target, counting the result
+fn send(
+    senders: &Senders,
+    state: &SharedState,
+    target: &Target,
+    data: &[u8],
+) -> Result<(), io::Error> {
+    count(state, target, senders.send_to(data, &target.addr))
+}
+
+/// Count the result of sending to a target, recording failures
+fn count(
+    state: &SharedState,
+    target: &Target,
+    result: Result<usize, io::Error>,
+) -> Result<(), io::Error> {
+    match result {
+        Ok(num_bytes) => {
+            target.sent.add(num_bytes);
+            Ok(())
+        }
+        Err(e) => {
+            target.send_errors.fetch_add(1, Ordering::Relaxed);
+            state.record_error(format!("failed to send to {}: {e}", target.addr));
+            Err(e)
+        }
+    }
+}
+
+/// Set of IPv4/IPv6-bound sockets to use for sending
 struct Senders {
+    /// Network the senders are bound in
+    network: Box<dyn Network>,
     /// IPv4-bound socket, only used if we have any IPv4 forwarding targets
-    sender_v4: Option<UdpSocket>,
+    sender_v4: Option<Box<dyn Socket>>,
     /// IPv6-bound socket, only used if we have any IPv6 forwarding targets
-    sender_v6: Option<UdpSocket>,
+    sender_v6: Option<Box<dyn Socket>>,
+    /// VRF devices of the targets sent to from within a VRF
+    vrfs: HashMap<SocketAddr, String>,
+    /// Sockets bound into VRFs, by VRF device and the unspecified address bound to
+    vrf_senders: HashMap<(String, SocketAddr), Box<dyn Socket>>,
+    /// Source addresses which packets sent to the targets carry
+    own_addrs: Vec<SocketAddr>,
+    /// Source addresses of the sockets bound for sessions, which outlive the senders
+    session_addrs: Vec<SocketAddr>,
+    /// Whether the IPv4 senders omit UDP checksums
+    no_checksums: bool,
+    /// Firewall mark of packets sent by all senders
+    mark: Option<u32>,
 }
 
 impl Senders {
     /// Create a set of senders for the given forward specifications
-    fn for_addresses(forward_specs: &[SocketAddr]) -> Result<Self, io::Error> {
-        let sender_v4 = if forward_specs.iter().any(|addr| addr.is_ipv4()) {
-            match UdpSocket::bind(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))) {
-                Ok(sender) => Some(sender),
-                Err(e) => return Err(e),
-            }
-        } else {
-            None
+    fn for_addresses(
+        network: Box<dyn Network>,
+        forward_specs: &[SocketAddr],
+    ) -> Result<Self, io::Error> {
+        let mut senders = Self {
+            network,
+            sender_v4: None,
+            sender_v6: None,
+            vrfs: HashMap::new(),
+            vrf_senders: HashMap::new(),
+            own_addrs: Vec::new(),
+            session_addrs: Vec::new(),
+            no_checksums: false,
+            mark: None,
         };
+        for addr in forward_specs {
+            senders.ensure_for(addr)?;
+        }
+
+        Ok(senders)
+    }
 
-        let sender_v6 = if forward_specs.iter().any(|addr| addr.is_ipv6()) {
-            match UdpSocket::bind(SocketAddr::V6(SocketAddrV6::new(
-                Ipv6Addr::UNSPECIFIED,
-                0,
-                0,
-                0,
-            ))) {
-                Ok(sender) => Some(sender),
-                Err(e) => return Err(e),
+    /// Bind the sender for the IP family and VRF of the given address if not done yet
+    ///
+    /// Also remembers the source address of packets to `addr` to detect forwarding loops.
+    fn ensure_for(&mut self, addr: &SocketAddr) -> Result<(), io::Error> {
+        let unspecified = unspecified_for(addr);
+        match (self.vrfs.get(addr), addr) {
+            (Some(vrf), _) => {
+                let key = (vrf.clone(), unspecified);
+                if !self.vrf_senders.contains_key(&key) {
+                    let sender = self.bind(unspecified, Some(vrf))?;
+                    self.vrf_senders.insert(key, sender);
+                }
             }
-        } else {
-            None
-        };
+            (None, SocketAddr::V4(_)) if self.sender_v4.is_none() => {
+                self.sender_v4 = Some(self.bind(unspecified, None)?);
+            }
+            (None, SocketAddr::V6(_)) if self.sender_v6.is_none() => {
+                self.sender_v6 = Some(self.bind(unspecified, None)?);
+            }
+            _ => {}
+        }
 
-        Ok(Self {
-            sender_v4,
-            sender_v6,
-        })
+        if let Some(own_addr) = self.source_addr(addr)
+            && !self.own_addrs.contains(&own_addr)
+        {
+            self.own_addrs.push(own_addr);
+        }
+
+        Ok(())
+    }
+
+    /// Bind a sender to `addr`, into the VRF `vrf` if given, and apply the socket options
+    fn bind(&self, addr: SocketAddr, vrf: Option<&str>) -> Result<Box<dyn Socket>, io::Error> {
+        let sender = self.network.bind(addr)?;
+        if let Some(vrf) = vrf {
+            bind_to_vrf(sender.as_ref(), vrf)?;
+        }
+        if self.no_checksums && addr.is_ipv4() {
+            disable_checksums(sender.as_ref())?;
+        }
+        if let Some(mark) = self.mark {
+            set_mark(sender.as_ref(), mark)?;
+        }
+
+        Ok(sender)
+    }
+
+    /// Bind a socket of its own for sending to `addr`, within its VRF if it has one
+    ///
+    /// Also remembers the source address of its packets to detect forwarding loops,
+    /// which is returned along with it.
+    fn bind_for(
+        &mut self,
+        addr: &SocketAddr,
+    ) -> Result<(Box<dyn Socket>, Option<SocketAddr>), io::Error> {
+        let sender = self.bind(
+            unspecified_for(addr),
+            self.vrfs.get(addr).map(String::as_str),
+        )?;
+        let own_addr = self.network.source_addr(sender.as_ref(), addr);
+        if let Some(own_addr) = own_addr
+            && !self.session_addrs.contains(&own_addr)
+        {
+            self.session_addrs.push(own_addr);
+        }
+
+        Ok((sender, own_addr))
+    }
+
+    /// Forget a source address of a socket which was dropped
+    fn forget(&mut self, own_addr: &SocketAddr) {
+        self.session_addrs.retain(|addr| addr != own_addr);
+    }
+
+    /// Drop the senders, to bind them anew for the next targets
+    ///
+    /// The source addresses of sockets bound for sessions are kept.
+    fn reset(&mut self) {
+        self.sender_v4 = None;
+        self.sender_v6 = None;
+        self.vrf_senders.clear();
+        self.own_addrs.clear();
+    }
+
+    /// All senders bound, with the address they are bound to
+    fn all(&self) -> impl Iterator<Item = (SocketAddr, &dyn Socket)> {
+        let v4 = self
+            .sender_v4
+            .as_deref()
+            .map(|sender| (SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), sender));
+        let v6 = self
+            .sender_v6
+            .as_deref()
+            .map(|sender| (SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)), sender));
+        let vrfs = self
+            .vrf_senders
+            .iter()
+            .map(|((_, addr), sender)| (*addr, sender.as_ref()));
+
+        v4.into_iter().chain(v6).chain(vrfs)
+    }
+
+    /// Sender for packets to `addr`, the one of its VRF if it is sent to from a VRF
+    fn sender(&self, addr: &SocketAddr) -> Option<&dyn Socket> {
+        if let Some(vrf) = self.vrfs.get(addr) {
+            return self
+                .vrf_senders
+                .get(&(vrf.clone(), unspecified_for(addr)))
+                .map(|sender| sender.as_ref());
+        }
+        match addr {
+            SocketAddr::V4(_) => self.sender_v4.as_deref(),
+            SocketAddr::V6(_) => self.sender_v6.as_deref(),
+        }
+    }
+
+    /// Source address of packets sent to `addr`, if it can be determined
+    fn source_addr(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        self.network.source_addr(self.sender(addr)?, addr)
+    }
+
+    /// Check if a packet from `source` was sent by one of the senders
+    fn is_own(&self, source: &SocketAddr) -> bool {
+        self.own_addrs.contains(source) || self.session_addrs.contains(source)
     }
 
     /// Send data to the given address, using the correct sender for the IP family of the address
+    ///
+    /// Fails with `NotConnected` if no sender was bound for the IP family.
     fn send_to(&self, data: &[u8], addr: &SocketAddr) -> Result<usize, io::Error> {
-        let sender = match addr {
-            SocketAddr::V4(_) => self
-                .sender_v4
-                .as_ref()
-                .expect("initialized sender for IPv4"),
-            SocketAddr::V6(_) => self
-                .sender_v6
-                .as_ref()
-                .expect("initialized sender for IPv6"),
-        };
-
-        sender.send_to(data, addr)
+        let family = match addr {
+            SocketAddr::V4(_) => "IPv4",
+            SocketAddr::V6(_) => "IPv6",
+        };
+        let sender = self.sender(addr).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("no sender for {family}"),
+            )
+        })?;
+
+        sender.send_to(data, *addr)
+    }
+}
+
+/// Unspecified address of the IP family of `addr`, which senders bind to
+fn unspecified_for(addr: &SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    }
+}
+
+/// Send from `socket` within the VRF `vrf`, if it is a socket of the operating system
+#[cfg(target_os = "linux")]
+fn bind_to_vrf(socket: &dyn Socket, vrf: &str) -> Result<(), io::Error> {
+    use crate::sockopt::{SO_BINDTODEVICE, SOL_SOCKET, set_bytes};
+
+    match socket.udp_socket() {
+        Some(socket) => set_bytes(socket, SOL_SOCKET, SO_BINDTODEVICE, vrf.as_bytes()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_vrf(_socket: &dyn Socket, _vrf: &str) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to a VRF is only available on Linux",
+    ))
+}
+
+/// Send from `socket` without UDP checksums, if it is a socket of the operating system
+#[cfg(target_os = "linux")]
+fn disable_checksums(socket: &dyn Socket) -> Result<(), io::Error> {
+    use crate::sockopt::{SO_NO_CHECK, SOL_SOCKET, set_int};
+
+    match socket.udp_socket() {
+        Some(socket) => set_int(socket, SOL_SOCKET, SO_NO_CHECK, 1),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn disable_checksums(_socket: &dyn Socket) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "disabling UDP checksums is only available on Linux",
+    ))
+}
+
+/// Mark packets sent from `socket`, if it is a socket of the operating system
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &dyn Socket, mark: u32) -> Result<(), io::Error> {
+    use crate::sockopt::{SO_MARK, SOL_SOCKET, set_bytes};
+
+    match socket.udp_socket() {
+        Some(socket) => set_bytes(socket, SOL_SOCKET, SO_MARK, &mark.to_ne_bytes()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_socket: &dyn Socket, _mark: u32) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "marking packets is only available on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        socket::{MemoryNetwork, MemorySocket},
+        source::ChannelSource,
+        state::Totals,
+    };
+
+    /// Forwarder listening on `127.0.0.1:4000` of `network`, forwarding to `targets`
+    fn forwarder(network: &MemoryNetwork, targets: &[&MemorySocket]) -> Forwarder {
+        let listener = network.bind("127.0.0.1:4000".parse().unwrap()).unwrap();
+        let targets = targets
+            .iter()
+            .map(|target| target.local_addr().unwrap())
+            .collect();
+        Forwarder::from_source_and_network(listener, targets, network.clone()).unwrap()
+    }
+
+    fn recv(socket: &MemorySocket) -> Option<Vec<u8>> {
+        let mut buffer = [0; 64];
+        let (num_bytes, _) = socket.recv_from(&mut buffer).ok()?;
+        Some(buffer[..num_bytes].to_vec())
+    }
+
+    #[test]
+    fn fanned_out_to_all_targets() {
+        let network = MemoryNetwork::new();
+        let targets = ["127.0.0.1:5000", "[::1]:5000"].map(|addr| {
+            let mut target = network.bind(addr.parse().unwrap()).unwrap();
+            target.set_nonblocking(true).unwrap();
+            target
+        });
+        let mut forwarder = forwarder(&network, &[&targets[0], &targets[1]]);
+
+        let sender = network.bind("10.1.1.10:0".parse().unwrap()).unwrap();
+        sender
+            .send_to(b"hello", "127.0.0.1:4000".parse().unwrap())
+            .unwrap();
+        assert_eq!(Some(1), forwarder.poll_once().unwrap());
+
+        for target in &targets {
+            assert_eq!(Some(b"hello".to_vec()), recv(target));
+            assert_eq!(None, recv(target));
+        }
+        assert_eq!(1, forwarder.state().received().packets());
+    }
+
+    #[test]
+    fn send_errors_counted_without_stopping() {
+        let network = MemoryNetwork::new();
+        let reachable = network.bind("127.0.0.1:5000".parse().unwrap()).unwrap();
+        let unreachable = network.bind("127.0.0.1:5001".parse().unwrap()).unwrap();
+        network.set_unreachable(unreachable.local_addr().unwrap(), true);
+        let (errors, failed) = mpsc::channel();
+        let mut forwarder = forwarder(&network, &[&reachable, &unreachable])
+            .on_send_error(move |addr, _| errors.send(addr).unwrap());
+
+        let sender = network.bind("10.1.1.10:0".parse().unwrap()).unwrap();
+        for _ in 0..2 {
+            sender
+                .send_to(b"hello", "127.0.0.1:4000".parse().unwrap())
+                .unwrap();
+        }
+        assert_eq!(Some(2), forwarder.poll_once().unwrap());
+
+        assert_eq!(Some(b"hello".to_vec()), recv(&reachable));
+        assert_eq!(Some(b"hello".to_vec()), recv(&reachable));
+        let failed: Vec<_> = failed.try_iter().collect();
+        assert_eq!(vec![unreachable.local_addr().unwrap(); 2], failed);
+        let targets = forwarder.state().active_targets();
+        assert_eq!(2, targets[1].send_errors.load(Ordering::Relaxed));
+        assert_eq!(2, targets[0].sent.packets());
+    }
+
+    #[test]
+    fn stats_returned_when_exhausted() {
+        let network = MemoryNetwork::new();
+        let target = network.bind("127.0.0.1:5000".parse().unwrap()).unwrap();
+        let (input, source) = mpsc::channel();
+        let forwarder = Forwarder::from_source_and_network(
+            ChannelSource::new(source),
+            vec![target.local_addr().unwrap()],
+            network.clone(),
+        )
+        .unwrap();
+
+        for _ in 0..2 {
+            let packet = Packet {
+                source: "10.1.1.10:5000".parse().unwrap(),
+                payload: b"hello".to_vec(),
+            };
+            input.send(packet).unwrap();
+        }
+        drop(input);
+
+        let stats = forwarder.run().unwrap();
+        assert_eq!(
+            Totals {
+                packets: 2,
+                bytes: 10
+            },
+            stats.received
+        );
+        assert_eq!(
+            Totals {
+                packets: 2,
+                bytes: 10
+            },
+            stats.sent()
+        );
+        assert_eq!(target.local_addr().unwrap(), stats.targets[0].addr);
+        assert_eq!(0, stats.dropped_packets());
+    }
+
+    #[test]
+    fn queued_targets_sent_to_from_own_sockets() {
+        let network = MemoryNetwork::new();
+        let targets = ["127.0.0.1:5000", "127.0.0.1:5001"].map(|addr| {
+            let mut target = network.bind(addr.parse().unwrap()).unwrap();
+            target.set_nonblocking(true).unwrap();
+            target
+        });
+        let (input, source) = mpsc::channel();
+        let forwarder = Forwarder::from_source_and_network(
+            ChannelSource::new(source),
+            targets
+                .iter()
+                .map(|target| target.local_addr().unwrap())
+                .collect(),
+            network.clone(),
+        )
+        .unwrap()
+        .with_target_queues(16);
+
+        for _ in 0..2 {
+            let packet = Packet {
+                source: "10.1.1.10:5000".parse().unwrap(),
+                payload: b"hello".to_vec(),
+            };
+            input.send(packet).unwrap();
+        }
+        drop(input);
+
+        // Running returns once the queues are flushed
+        let stats = forwarder.run().unwrap();
+        assert_eq!(4, stats.sent().packets);
+        let mut buffer = [0; 64];
+        let senders = targets.map(|target| {
+            let (_, first) = target.recv_from(&mut buffer).unwrap();
+            let (_, second) = target.recv_from(&mut buffer).unwrap();
+            assert_eq!(first, second);
+            first
+        });
+        assert_ne!(senders[0], senders[1]);
+        assert!(
+            stats
+                .targets
+                .iter()
+                .all(|target| target.queue_dropped.packets == 0)
+        );
+    }
+
+    #[test]
+    fn mirror_targets_truncated() {
+        let network = MemoryNetwork::new();
+        let targets = ["127.0.0.1:5000", "127.0.0.1:5001"].map(|addr| {
+            let mut target = network.bind(addr.parse().unwrap()).unwrap();
+            target.set_nonblocking(true).unwrap();
+            target
+        });
+        let mut forwarder = forwarder(&network, &[&targets[0], &targets[1]])
+            .with_mirror(targets[1].local_addr().unwrap(), 4);
+
+        let sender = network.bind("10.1.1.10:0".parse().unwrap()).unwrap();
+        for payload in [b"hello".as_slice(), b"hi"] {
+            sender
+                .send_to(payload, "127.0.0.1:4000".parse().unwrap())
+                .unwrap();
+        }
+        assert_eq!(Some(2), forwarder.poll_once().unwrap());
+
+        assert_eq!(Some(b"hello".to_vec()), recv(&targets[0]));
+        assert_eq!(Some(b"hell".to_vec()), recv(&targets[1]));
+        assert_eq!(Some(b"hi".to_vec()), recv(&targets[1]));
+        assert_eq!(6, forwarder.state().active_targets()[1].sent.bytes());
+    }
+
+    #[test]
+    fn restarted_after_network_loss() {
+        /// Source of a listener whose network went down
+        struct NetworkDown;
+
+        impl PacketSource for NetworkDown {
+            fn recv(&mut self, _: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+                Err(io::ErrorKind::NetworkDown.into())
+            }
+        }
+
+        let network = MemoryNetwork::new();
+        let target = network.bind("127.0.0.1:5000".parse().unwrap()).unwrap();
+        let forwarder = Forwarder::from_source_and_network(
+            NetworkDown,
+            vec![target.local_addr().unwrap()],
+            network.clone(),
+        )
+        .unwrap();
+        let policy = RetryPolicy {
+            min_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+        };
+
+        let mut retries = Vec::new();
+        let stats = forwarder
+            .run_supervised(
+                policy,
+                || {
+                    let (input, source) = mpsc::channel();
+                    let packet = Packet {
+                        source: "10.1.1.10:5000".parse().unwrap(),
+                        payload: b"hello".to_vec(),
+                    };
+                    input.send(packet).unwrap();
+                    Ok(Box::new(ChannelSource::new(source)))
+                },
+                |error, backoff| retries.push((error.kind(), backoff)),
+            )
+            .unwrap();
+
+        assert_eq!(
+            vec![(io::ErrorKind::NetworkDown, Duration::from_millis(1))],
+            retries
+        );
+        assert_eq!(1, stats.received.packets);
+        assert_eq!(Some(b"hello".to_vec()), recv(&target));
+    }
+
+    #[test]
+    fn history_dumped_on_failure() {
+        /// Source receiving one packet before its network goes down
+        struct OnePacket(bool);
+
+        impl PacketSource for OnePacket {
+            fn recv(
+                &mut self,
+                buffer: &mut [u8],
+            ) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+                if std::mem::replace(&mut self.0, true) {
+                    return Err(io::ErrorKind::NetworkDown.into());
+                }
+                buffer[..5].copy_from_slice(b"hello");
+                Ok(Some((5, "10.1.1.10:5000".parse().unwrap())))
+            }
+        }
+
+        let network = MemoryNetwork::new();
+        let target = network.bind("127.0.0.1:5000".parse().unwrap()).unwrap();
+        let (dumps, dumped) = mpsc::channel();
+        let forwarder = Forwarder::from_source_and_network(
+            OnePacket(false),
+            vec![target.local_addr().unwrap()],
+            network.clone(),
+        )
+        .unwrap()
+        .with_packet_history(4)
+        .on_packet_history(move |trigger, summaries| {
+            let _ = dumps.send((trigger, summaries.to_vec()));
+        });
+
+        assert!(forwarder.run().is_err());
+        let (trigger, summaries) = dumped.try_recv().unwrap();
+        assert_eq!(DumpTrigger::Failed, trigger);
+        assert_eq!(1, summaries.len());
+        assert_eq!(b"hello", summaries[0].head());
+        assert_eq!(5, summaries[0].size);
+    }
+
+    #[test]
+    fn looped_packets_dropped() {
+        let network = MemoryNetwork::new();
+        let mut forwarder = forwarder(&network, &[]);
+        // Forward to the listener itself
+        forwarder
+            .state()
+            .add_target("127.0.0.1:4000".parse().unwrap());
+
+        let sender = network.bind("10.1.1.10:0".parse().unwrap()).unwrap();
+        sender
+            .send_to(b"hello", "127.0.0.1:4000".parse().unwrap())
+            .unwrap();
+        assert_eq!(Some(2), forwarder.poll_once().unwrap());
+
+        assert_eq!(1, forwarder.state().looped().packets());
+    }
+
+    #[test]
+    fn sessions_looped_after_rebinding() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+        let rebound = listener.try_clone().unwrap();
+        // Forward to the listener itself, from the socket of the session
+        let mut forwarder = Forwarder::from_source(listener, vec![listener_addr])
+            .unwrap()
+            .with_reply_path(Duration::from_secs(5))
+            .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        for looped in 1..=2 {
+            client.send_to(b"hello", listener_addr).unwrap();
+            forwarder.poll_once().unwrap();
+            forwarder.poll_once().unwrap();
+            assert_eq!(looped, forwarder.state().looped().packets());
+            // Only the packet of the client and the looped one, not forwarded again
+            assert_eq!(2 * looped, forwarder.state().received().packets());
+
+            forwarder
+                .replace_listener(Box::new(rebound.try_clone().unwrap()))
+                .unwrap();
+            let sessions = forwarder.sessions.as_ref().unwrap();
+            assert!(sessions.contains(client_addr, listener_addr));
+        }
+    }
+
+    #[test]
+    fn missing_sender_is_error() {
+        let network = MemoryNetwork::new();
+        let senders =
+            Senders::for_addresses(Box::new(network), &["127.0.0.1:4000".parse().unwrap()])
+                .unwrap();
+
+        let error = senders
+            .send_to(b"hello", &"[::1]:4000".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::NotConnected, error.kind());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn udp_checksums_disabled_for_ipv4_senders() {
+        use std::ffi::c_int;
+
+        use crate::sockopt::{SO_NO_CHECK, SOL_SOCKET, get_struct};
+
+        let target: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut forwarder = Forwarder::from_source(listener, vec![target])
+            .unwrap()
+            .without_udp_checksums()
+            .unwrap();
+        let no_check = |senders: &Senders| {
+            let sender = senders.sender_v4.as_ref().unwrap().udp_socket().unwrap();
+            get_struct::<c_int>(sender, SOL_SOCKET, SO_NO_CHECK).unwrap()
+        };
+        assert_eq!(1, no_check(&forwarder.senders));
+
+        // Senders bound anew keep it
+        forwarder.senders.reset();
+        forwarder.senders.ensure_for(&target).unwrap();
+        assert_eq!(1, no_check(&forwarder.senders));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fwmark_set_on_senders() {
+        use crate::sockopt::{SO_MARK, SOL_SOCKET, get_struct};
+
+        let target: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let forwarder = Forwarder::from_source(listener, vec![target]).unwrap();
+        // Setting the mark requires CAP_NET_ADMIN
+        let mut forwarder = match forwarder.with_fwmark(100) {
+            Ok(forwarder) => forwarder,
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(err) => panic!("set fwmark: {err}"),
+        };
+        let mark = |sender: &Option<Box<dyn Socket>>| {
+            let sender = sender.as_ref().unwrap().udp_socket().unwrap();
+            get_struct::<u32>(sender, SOL_SOCKET, SO_MARK).unwrap()
+        };
+        assert_eq!(100, mark(&forwarder.senders.sender_v4));
+
+        // Senders bound anew for either family carry it
+        forwarder.senders.reset();
+        forwarder.senders.ensure_for(&target).unwrap();
+        forwarder
+            .senders
+            .ensure_for(&"[::1]:4000".parse().unwrap())
+            .unwrap();
+        assert_eq!(100, mark(&forwarder.senders.sender_v4));
+        assert_eq!(100, mark(&forwarder.senders.sender_v6));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn target_sent_to_from_vrf_device() {
+        use crate::sockopt::{SO_BINDTODEVICE, SOL_SOCKET, get_struct};
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let target = receiver.local_addr().unwrap();
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // Any device can stand in for a VRF device, the loopback one always exists
+        let forwarder = Forwarder::from_source(listener, vec![target])
+            .unwrap()
+            .with_target_vrf(target, "lo")
+            .unwrap();
+
+        let sender = forwarder.senders.sender(&target).unwrap();
+        let device =
+            get_struct::<[u8; 16]>(sender.udp_socket().unwrap(), SOL_SOCKET, SO_BINDTODEVICE)
+                .unwrap();
+        assert_eq!(b"lo\0", &device[..3]);
+        assert_eq!(1, forwarder.senders.vrf_senders.len());
+
+        forwarder.senders.send_to(b"hello", &target).unwrap();
+        let mut buffer = [0; 16];
+        let (len, source) = receiver.recv_from(&mut buffer).unwrap();
+        assert_eq!(b"hello", &buffer[..len]);
+        assert_eq!(sender.local_addr().unwrap().port(), source.port());
     }
 }
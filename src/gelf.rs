@@ -0,0 +1,144 @@
+//! Chunking of GELF messages for Graylog
+//!
+//! Graylog accepts GELF messages larger than a datagram in chunks of their own:
+//! a header of the magic bytes `0x1e 0x0f`, an 8-byte message ID, the sequence
+//! number and the sequence count, followed by a slice of the payload. At most
+//! 128 chunks make up a message.
+
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    str::FromStr,
+};
+
+/// Magic bytes starting a chunk
+const MAGIC: [u8; 2] = [0x1e, 0x0f];
+/// Length of the header of a chunk
+const HEADER_LEN: usize = 12;
+/// Maximum number of chunks of a message
+const MAX_CHUNKS: usize = 128;
+
+/// Chunking of GELF payloads larger than a datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Gelf {
+    /// Maximum size of forwarded datagrams including the chunk header
+    pub chunk_size: usize,
+}
+
+impl Gelf {
+    /// Chunk size recommended by Graylog for paths over the internet
+    pub const DEFAULT_CHUNK_SIZE: usize = 1420;
+}
+
+impl Default for Gelf {
+    fn default() -> Self {
+        Self {
+            chunk_size: Self::DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+impl FromStr for Gelf {
+    type Err = ();
+
+    /// Parse the chunk size, which must leave room for payload after the header
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(chunk_size) if chunk_size > HEADER_LEN => Ok(Self { chunk_size }),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Splits payloads into chunks, numbering the messages
+#[derive(Debug)]
+pub(crate) struct Chunker {
+    /// Maximum size of a chunk including its header
+    chunk_size: usize,
+    /// ID of the next chunked message
+    next_id: u64,
+}
+
+impl Chunker {
+    /// Create a chunker, starting at a random message ID
+    ///
+    /// Graylog merges chunks by ID, so IDs of different forwarders must not collide.
+    pub(crate) fn new(gelf: Gelf) -> Self {
+        Self {
+            chunk_size: gelf.chunk_size,
+            next_id: RandomState::new().build_hasher().finish(),
+        }
+    }
+
+    /// Split `payload` into chunks if it exceeds the chunk size
+    ///
+    /// Returns `None` if the payload fits into a datagram or is a chunk already.
+    /// Payloads which would need more than 128 chunks are an error.
+    pub(crate) fn chunk(&mut self, payload: &[u8]) -> Option<Result<Vec<Vec<u8>>, usize>> {
+        if payload.len() <= self.chunk_size || payload.starts_with(&MAGIC) {
+            return None;
+        }
+
+        let slices: Vec<&[u8]> = payload.chunks(self.chunk_size - HEADER_LEN).collect();
+        if slices.len() > MAX_CHUNKS {
+            return Some(Err(slices.len()));
+        }
+
+        let id = self.next_id.to_be_bytes();
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let chunks = slices
+            .iter()
+            .enumerate()
+            .map(|(seq, slice)| {
+                let mut chunk = Vec::with_capacity(HEADER_LEN + slice.len());
+                chunk.extend_from_slice(&MAGIC);
+                chunk.extend_from_slice(&id);
+                chunk.push(seq as u8);
+                chunk.push(slices.len() as u8);
+                chunk.extend_from_slice(slice);
+                chunk
+            })
+            .collect();
+        Some(Ok(chunks))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn large_payload_chunked() {
+        let mut chunker = Chunker::new(Gelf {
+            chunk_size: HEADER_LEN + 4,
+        });
+        let payload = b"{\"short_message\":\"x\"}";
+
+        let chunks = chunker.chunk(payload).unwrap().unwrap();
+        assert_eq!(6, chunks.len());
+        for (seq, chunk) in chunks.iter().enumerate() {
+            assert_eq!(MAGIC, chunk[..2]);
+            assert_eq!(chunks[0][2..10], chunk[2..10]);
+            assert_eq!([seq as u8, 6], chunk[10..12]);
+        }
+        let merged: Vec<u8> = chunks
+            .iter()
+            .flat_map(|chunk| chunk[HEADER_LEN..].to_vec())
+            .collect();
+        assert_eq!(payload, merged.as_slice());
+
+        // Next message gets another ID
+        let next = chunker.chunk(payload).unwrap().unwrap();
+        assert_ne!(chunks[0][2..10], next[0][2..10]);
+    }
+
+    #[test]
+    fn small_payloads_and_chunks_passed() {
+        let mut chunker = Chunker::new(Gelf::default());
+
+        assert!(chunker.chunk(b"{}").is_none());
+        assert!(chunker.chunk(&[&MAGIC[..], &[0; 2000]].concat()).is_none());
+        assert_eq!(Some(Err(1441)), chunker.chunk(&vec![b'x'; 1408 * 1440 + 1]));
+    }
+}
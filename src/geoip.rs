@@ -0,0 +1,430 @@
+//! GeoIP lookup of sources
+//!
+//! Reads MaxMind databases (`.mmdb`), e.g. GeoLite2-Country and GeoLite2-ASN,
+//! to annotate sources with their country and autonomous system. Operators of
+//! listeners exposed to the internet see where traffic is coming from.
+//!
+//! The databases are a binary search tree over the address bits whose leaves point
+//! into a data section of typed values, followed by a metadata map.
+
+use std::{
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+/// Marker preceding the metadata at the end of a database
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// Size of the separator between search tree and data section
+const DATA_SECTION_SEPARATOR: usize = 16;
+/// Maximum nesting of values, to stop at malformed databases
+const MAX_DEPTH: usize = 32;
+
+/// Country and autonomous system of an address
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Location {
+    /// ISO 3166-1 code of the country
+    pub country: Option<String>,
+    /// Number of the autonomous system
+    pub asn: Option<u32>,
+    /// Organization operating the autonomous system
+    pub as_org: Option<String>,
+}
+
+impl Location {
+    /// Merge what `other` knows into `self`
+    fn merge(&mut self, other: Location) {
+        self.country = self.country.take().or(other.country);
+        self.asn = self.asn.or(other.asn);
+        self.as_org = self.as_org.take().or(other.as_org);
+    }
+
+    /// Check if nothing is known about the address
+    fn is_empty(&self) -> bool {
+        self.country.is_none() && self.asn.is_none() && self.as_org.is_none()
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(country) = &self.country {
+            parts.push(country.clone());
+        }
+        match (self.asn, &self.as_org) {
+            (Some(asn), Some(org)) => parts.push(format!("AS{asn} {org}")),
+            (Some(asn), None) => parts.push(format!("AS{asn}")),
+            (None, Some(org)) => parts.push(org.clone()),
+            (None, None) => {}
+        }
+
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// Set of MaxMind databases to look up addresses in
+#[derive(Debug, Default)]
+pub struct GeoIp {
+    databases: Vec<Database>,
+}
+
+impl GeoIp {
+    /// Load all given databases
+    pub fn open(paths: &[PathBuf]) -> Result<Self, io::Error> {
+        let databases = paths
+            .iter()
+            .map(|path| Database::open(path))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { databases })
+    }
+
+    /// Country and autonomous system of `ip` as far as known to any database
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let mut location = Location::default();
+        for database in &self.databases {
+            if let Some(found) = database.lookup(ip) {
+                location.merge(found);
+            }
+        }
+
+        (!location.is_empty()).then_some(location)
+    }
+}
+
+/// Value in the data section
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Uint(u128),
+    Int(i32),
+    Double(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Value of `key` if this is a map
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+/// Single MaxMind database loaded into memory
+#[derive(Debug)]
+struct Database {
+    /// Content of the database file
+    content: Vec<u8>,
+    /// Number of nodes in the search tree
+    node_count: usize,
+    /// Size of each of the two records of a node in bits
+    record_size: usize,
+    /// Whether the tree holds IPv6 addresses, with IPv4 below `::/96`
+    ipv6: bool,
+    /// Offset of the data section
+    data_offset: usize,
+}
+
+impl Database {
+    fn open(path: &Path) -> Result<Self, io::Error> {
+        let content = fs::read(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        Self::parse(content).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is no valid MaxMind database", path.display()),
+            )
+        })
+    }
+
+    fn parse(content: Vec<u8>) -> Option<Self> {
+        let marker = content
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)?;
+        let metadata_offset = marker + METADATA_MARKER.len();
+        let (metadata, _) = decode(&content[metadata_offset..], 0, 0)?;
+
+        let node_count = usize::try_from(metadata.get("node_count")?.as_uint()?).ok()?;
+        let record_size = usize::try_from(metadata.get("record_size")?.as_uint()?).ok()?;
+        if ![24, 28, 32].contains(&record_size) {
+            return None;
+        }
+        let ipv6 = metadata.get("ip_version")?.as_uint()? == 6;
+        let data_offset = node_count
+            .checked_mul(record_size * 2 / 8)?
+            .checked_add(DATA_SECTION_SEPARATOR)?;
+        if data_offset > marker {
+            return None;
+        }
+
+        Some(Self {
+            content,
+            node_count,
+            record_size,
+            ipv6,
+            data_offset,
+        })
+    }
+
+    /// Look up the location of `ip`
+    fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let bits: Vec<bool> = match (ip, self.ipv6) {
+            // IPv4 addresses are stored below the first 96 bits of zeros
+            (IpAddr::V4(ip), true) => std::iter::repeat_n(false, 96)
+                .chain(bit_iter(&ip.octets()))
+                .collect(),
+            (IpAddr::V4(ip), false) => bit_iter(&ip.octets()).collect(),
+            (IpAddr::V6(ip), true) => bit_iter(&ip.octets()).collect(),
+            (IpAddr::V6(ip), false) => bit_iter(&ip.to_ipv4_mapped()?.octets()).collect(),
+        };
+
+        let mut node = 0;
+        for bit in bits {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, bit)?;
+        }
+
+        if node <= self.node_count {
+            // Not found or tree exhausted without reaching data
+            return None;
+        }
+        let offset = node.checked_sub(self.node_count + DATA_SECTION_SEPARATOR)?;
+        let data = self.content.get(self.data_offset..)?;
+        let (value, _) = decode(data, offset, 0)?;
+
+        Some(Location {
+            country: value
+                .get("country")
+                .and_then(|country| country.get("iso_code"))
+                .and_then(Value::as_str)
+                .map(str::to_owned),
+            asn: value
+                .get("autonomous_system_number")
+                .and_then(Value::as_uint)
+                .and_then(|asn| u32::try_from(asn).ok()),
+            as_org: value
+                .get("autonomous_system_organization")
+                .and_then(Value::as_str)
+                .map(str::to_owned),
+        })
+    }
+
+    /// Left or right record of a node of the search tree
+    fn record(&self, node: usize, right: bool) -> Option<usize> {
+        let node_len = self.record_size * 2 / 8;
+        let bytes = self.content.get(node * node_len..(node + 1) * node_len)?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as usize);
+
+        Some(match (self.record_size, right) {
+            (24, false) => be(&bytes[..3]),
+            (24, true) => be(&bytes[3..]),
+            (28, false) => (bytes[3] as usize & 0xf0) << 20 | be(&bytes[..3]),
+            (28, true) => (bytes[3] as usize & 0x0f) << 24 | be(&bytes[4..]),
+            (_, false) => be(&bytes[..4]),
+            (_, true) => be(&bytes[4..]),
+        })
+    }
+}
+
+/// Bits of an address, most significant first
+fn bit_iter(octets: &[u8]) -> impl Iterator<Item = bool> + '_ {
+    octets
+        .iter()
+        .flat_map(|octet| (0..8).rev().map(move |bit| octet >> bit & 1 == 1))
+}
+
+/// Decode the value at `offset` of a data section
+///
+/// Returns the value and the offset following it. Pointers are relative to the
+/// start of `data` and followed, but don't advance the offset beyond themselves.
+fn decode(data: &[u8], offset: usize, depth: usize) -> Option<(Value, usize)> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let control = *data.get(offset)?;
+    let mut offset = offset + 1;
+
+    let mut kind = control >> 5;
+    if kind == 1 {
+        let (pointer, next) = decode_pointer(data, control, offset)?;
+        let (value, _) = decode(data, pointer, depth + 1)?;
+        return Some((value, next));
+    }
+    if kind == 0 {
+        kind = data.get(offset)?.checked_add(7)?;
+        offset += 1;
+    }
+
+    let (size, mut offset) = decode_size(data, control & 0x1f, offset)?;
+    let bytes = |len: usize| data.get(offset..offset + len);
+    let uint = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .fold(0u128, |acc, byte| acc << 8 | *byte as u128)
+    };
+
+    let value = match kind {
+        2 => Value::String(String::from_utf8_lossy(bytes(size)?).into_owned()),
+        3 => Value::Double(f64::from_be_bytes(bytes(8)?.try_into().ok()?)),
+        4 => Value::Bytes(bytes(size)?.to_vec()),
+        5 | 6 | 9 | 10 => Value::Uint(uint(bytes(size)?)),
+        8 => Value::Int(uint(bytes(size)?) as u32 as i32),
+        14 => return Some((Value::Bool(size != 0), offset)),
+        15 => Value::Double(f32::from_be_bytes(bytes(4)?.try_into().ok()?).into()),
+        7 => {
+            let mut entries = Vec::with_capacity(size.min(64));
+            for _ in 0..size {
+                let (key, next) = decode(data, offset, depth + 1)?;
+                let (value, next) = decode(data, next, depth + 1)?;
+                entries.push((key.as_str()?.to_owned(), value));
+                offset = next;
+            }
+            return Some((Value::Map(entries), offset));
+        }
+        11 => {
+            let mut values = Vec::with_capacity(size.min(64));
+            for _ in 0..size {
+                let (value, next) = decode(data, offset, depth + 1)?;
+                values.push(value);
+                offset = next;
+            }
+            return Some((Value::Array(values), offset));
+        }
+        _ => return None,
+    };
+
+    let len = match kind {
+        3 => 8,
+        15 => 4,
+        _ => size,
+    };
+    offset += len;
+    Some((value, offset))
+}
+
+/// Decode the target of a pointer with the given control byte
+fn decode_pointer(data: &[u8], control: u8, offset: usize) -> Option<(usize, usize)> {
+    let len = ((control >> 3) & 0x3) as usize + 1;
+    let bytes = data.get(offset..offset + len)?;
+    let value = bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as usize);
+    let high = (control & 0x7) as usize;
+
+    let pointer = match len {
+        1 => high << 8 | value,
+        2 => (high << 16 | value) + 2048,
+        3 => (high << 24 | value) + 526_336,
+        _ => value,
+    };
+    Some((pointer, offset + len))
+}
+
+/// Decode the size of a value from the low bits of its control byte
+fn decode_size(data: &[u8], size: u8, offset: usize) -> Option<(usize, usize)> {
+    let extra = |len: usize| {
+        let bytes = data.get(offset..offset + len)?;
+        Some(bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as usize))
+    };
+
+    match size {
+        29 => Some((29 + extra(1)?, offset + 1)),
+        30 => Some((285 + extra(2)?, offset + 2)),
+        31 => Some((65_821 + extra(3)?, offset + 3)),
+        size => Some((size as usize, offset)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Encode a string value
+    fn string(s: &str) -> Vec<u8> {
+        let mut encoded = vec![2 << 5 | s.len() as u8];
+        encoded.extend_from_slice(s.as_bytes());
+        encoded
+    }
+
+    /// Encode an unsigned 32-bit value
+    fn uint32(value: u32) -> Vec<u8> {
+        let mut encoded = vec![6 << 5 | 4];
+        encoded.extend_from_slice(&value.to_be_bytes());
+        encoded
+    }
+
+    /// Encode a map with the given entries
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut encoded = vec![7 << 5 | entries.len() as u8];
+        for (key, value) in entries {
+            encoded.extend(string(key));
+            encoded.extend_from_slice(value);
+        }
+        encoded
+    }
+
+    /// IPv4 database with a single node: `0.0.0.0/1` is German, the rest unknown
+    fn database() -> Vec<u8> {
+        let node_count = 1;
+        let data = map(&[
+            ("country", map(&[("iso_code", string("DE"))])),
+            ("autonomous_system_number", uint32(3320)),
+        ]);
+
+        // Left record points to the data at offset 0, right record is "not found"
+        let mut content = Vec::new();
+        content.extend_from_slice(&(node_count + DATA_SECTION_SEPARATOR as u32).to_be_bytes()[1..]);
+        content.extend_from_slice(&node_count.to_be_bytes()[1..]);
+        content.extend_from_slice(&[0; DATA_SECTION_SEPARATOR]);
+        content.extend(data);
+        content.extend_from_slice(METADATA_MARKER);
+        content.extend(map(&[
+            ("node_count", uint32(node_count)),
+            ("record_size", uint32(24)),
+            ("ip_version", uint32(4)),
+        ]));
+        content
+    }
+
+    #[test]
+    fn lookup_in_database() {
+        let database = Database::parse(database()).unwrap();
+
+        let location = database.lookup("10.1.1.10".parse().unwrap()).unwrap();
+        assert_eq!(Some("DE"), location.country.as_deref());
+        assert_eq!(Some(3320), location.asn);
+        assert_eq!("DE, AS3320", location.to_string());
+        assert_eq!(None, database.lookup("192.168.1.10".parse().unwrap()));
+    }
+
+    #[test]
+    fn pointers_followed() {
+        // Pointer to the string at offset 2, followed by padding and the string
+        let data = [1 << 5, 2, 0, 0, 2 << 5 | 2, b'D', b'E'];
+        let data = [&data[..2], &data[4..]].concat();
+
+        assert_eq!(
+            Some((Value::String("DE".to_owned()), 2)),
+            decode(&data, 0, 0)
+        );
+    }
+}
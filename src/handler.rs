@@ -0,0 +1,85 @@
+//! Custom packet handling
+//!
+//! Applications embedding the forwarder can filter and transform packets
+//! with a chain of [PacketHandler]s, without forking the forwarding loop.
+//! The chain sees every received packet before it is forwarded.
+
+use std::net::SocketAddr;
+
+/// Received packet passed through the handlers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    /// Address the packet was received from
+    pub source: SocketAddr,
+    /// Payload, which handlers may modify
+    pub payload: Vec<u8>,
+}
+
+/// Decision of a handler about a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Pass the packet on to the next handler and finally forward it
+    Forward,
+    /// Drop the packet, skipping the remaining handlers
+    Drop,
+}
+
+/// Handler filtering or transforming packets before they are forwarded
+///
+/// Implemented for closures taking a `&mut Packet`.
+pub trait PacketHandler: Send {
+    /// Inspect or modify a packet and decide whether to forward it
+    fn handle(&mut self, packet: &mut Packet) -> Action;
+}
+
+impl<F> PacketHandler for F
+where
+    F: FnMut(&mut Packet) -> Action + Send,
+{
+    fn handle(&mut self, packet: &mut Packet) -> Action {
+        self(packet)
+    }
+}
+
+/// Pass a packet through the chain of handlers in order
+pub(crate) fn apply(handlers: &mut [Box<dyn PacketHandler>], packet: &mut Packet) -> Action {
+    for handler in handlers {
+        if handler.handle(packet) == Action::Drop {
+            return Action::Drop;
+        }
+    }
+
+    Action::Forward
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chain_applied_in_order() {
+        let mut handlers: Vec<Box<dyn PacketHandler>> = vec![
+            Box::new(
+                |packet: &mut Packet| match packet.payload.starts_with(b"drop") {
+                    true => Action::Drop,
+                    false => Action::Forward,
+                },
+            ),
+            Box::new(|packet: &mut Packet| {
+                packet.payload.make_ascii_uppercase();
+                Action::Forward
+            }),
+        ];
+        let mut packet = Packet {
+            source: "127.0.0.1:4000".parse().unwrap(),
+            payload: b"hello".to_vec(),
+        };
+
+        assert_eq!(Action::Forward, apply(&mut handlers, &mut packet));
+        assert_eq!(b"HELLO", packet.payload.as_slice());
+
+        packet.payload = b"drop me".to_vec();
+        assert_eq!(Action::Drop, apply(&mut handlers, &mut packet));
+        assert_eq!(b"drop me", packet.payload.as_slice());
+    }
+}
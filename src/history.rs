@@ -0,0 +1,136 @@
+//! Summaries of the most recent packets
+//!
+//! At high packet rates, logging every packet is out of the question, yet the packets
+//! right before a failure are often what explains it. The forwarder keeps the time,
+//! source, size and first bytes of the last packets in a ring and dumps them when
+//! forwarding fails or the stream turns silent.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+/// Packets kept by default
+pub const DEFAULT_CAPACITY: usize = 32;
+/// Bytes kept of the start of each payload
+pub const HEAD_LEN: usize = 16;
+
+/// Time, source, size and first bytes of a received packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketSummary {
+    /// Time the packet was received
+    pub time: SystemTime,
+    /// Address the packet was received from
+    pub source: SocketAddr,
+    /// Size of the payload in bytes
+    pub size: usize,
+    head: [u8; HEAD_LEN],
+}
+
+impl PacketSummary {
+    /// Summarize the payload received from `source` at `time`
+    pub fn new(time: SystemTime, source: SocketAddr, payload: &[u8]) -> Self {
+        let mut head = [0; HEAD_LEN];
+        let head_len = payload.len().min(HEAD_LEN);
+        head[..head_len].copy_from_slice(&payload[..head_len]);
+
+        Self {
+            time,
+            source,
+            size: payload.len(),
+            head,
+        }
+    }
+
+    /// First bytes of the payload, up to [HEAD_LEN]
+    pub fn head(&self) -> &[u8] {
+        &self.head[..self.size.min(HEAD_LEN)]
+    }
+}
+
+/// Formats the summary as seconds since the epoch, source, size and head in hex
+impl fmt::Display for PacketSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        write!(
+            f,
+            "{}.{:06} {} {} bytes",
+            time.as_secs(),
+            time.subsec_micros(),
+            self.source,
+            self.size
+        )?;
+        if !self.head().is_empty() {
+            write!(f, ":")?;
+        }
+        for byte in self.head() {
+            write!(f, " {byte:02x}")?;
+        }
+        if self.size > HEAD_LEN {
+            write!(f, " ...")?;
+        }
+        Ok(())
+    }
+}
+
+/// Ring of the summaries of the most recent packets
+#[derive(Debug, Clone)]
+pub struct PacketHistory {
+    summaries: VecDeque<PacketSummary>,
+    capacity: usize,
+}
+
+impl PacketHistory {
+    /// Keep the summaries of the last `capacity` packets
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            summaries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Add the summary of a packet, dropping the oldest if the ring is full
+    pub fn push(&mut self, summary: PacketSummary) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.summaries.len() == self.capacity {
+            self.summaries.pop_front();
+        }
+        self.summaries.push_back(summary);
+    }
+
+    /// Summaries kept, oldest first
+    pub fn summaries(&self) -> Vec<PacketSummary> {
+        self.summaries.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn oldest_dropped() {
+        let source: SocketAddr = "10.1.1.10:4000".parse().unwrap();
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_042);
+        let mut history = PacketHistory::new(2);
+        for payload in [&b"first"[..], b"second", &[0xab; 20]] {
+            history.push(PacketSummary::new(time, source, payload));
+        }
+
+        let summaries = history.summaries();
+        assert_eq!(2, summaries.len());
+        assert_eq!(b"second", summaries[0].head());
+        assert_eq!(
+            "1700000000.000042 10.1.1.10:4000 20 bytes: \
+             ab ab ab ab ab ab ab ab ab ab ab ab ab ab ab ab ...",
+            summaries[1].to_string()
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Instrumentation callbacks
+//!
+//! Applications embedding the forwarder can observe packets, drops, send errors
+//! and changes of the targets as they happen, e.g. to collect metrics of their own,
+//! without polling the shared state or parsing logs. Hooks run on the forwarding
+//! thread and should return quickly.
+
+use std::{fmt, io, net::SocketAddr};
+
+use crate::{alert::RateAlert, history::PacketSummary, silence::StreamState};
+
+/// Reason a received packet was not forwarded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Sent by this forwarder and received again
+    Looped,
+    /// Forwarding is paused
+    Paused,
+    /// Dropped by a packet handler
+    Filtered,
+    /// Hop limit of the packet reached
+    Expired,
+    /// Needs more GELF chunks than allowed
+    Oversized,
+}
+
+/// Change of a target picked up by the forwarding loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetState {
+    /// Packets are forwarded to the target from now on
+    Active,
+    /// Packets are no longer forwarded to the target
+    Inactive,
+}
+
+/// Event the summaries of the most recent packets are dumped for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpTrigger {
+    /// Forwarding stopped with an error
+    Failed,
+    /// No packet arrived for the time of the dead-stream alarm
+    Silent,
+}
+
+impl fmt::Display for DumpTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failed => write!(f, "forwarding failed"),
+            Self::Silent => write!(f, "the stream turned silent"),
+        }
+    }
+}
+
+type PacketHook = Box<dyn FnMut(SocketAddr, &[u8]) + Send>;
+type SendErrorHook = Box<dyn FnMut(SocketAddr, &io::Error) + Send>;
+type DropHook = Box<dyn FnMut(SocketAddr, usize, DropReason) + Send>;
+type TargetHook = Box<dyn FnMut(SocketAddr, TargetState) + Send>;
+type RateAlertHook = Box<dyn FnMut(RateAlert) + Send>;
+type StreamStateHook = Box<dyn FnMut(StreamState) + Send>;
+type HistoryHook = Box<dyn FnMut(DumpTrigger, &[PacketSummary]) + Send>;
+
+/// Callbacks set on the forwarder, each optional
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub(crate) packet: Option<PacketHook>,
+    pub(crate) send_error: Option<SendErrorHook>,
+    pub(crate) drop: Option<DropHook>,
+    pub(crate) target_state: Option<TargetHook>,
+    pub(crate) rate_alert: Option<RateAlertHook>,
+    pub(crate) stream_state: Option<StreamStateHook>,
+    pub(crate) history: Option<HistoryHook>,
+}
+
+impl Hooks {
+    /// Report a packet received from `source`
+    pub(crate) fn packet(&mut self, source: SocketAddr, payload: &[u8]) {
+        if let Some(hook) = &mut self.packet {
+            hook(source, payload);
+        }
+    }
+
+    /// Report a failure to send to `destination`
+    pub(crate) fn send_error(&mut self, destination: SocketAddr, error: &io::Error) {
+        if let Some(hook) = &mut self.send_error {
+            hook(destination, error);
+        }
+    }
+
+    /// Report a packet of `num_bytes` from `source` which is not forwarded
+    pub(crate) fn drop(&mut self, source: SocketAddr, num_bytes: usize, reason: DropReason) {
+        if let Some(hook) = &mut self.drop {
+            hook(source, num_bytes, reason);
+        }
+    }
+
+    /// Report a change of the packet rate relative to the envelope
+    pub(crate) fn rate_alert(&mut self, alert: RateAlert) {
+        if let Some(hook) = &mut self.rate_alert {
+            hook(alert);
+        }
+    }
+
+    /// Report a transition of the stream between active and silent
+    pub(crate) fn stream_state(&mut self, state: StreamState) {
+        if let Some(hook) = &mut self.stream_state {
+            hook(state);
+        }
+    }
+
+    /// Report the summaries of the most recent packets, oldest first
+    pub(crate) fn history(&mut self, trigger: DumpTrigger, summaries: &[PacketSummary]) {
+        if let Some(hook) = &mut self.history {
+            hook(trigger, summaries);
+        }
+    }
+
+    /// Report the targets which became active or inactive between `old` and `new`
+    pub(crate) fn targets_changed(&mut self, old: &[SocketAddr], new: &[SocketAddr]) {
+        let Some(hook) = &mut self.target_state else {
+            return;
+        };
+        for addr in old.iter().filter(|addr| !new.contains(addr)) {
+            hook(*addr, TargetState::Inactive);
+        }
+        for addr in new.iter().filter(|addr| !old.contains(addr)) {
+            hook(*addr, TargetState::Active);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn target_changes_reported() {
+        let (sender, changes) = mpsc::channel();
+        let mut hooks = Hooks {
+            target_state: Some(Box::new(move |addr, state| {
+                let _ = sender.send((addr, state));
+            })),
+            ..Hooks::default()
+        };
+
+        let a: SocketAddr = "10.1.1.10:4000".parse().unwrap();
+        let b: SocketAddr = "10.1.1.11:4000".parse().unwrap();
+        let c: SocketAddr = "[::1]:4000".parse().unwrap();
+        hooks.targets_changed(&[a, b], &[b, c]);
+
+        let changes: Vec<_> = changes.try_iter().collect();
+        assert_eq!(
+            vec![(a, TargetState::Inactive), (c, TargetState::Active)],
+            changes
+        );
+    }
+}
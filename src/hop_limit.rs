@@ -0,0 +1,79 @@
+//! Hop-limit header for chained forwarders
+//!
+//! Forwarders relaying to each other prefix packets with a small header
+//! carrying the number of hops left. Each hop decrements it and drops the packet
+//! at zero, so a loop in a chain or mesh of forwarders dies out instead of
+//! circulating forever.
+//!
+//! The header is `UFH` followed by one byte with the hops left.
+
+/// Magic bytes identifying the header
+const MAGIC: &[u8; 3] = b"UFH";
+
+/// Handling of the hop-limit header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum HopLimit {
+    /// Forward with a header, packets without one may pass this many forwarders
+    Tag(u8),
+    /// Forward the payload without header, for the last forwarder of a chain
+    Strip,
+}
+
+impl HopLimit {
+    /// Prepare a received packet for forwarding
+    ///
+    /// Returns `None` if the packet used up its hops and must be dropped.
+    /// Otherwise the packet to forward is either `out` or the payload of `packet`.
+    pub(crate) fn apply<'a>(&self, packet: &'a [u8], out: &'a mut Vec<u8>) -> Option<&'a [u8]> {
+        let (hops, payload) = match packet.strip_prefix(MAGIC) {
+            Some([hops, payload @ ..]) => (*hops, payload),
+            _ => match self {
+                Self::Tag(limit) => (*limit, packet),
+                Self::Strip => return Some(packet),
+            },
+        };
+        let hops = hops.checked_sub(1)?;
+
+        match self {
+            Self::Tag(_) => {
+                out.clear();
+                out.extend_from_slice(MAGIC);
+                out.push(hops);
+                out.extend_from_slice(payload);
+                Some(out)
+            }
+            Self::Strip => Some(payload),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hops_decrement_until_dropped() {
+        let hop_limit = HopLimit::Tag(2);
+        let mut out = Vec::new();
+
+        let packet = hop_limit.apply(b"data", &mut out).unwrap().to_vec();
+        assert_eq!(b"UFH\x01data", packet.as_slice());
+        let packet = hop_limit.apply(&packet, &mut out).unwrap().to_vec();
+        assert_eq!(b"UFH\x00data", packet.as_slice());
+        assert!(hop_limit.apply(&packet, &mut out).is_none());
+    }
+
+    #[test]
+    fn strip_header() {
+        let mut out = Vec::new();
+
+        assert_eq!(
+            Some(&b"data"[..]),
+            HopLimit::Strip.apply(b"UFH\x01data", &mut out)
+        );
+        assert_eq!(Some(&b"data"[..]), HopLimit::Strip.apply(b"data", &mut out));
+        assert!(HopLimit::Strip.apply(b"UFH\x00data", &mut out).is_none());
+    }
+}
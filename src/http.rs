@@ -0,0 +1,485 @@
+//! Minimal HTTP/1.1 server and client
+//!
+//! Just enough HTTP to serve small requests of local tools like `curl`
+//! and to query service registries, keeping the crate free of dependencies.
+//! Connections are handled by a few threads started up front, so that no threads
+//! are started once sandboxed, and closed after each response. A client has to
+//! send its request within a deadline, so stalled clients only briefly occupy a
+//! thread. The server is only built with the `control` feature.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+#[cfg(feature = "control")]
+use std::{
+    net::TcpListener,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+/// Maximum size of the request line and headers
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+/// Maximum size of a request body
+#[cfg(any(feature = "control", test))]
+const MAX_BODY_SIZE: usize = 64 * 1024;
+/// Maximum size of a response received as client
+const MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+/// Number of threads handling connections of a server
+#[cfg(feature = "control")]
+const WORKERS: usize = 4;
+/// Time a client may take to send its request, and to receive each part of the response
+#[cfg(feature = "control")]
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parsed HTTP request
+#[cfg(any(feature = "control", test))]
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    /// Method like `GET` or `POST`
+    pub method: String,
+    /// Path without query
+    pub path: String,
+    /// Query string without the leading `?`, if any
+    pub query: Option<String>,
+    /// Value of the `Host` header, if any
+    pub host: Option<String>,
+    /// Value of the `Origin` header sent by browsers, if any
+    pub origin: Option<String>,
+    /// Request body
+    pub body: Vec<u8>,
+}
+
+/// HTTP response to send
+#[cfg(feature = "control")]
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    /// Status code
+    pub status: u16,
+    /// Value of the `Content-Type` header
+    pub content_type: &'static str,
+    /// Response body
+    pub body: Vec<u8>,
+}
+
+#[cfg(feature = "control")]
+impl Response {
+    /// Response with a JSON body
+    pub fn json(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    /// Response with a plain text body
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    /// Response with an HTML body
+    pub fn html(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/html; charset=utf-8",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    /// Empty `404 Not Found` response
+    pub fn not_found() -> Self {
+        Self::text(404, "not found\n")
+    }
+}
+
+/// Response received as client
+#[derive(Debug, PartialEq)]
+pub(crate) struct ClientResponse {
+    /// Status code
+    pub(crate) status: u16,
+    /// Header names and values
+    pub(crate) headers: Vec<(String, String)>,
+    /// Response body
+    pub(crate) body: Vec<u8>,
+}
+
+impl ClientResponse {
+    /// Value of the header `name`, compared case-insensitively
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Send a request to the server at `addr` and read the complete response
+///
+/// Requests are sent as HTTP/1.0, so that the server closes the connection
+/// after the response instead of using chunked encoding.
+pub(crate) fn request(
+    addr: SocketAddr,
+    host: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+) -> Result<ClientResponse, io::Error> {
+    let (mut response, reader) = stream_request(addr, host, method, path, headers, body, timeout)?;
+
+    let mut body = Vec::new();
+    reader
+        .take(MAX_RESPONSE_SIZE as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > MAX_RESPONSE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "response too large",
+        ));
+    }
+    if let Some(length) = response
+        .header("content-length")
+        .and_then(|length| length.parse().ok())
+    {
+        body.truncate(length);
+    }
+
+    response.body = body;
+    Ok(response)
+}
+
+/// Send a request to the server at `addr` and read the head of the response
+///
+/// Returns the response without body and a reader of the body,
+/// e.g. to consume a stream of events as they arrive.
+pub(crate) fn stream_request(
+    addr: SocketAddr,
+    host: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+) -> Result<(ClientResponse, BufReader<TcpStream>), io::Error> {
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut head = format!(
+        "{method} {path} HTTP/1.0\r\nHost: {host}\r\nContent-Length: {}\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+
+    let mut reader = BufReader::new(stream);
+    let response = read_response_head(&mut reader)?;
+    Ok((response, reader))
+}
+
+/// Read the status line and headers of a response
+fn read_response_head(reader: &mut impl BufRead) -> Result<ClientResponse, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+    // Lines are read without limit otherwise, leave the body for the caller
+    let mut reader = reader.take(MAX_HEAD_SIZE as u64 + 1);
+
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    if status_line.len() > MAX_HEAD_SIZE {
+        return Err(invalid("response head too large"));
+    }
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("malformed status line"))?;
+
+    let mut head_size = status_line.len();
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("incomplete response head"));
+        }
+        head_size += line.len();
+        if head_size > MAX_HEAD_SIZE {
+            return Err(invalid("response head too large"));
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+
+    Ok(ClientResponse {
+        status,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Bind to `addr` and handle requests with `handler` on new threads
+///
+/// Each of the threads accepts connections of its own, so a stalled client
+/// does not hold up the others.
+#[cfg(feature = "control")]
+pub fn serve(
+    addr: SocketAddr,
+    name: &str,
+    handler: impl Fn(&Request) -> Response + Send + Sync + 'static,
+) -> Result<Vec<JoinHandle<()>>, io::Error> {
+    let listener = TcpListener::bind(addr)?;
+    let handler = Arc::new(handler);
+
+    (0..WORKERS)
+        .map(|_| {
+            let listener = listener.try_clone()?;
+            let handler = Arc::clone(&handler);
+            thread::Builder::new().name(name.to_owned()).spawn(move || {
+                for stream in listener.incoming() {
+                    // Errors of single connections only affect that client
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let _ = handle_connection(stream, &*handler);
+                }
+            })
+        })
+        .collect()
+}
+
+/// Read one request, respond and close the connection
+#[cfg(feature = "control")]
+fn handle_connection(
+    mut stream: TcpStream,
+    handler: &impl Fn(&Request) -> Response,
+) -> Result<(), io::Error> {
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let reader = DeadlineReader {
+        stream: &stream,
+        deadline: Instant::now() + CLIENT_TIMEOUT,
+    };
+    let response = match read_request(reader) {
+        Ok(request) => handler(&request),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Response::text(400, "bad request\n"),
+        Err(e) => return Err(e),
+    };
+
+    write_response(&mut stream, &response)
+}
+
+/// Reader of a stream failing once the deadline has passed
+///
+/// A read timeout alone is reset by each byte a client trickles in.
+#[cfg(feature = "control")]
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+#[cfg(feature = "control")]
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "request not received in time",
+            ));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Read and parse a request from `stream`
+#[cfg(any(feature = "control", test))]
+pub(crate) fn read_request(stream: impl Read) -> Result<Request, io::Error> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+
+    let mut reader = BufReader::new(stream.take((MAX_HEAD_SIZE + MAX_BODY_SIZE) as u64));
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+        None => (target.to_owned(), None),
+    };
+
+    let mut head_size = request_line.len();
+    let mut content_length = 0;
+    let mut host = None;
+    let mut origin = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("incomplete headers"));
+        }
+        head_size += line.len();
+        if head_size > MAX_HEAD_SIZE {
+            return Err(invalid("headers too large"));
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .trim()
+                .parse()
+                .map_err(|_| invalid("invalid content length"))?;
+        } else if name.eq_ignore_ascii_case("host") {
+            host = Some(value.trim().to_owned());
+        } else if name.eq_ignore_ascii_case("origin") {
+            origin = Some(value.trim().to_owned());
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(invalid("body too large"));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Request {
+        method: method.to_owned(),
+        path,
+        query,
+        host,
+        origin,
+        body,
+    })
+}
+
+/// Write `response` to `stream`
+#[cfg(feature = "control")]
+fn write_response(mut stream: impl Write, response: &Response) -> Result<(), io::Error> {
+    let reason = match response.status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        421 => "Misdirected Request",
+        _ => "",
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_request_with_body_ok() {
+        let raw = b"POST /targets?x=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 14\r\n\r\n127.0.0.1:4001";
+
+        let expected = Request {
+            method: "POST".to_owned(),
+            path: "/targets".to_owned(),
+            query: Some("x=1".to_owned()),
+            host: Some("localhost".to_owned()),
+            origin: None,
+            body: b"127.0.0.1:4001".to_vec(),
+        };
+        assert_eq!(expected, read_request(&raw[..]).unwrap());
+    }
+
+    #[test]
+    fn read_response_head_ok() {
+        let mut raw = &b"HTTP/1.1 200 OK\r\nX-Consul-Index: 42\r\nContent-Length: 2\r\n\r\n[]"[..];
+
+        let response = read_response_head(&mut raw).unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(Some("42"), response.header("x-consul-index"));
+        assert_eq!(b"[]", raw);
+    }
+
+    #[test]
+    fn read_response_head_oversized_err() {
+        // Endless status line
+        let raw = (&b"HTTP/1.1 200 "[..]).chain(io::repeat(b'A'));
+
+        let error = read_response_head(&mut BufReader::new(raw)).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+        assert_eq!("response head too large", error.to_string());
+
+        let mut headers = b"HTTP/1.1 200 OK\r\n".to_vec();
+        for _ in 0..MAX_HEAD_SIZE {
+            headers.extend_from_slice(b"X: 1\r\n");
+        }
+        let error = read_response_head(&mut &headers[..]).unwrap_err();
+        assert_eq!("response head too large", error.to_string());
+    }
+
+    #[cfg(feature = "control")]
+    #[test]
+    fn stalled_client_not_blocking() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        serve(addr, "test", |_| Response::text(200, "ok\n")).unwrap();
+
+        let _stalled = TcpStream::connect(addr).unwrap();
+        let response = request(
+            addr,
+            "localhost",
+            "GET",
+            "/",
+            &[],
+            &[],
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(b"ok\n", &response.body[..]);
+    }
+
+    #[test]
+    fn read_request_malformed_err() {
+        let raw = b"GARBAGE\r\n\r\n";
+
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            read_request(&raw[..]).unwrap_err().kind()
+        );
+    }
+}
@@ -0,0 +1,201 @@
+//! Hub relay between unicast peers
+//!
+//! In hub mode, every peer sending to the listener is remembered and each received
+//! packet is relayed to all other peers, which emulates a multicast group over
+//! unicast. Peers are forgotten when they have not sent anything for a while.
+//!
+//! The peers can be checkpointed to a file and restored from it, so that a quick
+//! restart or upgrade keeps relaying between established peers. The file holds one
+//! peer per line with the time of its last packet in seconds since the Unix epoch.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs::{self, File},
+    io::{self, Write as _},
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Time after which silent peers are forgotten if not configured otherwise
+pub const DEFAULT_PEER_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Peers known to the hub with the time they were last heard of
+#[derive(Debug)]
+pub(crate) struct Peers {
+    /// Time after which silent peers are forgotten
+    expiry: Duration,
+    /// Known peers with the time of their last packet
+    last_seen: HashMap<SocketAddr, Instant>,
+    /// Whether peers were seen since the last checkpoint
+    changed: bool,
+}
+
+impl Peers {
+    pub(crate) fn new(expiry: Duration) -> Self {
+        Self {
+            expiry,
+            last_seen: HashMap::new(),
+            changed: false,
+        }
+    }
+
+    /// Remember that a packet was received from `peer` and forget expired peers
+    pub(crate) fn seen(&mut self, peer: SocketAddr, now: Instant) {
+        self.last_seen.insert(peer, now);
+        self.changed = true;
+        self.last_seen
+            .retain(|_, last_seen| now.duration_since(*last_seen) <= self.expiry);
+    }
+
+    /// All known peers except `source`
+    pub(crate) fn others(&self, source: SocketAddr) -> impl Iterator<Item = &SocketAddr> {
+        self.last_seen.keys().filter(move |peer| **peer != source)
+    }
+
+    /// Number of known peers
+    pub(crate) fn len(&self) -> usize {
+        self.last_seen.len()
+    }
+
+    /// Whether peers were seen since the last checkpoint
+    pub(crate) fn changed(&self) -> bool {
+        self.changed
+    }
+
+    /// Restore the peers checkpointed to `path` which have not expired yet
+    ///
+    /// Returns the number of restored peers, none if the file doesn't exist.
+    /// Malformed lines, e.g. of a checkpoint cut short, are skipped.
+    pub(crate) fn restore(&mut self, path: &Path) -> Result<usize, io::Error> {
+        let checkpoint = match fs::read_to_string(path) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        Ok(self.restore_from(&checkpoint, Instant::now(), SystemTime::now()))
+    }
+
+    /// Write the known peers to `path`
+    ///
+    /// The peers are written to a file next to it which then replaces it, so that a
+    /// crash while writing leaves the previous checkpoint intact.
+    pub(crate) fn checkpoint(&mut self, path: &Path) -> Result<(), io::Error> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".tmp");
+        let mut file = File::create(&partial)?;
+        file.write_all(
+            self.checkpoint_at(Instant::now(), SystemTime::now())
+                .as_bytes(),
+        )?;
+        file.sync_all()?;
+        fs::rename(&partial, path)?;
+        self.changed = false;
+        Ok(())
+    }
+
+    fn restore_from(&mut self, checkpoint: &str, now: Instant, wall_now: SystemTime) -> usize {
+        let mut restored = 0;
+        for line in checkpoint.lines() {
+            let Some((peer, secs)) = line.split_once(' ') else {
+                continue;
+            };
+            let (Ok(peer), Ok(secs)) = (peer.parse(), secs.parse()) else {
+                continue;
+            };
+            let Ok(last_seen) = Duration::try_from_secs_f64(secs) else {
+                continue;
+            };
+            let age = wall_now
+                .duration_since(SystemTime::UNIX_EPOCH + last_seen)
+                .unwrap_or_default();
+            if age > self.expiry {
+                continue;
+            }
+            self.last_seen
+                .insert(peer, now.checked_sub(age).unwrap_or(now));
+            restored += 1;
+        }
+        restored
+    }
+
+    fn checkpoint_at(&self, now: Instant, wall_now: SystemTime) -> String {
+        let mut checkpoint = String::new();
+        for (peer, last_seen) in &self.last_seen {
+            let last_seen = wall_now - now.duration_since(*last_seen);
+            let secs = last_seen
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            let _ = writeln!(checkpoint, "{peer} {secs:.3}");
+        }
+        checkpoint
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn peers_relay_to_others_and_expire() {
+        let first: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+        let start = Instant::now();
+        let mut peers = Peers::new(Duration::from_secs(10));
+
+        peers.seen(first, start);
+        peers.seen(second, start + Duration::from_secs(5));
+        assert_eq!(vec![&second], peers.others(first).collect::<Vec<_>>());
+
+        peers.seen(second, start + Duration::from_secs(11));
+        assert_eq!(1, peers.len());
+        assert_eq!(0, peers.others(second).count());
+    }
+
+    #[test]
+    fn peers_checkpointed_and_restored() {
+        let first: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let second: SocketAddr = "[::1]:4002".parse().unwrap();
+        let start = Instant::now();
+        let wall_start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut peers = Peers::new(Duration::from_secs(10));
+        peers.seen(first, start);
+        peers.seen(second, start + Duration::from_secs(4));
+
+        let checkpoint = peers.checkpoint_at(start + Duration::from_secs(5), wall_start);
+        assert!(checkpoint.contains("127.0.0.1:4001 1699999995.000\n"));
+        assert!(checkpoint.contains("[::1]:4002 1699999999.000\n"));
+
+        // Restarted after 8s, when the first peer expired
+        let mut restored = Peers::new(Duration::from_secs(10));
+        let checkpoint = format!("{checkpoint}garbage\n127.0.0.1:4003");
+        let now = Instant::now();
+        assert_eq!(
+            1,
+            restored.restore_from(&checkpoint, now, wall_start + Duration::from_secs(8))
+        );
+        assert_eq!(vec![&second], restored.others(first).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn checkpoint_replaces_file() {
+        let path = std::env::temp_dir().join(format!("udpforwarder-peers-{}", std::process::id()));
+        fs::write(&path, "127.0.0.1:4001 0.000\n").unwrap();
+        let mut peers = Peers::new(Duration::from_secs(10));
+        peers.seen("127.0.0.1:4002".parse().unwrap(), Instant::now());
+
+        peers.checkpoint(&path).unwrap();
+        assert!(!peers.changed());
+        let checkpoint = fs::read_to_string(&path).unwrap();
+        assert!(checkpoint.starts_with("127.0.0.1:4002 "));
+        assert_eq!(1, checkpoint.lines().count());
+        assert!(
+            !path
+                .with_file_name(format!("udpforwarder-peers-{}.tmp", std::process::id()))
+                .exists()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+//! iperf 2 compatible UDP throughput test
+//!
+//! Speaks the UDP test protocol of iperf 2, so that path capacity between forwarder
+//! hosts can be validated against `iperf -u` on either end. iperf3 is not supported, it
+//! negotiates its tests over a TCP control connection which is not implemented here. Every datagram starts with
+//! a 32-bit sequence number and the send time in seconds and microseconds, all
+//! big-endian. The client ends the stream with a negative sequence number, which the
+//! server answers with a report of what arrived: bytes, lost and reordered datagrams
+//! and the jitter of their transit times (RFC 3550).
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{ListenerBuilder, ListenerSpec, tools::is_timeout};
+
+/// Bandwidth of the client if not configured otherwise, in bits per second
+pub const DEFAULT_BANDWIDTH: u64 = 1_000_000;
+
+/// Duration of the test if not configured otherwise
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+
+/// Datagram size of the client if not configured otherwise, fitting an Ethernet frame
+pub const DEFAULT_LEN: usize = 1470;
+
+/// Length of the sequence number and send time starting each datagram
+const HEADER_LEN: usize = 12;
+
+/// Length of the report following the header in the answer to the final datagram
+const REPORT_LEN: usize = 40;
+
+/// Flag of the report marking the version of its layout
+const REPORT_VERSION1: u32 = 0x8000_0000;
+
+/// Number of final datagrams sent while waiting for the report of the server
+const FIN_ATTEMPTS: usize = 10;
+
+/// Time to wait for the report of the server after each final datagram
+const FIN_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// What arrived of a stream at the server
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StreamReport {
+    /// Time from the first to the last datagram
+    pub duration: Duration,
+    /// Number of bytes received
+    pub bytes: u64,
+    /// Number of datagrams received
+    pub packets: u64,
+    /// Number of datagrams which did not arrive
+    pub lost: u64,
+    /// Number of datagrams which arrived after a later one
+    pub out_of_order: u64,
+    /// Smoothed variation of the transit time of the datagrams
+    pub jitter: Duration,
+}
+
+impl StreamReport {
+    /// Received bits per second
+    pub fn bandwidth(&self) -> f64 {
+        self.bytes as f64 * 8.0 / self.duration.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Share of the datagrams sent which did not arrive, in percent
+    pub fn loss_percent(&self) -> f64 {
+        match self.packets + self.lost {
+            0 => 0.0,
+            total => self.lost as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+/// What the client sent and, if it answered, the report of the server
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientReport {
+    /// Time spent sending
+    pub duration: Duration,
+    /// Number of bytes sent
+    pub bytes: u64,
+    /// Number of datagrams sent
+    pub packets: u64,
+    /// What arrived at the server, `None` if the server did not answer
+    pub server: Option<StreamReport>,
+}
+
+/// Send datagrams of `len` bytes to `target` at `bandwidth` bits per second for `duration`
+pub fn client(
+    target: SocketAddr,
+    bandwidth: u64,
+    duration: Duration,
+    len: usize,
+) -> Result<ClientReport, io::Error> {
+    if len < HEADER_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("datagrams need to be at least {HEADER_LEN} bytes"),
+        ));
+    }
+    if bandwidth == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "bandwidth needs to be positive",
+        ));
+    }
+    let socket = match target {
+        SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+        SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+    };
+    let interval = Duration::from_secs_f64(len as f64 * 8.0 / bandwidth as f64);
+    let mut datagram = vec![0; len];
+
+    let start = Instant::now();
+    let mut next_send = start;
+    let mut id: i32 = 0;
+    while start.elapsed() < duration && id < i32::MAX {
+        write_header(&mut datagram, id);
+        socket.send_to(&datagram, target)?;
+        id += 1;
+
+        next_send += interval;
+        if let Some(wait) = next_send.checked_duration_since(Instant::now()) {
+            thread::sleep(wait.min(duration.saturating_sub(start.elapsed())));
+        }
+    }
+    let sent = start.elapsed();
+
+    // Repeat the final datagram until the server answers with its report
+    socket.set_read_timeout(Some(FIN_TIMEOUT))?;
+    let mut server = None;
+    let mut buffer = [0; HEADER_LEN + REPORT_LEN];
+    for _ in 0..FIN_ATTEMPTS {
+        write_header(&mut datagram, -id);
+        socket.send_to(&datagram, target)?;
+        match socket.recv_from(&mut buffer) {
+            Ok((num_bytes, source)) if source == target => {
+                server = parse_report(&buffer[..num_bytes]);
+                if server.is_some() {
+                    break;
+                }
+            }
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(ClientReport {
+        duration: sent,
+        bytes: id as u64 * len as u64,
+        packets: id as u64,
+        server,
+    })
+}
+
+/// Receive streams on the listener and call `report` with what arrived of each
+///
+/// Streams of several clients are told apart by their source address.
+pub fn server(
+    listener_spec: ListenerSpec,
+    mut report: impl FnMut(SocketAddr, StreamReport),
+) -> Result<(), io::Error> {
+    let socket = ListenerBuilder::new(&listener_spec).bind()?;
+
+    let mut streams: HashMap<SocketAddr, Stream> = HashMap::new();
+    let mut finished: HashMap<SocketAddr, StreamReport> = HashMap::new();
+    let mut buffer = [0; 65_536];
+
+    loop {
+        let (num_bytes, source) = socket.recv_from(&mut buffer)?;
+        let Some((id, sent_at)) = parse_header(&buffer[..num_bytes]) else {
+            continue;
+        };
+        let arrived_at = since_epoch(SystemTime::now());
+
+        if id >= 0 {
+            finished.remove(&source);
+            streams
+                .entry(source)
+                .or_insert_with(Stream::new)
+                .add(id, num_bytes, sent_at, arrived_at);
+            continue;
+        }
+
+        // Final datagram, possibly repeated after the report got lost
+        let stream_report = match streams.remove(&source) {
+            Some(stream) => {
+                let stream_report = stream.report(id.unsigned_abs());
+                report(source, stream_report);
+                finished.insert(source, stream_report);
+                stream_report
+            }
+            None => match finished.get(&source) {
+                Some(stream_report) => *stream_report,
+                None => continue,
+            },
+        };
+        let mut answer = [0; HEADER_LEN + REPORT_LEN];
+        write_header(&mut answer, id);
+        write_report(&mut answer[HEADER_LEN..], &stream_report);
+        socket.send_to(&answer, source)?;
+    }
+}
+
+/// Stream of a client while it is running
+struct Stream {
+    first: Instant,
+    last: Instant,
+    bytes: u64,
+    packets: u64,
+    /// Highest sequence number seen
+    max_id: Option<u32>,
+    out_of_order: u64,
+    /// Transit time of the previous datagram, in seconds
+    transit: Option<f64>,
+    /// Jitter in seconds
+    jitter: f64,
+}
+
+impl Stream {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            first: now,
+            last: now,
+            bytes: 0,
+            packets: 0,
+            max_id: None,
+            out_of_order: 0,
+            transit: None,
+            jitter: 0.0,
+        }
+    }
+
+    /// Count a datagram, the times being seconds since the epoch on sender and receiver
+    fn add(&mut self, id: i32, num_bytes: usize, sent_at: f64, arrived_at: f64) {
+        let id = id.unsigned_abs();
+        self.last = Instant::now();
+        self.bytes += num_bytes as u64;
+        self.packets += 1;
+        match self.max_id {
+            Some(max_id) if id < max_id => self.out_of_order += 1,
+            _ => self.max_id = Some(id),
+        }
+
+        // The clocks need not be synchronized, only the variation of the transit counts
+        let transit = arrived_at - sent_at;
+        if let Some(previous) = self.transit.replace(transit) {
+            self.jitter += ((transit - previous).abs() - self.jitter) / 16.0;
+        }
+    }
+
+    /// Report of the stream, given the number of datagrams the client sent
+    fn report(&self, sent: u32) -> StreamReport {
+        let expected = self.max_id.map_or(0, |max_id| max_id as u64 + 1);
+        let expected = expected.max(sent as u64);
+        StreamReport {
+            duration: self.last - self.first,
+            bytes: self.bytes,
+            packets: self.packets,
+            lost: expected.saturating_sub(self.packets),
+            out_of_order: self.out_of_order,
+            jitter: Duration::from_secs_f64(self.jitter),
+        }
+    }
+}
+
+/// Seconds since the epoch, zero for earlier times
+fn since_epoch(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Write the sequence number and the current time to the start of `datagram`
+fn write_header(datagram: &mut [u8], id: i32) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    datagram[..4].copy_from_slice(&id.to_be_bytes());
+    datagram[4..8].copy_from_slice(&(now.as_secs() as u32).to_be_bytes());
+    datagram[8..12].copy_from_slice(&now.subsec_micros().to_be_bytes());
+}
+
+/// Sequence number and send time in seconds since the epoch of a datagram
+fn parse_header(datagram: &[u8]) -> Option<(i32, f64)> {
+    let (header, _) = datagram.split_first_chunk::<HEADER_LEN>()?;
+    let (words, _) = header.as_chunks::<4>();
+    let id = i32::from_be_bytes(words[0]);
+    let secs = u32::from_be_bytes(words[1]) as f64;
+    let micros = u32::from_be_bytes(words[2]) as f64;
+    Some((id, secs + micros / 1e6))
+}
+
+/// Write the report of the server in the layout of iperf 2
+fn write_report(buffer: &mut [u8], report: &StreamReport) {
+    let jitter = report.jitter;
+    let words = [
+        REPORT_VERSION1,
+        (report.bytes >> 32) as u32,
+        report.bytes as u32,
+        report.duration.as_secs() as u32,
+        report.duration.subsec_micros(),
+        report.lost as u32,
+        report.out_of_order as u32,
+        (report.packets + report.lost) as u32,
+        jitter.as_secs() as u32,
+        jitter.subsec_micros(),
+    ];
+    for (chunk, word) in buffer.chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+}
+
+/// Parse the answer of the server to the final datagram
+fn parse_report(answer: &[u8]) -> Option<StreamReport> {
+    let report = answer.get(HEADER_LEN..HEADER_LEN + REPORT_LEN)?;
+    let (words, _) = report.as_chunks::<4>();
+    let words: Vec<u32> = words.iter().map(|word| u32::from_be_bytes(*word)).collect();
+    let &[
+        flags,
+        len_high,
+        len_low,
+        secs,
+        micros,
+        lost,
+        out_of_order,
+        datagrams,
+        jitter_secs,
+        jitter_micros,
+    ] = words.as_slice()
+    else {
+        return None;
+    };
+    if flags & REPORT_VERSION1 == 0 {
+        return None;
+    }
+
+    let duration =
+        |secs: u32, micros: u32| Duration::from_micros(secs as u64 * 1_000_000 + micros as u64);
+    Some(StreamReport {
+        duration: duration(secs, micros),
+        bytes: (len_high as u64) << 32 | len_low as u64,
+        packets: datagrams.saturating_sub(lost) as u64,
+        lost: lost as u64,
+        out_of_order: out_of_order as u64,
+        jitter: duration(jitter_secs, jitter_micros),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report_round_trip() {
+        let report = StreamReport {
+            duration: Duration::from_millis(10_250),
+            bytes: 5 << 32 | 1470,
+            packets: 900,
+            lost: 100,
+            out_of_order: 3,
+            jitter: Duration::from_micros(1_250),
+        };
+        let mut answer = [0; HEADER_LEN + REPORT_LEN];
+        write_header(&mut answer, -1000);
+        write_report(&mut answer[HEADER_LEN..], &report);
+
+        assert_eq!(-1000, parse_header(&answer).unwrap().0);
+        assert_eq!(Some(report), parse_report(&answer));
+        assert_eq!(10.0, report.loss_percent());
+    }
+
+    #[test]
+    fn losses_and_reordering_counted() {
+        let mut stream = Stream::new();
+        for (id, sent_at, arrived_at) in [(0, 0.0, 1.0), (2, 0.2, 1.2), (1, 0.1, 1.5)] {
+            stream.add(id, 100, sent_at, arrived_at);
+        }
+
+        let report = stream.report(4);
+        assert_eq!(3, report.packets);
+        assert_eq!(1, report.lost);
+        assert_eq!(1, report.out_of_order);
+        assert!(report.jitter > Duration::ZERO);
+    }
+}
@@ -0,0 +1,181 @@
+//! Inter-arrival jitter
+//!
+//! The gaps between consecutive packets are summarized per stats interval, so that
+//! bursts upstream or stalls of the forwarding thread show up as spread between
+//! the typical and the largest gap.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+/// Sub-buckets per power of two of the histogram, as bits
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// Buckets covering all gaps up to `u64::MAX` nanoseconds
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Gaps between packets during one stats interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct JitterStats {
+    /// Number of gaps, one less than the packets if the interval saw the first packet
+    pub gaps: u64,
+    pub min: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+    /// Gap 99% of the gaps are shorter than, within 12.5%
+    pub p99: Duration,
+}
+
+impl fmt::Display for JitterStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "gaps min {:?} mean {:?} max {:?} p99 {:?}",
+            self.min, self.mean, self.max, self.p99
+        )
+    }
+}
+
+/// Histogram of the gaps between the packets of the current interval
+///
+/// Buckets are spaced logarithmically, so that the percentile is found
+/// in constant memory with a relative error of one sub-bucket.
+#[derive(Debug)]
+pub(crate) struct Arrivals {
+    last: Option<SystemTime>,
+    buckets: Box<[u64; BUCKETS]>,
+    gaps: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Arrivals {
+    pub(crate) fn new() -> Self {
+        Self {
+            last: None,
+            buckets: Box::new([0; BUCKETS]),
+            gaps: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    /// Count the gap since the previous packet
+    ///
+    /// Arrivals are wall-clock times, so that the timestamps of the kernel can be mixed
+    /// with the time the packets were read at. Gaps are zero if the clock steps back.
+    pub(crate) fn add(&mut self, arrival: SystemTime) {
+        let Some(last) = self.last.replace(arrival) else {
+            return;
+        };
+        let gap = arrival
+            .duration_since(last)
+            .unwrap_or_default()
+            .as_nanos()
+            .min(u64::MAX as u128) as u64;
+
+        self.buckets[bucket(gap)] += 1;
+        self.gaps += 1;
+        self.sum += gap as u128;
+        self.min = self.min.min(gap);
+        self.max = self.max.max(gap);
+    }
+
+    /// Summarize the gaps counted so far and start a new interval
+    ///
+    /// Returns `None` if there were less than two packets.
+    pub(crate) fn take(&mut self) -> Option<JitterStats> {
+        if self.gaps == 0 {
+            return None;
+        }
+
+        // Smallest gap at least 99% of the gaps are not longer than
+        let rank = (self.gaps * 99).div_ceil(100);
+        let mut seen = 0;
+        let p99 = self
+            .buckets
+            .iter()
+            .position(|count| {
+                seen += count;
+                seen >= rank
+            })
+            .map_or(self.max, |idx| upper_bound(idx).min(self.max));
+        let stats = JitterStats {
+            gaps: self.gaps,
+            min: Duration::from_nanos(self.min),
+            mean: Duration::from_nanos((self.sum / self.gaps as u128) as u64),
+            max: Duration::from_nanos(self.max),
+            p99: Duration::from_nanos(p99),
+        };
+
+        *self = Self {
+            last: self.last,
+            ..Self::new()
+        };
+        Some(stats)
+    }
+}
+
+/// Bucket of a gap in nanoseconds
+fn bucket(gap: u64) -> usize {
+    if gap < SUB_BUCKETS as u64 {
+        return gap as usize;
+    }
+    let exponent = 63 - gap.leading_zeros();
+    let sub_bucket = (gap >> (exponent - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exponent - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub_bucket
+}
+
+/// Largest gap in nanoseconds falling into bucket `idx`
+fn upper_bound(idx: usize) -> u64 {
+    if idx < SUB_BUCKETS {
+        return idx as u64;
+    }
+    let shift = (idx / SUB_BUCKETS) as u32 - 1;
+    let lower = ((SUB_BUCKETS + idx % SUB_BUCKETS) as u64) << shift;
+    lower + ((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_cover_gaps() {
+        for gap in [0, 7, 8, 15, 16, 1_000, 1_000_000, 123_456_789, u64::MAX] {
+            let idx = bucket(gap);
+            assert!(upper_bound(idx) >= gap, "{gap}");
+            assert!(idx == 0 || upper_bound(idx - 1) < gap, "{gap}");
+        }
+        assert_eq!(BUCKETS - 1, bucket(u64::MAX));
+    }
+
+    #[test]
+    fn gaps_summarized() {
+        let mut arrivals = Arrivals::new();
+        let mut arrival = SystemTime::now();
+        arrivals.add(arrival);
+        assert_eq!(None, arrivals.take());
+
+        // 99 regular gaps of 1ms and a stall of 50ms
+        for idx in 0..100 {
+            arrival += Duration::from_millis(if idx == 50 { 50 } else { 1 });
+            arrivals.add(arrival);
+        }
+        let stats = arrivals.take().unwrap();
+        assert_eq!(100, stats.gaps);
+        assert_eq!(Duration::from_millis(1), stats.min);
+        assert_eq!(Duration::from_micros(1490), stats.mean);
+        assert_eq!(Duration::from_millis(50), stats.max);
+        assert!(stats.p99 >= Duration::from_millis(1));
+        assert!(stats.p99 < Duration::from_micros(1125));
+
+        // The gap to the last packet of the previous interval counts
+        arrivals.add(arrival + Duration::from_millis(2));
+        assert_eq!(Duration::from_millis(2), arrivals.take().unwrap().max);
+    }
+}
@@ -0,0 +1,292 @@
+//! Minimal JSON parser
+//!
+//! Parses the responses of service registries like Consul, keeping the crate
+//! free of dependencies. Numbers are kept as `f64`, which covers ports and indices.
+//! Documents written by the crate are formatted by hand, quoting strings with
+//! [json_string] and rendering string maps with [json_object].
+
+use std::fmt::Write;
+
+/// Maximum nesting of arrays and objects
+const MAX_DEPTH: usize = 64;
+
+/// Parsed JSON value
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Parse a complete JSON document
+    pub(crate) fn parse(s: &str) -> Option<Json> {
+        let mut parser = Parser {
+            input: s.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+
+        (parser.pos == parser.input.len()).then_some(value)
+    }
+
+    /// Value of `key` if this is an object
+    pub(crate) fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+/// Quote and escape `s` as JSON string
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Render `entries` like labels as JSON object of strings
+pub(crate) fn json_object(entries: &[(String, String)]) -> String {
+    let members: Vec<String> = entries
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+        .collect();
+    format!("{{{}}}", members.join(","))
+}
+
+/// Recursive descent parser over the input bytes
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn value(&mut self, depth: usize) -> Option<Json> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        self.skip_whitespace();
+
+        match *self.input.get(self.pos)? {
+            b'{' => self.object(depth),
+            b'[' => self.array(depth),
+            b'"' => self.string().map(Json::String),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'n' => self.literal("null", Json::Null),
+            _ => self.number(),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Option<Json> {
+        self.pos += 1;
+        let mut entries = Vec::new();
+        self.skip_whitespace();
+        if self.eat(b'}') {
+            return Some(Json::Object(entries));
+        }
+
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(b':') {
+                return None;
+            }
+            entries.push((key, self.value(depth + 1)?));
+            self.skip_whitespace();
+            match self.eat(b',') {
+                true => continue,
+                false if self.eat(b'}') => return Some(Json::Object(entries)),
+                false => return None,
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Option<Json> {
+        self.pos += 1;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.eat(b']') {
+            return Some(Json::Array(values));
+        }
+
+        loop {
+            values.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            match self.eat(b',') {
+                true => continue,
+                false if self.eat(b']') => return Some(Json::Array(values)),
+                false => return None,
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if !self.eat(b'"') {
+            return None;
+        }
+
+        let mut s = Vec::new();
+        loop {
+            let byte = *self.input.get(self.pos)?;
+            self.pos += 1;
+            match byte {
+                b'"' => return String::from_utf8(s).ok(),
+                b'\\' => {
+                    let escaped = *self.input.get(self.pos)?;
+                    self.pos += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return None,
+                    };
+                    s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                _ => s.push(byte),
+            }
+        }
+    }
+
+    /// Decode `XXXX` after `\u`, combining surrogate pairs
+    fn unicode_escape(&mut self) -> Option<char> {
+        let high = self.hex4()?;
+        if !(0xd800..0xdc00).contains(&high) {
+            return char::from_u32(high);
+        }
+
+        if self.input.get(self.pos..self.pos + 2)? != b"\\u" {
+            return None;
+        }
+        self.pos += 2;
+        let low = self.hex4()?;
+        if !(0xdc00..0xe000).contains(&low) {
+            return None;
+        }
+        char::from_u32(0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00))
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = std::str::from_utf8(self.input.get(self.pos..self.pos + 4)?).ok()?;
+        self.pos += 4;
+        u32::from_str_radix(digits, 16).ok()
+    }
+
+    fn number(&mut self) -> Option<Json> {
+        let start = self.pos;
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|byte| matches!(byte, b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'))
+        {
+            self.pos += 1;
+        }
+
+        let number = std::str::from_utf8(&self.input[start..self.pos]).ok()?;
+        number.parse().ok().map(Json::Number)
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> Option<Json> {
+        let end = self.pos + literal.len();
+        if self.input.get(self.pos..end)? != literal.as_bytes() {
+            return None;
+        }
+
+        self.pos = end;
+        Some(value)
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.input.get(self.pos) == Some(&byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .input
+            .get(self.pos)
+            .is_some_and(|byte| byte.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn document_parsed() {
+        let json = Json::parse(
+            r#" {"Service": {"Address": "10.1.1.11", "Port": 4000, "Tags": []},
+                "Checks": [{"Status": "passing", "Ok": true, "Output": "ä😀\n"}], "Null": null} "#,
+        )
+        .unwrap();
+
+        let service = json.get("Service").unwrap();
+        assert_eq!(
+            Some("10.1.1.11"),
+            service.get("Address").and_then(Json::as_str)
+        );
+        assert_eq!(Some(4000), service.get("Port").and_then(Json::as_u64));
+        let check = &json.get("Checks").and_then(Json::as_array).unwrap()[0];
+        assert_eq!(Some(&Json::Bool(true)), check.get("Ok"));
+        assert_eq!(Some("ä😀\n"), check.get("Output").and_then(Json::as_str));
+        assert_eq!(Some(&Json::Null), json.get("Null"));
+    }
+
+    #[test]
+    fn malformed_rejected() {
+        for malformed in ["", "{", "[1,]", r#"{"a" 1}"#, "[1] 2", r#""\x""#, "nul"] {
+            assert_eq!(None, Json::parse(malformed), "{malformed}");
+        }
+    }
+}
@@ -0,0 +1,837 @@
+//! KCP transport
+//!
+//! Carries the forwarded packets between two forwarders over KCP, an ARQ protocol on
+//! top of UDP which retransmits lost packets and controls congestion at the cost of
+//! some added latency. The sending forwarder has a `kcp://` target, the receiving one
+//! takes the packets on its listener with `--kcp`, acknowledging and reordering them
+//! before forwarding them on.
+//!
+//! Segments follow the reference implementation (ikcp) in its fast mode: a 10 ms
+//! update interval, fast retransmission after two skipping acknowledgements and the
+//! congestion window kept. Each forwarded packet is one KCP message, split into
+//! fragments if larger than a segment. The sender picks a random conversation ID on
+//! every start, so the receiver tells restarted senders apart.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    hash::{BuildHasher, Hasher, RandomState},
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    sink::Sink,
+    source::{Source, copy_truncated},
+    state::SharedState,
+    tools::is_timeout,
+};
+
+/// Segments in flight and kept for reordering by default
+pub const DEFAULT_WINDOW: u16 = 1024;
+
+/// Interval of flushing acknowledgements and retransmissions
+const INTERVAL: Duration = Duration::from_millis(10);
+/// Largest datagram sent, including the segment headers
+const MTU: usize = 1400;
+/// Length of the segment header
+const OVERHEAD: usize = 24;
+/// Largest fragment of a message carried in one segment
+const MSS: usize = MTU - OVERHEAD;
+/// Largest datagram expected from the peer
+const MAX_DATAGRAM: usize = 65536;
+/// Queued and unacknowledged segments at most, in windows, before packets are refused
+const MAX_WAITING_WINDOWS: usize = 4;
+/// Time without segments after which the receiver forgets a sender
+const SESSION_EXPIRY: Duration = Duration::from_secs(60);
+/// Time to wait after failing to receive acknowledgements
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Segment commands
+const CMD_PUSH: u8 = 81;
+const CMD_ACK: u8 = 82;
+const CMD_WASK: u8 = 83;
+const CMD_WINS: u8 = 84;
+/// Pending window probes, asking for the window of the peer and telling ours
+const ASK_SEND: u8 = 1;
+const ASK_TELL: u8 = 2;
+
+/// Retransmission timeouts in milliseconds, initial, least in fast mode and most
+const RTO_DEFAULT: u32 = 200;
+const RTO_MIN: u32 = 30;
+const RTO_MAX: u32 = 60_000;
+/// Window of the peer assumed until it tells
+const REMOTE_WINDOW: u32 = 128;
+/// Initial and least slow start threshold
+const THRESH_INIT: u32 = 2;
+const THRESH_MIN: u32 = 2;
+/// Skipping acknowledgements triggering a fast retransmission
+const FAST_RESEND: u32 = 2;
+/// Transmissions after which fast retransmissions stop
+const FAST_LIMIT: u32 = 5;
+/// Transmissions of a segment after which the peer is considered gone
+const DEAD_LINK: u32 = 20;
+/// Initial and longest interval in milliseconds of probing a closed window of the peer
+const PROBE_INIT: u32 = 7000;
+const PROBE_LIMIT: u32 = 120_000;
+
+/// Forwarder receiving over KCP, given as `kcp://host:port[?window=1024]`
+///
+/// The window is the number of segments in flight at most.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KcpTarget {
+    /// Host of the receiver
+    pub host: String,
+    /// Port the receiver listens on
+    pub port: u16,
+    /// Segments in flight at most
+    pub window: u16,
+}
+
+impl KcpTarget {
+    /// Resolve the address of the receiver
+    fn addr(&self) -> Result<SocketAddr, io::Error> {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        (host, self.port).to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address for KCP host {}", self.host),
+            )
+        })
+    }
+}
+
+impl FromStr for KcpTarget {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s.strip_prefix("kcp://").ok_or(())?;
+        let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (host, port) = authority.rsplit_once(':').ok_or(())?;
+        let mut target = Self {
+            host: host.to_owned(),
+            port: port.parse().map_err(|_| ())?,
+            window: DEFAULT_WINDOW,
+        };
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            match param.split_once('=').ok_or(())? {
+                ("window", window) => target.window = window.parse().map_err(|_| ())?,
+                _ => return Err(()),
+            }
+        }
+        if target.host.is_empty() || target.window == 0 {
+            return Err(());
+        }
+
+        Ok(target)
+    }
+}
+
+/// Sends packets to a forwarder receiving over KCP
+pub struct KcpSink {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    window: u16,
+    kcp: Arc<Mutex<Kcp>>,
+}
+
+impl KcpSink {
+    /// Start sending to the receiver of `target`
+    ///
+    /// Spawns a thread taking acknowledgements and retransmitting, which records
+    /// failures in `state`.
+    pub fn connect(target: &KcpTarget, state: Arc<SharedState>) -> Result<Self, io::Error> {
+        let addr = target.addr()?;
+        let unspecified = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = UdpSocket::bind(unspecified)?;
+        socket.set_read_timeout(Some(INTERVAL))?;
+        let kcp = Arc::new(Mutex::new(Kcp::new(random_u32(), target.window)));
+
+        {
+            let socket = socket.try_clone()?;
+            let kcp = Arc::clone(&kcp);
+            let window = target.window;
+            thread::Builder::new()
+                .name("kcp".to_owned())
+                .spawn(move || serve(&socket, addr, window, &kcp, &state))?;
+        }
+
+        Ok(Self {
+            socket,
+            addr,
+            window: target.window,
+            kcp,
+        })
+    }
+
+    /// Address segments are sent from
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+}
+
+impl Sink for KcpSink {
+    fn send(&mut self, _source: SocketAddr, payload: &[u8]) -> Result<(), io::Error> {
+        let datagrams = {
+            let mut kcp = lock(&self.kcp);
+            let waiting = kcp.waiting();
+            if waiting >= MAX_WAITING_WINDOWS * usize::from(self.window) {
+                return Err(io::Error::other(format!(
+                    "{waiting} segments wait for acknowledgement by KCP receiver {}",
+                    self.addr
+                )));
+            }
+            kcp.send(payload);
+            kcp.flush()
+        };
+        for datagram in datagrams {
+            self.socket.send_to(&datagram, self.addr)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for KcpSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KCP receiver {}", self.addr)
+    }
+}
+
+/// Take acknowledgements and retransmit lost segments
+///
+/// Starts a new conversation if the receiver stops acknowledging. Runs until the sink
+/// is dropped.
+fn serve(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    window: u16,
+    kcp: &Arc<Mutex<Kcp>>,
+    state: &SharedState,
+) {
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    let mut next_flush = Instant::now();
+    while Arc::strong_count(kcp) > 1 {
+        match socket.recv_from(&mut buffer) {
+            Ok((len, from)) if from == addr => {
+                lock(kcp).input(&buffer[..len]);
+            }
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::ConnectionRefused => {}
+            Err(e) => {
+                state.record_error(format!("failed to receive from KCP receiver {addr}: {e}"));
+                thread::sleep(RETRY_INTERVAL);
+            }
+        }
+
+        let now = Instant::now();
+        if now < next_flush {
+            continue;
+        }
+        next_flush = now + INTERVAL;
+        let datagrams = {
+            let mut kcp = lock(kcp);
+            if kcp.dead {
+                state.record_error(format!(
+                    "KCP receiver {addr} stopped acknowledging, dropped {} segments",
+                    kcp.waiting()
+                ));
+                *kcp = Kcp::new(random_u32(), window);
+            }
+            kcp.flush()
+        };
+        for datagram in datagrams {
+            // Unreachable receivers show as dead link
+            if let Err(e) = socket.send_to(&datagram, addr)
+                && e.kind() != io::ErrorKind::ConnectionRefused
+            {
+                state.record_error(format!("failed to send to KCP receiver {addr}: {e}"));
+            }
+        }
+    }
+}
+
+/// Listener socket receiving from forwarders sending over KCP
+///
+/// Each sender and conversation has its own state. Messages are handed on in order
+/// once complete, acknowledgements are sent right as segments arrive.
+pub struct KcpSource {
+    socket: UdpSocket,
+    sessions: HashMap<(SocketAddr, u32), (Kcp, Instant)>,
+    ready: VecDeque<(Vec<u8>, SocketAddr)>,
+    datagram: Vec<u8>,
+}
+
+impl KcpSource {
+    /// Receive KCP segments on `socket`
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            sessions: HashMap::new(),
+            ready: VecDeque::new(),
+            datagram: vec![0; MAX_DATAGRAM],
+        }
+    }
+}
+
+impl Source for KcpSource {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        loop {
+            if let Some((message, from)) = self.ready.pop_front() {
+                return Ok(Some((copy_truncated(&message, buffer), from)));
+            }
+
+            let (len, from) = self.socket.recv_from(&mut self.datagram)?;
+            let Some(conv) = self.datagram[..len].first_chunk::<4>() else {
+                continue;
+            };
+            let conv = u32::from_le_bytes(*conv);
+            let now = Instant::now();
+            self.sessions
+                .retain(|_, (_, last_input)| now.duration_since(*last_input) < SESSION_EXPIRY);
+
+            let (kcp, last_input) = self
+                .sessions
+                .entry((from, conv))
+                .or_insert_with(|| (Kcp::new(conv, DEFAULT_WINDOW), now));
+            if !kcp.input(&self.datagram[..len]) {
+                continue;
+            }
+            *last_input = now;
+            while let Some(message) = kcp.recv() {
+                self.ready.push_back((message, from));
+            }
+            for datagram in kcp.flush() {
+                self.socket.send_to(&datagram, from)?;
+            }
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+}
+
+/// Fragment of a message, queued, in flight or received
+#[derive(Debug, Default)]
+struct Segment {
+    /// Fragments following in the message
+    frg: u8,
+    ts: u32,
+    sn: u32,
+    /// Time to retransmit at, in milliseconds
+    resend_at: u32,
+    rto: u32,
+    /// Acknowledgements of later segments received since sent
+    fastack: u32,
+    /// Transmissions so far
+    xmit: u32,
+    data: Vec<u8>,
+}
+
+/// State of one conversation, sending and receiving
+///
+/// Times are milliseconds since the state was created, wrapping like sequence numbers.
+struct Kcp {
+    conv: u32,
+    start: Instant,
+    /// First unacknowledged and next sequence number sent
+    snd_una: u32,
+    snd_nxt: u32,
+    /// Next sequence number to receive in order
+    rcv_nxt: u32,
+    ssthresh: u32,
+    /// Smoothed round-trip time and its variation, in milliseconds
+    rx_srtt: u32,
+    rx_rttval: u32,
+    rx_rto: u32,
+    snd_wnd: u32,
+    rcv_wnd: u32,
+    rmt_wnd: u32,
+    cwnd: u32,
+    /// Bytes the congestion window grows by, in congestion avoidance
+    incr: u32,
+    probe: u8,
+    ts_probe: u32,
+    probe_wait: u32,
+    /// Whether a segment was transmitted too often without acknowledgement
+    dead: bool,
+    snd_queue: VecDeque<Segment>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: VecDeque<Segment>,
+    rcv_queue: VecDeque<Segment>,
+    /// Sequence numbers and times of segments to acknowledge
+    acks: Vec<(u32, u32)>,
+}
+
+impl Kcp {
+    fn new(conv: u32, window: u16) -> Self {
+        Self {
+            conv,
+            start: Instant::now(),
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            ssthresh: THRESH_INIT,
+            rx_srtt: 0,
+            rx_rttval: 0,
+            rx_rto: RTO_DEFAULT,
+            snd_wnd: window.into(),
+            rcv_wnd: window.into(),
+            rmt_wnd: REMOTE_WINDOW,
+            cwnd: 0,
+            incr: 0,
+            probe: 0,
+            ts_probe: 0,
+            probe_wait: 0,
+            dead: false,
+            snd_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+            rcv_queue: VecDeque::new(),
+            acks: Vec::new(),
+        }
+    }
+
+    fn now(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+
+    /// Segments queued or waiting for acknowledgement
+    fn waiting(&self) -> usize {
+        self.snd_queue.len() + self.snd_buf.len()
+    }
+
+    /// Queue `message`, split into fragments of at most one segment
+    fn send(&mut self, message: &[u8]) {
+        let fragments: Vec<&[u8]> = match message.is_empty() {
+            true => vec![&[]],
+            false => message.chunks(MSS).collect(),
+        };
+        let count = fragments.len();
+        for (idx, fragment) in fragments.into_iter().enumerate() {
+            self.snd_queue.push_back(Segment {
+                frg: (count - idx - 1) as u8,
+                data: fragment.to_vec(),
+                ..Segment::default()
+            });
+        }
+    }
+
+    /// Next complete message received in order
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        let count = usize::from(self.rcv_queue.front()?.frg) + 1;
+        if self.rcv_queue.len() < count {
+            return None;
+        }
+        let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
+        let message = self
+            .rcv_queue
+            .drain(..count)
+            .flat_map(|segment| segment.data)
+            .collect();
+        self.move_received();
+        // Tell the peer the window opened again
+        if recover && self.rcv_queue.len() < self.rcv_wnd as usize {
+            self.probe |= ASK_TELL;
+        }
+        Some(message)
+    }
+
+    /// Take the segments of a datagram of the peer, `false` if not of this conversation
+    fn input(&mut self, mut datagram: &[u8]) -> bool {
+        let now = self.now();
+        let prev_una = self.snd_una;
+        let mut max_ack = None;
+        if datagram.len() < OVERHEAD {
+            return false;
+        }
+
+        while let Some(header) = datagram.first_chunk::<OVERHEAD>() {
+            let word =
+                |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap());
+            let (conv, cmd, frg) = (word(0), header[4], header[5]);
+            let wnd = u16::from_le_bytes([header[6], header[7]]);
+            let (ts, sn, una, len) = (word(8), word(12), word(16), word(20) as usize);
+            let Some(data) = datagram.get(OVERHEAD..OVERHEAD + len) else {
+                return false;
+            };
+            if conv != self.conv || !(CMD_PUSH..=CMD_WINS).contains(&cmd) {
+                return false;
+            }
+
+            self.rmt_wnd = wnd.into();
+            self.parse_una(una);
+            match cmd {
+                CMD_ACK => {
+                    if diff(now, ts) >= 0 {
+                        self.update_rtt(diff(now, ts) as u32);
+                    }
+                    self.parse_ack(sn);
+                    if max_ack.is_none_or(|max_ack| diff(sn, max_ack) > 0) {
+                        max_ack = Some(sn);
+                    }
+                }
+                CMD_PUSH if diff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0 => {
+                    self.acks.push((sn, ts));
+                    if diff(sn, self.rcv_nxt) >= 0 {
+                        self.parse_data(Segment {
+                            frg,
+                            ts,
+                            sn,
+                            data: data.to_vec(),
+                            ..Segment::default()
+                        });
+                    }
+                }
+                CMD_WASK => self.probe |= ASK_TELL,
+                _ => {}
+            }
+            datagram = &datagram[OVERHEAD + len..];
+        }
+
+        if let Some(max_ack) = max_ack {
+            self.parse_fastack(max_ack);
+        }
+        // Grow the congestion window, exponentially in slow start
+        if diff(self.snd_una, prev_una) > 0 && self.cwnd < self.rmt_wnd {
+            let mss = MSS as u32;
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+                self.incr += mss;
+            } else {
+                self.incr = self.incr.max(mss);
+                self.incr += mss * mss / self.incr + mss / 16;
+                if (self.cwnd + 1) * mss <= self.incr {
+                    self.cwnd = self.incr.div_ceil(mss);
+                }
+            }
+            if self.cwnd > self.rmt_wnd {
+                self.cwnd = self.rmt_wnd;
+                self.incr = self.rmt_wnd * mss;
+            }
+        }
+        true
+    }
+
+    fn update_rtt(&mut self, rtt: u32) {
+        if self.rx_srtt == 0 {
+            self.rx_srtt = rtt;
+            self.rx_rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.rx_srtt);
+            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
+            self.rx_srtt = ((7 * self.rx_srtt + rtt) / 8).max(1);
+        }
+        let rto = self.rx_srtt + (INTERVAL.as_millis() as u32).max(4 * self.rx_rttval);
+        self.rx_rto = rto.clamp(RTO_MIN, RTO_MAX);
+    }
+
+    /// Drop the segments the peer received all up to `una`
+    fn parse_una(&mut self, una: u32) {
+        while self
+            .snd_buf
+            .front()
+            .is_some_and(|segment| diff(una, segment.sn) > 0)
+        {
+            self.snd_buf.pop_front();
+        }
+        self.shrink_buf();
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if diff(sn, self.snd_una) < 0 || diff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        if let Some(idx) = self.snd_buf.iter().position(|segment| segment.sn == sn) {
+            self.snd_buf.remove(idx);
+        }
+        self.shrink_buf();
+    }
+
+    /// Count the acknowledgement of `sn` as skipping the earlier segments
+    fn parse_fastack(&mut self, sn: u32) {
+        if diff(sn, self.snd_una) < 0 || diff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for segment in &mut self.snd_buf {
+            if diff(sn, segment.sn) < 0 {
+                break;
+            }
+            if sn != segment.sn {
+                segment.fastack += 1;
+            }
+        }
+    }
+
+    fn shrink_buf(&mut self) {
+        self.snd_una = self
+            .snd_buf
+            .front()
+            .map_or(self.snd_nxt, |segment| segment.sn);
+    }
+
+    /// Keep a received segment in order, unless it is a duplicate
+    fn parse_data(&mut self, segment: Segment) {
+        let sn = segment.sn;
+        if diff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0 || diff(sn, self.rcv_nxt) < 0 {
+            return;
+        }
+        match self
+            .rcv_buf
+            .iter()
+            .rposition(|received| diff(received.sn, sn) <= 0)
+        {
+            Some(idx) if self.rcv_buf[idx].sn == sn => return,
+            Some(idx) => self.rcv_buf.insert(idx + 1, segment),
+            None => self.rcv_buf.push_front(segment),
+        }
+        self.move_received();
+    }
+
+    /// Move segments received in order to the queue of complete messages
+    fn move_received(&mut self) {
+        while self.rcv_queue.len() < self.rcv_wnd as usize
+            && let Some(segment) = self.rcv_buf.pop_front()
+        {
+            if segment.sn != self.rcv_nxt {
+                self.rcv_buf.push_front(segment);
+                break;
+            }
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.rcv_queue.push_back(segment);
+        }
+    }
+
+    fn wnd_unused(&self) -> u16 {
+        let unused = (self.rcv_wnd as usize).saturating_sub(self.rcv_queue.len());
+        unused.min(usize::from(u16::MAX)) as u16
+    }
+
+    /// Datagrams with the pending acknowledgements, window probes and the segments due
+    /// for transmission
+    fn flush(&mut self) -> Vec<Vec<u8>> {
+        let now = self.now();
+        let (conv, wnd, una) = (self.conv, self.wnd_unused(), self.rcv_nxt);
+        let mut datagrams = Vec::new();
+        let mut buffer = Vec::with_capacity(MTU);
+        let mut header = |buffer: &mut Vec<u8>, cmd: u8, frg: u8, ts: u32, sn: u32, len: usize| {
+            if buffer.len() + OVERHEAD + len > MTU {
+                datagrams.push(mem::replace(buffer, Vec::with_capacity(MTU)));
+            }
+            buffer.extend_from_slice(&conv.to_le_bytes());
+            buffer.extend_from_slice(&[cmd, frg]);
+            buffer.extend_from_slice(&wnd.to_le_bytes());
+            for word in [ts, sn, una, len as u32] {
+                buffer.extend_from_slice(&word.to_le_bytes());
+            }
+        };
+
+        for (sn, ts) in mem::take(&mut self.acks) {
+            header(&mut buffer, CMD_ACK, 0, ts, sn, 0);
+        }
+
+        // Probe the window of the peer while it is closed, backing off
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = PROBE_INIT;
+                self.ts_probe = now.wrapping_add(self.probe_wait);
+            } else if diff(now, self.ts_probe) >= 0 {
+                self.probe_wait = self.probe_wait.max(PROBE_INIT);
+                self.probe_wait = (self.probe_wait + self.probe_wait / 2).min(PROBE_LIMIT);
+                self.ts_probe = now.wrapping_add(self.probe_wait);
+                self.probe |= ASK_SEND;
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+        if self.probe & ASK_SEND != 0 {
+            header(&mut buffer, CMD_WASK, 0, 0, 0, 0);
+        }
+        if self.probe & ASK_TELL != 0 {
+            header(&mut buffer, CMD_WINS, 0, 0, 0, 0);
+        }
+        self.probe = 0;
+
+        // Move queued segments into the window
+        let cwnd = self.snd_wnd.min(self.rmt_wnd).min(self.cwnd);
+        while diff(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) < 0
+            && let Some(mut segment) = self.snd_queue.pop_front()
+        {
+            segment.sn = self.snd_nxt;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.snd_buf.push_back(segment);
+        }
+
+        let (mut change, mut lost) = (false, false);
+        for segment in &mut self.snd_buf {
+            let due = if segment.xmit == 0 {
+                segment.rto = self.rx_rto;
+                segment.resend_at = now.wrapping_add(segment.rto);
+                true
+            } else if diff(now, segment.resend_at) >= 0 {
+                segment.rto += segment.rto / 2;
+                segment.resend_at = now.wrapping_add(segment.rto);
+                lost = true;
+                true
+            } else if segment.fastack >= FAST_RESEND && segment.xmit <= FAST_LIMIT {
+                segment.fastack = 0;
+                segment.resend_at = now.wrapping_add(segment.rto);
+                change = true;
+                true
+            } else {
+                false
+            };
+            if !due {
+                continue;
+            }
+            segment.xmit += 1;
+            segment.ts = now;
+            let len = segment.data.len();
+            header(&mut buffer, CMD_PUSH, segment.frg, now, segment.sn, len);
+            buffer.extend_from_slice(&segment.data);
+            if segment.xmit >= DEAD_LINK {
+                self.dead = true;
+            }
+        }
+        if !buffer.is_empty() {
+            datagrams.push(buffer);
+        }
+
+        let mss = MSS as u32;
+        if change {
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.ssthresh = (inflight / 2).max(THRESH_MIN);
+            self.cwnd = self.ssthresh + FAST_RESEND;
+            self.incr = self.cwnd * mss;
+        }
+        if lost {
+            self.ssthresh = (cwnd / 2).max(THRESH_MIN);
+            self.cwnd = 1;
+            self.incr = mss;
+        }
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = mss;
+        }
+        datagrams
+    }
+}
+
+/// Signed distance of wrapping sequence numbers or times
+fn diff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+/// Random conversation ID
+fn random_u32() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+fn lock(kcp: &Mutex<Kcp>) -> MutexGuard<'_, Kcp> {
+    kcp.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kcp_target_ok() {
+        let target: KcpTarget = "kcp://relay.example.com:4000?window=256".parse().unwrap();
+        assert_eq!("relay.example.com", target.host);
+        assert_eq!(4000, target.port);
+        assert_eq!(256, target.window);
+
+        let target: KcpTarget = "kcp://[::1]:4000".parse().unwrap();
+        assert_eq!(DEFAULT_WINDOW, target.window);
+
+        assert!("kcp://:4000".parse::<KcpTarget>().is_err());
+        assert!("kcp://host:4000?window=0".parse::<KcpTarget>().is_err());
+        assert!("kcp://host:4000?conv=1".parse::<KcpTarget>().is_err());
+    }
+
+    #[test]
+    fn lost_segments_retransmitted_in_order() {
+        let mut sender = Kcp::new(7, 32);
+        let mut receiver = Kcp::new(7, 32);
+        let messages: Vec<Vec<u8>> = (0..10u8)
+            .map(|idx| vec![idx; 100])
+            .chain([vec![10; 3 * MSS]])
+            .collect();
+        for message in &messages {
+            sender.send(message);
+        }
+
+        let mut received = Vec::new();
+        let mut dropped = 0;
+        for _ in 0..500 {
+            for datagram in sender.flush() {
+                // Lose every third datagram on the way
+                dropped += 1;
+                if dropped % 3 != 0 {
+                    assert!(receiver.input(&datagram));
+                }
+            }
+            while let Some(message) = receiver.recv() {
+                received.push(message);
+            }
+            for datagram in receiver.flush() {
+                assert!(sender.input(&datagram));
+            }
+            if received.len() == messages.len() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+
+        assert_eq!(messages, received);
+        assert_eq!(0, sender.waiting());
+        assert!(!sender.dead);
+
+        // Segments of other conversations are refused
+        let mut other = Kcp::new(8, 32);
+        other.send(b"other");
+        // The congestion window opens with the first flush
+        assert!(other.flush().is_empty());
+        assert!(!receiver.input(&other.flush().concat()));
+    }
+
+    #[test]
+    fn packets_forwarded_between_sink_and_source() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut source = KcpSource::new(listener);
+        source
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let target = format!("kcp://{addr}").parse().unwrap();
+        let mut sink = KcpSink::connect(&target, Arc::new(SharedState::new(None, &[]))).unwrap();
+        let sender = sink.local_addr().unwrap();
+        let sender = SocketAddr::from(([127, 0, 0, 1], sender.port()));
+        for payload in [&b"first"[..], b"second", &[0x47; 4000]] {
+            sink.send(sender, payload).unwrap();
+        }
+
+        let mut buffer = [0; 5000];
+        for payload in [&b"first"[..], b"second", &[0x47; 4000]] {
+            let (len, from) = source.recv(&mut buffer).unwrap().unwrap();
+            assert_eq!(payload, &buffer[..len]);
+            assert_eq!(sender, from);
+        }
+    }
+}
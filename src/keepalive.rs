@@ -0,0 +1,114 @@
+//! NAT keepalive and heartbeat packets
+//!
+//! NAT gateways and stateful firewalls forget the mapping of a UDP flow after a while
+//! without traffic. Sending a small datagram to each target while the stream is idle
+//! keeps the pinholes open which replies or tunnels rely on.
+//!
+//! Heartbeats are sent on their interval regardless of traffic, so that consumers can
+//! tell a dead forwarder from a silent source.
+
+use std::{str::FromStr, time::Duration};
+
+use crate::args::parse_duration;
+
+/// Keepalive sent to all targets after the stream was idle for an interval
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Keepalive {
+    /// Idle time after which a keepalive is sent
+    pub interval: Duration,
+    /// Payload of the keepalive, empty by default
+    pub payload: Vec<u8>,
+}
+
+impl FromStr for Keepalive {
+    type Err = ();
+
+    /// Parse `interval[,payload]`, e.g. `25s` or `25s,ping`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (interval, payload) = parse_interval_payload(s)?;
+
+        Ok(Self {
+            interval,
+            payload: payload.unwrap_or_default(),
+        })
+    }
+}
+
+/// Heartbeat sent to all targets on an interval, also during traffic
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Heartbeat {
+    /// Interval between heartbeats
+    pub interval: Duration,
+    /// Payload of the heartbeat, `udpforwarder heartbeat` if not configured otherwise
+    pub payload: Vec<u8>,
+}
+
+/// Payload of heartbeats if not configured otherwise
+const DEFAULT_HEARTBEAT_PAYLOAD: &[u8] = b"udpforwarder heartbeat";
+
+impl FromStr for Heartbeat {
+    type Err = ();
+
+    /// Parse `interval[,payload]`, e.g. `1s` or `1s,alive`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (interval, payload) = parse_interval_payload(s)?;
+
+        Ok(Self {
+            interval,
+            payload: payload.unwrap_or_else(|| DEFAULT_HEARTBEAT_PAYLOAD.to_vec()),
+        })
+    }
+}
+
+/// Parse a non-zero interval with an optional payload after a comma
+fn parse_interval_payload(s: &str) -> Result<(Duration, Option<Vec<u8>>), ()> {
+    let (interval, payload) = match s.split_once(',') {
+        Some((interval, payload)) => (interval, Some(payload.as_bytes().to_vec())),
+        None => (s, None),
+    };
+    let interval = parse_duration(interval)?;
+    if interval.is_zero() {
+        return Err(());
+    }
+
+    Ok((interval, payload))
+}
+
+/// Timeout to wait for packets with, so that packets due after `interval` are sent in time
+pub(crate) fn poll_interval(interval: Duration) -> Duration {
+    (interval / 10).max(Duration::from_millis(10))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keepalive_spec_ok() {
+        assert_eq!(
+            Ok(Keepalive {
+                interval: Duration::from_secs(25),
+                payload: Vec::new(),
+            }),
+            "25s".parse()
+        );
+        assert_eq!(
+            Ok(Keepalive {
+                interval: Duration::from_secs(25),
+                payload: b"ping".to_vec(),
+            }),
+            "25s,ping".parse()
+        );
+        assert!("0s".parse::<Keepalive>().is_err());
+    }
+
+    #[test]
+    fn heartbeat_default_payload() {
+        let heartbeat: Heartbeat = "1s".parse().unwrap();
+
+        assert_eq!(Duration::from_secs(1), heartbeat.interval);
+        assert_eq!(DEFAULT_HEARTBEAT_PAYLOAD, heartbeat.payload);
+    }
+}
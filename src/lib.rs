@@ -1,9 +1,104 @@
 //! UDP forwarding
 
-pub use self::args::{ParseArgsError, parse_args};
-pub use self::forwarding::forward;
-pub use self::listener::ListenerSpec;
+pub use self::args::{
+    Args, ArgsBuilder, Command, LISTENER_ENV, Options, ParseArgsError, Rule, TARGETS_ENV,
+    parse_args, parse_command,
+};
+pub use self::forwarding::{
+    ForwardError, Forwarder, check, forward, forward_rules, forward_with_handlers,
+    forward_with_shutdown,
+};
+pub use self::gelf::Gelf;
+pub use self::handler::{Action, Packet, PacketHandler};
+pub use self::hop_limit::HopLimit;
+pub use self::kcp::KcpTarget;
+pub use self::keepalive::{Heartbeat, Keepalive};
+pub use self::listener::{ListenerBuilder, ListenerSpec, ListenerSpecParseError};
+pub use self::manpage::{render_help, render_manpage};
+pub use self::rendezvous::Rendezvous;
+pub use self::rist::RistTarget;
+pub use self::shutdown::ShutdownHandle;
+pub use self::srt::SrtTarget;
+pub use self::state::ForwardStats;
+pub use self::syslog::Syslog;
+#[cfg(feature = "tokio")]
+pub use self::tokio_forward::{AsyncForwarder, async_forward};
+pub use self::turn::TurnTarget;
 
+pub mod admin;
+#[cfg(feature = "encryption")]
+mod aes;
+pub mod alert;
+pub mod amt;
 mod args;
+mod base64;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod capture;
+pub mod config;
+#[cfg(feature = "control")]
+pub mod control;
+pub mod daemon;
+pub mod delay;
+pub mod diagnostics;
+mod digest;
+pub mod discovery;
+pub mod dns;
+#[cfg(feature = "dpdk")]
+pub mod dpdk;
+pub mod event;
 mod forwarding;
+mod gelf;
+pub mod geoip;
+mod handler;
+pub mod history;
+pub mod hooks;
+mod hop_limit;
+mod http;
+pub mod hub;
+pub mod iperf;
+pub mod jitter;
+mod json;
+pub mod kcp;
+mod keepalive;
 mod listener;
+pub mod log;
+mod manpage;
+pub mod mdns;
+#[cfg(feature = "control")]
+pub mod metrics;
+pub mod netns;
+pub mod notify;
+#[cfg(feature = "python")]
+mod python;
+mod queue;
+pub mod reload;
+pub mod rendezvous;
+pub mod rist;
+pub mod sandbox;
+pub mod sap;
+pub mod sdp;
+pub mod sequence;
+pub mod session;
+pub mod shutdown;
+pub mod signal;
+pub mod silence;
+pub mod sink;
+pub mod socket;
+mod sockopt;
+pub mod source;
+pub mod srt;
+pub mod state;
+#[cfg(feature = "async")]
+pub mod stream;
+pub mod stun;
+pub mod summary;
+pub mod supervise;
+mod syslog;
+pub mod timestamp;
+#[cfg(feature = "tokio")]
+pub mod tokio_forward;
+pub mod tools;
+pub mod tui;
+pub mod turn;
+pub mod xdp;
@@ -5,58 +5,665 @@
 //! and multicast groups,
 //! all available as IPv4 and IPv6.
 //!
+//! A multicast group can be joined on several interfaces with one socket, merging the
+//! traffic of redundant feed networks.
+//!
 //! Note that firewall rules are a common source of issues with multicast setups.
+//!
+//! On Unix, the listener socket can also be received from systemd socket activation
+//! instead of being bound in-process.
+//!
+//! A [ListenerBuilder] binds the listener socket, setting options like buffer sizes or
+//! the device to bind to before the socket is bound.
 
 use std::{
-    io,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    error, fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
 };
 
+#[cfg(target_os = "linux")]
+use crate::sockopt;
+
 /// Specification of the UDP listener
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum ListenerSpec {
     /// Incoming unicast stream, IPv4 or IPv6
     Unicast(SocketAddr),
     /// IPv4 multicast group to join with local address of the interface to use
     ///
     /// If the user does not specify the local address, it is [Ipv4Addr::UNSPECIFIED].
+    /// With `interfaces` given by name or local address, the group is joined on each of
+    /// them instead. With `sources`, only packets from them are received (source-specific
+    /// multicast).
     MulticastV4 {
         multicast_group: SocketAddrV4,
         local_addr: Ipv4Addr,
+        #[cfg_attr(feature = "serde", serde(default))]
+        interfaces: Vec<String>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        sources: Vec<Ipv4Addr>,
     },
     /// IPv6 multicast group to join with ID of the interface to use
     ///
     /// If the user does not specify the interface ID, it is `0` for any interface.
+    /// With `interfaces` given by name or ID, the group is joined on each of them instead.
+    /// With `sources`, only packets from them are received (source-specific multicast).
     MulticastV6 {
         multicast_group: SocketAddrV6,
         interface_id: u32,
+        #[cfg_attr(feature = "serde", serde(default))]
+        interfaces: Vec<String>,
+        #[cfg_attr(feature = "serde", serde(default))]
+        sources: Vec<Ipv6Addr>,
     },
+    /// Pre-bound socket passed by systemd socket activation, by index among the passed sockets
+    ///
+    /// Only available on Unix.
+    Systemd(usize),
 }
 
-impl TryFrom<ListenerSpec> for UdpSocket {
-    type Error = io::Error;
+impl ListenerSpec {
+    /// Port to listen on, unknown for sockets passed by systemd
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            ListenerSpec::Unicast(addr) => Some(addr.port()),
+            ListenerSpec::MulticastV4 {
+                multicast_group, ..
+            } => Some(multicast_group.port()),
+            ListenerSpec::MulticastV6 {
+                multicast_group, ..
+            } => Some(multicast_group.port()),
+            ListenerSpec::Systemd(_) => None,
+        }
+    }
+
+    /// Address senders send to, unknown for sockets passed by systemd
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            ListenerSpec::Unicast(addr) => Some(*addr),
+            ListenerSpec::MulticastV4 {
+                multicast_group, ..
+            } => Some(SocketAddr::V4(*multicast_group)),
+            ListenerSpec::MulticastV6 {
+                multicast_group, ..
+            } => Some(SocketAddr::V6(*multicast_group)),
+            ListenerSpec::Systemd(_) => None,
+        }
+    }
+}
 
-    fn try_from(listener_spec: ListenerSpec) -> Result<Self, Self::Error> {
-        match listener_spec {
-            ListenerSpec::Unicast(socket_addr) => UdpSocket::bind(socket_addr),
+/// Formats the specification in the syntax it is parsed from
+///
+/// Defaults like the unspecified local address are left out. A list of a single
+/// interface which reads like a local address or interface ID ends with a comma, so that
+/// it is not parsed as such.
+impl fmt::Display for ListenerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerSpec::Unicast(addr) => write!(f, "{addr}"),
             ListenerSpec::MulticastV4 {
                 multicast_group,
                 local_addr,
+                interfaces,
+                sources,
             } => {
-                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, multicast_group.port()))?;
-                socket.join_multicast_v4(multicast_group.ip(), &local_addr)?;
-
-                Ok(socket)
+                write_sources(f, sources)?;
+                match (interfaces.is_empty(), local_addr.is_unspecified()) {
+                    (false, _) => {
+                        write!(f, "{multicast_group}/")?;
+                        write_interfaces(f, interfaces, |interface| {
+                            interface.parse::<Ipv4Addr>().is_ok()
+                        })
+                    }
+                    (true, true) => write!(f, "{multicast_group}"),
+                    (true, false) => write!(f, "{multicast_group}/{local_addr}"),
+                }
             }
             ListenerSpec::MulticastV6 {
                 multicast_group,
                 interface_id,
+                interfaces,
+                sources,
             } => {
-                let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, multicast_group.port()))?;
-                socket.join_multicast_v6(multicast_group.ip(), interface_id)?;
+                write_sources(f, sources)?;
+                match (interfaces.is_empty(), interface_id) {
+                    (false, _) => {
+                        write!(f, "{multicast_group}/")?;
+                        write_interfaces(f, interfaces, |interface| {
+                            interface.parse::<u32>().is_ok()
+                        })
+                    }
+                    (true, 0) => write!(f, "{multicast_group}"),
+                    (true, _) => write!(f, "{multicast_group}/{interface_id}"),
+                }
+            }
+            ListenerSpec::Systemd(0) => write!(f, "systemd"),
+            ListenerSpec::Systemd(index) => write!(f, "systemd:{index}"),
+        }
+    }
+}
+
+/// Write the interfaces to join a group on, followed by a comma if there is a single one
+/// which is `detail` of the group otherwise, like its local address
+fn write_interfaces(
+    f: &mut fmt::Formatter<'_>,
+    interfaces: &[String],
+    detail: fn(&str) -> bool,
+) -> fmt::Result {
+    match interfaces {
+        [interface] if detail(interface) => write!(f, "{interface},"),
+        _ => write!(f, "{}", interfaces.join(",")),
+    }
+}
+
+/// Write the sources of a source-specific group followed by `@`, if any
+fn write_sources(f: &mut fmt::Formatter<'_>, sources: &[impl fmt::Display]) -> fmt::Result {
+    for (idx, source) in sources.iter().enumerate() {
+        match idx {
+            0 => write!(f, "{source}")?,
+            _ => write!(f, ",{source}")?,
+        }
+    }
+    match sources.is_empty() {
+        true => Ok(()),
+        false => write!(f, "@"),
+    }
+}
+
+/// Reason a listener specification could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerSpecParseError {
+    /// Neither a socket address nor a multicast group with details
+    InvalidAddress(String),
+    /// Interface details given for a unicast address
+    UnicastWithDetails(String),
+    /// Local address, interface ID or interface name of a multicast group not valid for its IP family
+    InvalidInterface(String),
+    /// Index of the socket passed by systemd is no number
+    InvalidSystemdIndex(String),
+    /// Sources given for a unicast address or not of the IP family of the group
+    InvalidSources(String),
+    /// SDP file not readable or describing no media stream, with the reason
+    InvalidSdp(String, String),
+}
+
+impl fmt::Display for ListenerSpecParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidAddress(spec) => write!(f, "{spec} is no valid socket address"),
+            Self::UnicastWithDetails(spec) => {
+                write!(f, "{spec} is no multicast group to give an interface for")
+            }
+            Self::InvalidInterface(interface) => write!(
+                f,
+                "{interface} is no interface name, local IPv4 address or IPv6 interface ID matching the group"
+            ),
+            Self::InvalidSystemdIndex(index) => write!(f, "{index} is no socket index"),
+            Self::InvalidSources(sources) => write!(
+                f,
+                "{sources} are no source addresses matching a multicast group"
+            ),
+            Self::InvalidSdp(path, reason) => write!(f, "failed to read SDP file {path}: {reason}"),
+        }
+    }
+}
+
+impl error::Error for ListenerSpecParseError {}
+
+/// First file descriptor passed by systemd, following stdin, stdout and stderr
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: usize = 3;
+
+/// Take the socket with the given index passed by systemd socket activation
+///
+/// Follows the protocol of `sd_listen_fds`: the sockets are only used if
+/// `LISTEN_PID` matches the current process and `LISTEN_FDS` covers the index.
+#[cfg(unix)]
+fn systemd_socket(index: usize) -> Result<UdpSocket, io::Error> {
+    use std::os::fd::{FromRawFd, RawFd};
+
+    let not_activated = |reason: &str| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no socket activation: {reason}"),
+        )
+    };
+
+    let listen_pid: u32 = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse().ok())
+        .ok_or_else(|| not_activated("LISTEN_PID not set"))?;
+    if listen_pid != std::process::id() {
+        return Err(not_activated("LISTEN_PID does not match"));
+    }
+
+    let listen_fds: usize = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse().ok())
+        .ok_or_else(|| not_activated("LISTEN_FDS not set"))?;
+    if index >= listen_fds {
+        return Err(not_activated("fewer sockets passed than requested"));
+    }
+
+    let fd = (SD_LISTEN_FDS_START + index) as RawFd;
+    // SAFETY: systemd passes ownership of the file descriptors starting at
+    // `SD_LISTEN_FDS_START` to this process and they are not used elsewhere.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    // Make sure we actually got a socket,
+    // without closing a file descriptor we don't own otherwise
+    if let Err(e) = socket.local_addr() {
+        std::mem::forget(socket);
+        return Err(e);
+    }
+
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn systemd_socket(_index: usize) -> Result<UdpSocket, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "socket activation is only available on Unix",
+    ))
+}
+
+/// Join the IPv4 multicast group `group` on the interface with local address
+/// `local_addr`, from `sources` only if any
+fn join_multicast_v4(
+    socket: &UdpSocket,
+    group: &Ipv4Addr,
+    local_addr: &Ipv4Addr,
+    sources: &[Ipv4Addr],
+) -> Result<(), io::Error> {
+    if sources.is_empty() {
+        return socket.join_multicast_v4(group, local_addr);
+    }
+    for source in sources {
+        join_source_v4(socket, group, local_addr, source)?;
+    }
+    Ok(())
+}
+
+/// Join the IPv6 multicast group `group` on the interface with ID `interface_id`, from
+/// `sources` only if any
+fn join_multicast_v6(
+    socket: &UdpSocket,
+    group: &Ipv6Addr,
+    interface_id: u32,
+    sources: &[Ipv6Addr],
+) -> Result<(), io::Error> {
+    if sources.is_empty() {
+        return socket.join_multicast_v6(group, interface_id);
+    }
+    for source in sources {
+        join_source_group(
+            socket,
+            IpAddr::V6(*group),
+            IpAddr::V6(*source),
+            interface_id,
+        )?;
+    }
+    Ok(())
+}
+
+/// Join the IPv4 multicast group `group` on the network interface called `interface`,
+/// from `sources` only if any
+#[cfg(target_os = "linux")]
+fn join_multicast_v4_on(
+    socket: &UdpSocket,
+    group: &Ipv4Addr,
+    interface: &str,
+    sources: &[Ipv4Addr],
+) -> Result<(), io::Error> {
+    let ifindex = crate::capture::interface_index(interface)?;
+    if sources.is_empty() {
+        return sockopt::join_multicast_v4(socket, group, ifindex);
+    }
+    for source in sources {
+        sockopt::join_source_group(
+            socket,
+            IpAddr::V4(*group),
+            IpAddr::V4(*source),
+            ifindex as u32,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn join_multicast_v4_on(
+    _socket: &UdpSocket,
+    _group: &Ipv4Addr,
+    _interface: &str,
+    _sources: &[Ipv4Addr],
+) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "joining IPv4 multicast groups by interface name is only available on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn join_source_v4(
+    socket: &UdpSocket,
+    group: &Ipv4Addr,
+    local_addr: &Ipv4Addr,
+    source: &Ipv4Addr,
+) -> Result<(), io::Error> {
+    sockopt::join_source_v4(socket, group, local_addr, source)
+}
+
+#[cfg(target_os = "linux")]
+fn join_source_group(
+    socket: &UdpSocket,
+    group: IpAddr,
+    source: IpAddr,
+    interface_id: u32,
+) -> Result<(), io::Error> {
+    sockopt::join_source_group(socket, group, source, interface_id)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn join_source_v4(
+    _socket: &UdpSocket,
+    _group: &Ipv4Addr,
+    _local_addr: &Ipv4Addr,
+    _source: &Ipv4Addr,
+) -> Result<(), io::Error> {
+    Err(source_specific_unsupported())
+}
 
-                Ok(socket)
+#[cfg(not(target_os = "linux"))]
+fn join_source_group(
+    _socket: &UdpSocket,
+    _group: IpAddr,
+    _source: IpAddr,
+    _interface_id: u32,
+) -> Result<(), io::Error> {
+    Err(source_specific_unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn source_specific_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "source-specific multicast is only available on Linux",
+    )
+}
+
+/// Index of the network interface called `interface`, as IPv6 multicast joins take it
+#[cfg(target_os = "linux")]
+fn interface_index(interface: &str) -> Result<u32, io::Error> {
+    crate::capture::interface_index(interface).map(|ifindex| ifindex as u32)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_index(_interface: &str) -> Result<u32, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "joining IPv6 multicast groups by interface name is only available on Linux",
+    ))
+}
+
+/// Listener socket with options applied before it is bound
+///
+/// Options not available on the platform fail binding. Sockets passed by systemd
+/// are bound already, options are applied to them afterwards.
+#[derive(Debug, Clone)]
+pub struct ListenerBuilder<'a> {
+    listener_spec: &'a ListenerSpec,
+    reuse_addr: bool,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    device: Option<String>,
+    ttl: Option<u32>,
+    nonblocking: bool,
+}
+
+impl<'a> ListenerBuilder<'a> {
+    pub fn new(listener_spec: &'a ListenerSpec) -> Self {
+        Self {
+            listener_spec,
+            reuse_addr: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            device: None,
+            ttl: None,
+            nonblocking: false,
+        }
+    }
+
+    /// Allow other sockets to bind the same address, e.g. to receive a multicast group twice
+    pub fn reuse_addr(mut self, reuse_addr: bool) -> Self {
+        self.reuse_addr = reuse_addr;
+        self
+    }
+
+    /// Size of the kernel receive buffer in bytes, to absorb bursts
+    pub fn recv_buffer_size(mut self, size: usize) -> Self {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Size of the kernel send buffer in bytes, used when relaying in hub mode
+    pub fn send_buffer_size(mut self, size: usize) -> Self {
+        self.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Only receive packets arriving on the network interface `device`, e.g. `eth0`
+    ///
+    /// A VRF device binds the listener into the VRF, receiving from its interfaces.
+    pub fn device(mut self, device: impl Into<String>) -> Self {
+        self.device = Some(device.into());
+        self
+    }
+
+    /// Time to live or hop limit of packets sent from the listener
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Return `WouldBlock` errors instead of waiting for packets
+    pub fn nonblocking(mut self, nonblocking: bool) -> Self {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    /// Create the socket, apply the options, bind it and join the multicast group if any
+    pub fn bind(&self) -> Result<UdpSocket, io::Error> {
+        let socket = match self.listener_spec {
+            ListenerSpec::Unicast(addr) => self.bind_to(addr)?,
+            ListenerSpec::MulticastV4 {
+                multicast_group,
+                local_addr,
+                interfaces,
+                sources,
+            } => {
+                let socket = self.bind_to(&SocketAddr::from((
+                    Ipv4Addr::UNSPECIFIED,
+                    multicast_group.port(),
+                )))?;
+                let group = multicast_group.ip();
+                if interfaces.is_empty() {
+                    join_multicast_v4(&socket, group, local_addr, sources)?;
+                }
+                for interface in interfaces {
+                    match interface.parse() {
+                        Ok(local_addr) => join_multicast_v4(&socket, group, &local_addr, sources)?,
+                        Err(_) => join_multicast_v4_on(&socket, group, interface, sources)?,
+                    }
+                }
+                socket
+            }
+            ListenerSpec::MulticastV6 {
+                multicast_group,
+                interface_id,
+                interfaces,
+                sources,
+            } => {
+                let socket = self.bind_to(&SocketAddr::from((
+                    Ipv6Addr::UNSPECIFIED,
+                    multicast_group.port(),
+                )))?;
+                let group = multicast_group.ip();
+                if interfaces.is_empty() {
+                    join_multicast_v6(&socket, group, *interface_id, sources)?;
+                }
+                for interface in interfaces {
+                    let interface_id = match interface.parse() {
+                        Ok(interface_id) => interface_id,
+                        Err(_) => interface_index(interface)?,
+                    };
+                    join_multicast_v6(&socket, group, interface_id, sources)?;
+                }
+                socket
             }
+            ListenerSpec::Systemd(index) => {
+                let socket = systemd_socket(*index)?;
+                if self.has_socket_options() {
+                    self.apply_options(&socket)?;
+                }
+                socket
+            }
+        };
+
+        if let Some(ttl) = self.ttl {
+            self.set_ttl(&socket, ttl)?;
+        }
+        socket.set_nonblocking(self.nonblocking)?;
+
+        Ok(socket)
+    }
+
+    /// Bind a socket to `addr`, applying the options beforehand
+    fn bind_to(&self, addr: &SocketAddr) -> Result<UdpSocket, io::Error> {
+        match self.has_socket_options() {
+            false => UdpSocket::bind(addr),
+            true => self.bind_with_options(addr),
+        }
+    }
+
+    /// Whether options need to be set with the C library
+    fn has_socket_options(&self) -> bool {
+        self.reuse_addr
+            || self.recv_buffer_size.is_some()
+            || self.send_buffer_size.is_some()
+            || self.device.is_some()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn bind_with_options(&self, addr: &SocketAddr) -> Result<UdpSocket, io::Error> {
+        let socket = sockopt::unbound(addr)?;
+        self.apply_options(&socket)?;
+        sockopt::bind_to(&socket, addr)?;
+
+        Ok(socket)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn bind_with_options(&self, _addr: &SocketAddr) -> Result<UdpSocket, io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "socket options are only available on Linux",
+        ))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply_options(&self, socket: &UdpSocket) -> Result<(), io::Error> {
+        use sockopt::{SO_BINDTODEVICE, SO_RCVBUF, SO_REUSEADDR, SO_SNDBUF, SOL_SOCKET};
+
+        let size = |size: usize| {
+            size.try_into()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "buffer size too large"))
+        };
+
+        if self.reuse_addr {
+            sockopt::set_int(socket, SOL_SOCKET, SO_REUSEADDR, 1)?;
         }
+        if let Some(recv_buffer_size) = self.recv_buffer_size {
+            sockopt::set_int(socket, SOL_SOCKET, SO_RCVBUF, size(recv_buffer_size)?)?;
+        }
+        if let Some(send_buffer_size) = self.send_buffer_size {
+            sockopt::set_int(socket, SOL_SOCKET, SO_SNDBUF, size(send_buffer_size)?)?;
+        }
+        if let Some(device) = &self.device {
+            sockopt::set_bytes(socket, SOL_SOCKET, SO_BINDTODEVICE, device.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply_options(&self, _socket: &UdpSocket) -> Result<(), io::Error> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "socket options are only available on Linux",
+        ))
+    }
+
+    /// Set the TTL for IPv4 and the unicast hop limit for IPv6
+    fn set_ttl(&self, socket: &UdpSocket, ttl: u32) -> Result<(), io::Error> {
+        match socket.local_addr()? {
+            SocketAddr::V4(_) => socket.set_ttl(ttl),
+            #[cfg(target_os = "linux")]
+            SocketAddr::V6(_) => sockopt::set_int(
+                socket,
+                sockopt::IPPROTO_IPV6,
+                sockopt::IPV6_UNICAST_HOPS,
+                ttl.try_into().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "hop limit too large")
+                })?,
+            ),
+            #[cfg(not(target_os = "linux"))]
+            SocketAddr::V6(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "hop limit of IPv6 sockets is only available on Linux",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn options_applied_before_binding() {
+        let listener_spec = ListenerSpec::Unicast("127.0.0.1:0".parse().unwrap());
+        let builder = ListenerBuilder::new(&listener_spec)
+            .reuse_addr(true)
+            .recv_buffer_size(1 << 16)
+            .ttl(7)
+            .nonblocking(true);
+        let socket = builder.bind().unwrap();
+        assert_eq!(7, socket.ttl().unwrap());
+        let mut buffer = [0; 8];
+        let would_block = socket.recv_from(&mut buffer).unwrap_err();
+        assert_eq!(io::ErrorKind::WouldBlock, would_block.kind());
+
+        // Shares the address with the first socket
+        let shared_spec = ListenerSpec::Unicast(socket.local_addr().unwrap());
+        ListenerBuilder::new(&shared_spec)
+            .reuse_addr(true)
+            .bind()
+            .unwrap();
+        assert!(ListenerBuilder::new(&shared_spec).bind().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn group_joined_on_each_interface() {
+        let listener_spec: ListenerSpec = "224.0.0.251:0/lo,127.0.0.1".parse().unwrap();
+        // Joining twice on the loopback interface is refused, by name and by address
+        assert!(ListenerBuilder::new(&listener_spec).bind().is_err());
+
+        let listener_spec: ListenerSpec = "224.0.0.251:0/lo".parse().unwrap();
+        ListenerBuilder::new(&listener_spec).bind().unwrap();
     }
 }
@@ -0,0 +1,224 @@
+//! Log levels and rate limiting
+//!
+//! Some messages can repeat for every packet, e.g. failures to send to a target which
+//! went away. Identical messages are only logged a few times per window, followed by
+//! the number of suppressed repetitions once the window is over, so that a flapping
+//! target can't fill the disk.
+//!
+//! Forwarders serving several streams prefix their lines with labels like
+//! `[tenant=teamA stream=cam01]`, see [label_prefix].
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Identical messages logged per window at most by default
+pub const DEFAULT_BURST: u32 = 5;
+/// Window identical messages are counted in by default
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+/// Distinct messages counted at most, older ones are forgotten beyond
+const MAX_MESSAGES: usize = 1024;
+
+/// Severity of a message, ordered from the most to the least severe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum LogLevel {
+    /// Failures which stop forwarding
+    Error,
+    /// Failures forwarding continues after
+    Warn,
+    /// Progress like sockets being set up
+    #[default]
+    Info,
+}
+
+impl FromStr for LogLevel {
+    type Err = ParseLogLevelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            _ => Err(ParseLogLevelError),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warn => write!(f, "warn"),
+            Self::Info => write!(f, "info"),
+        }
+    }
+}
+
+/// Log level other than `error`, `warn` or `info`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLogLevelError;
+
+impl fmt::Display for ParseLogLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected error, warn or info")
+    }
+}
+
+impl std::error::Error for ParseLogLevelError {}
+
+/// Decision on logging a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// Log the message, mentioning the repetitions suppressed before if any
+    Log { suppressed: u64 },
+    /// Drop the message, it was logged often enough in the current window
+    Suppress,
+}
+
+/// Counts of a message in the current window
+#[derive(Debug)]
+struct Repetitions {
+    window_started: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+/// Limit of the repetitions of identical messages
+#[derive(Debug)]
+pub struct RateLimiter {
+    burst: u32,
+    window: Duration,
+    messages: HashMap<String, Repetitions>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_BURST, DEFAULT_WINDOW)
+    }
+}
+
+impl RateLimiter {
+    /// Log each message `burst` times per `window` at most
+    pub fn new(burst: u32, window: Duration) -> Self {
+        Self {
+            burst,
+            window,
+            messages: HashMap::new(),
+        }
+    }
+
+    /// Decide whether to log `message` at `now`
+    pub fn check(&mut self, message: &str, now: Instant) -> Verdict {
+        if self.messages.len() >= MAX_MESSAGES && !self.messages.contains_key(message) {
+            let window = self.window;
+            self.messages
+                .retain(|_, repetitions| now.duration_since(repetitions.window_started) < window);
+        }
+        // Still too many messages within the window, forget everything
+        if self.messages.len() >= MAX_MESSAGES {
+            self.messages.clear();
+        }
+
+        let Some(repetitions) = self.messages.get_mut(message) else {
+            self.messages.insert(
+                message.to_owned(),
+                Repetitions {
+                    window_started: now,
+                    logged: 1,
+                    suppressed: 0,
+                },
+            );
+            return Verdict::Log { suppressed: 0 };
+        };
+
+        if now.duration_since(repetitions.window_started) >= self.window {
+            let suppressed = std::mem::take(&mut repetitions.suppressed);
+            repetitions.window_started = now;
+            repetitions.logged = 1;
+            return Verdict::Log { suppressed };
+        }
+        if repetitions.logged < self.burst {
+            repetitions.logged += 1;
+            return Verdict::Log { suppressed: 0 };
+        }
+        repetitions.suppressed += 1;
+        Verdict::Suppress
+    }
+}
+
+/// Labels as `[key=value ...] ` to start log lines with, empty without labels
+pub fn label_prefix(labels: &[(String, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect();
+    format!("[{}] ", labels.join(" "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn repetitions_suppressed() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let verdicts: Vec<_> = [0, 1, 2, 3, 4]
+            .into_iter()
+            .map(|secs| limiter.check("failed to send", at(secs)))
+            .collect();
+        assert_eq!(
+            vec![
+                Verdict::Log { suppressed: 0 },
+                Verdict::Log { suppressed: 0 },
+                Verdict::Suppress,
+                Verdict::Suppress,
+                Verdict::Suppress,
+            ],
+            verdicts
+        );
+        // Other messages are counted on their own
+        assert_eq!(
+            Verdict::Log { suppressed: 0 },
+            limiter.check("target up", at(5))
+        );
+
+        assert_eq!(
+            Verdict::Log { suppressed: 3 },
+            limiter.check("failed to send", at(10))
+        );
+        assert_eq!(
+            Verdict::Log { suppressed: 0 },
+            limiter.check("failed to send", at(11))
+        );
+    }
+
+    #[test]
+    fn labels_prefixed() {
+        assert_eq!("", label_prefix(&[]));
+        assert_eq!(
+            "[tenant=teamA stream=cam01] ",
+            label_prefix(&[
+                ("tenant".to_owned(), "teamA".to_owned()),
+                ("stream".to_owned(), "cam01".to_owned())
+            ])
+        );
+    }
+
+    #[test]
+    fn levels_ordered() {
+        assert!(LogLevel::Error < LogLevel::Warn);
+        assert_eq!(Ok(LogLevel::Warn), "warn".parse());
+        assert!("debug".parse::<LogLevel>().is_err());
+    }
+}
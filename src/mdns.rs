@@ -0,0 +1,393 @@
+//! Zeroconf advertisement of the listener
+//!
+//! Answers multicast DNS queries (RFC 6762) for the `_udpforwarder._udp` service
+//! type with DNS-SD records (RFC 6763), so that clients and dashboards on the LAN
+//! find the relays available. The TXT record tells the multicast group and port relayed.
+//!
+//! Only IPv4 mDNS is answered. On Linux, port 5353 is shared with other responders
+//! like Avahi. The C functions needed for that are declared in the crate to stay free of dependencies.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+use crate::sockopt;
+use crate::{
+    ListenerSpec,
+    dns::{self, QueryType},
+};
+
+/// Service type advertised
+pub const SERVICE_TYPE: &str = "_udpforwarder._udp.local";
+/// Labels of the service type
+const SERVICE_TYPE_LABELS: [&str; 3] = ["_udpforwarder", "_udp", "local"];
+
+/// Multicast address and port of mDNS
+const MDNS_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+/// Name listing all service types for DNS-SD browsing
+const SERVICES_NAME: &str = "_services._dns-sd._udp.local";
+/// Labels of the name listing all service types
+const SERVICES_LABELS: [&str; 4] = ["_services", "_dns-sd", "_udp", "local"];
+/// TTL of records naming the host, as recommended by RFC 6762
+const HOST_TTL: u32 = 120;
+/// TTL of other records
+const OTHER_TTL: u32 = 4500;
+/// Class IN with the cache-flush bit of records unique to this host
+const CLASS_IN_FLUSH: u16 = 0x8001;
+/// Class IN of shared records
+const CLASS_IN: u16 = 1;
+/// Number of unsolicited announcements on startup
+const NUM_ANNOUNCEMENTS: usize = 2;
+/// Interval between announcements
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+const PTR: QueryType = QueryType(12);
+const TXT: QueryType = QueryType(16);
+const SRV: QueryType = QueryType(33);
+const ANY: QueryType = QueryType(255);
+
+/// Advertisement of the listener via mDNS
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mdns {
+    /// Name of the service instance, defaults to the hostname and port
+    pub instance: Option<String>,
+}
+
+impl Mdns {
+    /// Describe the service of `listener_spec`
+    ///
+    /// Fails for sockets passed by systemd, whose port is unknown.
+    pub fn service(&self, listener_spec: &ListenerSpec) -> Result<Service, io::Error> {
+        let port = listener_spec.port().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "port of sockets passed by systemd is unknown",
+            )
+        })?;
+        let hostname = hostname();
+
+        let mut txt = vec!["txtvers=1".to_owned()];
+        let local_ip = match listener_spec {
+            ListenerSpec::Unicast(addr) => Some(addr.ip()),
+            ListenerSpec::MulticastV4 {
+                multicast_group,
+                local_addr,
+                ..
+            } => {
+                txt.push(format!("group={}", multicast_group.ip()));
+                Some(IpAddr::V4(*local_addr))
+            }
+            ListenerSpec::MulticastV6 {
+                multicast_group, ..
+            } => {
+                txt.push(format!("group={}", multicast_group.ip()));
+                None
+            }
+            ListenerSpec::Systemd(_) => None,
+        };
+        txt.push(format!("port={port}"));
+
+        // Address of the interface mDNS is sent on unless bound to a specific one
+        let addr = match local_ip {
+            Some(IpAddr::V4(ip)) if !ip.is_unspecified() => ip,
+            _ => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+                socket.connect(MDNS_ADDR)?;
+                match socket.local_addr()?.ip() {
+                    IpAddr::V4(ip) => ip,
+                    IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+                }
+            }
+        };
+
+        Ok(Service {
+            instance: self
+                .instance
+                .clone()
+                .unwrap_or_else(|| format!("{hostname}:{port}")),
+            host: format!("{hostname}.local"),
+            addr,
+            port,
+            txt,
+        })
+    }
+}
+
+/// Records of an advertised service instance
+#[derive(Debug, Clone, PartialEq)]
+pub struct Service {
+    /// Name of the instance, a single label
+    pub instance: String,
+    /// Name of the host, ending in `.local`
+    pub host: String,
+    /// Address of the host
+    pub addr: Ipv4Addr,
+    /// Port of the listener
+    pub port: u16,
+    /// Entries of the TXT record as `key=value`
+    pub txt: Vec<String>,
+}
+
+impl Service {
+    /// Full name of the instance
+    fn name(&self) -> String {
+        format!("{}.{SERVICE_TYPE}", self.instance)
+    }
+
+    /// Whether the question asks for records of this service
+    fn answers(&self, name: &str, kind: QueryType) -> bool {
+        let is = |expected: &str| name.eq_ignore_ascii_case(expected);
+
+        match kind {
+            PTR => is(SERVICE_TYPE) || is(SERVICES_NAME),
+            SRV | TXT => is(&self.name()),
+            QueryType::A => is(&self.host),
+            ANY => is(SERVICE_TYPE) || is(&self.name()) || is(&self.host),
+            _ => false,
+        }
+    }
+
+    /// Build a response carrying all records of the service
+    ///
+    /// `query` holds the ID and questions of a legacy unicast query to repeat.
+    fn response(&self, query: Option<(u16, &[u8])>) -> Vec<u8> {
+        let (id, questions) = query.unwrap_or((0, &[]));
+        let num_questions = match questions.is_empty() {
+            true => 0u16,
+            false => 1,
+        };
+        let instance = [
+            self.instance.as_str(),
+            SERVICE_TYPE_LABELS[0],
+            SERVICE_TYPE_LABELS[1],
+            SERVICE_TYPE_LABELS[2],
+        ];
+        let host: Vec<&str> = self.host.split('.').collect();
+
+        let mut packet = Vec::with_capacity(512);
+        packet.extend_from_slice(&id.to_be_bytes());
+        // Authoritative response
+        packet.extend_from_slice(&[0x84, 0x00]);
+        packet.extend_from_slice(&num_questions.to_be_bytes());
+        packet.extend_from_slice(&5u16.to_be_bytes());
+        packet.extend_from_slice(&[0; 4]);
+        packet.extend_from_slice(questions);
+
+        push_record(
+            &mut packet,
+            &SERVICES_LABELS,
+            PTR,
+            CLASS_IN,
+            OTHER_TTL,
+            |data| push_name(data, &SERVICE_TYPE_LABELS),
+        );
+        push_record(
+            &mut packet,
+            &SERVICE_TYPE_LABELS,
+            PTR,
+            CLASS_IN,
+            OTHER_TTL,
+            |data| push_name(data, &instance),
+        );
+        push_record(
+            &mut packet,
+            &instance,
+            SRV,
+            CLASS_IN_FLUSH,
+            HOST_TTL,
+            |data| {
+                // Priority and weight
+                data.extend_from_slice(&[0; 4]);
+                data.extend_from_slice(&self.port.to_be_bytes());
+                push_name(data, &host);
+            },
+        );
+        push_record(
+            &mut packet,
+            &instance,
+            TXT,
+            CLASS_IN_FLUSH,
+            OTHER_TTL,
+            |data| {
+                for entry in &self.txt {
+                    data.push(entry.len().min(255) as u8);
+                    data.extend_from_slice(&entry.as_bytes()[..entry.len().min(255)]);
+                }
+            },
+        );
+        push_record(
+            &mut packet,
+            &host,
+            QueryType::A,
+            CLASS_IN_FLUSH,
+            HOST_TTL,
+            |data| data.extend_from_slice(&self.addr.octets()),
+        );
+        packet
+    }
+}
+
+/// Join the mDNS group, announce `service` and answer queries for it in a new thread
+pub fn advertise(service: Service) -> Result<JoinHandle<()>, io::Error> {
+    let socket = bind_shared(MDNS_ADDR.port())?;
+    socket.join_multicast_v4(MDNS_ADDR.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    // Receivers discard mDNS packets from other networks by their TTL
+    socket.set_multicast_ttl_v4(255)?;
+
+    thread::Builder::new()
+        .name("mdns".to_owned())
+        .spawn(move || {
+            let announcement = service.response(None);
+            for idx in 0..NUM_ANNOUNCEMENTS {
+                if idx > 0 {
+                    thread::sleep(ANNOUNCE_INTERVAL);
+                }
+                let _ = socket.send_to(&announcement, MDNS_ADDR);
+            }
+
+            let mut buffer = [0; 9000];
+            loop {
+                let Ok((len, source)) = socket.recv_from(&mut buffer) else {
+                    continue;
+                };
+                let Some(questions) = matching_questions(&buffer[..len], &service) else {
+                    continue;
+                };
+
+                // Queries from other ports are one-shot lookups expecting a unicast response
+                let _ = match source.port() == MDNS_ADDR.port() {
+                    true => socket.send_to(&announcement, MDNS_ADDR),
+                    false => {
+                        let id = u16::from_be_bytes([buffer[0], buffer[1]]);
+                        socket.send_to(&service.response(Some((id, questions))), source)
+                    }
+                };
+            }
+        })
+}
+
+/// Check if `packet` is a query asking for `service`
+///
+/// Returns the first question, repeated in unicast responses.
+fn matching_questions<'a>(packet: &'a [u8], service: &Service) -> Option<&'a [u8]> {
+    let header = packet.get(..12)?;
+    if header[2] & 0x80 != 0 {
+        return None;
+    }
+    let num_questions = u16::from_be_bytes([header[4], header[5]]);
+
+    let mut offset = 12;
+    let mut first = None;
+    let mut matched = false;
+    for _ in 0..num_questions {
+        let (name, end) = dns::parse_name(packet, offset)?;
+        let kind = packet.get(end..end + 2)?;
+        let kind = QueryType(u16::from_be_bytes([kind[0], kind[1]]));
+        first.get_or_insert(&packet[offset..end + 4]);
+        matched |= service.answers(&name, kind);
+        offset = end + 4;
+    }
+
+    first.filter(|_| matched)
+}
+
+/// Append a resource record, with the data written by `data`
+fn push_record(
+    packet: &mut Vec<u8>,
+    name: &[&str],
+    kind: QueryType,
+    class: u16,
+    ttl: u32,
+    data: impl FnOnce(&mut Vec<u8>),
+) {
+    push_name(packet, name);
+    packet.extend_from_slice(&kind.0.to_be_bytes());
+    packet.extend_from_slice(&class.to_be_bytes());
+    packet.extend_from_slice(&ttl.to_be_bytes());
+
+    let len_offset = packet.len();
+    packet.extend_from_slice(&[0; 2]);
+    data(packet);
+    let len = (packet.len() - len_offset - 2) as u16;
+    packet[len_offset..len_offset + 2].copy_from_slice(&len.to_be_bytes());
+}
+
+/// Append a name of `labels` without compression
+fn push_name(packet: &mut Vec<u8>, labels: &[&str]) {
+    for label in labels {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label);
+    }
+    packet.push(0);
+}
+
+/// Name of this host, without domain
+fn hostname() -> String {
+    ["/proc/sys/kernel/hostname", "/etc/hostname"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .and_then(|name| {
+            let name = name.trim().split('.').next()?.to_owned();
+            (!name.is_empty()).then_some(name)
+        })
+        .unwrap_or_else(|| "udpforwarder".to_owned())
+}
+
+/// Bind a UDP socket to `port`, sharing it with other mDNS responders
+#[cfg(target_os = "linux")]
+fn bind_shared(port: u16) -> Result<UdpSocket, io::Error> {
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let socket = sockopt::unbound(&addr)?;
+    sockopt::set_int(&socket, sockopt::SOL_SOCKET, sockopt::SO_REUSEADDR, 1)?;
+    sockopt::bind_to(&socket, &addr)?;
+
+    Ok(socket)
+}
+
+/// Bind a UDP socket to `port`, failing if another responder uses it
+#[cfg(not(target_os = "linux"))]
+fn bind_shared(port: u16) -> Result<UdpSocket, io::Error> {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn queries_for_service_answered() {
+        let listener_spec = ListenerSpec::MulticastV4 {
+            multicast_group: "224.10.10.10:4000".parse().unwrap(),
+            local_addr: Ipv4Addr::new(10, 1, 1, 10),
+            interfaces: Vec::new(),
+            sources: Vec::new(),
+        };
+        let mdns = Mdns {
+            instance: Some("Camera feed".to_owned()),
+        };
+        let service = mdns.service(&listener_spec).unwrap();
+        assert_eq!(Ipv4Addr::new(10, 1, 1, 10), service.addr);
+        assert_eq!(
+            vec!["txtvers=1", "group=224.10.10.10", "port=4000"],
+            service.txt
+        );
+
+        let query = dns::build_query(0x1234, SERVICE_TYPE, PTR);
+        let question = matching_questions(&query, &service).unwrap();
+        let response = service.response(Some((0x1234, question)));
+        assert_eq!(
+            Some(Ok(vec![IpAddr::from([10, 1, 1, 10])])),
+            dns::parse_addresses(&response, 0x1234)
+        );
+
+        let other = dns::build_query(0x1234, "_http._tcp.local", PTR);
+        assert_eq!(None, matching_questions(&other, &service));
+        assert_eq!(None, matching_questions(&response, &service));
+    }
+}
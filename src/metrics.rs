@@ -0,0 +1,238 @@
+//! Prometheus metrics endpoint
+//!
+//! Serves the counters of a running forwarder at `GET /metrics` in the text exposition
+//! format, so that it is scraped like the rest of the monitored infrastructure. Each
+//! series carries the labels given with `--label`, the per-target series also the
+//! address of the target. Only available with the `control` feature.
+//!
+//! | Metric                                     | Labels     | Meaning                                    |
+//! |--------------------------------------------|------------|--------------------------------------------|
+//! | `udpforwarder_received_packets_total`      |            | packets received on the listener           |
+//! | `udpforwarder_received_bytes_total`        |            | bytes received on the listener             |
+//! | `udpforwarder_dropped_packets_total`       | `reason`   | packets not forwarded, see below           |
+//! | `udpforwarder_dropped_bytes_total`         | `reason`   | bytes not forwarded                        |
+//! | `udpforwarder_relayed_packets_total`       |            | packets relayed to peers or clients        |
+//! | `udpforwarder_relayed_bytes_total`         |            | bytes relayed to peers or clients          |
+//! | `udpforwarder_sent_packets_total`          | `target`   | packets sent to a target                   |
+//! | `udpforwarder_sent_bytes_total`            | `target`   | bytes sent to a target                     |
+//! | `udpforwarder_send_errors_total`           | `target`   | failed sends to a target                   |
+//! | `udpforwarder_queue_dropped_packets_total` | `target`   | packets dropped from the full target queue |
+//! | `udpforwarder_paused`                      |            | 1 while forwarding is paused               |
+//! | `udpforwarder_uptime_seconds`              |            | time since the forwarder was set up        |
+//!
+//! Drops are counted by reason: `looped` for packets sent by the forwarder itself,
+//! `expired` for packets whose hop limit was reached, `filtered` for packets dropped by
+//! packet handlers and `paused` for packets received while paused.
+
+use std::{
+    fmt::Write,
+    io,
+    net::SocketAddr,
+    sync::{Arc, atomic::Ordering},
+    thread::JoinHandle,
+};
+
+use crate::{
+    http::{self, Request, Response},
+    state::{Counter, SharedState, Target},
+};
+
+/// Content type of the text exposition format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Serve the metrics on `addr` in new threads
+pub fn serve(addr: SocketAddr, state: Arc<SharedState>) -> Result<Vec<JoinHandle<()>>, io::Error> {
+    http::serve(addr, "metrics", move |request| handle(&state, request))
+}
+
+/// Handle a single scrape
+fn handle(state: &SharedState, request: &Request) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: CONTENT_TYPE,
+            body: render(state).into_bytes(),
+        },
+        _ => Response::not_found(),
+    }
+}
+
+/// Render the counters of `state` in the text exposition format
+fn render(state: &SharedState) -> String {
+    let labels: Vec<String> = state
+        .labels()
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{}\"", escape(value)))
+        .collect();
+    // Labels of a series, the configured ones followed by `extra`
+    let series = |extra: Option<(&str, &str)>| {
+        let mut all = labels.clone();
+        if let Some((key, value)) = extra {
+            all.push(format!("{key}=\"{}\"", escape(value)));
+        }
+        match all.is_empty() {
+            true => String::new(),
+            false => format!("{{{}}}", all.join(",")),
+        }
+    };
+    let mut metrics = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        let _ = writeln!(metrics, "# HELP udpforwarder_{name} {help}");
+        let _ = writeln!(metrics, "# TYPE udpforwarder_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(metrics, "udpforwarder_{name}{labels} {value}");
+        }
+    };
+
+    metric(
+        "received_packets_total",
+        "counter",
+        "Packets received on the listener.",
+        &[(series(None), state.received().packets())],
+    );
+    metric(
+        "received_bytes_total",
+        "counter",
+        "Bytes received on the listener.",
+        &[(series(None), state.received().bytes())],
+    );
+
+    let drops: [(&str, &Counter); 4] = [
+        ("looped", state.looped()),
+        ("expired", state.expired()),
+        ("filtered", state.filtered()),
+        ("paused", state.paused_dropped()),
+    ];
+    metric(
+        "dropped_packets_total",
+        "counter",
+        "Received packets which were not forwarded, by reason.",
+        &drops.map(|(reason, counter)| (series(Some(("reason", reason))), counter.packets())),
+    );
+    metric(
+        "dropped_bytes_total",
+        "counter",
+        "Received bytes which were not forwarded, by reason.",
+        &drops.map(|(reason, counter)| (series(Some(("reason", reason))), counter.bytes())),
+    );
+    metric(
+        "relayed_packets_total",
+        "counter",
+        "Packets relayed to hub peers, the rendezvous peer, through TURN or to clients.",
+        &[(series(None), state.relayed().packets())],
+    );
+    metric(
+        "relayed_bytes_total",
+        "counter",
+        "Bytes relayed to hub peers, the rendezvous peer, through TURN or to clients.",
+        &[(series(None), state.relayed().bytes())],
+    );
+
+    let targets = state.targets();
+    let per_target = |value: &dyn Fn(&Target) -> u64| -> Vec<(String, u64)> {
+        targets
+            .iter()
+            .map(|target| {
+                let addr = target.addr.to_string();
+                (series(Some(("target", &addr))), value(target))
+            })
+            .collect()
+    };
+    metric(
+        "sent_packets_total",
+        "counter",
+        "Packets sent to a target.",
+        &per_target(&|target| target.sent.packets()),
+    );
+    metric(
+        "sent_bytes_total",
+        "counter",
+        "Bytes sent to a target.",
+        &per_target(&|target| target.sent.bytes()),
+    );
+    metric(
+        "send_errors_total",
+        "counter",
+        "Failed sends to a target.",
+        &per_target(&|target| target.send_errors.load(Ordering::Relaxed)),
+    );
+    metric(
+        "queue_dropped_packets_total",
+        "counter",
+        "Packets dropped because the send queue of a target was full.",
+        &per_target(&|target| target.queue_dropped.packets()),
+    );
+
+    metric(
+        "paused",
+        "gauge",
+        "Whether forwarding is paused.",
+        &[(series(None), u64::from(state.is_paused()))],
+    );
+    let _ = writeln!(
+        metrics,
+        "# HELP udpforwarder_uptime_seconds Time since the forwarder was set up.\n\
+         # TYPE udpforwarder_uptime_seconds gauge\n\
+         udpforwarder_uptime_seconds{} {:.3}",
+        series(None),
+        state.uptime().as_secs_f64()
+    );
+
+    metrics
+}
+
+/// Escape a label value for the text exposition format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(method: &str, path: &str) -> Request {
+        Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            query: None,
+            host: None,
+            origin: None,
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn counters_rendered() {
+        let state = SharedState::new(None, &["127.0.0.1:4001".parse().unwrap()]);
+        state.set_labels(vec![("tenant".to_owned(), "team\"A\"".to_owned())]);
+        state.received().add(100);
+        state.looped().add(10);
+        state.targets()[0].sent.add(100);
+        state.targets()[0]
+            .send_errors
+            .fetch_add(2, Ordering::Relaxed);
+
+        let response = handle(&state, &request("GET", "/metrics"));
+        assert_eq!(200, response.status);
+        assert_eq!(CONTENT_TYPE, response.content_type);
+        let metrics = String::from_utf8(response.body).unwrap();
+        for line in [
+            "# TYPE udpforwarder_received_packets_total counter",
+            "udpforwarder_received_packets_total{tenant=\"team\\\"A\\\"\"} 1",
+            "udpforwarder_received_bytes_total{tenant=\"team\\\"A\\\"\"} 100",
+            "udpforwarder_dropped_packets_total{tenant=\"team\\\"A\\\"\",reason=\"looped\"} 1",
+            "udpforwarder_dropped_bytes_total{tenant=\"team\\\"A\\\"\",reason=\"paused\"} 0",
+            "udpforwarder_sent_bytes_total{tenant=\"team\\\"A\\\"\",target=\"127.0.0.1:4001\"} 100",
+            "udpforwarder_send_errors_total{tenant=\"team\\\"A\\\"\",target=\"127.0.0.1:4001\"} 2",
+            "udpforwarder_paused{tenant=\"team\\\"A\\\"\"} 0",
+        ] {
+            assert!(metrics.lines().any(|l| l == line), "{line} in {metrics}");
+        }
+
+        assert_eq!(404, handle(&state, &request("GET", "/")).status);
+        assert_eq!(404, handle(&state, &request("POST", "/metrics")).status);
+    }
+}
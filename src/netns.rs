@@ -0,0 +1,136 @@
+//! Network namespaces
+//!
+//! A socket belongs to the network namespace of the thread creating it and stays there.
+//! Entering a namespace with `setns` only while creating a socket lets the listener and
+//! the senders live in different namespaces, e.g. of two containers, so the forwarder
+//! passes packets between them without veth plumbing. Only available on Linux and
+//! requires `CAP_SYS_ADMIN`.
+
+#![cfg(target_os = "linux")]
+
+use std::{
+    ffi::c_int,
+    fmt,
+    fs::File,
+    io,
+    net::{SocketAddr, UdpSocket},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use crate::socket::{Network, Socket, SystemNetwork};
+
+const CLONE_NEWNET: c_int = 0x40000000;
+/// Directory where `ip netns` keeps the named namespaces
+const NETNS_RUN_DIR: &str = "/run/netns";
+/// Namespace of the calling thread, to return to
+const THREAD_NETNS: &str = "/proc/thread-self/ns/net";
+
+unsafe extern "C" {
+    fn setns(fd: c_int, nstype: c_int) -> c_int;
+}
+
+/// Network namespace named like with `ip netns` or given by path
+///
+/// As a [Network], it binds the senders of a forwarder in the namespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Netns {
+    path: PathBuf,
+}
+
+impl Netns {
+    /// Namespace `name` as added by `ip netns add`, or at the path `name` like
+    /// `/proc/1234/ns/net` if it contains a `/`
+    pub fn new(name: &str) -> Self {
+        let path = match name.contains('/') {
+            true => PathBuf::from(name),
+            false => Path::new(NETNS_RUN_DIR).join(name),
+        };
+        Self { path }
+    }
+
+    /// File of the namespace
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Run `f` with the calling thread in the namespace, returning to the current one after
+    ///
+    /// Sockets created by `f` stay in the namespace, just like threads spawned by it.
+    pub fn enter<T>(&self, f: impl FnOnce() -> Result<T, io::Error>) -> Result<T, io::Error> {
+        let current = File::open(THREAD_NETNS)?;
+        let namespace = File::open(&self.path)
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot open {self}: {e}")))?;
+        set_netns(&namespace)?;
+        let result = f();
+        // Left in the namespace, the thread would create all later sockets there
+        set_netns(&current)?;
+        result
+    }
+}
+
+/// Shows the path of the namespace
+impl fmt::Display for Netns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "network namespace {}", self.path.display())
+    }
+}
+
+impl Network for Netns {
+    fn bind(&self, addr: SocketAddr) -> Result<Box<dyn Socket>, io::Error> {
+        let socket = self.enter(|| UdpSocket::bind(addr))?;
+        Ok(Box::new(socket))
+    }
+
+    /// Routes of the namespace decide the source address, so it is probed there
+    fn source_addr(&self, socket: &dyn Socket, destination: &SocketAddr) -> Option<SocketAddr> {
+        self.enter(|| Ok(SystemNetwork.source_addr(socket, destination)))
+            .ok()
+            .flatten()
+    }
+}
+
+/// Move the calling thread into the network namespace of the file `namespace`
+fn set_netns(namespace: &File) -> Result<(), io::Error> {
+    // SAFETY: Plain syscall on an open file descriptor
+    match unsafe { setns(namespace.as_raw_fd(), CLONE_NEWNET) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn netns_named_or_by_path() {
+        assert_eq!(Path::new("/run/netns/blue"), Netns::new("blue").path());
+        assert_eq!(
+            Path::new("/proc/1/ns/net"),
+            Netns::new("/proc/1/ns/net").path()
+        );
+    }
+
+    #[test]
+    fn sockets_bound_in_namespace() {
+        // The namespace of the process itself can be entered without creating one
+        let netns = Netns::new("/proc/self/ns/net");
+        let socket = match Network::bind(&netns, "127.0.0.1:0".parse().unwrap()) {
+            Ok(socket) => socket,
+            // Entering a namespace requires CAP_SYS_ADMIN
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => return,
+            Err(err) => panic!("bind in namespace: {err}"),
+        };
+        let addr = socket.local_addr().unwrap();
+        assert_eq!(
+            Some(addr),
+            netns.source_addr(socket.as_ref(), &"127.0.0.1:4000".parse().unwrap())
+        );
+
+        // Missing namespaces fail without leaving the current one
+        let missing = Netns::new("udpforwarder-missing");
+        let error = missing.enter(|| Ok(())).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, error.kind());
+    }
+}
@@ -0,0 +1,369 @@
+//! Notifications on significant events
+//!
+//! Operators can wire in their own remediation by having a command run or a webhook
+//! called whenever a target goes up or down, the stream turns silent or active, the
+//! rate leaves its envelope or forwarding fails for good.
+
+use std::{
+    fmt, io,
+    net::{SocketAddr, ToSocketAddrs},
+    process::{Command, Stdio},
+    str::FromStr,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{alert::RateAlert, hooks::TargetState, http, json::json_string, silence::StreamState};
+
+/// Timeout of each attempt to call a webhook
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Attempts to call a webhook before giving up
+const WEBHOOK_ATTEMPTS: u32 = 4;
+/// Wait before the first retry, doubled for each further one
+const WEBHOOK_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Significant event of a running forwarder
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Packets are forwarded to the target from now on
+    TargetUp(SocketAddr),
+    /// Packets are no longer forwarded to the target
+    TargetDown(SocketAddr),
+    /// The stream at the listener turned active or silent
+    Stream(StreamState),
+    /// The packet rate left or returned into its envelope
+    Rate(RateAlert),
+    /// Forwarding stopped with the given error
+    Fatal(String),
+}
+
+impl Event {
+    /// Event of a target turning active or inactive
+    pub fn target(addr: SocketAddr, state: TargetState) -> Self {
+        match state {
+            TargetState::Active => Self::TargetUp(addr),
+            TargetState::Inactive => Self::TargetDown(addr),
+        }
+    }
+
+    /// Short name of the kind of event, e.g. `target_down`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::TargetUp(_) => "target_up",
+            Self::TargetDown(_) => "target_down",
+            Self::Stream(StreamState::Active) => "stream_active",
+            Self::Stream(StreamState::Silent) => "stream_silent",
+            Self::Rate(RateAlert::BelowMin { .. }) => "rate_below_min",
+            Self::Rate(RateAlert::AboveMax { .. }) => "rate_above_max",
+            Self::Rate(RateAlert::Recovered { .. }) => "rate_recovered",
+            Self::Fatal(_) => "fatal",
+        }
+    }
+
+    /// Human-readable detail, e.g. the address of the target
+    pub fn detail(&self) -> String {
+        match self {
+            Self::TargetUp(addr) | Self::TargetDown(addr) => addr.to_string(),
+            Self::Stream(state) => format!("stream {state}"),
+            Self::Rate(alert) => alert.to_string(),
+            Self::Fatal(error) => error.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name(), self.detail())
+    }
+}
+
+/// Render `event` as JSON object with its name, detail and the time in seconds since the epoch
+fn event_json(event: &Event, time: SystemTime) -> String {
+    let time = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    format!(
+        "{{\"event\":\"{}\",\"detail\":{},\"time\":{time:.3}}}",
+        event.name(),
+        json_string(&event.detail())
+    )
+}
+
+/// Command run on every event
+///
+/// Parsed from a template like `script.sh {event} {detail}`, which is split at
+/// whitespace and run without a shell. `{event}` and `{detail}` are replaced by the
+/// name and detail of the event within each word, so a detail with spaces stays
+/// a single argument.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExecHook {
+    /// Program and arguments with placeholders
+    pub template: Vec<String>,
+}
+
+impl ExecHook {
+    /// Program and arguments for `event`
+    fn command_line(&self, event: &Event) -> Vec<String> {
+        let detail = event.detail();
+        self.template
+            .iter()
+            .map(|word| {
+                word.replace("{event}", event.name())
+                    .replace("{detail}", &detail)
+            })
+            .collect()
+    }
+
+    /// Start the command for `event` without waiting for it to finish
+    ///
+    /// The command is reaped on a thread of its own, so that a slow script
+    /// doesn't hold up forwarding.
+    pub fn run(&self, event: &Event) -> Result<(), io::Error> {
+        let command_line = self.command_line(event);
+        let Some((program, args)) = command_line.split_first() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty command"));
+        };
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .spawn()?;
+        thread::Builder::new()
+            .name("on-event".to_owned())
+            .spawn(move || child.wait())?;
+        Ok(())
+    }
+}
+
+impl FromStr for ExecHook {
+    type Err = ParseExecHookError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let template: Vec<String> = s.split_whitespace().map(str::to_owned).collect();
+        if template.is_empty() {
+            return Err(ParseExecHookError);
+        }
+        Ok(Self { template })
+    }
+}
+
+/// URL receiving every event as JSON in a `POST` request
+///
+/// Only plain HTTP is supported, services which require HTTPS can be reached through
+/// a local relay or proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Webhook {
+    /// Host and optional port, sent as `Host`
+    pub host: String,
+    /// Path and query
+    pub path: String,
+}
+
+impl Webhook {
+    /// Post `event` to the URL, retrying with backoff while the server is unreachable
+    ///
+    /// Blocks until the event is delivered or the last attempt failed, call it on
+    /// a thread of its own to not hold up forwarding. Server errors and 429 are
+    /// retried, other responses than 2xx fail immediately.
+    pub fn send(&self, event: &Event) -> Result<(), io::Error> {
+        let body = event_json(event, SystemTime::now());
+        let mut backoff = WEBHOOK_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match self.post(body.as_bytes()) {
+                Ok(()) => return Ok(()),
+                Err(e)
+                    if attempt == WEBHOOK_ATTEMPTS || e.kind() == io::ErrorKind::InvalidInput =>
+                {
+                    return Err(e);
+                }
+                Err(_) => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Post `body` once
+    fn post(&self, body: &[u8]) -> Result<(), io::Error> {
+        let addr = match self.host.to_socket_addrs() {
+            Ok(mut addrs) => addrs.next(),
+            Err(_) => (self.host.as_str(), 80).to_socket_addrs()?.next(),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address for {}", self.host),
+            )
+        })?;
+
+        let response = http::request(
+            addr,
+            &self.host,
+            "POST",
+            &self.path,
+            &[("Content-Type", "application/json")],
+            body,
+            WEBHOOK_TIMEOUT,
+        )?;
+        match response.status {
+            200..=299 => Ok(()),
+            429 | 500.. => Err(io::Error::other(format!("status {}", response.status))),
+            // Retrying won't help with a rejected request
+            status => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("status {status}"),
+            )),
+        }
+    }
+}
+
+impl FromStr for Webhook {
+    type Err = ParseWebhookError;
+
+    /// Parse an URL like `http://hooks.example.com:8080/udpforwarder`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s.strip_prefix("http://").ok_or(ParseWebhookError)?;
+        let (host, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(ParseWebhookError);
+        }
+        Ok(Self {
+            host: host.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+}
+
+/// URL other than `http://host[:port][/path]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseWebhookError;
+
+impl fmt::Display for ParseWebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected http://host[:port][/path]")
+    }
+}
+
+impl std::error::Error for ParseWebhookError {}
+
+/// Command template without a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseExecHookError;
+
+impl fmt::Display for ParseExecHookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected a command to run")
+    }
+}
+
+impl std::error::Error for ParseExecHookError {}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs,
+        io::Write,
+        net::TcpListener,
+        process,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    #[test]
+    fn placeholders_replaced() {
+        let hook: ExecHook = "notify.sh --kind={event} {detail}".parse().unwrap();
+        let event = Event::Rate(RateAlert::BelowMin {
+            pps: 0.0,
+            min_pps: 10.0,
+        });
+        assert_eq!(
+            vec![
+                "notify.sh",
+                "--kind=rate_below_min",
+                "rate 0.0 pps below the minimum of 10 pps"
+            ],
+            hook.command_line(&event)
+        );
+
+        assert!("  ".parse::<ExecHook>().is_err());
+    }
+
+    #[test]
+    fn webhooks_parsed() {
+        assert_eq!(
+            Ok(Webhook {
+                host: "hooks.example.com:8080".to_owned(),
+                path: "/udp?key=1".to_owned(),
+            }),
+            "http://hooks.example.com:8080/udp?key=1".parse()
+        );
+        let hook: Webhook = "http://[::1]".parse().unwrap();
+        assert_eq!("/", hook.path);
+        assert!("https://hooks.example.com".parse::<Webhook>().is_err());
+        assert!("http:///path".parse::<Webhook>().is_err());
+    }
+
+    #[test]
+    fn event_posted_as_json() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        assert_eq!(
+            r#"{"event":"fatal","detail":"\"eth0\" gone","time":1700000000.500}"#,
+            event_json(&Event::Fatal("\"eth0\" gone".to_owned()), time)
+        );
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hook: Webhook = format!("http://{}/events", server.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let received = thread::spawn(move || {
+            let (mut stream, _) = server.accept().unwrap();
+            let request = http::read_request(&mut stream).unwrap();
+            stream
+                .write_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+                .unwrap();
+            request
+        });
+
+        hook.send(&Event::Stream(StreamState::Silent)).unwrap();
+        let request = received.join().unwrap();
+        assert_eq!("POST", request.method);
+        assert_eq!("/events", request.path);
+        assert!(
+            String::from_utf8(request.body)
+                .unwrap()
+                .starts_with(r#"{"event":"stream_silent","detail":"stream silent","time":"#)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_run() {
+        let dir = env::temp_dir().join(format!("udpforwarder-on-event-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let hook: ExecHook = format!("touch {}/{{event}}", dir.display())
+            .parse()
+            .unwrap();
+
+        let addr = "10.1.1.10:4000".parse().unwrap();
+        hook.run(&Event::target(addr, TargetState::Inactive))
+            .unwrap();
+
+        // The command runs in the background
+        let created = dir.join("target_down");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !created.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(created.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
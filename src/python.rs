@@ -0,0 +1,274 @@
+//! Python bindings
+//!
+//! The `python` feature builds the `udpforwarder` extension module, e.g. with
+//! `maturin develop --features python`, so that test automation can start relays
+//! in-process, change their targets and read their counters.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, atomic::Ordering},
+};
+
+use pyo3::{
+    exceptions::{PyRuntimeError, PyValueError},
+    prelude::*,
+    types::PyDict,
+};
+
+use crate::{Forwarder, ListenerSpec, forwarding::Background, state::SharedState};
+
+/// Listener specification parsed from the CLI syntax
+#[pyclass(name = "ListenerSpec", frozen)]
+struct PyListenerSpec(ListenerSpec);
+
+#[pymethods]
+impl PyListenerSpec {
+    #[new]
+    fn new(spec: &str) -> PyResult<Self> {
+        spec.parse()
+            .map(Self)
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Port to listen on, `None` for sockets passed by systemd
+    #[getter]
+    fn port(&self) -> Option<u16> {
+        self.0.port()
+    }
+
+    /// Address senders send to, `None` for sockets passed by systemd
+    #[getter]
+    fn addr(&self) -> Option<String> {
+        self.0.addr().map(|addr| addr.to_string())
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("ListenerSpec('{}')", self.0)
+    }
+}
+
+/// Stage of a forwarder controlled from Python
+enum Stage {
+    /// Sockets bound, not forwarding yet
+    Ready(Box<Forwarder>),
+    Running(Background),
+    Stopped,
+}
+
+/// Forwarder binding its sockets on creation and forwarding between `start()` and `stop()`
+///
+/// Can be used as context manager to forward within a `with` block.
+#[pyclass(name = "Forwarder")]
+struct PyForwarder {
+    state: Arc<SharedState>,
+    stage: Mutex<Stage>,
+}
+
+#[pymethods]
+impl PyForwarder {
+    /// Bind the listener, given as string or `ListenerSpec`, and the senders to `targets`
+    #[new]
+    fn new(listener: &Bound<'_, PyAny>, targets: Vec<String>) -> PyResult<Self> {
+        let listener_spec = match listener.cast::<PyListenerSpec>() {
+            Ok(spec) => spec.get().0.clone(),
+            Err(_) => PyListenerSpec::new(&listener.extract::<String>()?)?.0,
+        };
+        let forward_addrs = targets
+            .iter()
+            .map(|target| parse_addr(target))
+            .collect::<PyResult<_>>()?;
+
+        let forwarder = Forwarder::new(listener_spec, forward_addrs)?;
+        Ok(Self {
+            state: forwarder.state(),
+            stage: Mutex::new(Stage::Ready(Box::new(forwarder))),
+        })
+    }
+
+    /// Start forwarding on a thread of its own
+    fn start(&self) -> PyResult<()> {
+        let mut stage = self.lock_stage();
+        match std::mem::replace(&mut *stage, Stage::Stopped) {
+            Stage::Ready(forwarder) => {
+                *stage = Stage::Running(Background::spawn(*forwarder)?);
+                Ok(())
+            }
+            started => {
+                *stage = started;
+                Err(PyRuntimeError::new_err("forwarder was started before"))
+            }
+        }
+    }
+
+    /// Stop forwarding, raising the error forwarding stopped with before, if any
+    fn stop(&self, py: Python<'_>) -> PyResult<()> {
+        let stage = std::mem::replace(&mut *self.lock_stage(), Stage::Stopped);
+        match stage {
+            Stage::Running(background) => {
+                py.detach(|| background.stop())?;
+                Ok(())
+            }
+            Stage::Ready(_) | Stage::Stopped => Ok(()),
+        }
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyResult<PyRef<'_, Self>> {
+        slf.start()?;
+        Ok(slf)
+    }
+
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: Option<&Bound<'_, PyAny>>,
+        _exc_value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        self.stop(py)?;
+        Ok(false)
+    }
+
+    /// Counters with the same keys as `/stats` of the control API, totalled over targets
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let state = &self.state;
+        let stats = PyDict::new(py);
+        stats.set_item("received_packets", state.received().packets())?;
+        stats.set_item("received_bytes", state.received().bytes())?;
+        stats.set_item("looped_packets", state.looped().packets())?;
+        stats.set_item("expired_packets", state.expired().packets())?;
+        stats.set_item("filtered_packets", state.filtered().packets())?;
+        stats.set_item("relayed_packets", state.relayed().packets())?;
+        stats.set_item("peers", state.num_peers())?;
+
+        let targets = state.targets();
+        stats.set_item(
+            "sent_packets",
+            targets.iter().map(|t| t.sent.packets()).sum::<u64>(),
+        )?;
+        stats.set_item(
+            "sent_bytes",
+            targets.iter().map(|t| t.sent.bytes()).sum::<u64>(),
+        )?;
+        stats.set_item(
+            "send_errors",
+            targets
+                .iter()
+                .map(|t| t.send_errors.load(Ordering::Relaxed))
+                .sum::<u64>(),
+        )?;
+
+        Ok(stats)
+    }
+
+    /// Local address of the listener
+    #[getter]
+    fn listener_addr(&self) -> Option<String> {
+        self.state.listener_addr().map(|addr| addr.to_string())
+    }
+
+    /// Current targets
+    #[getter]
+    fn targets(&self) -> Vec<String> {
+        self.state
+            .targets()
+            .iter()
+            .map(|target| target.addr.to_string())
+            .collect()
+    }
+
+    /// Whether received packets are dropped instead of forwarded
+    #[getter]
+    fn paused(&self) -> bool {
+        self.state.is_paused()
+    }
+
+    #[setter]
+    fn set_paused(&self, paused: bool) {
+        self.state.set_paused(paused);
+    }
+
+    /// Forward to `target` as well, returning `False` if it is a target already
+    fn add_target(&self, target: &str) -> PyResult<bool> {
+        Ok(self.state.add_target(parse_addr(target)?))
+    }
+
+    /// Stop forwarding to `target`, returning `False` if it is no target
+    fn remove_target(&self, target: &str) -> PyResult<bool> {
+        Ok(self.state.remove_target(parse_addr(target)?))
+    }
+}
+
+impl PyForwarder {
+    fn lock_stage(&self) -> std::sync::MutexGuard<'_, Stage> {
+        self.stage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for PyForwarder {
+    /// Don't leave the forwarding thread running once the object is collected
+    fn drop(&mut self) {
+        let stage = std::mem::replace(&mut *self.lock_stage(), Stage::Stopped);
+        if let Stage::Running(background) = stage {
+            let _ = background.stop();
+        }
+    }
+}
+
+fn parse_addr(addr: &str) -> PyResult<SocketAddr> {
+    addr.parse()
+        .map_err(|e| PyValueError::new_err(format!("invalid address {addr}: {e}")))
+}
+
+#[pymodule]
+fn udpforwarder(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyListenerSpec>()?;
+    module.add_class::<PyForwarder>()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn forwarder_controlled_from_python() {
+        Python::initialize();
+        Python::attach(|py| {
+            let module = PyModule::new(py, "udpforwarder").unwrap();
+            udpforwarder(&module).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("udpforwarder", module).unwrap();
+
+            py.run(
+                cr#"
+import socket
+
+spec = udpforwarder.ListenerSpec("127.0.0.1:4330")
+assert str(spec) == "127.0.0.1:4330" and spec.port == 4330
+try:
+    udpforwarder.ListenerSpec("10.1.1.10")
+    raise AssertionError("parsed invalid spec")
+except ValueError as e:
+    assert "no valid socket address" in str(e)
+
+target = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+target.bind(("127.0.0.1", 0))
+target.settimeout(1)
+with udpforwarder.Forwarder(spec, ["{}:{}".format(*target.getsockname())]) as forwarder:
+    target.sendto(b"hello", ("127.0.0.1", 4330))
+    assert target.recv(8) == b"hello"
+    assert forwarder.stats()["received_packets"] == 1
+    forwarder.paused = True
+    assert forwarder.paused
+"#,
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+}
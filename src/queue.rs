@@ -0,0 +1,270 @@
+//! Send queues of targets
+//!
+//! Sending blocks once the send buffer of a socket is full, and all targets of an IP
+//! family share a sender by default. A target behind a congested link, e.g. a VPN,
+//! thus delays the delivery to all others. With send queues, each target is sent to
+//! from a socket of its own by a worker thread, which takes the packets from a bounded
+//! queue. Once the queue of a target is full, further packets are dropped for that
+//! target only.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::Ordering,
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    socket::Socket,
+    state::{SharedState, Target},
+};
+
+/// Payload waiting to be sent to a target
+struct Message {
+    payload: Vec<u8>,
+    /// Kind of the payload if it is no forwarded packet, e.g. `keepalive`, to name it
+    /// in errors
+    kind: Option<&'static str>,
+}
+
+/// Queue of a target with the worker sending its packets
+struct Queue {
+    target: Arc<Target>,
+    sender: SyncSender<Message>,
+    worker: JoinHandle<()>,
+}
+
+impl Queue {
+    /// Start a worker sending the packets queued for `target` from `socket`
+    ///
+    /// Failures to send are counted and recorded like those of the forwarder and
+    /// reported to `errors`.
+    fn spawn(
+        socket: Box<dyn Socket>,
+        target: Arc<Target>,
+        state: Arc<SharedState>,
+        capacity: usize,
+        errors: Sender<(SocketAddr, io::Error)>,
+    ) -> Result<Self, io::Error> {
+        let (sender, receiver) = mpsc::sync_channel::<Message>(capacity);
+        let worker = {
+            let target = Arc::clone(&target);
+            thread::Builder::new()
+                .name("send-queue".to_owned())
+                .spawn(move || {
+                    for message in receiver {
+                        match socket.send_to(&message.payload, target.addr) {
+                            Ok(num_bytes) => {
+                                if message.kind.is_none() {
+                                    target.sent.add(num_bytes);
+                                }
+                            }
+                            Err(e) => {
+                                match message.kind {
+                                    None => {
+                                        target.send_errors.fetch_add(1, Ordering::Relaxed);
+                                        state.record_error(format!(
+                                            "failed to send to {}: {e}",
+                                            target.addr
+                                        ));
+                                    }
+                                    Some(kind) => state.record_error(format!(
+                                        "failed to send {kind} to {}: {e}",
+                                        target.addr
+                                    )),
+                                }
+                                let _ = errors.send((target.addr, e));
+                            }
+                        }
+                    }
+                })?
+        };
+
+        Ok(Self {
+            target,
+            sender,
+            worker,
+        })
+    }
+}
+
+/// Send queues of the targets sent to through one
+pub(crate) struct TargetQueues {
+    /// Packets each queue holds at most
+    capacity: usize,
+    queues: HashMap<SocketAddr, Queue>,
+    /// Failures of the workers to report to the hooks
+    errors: Receiver<(SocketAddr, io::Error)>,
+    report: Sender<(SocketAddr, io::Error)>,
+}
+
+impl TargetQueues {
+    /// Queues holding up to `capacity` packets each
+    pub(crate) fn new(capacity: usize) -> Self {
+        let (report, errors) = mpsc::channel();
+        Self {
+            capacity: capacity.max(1),
+            queues: HashMap::new(),
+            errors,
+            report,
+        }
+    }
+
+    /// Check if `target` is sent to through a queue
+    pub(crate) fn contains(&self, target: &Arc<Target>) -> bool {
+        self.queues
+            .get(&target.addr)
+            .is_some_and(|queue| Arc::ptr_eq(&queue.target, target))
+    }
+
+    /// Send to `target` from `socket` through a queue of its own
+    pub(crate) fn insert(
+        &mut self,
+        target: Arc<Target>,
+        socket: Box<dyn Socket>,
+        state: Arc<SharedState>,
+    ) -> Result<(), io::Error> {
+        let queue = Queue::spawn(
+            socket,
+            Arc::clone(&target),
+            state,
+            self.capacity,
+            self.report.clone(),
+        )?;
+        self.queues.insert(target.addr, queue);
+
+        Ok(())
+    }
+
+    /// Stop the queues of targets other than `targets`
+    ///
+    /// Their workers send the packets still queued before they finish.
+    pub(crate) fn retain(&mut self, targets: &[Arc<Target>]) {
+        self.queues.retain(|_, queue| {
+            targets
+                .iter()
+                .any(|target| Arc::ptr_eq(&queue.target, target))
+        });
+    }
+
+    /// Queue `payload` for `target`, `kind` naming it if it is no forwarded packet
+    ///
+    /// Packets not fitting into the full queue are counted as dropped for the target.
+    /// Returns false if the target is not sent to through a queue.
+    pub(crate) fn push(
+        &self,
+        target: &Arc<Target>,
+        payload: &[u8],
+        kind: Option<&'static str>,
+    ) -> bool {
+        let Some(queue) = self.queues.get(&target.addr) else {
+            return false;
+        };
+        if !Arc::ptr_eq(&queue.target, target) {
+            return false;
+        }
+        let message = Message {
+            payload: payload.to_vec(),
+            kind,
+        };
+        match queue.sender.try_send(message) {
+            Ok(()) => (),
+            Err(TrySendError::Full(message)) => {
+                if message.kind.is_none() {
+                    target.queue_dropped.add(message.payload.len());
+                }
+            }
+            // The worker only stops when the queue is dropped
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+
+        true
+    }
+
+    /// Failures of the workers since the last call
+    pub(crate) fn errors(&self) -> impl Iterator<Item = (SocketAddr, io::Error)> + '_ {
+        self.errors.try_iter()
+    }
+
+    /// Stop all queues and wait until the packets still queued are sent
+    pub(crate) fn clear(&mut self) {
+        for (_, queue) in self.queues.drain() {
+            drop(queue.sender);
+            let _ = queue.worker.join();
+        }
+    }
+}
+
+impl Drop for TargetQueues {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Condvar, Mutex};
+
+    use super::*;
+
+    /// Socket blocking all sends until released
+    struct Gate {
+        open: Arc<(Mutex<bool>, Condvar)>,
+    }
+
+    impl Socket for Gate {
+        fn send_to(&self, buffer: &[u8], _addr: SocketAddr) -> Result<usize, io::Error> {
+            let (open, released) = &*self.open;
+            let mut open = open.lock().unwrap();
+            while !*open {
+                open = released.wait(open).unwrap();
+            }
+            Ok(buffer.len())
+        }
+
+        fn recv_from(&self, _buffer: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+            Ok("0.0.0.0:0".parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn full_queue_drops_for_its_target() {
+        let addr: SocketAddr = "10.1.1.2:4000".parse().unwrap();
+        let state = Arc::new(SharedState::new(None, &[addr]));
+        let target = state.targets().remove(0);
+        let open = Arc::new((Mutex::new(false), Condvar::new()));
+        let mut queues = TargetQueues::new(2);
+        queues
+            .insert(
+                Arc::clone(&target),
+                Box::new(Gate {
+                    open: Arc::clone(&open),
+                }),
+                Arc::clone(&state),
+            )
+            .unwrap();
+
+        assert!(queues.contains(&target));
+        for _ in 0..10 {
+            assert!(queues.push(&target, b"hello", None));
+        }
+        // The worker holds one packet, the queue two more
+        assert!(target.queue_dropped.packets() >= 7);
+
+        *open.0.lock().unwrap() = true;
+        open.1.notify_all();
+        queues.clear();
+        assert_eq!(10, target.sent.packets() + target.queue_dropped.packets());
+        assert_eq!(0, target.send_errors.load(Ordering::Relaxed));
+        assert!(!queues.push(&target, b"hello", None));
+    }
+}
@@ -0,0 +1,258 @@
+//! Reloading the configuration
+//!
+//! A [Reloader] parses the arguments of the forwarder again, reading the config file
+//! and targets files anew, and applies the targets added or removed since the last load
+//! to the [SharedState] of the running forwarder. The listener stays bound and the
+//! targets which are kept are sent to without interruption. Targets added through the
+//! control API or discovery are left alone. Changes of the listener and the options only
+//! take effect after a restart, they are reported instead.
+//!
+//! [watch] reloads whenever the config file changed. The file is polled rather than
+//! watched with inotify, which works the same on all platforms and for files replaced
+//! in a mounted volume, as with Kubernetes ConfigMaps. The binary also reloads on
+//! `SIGHUP`, see [crate::signal::reload_on_hangup].
+
+use std::{
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use crate::{Args, ListenerSpec, ParseArgsError, parse_args, state::SharedState};
+
+/// Interval the config file is checked for changes in
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Applies changes of the arguments to a running forwarder
+///
+/// Clones share what was loaded last, so that reloads triggered in different ways,
+/// e.g. by a changed file and a signal, only apply each change once.
+#[derive(Clone)]
+pub struct Reloader {
+    /// Arguments to parse again, without the program name and subcommand
+    args: Vec<String>,
+    listener_spec: ListenerSpec,
+    loaded: Arc<Mutex<Loaded>>,
+    state: Arc<SharedState>,
+}
+
+/// Targets and options of the last load
+struct Loaded {
+    targets: Vec<SocketAddr>,
+    options: Vec<(String, Option<String>)>,
+}
+
+/// Changes applied by a reload
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reload {
+    /// Targets added to the forwarder
+    pub added: Vec<SocketAddr>,
+    /// Targets removed from the forwarder
+    pub removed: Vec<SocketAddr>,
+    /// Options which changed, or `listener`, which only take effect after a restart
+    pub needs_restart: Vec<String>,
+}
+
+impl Reloader {
+    /// Reload the forwarder of `state`, started with `args` parsed into `current`
+    ///
+    /// Fails if the targets were read from stdin, which cannot be read again.
+    pub fn new(
+        args: Vec<String>,
+        current: &Args,
+        state: Arc<SharedState>,
+    ) -> Result<Self, io::Error> {
+        if args.iter().any(|arg| arg == "--targets-stdin") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "targets read from stdin cannot be reloaded",
+            ));
+        }
+
+        Ok(Self {
+            args,
+            listener_spec: current.listener_spec.clone(),
+            loaded: Arc::new(Mutex::new(Loaded {
+                targets: current.forward_addrs.clone(),
+                options: current.applied_options.clone(),
+            })),
+            state,
+        })
+    }
+
+    /// Parse the arguments again and add and remove the targets which changed
+    ///
+    /// Invalid arguments leave the forwarder as it is.
+    pub fn reload(&self) -> Result<Reload, ParseArgsError> {
+        let args = parse_args(self.args.iter().cloned())?;
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut reload = Reload::default();
+        for addr in &args.forward_addrs {
+            if !loaded.targets.contains(addr) && !reload.added.contains(addr) {
+                self.state.add_target(*addr);
+                reload.added.push(*addr);
+            }
+        }
+        for addr in &loaded.targets {
+            if !args.forward_addrs.contains(addr) && !reload.removed.contains(addr) {
+                self.state.remove_target(*addr);
+                reload.removed.push(*addr);
+            }
+        }
+
+        if args.listener_spec != self.listener_spec {
+            reload.needs_restart.push("listener".to_owned());
+        }
+        // Options set, changed or unset show up on one side only
+        let changed = args
+            .applied_options
+            .iter()
+            .filter(|option| !loaded.options.contains(option))
+            .chain(
+                loaded
+                    .options
+                    .iter()
+                    .filter(|option| !args.applied_options.contains(option)),
+            );
+        for (name, _) in changed {
+            if !reload.needs_restart.contains(name) {
+                reload.needs_restart.push(name.clone());
+            }
+        }
+
+        loaded.targets = args.forward_addrs;
+        loaded.options = args.applied_options;
+        Ok(reload)
+    }
+}
+
+/// Reload with `reloader` whenever the file at `path` changed, checked every
+/// [WATCH_INTERVAL]
+///
+/// `on_reload` is called with the outcome of each reload.
+pub fn watch(
+    path: PathBuf,
+    reloader: Reloader,
+    mut on_reload: impl FnMut(Result<Reload, ParseArgsError>) + Send + 'static,
+) -> Result<JoinHandle<()>, io::Error> {
+    let mut version = version(&path)?;
+    thread::Builder::new()
+        .name("watch-config".to_owned())
+        .spawn(move || {
+            loop {
+                thread::sleep(WATCH_INTERVAL);
+                // The file can be missing for a moment while it is replaced
+                let Ok(current) = self::version(&path) else {
+                    continue;
+                };
+                if current != version {
+                    version = current;
+                    on_reload(reloader.reload());
+                }
+            }
+        })
+}
+
+/// Modification time and length of the file at `path`, which change with its content
+fn version(path: &Path) -> Result<(SystemTime, u64), io::Error> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn write_config(path: &Path, targets: &str) {
+        fs::write(
+            path,
+            format!("listener = \"127.0.0.1:4000\"\ntargets = [{targets}]\n"),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn targets_reloaded() {
+        let path =
+            std::env::temp_dir().join(format!("udpforwarder-reload-{}.toml", std::process::id()));
+        write_config(&path, "\"10.1.1.1:4000\", \"10.1.1.2:4000\"");
+        let command_line = vec!["--config".to_owned(), path.display().to_string()];
+        let args = parse_args(command_line.clone()).unwrap_or_else(|_| panic!("parse args"));
+        let state = Arc::new(SharedState::new(None, &args.forward_addrs));
+        // Added through the control API, which the config file does not know about
+        state.add_target("10.9.9.9:4000".parse().unwrap());
+        let reloader = Reloader::new(command_line, &args, state.clone()).unwrap();
+
+        write_config(&path, "\"10.1.1.2:4000\", \"10.1.1.3:4000\"");
+        fs::write(
+            &path,
+            fs::read_to_string(&path).unwrap() + "stats-interval = \"5s\"\n",
+        )
+        .unwrap();
+        let reload = reloader.reload().unwrap_or_else(|_| panic!("reload"));
+        assert_eq!(
+            vec!["10.1.1.3:4000".parse::<SocketAddr>().unwrap()],
+            reload.added
+        );
+        assert_eq!(
+            vec!["10.1.1.1:4000".parse::<SocketAddr>().unwrap()],
+            reload.removed
+        );
+        assert_eq!(vec!["--stats-interval".to_owned()], reload.needs_restart);
+        let targets: Vec<_> = state.targets().iter().map(|target| target.addr).collect();
+        assert_eq!(
+            vec![
+                "10.1.1.2:4000".parse::<SocketAddr>().unwrap(),
+                "10.9.9.9:4000".parse().unwrap(),
+                "10.1.1.3:4000".parse().unwrap(),
+            ],
+            targets
+        );
+
+        // Clones only apply what changed since the last reload of any of them
+        let reload = reloader
+            .clone()
+            .reload()
+            .unwrap_or_else(|_| panic!("reload"));
+        assert_eq!(Reload::default(), reload);
+
+        // Invalid files are not applied
+        fs::write(&path, "targets = [\"10.1.1.4\"]\n").unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(3, state.targets().len());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changed_config_reloaded() {
+        let path = std::env::temp_dir().join(format!(
+            "udpforwarder-watch-config-{}.toml",
+            std::process::id()
+        ));
+        write_config(&path, "\"10.1.1.1:4000\"");
+        let command_line = vec!["--config".to_owned(), path.display().to_string()];
+        let args = parse_args(command_line.clone()).unwrap_or_else(|_| panic!("parse args"));
+        let state = Arc::new(SharedState::new(None, &args.forward_addrs));
+        let reloader = Reloader::new(command_line, &args, state).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        watch(path.clone(), reloader, move |reload| {
+            let _ = sender.send(reload.map_err(|e| e.to_string()));
+        })
+        .unwrap();
+        write_config(&path, "\"10.1.1.1:4000\", \"10.1.1.2:4000\"");
+
+        let reload = receiver.recv_timeout(WATCH_INTERVAL * 3).unwrap().unwrap();
+        assert_eq!(
+            vec!["10.1.1.2:4000".parse::<SocketAddr>().unwrap()],
+            reload.added
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,323 @@
+//! Rendezvous for UDP hole punching
+//!
+//! Two forwarders behind NAT register under a common session name with a rendezvous
+//! server on a public address. The server tells each of them the address it observed
+//! for the other one and both start sending punch packets to each other at the same time.
+//! Once a punch arrives, the NAT mappings on both sides are open and packets can be
+//! relayed directly, without forwarding ports manually.
+//!
+//! Messages are `UFR` followed by one byte with the message type and its payload:
+//! `R` with the session name to register, `P` with the observed address of the peer
+//! and `H` with the session name to punch.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    str::{self, FromStr},
+    time::{Duration, Instant},
+};
+
+use crate::{ListenerBuilder, ListenerSpec, tools::is_timeout};
+
+/// Magic bytes identifying rendezvous messages
+const MAGIC: &[u8; 3] = b"UFR";
+
+/// Time after which registrations are forgotten if not configured otherwise
+pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Time to wait for the peer to register and become reachable
+pub const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval between repeated registrations and punches
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Session to join at a rendezvous server, given as `session@host:port`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rendezvous {
+    /// Name shared by the two peers to find each other
+    pub session: String,
+    /// Public address of the rendezvous server
+    pub server: SocketAddr,
+}
+
+impl FromStr for Rendezvous {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (session, server) = s.rsplit_once('@').ok_or(())?;
+        if session.is_empty() || session.contains(char::is_whitespace) {
+            return Err(());
+        }
+
+        Ok(Self {
+            session: session.to_owned(),
+            server: server.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+/// Message exchanged between peers and the rendezvous server
+#[derive(Debug, PartialEq)]
+enum Message<'a> {
+    /// Register with the server under a session name
+    Register(&'a str),
+    /// Observed address of the other peer of the session
+    Peer(SocketAddr),
+    /// Open the NAT mapping towards the other peer of the session
+    Punch(&'a str),
+}
+
+impl<'a> Message<'a> {
+    /// Parse a received packet, `None` if it is no rendezvous message
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        let (kind, payload) = packet.strip_prefix(MAGIC)?.split_first()?;
+        let payload = str::from_utf8(payload).ok()?;
+
+        match kind {
+            b'R' => Some(Self::Register(payload)),
+            b'P' => payload.parse().ok().map(Self::Peer),
+            b'H' => Some(Self::Punch(payload)),
+            _ => None,
+        }
+    }
+
+    /// Encode the message to send it
+    fn encode(&self) -> Vec<u8> {
+        let (kind, payload) = match self {
+            Self::Register(session) => (b'R', session.to_string()),
+            Self::Peer(addr) => (b'P', addr.to_string()),
+            Self::Punch(session) => (b'H', session.to_string()),
+        };
+
+        let mut packet = MAGIC.to_vec();
+        packet.push(kind);
+        packet.extend_from_slice(payload.as_bytes());
+        packet
+    }
+}
+
+/// Check if a packet is a rendezvous message, e.g. a late punch of the peer
+pub(crate) fn is_message(packet: &[u8]) -> bool {
+    Message::parse(packet).is_some()
+}
+
+/// Registrations of the rendezvous server
+#[derive(Debug)]
+struct Sessions {
+    /// Time after which registrations are forgotten
+    expiry: Duration,
+    /// Registered peers per session with the time of their last registration
+    sessions: HashMap<String, Vec<(SocketAddr, Instant)>>,
+}
+
+impl Sessions {
+    fn new(expiry: Duration) -> Self {
+        Self {
+            expiry,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Register `peer` under `session` and return the other peer of the session, if any
+    ///
+    /// A session holds two peers, a third one replaces the peer registered least recently.
+    fn register(&mut self, session: &str, peer: SocketAddr, now: Instant) -> Option<SocketAddr> {
+        for peers in self.sessions.values_mut() {
+            peers.retain(|(_, registered)| now.duration_since(*registered) <= self.expiry);
+        }
+        self.sessions.retain(|_, peers| !peers.is_empty());
+
+        let peers = self.sessions.entry(session.to_owned()).or_default();
+        match peers.iter_mut().find(|(addr, _)| *addr == peer) {
+            Some((_, registered)) => *registered = now,
+            None => {
+                if peers.len() == 2 {
+                    peers.sort_by_key(|(_, registered)| *registered);
+                    peers.remove(0);
+                }
+                peers.push((peer, now));
+            }
+        }
+
+        peers
+            .iter()
+            .map(|(addr, _)| *addr)
+            .find(|addr| *addr != peer)
+    }
+}
+
+/// Serve as rendezvous server on a public listener
+///
+/// Whenever both peers of a session are registered, each one is told the address of
+/// the other one at the same time, so that their punches cross. `report` is called with
+/// the session and both peers on each introduction.
+pub fn serve(
+    listener_spec: ListenerSpec,
+    expiry: Duration,
+    report: impl FnMut(&str, SocketAddr, SocketAddr),
+) -> Result<(), io::Error> {
+    serve_on(ListenerBuilder::new(&listener_spec).bind()?, expiry, report)
+}
+
+/// Serve as rendezvous server on a bound socket
+fn serve_on(
+    listener: UdpSocket,
+    expiry: Duration,
+    mut report: impl FnMut(&str, SocketAddr, SocketAddr),
+) -> Result<(), io::Error> {
+    let mut sessions = Sessions::new(expiry);
+    let mut buffer = [0; 1500];
+
+    loop {
+        let (num_bytes, source) = listener.recv_from(&mut buffer)?;
+        let Some(Message::Register(session)) = Message::parse(&buffer[..num_bytes]) else {
+            continue;
+        };
+
+        if let Some(peer) = sessions.register(session, source, Instant::now()) {
+            // A peer may have gone away meanwhile, which must not stop the server
+            let _ = listener.send_to(&Message::Peer(peer).encode(), source);
+            let _ = listener.send_to(&Message::Peer(source).encode(), peer);
+            report(session, source, peer);
+        }
+    }
+}
+
+/// Find the peer registered under the same session and open a direct path to it
+///
+/// Runs on the socket which relays afterwards, so that the NAT mapping observed by the
+/// server and opened by the punches is the one used by the relayed packets. Other packets
+/// received meanwhile are dropped. Returns the address the punch of the peer came from.
+pub(crate) fn punch(
+    socket: &UdpSocket,
+    rendezvous: &Rendezvous,
+    timeout: Duration,
+) -> Result<SocketAddr, io::Error> {
+    let deadline = Instant::now()
+        .checked_add(timeout)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "timeout too long"))?;
+    let read_timeout = socket.read_timeout()?;
+    socket.set_read_timeout(Some(RETRY_INTERVAL))?;
+    let result = punch_until(socket, rendezvous, deadline);
+    socket.set_read_timeout(read_timeout)?;
+
+    result
+}
+
+/// Register and punch until the punch of the peer arrives or `deadline` passes
+fn punch_until(
+    socket: &UdpSocket,
+    rendezvous: &Rendezvous,
+    deadline: Instant,
+) -> Result<SocketAddr, io::Error> {
+    let register = Message::Register(&rendezvous.session).encode();
+    let punch = Message::Punch(&rendezvous.session).encode();
+
+    let mut peer = None;
+    let mut buffer = [0; 1500];
+
+    while Instant::now() < deadline {
+        match peer {
+            None => socket.send_to(&register, rendezvous.server)?,
+            Some(peer) => socket.send_to(&punch, peer)?,
+        };
+
+        let (num_bytes, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Err(e),
+        };
+
+        match Message::parse(&buffer[..num_bytes]) {
+            Some(Message::Peer(addr)) if source == rendezvous.server => peer = Some(addr),
+            Some(Message::Punch(session)) if session == rendezvous.session => {
+                // Answer so that the peer sees the path open as well
+                socket.send_to(&punch, source)?;
+                return Ok(source);
+            }
+            _ => {}
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "peer did not become reachable in time",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let addr: SocketAddr = "203.0.113.7:40123".parse().unwrap();
+
+        for message in [
+            Message::Register("lab"),
+            Message::Peer(addr),
+            Message::Punch("lab"),
+        ] {
+            assert_eq!(Some(&message), Message::parse(&message.encode()).as_ref());
+        }
+        assert_eq!(None, Message::parse(b"data"));
+    }
+
+    #[test]
+    fn sessions_pair_two_peers() {
+        let first: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+        let third: SocketAddr = "127.0.0.1:4003".parse().unwrap();
+        let start = Instant::now();
+        let mut sessions = Sessions::new(Duration::from_secs(10));
+
+        assert_eq!(None, sessions.register("lab", first, start));
+        assert_eq!(None, sessions.register("other", second, start));
+        assert_eq!(Some(first), sessions.register("lab", second, start));
+        assert_eq!(Some(second), sessions.register("lab", third, start));
+        assert_eq!(
+            None,
+            sessions.register("lab", first, start + Duration::from_secs(11))
+        );
+    }
+
+    #[test]
+    fn peers_punch_through_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let rendezvous = Rendezvous {
+            session: "lab".to_owned(),
+            server: server.local_addr().unwrap(),
+        };
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let first_addr = first.local_addr().unwrap();
+        let second_addr = second.local_addr().unwrap();
+
+        thread::spawn(move || serve_on(server, DEFAULT_EXPIRY, |_, _, _| {}));
+
+        let other = rendezvous.clone();
+        let handle = thread::spawn(move || punch(&second, &other, Duration::from_secs(5)));
+
+        assert_eq!(
+            second_addr,
+            punch(&first, &rendezvous, Duration::from_secs(5)).unwrap()
+        );
+        assert_eq!(first_addr, handle.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn rendezvous_spec_ok() {
+        let expected = Rendezvous {
+            session: "lab".to_owned(),
+            server: "203.0.113.7:3478".parse().unwrap(),
+        };
+
+        assert_eq!(Ok(expected), "lab@203.0.113.7:3478".parse());
+        assert!("203.0.113.7:3478".parse::<Rendezvous>().is_err());
+        assert!("@203.0.113.7:3478".parse::<Rendezvous>().is_err());
+    }
+}
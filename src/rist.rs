@@ -0,0 +1,543 @@
+//! RIST sender
+//!
+//! Hands the forwarded packets, usually MPEG-TS, to receivers speaking the simple
+//! profile of RIST (Reliable Internet Stream Transport, VSF TR-06-1), which many
+//! broadcast contribution links standardize on. Each packet is sent as RTP to the even
+//! port of the receiver, which reports losses as RTCP NACKs to the port above it.
+//!
+//! The sender keeps the packets sent during the buffer time and retransmits the ones
+//! reported lost, marked by the lowest bit of the SSRC as the profile requires. A
+//! thread sends sender reports, answers NACKs and records failures in the
+//! [SharedState]. There is no handshake, packets flow as soon as the sink exists.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    hash::{BuildHasher, Hasher, RandomState},
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{sink::Sink, state::SharedState, tools::is_timeout};
+
+/// Time packets are kept for retransmission by default, as by other RIST senders
+pub const DEFAULT_BUFFER: Duration = Duration::from_secs(1);
+/// Largest payload carried in one RTP packet within the Ethernet MTU, over IPv6 too
+pub const MAX_PAYLOAD: usize = 1500 - 48 - 12;
+
+/// Interval of sender reports, which also keep NAT bindings open
+const REPORT_INTERVAL: Duration = Duration::from_millis(100);
+/// Largest RTCP packet expected from receivers
+const MAX_RTCP: usize = 1500;
+/// Time to wait after failing to receive RTCP packets
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Packets kept at most, whatever the buffer time
+const MAX_PACKETS: usize = 1 << 15;
+
+/// RTP payload type of MPEG-TS
+const PT_MP2T: u8 = 33;
+/// RTP clock rate of MPEG-TS
+const CLOCK_RATE: u128 = 90_000;
+
+/// RTCP packet types
+const SR: u8 = 200;
+const SDES: u8 = 202;
+const BYE: u8 = 203;
+const APP: u8 = 204;
+const RTPFB: u8 = 205;
+/// Feedback format of generic NACKs (RFC 4585)
+const GENERIC_NACK: u8 = 1;
+/// Subtype of range NACKs, sent as application packets named `RIST`
+const RANGE_NACK: u8 = 0;
+/// SDES item of the canonical name
+const CNAME: u8 = 1;
+
+/// Seconds from the NTP epoch in 1900 to the Unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// RIST receiver, given as `rist://host:port[?buffer=1000&cname=...]`
+///
+/// The port is the even RTP port, RTCP goes to the port above it. The buffer is the
+/// time in milliseconds packets are kept for retransmission, like in the URLs of
+/// other RIST tools.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RistTarget {
+    /// Host of the receiver
+    pub host: String,
+    /// Even port the receiver expects RTP packets on
+    pub port: u16,
+    /// Time packets are kept for retransmission
+    pub buffer: Duration,
+    /// Canonical name announced in the sender reports
+    pub cname: Option<String>,
+}
+
+impl RistTarget {
+    /// Resolve the RTP address of the receiver
+    fn addr(&self) -> Result<SocketAddr, io::Error> {
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        (host, self.port).to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address for RIST host {}", self.host),
+            )
+        })
+    }
+}
+
+impl FromStr for RistTarget {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s.strip_prefix("rist://").ok_or(())?;
+        let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (host, port) = authority.rsplit_once(':').ok_or(())?;
+        let mut target = Self {
+            host: host.to_owned(),
+            port: port.parse().map_err(|_| ())?,
+            buffer: DEFAULT_BUFFER,
+            cname: None,
+        };
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            match param.split_once('=').ok_or(())? {
+                ("buffer", millis) => {
+                    let millis: u16 = millis.parse().map_err(|_| ())?;
+                    target.buffer = Duration::from_millis(millis.into());
+                }
+                // SDES items are at most 255 bytes long
+                ("cname", cname) if !cname.is_empty() && cname.len() <= 255 => {
+                    target.cname = Some(cname.to_owned());
+                }
+                _ => return Err(()),
+            }
+        }
+        // RTCP is sent to the port above, so RTP ports are even
+        if target.host.is_empty() || !target.port.is_multiple_of(2) {
+            return Err(());
+        }
+
+        Ok(target)
+    }
+}
+
+/// Sends packets to a RIST receiver
+pub struct RistSink {
+    rtp: UdpSocket,
+    rtcp: UdpSocket,
+    addr: SocketAddr,
+    stream: Arc<Mutex<Stream>>,
+}
+
+impl RistSink {
+    /// Start sending to the receiver of `target`
+    ///
+    /// Spawns a thread sending sender reports and answering NACKs, which records
+    /// failures in `state`.
+    pub fn connect(target: &RistTarget, state: Arc<SharedState>) -> Result<Self, io::Error> {
+        let addr = target.addr()?;
+        let unspecified = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let rtp = UdpSocket::bind(unspecified)?;
+        let rtcp = UdpSocket::bind(unspecified)?;
+        rtcp.set_read_timeout(Some(REPORT_INTERVAL))?;
+
+        let cname = match &target.cname {
+            Some(cname) => cname.clone(),
+            None => format!("udpforwarder@{}", rtcp.local_addr()?),
+        };
+        let stream = Arc::new(Mutex::new(Stream::new(cname, target.buffer)));
+
+        {
+            let rtp = rtp.try_clone()?;
+            let rtcp = rtcp.try_clone()?;
+            let stream = Arc::clone(&stream);
+            let rtcp_addr = SocketAddr::new(addr.ip(), addr.port() + 1);
+            thread::Builder::new()
+                .name("rist".to_owned())
+                .spawn(move || serve(&rtp, &rtcp, addr, rtcp_addr, &stream, &state))?;
+        }
+
+        Ok(Self {
+            rtp,
+            rtcp,
+            addr,
+            stream,
+        })
+    }
+
+    /// Address RTP packets are sent from
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.rtp.local_addr()
+    }
+}
+
+impl Sink for RistSink {
+    fn send(&mut self, _source: SocketAddr, payload: &[u8]) -> Result<(), io::Error> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "payload of {} bytes exceeds the RIST maximum of {MAX_PAYLOAD}",
+                    payload.len()
+                ),
+            ));
+        }
+
+        let mut stream = lock(&self.stream);
+        let packet = stream.rtp_packet(payload);
+        self.rtp.send_to(packet, self.addr).map(|_| ())
+    }
+}
+
+impl fmt::Display for RistSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RIST receiver {}", self.addr)
+    }
+}
+
+/// Tell the receiver that the stream ended
+impl Drop for RistSink {
+    fn drop(&mut self) {
+        let stream = lock(&self.stream);
+        let packet = rtcp_packet(0x81, BYE, &stream.ssrc.to_be_bytes());
+        let rtcp_addr = SocketAddr::new(self.addr.ip(), self.addr.port() + 1);
+        let _ = self.rtcp.send_to(&packet, rtcp_addr);
+    }
+}
+
+/// State of the RTP stream, shared by the sink and the thread answering RTCP packets
+struct Stream {
+    /// Source of the original packets, even since retransmissions set the lowest bit
+    ssrc: u32,
+    cname: String,
+    /// Time the RTP timestamps count from
+    start: Instant,
+    /// RTP timestamp at `start`
+    initial_timestamp: u32,
+    /// Sequence number of the next packet
+    next_seq: u16,
+    /// Packets and payload bytes sent, for the sender reports
+    packets: u32,
+    octets: u32,
+    buffer: Duration,
+    /// Packets sent, with the time they were sent, kept for retransmission
+    sent: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl Stream {
+    fn new(cname: String, buffer: Duration) -> Self {
+        Self {
+            ssrc: random_u32() & !1,
+            cname,
+            start: Instant::now(),
+            initial_timestamp: random_u32(),
+            next_seq: random_u32() as u16,
+            packets: 0,
+            octets: 0,
+            buffer,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// RTP timestamp of packets sent now, in the 90 kHz clock of MPEG-TS
+    fn timestamp(&self) -> u32 {
+        let ticks = self.start.elapsed().as_micros() * CLOCK_RATE / 1_000_000;
+        self.initial_timestamp.wrapping_add(ticks as u32)
+    }
+
+    /// Build the next RTP packet carrying `payload`
+    fn rtp_packet(&mut self, payload: &[u8]) -> &[u8] {
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+        self.packets = self.packets.wrapping_add(1);
+        self.octets = self.octets.wrapping_add(payload.len() as u32);
+
+        let mut packet = Vec::with_capacity(12 + payload.len());
+        packet.extend_from_slice(&[0x80, PT_MP2T]);
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp().to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        packet.extend_from_slice(payload);
+
+        let now = Instant::now();
+        while self.sent.len() >= MAX_PACKETS
+            || self
+                .sent
+                .front()
+                .is_some_and(|(sent_at, _)| now.duration_since(*sent_at) > self.buffer)
+        {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((now, packet));
+
+        self.sent
+            .back()
+            .map(|(_, packet)| packet.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Packet with sequence number `seq` marked as retransmitted, if still kept
+    fn retransmission(&self, seq: u16) -> Option<Vec<u8>> {
+        let (_, first) = self.sent.front()?;
+        let first_seq = u16::from_be_bytes([first[2], first[3]]);
+        let (_, packet) = self.sent.get(usize::from(seq.wrapping_sub(first_seq)))?;
+        let mut packet = packet.clone();
+        packet[11] |= 1;
+        Some(packet)
+    }
+
+    /// Packets to retransmit for the NACKs in an RTCP compound packet
+    fn lost(&self, compound: &[u8]) -> Vec<Vec<u8>> {
+        let mut lost = Vec::new();
+        let mut rest = compound;
+        while let Some(header) = rest.first_chunk::<4>() {
+            let len = (usize::from(u16::from_be_bytes([header[2], header[3]])) + 1) * 4;
+            if header[0] >> 6 != 2 || rest.len() < len {
+                break;
+            }
+            let (packet, next) = rest.split_at(len);
+            rest = next;
+
+            let subtype = header[0] & 0x1f;
+            let entries = match header[1] {
+                RTPFB if subtype == GENERIC_NACK => packet.get(12..),
+                APP if subtype == RANGE_NACK && packet.get(8..12) == Some(b"RIST") => {
+                    packet.get(12..)
+                }
+                _ => None,
+            };
+            for entry in entries.unwrap_or_default().as_chunks::<4>().0 {
+                let first = u16::from_be_bytes([entry[0], entry[1]]);
+                let second = u16::from_be_bytes([entry[2], entry[3]]);
+                let seqs: Vec<u16> = match header[1] {
+                    // Packet ID followed by a bitmask of the 16 packets after it
+                    RTPFB => std::iter::once(first)
+                        .chain(
+                            (0..16)
+                                .filter(|bit| second & (1 << bit) != 0)
+                                .map(|bit| first.wrapping_add(bit + 1)),
+                        )
+                        .collect(),
+                    // First packet followed by the number of packets after it
+                    _ => (0..=second).map(|idx| first.wrapping_add(idx)).collect(),
+                };
+                lost.extend(seqs.into_iter().filter_map(|seq| self.retransmission(seq)));
+            }
+        }
+        lost
+    }
+
+    /// Sender report followed by the canonical name, as a compound RTCP packet
+    fn report(&self) -> Vec<u8> {
+        let since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        let fraction = (u64::from(since_epoch.subsec_nanos()) << 32) / 1_000_000_000;
+
+        let mut sender_info = Vec::with_capacity(24);
+        sender_info.extend_from_slice(&self.ssrc.to_be_bytes());
+        sender_info
+            .extend_from_slice(&(since_epoch.as_secs() + NTP_UNIX_OFFSET).to_be_bytes()[4..]);
+        sender_info.extend_from_slice(&(fraction as u32).to_be_bytes());
+        sender_info.extend_from_slice(&self.timestamp().to_be_bytes());
+        sender_info.extend_from_slice(&self.packets.to_be_bytes());
+        sender_info.extend_from_slice(&self.octets.to_be_bytes());
+        let mut compound = rtcp_packet(0x80, SR, &sender_info);
+
+        // One chunk with the name, ended by a null item and padded to 32-bit words
+        let mut chunk = self.ssrc.to_be_bytes().to_vec();
+        chunk.extend_from_slice(&[CNAME, self.cname.len() as u8]);
+        chunk.extend_from_slice(self.cname.as_bytes());
+        chunk.push(0);
+        chunk.resize(chunk.len().next_multiple_of(4), 0);
+        compound.extend(rtcp_packet(0x81, SDES, &chunk));
+        compound
+    }
+}
+
+/// Send sender reports and retransmit the packets the receiver reports lost
+///
+/// Runs until the sink is dropped.
+fn serve(
+    rtp: &UdpSocket,
+    rtcp: &UdpSocket,
+    addr: SocketAddr,
+    rtcp_addr: SocketAddr,
+    stream: &Arc<Mutex<Stream>>,
+    state: &SharedState,
+) {
+    let mut buffer = [0; MAX_RTCP];
+    let mut last_report: Option<Instant> = None;
+    while Arc::strong_count(stream) > 1 {
+        if last_report.is_none_or(|reported| reported.elapsed() >= REPORT_INTERVAL) {
+            last_report = Some(Instant::now());
+            let report = lock(stream).report();
+            // Unreachable receivers are reported by the RTP socket already
+            if let Err(e) = rtcp.send_to(&report, rtcp_addr)
+                && e.kind() != io::ErrorKind::ConnectionRefused
+            {
+                state.record_error(format!("failed to send RTCP to RIST receiver {addr}: {e}"));
+            }
+        }
+
+        match rtcp.recv_from(&mut buffer) {
+            Ok((len, from)) if from.ip() == addr.ip() => {
+                for packet in lock(stream).lost(&buffer[..len]) {
+                    if let Err(e) = rtp.send_to(&packet, addr) {
+                        state.record_error(format!(
+                            "failed to retransmit to RIST receiver {addr}: {e}"
+                        ));
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::ConnectionRefused => {}
+            Err(e) => {
+                state.record_error(format!("failed to receive RTCP from {addr}: {e}"));
+                thread::sleep(RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Build an RTCP packet of type `kind`, with `first_byte` holding version and count
+fn rtcp_packet(first_byte: u8, kind: u8, body: &[u8]) -> Vec<u8> {
+    let words = (body.len() / 4) as u16;
+    let mut packet = Vec::with_capacity(4 + body.len());
+    packet.extend_from_slice(&[first_byte, kind]);
+    packet.extend_from_slice(&words.to_be_bytes());
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Random number for the SSRC and the initial sequence number and timestamp
+fn random_u32() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+fn lock(stream: &Mutex<Stream>) -> MutexGuard<'_, Stream> {
+    stream
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rist_target_ok() {
+        let target: RistTarget = "rist://rist.example.com:5000?buffer=2000&cname=relay"
+            .parse()
+            .unwrap();
+        assert_eq!("rist.example.com", target.host);
+        assert_eq!(5000, target.port);
+        assert_eq!(Duration::from_secs(2), target.buffer);
+        assert_eq!(Some("relay"), target.cname.as_deref());
+
+        let target: RistTarget = "rist://[::1]:5000".parse().unwrap();
+        assert_eq!(DEFAULT_BUFFER, target.buffer);
+        assert_eq!(None, target.cname);
+
+        // RTP ports are even, receivers need a host
+        assert!(
+            "rist://rist.example.com:5001"
+                .parse::<RistTarget>()
+                .is_err()
+        );
+        assert!("rist://:5000".parse::<RistTarget>().is_err());
+        assert!(
+            "rist://rist.example.com:5000?bandwidth=1"
+                .parse::<RistTarget>()
+                .is_err()
+        );
+    }
+
+    /// Bind the RTP and RTCP sockets of a receiver to an even port and the one above
+    fn receiver_sockets() -> (UdpSocket, UdpSocket) {
+        loop {
+            let rtp = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let port = rtp.local_addr().unwrap().port();
+            if !port.is_multiple_of(2) {
+                continue;
+            }
+            if let Ok(rtcp) = UdpSocket::bind(("127.0.0.1", port + 1)) {
+                for socket in [&rtp, &rtcp] {
+                    socket
+                        .set_read_timeout(Some(Duration::from_secs(5)))
+                        .unwrap();
+                }
+                return (rtp, rtcp);
+            }
+        }
+    }
+
+    #[test]
+    fn sends_rtp_and_retransmits() {
+        let (rtp, rtcp) = receiver_sockets();
+        let target: RistTarget = format!("rist://{}", rtp.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let mut sink = RistSink::connect(&target, Arc::new(SharedState::new(None, &[]))).unwrap();
+        let source = "10.1.1.10:4000".parse().unwrap();
+        for payload in [b"first", b"other"] {
+            sink.send(source, payload).unwrap();
+        }
+
+        let mut buffer = [0; MAX_RTCP];
+        let len = rtp.recv(&mut buffer).unwrap();
+        assert_eq!([0x80, PT_MP2T], buffer[..2]);
+        assert_eq!(b"first", &buffer[12..len]);
+        let seq = u16::from_be_bytes([buffer[2], buffer[3]]);
+        let ssrc = u32::from_be_bytes(buffer[8..12].try_into().unwrap());
+        assert_eq!(0, ssrc & 1);
+
+        // Sender reports come with the canonical name
+        let (len, sender_rtcp) = rtcp.recv_from(&mut buffer).unwrap();
+        assert_eq!(SR, buffer[1]);
+        assert_eq!(ssrc.to_be_bytes(), buffer[4..8]);
+        assert_eq!(SDES, buffer[29]);
+        assert!(buffer[..len].windows(5).any(|window| window == b"udpfo"));
+
+        // Generic NACK for the first packet, range NACK for both
+        let mut nack = 0u32.to_be_bytes().to_vec();
+        nack.extend_from_slice(&ssrc.to_be_bytes());
+        nack.extend_from_slice(&seq.to_be_bytes());
+        nack.extend_from_slice(&[0, 0]);
+        let mut compound = rtcp_packet(0x80 | GENERIC_NACK, RTPFB, &nack);
+        let mut range = 0u32.to_be_bytes().to_vec();
+        range.extend_from_slice(b"RIST");
+        range.extend_from_slice(&seq.to_be_bytes());
+        range.extend_from_slice(&1u16.to_be_bytes());
+        compound.extend(rtcp_packet(0x80 | RANGE_NACK, APP, &range));
+        rtcp.send_to(&compound, sender_rtcp).unwrap();
+
+        let mut retransmitted = Vec::new();
+        while retransmitted.len() < 3 {
+            let len = rtp.recv(&mut buffer).unwrap();
+            let packet = &buffer[..len];
+            if packet[8..12] == (ssrc | 1).to_be_bytes() {
+                retransmitted.push((
+                    u16::from_be_bytes([packet[2], packet[3]]),
+                    packet[12..].to_vec(),
+                ));
+            }
+        }
+        assert_eq!(
+            vec![
+                (seq, b"first".to_vec()),
+                (seq, b"first".to_vec()),
+                (seq.wrapping_add(1), b"other".to_vec())
+            ],
+            retransmitted
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! Sandboxing of the forwarding loop
+//!
+//! Once all sockets are set up, the steady-state forwarding loop only needs
+//! a handful of syscalls and almost no filesystem access. A seccomp filter
+//! and Landlock rules permitting only those reduce what an attacker
+//! could do through a compromised process.
+//!
+//! The seccomp filter applies to all threads of the process, so the threads serving the
+//! control API, the metrics and the admin socket are restricted like the forwarding loop.
+//! Threads needing further syscalls, e.g. to read files or to start threads, must not run
+//! once it is installed.
+//!
+//! Only available on Linux, seccomp only for x86_64 and aarch64.
+
+use std::{
+    io,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Set once the seccomp filter is installed
+static SECCOMP_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        ffi::{c_int, c_long, c_ulong},
+        io,
+    };
+
+    unsafe extern "C" {
+        pub fn prctl(option: c_int, ...) -> c_int;
+        pub fn syscall(number: c_long, ...) -> c_long;
+    }
+
+    /// Prevent gaining privileges, required for unprivileged sandboxing
+    pub fn set_no_new_privs() -> Result<(), io::Error> {
+        const PR_SET_NO_NEW_PRIVS: c_int = 38;
+
+        // SAFETY: Setting a process flag without memory arguments
+        let ret = unsafe {
+            prctl(
+                PR_SET_NO_NEW_PRIVS,
+                1 as c_ulong,
+                0 as c_ulong,
+                0 as c_ulong,
+                0 as c_ulong,
+            )
+        };
+        match ret {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Install a seccomp filter permitting only the syscalls of the forwarding loop
+///
+/// Opening files fails with a permission error, as the C library falls back gracefully
+/// when threads started right before the filter look up the number of CPUs. Any other
+/// syscall terminates the process. The filter is synchronized to all threads of the
+/// process, fails if that is not possible and cannot be undone.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub fn install_seccomp_filter() -> Result<(), io::Error> {
+    use std::ffi::c_ulong;
+
+    /// Instruction of a classic BPF program
+    #[repr(C)]
+    struct SockFilter {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    /// Classic BPF program
+    #[repr(C)]
+    struct SockFprog {
+        len: u16,
+        filter: *const SockFilter,
+    }
+
+    const SECCOMP_SET_MODE_FILTER: c_ulong = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: c_ulong = 1;
+
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JEQ_K: u16 = 0x15;
+    const BPF_RET_K: u16 = 0x06;
+
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const EACCES: u32 = 13;
+
+    // Offsets into `struct seccomp_data`
+    const OFFSET_NR: u32 = 0;
+    const OFFSET_ARCH: u32 = 4;
+    /// Lower half of the first argument on little-endian architectures
+    const OFFSET_ARG0: u32 = 16;
+
+    const PR_SET_NAME: u32 = 15;
+
+    let instruction = |code, jt, jf, k| SockFilter { code, jt, jf, k };
+
+    let mut program = vec![
+        // Kill if the architecture does not match the syscall numbers
+        instruction(BPF_LD_W_ABS, 0, 0, OFFSET_ARCH),
+        instruction(BPF_JEQ_K, 1, 0, arch::AUDIT_ARCH),
+        instruction(BPF_RET_K, 0, 0, SECCOMP_RET_KILL_PROCESS),
+        instruction(BPF_LD_W_ABS, 0, 0, OFFSET_NR),
+        // Threads started right before the filter name themselves through prctl
+        instruction(BPF_JEQ_K, 0, 4, arch::SYS_PRCTL),
+        instruction(BPF_LD_W_ABS, 0, 0, OFFSET_ARG0),
+        instruction(BPF_JEQ_K, 0, 1, PR_SET_NAME),
+        instruction(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW),
+        instruction(BPF_RET_K, 0, 0, SECCOMP_RET_KILL_PROCESS),
+        // Refuse opening files without terminating
+        instruction(BPF_JEQ_K, 0, 1, arch::SYS_OPENAT),
+        instruction(BPF_RET_K, 0, 0, SECCOMP_RET_ERRNO | EACCES),
+    ];
+    // Jump to the final allow for each permitted syscall
+    let num_allowed = arch::ALLOWED_SYSCALLS.len();
+    for (idx, nr) in arch::ALLOWED_SYSCALLS.iter().enumerate() {
+        program.push(instruction(BPF_JEQ_K, (num_allowed - idx) as u8, 0, *nr));
+    }
+    program.push(instruction(BPF_RET_K, 0, 0, SECCOMP_RET_KILL_PROCESS));
+    program.push(instruction(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW));
+
+    let fprog = SockFprog {
+        len: program.len() as u16,
+        filter: program.as_ptr(),
+    };
+
+    sys::set_no_new_privs()?;
+    // SAFETY: `fprog` points to a valid program which the kernel copies
+    let ret = unsafe {
+        sys::syscall(
+            arch::SYS_SECCOMP,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &fprog as *const SockFprog,
+        )
+    };
+    match ret {
+        -1 => return Err(io::Error::last_os_error()),
+        // ID of a thread which could not be synchronized
+        0 => {}
+        tid => {
+            return Err(io::Error::other(format!(
+                "failed to apply the filter to thread {tid}"
+            )));
+        }
+    }
+    SECCOMP_INSTALLED.store(true, Ordering::Release);
+
+    Ok(())
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub fn install_seccomp_filter() -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "seccomp is only available on Linux for x86_64 and aarch64",
+    ))
+}
+
+/// Check if the seccomp filter is installed, forbidding e.g. to read files
+pub fn is_seccomp_installed() -> bool {
+    SECCOMP_INSTALLED.load(Ordering::Acquire)
+}
+
+/// Restrict filesystem access to the given paths with Landlock
+///
+/// The `allowed_paths` may be read and written but not created or removed. The
+/// `replaced_paths` may also be replaced by renaming a file written next to them,
+/// which permits creating and removing regular files in their directories.
+/// Files opened before remain usable. Fails with [io::ErrorKind::Unsupported]
+/// if the kernel does not support Landlock.
+#[cfg(target_os = "linux")]
+pub fn restrict_filesystem(
+    allowed_paths: &[&Path],
+    replaced_paths: &[&Path],
+) -> Result<(), io::Error> {
+    use std::{ffi::c_long, fs::File, os::fd::AsRawFd};
+
+    /// Landlock ABI v1 `struct landlock_ruleset_attr`
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    /// `struct landlock_path_beneath_attr`
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    // Same syscall numbers on all architectures
+    const SYS_LANDLOCK_CREATE_RULESET: c_long = 444;
+    const SYS_LANDLOCK_ADD_RULE: c_long = 445;
+    const SYS_LANDLOCK_RESTRICT_SELF: c_long = 446;
+    const LANDLOCK_RULE_PATH_BENEATH: c_long = 1;
+
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    /// All access rights of ABI v1, from executing to creating symlinks
+    const ACCESS_FS_ALL_V1: u64 = (1 << 13) - 1;
+
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_FS_ALL_V1,
+    };
+    // SAFETY: `attr` is a valid ruleset attribute of the given size
+    let ruleset_fd = unsafe {
+        sys::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            size_of::<RulesetAttr>(),
+            0u32,
+        )
+    };
+    if ruleset_fd < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            // ENOSYS or EOPNOTSUPP
+            Some(38 | 95) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Landlock is not supported by the kernel",
+            )),
+            _ => Err(e),
+        };
+    }
+    // SAFETY: The kernel returned a new file descriptor owned by us
+    let ruleset: File = unsafe { std::os::fd::FromRawFd::from_raw_fd(ruleset_fd as i32) };
+
+    let file_access = ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE;
+    let dir_access = file_access | ACCESS_FS_REMOVE_FILE | ACCESS_FS_MAKE_REG;
+    let rules =
+        allowed_paths
+            .iter()
+            .map(|path| (*path, file_access))
+            .chain(replaced_paths.iter().map(|path| {
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                (dir, dir_access)
+            }));
+    for (path, access) in rules {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            // Nothing to permit for paths which don't exist
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let rule = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: file.as_raw_fd(),
+        };
+        // SAFETY: `rule` is a valid path beneath attribute with an open file descriptor
+        let ret = unsafe {
+            sys::syscall(
+                SYS_LANDLOCK_ADD_RULE,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule as *const PathBeneathAttr,
+                0u32,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    sys::set_no_new_privs()?;
+    // SAFETY: Restricting with a valid ruleset file descriptor
+    if unsafe { sys::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0u32) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict_filesystem(
+    _allowed_paths: &[&Path],
+    _replaced_paths: &[&Path],
+) -> Result<(), io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Landlock is only available on Linux",
+    ))
+}
+
+/// Syscalls of the forwarding loop on x86_64
+///
+/// Besides receiving and sending, this covers memory allocation, setting up threads
+/// started right before the filter, writing error messages, time keeping, exiting,
+/// restarting sleeps interrupted by signals, binding senders and probing
+/// their source addresses for targets added at runtime and serving connections
+/// of the control API, the metrics and the admin socket.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod arch {
+    pub const AUDIT_ARCH: u32 = 0xc000_003e;
+    pub const SYS_SECCOMP: std::ffi::c_long = 317;
+    pub const SYS_PRCTL: u32 = 157;
+    pub const SYS_OPENAT: u32 = 257;
+
+    pub const ALLOWED_SYSCALLS: &[u32] = &[
+        0,   // read
+        1,   // write
+        3,   // close
+        7,   // poll
+        9,   // mmap
+        10,  // mprotect
+        11,  // munmap
+        12,  // brk
+        13,  // rt_sigaction
+        14,  // rt_sigprocmask
+        15,  // rt_sigreturn
+        20,  // writev
+        24,  // sched_yield
+        25,  // mremap
+        28,  // madvise
+        35,  // nanosleep
+        41,  // socket
+        42,  // connect
+        43,  // accept
+        44,  // sendto
+        45,  // recvfrom
+        46,  // sendmsg
+        47,  // recvmsg
+        48,  // shutdown
+        49,  // bind
+        51,  // getsockname
+        54,  // setsockopt
+        55,  // getsockopt
+        60,  // exit
+        72,  // fcntl
+        131, // sigaltstack
+        186, // gettid
+        202, // futex
+        204, // sched_getaffinity
+        219, // restart_syscall
+        228, // clock_gettime
+        230, // clock_nanosleep
+        231, // exit_group
+        232, // epoll_wait
+        234, // tgkill
+        271, // ppoll
+        273, // set_robust_list
+        288, // accept4
+        299, // recvmmsg
+        307, // sendmmsg
+        318, // getrandom
+        334, // rseq
+    ];
+}
+
+/// Syscalls of the forwarding loop on aarch64
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+mod arch {
+    pub const AUDIT_ARCH: u32 = 0xc000_00b7;
+    pub const SYS_SECCOMP: std::ffi::c_long = 277;
+    pub const SYS_PRCTL: u32 = 167;
+    pub const SYS_OPENAT: u32 = 56;
+
+    pub const ALLOWED_SYSCALLS: &[u32] = &[
+        22,  // epoll_pwait
+        25,  // fcntl
+        57,  // close
+        63,  // read
+        64,  // write
+        66,  // writev
+        73,  // ppoll
+        93,  // exit
+        94,  // exit_group
+        98,  // futex
+        99,  // set_robust_list
+        101, // nanosleep
+        113, // clock_gettime
+        115, // clock_nanosleep
+        123, // sched_getaffinity
+        124, // sched_yield
+        128, // restart_syscall
+        131, // tgkill
+        132, // sigaltstack
+        134, // rt_sigaction
+        135, // rt_sigprocmask
+        139, // rt_sigreturn
+        178, // gettid
+        198, // socket
+        200, // bind
+        202, // accept
+        203, // connect
+        204, // getsockname
+        206, // sendto
+        207, // recvfrom
+        208, // setsockopt
+        209, // getsockopt
+        210, // shutdown
+        211, // sendmsg
+        212, // recvmsg
+        214, // brk
+        215, // munmap
+        216, // mremap
+        222, // mmap
+        226, // mprotect
+        233, // madvise
+        242, // accept4
+        243, // recvmmsg
+        269, // sendmmsg
+        278, // getrandom
+        293, // rseq
+    ];
+}
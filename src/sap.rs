@@ -0,0 +1,525 @@
+//! Relaying of the sessions announced through SAP
+//!
+//! Broadcast facilities announce their multicast streams with the Session Announcement
+//! Protocol (RFC 2974), which periodically sends an SDP description (RFC 8866) of each
+//! session to a well-known group. Instead of configuring a forwarder per stream, the
+//! relay listens to the announcements and forwards every session whose name or address
+//! matches a filter to a target address, keeping the port of the session. Sessions are
+//! stopped when their announcement is deleted or times out.
+//!
+//! Optionally, the relayed sessions are announced again on the output side, with the
+//! connection address of the description replaced by the target, so that receivers
+//! there find them the same way.
+//!
+//! Only the first media stream of a session is relayed. Announcements are received on
+//! the IPv4 SAP group, encrypted and compressed ones are ignored.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    str,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ForwardError, ForwardStats, Forwarder, ListenerBuilder, ListenerSpec,
+    sdp::{DEFAULT_TTL, Session},
+    tools::is_timeout,
+};
+
+/// Group and port SAP announcements are sent to, for global scope IPv4 sessions
+pub const SAP_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 2, 127, 254), 9875);
+/// Group SAP announcements of global scope IPv6 sessions are sent to
+const SAP_ADDR_V6: Ipv6Addr = Ipv6Addr::new(0xff0e, 0, 0, 0, 0, 0, 2, 0x7ffe);
+/// Shortest time a session is kept without being announced again (RFC 2974, 3.1)
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(3600);
+/// Announcement intervals a session is kept for, if longer than [SESSION_TIMEOUT]
+const TIMEOUT_INTERVALS: u32 = 10;
+
+/// Interval to check for stopped forwarders and timed out sessions in
+const TICK: Duration = Duration::from_secs(1);
+/// Payload type of session descriptions
+const SDP_TYPE: &[u8] = b"application/sdp";
+/// Version 1 in the first byte of SAP headers
+const SAP_V1: u8 = 0x20;
+/// Flags in the first byte of SAP headers
+const IPV6_ORIGIN: u8 = 0x10;
+const DELETION: u8 = 0x04;
+const ENCRYPTED: u8 = 0x02;
+const COMPRESSED: u8 = 0x01;
+
+/// Settings of the SAP relay
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SapRelay {
+    /// Patterns matched against the name and the `group:port` of announced sessions,
+    /// with `*` matching any text and `?` any character, empty to relay all sessions
+    pub filters: Vec<String>,
+    /// Address to relay the sessions to, keeping their ports
+    pub target: IpAddr,
+    /// Local address of the interface to receive announcements and sessions on
+    pub interface: Ipv4Addr,
+    /// Whether to announce the relayed sessions again on the output side
+    pub announce: bool,
+}
+
+impl SapRelay {
+    /// Relay all announced sessions to `target`
+    pub fn new(target: IpAddr) -> Self {
+        Self {
+            filters: Vec::new(),
+            target,
+            interface: Ipv4Addr::UNSPECIFIED,
+            announce: false,
+        }
+    }
+
+    /// Whether an announced session is to be relayed
+    fn matches(&self, session: &Session) -> bool {
+        let addr = session.addr.to_string();
+        self.filters.is_empty()
+            || self
+                .filters
+                .iter()
+                .any(|filter| glob_match(filter, &session.name) || glob_match(filter, &addr))
+    }
+}
+
+/// SAP announcement or deletion
+#[derive(Debug, PartialEq, Eq)]
+struct Announcement<'a> {
+    deletion: bool,
+    sdp: &'a str,
+}
+
+impl<'a> Announcement<'a> {
+    /// Parse a SAP packet, `None` if it is malformed, encrypted, compressed or carries
+    /// no session description
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        let (&[flags, auth_len], rest) = packet.split_first_chunk::<2>()?;
+        if flags & 0xe0 != SAP_V1 || flags & (ENCRYPTED | COMPRESSED) != 0 {
+            return None;
+        }
+        let origin_len = match flags & IPV6_ORIGIN {
+            0 => 4,
+            _ => 16,
+        };
+        // Message ID hash, originating source and authentication data
+        let payload = rest.get(2 + origin_len + usize::from(auth_len) * 4..)?;
+        let sdp = match payload.starts_with(b"v=0") {
+            true => payload,
+            false => {
+                let (payload_type, sdp) = payload.split_at(payload.iter().position(|b| *b == 0)?);
+                if payload_type != SDP_TYPE {
+                    return None;
+                }
+                &sdp[1..]
+            }
+        };
+
+        Some(Self {
+            deletion: flags & DELETION != 0,
+            sdp: str::from_utf8(sdp).ok()?,
+        })
+    }
+
+    /// Encode as SAP packet from `origin`, with the payload type
+    fn encode(&self, origin: IpAddr) -> Vec<u8> {
+        let mut flags = SAP_V1;
+        if origin.is_ipv6() {
+            flags |= IPV6_ORIGIN;
+        }
+        if self.deletion {
+            flags |= DELETION;
+        }
+        // The hash changes with the description, which is all it needs to do
+        let mut hasher = DefaultHasher::new();
+        self.sdp.hash(&mut hasher);
+
+        let mut packet = vec![flags, 0];
+        packet.extend_from_slice(&(hasher.finish() as u16).to_be_bytes());
+        match origin {
+            IpAddr::V4(ip) => packet.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => packet.extend_from_slice(&ip.octets()),
+        }
+        packet.extend_from_slice(SDP_TYPE);
+        packet.push(0);
+        packet.extend_from_slice(self.sdp.as_bytes());
+        packet
+    }
+}
+
+/// Change of a relayed session, reported by [relay]
+#[derive(Debug)]
+pub enum SapEvent {
+    /// Started relaying the session to the address
+    Relaying(Session, SocketAddr),
+    /// Stopped relaying the session since its announcement was deleted
+    Deleted(Session),
+    /// Stopped relaying the session since it was not announced again in time
+    Expired(Session),
+    /// Failed to relay the session, it is tried again with its next announcement
+    Failed(Session, io::Error),
+}
+
+impl fmt::Display for SapEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Relaying(session, target) => write!(f, "Relaying {session} to {target}"),
+            Self::Deleted(session) => write!(f, "Stopped relaying deleted {session}"),
+            Self::Expired(session) => write!(f, "Stopped relaying expired {session}"),
+            Self::Failed(session, e) => write!(f, "Failed to relay {session}: {e}"),
+        }
+    }
+}
+
+/// Thread forwarding a relayed session
+type RelayThread = JoinHandle<Result<ForwardStats, ForwardError>>;
+
+/// Session being relayed
+struct Relayed {
+    session: Session,
+    stop: Arc<AtomicBool>,
+    thread: RelayThread,
+    last_announced: Instant,
+    /// Time between the last two announcements, once announced twice
+    interval: Option<Duration>,
+}
+
+impl Relayed {
+    /// Whether the session was not announced again in time
+    fn is_expired(&self, now: Instant) -> bool {
+        let timeout = self.interval.map_or(SESSION_TIMEOUT, |interval| {
+            (interval * TIMEOUT_INTERVALS).max(SESSION_TIMEOUT)
+        });
+        now.duration_since(self.last_announced) > timeout
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+        let _ = self.thread.join();
+    }
+}
+
+/// Listen to SAP announcements and relay the matching sessions
+///
+/// Runs until receiving announcements fails. `report` is called whenever relaying a
+/// session starts, stops or fails.
+pub fn relay(relay: &SapRelay, report: impl FnMut(SapEvent)) -> Result<(), io::Error> {
+    let listener_spec = ListenerSpec::MulticastV4 {
+        multicast_group: SAP_ADDR,
+        local_addr: relay.interface,
+        interfaces: Vec::new(),
+        sources: Vec::new(),
+    };
+    let listener = ListenerBuilder::new(&listener_spec).bind()?;
+    let announce_addr = match relay.target {
+        IpAddr::V4(ip) if ip.is_multicast() => SocketAddr::V4(SAP_ADDR),
+        IpAddr::V6(ip) if ip.is_multicast() => SocketAddr::from((SAP_ADDR_V6, SAP_ADDR.port())),
+        // Relays at the other end pick up the announcements on their unicast listener
+        target => SocketAddr::new(target, SAP_ADDR.port()),
+    };
+    relay_on(listener, relay, announce_addr, report)
+}
+
+/// Relay the sessions announced to the bound `listener`, announcing them to
+/// `announce_addr` if enabled
+fn relay_on(
+    listener: UdpSocket,
+    relay: &SapRelay,
+    announce_addr: SocketAddr,
+    mut report: impl FnMut(SapEvent),
+) -> Result<(), io::Error> {
+    listener.set_read_timeout(Some(TICK))?;
+    let announcer = match relay.announce {
+        true => {
+            let socket = match announce_addr {
+                SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+                SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+            };
+            // Connected to learn the address announcements originate from
+            socket.connect(announce_addr)?;
+            Some(socket)
+        }
+        false => None,
+    };
+    let announce = |session: &Session, deletion: bool| {
+        let Some(socket) = &announcer else {
+            return;
+        };
+        let ttl = session.ttl.unwrap_or(DEFAULT_TTL);
+        let _ = match announce_addr {
+            SocketAddr::V4(_) => socket.set_multicast_ttl_v4(ttl.into()),
+            SocketAddr::V6(_) => Ok(()),
+        };
+        let sdp = session.relayed_sdp(relay.target);
+        let announcement = Announcement {
+            deletion,
+            sdp: &sdp,
+        };
+        if let Ok(local_addr) = socket.local_addr() {
+            // Announcements are repeated, a lost one does no harm
+            let _ = socket.send(&announcement.encode(local_addr.ip()));
+        }
+    };
+
+    let mut relayed: HashMap<String, Relayed> = HashMap::new();
+    let mut buffer = [0; 65536];
+    loop {
+        let now = Instant::now();
+        let finished: Vec<String> = relayed
+            .iter()
+            .filter(|(_, relayed)| relayed.thread.is_finished() || relayed.is_expired(now))
+            .map(|(origin, _)| origin.clone())
+            .collect();
+        for origin in finished {
+            let Some(session) = relayed.remove(&origin) else {
+                continue;
+            };
+            if session.thread.is_finished() {
+                let result = session.thread.join();
+                let error = match result {
+                    Ok(Ok(_)) => io::Error::other("forwarding stopped"),
+                    Ok(Err(e)) => e.error,
+                    Err(_) => io::Error::other("forwarding thread panicked"),
+                };
+                report(SapEvent::Failed(session.session, error));
+            } else {
+                announce(&session.session, true);
+                let session_info = session.session.clone();
+                session.stop();
+                report(SapEvent::Expired(session_info));
+            }
+        }
+
+        let num_bytes = match listener.recv(&mut buffer) {
+            Ok(num_bytes) => num_bytes,
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        let Some(announcement) = Announcement::parse(&buffer[..num_bytes]) else {
+            continue;
+        };
+        let Some(session) = Session::parse(announcement.sdp) else {
+            continue;
+        };
+
+        if announcement.deletion {
+            if let Some(deleted) = relayed.remove(&session.origin) {
+                announce(&deleted.session, true);
+                let session = deleted.session.clone();
+                deleted.stop();
+                report(SapEvent::Deleted(session));
+            }
+            continue;
+        }
+        if let Some(relayed) = relayed.get_mut(&session.origin) {
+            relayed.interval = Some(now.duration_since(relayed.last_announced));
+            relayed.last_announced = now;
+            announce(&relayed.session, false);
+            continue;
+        }
+        // Sessions announced on the output side must not be relayed onto themselves
+        if session.addr.ip() == relay.target || !relay.matches(&session) {
+            continue;
+        }
+        if let Some(other) = relayed
+            .values()
+            .find(|other| other.session.addr.port() == session.addr.port())
+        {
+            let error = io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("port relayed already for {}", other.session),
+            );
+            report(SapEvent::Failed(session, error));
+            continue;
+        }
+
+        let target = SocketAddr::new(relay.target, session.addr.port());
+        match start(&session, relay.interface, target) {
+            Ok((stop, thread)) => {
+                announce(&session, false);
+                report(SapEvent::Relaying(session.clone(), target));
+                relayed.insert(
+                    session.origin.clone(),
+                    Relayed {
+                        session,
+                        stop,
+                        thread,
+                        last_announced: now,
+                        interval: None,
+                    },
+                );
+            }
+            Err(e) => report(SapEvent::Failed(session, e)),
+        }
+    }
+}
+
+/// Start forwarding the media stream of `session` received on `interface` to `target`
+fn start(
+    session: &Session,
+    interface: Ipv4Addr,
+    target: SocketAddr,
+) -> Result<(Arc<AtomicBool>, RelayThread), io::Error> {
+    let mut listener_spec = session.listener_spec();
+    if let ListenerSpec::MulticastV4 { local_addr, .. } = &mut listener_spec {
+        *local_addr = interface;
+    }
+    let forwarder = Forwarder::new(listener_spec, vec![target])?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = Arc::clone(&stop);
+        thread::Builder::new()
+            .name("sap-relay".to_owned())
+            .spawn(move || forwarder.run_until(Some(&stop)))?
+    };
+    Ok((stop, thread))
+}
+
+/// Whether `text` matches `pattern`, with `*` matching any text and `?` any character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Position after the last `*` and the text it matched up to, to backtrack to
+    let mut backtrack = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+            }
+            Some('?') => (p, t) = (p + 1, t + 1),
+            Some(c) if *c == text[t] => (p, t) = (p + 1, t + 1),
+            _ => match backtrack {
+                Some((after_star, matched)) => {
+                    backtrack = Some((after_star, matched + 1));
+                    (p, t) = (after_star, matched + 1);
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    const SDP: &str = "v=0\r\n\
+        o=- 1234 5 IN IP4 10.1.1.10\r\n\
+        s=Camera 1\r\n\
+        c=IN IP4 239.1.1.1/32\r\n\
+        t=0 0\r\n\
+        m=video 5004 RTP/AVP 33\r\n\
+        c=IN IP4 239.1.1.2/16\r\n\
+        m=audio 5006 RTP/AVP 10\r\n";
+
+    #[test]
+    fn announcement_parsed() {
+        let announcement = Announcement {
+            deletion: false,
+            sdp: SDP,
+        };
+        let packet = announcement.encode(IpAddr::V4(Ipv4Addr::new(10, 1, 1, 10)));
+        assert_eq!(Some(announcement), Announcement::parse(&packet));
+
+        // Media-level connections override the session-level one
+        let session = Session::parse(SDP).unwrap();
+        assert_eq!("Camera 1", session.name);
+        assert_eq!(
+            "239.1.1.2:5004".parse::<SocketAddr>().unwrap(),
+            session.addr
+        );
+        assert_eq!(Some(16), session.ttl);
+        assert_eq!("- 1234 IN IP4 10.1.1.10", session.origin);
+
+        let relayed = session.relayed_sdp(IpAddr::V4(Ipv4Addr::new(239, 9, 9, 9)));
+        assert!(relayed.contains("c=IN IP4 239.9.9.9/16\r\nm=audio"));
+        assert!(!relayed.contains("239.1.1"));
+
+        // Encrypted announcements are ignored
+        let mut encrypted = packet.clone();
+        encrypted[0] |= ENCRYPTED;
+        assert_eq!(None, Announcement::parse(&encrypted));
+    }
+
+    #[test]
+    fn filters_matched() {
+        assert!(glob_match("Camera *", "Camera 1"));
+        assert!(glob_match("*1", "Camera 1"));
+        assert!(glob_match("239.1.?.*:5004", "239.1.1.2:5004"));
+        assert!(!glob_match("Camera", "Camera 1"));
+        assert!(!glob_match("*2", "Camera 1"));
+
+        let session = Session::parse(SDP).unwrap();
+        let mut relay = SapRelay::new(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(relay.matches(&session));
+        relay.filters = vec!["Studio*".to_owned(), "239.1.1.*".to_owned()];
+        assert!(relay.matches(&session));
+        relay.filters = vec!["Studio*".to_owned()];
+        assert!(!relay.matches(&session));
+    }
+
+    #[test]
+    fn announced_session_relayed() {
+        let receiver = UdpSocket::bind("127.0.0.2:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sap_addr = listener.local_addr().unwrap();
+
+        let relay = SapRelay::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)));
+        let (events, received) = mpsc::channel();
+        thread::spawn(move || {
+            relay_on(listener, &relay, sap_addr, |event| {
+                let _ = events.send(event);
+            })
+        });
+
+        let sdp = format!(
+            "v=0\r\no=- 1 1 IN IP4 127.0.0.1\r\ns=Test\r\nc=IN IP4 127.0.0.1\r\nt=0 0\r\n\
+             m=video {port} RTP/AVP 33\r\n"
+        );
+        let announcer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut announcement = Announcement {
+            deletion: false,
+            sdp: &sdp,
+        };
+        let origin = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        announcer
+            .send_to(&announcement.encode(origin), sap_addr)
+            .unwrap();
+        let event = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(
+            matches!(&event, SapEvent::Relaying(session, target)
+                if session.name == "Test" && target.port() == port),
+            "{event}"
+        );
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"payload", ("127.0.0.1", port)).unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(7, receiver.recv(&mut buffer).unwrap());
+
+        announcement.deletion = true;
+        announcer
+            .send_to(&announcement.encode(origin), sap_addr)
+            .unwrap();
+        let event = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(event, SapEvent::Deleted(_)), "{event}");
+    }
+}
@@ -0,0 +1,279 @@
+//! Session descriptions
+//!
+//! Feed providers and SAP announcements describe multicast streams with SDP (RFC 8866)
+//! rather than raw addresses. The name, the connection address and port of the first
+//! media stream and the sources of a source-specific multicast group (RFC 4570) are
+//! taken from the description, everything else is kept as is.
+
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::Path,
+};
+
+use crate::ListenerSpec;
+
+/// TTL of multicast groups described without one
+pub(crate) const DEFAULT_TTL: u8 = 15;
+
+/// Session described by SDP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    /// Name of the session, the `s=` line
+    pub name: String,
+    /// Address and port of the first media stream
+    pub addr: SocketAddr,
+    /// TTL of the multicast group, if given
+    pub ttl: Option<u8>,
+    /// Sources to receive the multicast group from, empty for any source
+    pub sources: Vec<IpAddr>,
+    /// Username, ID and address of the `o=` line, identifying the session
+    pub(crate) origin: String,
+    /// Full description
+    sdp: String,
+}
+
+impl Session {
+    /// Parse a session description, `None` if it has no media stream with a
+    /// connection address
+    pub fn parse(sdp: &str) -> Option<Self> {
+        let mut name = None;
+        let mut origin = None;
+        let mut port = None;
+        // Session-level lines apply unless the media stream overrides them
+        let mut connection = [None, None];
+        let mut source_filters = [Vec::new(), Vec::new()];
+        for line in sdp.lines() {
+            let level = usize::from(port.is_some());
+            match line.split_once('=') {
+                Some(("o", value)) => {
+                    // The version changes with every modification of the session
+                    let fields: Vec<&str> = value.split_whitespace().collect();
+                    if let [user, id, _version, rest @ ..] = fields.as_slice() {
+                        origin = Some(format!("{user} {id} {}", rest.join(" ")));
+                    }
+                }
+                Some(("s", value)) => name = Some(value.to_owned()),
+                Some(("c", value)) => {
+                    connection[level] = connection[level].or(parse_connection(value));
+                }
+                Some(("a", value)) => {
+                    if let Some(filter) = value.strip_prefix("source-filter:") {
+                        source_filters[level].extend(parse_source_filter(filter));
+                    }
+                }
+                Some(("m", value)) if port.is_none() => {
+                    port = value.split_whitespace().nth(1)?.parse().ok();
+                }
+                // Only the first media stream is used
+                Some(("m", _)) => break,
+                _ => {}
+            }
+        }
+        let (ip, ttl) = connection[1].or(connection[0])?;
+        let [session_filters, media_filters] = source_filters;
+        let filters = match media_filters.is_empty() {
+            true => session_filters,
+            false => media_filters,
+        };
+        let sources = filters
+            .into_iter()
+            .filter(|(destination, _)| destination.is_none_or(|destination| destination == ip))
+            .map(|(_, source)| source)
+            .collect();
+
+        Some(Self {
+            name: name?,
+            addr: SocketAddr::from((ip, port?)),
+            ttl,
+            sources,
+            origin: origin?,
+            sdp: sdp.to_owned(),
+        })
+    }
+
+    /// Read the description from the file at `path`
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let sdp = fs::read_to_string(path)?;
+        Self::parse(&sdp).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no named session with a media stream and connection address",
+            )
+        })
+    }
+
+    /// Listener receiving the media stream, from the given sources for source-specific
+    /// multicast
+    pub fn listener_spec(&self) -> ListenerSpec {
+        let sources = self.sources.iter();
+        match self.addr {
+            SocketAddr::V4(group) if group.ip().is_multicast() => ListenerSpec::MulticastV4 {
+                multicast_group: group,
+                local_addr: Ipv4Addr::UNSPECIFIED,
+                interfaces: Vec::new(),
+                sources: sources
+                    .filter_map(|source| match source {
+                        IpAddr::V4(source) => Some(*source),
+                        IpAddr::V6(_) => None,
+                    })
+                    .collect(),
+            },
+            SocketAddr::V6(group) if group.ip().is_multicast() => ListenerSpec::MulticastV6 {
+                multicast_group: group,
+                interface_id: 0,
+                interfaces: Vec::new(),
+                sources: sources
+                    .filter_map(|source| match source {
+                        IpAddr::V6(source) => Some(*source),
+                        IpAddr::V4(_) => None,
+                    })
+                    .collect(),
+            },
+            addr => ListenerSpec::Unicast(addr),
+        }
+    }
+
+    /// Description of the session relayed to `target` by this host
+    ///
+    /// The source filter is left out, since the relay is the source now.
+    pub(crate) fn relayed_sdp(&self, target: IpAddr) -> String {
+        let mut sdp = String::with_capacity(self.sdp.len());
+        for line in self.sdp.lines() {
+            if line.starts_with("a=source-filter:") {
+                continue;
+            }
+            if line.starts_with("c=") {
+                let ttl = self.ttl.unwrap_or(DEFAULT_TTL);
+                sdp.push_str(&match target {
+                    IpAddr::V4(ip) if ip.is_multicast() => format!("c=IN IP4 {ip}/{ttl}"),
+                    IpAddr::V4(ip) => format!("c=IN IP4 {ip}"),
+                    IpAddr::V6(ip) => format!("c=IN IP6 {ip}"),
+                });
+            } else {
+                sdp.push_str(line);
+            }
+            sdp.push_str("\r\n");
+        }
+        sdp
+    }
+}
+
+/// Shows the name with the address of the media stream
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "session '{}' at {}", self.name, self.addr)
+    }
+}
+
+/// Address and TTL of a `c=` line
+fn parse_connection(value: &str) -> Option<(IpAddr, Option<u8>)> {
+    let mut fields = value.split_whitespace();
+    if fields.next()? != "IN" {
+        return None;
+    }
+    let family = fields.next()?;
+    let mut parts = fields.next()?.split('/');
+    let ip = match family {
+        "IP4" => IpAddr::V4(parts.next()?.parse().ok()?),
+        "IP6" => IpAddr::V6(parts.next()?.parse().ok()?),
+        _ => return None,
+    };
+    // IPv6 groups have no TTL, only a number of addresses
+    let ttl = match ip {
+        IpAddr::V4(_) => parts.next().and_then(|ttl| ttl.parse().ok()),
+        IpAddr::V6(_) => None,
+    };
+    Some((ip, ttl))
+}
+
+/// Sources included by an `a=source-filter:` line, with the destination they apply
+/// to, `None` for all
+fn parse_source_filter(value: &str) -> Vec<(Option<IpAddr>, IpAddr)> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let ["incl", "IN", "IP4" | "IP6", destination, sources @ ..] = fields.as_slice() else {
+        return Vec::new();
+    };
+    let destination = match *destination {
+        "*" => None,
+        destination => match destination.parse() {
+            Ok(destination) => Some(destination),
+            Err(_) => return Vec::new(),
+        },
+    };
+    sources
+        .iter()
+        .filter_map(|source| source.parse().ok())
+        .map(|source| (destination, source))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn source_specific_session_parsed() {
+        let sdp = "v=0\r\n\
+            o=- 1234 5 IN IP4 10.1.1.10\r\n\
+            s=Channel 1\r\n\
+            c=IN IP4 232.1.1.1/32\r\n\
+            a=source-filter: incl IN IP4 * 10.9.9.9\r\n\
+            t=0 0\r\n\
+            m=video 5004 RTP/AVP 33\r\n\
+            a=source-filter: incl IN IP4 232.1.1.1 10.1.1.10 10.1.1.11\r\n\
+            a=source-filter: incl IN IP4 232.2.2.2 10.2.2.2\r\n\
+            m=audio 5006 RTP/AVP 10\r\n\
+            c=IN IP4 232.3.3.3/32\r\n";
+        let session = Session::parse(sdp).unwrap();
+
+        assert_eq!("Channel 1", session.name);
+        assert_eq!(
+            "232.1.1.1:5004".parse::<SocketAddr>().unwrap(),
+            session.addr
+        );
+        assert_eq!(Some(32), session.ttl);
+        // Media-level filters override session-level ones, only those of the group apply
+        assert_eq!(
+            vec![
+                IpAddr::V4(Ipv4Addr::new(10, 1, 1, 10)),
+                IpAddr::V4(Ipv4Addr::new(10, 1, 1, 11))
+            ],
+            session.sources
+        );
+        assert_eq!(
+            "10.1.1.10,10.1.1.11@232.1.1.1:5004",
+            session.listener_spec().to_string()
+        );
+    }
+
+    #[test]
+    fn ipv6_session_parsed() {
+        let sdp = "v=0\r\n\
+            o=- 1 1 IN IP6 fd00::1\r\n\
+            s=Radio\r\n\
+            t=0 0\r\n\
+            m=audio 5004 RTP/AVP 10\r\n\
+            c=IN IP6 ff3e::1234\r\n";
+        let session = Session::parse(sdp).unwrap();
+
+        assert_eq!(
+            SocketAddr::from(("ff3e::1234".parse::<Ipv6Addr>().unwrap(), 5004)),
+            session.addr
+        );
+        assert_eq!(None, session.ttl);
+        assert!(session.sources.is_empty());
+        assert!(matches!(
+            session.listener_spec(),
+            ListenerSpec::MulticastV6 { .. }
+        ));
+
+        // Descriptions without connection address are of no use
+        assert_eq!(
+            None,
+            Session::parse("v=0\r\ns=Radio\r\nm=audio 5004 RTP/AVP 10\r\n")
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! Sequence numbers of relayed protocols
+//!
+//! Many protocols carry a sequence number at a fixed position in the payload, e.g.
+//! market data feeds and telemetry. Given where it lives, the forwarder follows the
+//! sequence of each source and counts gaps, resets, reordered and duplicate packets
+//! without understanding the protocol otherwise. Reordering hints at the need for a
+//! jitter buffer downstream, duplicates at redundant paths upstream.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Packets a sequence may step back before it is considered reset
+const MAX_STEP_BACK: u64 = 1024;
+/// Words of the bitmap of the packets seen within the step back
+const SEEN_WORDS: usize = MAX_STEP_BACK as usize / 64;
+
+/// Position of a sequence number in the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SequenceField {
+    /// Offset of the first byte
+    pub offset: usize,
+    /// Number of bytes, from 1 to 8
+    pub width: u8,
+    /// Byte order, network byte order if `false`
+    pub little_endian: bool,
+}
+
+impl SequenceField {
+    /// Sequence number of `payload`, `None` if it is too short
+    pub fn read(&self, payload: &[u8]) -> Option<u64> {
+        let bytes = payload.get(self.offset..)?.get(..self.width as usize)?;
+        let fold = |number: u64, byte: &u8| number << 8 | *byte as u64;
+        match self.little_endian {
+            false => Some(bytes.iter().fold(0, fold)),
+            true => Some(bytes.iter().rev().fold(0, fold)),
+        }
+    }
+
+    /// Number of distinct sequence numbers before they wrap around, less one
+    fn mask(&self) -> u64 {
+        u64::MAX >> (64 - 8 * self.width as u32)
+    }
+}
+
+impl FromStr for SequenceField {
+    type Err = ParseSequenceFieldError;
+
+    /// Parse `offset:width` with an optional `:le` or `:be` for the byte order
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let (Some(offset), Some(width)) = (parts.next(), parts.next()) else {
+            return Err(ParseSequenceFieldError);
+        };
+        let little_endian = match parts.next() {
+            None | Some("be") => false,
+            Some("le") => true,
+            Some(_) => return Err(ParseSequenceFieldError),
+        };
+        let offset = offset.parse().map_err(|_| ParseSequenceFieldError)?;
+        let width = width.parse().map_err(|_| ParseSequenceFieldError)?;
+        if parts.next().is_some() || !(1..=8).contains(&width) {
+            return Err(ParseSequenceFieldError);
+        }
+
+        Ok(Self {
+            offset,
+            width,
+            little_endian,
+        })
+    }
+}
+
+impl fmt::Display for SequenceField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.offset, self.width)?;
+        if self.little_endian {
+            write!(f, ":le")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sequence field other than `offset:width[:le|:be]` with a width of 1 to 8 bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseSequenceFieldError;
+
+impl fmt::Display for ParseSequenceFieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected offset:width[:le|:be] with a width of 1 to 8 bytes"
+        )
+    }
+}
+
+impl std::error::Error for ParseSequenceFieldError {}
+
+/// Counters of the sequence of a source
+#[derive(Debug, Default)]
+pub struct SequenceCounters {
+    /// Jumps ahead in the sequence, each missing one or more packets
+    pub gaps: AtomicU64,
+    /// Packets missing in the gaps, including those arriving later as reordered
+    pub lost: AtomicU64,
+    /// Restarts of the sequence, e.g. by a restarted sender
+    pub resets: AtomicU64,
+    /// Packets arriving after a later one of the sequence
+    pub reordered: AtomicU64,
+    /// Packets arriving again
+    pub duplicates: AtomicU64,
+}
+
+/// Position of a packet in the sequence of its source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// The packet expected next, or the first one
+    InOrder,
+    /// The given number of packets is missing before this one
+    Gap(u64),
+    /// Behind the expected packet, arriving late
+    Reordered,
+    /// Seen before
+    Duplicate,
+    /// Too far behind the expected packet to be late, the sequence starts over
+    Reset,
+}
+
+/// Sequence followed for a source
+#[derive(Debug)]
+pub(crate) struct Stream {
+    /// Sequence number expected next
+    next: Option<u64>,
+    /// Bitmap of the packets seen, indexed by the sequence number modulo its size
+    seen: [u64; SEEN_WORDS],
+}
+
+impl Default for Stream {
+    fn default() -> Self {
+        Self {
+            next: None,
+            seen: [0; SEEN_WORDS],
+        }
+    }
+}
+
+impl Stream {
+    /// Follow the sequence with the number of the next packet, updating `counters`
+    pub(crate) fn track(
+        &mut self,
+        field: &SequenceField,
+        number: u64,
+        counters: &SequenceCounters,
+    ) -> Step {
+        let mask = field.mask();
+        let Some(next) = self.next else {
+            self.advance(number, mask);
+            return Step::InOrder;
+        };
+
+        // Distances wrap around like the sequence numbers
+        let ahead = number.wrapping_sub(next) & mask;
+        let behind = next.wrapping_sub(number) & mask;
+        let step = if ahead == 0 {
+            Step::InOrder
+        } else if ahead <= mask / 2 {
+            counters.gaps.fetch_add(1, Ordering::Relaxed);
+            counters.lost.fetch_add(ahead, Ordering::Relaxed);
+            // Missing packets may still arrive, so they must not count as seen
+            self.forget(next, ahead);
+            Step::Gap(ahead)
+        } else if behind <= MAX_STEP_BACK.min(mask / 4) {
+            if self.is_seen(number) {
+                counters.duplicates.fetch_add(1, Ordering::Relaxed);
+                return Step::Duplicate;
+            }
+            counters.reordered.fetch_add(1, Ordering::Relaxed);
+            self.mark_seen(number);
+            return Step::Reordered;
+        } else {
+            counters.resets.fetch_add(1, Ordering::Relaxed);
+            self.seen = [0; SEEN_WORDS];
+            Step::Reset
+        };
+
+        self.advance(number, mask);
+        step
+    }
+
+    /// Continue the sequence after `number`
+    fn advance(&mut self, number: u64, mask: u64) {
+        self.mark_seen(number);
+        let next = number.wrapping_add(1) & mask;
+        // Clear the slot of the next number, which last held a number a whole bitmap ago
+        self.forget(next, 1);
+        self.next = Some(next);
+    }
+
+    /// Clear the `count` slots from `first` on
+    fn forget(&mut self, first: u64, count: u64) {
+        for offset in 0..count.min(MAX_STEP_BACK) {
+            let bit = first.wrapping_add(offset) % MAX_STEP_BACK;
+            self.seen[bit as usize / 64] &= !(1 << (bit % 64));
+        }
+    }
+
+    fn is_seen(&self, number: u64) -> bool {
+        let bit = number % MAX_STEP_BACK;
+        self.seen[bit as usize / 64] & (1 << (bit % 64)) != 0
+    }
+
+    fn mark_seen(&mut self, number: u64) {
+        let bit = number % MAX_STEP_BACK;
+        self.seen[bit as usize / 64] |= 1 << (bit % 64);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fields_parsed_and_read() {
+        let field: SequenceField = "2:3".parse().unwrap();
+        assert_eq!(Some(0x010203), field.read(b"\xff\xff\x01\x02\x03\xff"));
+        assert_eq!(None, field.read(b"\xff\xff\x01\x02"));
+
+        let field: SequenceField = "0:2:le".parse().unwrap();
+        assert_eq!("0:2:le", field.to_string());
+        assert_eq!(Some(0x0201), field.read(b"\x01\x02"));
+
+        for invalid in ["4", "4:0", "4:9", "x:4", "4:4:me", "4:4:le:1"] {
+            assert!(invalid.parse::<SequenceField>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn gaps_and_resets_counted() {
+        let field: SequenceField = "0:1".parse().unwrap();
+        let counters = SequenceCounters::default();
+        let mut stream = Stream::default();
+
+        let steps: Vec<_> = [250, 251, 255, 0, 253, 253, 0, 3, 150, 151]
+            .into_iter()
+            .map(|number| stream.track(&field, number, &counters))
+            .collect();
+        assert_eq!(
+            vec![
+                Step::InOrder,
+                Step::InOrder,
+                Step::Gap(3),
+                Step::InOrder,
+                Step::Reordered,
+                Step::Duplicate,
+                Step::Duplicate,
+                Step::Gap(2),
+                Step::Reset,
+                Step::InOrder,
+            ],
+            steps
+        );
+        assert_eq!(2, counters.gaps.load(Ordering::Relaxed));
+        assert_eq!(5, counters.lost.load(Ordering::Relaxed));
+        assert_eq!(1, counters.resets.load(Ordering::Relaxed));
+        assert_eq!(1, counters.reordered.load(Ordering::Relaxed));
+        assert_eq!(2, counters.duplicates.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn seen_packets_forgotten_after_wrap() {
+        let field: SequenceField = "0:2".parse().unwrap();
+        let counters = SequenceCounters::default();
+        let mut stream = Stream::default();
+
+        // Each slot of the bitmap is reused by the sequence
+        for number in (0..3 * MAX_STEP_BACK).filter(|number| number % 7 != 0) {
+            stream.track(&field, number, &counters);
+        }
+        for number in (2 * MAX_STEP_BACK..3 * MAX_STEP_BACK).filter(|number| number % 7 == 0) {
+            assert_eq!(Step::Reordered, stream.track(&field, number, &counters));
+        }
+        assert_eq!(
+            Step::Duplicate,
+            stream.track(&field, 3 * MAX_STEP_BACK - 1, &counters)
+        );
+    }
+}
@@ -0,0 +1,296 @@
+//! Reply paths of clients
+//!
+//! In reply-path mode, the forwarder acts like a NAT for request-response protocols like
+//! DNS or game traffic: each client sending to the listener gets a socket of its own
+//! per target, and datagrams the target sends back to that socket are relayed to the
+//! client from the listener socket. A worker thread per session receives the replies.
+//! Sessions expire once neither direction carried a packet for the session timeout.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        Arc, PoisonError, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{socket::Socket, state::SharedState, tools::is_timeout};
+
+/// Time after which idle sessions expire if not configured otherwise
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+/// Sessions tracked at most, packets of further clients are forwarded without reply path
+const MAX_SESSIONS: usize = 1024;
+/// Longest time for a worker to notice that its session expired or was closed
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Largest payload of a UDP datagram over IPv4
+const MAX_DATAGRAM: usize = 65_507;
+
+/// Time of the last packet of a session in either direction
+struct Activity {
+    started: Instant,
+    /// Milliseconds since `started`
+    last: AtomicU64,
+    /// Set once the worker stopped or the session is to be dropped
+    closed: AtomicBool,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+/// Socket of a client towards a target with the state of its worker
+struct Session {
+    socket: Box<dyn Socket>,
+    /// Source address of the packets sent through the session, to detect loops
+    own_addr: Option<SocketAddr>,
+    activity: Arc<Activity>,
+}
+
+/// Sessions of the clients, by client and target address
+pub(crate) struct Sessions {
+    timeout: Duration,
+    /// Listener socket to relay the replies from, replaced when rebinding
+    listener: Arc<RwLock<UdpSocket>>,
+    sessions: HashMap<(SocketAddr, SocketAddr), Session>,
+}
+
+impl Sessions {
+    /// Track sessions expiring after `timeout`, relaying replies from `listener`
+    pub(crate) fn new(timeout: Duration, listener: UdpSocket) -> Self {
+        Self {
+            timeout,
+            listener: Arc::new(RwLock::new(listener)),
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Relay the replies of all sessions from `listener` from now on
+    pub(crate) fn rebind(&mut self, listener: UdpSocket) {
+        *self
+            .listener
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = listener;
+    }
+
+    /// Check if packets of `client` to `target` go through an open session
+    pub(crate) fn contains(&self, client: SocketAddr, target: SocketAddr) -> bool {
+        self.sessions
+            .get(&(client, target))
+            .is_some_and(|session| !session.activity.is_closed())
+    }
+
+    /// Check if no further sessions can be opened
+    pub(crate) fn is_full(&self) -> bool {
+        self.sessions.len() >= MAX_SESSIONS
+    }
+
+    /// Open a session of `client` towards `target` sending from `socket`
+    ///
+    /// The worker relaying the replies needs a socket of the operating system.
+    pub(crate) fn open(
+        &mut self,
+        client: SocketAddr,
+        target: SocketAddr,
+        socket: Box<dyn Socket>,
+        own_addr: Option<SocketAddr>,
+        state: Arc<SharedState>,
+    ) -> Result<(), io::Error> {
+        let replies = socket
+            .udp_socket()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "reply paths require sockets of the operating system",
+                )
+            })?
+            .try_clone()?;
+        replies.set_read_timeout(Some(self.timeout.min(CHECK_INTERVAL)))?;
+        let activity = Arc::new(Activity::new());
+        {
+            let activity = Arc::clone(&activity);
+            let listener = Arc::clone(&self.listener);
+            let timeout = self.timeout;
+            thread::Builder::new()
+                .name("reply-path".to_owned())
+                .spawn(move || {
+                    relay_replies(
+                        &replies, &listener, client, target, &state, &activity, timeout,
+                    )
+                })?;
+        }
+        self.sessions.insert(
+            (client, target),
+            Session {
+                socket,
+                own_addr,
+                activity,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Send `packet` of `client` to `target` through their session
+    pub(crate) fn send_to(
+        &self,
+        client: SocketAddr,
+        target: SocketAddr,
+        packet: &[u8],
+    ) -> Option<Result<usize, io::Error>> {
+        let session = self.sessions.get(&(client, target))?;
+        session.activity.touch();
+        Some(session.socket.send_to(packet, target))
+    }
+
+    /// Drop the sessions whose worker stopped, returning their source addresses
+    pub(crate) fn expire(&mut self) -> Vec<SocketAddr> {
+        let mut own_addrs = Vec::new();
+        self.sessions.retain(|_, session| {
+            let closed = session.activity.is_closed();
+            if closed && let Some(own_addr) = session.own_addr {
+                own_addrs.push(own_addr);
+            }
+            !closed
+        });
+        own_addrs
+    }
+}
+
+impl Drop for Sessions {
+    fn drop(&mut self) {
+        for session in self.sessions.values() {
+            session.activity.closed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Relay the replies of `target` arriving at `replies` to `client` until the session
+/// expired or was closed
+fn relay_replies(
+    replies: &UdpSocket,
+    listener: &RwLock<UdpSocket>,
+    client: SocketAddr,
+    target: SocketAddr,
+    state: &SharedState,
+    activity: &Activity,
+    timeout: Duration,
+) {
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    while !activity.is_closed() {
+        match replies.recv_from(&mut buffer) {
+            Ok((num_bytes, source)) if source == target => {
+                activity.touch();
+                let listener = listener.read().unwrap_or_else(PoisonError::into_inner);
+                match listener.send_to(&buffer[..num_bytes], client) {
+                    Ok(num_bytes) => state.relayed().add(num_bytes),
+                    Err(e) => state.record_error(format!("failed to relay reply to {client}: {e}")),
+                }
+            }
+            // Only the target may answer through the session
+            Ok(_) => (),
+            Err(e) if is_timeout(&e) => {
+                if activity.idle() >= timeout {
+                    break;
+                }
+            }
+            // Reported for earlier packets to an unreachable target on some systems
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => (),
+            Err(e) => {
+                state.record_error(format!("failed to receive replies for {client}: {e}"));
+                break;
+            }
+        }
+    }
+    activity.closed.store(true, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replies_relayed_to_client() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+        target
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let state = Arc::new(SharedState::new(None, &[]));
+        let (client_addr, target_addr) =
+            (client.local_addr().unwrap(), target.local_addr().unwrap());
+
+        let mut sessions = Sessions::new(Duration::from_millis(200), listener.try_clone().unwrap());
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sessions
+            .open(
+                client_addr,
+                target_addr,
+                Box::new(socket),
+                None,
+                Arc::clone(&state),
+            )
+            .unwrap();
+        assert!(sessions.contains(client_addr, target_addr));
+
+        sessions
+            .send_to(client_addr, target_addr, b"query")
+            .unwrap()
+            .unwrap();
+        let mut buffer = [0; 64];
+        let (num_bytes, session_addr) = target.recv_from(&mut buffer).unwrap();
+        assert_eq!(b"query", &buffer[..num_bytes]);
+
+        target.send_to(b"answer", session_addr).unwrap();
+        let (num_bytes, source) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(b"answer", &buffer[..num_bytes]);
+        assert_eq!(listener.local_addr().unwrap(), source);
+
+        // Relayed from the new listener once rebound, keeping the session
+        let rebound = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let rebound_addr = rebound.local_addr().unwrap();
+        sessions.rebind(rebound);
+        assert!(sessions.contains(client_addr, target_addr));
+        target.send_to(b"answer", session_addr).unwrap();
+        let (_, source) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(rebound_addr, source);
+
+        // Expired once idle for the session timeout
+        thread::sleep(Duration::from_millis(700));
+        assert_eq!(2, state.relayed().packets());
+        assert!(!sessions.contains(client_addr, target_addr));
+        sessions.expire();
+        assert!(
+            sessions
+                .send_to(client_addr, target_addr, b"query")
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,54 @@
+//! Stopping forwarders gracefully
+//!
+//! A [ShutdownHandle] is shared between the code driving a forwarder and the forwarder
+//! itself, see [crate::Forwarder::with_shutdown]. Once shut down, the forwarder notices
+//! within 100ms, sends what is still queued, checkpoints its peers and returns the
+//! statistics of the run like a forwarder whose source was exhausted. Dropping it then
+//! closes the listener, which leaves the multicast groups it joined.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+/// Request to stop forwarding, shared by all clones
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop all forwarders sharing the handle
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::Release);
+    }
+
+    /// Check if the forwarders were asked to stop
+    pub fn is_shutdown(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// Flag the forwarding loop checks between packets
+    pub(crate) fn flag(&self) -> &AtomicBool {
+        &self.requested
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shared_by_clones() {
+        let shutdown = ShutdownHandle::new();
+        let clone = shutdown.clone();
+        assert!(!shutdown.is_shutdown());
+
+        clone.shutdown();
+        assert!(shutdown.is_shutdown());
+    }
+}
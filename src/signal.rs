@@ -0,0 +1,289 @@
+//! Pausing, resuming, reloading and stopping through signals
+//!
+//! `SIGUSR1` pauses forwarding and `SIGUSR2` resumes it, like `POST /pause` and
+//! `POST /resume` of the control API, e.g. from a maintenance script running
+//! `pkill -USR1 udpforwarder`. The listener stays bound and multicast groups stay
+//! joined meanwhile, so the feed is back right away when resuming. `SIGHUP` reloads
+//! the targets, see [crate::reload], and `SIGINT` and `SIGTERM` shut forwarding down
+//! gracefully, see [ShutdownHandle].
+//!
+//! The signal handler only records the request, a thread applies it to the
+//! [SharedState], the [ShutdownHandle] or calls back. Only available on Unix.
+
+use std::{io, sync::Arc, thread::JoinHandle};
+
+use crate::{shutdown::ShutdownHandle, state::SharedState};
+
+/// Signals handled by the forwarder with their effect
+pub(crate) const SIGNALS: &[(&str, &str)] = &[
+    (
+        "SIGUSR1",
+        "Pause forwarding, dropping received packets while the listener stays bound.",
+    ),
+    ("SIGUSR2", "Resume forwarding."),
+    (
+        "SIGHUP",
+        "Read the config file and targets files again, adding and removing the targets \
+         which changed while the listener stays bound. Other changes are logged as needing \
+         a restart.",
+    ),
+    (
+        "SIGINT, SIGTERM",
+        "Stop forwarding gracefully, writing the statistics and the summary. A second \
+         signal terminates right away.",
+    ),
+];
+
+/// Handle `SIGUSR1` and `SIGUSR2` by pausing and resuming forwarding
+///
+/// `on_change` is called with the new paused flag whenever a signal changed it.
+#[cfg(unix)]
+pub fn watch(
+    state: Arc<SharedState>,
+    on_change: impl FnMut(bool) + Send + 'static,
+) -> Result<JoinHandle<()>, io::Error> {
+    unix::watch(state, on_change)
+}
+
+#[cfg(not(unix))]
+pub fn watch(
+    _state: Arc<SharedState>,
+    _on_change: impl FnMut(bool) + Send + 'static,
+) -> Result<JoinHandle<()>, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pausing through signals is only available on Unix",
+    ))
+}
+
+/// Handle `SIGHUP` by calling `on_hangup`, e.g. to reload the configuration
+///
+/// Signals arriving while `on_hangup` runs lead to one more call.
+#[cfg(unix)]
+pub fn reload_on_hangup(
+    on_hangup: impl FnMut() + Send + 'static,
+) -> Result<JoinHandle<()>, io::Error> {
+    unix::reload_on_hangup(on_hangup)
+}
+
+#[cfg(not(unix))]
+pub fn reload_on_hangup(
+    _on_hangup: impl FnMut() + Send + 'static,
+) -> Result<JoinHandle<()>, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reloading through signals is only available on Unix",
+    ))
+}
+
+/// Handle `SIGINT` and `SIGTERM` by shutting down `shutdown`
+///
+/// Only the first signal is handled, a second one terminates the process right away
+/// in case stopping gracefully hangs.
+#[cfg(unix)]
+pub fn shutdown_on_termination(shutdown: ShutdownHandle) -> Result<JoinHandle<()>, io::Error> {
+    unix::shutdown_on_termination(shutdown)
+}
+
+#[cfg(not(unix))]
+pub fn shutdown_on_termination(_shutdown: ShutdownHandle) -> Result<JoinHandle<()>, io::Error> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "stopping through signals is only available on Unix",
+    ))
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        ffi::c_int,
+        io,
+        sync::{
+            Arc,
+            atomic::{AtomicBool, AtomicU8, Ordering},
+        },
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use crate::{shutdown::ShutdownHandle, state::SharedState};
+
+    const SIGHUP: c_int = 1;
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SIGUSR1: c_int = 10;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SIGUSR2: c_int = 12;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SIGUSR1: c_int = 30;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SIGUSR2: c_int = 31;
+    /// Returned by `signal` if installing the handler failed
+    const SIG_ERR: usize = usize::MAX;
+    /// Handler terminating the process
+    const SIG_DFL: usize = 0;
+
+    /// No request since the last check
+    const NONE: u8 = 0;
+    const PAUSE: u8 = 1;
+    const RESUME: u8 = 2;
+
+    /// Interval requests are applied in
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Last request received through a signal
+    static REQUEST: AtomicU8 = AtomicU8::new(NONE);
+    /// Set when `SIGHUP` was received since the last reload
+    static HANGUP: AtomicBool = AtomicBool::new(false);
+    /// Set once `SIGINT` or `SIGTERM` was received
+    static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+    unsafe extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    /// Record the request, storing to an atomic is async-signal-safe
+    extern "C" fn on_signal(signum: c_int) {
+        let request = match signum {
+            SIGUSR1 => PAUSE,
+            _ => RESUME,
+        };
+        REQUEST.store(request, Ordering::Relaxed);
+    }
+
+    /// Record the hangup, storing to an atomic is async-signal-safe
+    extern "C" fn on_hangup(_signum: c_int) {
+        HANGUP.store(true, Ordering::Relaxed);
+    }
+
+    pub(super) fn reload_on_hangup(
+        mut reload: impl FnMut() + Send + 'static,
+    ) -> Result<JoinHandle<()>, io::Error> {
+        // SAFETY: The handler only stores to an atomic
+        if unsafe { signal(SIGHUP, on_hangup as extern "C" fn(c_int) as usize) } == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+
+        thread::Builder::new()
+            .name("reload".to_owned())
+            .spawn(move || {
+                loop {
+                    thread::sleep(POLL_INTERVAL);
+                    if HANGUP.swap(false, Ordering::Relaxed) {
+                        reload();
+                    }
+                }
+            })
+    }
+
+    /// Record the termination, storing to an atomic is async-signal-safe
+    extern "C" fn on_termination(_signum: c_int) {
+        TERMINATE.store(true, Ordering::Relaxed);
+    }
+
+    pub(super) fn shutdown_on_termination(
+        shutdown: ShutdownHandle,
+    ) -> Result<JoinHandle<()>, io::Error> {
+        for signum in [SIGINT, SIGTERM] {
+            // SAFETY: The handler only stores to an atomic
+            if unsafe { signal(signum, on_termination as extern "C" fn(c_int) as usize) } == SIG_ERR
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        thread::Builder::new()
+            .name("shutdown".to_owned())
+            .spawn(move || {
+                while !TERMINATE.load(Ordering::Relaxed) {
+                    thread::sleep(POLL_INTERVAL);
+                }
+                shutdown.shutdown();
+                for signum in [SIGINT, SIGTERM] {
+                    // SAFETY: Restores the default handler
+                    unsafe { signal(signum, SIG_DFL) };
+                }
+            })
+    }
+
+    pub(super) fn watch(
+        state: Arc<SharedState>,
+        mut on_change: impl FnMut(bool) + Send + 'static,
+    ) -> Result<JoinHandle<()>, io::Error> {
+        for signum in [SIGUSR1, SIGUSR2] {
+            // SAFETY: The handler only stores to an atomic
+            if unsafe { signal(signum, on_signal as extern "C" fn(c_int) as usize) } == SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        thread::Builder::new()
+            .name("signals".to_owned())
+            .spawn(move || {
+                loop {
+                    thread::sleep(POLL_INTERVAL);
+                    let paused = match REQUEST.swap(NONE, Ordering::Relaxed) {
+                        PAUSE => true,
+                        RESUME => false,
+                        _ => continue,
+                    };
+                    if state.is_paused() != paused {
+                        state.set_paused(paused);
+                        on_change(paused);
+                    }
+                }
+            })
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        unsafe extern "C" {
+            fn raise(signum: c_int) -> c_int;
+        }
+
+        #[test]
+        fn paused_and_resumed() {
+            let state = Arc::new(SharedState::new(None, &[]));
+            let (changes, changed) = std::sync::mpsc::channel();
+            watch(Arc::clone(&state), move |paused| {
+                let _ = changes.send(paused);
+            })
+            .unwrap();
+
+            for (signum, paused) in [(SIGUSR1, true), (SIGUSR2, false)] {
+                // SAFETY: The handler for the signal was installed above
+                assert_eq!(0, unsafe { raise(signum) });
+                assert_eq!(Ok(paused), changed.recv_timeout(Duration::from_secs(5)));
+                assert_eq!(paused, state.is_paused());
+            }
+        }
+
+        #[test]
+        fn reloaded_on_sighup() {
+            let (reloads, reloaded) = std::sync::mpsc::channel();
+            reload_on_hangup(move || {
+                let _ = reloads.send(());
+            })
+            .unwrap();
+
+            // SAFETY: The handler for the signal was installed above
+            assert_eq!(0, unsafe { raise(SIGHUP) });
+            assert_eq!(Ok(()), reloaded.recv_timeout(Duration::from_secs(5)));
+        }
+
+        #[test]
+        fn shut_down_on_sigterm() {
+            let shutdown = ShutdownHandle::new();
+            let watcher = shutdown_on_termination(shutdown.clone()).unwrap();
+
+            // SAFETY: The handler for the signal was installed above
+            assert_eq!(0, unsafe { raise(SIGTERM) });
+            watcher.join().unwrap();
+            assert!(shutdown.is_shutdown());
+        }
+    }
+}
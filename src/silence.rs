@@ -0,0 +1,140 @@
+//! Dead-stream alarm
+//!
+//! The stream at the listener is tracked as active or silent and every transition is
+//! reported once, which suits paging better than inferring silence from rates. The
+//! stream turns silent when no packet arrived for a while and only counts as active
+//! again once packets flowed without such a pause for a while, so that a flapping
+//! stream doesn't raise an alarm per packet.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Silence after which the stream is considered silent by default
+pub const DEFAULT_SILENT_AFTER: Duration = Duration::from_secs(5);
+/// Time packets need to flow for the stream to be active again by default
+pub const DEFAULT_ACTIVE_AFTER: Duration = Duration::from_secs(1);
+
+/// Thresholds of the transitions between active and silent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SilenceAlarm {
+    /// Time without packets after which an active stream turns silent
+    pub silent_after: Duration,
+    /// Time packets need to flow, each within `silent_after` of the previous one,
+    /// for a silent stream to turn active
+    pub active_after: Duration,
+}
+
+impl Default for SilenceAlarm {
+    fn default() -> Self {
+        Self {
+            silent_after: DEFAULT_SILENT_AFTER,
+            active_after: DEFAULT_ACTIVE_AFTER,
+        }
+    }
+}
+
+/// State of the stream at the listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum StreamState {
+    /// Packets are arriving
+    Active,
+    /// No packets arrived for a while, or since the start
+    Silent,
+}
+
+impl fmt::Display for StreamState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Active => write!(f, "active"),
+            Self::Silent => write!(f, "silent"),
+        }
+    }
+}
+
+/// Watch of the stream, starting silent
+#[derive(Debug)]
+pub(crate) struct StreamWatch {
+    state: StreamState,
+    /// First packet of the current run of packets without pause
+    run_started: Option<Instant>,
+    last_packet: Option<Instant>,
+}
+
+impl StreamWatch {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: StreamState::Silent,
+            run_started: None,
+            last_packet: None,
+        }
+    }
+
+    /// Note a packet arriving at `now`, returning the new state if it changed
+    pub(crate) fn packet(&mut self, alarm: &SilenceAlarm, now: Instant) -> Option<StreamState> {
+        let paused = self
+            .last_packet
+            .is_none_or(|last| now.saturating_duration_since(last) >= alarm.silent_after);
+        if paused {
+            self.run_started = Some(now);
+        }
+        self.last_packet = Some(now);
+
+        let run_started = self.run_started.unwrap_or(now);
+        if self.state == StreamState::Silent
+            && now.saturating_duration_since(run_started) >= alarm.active_after
+        {
+            self.state = StreamState::Active;
+            return Some(StreamState::Active);
+        }
+        None
+    }
+
+    /// Check for silence at `now`, returning the new state if it changed
+    pub(crate) fn check(&mut self, alarm: &SilenceAlarm, now: Instant) -> Option<StreamState> {
+        let silent = self
+            .last_packet
+            .is_some_and(|last| now.saturating_duration_since(last) >= alarm.silent_after);
+        if self.state == StreamState::Active && silent {
+            self.state = StreamState::Silent;
+            return Some(StreamState::Silent);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transitions_with_hysteresis() {
+        let alarm = SilenceAlarm {
+            silent_after: Duration::from_secs(2),
+            active_after: Duration::from_secs(1),
+        };
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut watch = StreamWatch::new();
+
+        // Active only after packets flowed for a second
+        assert_eq!(None, watch.packet(&alarm, at(0)));
+        assert_eq!(None, watch.packet(&alarm, at(500)));
+        assert_eq!(Some(StreamState::Active), watch.packet(&alarm, at(1000)));
+        assert_eq!(None, watch.packet(&alarm, at(1500)));
+
+        assert_eq!(None, watch.check(&alarm, at(3000)));
+        assert_eq!(Some(StreamState::Silent), watch.check(&alarm, at(3500)));
+        assert_eq!(None, watch.check(&alarm, at(4000)));
+
+        // A single packet after the silence doesn't make the stream active
+        assert_eq!(None, watch.packet(&alarm, at(6000)));
+        assert_eq!(None, watch.packet(&alarm, at(8500)));
+        assert_eq!(None, watch.check(&alarm, at(9000)));
+        assert_eq!(Some(StreamState::Active), watch.packet(&alarm, at(9500)));
+    }
+}
@@ -0,0 +1,349 @@
+//! Pluggable outputs
+//!
+//! Besides the UDP targets, the forwarder fans packets out to [Sink]s, so that
+//! applications embedding the crate can plug in their own delivery mechanisms.
+//! Sinks for UDP, line-based files, pcap captures and in-process channels are included.
+
+#[cfg(any(feature = "pcap", test))]
+use std::net::IpAddr;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+};
+#[cfg(feature = "pcap")]
+use std::{io::BufWriter, time::SystemTime};
+
+use crate::Packet;
+
+/// Output the forwarder delivers packets to
+///
+/// Failures are recorded as errors of the forwarder, naming the sink by its [fmt::Display].
+pub trait Sink: Send + fmt::Display {
+    /// Deliver a forwarded payload received from `source`
+    fn send(&mut self, source: SocketAddr, payload: &[u8]) -> Result<(), io::Error>;
+}
+
+/// Sends packets to an address from a socket of its own
+#[derive(Debug)]
+pub struct UdpSink {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+impl UdpSink {
+    /// Bind a socket of the IP family of `addr`
+    pub fn new(addr: SocketAddr) -> Result<Self, io::Error> {
+        let unspecified = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+
+        Ok(Self {
+            socket: UdpSocket::bind(unspecified)?,
+            addr,
+        })
+    }
+}
+
+impl Sink for UdpSink {
+    fn send(&mut self, _source: SocketAddr, payload: &[u8]) -> Result<(), io::Error> {
+        self.socket.send_to(payload, self.addr).map(|_| ())
+    }
+}
+
+impl fmt::Display for UdpSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addr)
+    }
+}
+
+/// Appends each payload as a line to a file, e.g. for syslog or JSON messages
+#[derive(Debug)]
+pub struct FileSink {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileSink {
+    /// Open `path` for appending, creating it if needed
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let path = path.as_ref().to_owned();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        Ok(Self { file, path })
+    }
+}
+
+impl Sink for FileSink {
+    fn send(&mut self, _source: SocketAddr, payload: &[u8]) -> Result<(), io::Error> {
+        let payload = payload.strip_suffix(b"\n").unwrap_or(payload);
+        let mut line = Vec::with_capacity(payload.len() + 1);
+        line.extend_from_slice(payload);
+        line.push(b'\n');
+
+        // Single write, so lines of concurrent writers do not interleave
+        self.file.write_all(&line)
+    }
+}
+
+impl fmt::Display for FileSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "file {}", self.path.display())
+    }
+}
+
+/// Writes packets to a pcap capture for Wireshark and tcpdump
+///
+/// Packets are recorded as raw IP with UDP headers from their source to
+/// the given destination, usually the listener.
+#[cfg(feature = "pcap")]
+#[derive(Debug)]
+pub struct PcapSink {
+    writer: BufWriter<File>,
+    path: PathBuf,
+    destination: SocketAddr,
+}
+
+#[cfg(feature = "pcap")]
+impl PcapSink {
+    /// Link type of raw IPv4 and IPv6 packets
+    const LINKTYPE_RAW: u32 = 101;
+    /// Maximum length of captured packets
+    const SNAPLEN: u32 = 262_144;
+
+    /// Create the capture file at `path`, replacing an existing one
+    pub fn create(path: impl AsRef<Path>, destination: SocketAddr) -> Result<Self, io::Error> {
+        let path = path.as_ref().to_owned();
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // Time zone and accuracy of timestamps
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&Self::SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&Self::LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+        writer.flush()?;
+
+        Ok(Self {
+            writer,
+            path,
+            destination,
+        })
+    }
+}
+
+#[cfg(feature = "pcap")]
+impl Sink for PcapSink {
+    fn send(&mut self, source: SocketAddr, payload: &[u8]) -> Result<(), io::Error> {
+        let packet = ip_packet(source, self.destination, payload);
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut record = Vec::with_capacity(16);
+        record.extend_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        self.writer.write_all(&record)?;
+        self.writer.write_all(&packet)?;
+        // Keep the capture readable while forwarding
+        self.writer.flush()
+    }
+}
+
+#[cfg(feature = "pcap")]
+impl fmt::Display for PcapSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pcap {}", self.path.display())
+    }
+}
+
+/// Passes packets to another part of the application through a channel
+#[derive(Debug)]
+pub struct ChannelSink {
+    sender: Sender<Packet>,
+}
+
+impl ChannelSink {
+    pub fn new(sender: Sender<Packet>) -> Self {
+        Self { sender }
+    }
+}
+
+impl Sink for ChannelSink {
+    fn send(&mut self, source: SocketAddr, payload: &[u8]) -> Result<(), io::Error> {
+        let packet = Packet {
+            source,
+            payload: payload.to_vec(),
+        };
+
+        self.sender
+            .send(packet)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver dropped"))
+    }
+}
+
+impl fmt::Display for ChannelSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("channel")
+    }
+}
+
+/// Build an IP packet with UDP header carrying `payload`
+///
+/// Uses IPv6 if either address is IPv6, with IPv4 addresses mapped.
+#[cfg(any(feature = "pcap", test))]
+pub(crate) fn ip_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    const UDP: u8 = 17;
+    const TTL: u8 = 64;
+
+    let udp_len = (8 + payload.len()) as u16;
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&source.port().to_be_bytes());
+    udp.extend_from_slice(&destination.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0; 2]);
+    udp.extend_from_slice(payload);
+
+    let mut packet = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let mut header = Vec::with_capacity(20 + udp.len());
+            header.extend_from_slice(&[0x45, 0]);
+            header.extend_from_slice(&(20 + udp_len).to_be_bytes());
+            // Identification, don't fragment
+            header.extend_from_slice(&[0, 0, 0x40, 0, TTL, UDP, 0, 0]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+            let header_checksum = checksum(&[&header]);
+            header[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+            let pseudo = [
+                &src.octets()[..],
+                &dst.octets(),
+                &[0, UDP],
+                &udp_len.to_be_bytes(),
+            ]
+            .concat();
+            let udp_checksum = checksum(&[&pseudo, &udp]);
+            udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+            header
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            let (src, dst) = (to_v6(src), to_v6(dst));
+
+            let mut header = Vec::with_capacity(40 + udp.len());
+            header.extend_from_slice(&[0x60, 0, 0, 0]);
+            header.extend_from_slice(&udp_len.to_be_bytes());
+            header.extend_from_slice(&[UDP, TTL]);
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+
+            let pseudo = [
+                &src.octets()[..],
+                &dst.octets(),
+                &(udp_len as u32).to_be_bytes(),
+                &[0, 0, 0, UDP],
+            ]
+            .concat();
+            let udp_checksum = checksum(&[&pseudo, &udp]);
+            udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+            header
+        }
+    };
+
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+/// Internet checksum (RFC 1071) over the concatenation of `parts`
+///
+/// All parts but the last must have an even length. A zero result is sent as
+/// `0xffff`, as UDP reserves zero for no checksum.
+pub(crate) fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for word in part.chunks(2) {
+            sum += u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]));
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    match !(sum as u16) {
+        0 => 0xffff,
+        checksum => checksum,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn ipv4_packet_built() {
+        let packet = ip_packet(
+            "10.1.1.10:5000".parse().unwrap(),
+            "224.10.10.10:4000".parse().unwrap(),
+            b"hello",
+        );
+
+        assert_eq!(20 + 8 + 5, packet.len());
+        assert_eq!([0x45, 0, 0, 33], packet[..4]);
+        // Checksums verify to zero
+        assert_eq!(0xffff, checksum(&[&packet[..20]]));
+        let pseudo = [&packet[12..20], &[0, 17, 0, 13]].concat();
+        assert_eq!(0xffff, checksum(&[&pseudo, &packet[20..]]));
+        assert_eq!(b"hello", &packet[28..]);
+    }
+
+    #[test]
+    fn ipv6_packet_built() {
+        let packet = ip_packet(
+            "10.1.1.10:5000".parse().unwrap(),
+            "[ff05::1]:4000".parse().unwrap(),
+            b"hello",
+        );
+
+        assert_eq!(40 + 8 + 5, packet.len());
+        assert_eq!(0x60, packet[0]);
+        assert_eq!(
+            Ipv4Addr::new(10, 1, 1, 10).to_ipv6_mapped().octets(),
+            packet[8..24]
+        );
+        assert_eq!(b"hello", &packet[48..]);
+    }
+
+    #[test]
+    fn channel_receives_packets() {
+        let (sender, receiver) = mpsc::channel();
+        let mut sink = ChannelSink::new(sender);
+        let source = "127.0.0.1:5000".parse().unwrap();
+
+        sink.send(source, b"hello").unwrap();
+        assert_eq!(
+            Packet {
+                source,
+                payload: b"hello".to_vec()
+            },
+            receiver.recv().unwrap()
+        );
+
+        drop(receiver);
+        assert!(sink.send(source, b"hello").is_err());
+    }
+}
@@ -0,0 +1,309 @@
+//! Socket abstraction
+//!
+//! The forwarder binds its senders through a [Network]. The [SystemNetwork] binds UDP
+//! sockets of the operating system, while the [MemoryNetwork] passes datagrams between
+//! in-memory sockets. The latter lets fan-out, filters and the handling of send errors
+//! be tested deterministically, without ports and timing of real sockets.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        Arc, Mutex, MutexGuard,
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    },
+    time::Duration,
+};
+
+use crate::source::Source;
+
+/// Datagram socket the forwarder sends with
+pub trait Socket: Send {
+    /// Send a datagram to `addr`, returning the number of bytes sent
+    fn send_to(&self, buffer: &[u8], addr: SocketAddr) -> Result<usize, io::Error>;
+
+    /// Receive a datagram into `buffer`, returning its length and sender
+    fn recv_from(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), io::Error>;
+
+    /// Address the socket is bound to
+    fn local_addr(&self) -> Result<SocketAddr, io::Error>;
+
+    /// UDP socket of the operating system, for protocols which need one like STUN
+    fn udp_socket(&self) -> Option<&UdpSocket> {
+        None
+    }
+}
+
+impl Socket for UdpSocket {
+    fn send_to(&self, buffer: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
+        UdpSocket::send_to(self, buffer, addr)
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        UdpSocket::recv_from(self, buffer)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn udp_socket(&self) -> Option<&UdpSocket> {
+        Some(self)
+    }
+}
+
+/// Network the forwarder binds its sockets in
+pub trait Network: Send {
+    /// Bind a socket to `addr`, picking a free port for port `0`
+    fn bind(&self, addr: SocketAddr) -> Result<Box<dyn Socket>, io::Error>;
+
+    /// Source address of packets sent from `socket` to `destination`, if it can be determined
+    fn source_addr(&self, socket: &dyn Socket, destination: &SocketAddr) -> Option<SocketAddr>;
+}
+
+/// Network of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemNetwork;
+
+impl Network for SystemNetwork {
+    fn bind(&self, addr: SocketAddr) -> Result<Box<dyn Socket>, io::Error> {
+        Ok(Box::new(UdpSocket::bind(addr)?))
+    }
+
+    /// Senders are bound to the unspecified address, so the kernel picks the source IP
+    /// by route, see [route_source_ip].
+    fn source_addr(&self, socket: &dyn Socket, destination: &SocketAddr) -> Option<SocketAddr> {
+        let ip = route_source_ip(destination)?;
+        let port = socket.local_addr().ok()?.port();
+
+        Some(SocketAddr::new(ip, port))
+    }
+}
+
+/// Source IP the kernel picks by route for packets to `destination`
+///
+/// Connecting a probe socket reveals which IP that is without sending anything.
+pub(crate) fn route_source_ip(destination: &SocketAddr) -> Option<IpAddr> {
+    let unspecified = match destination {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+
+    let probe = UdpSocket::bind(unspecified).ok()?;
+    probe.connect(destination).ok()?;
+    Some(probe.local_addr().ok()?.ip())
+}
+
+/// First port handed out when binding port `0`
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// Datagram with its sender
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// In-memory network passing datagrams between its sockets
+///
+/// Sockets bound to the unspecified address get the loopback address of their
+/// IP family. Datagrams to addresses without a socket are lost like with UDP,
+/// unless the address is marked unreachable to make sending fail.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<Sockets>>,
+}
+
+#[derive(Debug, Default)]
+struct Sockets {
+    bound: HashMap<SocketAddr, Sender<Datagram>>,
+    unreachable: HashSet<SocketAddr>,
+    next_port: u16,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a socket to `addr`, e.g. for a target to receive forwarded packets
+    pub fn bind(&self, addr: SocketAddr) -> Result<MemorySocket, io::Error> {
+        let mut sockets = self.sockets();
+
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        let port = match addr.port() {
+            0 => loop {
+                let port = sockets.next_port.max(FIRST_EPHEMERAL_PORT);
+                sockets.next_port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+                if !sockets.bound.contains_key(&SocketAddr::new(ip, port)) {
+                    break port;
+                }
+            },
+            port => port,
+        };
+        let addr = SocketAddr::new(ip, port);
+        if sockets.bound.contains_key(&addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        sockets.bound.insert(addr, sender);
+
+        Ok(MemorySocket {
+            addr,
+            receiver: Mutex::new(receiver),
+            timeout: None,
+            nonblocking: false,
+            network: self.clone(),
+        })
+    }
+
+    /// Let sending to `addr` fail with `ConnectionRefused`, or succeed again
+    pub fn set_unreachable(&self, addr: SocketAddr, unreachable: bool) {
+        let mut sockets = self.sockets();
+        match unreachable {
+            true => sockets.unreachable.insert(addr),
+            false => sockets.unreachable.remove(&addr),
+        };
+    }
+
+    /// Lock the sockets, which stay consistent even if a holder of the lock panicked
+    fn sockets(&self) -> MutexGuard<'_, Sockets> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Network for MemoryNetwork {
+    fn bind(&self, addr: SocketAddr) -> Result<Box<dyn Socket>, io::Error> {
+        Ok(Box::new(MemoryNetwork::bind(self, addr)?))
+    }
+
+    fn source_addr(&self, socket: &dyn Socket, _destination: &SocketAddr) -> Option<SocketAddr> {
+        socket.local_addr().ok()
+    }
+}
+
+/// Socket of a [MemoryNetwork], unbound when dropped
+///
+/// Can be the source of a forwarder, but not relay in hub mode or for a rendezvous.
+#[derive(Debug)]
+pub struct MemorySocket {
+    addr: SocketAddr,
+    receiver: Mutex<Receiver<Datagram>>,
+    timeout: Option<Duration>,
+    nonblocking: bool,
+    network: MemoryNetwork,
+}
+
+impl Socket for MemorySocket {
+    fn send_to(&self, buffer: &[u8], addr: SocketAddr) -> Result<usize, io::Error> {
+        let sockets = self.network.sockets();
+        if sockets.unreachable.contains(&addr) {
+            return Err(io::ErrorKind::ConnectionRefused.into());
+        }
+        if let Some(socket) = sockets.bound.get(&addr) {
+            let _ = socket.send((buffer.to_vec(), self.addr));
+        }
+
+        Ok(buffer.len())
+    }
+
+    fn recv_from(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        let receiver = self
+            .receiver
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (datagram, source) = match self.timeout {
+            _ if self.nonblocking => match receiver.try_recv() {
+                Ok(datagram) => datagram,
+                Err(TryRecvError::Empty) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(io::ErrorKind::NotConnected.into());
+                }
+            },
+            None => receiver.recv().map_err(|_| io::ErrorKind::NotConnected)?,
+            Some(timeout) => match receiver.recv_timeout(timeout) {
+                Ok(datagram) => datagram,
+                Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::ErrorKind::NotConnected.into());
+                }
+            },
+        };
+
+        // Truncated like UDP datagrams exceeding the buffer
+        let num_bytes = datagram.len().min(buffer.len());
+        buffer[..num_bytes].copy_from_slice(&datagram[..num_bytes]);
+
+        Ok((num_bytes, source))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        Ok(self.addr)
+    }
+}
+
+impl Source for MemorySocket {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        Socket::recv_from(self, buffer).map(Some)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        self.network.sockets().bound.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn datagrams_delivered_by_address() {
+        let network = MemoryNetwork::new();
+        let sender = network.bind("0.0.0.0:0".parse().unwrap()).unwrap();
+        let receiver = network.bind("127.0.0.1:4000".parse().unwrap()).unwrap();
+        assert_eq!(
+            io::ErrorKind::AddrInUse,
+            network
+                .bind("127.0.0.1:4000".parse().unwrap())
+                .unwrap_err()
+                .kind()
+        );
+
+        let sender_addr = sender.local_addr().unwrap();
+        assert_eq!(
+            SocketAddr::from((Ipv4Addr::LOCALHOST, FIRST_EPHEMERAL_PORT)),
+            sender_addr
+        );
+        sender.send_to(b"hello", receiver.addr).unwrap();
+        let mut buffer = [0; 3];
+        assert_eq!((3, sender_addr), receiver.recv_from(&mut buffer).unwrap());
+        assert_eq!(b"hel", &buffer);
+
+        network.set_unreachable(receiver.addr, true);
+        assert_eq!(
+            io::ErrorKind::ConnectionRefused,
+            sender.send_to(b"hello", receiver.addr).unwrap_err().kind()
+        );
+
+        // Unbound on drop, so the port can be bound again
+        let addr = receiver.addr;
+        drop(receiver);
+        network.bind(addr).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+//! Socket options not covered by the standard library
+//!
+//! Some options only take effect before binding, so sockets are created unbound
+//! through the C library. Only available on Linux.
+
+#![cfg(target_os = "linux")]
+
+use std::{
+    ffi::{c_int, c_void},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+pub(crate) const SOL_SOCKET: c_int = 1;
+pub(crate) const SO_REUSEADDR: c_int = 2;
+pub(crate) const SO_SNDBUF: c_int = 7;
+pub(crate) const SO_RCVBUF: c_int = 8;
+pub(crate) const SO_NO_CHECK: c_int = 11;
+pub(crate) const SO_BINDTODEVICE: c_int = 25;
+pub(crate) const SO_MARK: c_int = 36;
+const IPPROTO_IP: c_int = 0;
+const IP_ADD_MEMBERSHIP: c_int = 35;
+const IP_ADD_SOURCE_MEMBERSHIP: c_int = 39;
+const MCAST_JOIN_SOURCE_GROUP: c_int = 46;
+pub(crate) const IPPROTO_IPV6: c_int = 41;
+pub(crate) const IPV6_UNICAST_HOPS: c_int = 16;
+
+const AF_INET: c_int = 2;
+const AF_INET6: c_int = 10;
+const SOCK_DGRAM: c_int = 2;
+const SOCK_CLOEXEC: c_int = 0o2000000;
+
+/// `struct sockaddr_in`
+#[repr(C)]
+struct SockaddrIn {
+    sin_family: u16,
+    sin_port: [u8; 2],
+    sin_addr: [u8; 4],
+    sin_zero: [u8; 8],
+}
+
+/// `struct sockaddr_in6`
+#[repr(C)]
+struct SockaddrIn6 {
+    sin6_family: u16,
+    sin6_port: [u8; 2],
+    sin6_flowinfo: u32,
+    sin6_addr: [u8; 16],
+    sin6_scope_id: u32,
+}
+
+unsafe extern "C" {
+    fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+    fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, len: u32) -> c_int;
+    fn getsockopt(fd: c_int, level: c_int, name: c_int, value: *mut c_void, len: *mut u32)
+    -> c_int;
+    fn bind(fd: c_int, addr: *const c_void, len: u32) -> c_int;
+}
+
+/// Create a UDP socket of the IP family of `addr` without binding it
+pub(crate) fn unbound(addr: &SocketAddr) -> Result<UdpSocket, io::Error> {
+    let domain = match addr {
+        SocketAddr::V4(_) => AF_INET,
+        SocketAddr::V6(_) => AF_INET6,
+    };
+    // SAFETY: Plain syscall without memory arguments
+    let fd = unsafe { socket(domain, SOCK_DGRAM | SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a new socket owned by nothing else
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    Ok(UdpSocket::from(fd))
+}
+
+/// `struct ip_mreqn`
+#[repr(C)]
+struct IpMreqn {
+    imr_multiaddr: [u8; 4],
+    imr_address: [u8; 4],
+    imr_ifindex: c_int,
+}
+
+/// Join the IPv4 multicast group `group` on the interface with index `ifindex`
+///
+/// Unlike [UdpSocket::join_multicast_v4], this selects the interface by index rather
+/// than by local address, so interfaces can be named.
+pub(crate) fn join_multicast_v4(
+    socket: &UdpSocket,
+    group: &Ipv4Addr,
+    ifindex: c_int,
+) -> Result<(), io::Error> {
+    let request = IpMreqn {
+        imr_multiaddr: group.octets(),
+        imr_address: [0; 4],
+        imr_ifindex: ifindex,
+    };
+    set_struct(socket, IPPROTO_IP, IP_ADD_MEMBERSHIP, &request)
+}
+
+/// `struct ip_mreq_source`
+#[repr(C)]
+struct IpMreqSource {
+    imr_multiaddr: [u8; 4],
+    imr_interface: [u8; 4],
+    imr_sourceaddr: [u8; 4],
+}
+
+/// Join the IPv4 multicast group `group` on the interface with local address
+/// `interface`, receiving only the packets from `source`
+pub(crate) fn join_source_v4(
+    socket: &UdpSocket,
+    group: &Ipv4Addr,
+    interface: &Ipv4Addr,
+    source: &Ipv4Addr,
+) -> Result<(), io::Error> {
+    let request = IpMreqSource {
+        imr_multiaddr: group.octets(),
+        imr_interface: interface.octets(),
+        imr_sourceaddr: source.octets(),
+    };
+    set_struct(socket, IPPROTO_IP, IP_ADD_SOURCE_MEMBERSHIP, &request)
+}
+
+/// `struct sockaddr_storage`
+#[repr(C, align(8))]
+struct SockaddrStorage([u8; 128]);
+
+impl SockaddrStorage {
+    fn new(ip: IpAddr) -> Self {
+        let mut storage = [0; 128];
+        match ip {
+            IpAddr::V4(ip) => {
+                storage[..2].copy_from_slice(&(AF_INET as u16).to_ne_bytes());
+                storage[4..8].copy_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                storage[..2].copy_from_slice(&(AF_INET6 as u16).to_ne_bytes());
+                storage[8..24].copy_from_slice(&ip.octets());
+            }
+        }
+        Self(storage)
+    }
+}
+
+/// `struct group_source_req`
+#[repr(C)]
+struct GroupSourceReq {
+    gsr_interface: u32,
+    gsr_group: SockaddrStorage,
+    gsr_source: SockaddrStorage,
+}
+
+/// Join the multicast group `group` on the interface with index `ifindex`, receiving
+/// only the packets from `source`, which must be of the same IP family
+pub(crate) fn join_source_group(
+    socket: &UdpSocket,
+    group: IpAddr,
+    source: IpAddr,
+    ifindex: u32,
+) -> Result<(), io::Error> {
+    let level = match group {
+        IpAddr::V4(_) => IPPROTO_IP,
+        IpAddr::V6(_) => IPPROTO_IPV6,
+    };
+    let request = GroupSourceReq {
+        gsr_interface: ifindex,
+        gsr_group: SockaddrStorage::new(group),
+        gsr_source: SockaddrStorage::new(source),
+    };
+    set_struct(socket, level, MCAST_JOIN_SOURCE_GROUP, &request)
+}
+
+/// Bind an unbound socket to `addr`
+pub(crate) fn bind_to(socket: &UdpSocket, addr: &SocketAddr) -> Result<(), io::Error> {
+    let result = match addr {
+        SocketAddr::V4(addr) => {
+            let addr = SockaddrIn {
+                sin_family: AF_INET as u16,
+                sin_port: addr.port().to_be_bytes(),
+                sin_addr: addr.ip().octets(),
+                sin_zero: [0; 8],
+            };
+            // SAFETY: The address is valid for the given length
+            unsafe {
+                bind(
+                    socket.as_raw_fd(),
+                    &addr as *const SockaddrIn as *const c_void,
+                    size_of::<SockaddrIn>() as u32,
+                )
+            }
+        }
+        SocketAddr::V6(addr) => {
+            let addr = SockaddrIn6 {
+                sin6_family: AF_INET6 as u16,
+                sin6_port: addr.port().to_be_bytes(),
+                sin6_flowinfo: addr.flowinfo().to_be(),
+                sin6_addr: addr.ip().octets(),
+                sin6_scope_id: addr.scope_id(),
+            };
+            // SAFETY: The address is valid for the given length
+            unsafe {
+                bind(
+                    socket.as_raw_fd(),
+                    &addr as *const SockaddrIn6 as *const c_void,
+                    size_of::<SockaddrIn6>() as u32,
+                )
+            }
+        }
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Set an integer socket option
+pub(crate) fn set_int(
+    socket: &impl AsRawFd,
+    level: c_int,
+    name: c_int,
+    value: c_int,
+) -> Result<(), io::Error> {
+    set_bytes(socket, level, name, &value.to_ne_bytes())
+}
+
+/// Set a socket option with a raw value, e.g. a device name
+pub(crate) fn set_bytes(
+    socket: &impl AsRawFd,
+    level: c_int,
+    name: c_int,
+    value: &[u8],
+) -> Result<(), io::Error> {
+    // SAFETY: The option value is valid for the given length
+    unsafe {
+        set_raw(
+            socket,
+            level,
+            name,
+            value.as_ptr() as *const c_void,
+            value.len(),
+        )
+    }
+}
+
+/// Set a socket option taking a C struct, e.g. a BPF program
+pub(crate) fn set_struct<T>(
+    socket: &impl AsRawFd,
+    level: c_int,
+    name: c_int,
+    value: &T,
+) -> Result<(), io::Error> {
+    // SAFETY: The option value is valid for its size
+    unsafe {
+        set_raw(
+            socket,
+            level,
+            name,
+            value as *const T as *const c_void,
+            size_of::<T>(),
+        )
+    }
+}
+
+/// Get a socket option returning a C struct, e.g. ring offsets
+pub(crate) fn get_struct<T: Default>(
+    socket: &impl AsRawFd,
+    level: c_int,
+    name: c_int,
+) -> Result<T, io::Error> {
+    let mut value = T::default();
+    let mut len = size_of::<T>() as u32;
+    // SAFETY: The option value is valid for writes of its size
+    let result = unsafe {
+        getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut value as *mut T as *mut c_void,
+            &mut len,
+        )
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(value),
+    }
+}
+
+/// Set a socket option
+///
+/// # Safety
+///
+/// `value` must be valid for reads of `len` bytes.
+unsafe fn set_raw(
+    socket: &impl AsRawFd,
+    level: c_int,
+    name: c_int,
+    value: *const c_void,
+    len: usize,
+) -> Result<(), io::Error> {
+    // SAFETY: Guaranteed by the caller
+    let result = unsafe { setsockopt(socket.as_raw_fd(), level, name, value, len as u32) };
+    match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
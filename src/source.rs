@@ -0,0 +1,425 @@
+//! Pluggable inputs
+//!
+//! The forwarder receives packets from a [Source], usually the listener socket.
+//! Other sources drive the same forwarding and fan-out from non-socket inputs,
+//! e.g. replaying a pcap capture, lines on stdin or packets of another part of the
+//! application. Relaying back to peers in hub mode and rendezvous need a socket.
+
+#[cfg(feature = "pcap")]
+use std::{fs::File, io::Read, path::Path};
+use std::{
+    io::{self, BufRead, BufReader},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError},
+    time::{Duration, SystemTime},
+};
+
+use crate::Packet;
+
+/// Input the forwarder receives packets from
+pub trait Source: Send {
+    /// Receive the next packet into `buffer`, returning its length and sender
+    ///
+    /// Packets larger than the buffer are truncated. Returns `Ok(None)` once the
+    /// source is exhausted and a timeout error if nothing arrived within the read timeout.
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error>;
+
+    /// Limit the time [Source::recv] waits for a packet, if the source supports waiting
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// Let [Source::recv] return a `WouldBlock` error instead of waiting for a packet
+    ///
+    /// Sources reading files never wait and ignore this.
+    fn set_nonblocking(&mut self, _nonblocking: bool) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    /// Socket to send to peers from, if the source is one
+    fn socket(&self) -> Option<&UdpSocket> {
+        None
+    }
+
+    /// Time the kernel or network card received the last packet, if the source knows it
+    ///
+    /// The forwarder takes the time it read the packet at otherwise.
+    fn arrival(&self) -> Option<SystemTime> {
+        None
+    }
+}
+
+impl Source for UdpSocket {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        self.recv_from(buffer).map(Some)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        UdpSocket::set_nonblocking(self, nonblocking)
+    }
+
+    fn socket(&self) -> Option<&UdpSocket> {
+        Some(self)
+    }
+}
+
+impl<S: Source + ?Sized> Source for Box<S> {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        (**self).recv(buffer)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        (**self).set_nonblocking(nonblocking)
+    }
+
+    fn socket(&self) -> Option<&UdpSocket> {
+        (**self).socket()
+    }
+
+    fn arrival(&self) -> Option<SystemTime> {
+        (**self).arrival()
+    }
+}
+
+/// Receives the packets of another part of the application through a channel
+///
+/// The source is exhausted once all senders are dropped.
+#[derive(Debug)]
+pub struct ChannelSource {
+    receiver: Receiver<Packet>,
+    timeout: Option<Duration>,
+    nonblocking: bool,
+}
+
+impl ChannelSource {
+    pub fn new(receiver: Receiver<Packet>) -> Self {
+        Self {
+            receiver,
+            timeout: None,
+            nonblocking: false,
+        }
+    }
+}
+
+impl Source for ChannelSource {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        let packet = match self.timeout {
+            _ if self.nonblocking => match self.receiver.try_recv() {
+                Ok(packet) => packet,
+                Err(TryRecvError::Empty) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => return Ok(None),
+            },
+            None => match self.receiver.recv() {
+                Ok(packet) => packet,
+                Err(_) => return Ok(None),
+            },
+            Some(timeout) => match self.receiver.recv_timeout(timeout) {
+                Ok(packet) => packet,
+                Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+            },
+        };
+
+        Ok(Some((
+            copy_truncated(&packet.payload, buffer),
+            packet.source,
+        )))
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+/// Reads one packet per line, e.g. from stdin
+///
+/// Line endings are not part of the packets, which carry the unspecified address as sender.
+#[derive(Debug)]
+pub struct LineSource<R> {
+    reader: R,
+    line: Vec<u8>,
+}
+
+impl<R: BufRead + Send> LineSource<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl LineSource<BufReader<io::Stdin>> {
+    /// Read packets from stdin
+    pub fn stdin() -> Self {
+        Self::new(BufReader::new(io::stdin()))
+    }
+}
+
+impl<R: BufRead + Send> Source for LineSource<R> {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        self.line.clear();
+        if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+            return Ok(None);
+        }
+        let line = self.line.strip_suffix(b"\n").unwrap_or(&self.line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        Ok(Some((copy_truncated(line, buffer), unspecified)))
+    }
+}
+
+/// Replays the UDP packets of a pcap capture as fast as they can be forwarded
+///
+/// Understands captures of Ethernet, Linux cooked and raw IP links as written by
+/// tcpdump, Wireshark and [crate::sink::PcapSink]. Other packets are skipped.
+#[cfg(feature = "pcap")]
+#[derive(Debug)]
+pub struct PcapSource {
+    reader: BufReader<File>,
+    big_endian: bool,
+    link_type: u32,
+    record: Vec<u8>,
+}
+
+#[cfg(feature = "pcap")]
+impl PcapSource {
+    const LINKTYPE_ETHERNET: u32 = 1;
+    const LINKTYPE_RAW: u32 = 101;
+    const LINKTYPE_LINUX_SLL: u32 = 113;
+    const LINKTYPE_IPV4: u32 = 228;
+    const LINKTYPE_IPV6: u32 = 229;
+    /// Largest snapshot length of tcpdump, guarding against corrupt record lengths
+    const MAX_RECORD_LEN: usize = 262_144;
+
+    /// Open the capture at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [[0; 4]; 6];
+        reader.read_exact(header.as_flattened_mut())?;
+
+        // Microsecond and nanosecond timestamps, which are not used
+        let big_endian = match header[0] {
+            [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => false,
+            [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => true,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a pcap capture",
+                ));
+            }
+        };
+        let mut source = Self {
+            reader,
+            big_endian,
+            link_type: 0,
+            record: Vec::new(),
+        };
+        source.link_type = source.u32(header[5]);
+
+        match source.link_type {
+            Self::LINKTYPE_ETHERNET
+            | Self::LINKTYPE_RAW
+            | Self::LINKTYPE_LINUX_SLL
+            | Self::LINKTYPE_IPV4
+            | Self::LINKTYPE_IPV6 => Ok(source),
+            link_type => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported link type {link_type}"),
+            )),
+        }
+    }
+
+    fn u32(&self, bytes: [u8; 4]) -> u32 {
+        match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    }
+
+    /// IP packet of the current record
+    fn ip_packet(&self) -> Option<&[u8]> {
+        let frame = self.record.as_slice();
+        match self.link_type {
+            Self::LINKTYPE_ETHERNET => {
+                let mut offset = 12;
+                // VLAN tags
+                while matches!(frame.get(offset..offset + 2)?, [0x81, 0x00] | [0x88, 0xa8]) {
+                    offset += 4;
+                }
+                frame.get(offset + 2..)
+            }
+            Self::LINKTYPE_LINUX_SLL => frame.get(16..),
+            _ => Some(frame),
+        }
+    }
+}
+
+#[cfg(feature = "pcap")]
+impl Source for PcapSource {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        loop {
+            let mut header = [[0; 4]; 4];
+            match self.reader.read_exact(header.as_flattened_mut()) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+
+            let len = self.u32(header[2]) as usize;
+            if len > Self::MAX_RECORD_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("pcap record of {len} bytes exceeds the maximum snapshot length"),
+                ));
+            }
+            self.record.resize(len, 0);
+            self.reader.read_exact(&mut self.record)?;
+
+            if let Some((source, payload)) = self.ip_packet().and_then(udp_payload) {
+                return Ok(Some((copy_truncated(payload, buffer), source)));
+            }
+        }
+    }
+}
+
+/// Source address and payload of a UDP packet in an IPv4 or IPv6 packet
+///
+/// Fragments and IPv6 packets with extension headers are not reassembled or followed.
+pub(crate) fn udp_payload(packet: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    udp_datagram(packet).map(|(source, _, payload)| (source, payload))
+}
+
+/// Source and destination address and payload of a UDP packet in an IP packet
+pub(crate) fn udp_datagram(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    const UDP: u8 = 17;
+
+    let (src, dst, udp) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = ((packet[0] & 0x0f) as usize) * 4;
+            let header = packet.get(..20)?;
+            let fragment_offset = u16::from_be_bytes([header[6], header[7]]) & 0x1fff;
+            let more_fragments = header[6] & 0x20 != 0;
+            if header[9] != UDP || fragment_offset != 0 || more_fragments {
+                return None;
+            }
+            let src: [u8; 4] = header[12..16].try_into().ok()?;
+            let dst: [u8; 4] = header[16..20].try_into().ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(src)),
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                packet.get(header_len..)?,
+            )
+        }
+        6 => {
+            let header = packet.get(..40)?;
+            if header[6] != UDP {
+                return None;
+            }
+            let src: [u8; 16] = header[8..24].try_into().ok()?;
+            let dst: [u8; 16] = header[24..40].try_into().ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                &packet[40..],
+            )
+        }
+        _ => return None,
+    };
+
+    let src_port = u16::from_be_bytes([*udp.first()?, *udp.get(1)?]);
+    let dst_port = u16::from_be_bytes([*udp.get(2)?, *udp.get(3)?]);
+    let len = u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize;
+    let payload = udp.get(8..len.clamp(8, udp.len()))?;
+    Some((
+        SocketAddr::new(src, src_port),
+        SocketAddr::new(dst, dst_port),
+        payload,
+    ))
+}
+
+/// Copy as much of `data` as fits into `buffer`
+pub(crate) fn copy_truncated(data: &[u8], buffer: &mut [u8]) -> usize {
+    let len = data.len().min(buffer.len());
+    buffer[..len].copy_from_slice(&data[..len]);
+    len
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, sync::mpsc};
+
+    use super::*;
+
+    #[cfg(feature = "pcap")]
+    #[test]
+    fn pcap_written_by_sink_replayed() {
+        use std::{env, process};
+
+        use crate::sink::{PcapSink, Sink};
+
+        let path = env::temp_dir().join(format!("udpforwarder-replay-{}.pcap", process::id()));
+        let sources: [SocketAddr; 2] = [
+            "10.1.1.10:5000".parse().unwrap(),
+            "[fe80::1]:5001".parse().unwrap(),
+        ];
+
+        let mut sink = PcapSink::create(&path, "224.10.10.10:4000".parse().unwrap()).unwrap();
+        sink.send(sources[0], b"first").unwrap();
+        sink.send(sources[1], b"second").unwrap();
+        drop(sink);
+
+        let mut source = PcapSource::open(&path).unwrap();
+        let mut buffer = [0; 1500];
+        assert_eq!(Some((5, sources[0])), source.recv(&mut buffer).unwrap());
+        assert_eq!(b"first", &buffer[..5]);
+        assert_eq!(Some((6, sources[1])), source.recv(&mut buffer).unwrap());
+        assert_eq!(None, source.recv(&mut buffer).unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn lines_and_channel_received() {
+        let mut lines = LineSource::new(Cursor::new(b"first\r\nsecond".to_vec()));
+        let mut buffer = [0; 4];
+        assert_eq!(4, lines.recv(&mut buffer).unwrap().unwrap().0);
+        assert_eq!(b"firs", &buffer);
+        assert_eq!(4, lines.recv(&mut buffer).unwrap().unwrap().0);
+        assert_eq!(None, lines.recv(&mut buffer).unwrap());
+
+        let (sender, receiver) = mpsc::channel();
+        let mut channel = ChannelSource::new(receiver);
+        channel
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+        assert!(channel.recv(&mut buffer).is_err());
+        sender
+            .send(Packet {
+                source: "127.0.0.1:5000".parse().unwrap(),
+                payload: b"hi".to_vec(),
+            })
+            .unwrap();
+        drop(sender);
+        assert_eq!(
+            Some((2, "127.0.0.1:5000".parse().unwrap())),
+            channel.recv(&mut buffer).unwrap()
+        );
+        assert_eq!(None, channel.recv(&mut buffer).unwrap());
+    }
+}
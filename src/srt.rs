@@ -0,0 +1,1289 @@
+//! SRT sender
+//!
+//! Hands the forwarded packets, usually MPEG-TS, to endpoints speaking SRT (Secure
+//! Reliable Transport), which recovers losses on the public internet by retransmitting
+//! what the receiver reports missing. The forwarder either calls the receiver or listens
+//! for a receiver calling it, and encrypts the stream with AES if a passphrase is given.
+//!
+//! Only what a live sender needs is implemented: the version 5 handshake, data packets
+//! carrying one forwarded packet each, retransmissions on NAK, keepalives and
+//! shutdown. A thread answers the control packets of the receiver and calls it again
+//! after the connection broke.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    hash::{BuildHasher, Hasher, RandomState},
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "encryption")]
+use crate::aes;
+use crate::{sink::Sink, state::SharedState, tools::is_timeout};
+
+/// Time to wait for the receiver to answer the handshake
+pub const TIMEOUT: Duration = Duration::from_secs(3);
+/// Latency of the receiver if not given, the SRT default
+pub const DEFAULT_LATENCY: Duration = Duration::from_millis(120);
+
+/// Largest payload, the MTU of 1500 bytes without IPv4, UDP and SRT headers
+pub const MAX_PAYLOAD: usize = 1456;
+/// Maximum transmission unit announced in the handshake
+const MTU: u32 = 1500;
+/// Packets in flight announced in the handshake and kept for retransmission at most
+const FLOW_WINDOW: usize = 8192;
+
+/// Interval of resending handshake packets until answered
+const HANDSHAKE_RESEND: Duration = Duration::from_millis(250);
+/// Interval of checking for control packets and due keepalives
+const TICK: Duration = Duration::from_millis(100);
+/// Time without sending after which a keepalive is sent
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// Time without hearing from the receiver after which the connection is broken
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time to wait before calling the receiver again after a failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Version of SRT announced in the handshake, 1.5.0
+const SRT_VERSION: u32 = 0x0001_0500;
+/// Handshake type of the first exchange, asking the listener for a cookie
+const INDUCTION: u32 = 1;
+/// Handshake type of the second exchange, agreeing on the connection
+const CONCLUSION: u32 = 0xffff_ffff;
+/// Handshake types from here on reject the connection, adding the reason
+const REJECTION: u32 = 1000;
+/// Extension field of the answer to an induction, marking a version 5 listener
+const HS_MAGIC: u16 = 0x4a17;
+/// Extension field of a version 4 induction, the datagram socket type
+const UDT_DGRAM: u16 = 2;
+
+/// Control packet types
+const HANDSHAKE: u16 = 0;
+const KEEPALIVE: u16 = 1;
+const ACK: u16 = 2;
+const NAK: u16 = 3;
+const SHUTDOWN: u16 = 5;
+const ACKACK: u16 = 6;
+
+/// Handshake extensions, also flagged in the extension field by their bit
+const HSREQ: u16 = 1;
+const HSRSP: u16 = 2;
+const KMREQ: u16 = 3;
+const KMRSP: u16 = 4;
+const HSREQ_FLAG: u16 = 0x1;
+const KMREQ_FLAG: u16 = 0x2;
+
+/// Capabilities announced in the handshake: timestamp-based delivery in both directions,
+/// encryption, dropping too late packets, periodic NAKs and the retransmission flag
+const SRT_FLAGS: u32 = 0x01 | 0x02 | 0x04 | 0x08 | 0x10 | 0x20;
+
+/// Rejection reasons of interest
+const REJECT_BACKLOG: u32 = 5;
+const REJECT_BAD_SECRET: u32 = 10;
+const REJECT_UNSECURE: u32 = 11;
+/// States of the key material in a response reporting a problem
+const KM_BAD_SECRET: u32 = 3;
+const KM_NO_SECRET: u32 = 4;
+
+/// Iterations of PBKDF2 deriving the key-encrypting key from the passphrase
+#[cfg(feature = "encryption")]
+const PBKDF2_ITERATIONS: u32 = 2048;
+/// Length of the salt of the key material
+const SALT_LEN: usize = 16;
+
+/// Mask of 31-bit sequence numbers
+const SEQ_MASK: u32 = 0x7fff_ffff;
+/// Largest message number, they count from 1 in 26 bits
+const MAX_MSGNO: u32 = (1 << 26) - 1;
+
+/// Whether the forwarder calls the receiver or waits for it to call
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SrtMode {
+    /// Connect to the receiver listening on the address
+    #[default]
+    Caller,
+    /// Listen on the address for a receiver, one at a time
+    Listener,
+}
+
+/// SRT receiver, given as `srt://host:port[?mode=listener&passphrase=...&pbkeylen=16&latency=120]`
+///
+/// The host may be left out in listener mode to listen on all addresses.
+/// The latency is in milliseconds, like in the URIs of other SRT tools.
+#[derive(Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SrtTarget {
+    /// Host to call or address to listen on, empty to listen on all addresses
+    pub host: String,
+    /// Port to call or listen on
+    pub port: u16,
+    /// Whether to call or to listen
+    pub mode: SrtMode,
+    /// Passphrase to encrypt the stream with, 10 to 79 characters
+    pub passphrase: Option<String>,
+    /// Length of the AES key in bytes, 16, 24 or 32
+    pub key_len: usize,
+    /// Latency the receiver buffers packets for, at least
+    pub latency: Duration,
+}
+
+impl SrtTarget {
+    /// Resolve the address to call or listen on
+    fn addr(&self) -> Result<SocketAddr, io::Error> {
+        if self.host.is_empty() {
+            return Ok(SocketAddr::from((Ipv4Addr::UNSPECIFIED, self.port)));
+        }
+
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        (host, self.port).to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address for SRT host {}", self.host),
+            )
+        })
+    }
+}
+
+impl FromStr for SrtTarget {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s.strip_prefix("srt://").ok_or(())?;
+        let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (host, port) = authority.rsplit_once(':').ok_or(())?;
+        let mut target = Self {
+            host: host.to_owned(),
+            port: port.parse().map_err(|_| ())?,
+            mode: SrtMode::Caller,
+            passphrase: None,
+            key_len: 16,
+            latency: DEFAULT_LATENCY,
+        };
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            match param.split_once('=').ok_or(())? {
+                ("mode", "caller") => target.mode = SrtMode::Caller,
+                ("mode", "listener") => target.mode = SrtMode::Listener,
+                ("passphrase", passphrase) if (10..=79).contains(&passphrase.len()) => {
+                    target.passphrase = Some(passphrase.to_owned());
+                }
+                ("pbkeylen", len @ ("16" | "24" | "32")) => {
+                    target.key_len = len.parse().map_err(|_| ())?;
+                }
+                ("latency", millis) => {
+                    let millis: u16 = millis.parse().map_err(|_| ())?;
+                    target.latency = Duration::from_millis(millis.into());
+                }
+                _ => return Err(()),
+            }
+        }
+        if target.host.is_empty() && target.mode == SrtMode::Caller {
+            return Err(());
+        }
+
+        Ok(target)
+    }
+}
+
+/// Shows the target without the passphrase
+impl fmt::Debug for SrtTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            SrtMode::Caller => "caller",
+            SrtMode::Listener => "listener",
+        };
+        write!(
+            f,
+            "srt://{}:{}?mode={mode}&latency={}",
+            self.host,
+            self.port,
+            self.latency.as_millis()
+        )?;
+        if self.passphrase.is_some() {
+            write!(f, "&pbkeylen={}", self.key_len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends packets to an SRT receiver
+///
+/// Packets are dropped while no receiver is connected, i.e. while a listener waits
+/// for a caller and while a caller calls the receiver again.
+pub struct SrtSink {
+    socket: UdpSocket,
+    endpoint: Arc<Endpoint>,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SrtSink {
+    /// Call the receiver of `target`, or listen for it
+    ///
+    /// Callers block until the receiver accepted the connection. Spawns a thread
+    /// answering control packets, which records failures in `state`.
+    pub fn connect(target: &SrtTarget, state: Arc<SharedState>) -> Result<Self, io::Error> {
+        let addr = target.addr()?;
+        let socket = match target.mode {
+            SrtMode::Caller => match addr {
+                SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+                SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+            },
+            SrtMode::Listener => UdpSocket::bind(addr)?,
+        };
+        socket.set_read_timeout(Some(TICK))?;
+
+        let key = match &target.passphrase {
+            Some(_) if cfg!(not(feature = "encryption")) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "SRT passphrases require the encryption feature",
+                ));
+            }
+            Some(_) => Some(random_bytes(target.key_len)?),
+            None => None,
+        };
+        let endpoint = Arc::new(Endpoint {
+            addr,
+            mode: target.mode,
+            socket_id: random_u32() & SEQ_MASK | 1,
+            latency: target.latency,
+            passphrase: target.passphrase.clone(),
+            key,
+            start: Instant::now(),
+            cookies: RandomState::new(),
+        });
+
+        let mut connection = Connection::default();
+        if target.mode == SrtMode::Caller {
+            connection.connect(endpoint.call(&socket)?);
+        }
+        let connection = Arc::new(Mutex::new(connection));
+
+        {
+            let socket = socket.try_clone()?;
+            let endpoint = Arc::clone(&endpoint);
+            let connection = Arc::clone(&connection);
+            thread::Builder::new()
+                .name("srt".to_owned())
+                .spawn(move || serve(&socket, &endpoint, &connection, &state))?;
+        }
+
+        Ok(Self {
+            socket,
+            endpoint,
+            connection,
+        })
+    }
+
+    /// Address the sink listens on or sends from
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+
+    /// Address of the connected receiver, if any
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        lock(&self.connection).peer.as_ref().map(|peer| peer.addr)
+    }
+}
+
+impl Sink for SrtSink {
+    fn send(&mut self, _source: SocketAddr, payload: &[u8]) -> Result<(), io::Error> {
+        if payload.len() > MAX_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "payload of {} bytes exceeds the SRT maximum of {MAX_PAYLOAD}",
+                    payload.len()
+                ),
+            ));
+        }
+
+        let mut connection = lock(&self.connection);
+        let Some((addr, packet)) = connection.data_packet(payload, self.endpoint.latency) else {
+            return Ok(());
+        };
+        self.socket.send_to(packet, addr).map(|_| ())
+    }
+}
+
+impl fmt::Display for SrtSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.endpoint.mode {
+            SrtMode::Caller => write!(f, "SRT receiver {}", self.endpoint.addr),
+            SrtMode::Listener => write!(f, "SRT listener {}", self.endpoint.addr),
+        }
+    }
+}
+
+/// Tell the receiver that the stream ended
+impl Drop for SrtSink {
+    fn drop(&mut self) {
+        let mut connection = lock(&self.connection);
+        if let Some(peer) = connection.peer.take() {
+            let packet = control_packet(SHUTDOWN, 0, peer.timestamp(), peer.socket_id, &[0; 4]);
+            let _ = self.socket.send_to(&packet, peer.addr);
+        }
+    }
+}
+
+/// Settings of the sending end, fixed for its lifetime
+struct Endpoint {
+    /// Address to call or listen on
+    addr: SocketAddr,
+    mode: SrtMode,
+    /// ID of the sending end, which the receiver addresses its packets to
+    socket_id: u32,
+    latency: Duration,
+    passphrase: Option<String>,
+    /// Key to encrypt the stream with as caller, listeners take the key of the caller
+    key: Option<Vec<u8>>,
+    /// Time the handshake timestamps count from
+    start: Instant,
+    /// Hasher of the cookies handed to callers
+    cookies: RandomState,
+}
+
+impl Endpoint {
+    /// Call the receiver, returning it once it accepted the connection
+    fn call(&self, socket: &UdpSocket) -> Result<Peer, io::Error> {
+        let initial_seq = random_u32() & SEQ_MASK;
+        let induction = Handshake {
+            version: 4,
+            encryption: 0,
+            extension: UDT_DGRAM,
+            initial_seq,
+            handshake_type: INDUCTION,
+            socket_id: self.socket_id,
+            cookie: 0,
+            peer_ip: self.addr.ip(),
+            extensions: Vec::new(),
+        };
+        let answer = self.exchange(socket, &induction)?;
+        if let Some(reason) = rejection_reason(answer.handshake_type) {
+            return Err(rejection_error(reason));
+        }
+        if answer.extension != HS_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("SRT receiver {} does not speak SRT version 5", self.addr),
+            ));
+        }
+
+        let mut extensions = vec![(HSREQ, hs_extension(self.latency))];
+        let mut cipher = None;
+        let mut extension = HSREQ_FLAG;
+        let mut encryption = 0;
+        if let (Some(passphrase), Some(key)) = (&self.passphrase, &self.key) {
+            let salt: [u8; SALT_LEN] = random_salt();
+            let (Some(km), Some(stream_cipher)) = (
+                encode_key_material(passphrase, &salt, key),
+                StreamCipher::new(key, salt),
+            ) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "SRT stream keys are 16, 24 or 32 bytes long",
+                ));
+            };
+            extensions.push((KMREQ, km));
+            cipher = Some(stream_cipher);
+            extension |= KMREQ_FLAG;
+            // AES-128, AES-192 or AES-256
+            encryption = key.len() as u16 / 8;
+        }
+        let conclusion = Handshake {
+            version: 5,
+            encryption,
+            extension,
+            handshake_type: CONCLUSION,
+            cookie: answer.cookie,
+            extensions,
+            ..induction
+        };
+        let answer = self.exchange(socket, &conclusion)?;
+        if let Some(reason) = rejection_reason(answer.handshake_type) {
+            return Err(rejection_error(reason));
+        }
+        if let Some(state) = answer
+            .extension(KMRSP)
+            .filter(|km| km.len() == 4)
+            .map(|km| u32::from_be_bytes([km[0], km[1], km[2], km[3]]))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                match state {
+                    KM_NO_SECRET => "SRT receiver expects no passphrase".to_owned(),
+                    KM_BAD_SECRET => "SRT receiver rejected the passphrase".to_owned(),
+                    _ => format!("SRT receiver reported key material state {state}"),
+                },
+            ));
+        }
+
+        Ok(Peer::new(
+            self.addr,
+            answer.socket_id,
+            initial_seq,
+            self.start,
+            cipher,
+        ))
+    }
+
+    /// Send a handshake to the receiver until it answers, returning the answer
+    fn exchange(&self, socket: &UdpSocket, handshake: &Handshake) -> Result<Handshake, io::Error> {
+        let timestamp = self.start.elapsed().as_micros() as u32;
+        let packet = control_packet(HANDSHAKE, 0, timestamp, 0, &handshake.encode());
+        let deadline = Instant::now() + TIMEOUT;
+        let mut resend = Instant::now();
+        let mut buffer = [0; MTU as usize];
+
+        while Instant::now() < deadline {
+            if Instant::now() >= resend {
+                socket.send_to(&packet, self.addr)?;
+                resend = Instant::now() + HANDSHAKE_RESEND;
+            }
+            let (len, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if is_timeout(&e) => continue,
+                // Refusals of earlier attempts, the receiver may not be up yet
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(e) => return Err(e),
+            };
+            if from != self.addr {
+                continue;
+            }
+            if let Some((HANDSHAKE, _, cif)) = parse_control(&buffer[..len])
+                && let Some(answer) = Handshake::parse(cif)
+                && (answer.handshake_type == handshake.handshake_type
+                    || rejection_reason(answer.handshake_type).is_some())
+            {
+                return Ok(answer);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("SRT receiver {} did not answer", self.addr),
+        ))
+    }
+
+    /// Answer a handshake of a caller, connecting to it once it concludes
+    fn answer(
+        &self,
+        socket: &UdpSocket,
+        connection: &Mutex<Connection>,
+        from: SocketAddr,
+        handshake: &Handshake,
+    ) -> Result<(), io::Error> {
+        let cookie = self.cookies.hash_one(from) as u32;
+
+        let mut answer = Handshake {
+            version: 5,
+            encryption: 0,
+            extension: HS_MAGIC,
+            initial_seq: handshake.initial_seq,
+            handshake_type: INDUCTION,
+            socket_id: self.socket_id,
+            cookie,
+            peer_ip: from.ip(),
+            extensions: Vec::new(),
+        };
+        match handshake.handshake_type {
+            INDUCTION => {}
+            CONCLUSION if handshake.cookie == cookie => {
+                let mut connection = lock(connection);
+                answer.handshake_type = CONCLUSION;
+                answer.extension = 0;
+                match self.accept(&connection, from, handshake) {
+                    Ok(cipher) => {
+                        answer.extension = HSREQ_FLAG;
+                        answer.extensions.push((HSRSP, hs_extension(self.latency)));
+                        if let Some(km) = handshake.extension(KMREQ).filter(|_| cipher.is_some()) {
+                            answer.extension |= KMREQ_FLAG;
+                            answer.extensions.push((KMRSP, km.to_vec()));
+                        }
+                        connection.connect(Peer::new(
+                            from,
+                            handshake.socket_id,
+                            handshake.initial_seq,
+                            self.start,
+                            cipher,
+                        ));
+                    }
+                    Err(reason) => answer.handshake_type = REJECTION + reason,
+                }
+            }
+            _ => return Ok(()),
+        }
+
+        let timestamp = self.start.elapsed().as_micros() as u32;
+        let packet = control_packet(
+            HANDSHAKE,
+            0,
+            timestamp,
+            handshake.socket_id,
+            &answer.encode(),
+        );
+        socket.send_to(&packet, from).map(|_| ())
+    }
+
+    /// Check the conclusion of a caller, returning its stream cipher or the rejection reason
+    fn accept(
+        &self,
+        connection: &Connection,
+        from: SocketAddr,
+        handshake: &Handshake,
+    ) -> Result<Option<StreamCipher>, u32> {
+        if let Some(peer) = &connection.peer
+            && (peer.addr, peer.socket_id) != (from, handshake.socket_id)
+        {
+            return Err(REJECT_BACKLOG);
+        }
+
+        match (&self.passphrase, handshake.extension(KMREQ)) {
+            (None, None) => Ok(None),
+            (Some(passphrase), Some(km)) => decode_key_material(passphrase, km)
+                .and_then(|(salt, key)| StreamCipher::new(&key, salt))
+                .map(Some)
+                .ok_or(REJECT_BAD_SECRET),
+            _ => Err(REJECT_UNSECURE),
+        }
+    }
+}
+
+/// Receiver the sink is connected to
+struct Peer {
+    addr: SocketAddr,
+    /// ID of the receiving end, which packets are addressed to
+    socket_id: u32,
+    /// Time the timestamps of packets count from, the same as for the handshake,
+    /// since the receiver derives the clock of the sender from it
+    start: Instant,
+    /// Time a packet was last received from the receiver
+    last_received: Instant,
+    /// Time a packet was last sent to the receiver
+    last_sent: Instant,
+    /// Sequence number of the next data packet
+    next_seq: u32,
+    /// Message number of the next data packet
+    next_msgno: u32,
+    cipher: Option<StreamCipher>,
+}
+
+impl Peer {
+    fn new(
+        addr: SocketAddr,
+        socket_id: u32,
+        initial_seq: u32,
+        start: Instant,
+        cipher: Option<StreamCipher>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            addr,
+            socket_id,
+            start,
+            last_received: now,
+            last_sent: now,
+            next_seq: initial_seq,
+            next_msgno: 1,
+            cipher,
+        }
+    }
+
+    /// Timestamp of packets sent now, in microseconds
+    fn timestamp(&self) -> u32 {
+        self.start.elapsed().as_micros() as u32
+    }
+}
+
+/// State of the connection, shared by the sink and the thread answering control packets
+#[derive(Default)]
+struct Connection {
+    /// Connected receiver, if any
+    peer: Option<Peer>,
+    /// Data packets sent, with their sequence number and time, kept for retransmission
+    sent: VecDeque<(u32, Instant, Vec<u8>)>,
+}
+
+impl Connection {
+    /// Start sending to `peer`, forgetting the previous receiver
+    fn connect(&mut self, peer: Peer) {
+        self.peer = Some(peer);
+        self.sent.clear();
+    }
+
+    /// Build the next data packet carrying `payload`, returning it with the receiver
+    ///
+    /// Packets are kept for twice the latency, retransmissions after that arrive too late
+    /// for the receiver to play them out.
+    fn data_packet(&mut self, payload: &[u8], latency: Duration) -> Option<(SocketAddr, &[u8])> {
+        let peer = self.peer.as_mut()?;
+        let seq = peer.next_seq;
+        peer.next_seq = (seq + 1) & SEQ_MASK;
+        let msgno = peer.next_msgno;
+        peer.next_msgno = msgno % MAX_MSGNO + 1;
+
+        // Solo packet, not necessarily in order, with the even key if encrypted
+        let key_flags = u32::from(peer.cipher.is_some());
+        let mut packet = Vec::with_capacity(16 + payload.len());
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&(0b11 << 30 | key_flags << 27 | msgno).to_be_bytes());
+        packet.extend_from_slice(&peer.timestamp().to_be_bytes());
+        packet.extend_from_slice(&peer.socket_id.to_be_bytes());
+        packet.extend_from_slice(payload);
+        if let Some(cipher) = &peer.cipher {
+            cipher.apply(seq, &mut packet[16..]);
+        }
+
+        let now = Instant::now();
+        peer.last_sent = now;
+        let addr = peer.addr;
+        while self.sent.len() >= FLOW_WINDOW
+            || self
+                .sent
+                .front()
+                .is_some_and(|(_, sent_at, _)| now.duration_since(*sent_at) > latency * 2)
+        {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((seq, now, packet));
+
+        self.sent
+            .back()
+            .map(|(_, _, packet)| (addr, packet.as_slice()))
+    }
+
+    /// Forget the packets the receiver acknowledged, those before `next_seq`
+    fn acknowledged(&mut self, next_seq: u32) {
+        while self
+            .sent
+            .front()
+            .is_some_and(|(seq, _, _)| seq_before(*seq, next_seq))
+        {
+            self.sent.pop_front();
+        }
+    }
+
+    /// Packets to retransmit for a loss report, marked as retransmitted
+    fn lost(&mut self, report: &[u8]) -> Vec<Vec<u8>> {
+        let mut words = report
+            .as_chunks::<4>()
+            .0
+            .iter()
+            .map(|word| u32::from_be_bytes(*word));
+        let mut packets = Vec::new();
+        while let Some(word) = words.next() {
+            // Ranges start with the high bit set and end with the next number
+            let (first, last) = match word & !SEQ_MASK != 0 {
+                true => (word & SEQ_MASK, words.next().unwrap_or(word) & SEQ_MASK),
+                false => (word, word),
+            };
+            for (seq, _, packet) in &mut self.sent {
+                if !seq_before(*seq, first) && !seq_before(last, *seq) {
+                    // Retransmission flag
+                    packet[4] |= 0x04;
+                    packets.push(packet.clone());
+                }
+            }
+        }
+        packets
+    }
+}
+
+/// Answer control packets, send keepalives and call the receiver again after failures
+///
+/// Runs until the sink is dropped.
+fn serve(
+    socket: &UdpSocket,
+    endpoint: &Endpoint,
+    connection: &Arc<Mutex<Connection>>,
+    state: &SharedState,
+) {
+    let mut buffer = [0; MTU as usize];
+    while Arc::strong_count(connection) > 1 {
+        match socket.recv_from(&mut buffer) {
+            Ok((len, from)) => {
+                if let Err(e) = handle(socket, endpoint, connection, from, &buffer[..len]) {
+                    state.record_error(format!("failed to answer SRT peer {from}: {e}"));
+                }
+            }
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::ConnectionRefused => {}
+            Err(e) => {
+                state.record_error(format!("failed to receive from SRT peer: {e}"));
+                thread::sleep(RETRY_INTERVAL);
+            }
+        }
+
+        let mut guard = lock(connection);
+        let Some(peer) = &mut guard.peer else {
+            drop(guard);
+            if endpoint.mode == SrtMode::Caller {
+                match endpoint.call(socket) {
+                    Ok(peer) => lock(connection).connect(peer),
+                    Err(e) => {
+                        state.record_error(format!(
+                            "failed to call SRT receiver {}: {e}",
+                            endpoint.addr
+                        ));
+                        thread::sleep(RETRY_INTERVAL);
+                    }
+                }
+            }
+            continue;
+        };
+        if peer.last_received.elapsed() > PEER_TIMEOUT {
+            state.record_error(format!("SRT peer {} timed out", peer.addr));
+            guard.peer = None;
+        } else if peer.last_sent.elapsed() >= KEEPALIVE_INTERVAL {
+            peer.last_sent = Instant::now();
+            let packet = control_packet(KEEPALIVE, 0, peer.timestamp(), peer.socket_id, &[0; 4]);
+            if let Err(e) = socket.send_to(&packet, peer.addr) {
+                state.record_error(format!(
+                    "failed to send keepalive to SRT peer {}: {e}",
+                    peer.addr
+                ));
+            }
+        }
+    }
+}
+
+/// Handle a packet received from `from`
+fn handle(
+    socket: &UdpSocket,
+    endpoint: &Endpoint,
+    connection: &Mutex<Connection>,
+    from: SocketAddr,
+    packet: &[u8],
+) -> Result<(), io::Error> {
+    let Some((kind, type_info, cif)) = parse_control(packet) else {
+        // Data sent back by the receiver is of no interest
+        return Ok(());
+    };
+    if kind == HANDSHAKE {
+        return match (endpoint.mode, Handshake::parse(cif)) {
+            (SrtMode::Listener, Some(handshake)) => {
+                endpoint.answer(socket, connection, from, &handshake)
+            }
+            _ => Ok(()),
+        };
+    }
+
+    let mut connection = lock(connection);
+    let Some(peer) = connection.peer.as_mut().filter(|peer| peer.addr == from) else {
+        return Ok(());
+    };
+    peer.last_received = Instant::now();
+    let (timestamp, socket_id) = (peer.timestamp(), peer.socket_id);
+
+    match kind {
+        ACK => {
+            if let Some(next_seq) = cif.first_chunk::<4>() {
+                connection.acknowledged(u32::from_be_bytes(*next_seq) & SEQ_MASK);
+            }
+            // Full acknowledgements are confirmed to let the receiver measure the RTT
+            if cif.len() > 4 {
+                let packet = control_packet(ACKACK, type_info, timestamp, socket_id, &[0; 4]);
+                socket.send_to(&packet, from)?;
+            }
+        }
+        NAK => {
+            for packet in connection.lost(cif) {
+                socket.send_to(&packet, from)?;
+            }
+        }
+        SHUTDOWN => connection.peer = None,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Handshake, the control information of handshake packets
+#[derive(Debug, Clone)]
+struct Handshake {
+    version: u32,
+    /// Cipher and key length, 2 to 4 for AES-128 to AES-256
+    encryption: u16,
+    /// Flags of the extensions, or [HS_MAGIC] and [UDT_DGRAM] in inductions
+    extension: u16,
+    /// Sequence number of the first data packet
+    initial_seq: u32,
+    handshake_type: u32,
+    /// ID of the sending end
+    socket_id: u32,
+    /// Cookie of the listener, echoed by the caller
+    cookie: u32,
+    /// Address of the other end as seen by this end
+    peer_ip: IpAddr,
+    /// Extensions with their type and contents
+    extensions: Vec<(u16, Vec<u8>)>,
+}
+
+impl Handshake {
+    fn encode(&self) -> Vec<u8> {
+        let mut cif = Vec::with_capacity(48);
+        cif.extend_from_slice(&self.version.to_be_bytes());
+        cif.extend_from_slice(&self.encryption.to_be_bytes());
+        cif.extend_from_slice(&self.extension.to_be_bytes());
+        cif.extend_from_slice(&self.initial_seq.to_be_bytes());
+        cif.extend_from_slice(&MTU.to_be_bytes());
+        cif.extend_from_slice(&(FLOW_WINDOW as u32).to_be_bytes());
+        cif.extend_from_slice(&self.handshake_type.to_be_bytes());
+        cif.extend_from_slice(&self.socket_id.to_be_bytes());
+        cif.extend_from_slice(&self.cookie.to_be_bytes());
+        match self.peer_ip {
+            IpAddr::V4(ip) => {
+                cif.extend_from_slice(&ip.octets());
+                cif.extend_from_slice(&[0; 12]);
+            }
+            IpAddr::V6(ip) => cif.extend_from_slice(&ip.octets()),
+        }
+
+        for (kind, contents) in &self.extensions {
+            let num_words = contents.len().div_ceil(4);
+            cif.extend_from_slice(&kind.to_be_bytes());
+            cif.extend_from_slice(&(num_words as u16).to_be_bytes());
+            cif.extend_from_slice(contents);
+            cif.resize(cif.len() + num_words * 4 - contents.len(), 0);
+        }
+        cif
+    }
+
+    fn parse(cif: &[u8]) -> Option<Self> {
+        let word = |idx: usize| -> Option<u32> {
+            cif.get(idx * 4..idx * 4 + 4)
+                .map(|word| u32::from_be_bytes(word.try_into().unwrap()))
+        };
+        let ip: [u8; 16] = cif.get(32..48)?.try_into().ok()?;
+        let peer_ip = match ip[4..] == [0; 12] {
+            true => IpAddr::from([ip[0], ip[1], ip[2], ip[3]]),
+            false => IpAddr::from(ip),
+        };
+
+        let mut extensions = Vec::new();
+        let mut rest = &cif[48..];
+        while let Some((header, contents)) = rest.split_first_chunk::<4>() {
+            let kind = u16::from_be_bytes([header[0], header[1]]);
+            let len = usize::from(u16::from_be_bytes([header[2], header[3]])) * 4;
+            let contents = contents.get(..len)?;
+            extensions.push((kind, contents.to_vec()));
+            rest = &rest[4 + len..];
+        }
+
+        Some(Self {
+            version: word(0)?,
+            encryption: (word(1)? >> 16) as u16,
+            extension: word(1)? as u16,
+            initial_seq: word(2)? & SEQ_MASK,
+            handshake_type: word(5)?,
+            socket_id: word(6)?,
+            cookie: word(7)?,
+            peer_ip,
+            extensions,
+        })
+    }
+
+    /// Contents of the extension of type `kind`, if present
+    fn extension(&self, kind: u16) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|(extension, _)| *extension == kind)
+            .map(|(_, contents)| contents.as_slice())
+    }
+}
+
+/// Contents of the extension exchanging the SRT version, capabilities and latency
+///
+/// The latency is announced for both directions, the ends agree on the larger one.
+fn hs_extension(latency: Duration) -> Vec<u8> {
+    let millis = latency.as_millis().min(u16::MAX.into()) as u32;
+    let mut contents = Vec::with_capacity(12);
+    contents.extend_from_slice(&SRT_VERSION.to_be_bytes());
+    contents.extend_from_slice(&SRT_FLAGS.to_be_bytes());
+    contents.extend_from_slice(&(millis << 16 | millis).to_be_bytes());
+    contents
+}
+
+/// Reason of a handshake rejecting the connection, `None` for other handshakes
+fn rejection_reason(handshake_type: u32) -> Option<u32> {
+    // Negative types continue the handshake
+    (REJECTION..i32::MAX as u32)
+        .contains(&handshake_type)
+        .then(|| handshake_type - REJECTION)
+}
+
+/// Error for a connection the receiver rejected for `reason`
+fn rejection_error(reason: u32) -> io::Error {
+    match reason {
+        REJECT_BAD_SECRET => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SRT receiver rejected the passphrase",
+        ),
+        REJECT_UNSECURE => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SRT receiver and sender disagree on encryption",
+        ),
+        REJECT_BACKLOG => io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "SRT receiver is busy with another connection",
+        ),
+        _ => io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("SRT receiver rejected the connection for reason {reason}"),
+        ),
+    }
+}
+
+/// Key material message wrapping the stream key with a key derived from the passphrase
+///
+/// Announces the even key for AES in counter mode on a stream of MPEG-TS over SRT.
+/// `None` unless the key is 16, 24 or 32 bytes long.
+#[cfg(feature = "encryption")]
+fn encode_key_material(passphrase: &str, salt: &[u8; SALT_LEN], key: &[u8]) -> Option<Vec<u8>> {
+    let kek = key_encrypting_key(passphrase, salt, key.len());
+    let mut km = Vec::with_capacity(16 + SALT_LEN + key.len() + 8);
+    // Version 1, type key material, signature "HAI", even key
+    km.extend_from_slice(&[0x12, 0x20, 0x29, 0x01]);
+    // Index of the key-encrypting key
+    km.extend_from_slice(&[0; 4]);
+    // AES-CTR, no authentication, MPEG-TS over SRT, reserved
+    km.extend_from_slice(&[2, 0, 2, 0]);
+    // Reserved, salt and key length in words
+    km.extend_from_slice(&[0, 0, (SALT_LEN / 4) as u8, (key.len() / 4) as u8]);
+    km.extend_from_slice(salt);
+    km.extend_from_slice(&aes::wrap(&kek, key)?);
+    Some(km)
+}
+
+/// Salt and even stream key of a key material message, `None` if the passphrase is wrong
+/// or the message malformed
+#[cfg(feature = "encryption")]
+fn decode_key_material(passphrase: &str, km: &[u8]) -> Option<([u8; SALT_LEN], Vec<u8>)> {
+    let header = km.get(..16)?;
+    // Version and type, AES-CTR, salt length
+    if header[0] != 0x12 || header[8] != 2 || usize::from(header[14]) * 4 != SALT_LEN {
+        return None;
+    }
+    let key_len = usize::from(header[15]) * 4;
+    let salt: [u8; SALT_LEN] = km.get(16..16 + SALT_LEN)?.try_into().ok()?;
+    if !matches!(key_len, 16 | 24 | 32) {
+        return None;
+    }
+
+    // Both keys are wrapped together if the message carries the even and the odd key
+    let kek = key_encrypting_key(passphrase, &salt, key_len);
+    let mut keys = aes::unwrap(&kek, &km[16 + SALT_LEN..])?;
+    if keys.len() < key_len {
+        return None;
+    }
+    keys.truncate(key_len);
+    Some((salt, keys))
+}
+
+/// Key wrapping the stream key, derived from the passphrase and the end of the salt
+#[cfg(feature = "encryption")]
+fn key_encrypting_key(passphrase: &str, salt: &[u8; SALT_LEN], key_len: usize) -> Vec<u8> {
+    let mut kek = vec![0; key_len];
+    pbkdf2::pbkdf2_hmac::<sha1::Sha1>(
+        passphrase.as_bytes(),
+        &salt[SALT_LEN - 8..],
+        PBKDF2_ITERATIONS,
+        &mut kek,
+    );
+    kek
+}
+
+/// AES in counter mode with the stream key, the salt and the sequence number forming the IV
+#[cfg(feature = "encryption")]
+struct StreamCipher {
+    ctr: aes::Ctr,
+    salt: [u8; SALT_LEN],
+}
+
+#[cfg(feature = "encryption")]
+impl StreamCipher {
+    /// Cipher with the stream key `key`, `None` unless it is 16, 24 or 32 bytes long
+    fn new(key: &[u8], salt: [u8; SALT_LEN]) -> Option<Self> {
+        Some(Self {
+            ctr: aes::Ctr::new(key)?,
+            salt,
+        })
+    }
+
+    /// Encrypt or decrypt the payload of the data packet with sequence number `seq`
+    fn apply(&self, seq: u32, payload: &mut [u8]) {
+        // The first 112 bits of the salt, the sequence number XOR-ed in before the
+        // block counter in the last 16 bits
+        let mut iv = [0; 16];
+        iv[..14].copy_from_slice(&self.salt[..14]);
+        for (byte, seq_byte) in iv[10..14].iter_mut().zip(seq.to_be_bytes()) {
+            *byte ^= seq_byte;
+        }
+        self.ctr.apply(iv, payload);
+    }
+}
+
+// Without the encryption feature, connecting with a passphrase fails, so there are no
+// stream keys and no ciphers
+#[cfg(not(feature = "encryption"))]
+fn encode_key_material(_passphrase: &str, _salt: &[u8; SALT_LEN], _key: &[u8]) -> Option<Vec<u8>> {
+    unreachable!("SRT stream key without the encryption feature")
+}
+
+#[cfg(not(feature = "encryption"))]
+fn decode_key_material(_passphrase: &str, _km: &[u8]) -> Option<([u8; SALT_LEN], Vec<u8>)> {
+    None
+}
+
+#[cfg(not(feature = "encryption"))]
+struct StreamCipher;
+
+#[cfg(not(feature = "encryption"))]
+impl StreamCipher {
+    fn new(_key: &[u8], _salt: [u8; SALT_LEN]) -> Option<Self> {
+        unreachable!("SRT stream key without the encryption feature")
+    }
+
+    fn apply(&self, _seq: u32, _payload: &mut [u8]) {
+        unreachable!("SRT stream cipher without the encryption feature")
+    }
+}
+
+/// Build a control packet of type `kind` addressed to `socket_id`
+fn control_packet(
+    kind: u16,
+    type_info: u32,
+    timestamp: u32,
+    socket_id: u32,
+    cif: &[u8],
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16 + cif.len());
+    packet.extend_from_slice(&(0x8000 | kind).to_be_bytes());
+    // Subtype
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(&type_info.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&socket_id.to_be_bytes());
+    packet.extend_from_slice(cif);
+    packet
+}
+
+/// Type, type-specific information and control information of a control packet,
+/// `None` for data packets
+fn parse_control(packet: &[u8]) -> Option<(u16, u32, &[u8])> {
+    let (header, cif) = packet.split_first_chunk::<16>()?;
+    if header[0] & 0x80 == 0 {
+        return None;
+    }
+    let kind = u16::from_be_bytes([header[0] & 0x7f, header[1]]);
+    let type_info = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    Some((kind, type_info, cif))
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wrap-around
+fn seq_before(a: u32, b: u32) -> bool {
+    let distance = b.wrapping_sub(a) & SEQ_MASK;
+    distance != 0 && distance < SEQ_MASK / 2
+}
+
+/// Random bytes for keys from the random source of the system
+fn random_bytes(len: usize) -> Result<Vec<u8>, io::Error> {
+    let mut bytes = vec![0; len];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Random number for IDs and sequence numbers
+fn random_u32() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+/// Salt of the key material, which only needs to differ between connections
+fn random_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0; SALT_LEN];
+    for chunk in salt.chunks_mut(8) {
+        chunk.copy_from_slice(&RandomState::new().build_hasher().finish().to_be_bytes());
+    }
+    salt
+}
+
+fn lock(connection: &Mutex<Connection>) -> MutexGuard<'_, Connection> {
+    connection
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn srt_target_ok() {
+        let target: SrtTarget = "srt://:4200?mode=listener&passphrase=0123456789&pbkeylen=32"
+            .parse()
+            .unwrap();
+        assert_eq!("", target.host);
+        assert_eq!(4200, target.port);
+        assert_eq!(SrtMode::Listener, target.mode);
+        assert_eq!(Some("0123456789"), target.passphrase.as_deref());
+        assert_eq!(32, target.key_len);
+        assert_eq!(DEFAULT_LATENCY, target.latency);
+
+        let target: SrtTarget = "srt://srt.example.com:4200?latency=500".parse().unwrap();
+        assert_eq!(SrtMode::Caller, target.mode);
+        assert_eq!(Duration::from_millis(500), target.latency);
+        assert_eq!(
+            "srt://srt.example.com:4200?mode=caller&latency=500",
+            format!("{target:?}")
+        );
+
+        // Callers need a host, passphrases at least 10 characters
+        assert!("srt://:4200".parse::<SrtTarget>().is_err());
+        assert!(
+            "srt://srt.example.com:4200?passphrase=short"
+                .parse::<SrtTarget>()
+                .is_err()
+        );
+        assert!(
+            "srt://srt.example.com:4200?streamid=x"
+                .parse::<SrtTarget>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn key_material_round_trip() {
+        let salt = [7; SALT_LEN];
+        let key = [42; 16];
+        let mut km = encode_key_material("passphrase", &salt, &key).unwrap();
+
+        assert_eq!(16 + SALT_LEN + 24, km.len());
+        assert_eq!(
+            Some((salt, key.to_vec())),
+            decode_key_material("passphrase", &km)
+        );
+        assert_eq!(None, decode_key_material("wrong phrase", &km));
+
+        // Announcing a longer key than the one wrapped
+        km[15] = 32 / 4;
+        km.truncate(16 + SALT_LEN);
+        let kek = key_encrypting_key("passphrase", &salt, 32);
+        km.extend_from_slice(&aes::wrap(&kek, &key).unwrap());
+        assert_eq!(None, decode_key_material("passphrase", &km));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        assert!(seq_before(1, 2));
+        assert!(!seq_before(2, 2));
+        assert!(seq_before(SEQ_MASK, 0));
+        assert!(!seq_before(0, SEQ_MASK));
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn listener_sends_encrypted_and_retransmits() {
+        let target: SrtTarget = "srt://127.0.0.1:0?mode=listener&passphrase=0123456789"
+            .parse()
+            .unwrap();
+        let state = Arc::new(SharedState::new(None, &[]));
+        let mut sink = SrtSink::connect(&target, Arc::clone(&state)).unwrap();
+        let listener_addr = sink.local_addr().unwrap();
+        let source = "10.1.1.10:4000".parse().unwrap();
+
+        // Packets are dropped until a caller connected
+        sink.send(source, b"dropped").unwrap();
+
+        // Receiver calling the sink
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(TICK)).unwrap();
+        let caller = Endpoint {
+            addr: listener_addr,
+            mode: SrtMode::Caller,
+            socket_id: 7,
+            latency: DEFAULT_LATENCY,
+            passphrase: target.passphrase.clone(),
+            key: Some(vec![42; 16]),
+            start: Instant::now(),
+            cookies: RandomState::new(),
+        };
+        let peer = caller.call(&socket).unwrap();
+        assert_eq!(Some(socket.local_addr().unwrap()), sink.peer_addr());
+
+        sink.send(source, b"hello").unwrap();
+        let mut buffer = [0; MTU as usize];
+        let len = socket.recv(&mut buffer).unwrap();
+        let packet = &mut buffer[..len];
+        assert_eq!(peer.next_seq.to_be_bytes(), packet[..4]);
+        assert_eq!(7u32.to_be_bytes(), packet[12..16]);
+        assert_ne!(b"hello", &packet[16..]);
+        peer.cipher
+            .as_ref()
+            .unwrap()
+            .apply(peer.next_seq, &mut packet[16..]);
+        assert_eq!(b"hello", &packet[16..]);
+
+        // Lost packets are sent again, flagged as retransmitted
+        let nak = control_packet(
+            NAK,
+            0,
+            0,
+            sink.endpoint.socket_id,
+            &peer.next_seq.to_be_bytes(),
+        );
+        socket.send_to(&nak, listener_addr).unwrap();
+        let len = socket.recv(&mut buffer).unwrap();
+        assert_eq!(peer.next_seq.to_be_bytes(), buffer[..4]);
+        assert_eq!(0x04, buffer[4] & 0x04);
+        assert_eq!(16 + 5, len);
+
+        // Acknowledged packets are forgotten
+        let ack = control_packet(
+            ACK,
+            1,
+            0,
+            sink.endpoint.socket_id,
+            &(peer.next_seq + 1).to_be_bytes(),
+        );
+        socket.send_to(&ack, listener_addr).unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        while !lock(&sink.connection).sent.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(lock(&sink.connection).sent.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn wrong_passphrase_rejected() {
+        let target: SrtTarget = "srt://127.0.0.1:0?mode=listener&passphrase=0123456789"
+            .parse()
+            .unwrap();
+        let sink = SrtSink::connect(&target, Arc::new(SharedState::new(None, &[]))).unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(TICK)).unwrap();
+        let caller = Endpoint {
+            addr: sink.local_addr().unwrap(),
+            mode: SrtMode::Caller,
+            socket_id: 7,
+            latency: DEFAULT_LATENCY,
+            passphrase: Some("9876543210".to_owned()),
+            key: Some(vec![42; 16]),
+            start: Instant::now(),
+            cookies: RandomState::new(),
+        };
+
+        let error = caller.call(&socket).err().unwrap();
+        assert_eq!(io::ErrorKind::PermissionDenied, error.kind());
+        assert_eq!(None, sink.peer_addr());
+    }
+}
@@ -0,0 +1,729 @@
+//! Shared state of a running forwarder
+//!
+//! The forwarding loop publishes its counters here and picks up changes
+//! to the set of targets, so that other threads can observe and control it
+//! without interrupting the loop.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    alert::RateAlert,
+    delay::DelayStats,
+    diagnostics::Setup,
+    dns::{QueryStats, Question},
+    geoip::Location,
+    jitter::JitterStats,
+    sequence::SequenceCounters,
+    silence::StreamState,
+    stun::Mapping,
+};
+
+/// Number of recent errors to keep
+const MAX_RECENT_ERRORS: usize = 10;
+/// Number of sources to keep statistics for, later sources are not tracked
+pub const MAX_SOURCES: usize = 1024;
+
+/// Packet and byte counter
+#[derive(Debug, Default)]
+pub struct Counter {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Counter {
+    /// Count one packet of the given size
+    pub fn add(&self, num_bytes: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(num_bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Number of packets counted
+    pub fn packets(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes counted
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Forward target with its counters
+#[derive(Debug)]
+pub struct Target {
+    /// Address to forward to
+    pub addr: SocketAddr,
+    /// Packets and bytes sent successfully
+    pub sent: Counter,
+    /// Number of failed sends
+    pub send_errors: AtomicU64,
+    /// Packets and bytes dropped because the send queue of the target was full
+    pub queue_dropped: Counter,
+}
+
+impl Target {
+    fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            sent: Counter::default(),
+            send_errors: AtomicU64::new(0),
+            queue_dropped: Counter::default(),
+        }
+    }
+}
+
+/// Packets and bytes counted until some point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Totals {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl From<&Counter> for Totals {
+    fn from(counter: &Counter) -> Self {
+        Self {
+            packets: counter.packets(),
+            bytes: counter.bytes(),
+        }
+    }
+}
+
+/// Final counters of a target
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TargetStats {
+    pub addr: SocketAddr,
+    /// Packets and bytes sent successfully
+    pub sent: Totals,
+    /// Number of failed sends
+    pub send_errors: u64,
+    /// Packets and bytes dropped because the send queue of the target was full
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub queue_dropped: Totals,
+}
+
+/// Statistics of a forwarder once it stopped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ForwardStats {
+    /// Time spent forwarding
+    pub runtime: Duration,
+    /// Packets and bytes received on the listener
+    pub received: Totals,
+    /// Packets and bytes dropped because they were sent by the forwarder itself
+    pub looped: Totals,
+    /// Packets and bytes dropped because their hop limit was reached
+    pub expired: Totals,
+    /// Packets and bytes dropped by packet handlers
+    pub filtered: Totals,
+    /// Packets and bytes relayed to hub peers, the rendezvous peer, through TURN or as
+    /// replies to clients
+    pub relayed: Totals,
+    /// Packets and bytes dropped while forwarding was paused
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub paused_dropped: Totals,
+    /// Time forwarding was paused
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub paused_time: Duration,
+    /// Targets at the time the forwarder stopped
+    pub targets: Vec<TargetStats>,
+}
+
+impl ForwardStats {
+    /// Packets and bytes sent, summed over all targets
+    pub fn sent(&self) -> Totals {
+        self.targets
+            .iter()
+            .fold(Totals::default(), |sum, target| Totals {
+                packets: sum.packets + target.sent.packets,
+                bytes: sum.bytes + target.sent.bytes,
+            })
+    }
+
+    /// Failed sends, summed over all targets
+    pub fn send_errors(&self) -> u64 {
+        self.targets.iter().map(|target| target.send_errors).sum()
+    }
+
+    /// Number of received packets which were not forwarded
+    pub fn dropped_packets(&self) -> u64 {
+        self.looped.packets
+            + self.expired.packets
+            + self.filtered.packets
+            + self.paused_dropped.packets
+    }
+}
+
+impl fmt::Display for ForwardStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sent = self.sent();
+        write!(
+            f,
+            "received {} packets ({} bytes), sent {} packets ({} bytes) to {} target(s) \
+             with {} send errors, dropped {} packets in {:.1}s",
+            self.received.packets,
+            self.received.bytes,
+            sent.packets,
+            sent.bytes,
+            self.targets.len(),
+            self.send_errors(),
+            self.dropped_packets(),
+            self.runtime.as_secs_f64(),
+        )?;
+        if !self.paused_time.is_zero() {
+            write!(f, ", paused for {:.1}s", self.paused_time.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+/// Named group of targets which is enabled or disabled as a whole
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TargetGroup {
+    /// Label of the group
+    pub name: String,
+    /// Targets belonging to the group
+    pub addrs: Vec<SocketAddr>,
+    /// Whether packets are forwarded to the targets of the group
+    pub enabled: bool,
+}
+
+impl TargetGroup {
+    /// Create an enabled group without targets
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            addrs: Vec::new(),
+            enabled: true,
+        }
+    }
+}
+
+/// Source sending to the listener with its counters
+#[derive(Debug)]
+pub struct Source {
+    /// Address packets are received from
+    pub addr: SocketAddr,
+    /// Country and autonomous system of the address, if looked up
+    pub location: Option<Location>,
+    /// Packets and bytes received from the source
+    pub received: Counter,
+    /// Gaps, resets, reordered and duplicate packets in the sequence of the source, if followed
+    pub sequence: SequenceCounters,
+    /// Questions of DNS messages from the source, if inspected
+    dns: Mutex<QueryStats>,
+}
+
+impl Source {
+    /// Questions of DNS messages from the source
+    ///
+    /// Only counted with DNS inspection enabled.
+    pub fn dns(&self) -> MutexGuard<'_, QueryStats> {
+        lock(&self.dns)
+    }
+
+    /// Count the question of a DNS message from the source
+    pub(crate) fn add_dns_question(&self, question: Question) {
+        lock(&self.dns).add(question);
+    }
+}
+
+/// State shared between the forwarding loop and observers
+#[derive(Debug)]
+pub struct SharedState {
+    /// Time the forwarder was set up
+    started: Instant,
+    /// Local address of the listener
+    listener_addr: Option<SocketAddr>,
+    /// Packets and bytes received on the listener
+    received: Counter,
+    /// Packets and bytes received from the senders themselves and dropped
+    looped: Counter,
+    /// Packets and bytes dropped because their hop limit was reached
+    expired: Counter,
+    /// Packets and bytes dropped by packet handlers
+    filtered: Counter,
+    /// Packets and bytes relayed to hub peers, the rendezvous peer, through TURN or as
+    /// replies to clients
+    relayed: Counter,
+    /// Number of known hub peers
+    num_peers: AtomicUsize,
+    /// Whether forwarding is paused
+    paused: AtomicBool,
+    /// Start of the current pause and total time of the pauses before
+    pause_time: Mutex<(Option<Instant>, Duration)>,
+    /// Packets and bytes dropped while paused
+    paused_dropped: Counter,
+    /// Current set of targets
+    targets: Mutex<Vec<Arc<Target>>>,
+    /// Incremented on every change of the targets
+    generation: AtomicU64,
+    /// Most recent errors with the time they occurred, oldest first
+    recent_errors: Mutex<VecDeque<(SystemTime, String)>>,
+    /// Public addresses of the senders discovered with STUN
+    public_addrs: Mutex<Vec<Mapping>>,
+    /// Sources seen on the listener, in order of their first packet
+    sources: Mutex<Vec<Arc<Source>>>,
+    /// Named groups of targets
+    groups: Mutex<Vec<TargetGroup>>,
+    /// Gaps between packets during the last complete stats interval
+    jitter: Mutex<Option<JitterStats>>,
+    /// One-way delay of the packets during the last complete stats interval
+    delay: Mutex<Option<DelayStats>>,
+    /// Alert on the packet rate while it is outside the expected envelope
+    rate_alert: Mutex<Option<RateAlert>>,
+    /// Whether the stream at the listener is active or silent, if watched
+    stream_state: Mutex<Option<StreamState>>,
+    /// Options and listener socket, if published for diagnostics
+    setup: Mutex<Option<Setup>>,
+    /// Labels attributing the logs and statistics to the stream, like `tenant=teamA`
+    labels: Mutex<Vec<(String, String)>>,
+}
+
+impl SharedState {
+    /// Create the state for a listener and initial targets
+    pub(crate) fn new(listener_addr: Option<SocketAddr>, forward_addrs: &[SocketAddr]) -> Self {
+        Self {
+            started: Instant::now(),
+            listener_addr,
+            received: Counter::default(),
+            looped: Counter::default(),
+            expired: Counter::default(),
+            filtered: Counter::default(),
+            relayed: Counter::default(),
+            num_peers: AtomicUsize::new(0),
+            paused: AtomicBool::new(false),
+            pause_time: Mutex::new((None, Duration::ZERO)),
+            paused_dropped: Counter::default(),
+            targets: Mutex::new(
+                forward_addrs
+                    .iter()
+                    .map(|addr| Arc::new(Target::new(*addr)))
+                    .collect(),
+            ),
+            generation: AtomicU64::new(0),
+            recent_errors: Mutex::new(VecDeque::with_capacity(MAX_RECENT_ERRORS)),
+            public_addrs: Mutex::new(Vec::new()),
+            sources: Mutex::new(Vec::new()),
+            groups: Mutex::new(Vec::new()),
+            jitter: Mutex::new(None),
+            delay: Mutex::new(None),
+            rate_alert: Mutex::new(None),
+            stream_state: Mutex::new(None),
+            setup: Mutex::new(None),
+            labels: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Time since the forwarder was set up
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Local address of the listener, if known
+    pub fn listener_addr(&self) -> Option<SocketAddr> {
+        self.listener_addr
+    }
+
+    /// Packets and bytes received on the listener
+    pub fn received(&self) -> &Counter {
+        &self.received
+    }
+
+    /// Packets and bytes dropped because they were sent by this forwarder
+    pub fn looped(&self) -> &Counter {
+        &self.looped
+    }
+
+    /// Packets and bytes dropped because their hop limit was reached
+    pub fn expired(&self) -> &Counter {
+        &self.expired
+    }
+
+    /// Packets and bytes dropped by packet handlers
+    pub fn filtered(&self) -> &Counter {
+        &self.filtered
+    }
+
+    /// Packets and bytes relayed to hub peers, the rendezvous peer, through TURN or as
+    /// replies to clients
+    pub fn relayed(&self) -> &Counter {
+        &self.relayed
+    }
+
+    /// Number of peers known in hub mode
+    pub fn num_peers(&self) -> usize {
+        self.num_peers.load(Ordering::Relaxed)
+    }
+
+    /// Publish the number of known hub peers
+    pub(crate) fn set_num_peers(&self, num_peers: usize) {
+        self.num_peers.store(num_peers, Ordering::Relaxed);
+    }
+
+    /// Check if forwarding is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Pause or resume forwarding
+    ///
+    /// While paused, received packets are dropped. The listener stays bound and
+    /// multicast groups stay joined.
+    pub fn set_paused(&self, paused: bool) {
+        let mut pause_time = lock(&self.pause_time);
+        let (since, total) = &mut *pause_time;
+        match (paused, *since) {
+            (true, None) => *since = Some(Instant::now()),
+            (false, Some(start)) => {
+                *total += start.elapsed();
+                *since = None;
+            }
+            _ => (),
+        }
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Total time forwarding was paused, including the current pause
+    pub fn paused_time(&self) -> Duration {
+        let (since, total) = *lock(&self.pause_time);
+        total + since.map_or(Duration::ZERO, |since| since.elapsed())
+    }
+
+    /// Packets and bytes dropped while forwarding was paused
+    pub fn paused_dropped(&self) -> &Counter {
+        &self.paused_dropped
+    }
+
+    /// Snapshot of the counters for a forwarder which ran for `runtime`
+    pub(crate) fn forward_stats(&self, runtime: Duration) -> ForwardStats {
+        ForwardStats {
+            runtime,
+            received: self.received().into(),
+            looped: self.looped().into(),
+            expired: self.expired().into(),
+            filtered: self.filtered().into(),
+            relayed: self.relayed().into(),
+            paused_dropped: self.paused_dropped().into(),
+            paused_time: self.paused_time(),
+            targets: self
+                .targets()
+                .iter()
+                .map(|target| TargetStats {
+                    addr: target.addr,
+                    sent: (&target.sent).into(),
+                    send_errors: target.send_errors.load(Ordering::Relaxed),
+                    queue_dropped: (&target.queue_dropped).into(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Current set of targets
+    pub fn targets(&self) -> Vec<Arc<Target>> {
+        self.lock_targets().clone()
+    }
+
+    /// Targets to forward to, i.e. all targets except those of disabled groups
+    pub fn active_targets(&self) -> Vec<Arc<Target>> {
+        let targets = self.lock_targets();
+        let groups = lock(&self.groups);
+
+        targets
+            .iter()
+            .filter(|target| {
+                !groups
+                    .iter()
+                    .any(|group| !group.enabled && group.addrs.contains(&target.addr))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Named groups of targets
+    pub fn groups(&self) -> Vec<TargetGroup> {
+        lock(&self.groups).clone()
+    }
+
+    /// Add a group, merging it into an existing group of the same name
+    pub(crate) fn add_group(&self, group: TargetGroup) {
+        let mut groups = lock(&self.groups);
+        match groups
+            .iter_mut()
+            .find(|existing| existing.name == group.name)
+        {
+            Some(existing) => existing.addrs.extend(group.addrs),
+            None => groups.push(group),
+        }
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Enable or disable forwarding to the targets of a group
+    ///
+    /// Returns `false` if there is no group of that name.
+    pub fn set_group_enabled(&self, name: &str, enabled: bool) -> bool {
+        let mut groups = lock(&self.groups);
+        let Some(group) = groups.iter_mut().find(|group| group.name == name) else {
+            return false;
+        };
+
+        group.enabled = enabled;
+        self.generation.fetch_add(1, Ordering::Release);
+        true
+    }
+
+    /// Add a target, returns `false` if it already exists
+    pub fn add_target(&self, addr: SocketAddr) -> bool {
+        let mut targets = self.lock_targets();
+        if targets.iter().any(|target| target.addr == addr) {
+            return false;
+        }
+
+        targets.push(Arc::new(Target::new(addr)));
+        self.generation.fetch_add(1, Ordering::Release);
+        true
+    }
+
+    /// Remove a target, returns `false` if it does not exist
+    pub fn remove_target(&self, addr: SocketAddr) -> bool {
+        let mut targets = self.lock_targets();
+        let num_targets = targets.len();
+        targets.retain(|target| target.addr != addr);
+        if targets.len() == num_targets {
+            return false;
+        }
+
+        self.generation.fetch_add(1, Ordering::Release);
+        true
+    }
+
+    /// Counter of changes to the targets
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Setup of the forwarder, if published for diagnostics
+    pub fn setup(&self) -> MutexGuard<'_, Option<Setup>> {
+        lock(&self.setup)
+    }
+
+    /// Publish the options and listener socket, reported by diagnostics snapshots
+    pub fn set_setup(&self, setup: Setup) {
+        *lock(&self.setup) = Some(setup);
+    }
+
+    /// Labels of the forwarder in the order they were given
+    pub fn labels(&self) -> Vec<(String, String)> {
+        lock(&self.labels).clone()
+    }
+
+    /// Set the labels reported along with the statistics
+    pub fn set_labels(&self, labels: Vec<(String, String)>) {
+        *lock(&self.labels) = labels;
+    }
+
+    /// Remember an error, dropping the oldest if too many are kept
+    pub fn record_error(&self, error: String) {
+        let mut recent_errors = lock(&self.recent_errors);
+        if recent_errors.len() == MAX_RECENT_ERRORS {
+            recent_errors.pop_front();
+        }
+        recent_errors.push_back((SystemTime::now(), error));
+    }
+
+    /// Most recent errors with the time they occurred, oldest first
+    pub fn recent_errors(&self) -> Vec<(SystemTime, String)> {
+        lock(&self.recent_errors).iter().cloned().collect()
+    }
+
+    /// Public addresses of the senders discovered with STUN
+    pub fn public_addrs(&self) -> Vec<Mapping> {
+        lock(&self.public_addrs).clone()
+    }
+
+    /// Publish the public addresses of the senders
+    pub(crate) fn set_public_addrs(&self, public_addrs: Vec<Mapping>) {
+        *lock(&self.public_addrs) = public_addrs;
+    }
+
+    /// Sources seen on the listener, in order of their first packet
+    pub fn sources(&self) -> Vec<Arc<Source>> {
+        lock(&self.sources).clone()
+    }
+
+    /// Start tracking a new source
+    ///
+    /// Returns `None` once [MAX_SOURCES] are tracked.
+    pub(crate) fn add_source(
+        &self,
+        addr: SocketAddr,
+        location: Option<Location>,
+    ) -> Option<Arc<Source>> {
+        let mut sources = lock(&self.sources);
+        if sources.len() >= MAX_SOURCES {
+            return None;
+        }
+
+        let source = Arc::new(Source {
+            addr,
+            location,
+            received: Counter::default(),
+            sequence: SequenceCounters::default(),
+            dns: Mutex::new(QueryStats::default()),
+        });
+        sources.push(Arc::clone(&source));
+        Some(source)
+    }
+
+    /// Gaps between packets on the listener during the last complete stats interval
+    ///
+    /// `None` until an interval with at least two packets completed.
+    pub fn jitter(&self) -> Option<JitterStats> {
+        *lock(&self.jitter)
+    }
+
+    /// Publish the gaps between packets of a complete stats interval
+    pub(crate) fn set_jitter(&self, jitter: JitterStats) {
+        *lock(&self.jitter) = Some(jitter);
+    }
+
+    /// One-way delay of the packets during the last complete stats interval
+    ///
+    /// `None` unless measuring the delay and an interval with stamped packets completed.
+    pub fn delay(&self) -> Option<DelayStats> {
+        *lock(&self.delay)
+    }
+
+    /// Publish the one-way delay of the packets of a complete stats interval
+    pub(crate) fn set_delay(&self, delay: DelayStats) {
+        *lock(&self.delay) = Some(delay);
+    }
+
+    /// Alert on the packet rate while it is outside the expected envelope
+    ///
+    /// `None` unless rate alerts are enabled and the rate of the last complete stats
+    /// interval was below the minimum or above the maximum.
+    pub fn rate_alert(&self) -> Option<RateAlert> {
+        *lock(&self.rate_alert)
+    }
+
+    /// Publish a change of the packet rate relative to the envelope
+    pub(crate) fn set_rate_alert(&self, alert: RateAlert) {
+        *lock(&self.rate_alert) = match alert {
+            RateAlert::Recovered { .. } => None,
+            alert => Some(alert),
+        };
+    }
+
+    /// Whether the stream at the listener is active or silent
+    ///
+    /// `None` unless the dead-stream alarm is enabled.
+    pub fn stream_state(&self) -> Option<StreamState> {
+        *lock(&self.stream_state)
+    }
+
+    /// Publish a transition of the stream between active and silent
+    pub(crate) fn set_stream_state(&self, state: StreamState) {
+        *lock(&self.stream_state) = Some(state);
+    }
+
+    /// Lock the targets
+    fn lock_targets(&self) -> MutexGuard<'_, Vec<Arc<Target>>> {
+        lock(&self.targets)
+    }
+}
+
+/// Lock a mutex, recovering from a panic of another thread holding the lock
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn add_remove_targets_bumps_generation() {
+        let addr: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let state = SharedState::new(None, &[addr]);
+
+        assert!(!state.add_target(addr));
+        assert_eq!(0, state.generation());
+
+        let other: SocketAddr = "[::1]:4002".parse().unwrap();
+        assert!(state.add_target(other));
+        assert!(state.remove_target(addr));
+        assert!(!state.remove_target(addr));
+        assert_eq!(2, state.generation());
+
+        let targets: Vec<_> = state.targets().iter().map(|target| target.addr).collect();
+        assert_eq!(vec![other], targets);
+    }
+
+    #[test]
+    fn disabled_groups_inactive() {
+        let addrs: Vec<SocketAddr> = vec![
+            "127.0.0.1:4001".parse().unwrap(),
+            "127.0.0.1:4002".parse().unwrap(),
+        ];
+        let state = SharedState::new(None, &addrs);
+        let mut group = TargetGroup::new("lab".to_owned());
+        group.addrs.push(addrs[1]);
+        state.add_group(group);
+
+        assert!(state.set_group_enabled("lab", false));
+        assert!(!state.set_group_enabled("unknown", false));
+        let active: Vec<_> = state
+            .active_targets()
+            .iter()
+            .map(|target| target.addr)
+            .collect();
+        assert_eq!(addrs[..1], active);
+
+        assert!(state.set_group_enabled("lab", true));
+        assert_eq!(2, state.active_targets().len());
+    }
+
+    #[test]
+    fn pauses_timed() {
+        let state = SharedState::new(None, &[]);
+        assert_eq!(Duration::ZERO, state.paused_time());
+
+        state.set_paused(true);
+        std::thread::sleep(Duration::from_millis(20));
+        // Pausing again doesn't restart the pause
+        state.set_paused(true);
+        state.set_paused(false);
+        let paused_time = state.paused_time();
+        assert!(paused_time >= Duration::from_millis(20));
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(paused_time, state.paused_time());
+    }
+
+    #[test]
+    fn recent_errors_bounded() {
+        let state = SharedState::new(None, &[]);
+
+        for idx in 0..(MAX_RECENT_ERRORS + 2) {
+            state.record_error(format!("error {idx}"));
+        }
+
+        let errors = state.recent_errors();
+        assert_eq!(MAX_RECENT_ERRORS, errors.len());
+        assert_eq!("error 2", errors[0].1);
+    }
+}
@@ -0,0 +1,183 @@
+//! Async packet stream
+//!
+//! [PacketStream] yields the packets received on a listener as [futures_core::Stream],
+//! so async applications consume the listener without the built-in fan-out.
+//! A thread receives in the background and wakes the task polling the stream,
+//! which works with any async runtime. Only available with the `async` feature.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::{SocketAddr, UdpSocket},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use futures_core::Stream;
+
+use crate::{ListenerBuilder, ListenerSpec, tools::is_timeout};
+
+/// Packets buffered for a stream which is not polled fast enough
+const QUEUE_CAPACITY: usize = 4096;
+/// Interval of checking whether the stream was dropped
+const CLOSE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Largest payload of a UDP datagram over IPv4
+const MAX_DATAGRAM: usize = 65_507;
+
+/// Stream of the packets received on a listener with their source
+///
+/// Packets arriving while the queue is full are dropped and counted.
+/// The stream ends when receiving fails, [PacketStream::take_error] tells why.
+pub struct PacketStream {
+    shared: Arc<Mutex<Shared>>,
+}
+
+/// State shared with the receiving thread
+#[derive(Default)]
+struct Shared {
+    queue: VecDeque<(SocketAddr, Bytes)>,
+    waker: Option<Waker>,
+    dropped: u64,
+    error: Option<io::Error>,
+    /// Set by the receiving thread when it stopped, by the stream when dropped
+    closed: bool,
+}
+
+impl PacketStream {
+    /// Bind the listener and start receiving
+    pub fn new(listener_spec: ListenerSpec) -> Result<Self, io::Error> {
+        let socket = ListenerBuilder::new(&listener_spec).bind()?;
+        socket.set_read_timeout(Some(CLOSE_POLL_INTERVAL))?;
+        let shared = Arc::new(Mutex::new(Shared::default()));
+
+        let receiver = Arc::clone(&shared);
+        thread::Builder::new()
+            .name("packet-stream".to_owned())
+            .spawn(move || receive(&socket, &receiver))?;
+
+        Ok(Self { shared })
+    }
+
+    /// Number of packets dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        lock(&self.shared).dropped
+    }
+
+    /// Error which ended the stream, if any
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        lock(&self.shared).error.take()
+    }
+}
+
+impl Stream for PacketStream {
+    type Item = (SocketAddr, Bytes);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut shared = lock(&self.shared);
+        if let Some(packet) = shared.queue.pop_front() {
+            return Poll::Ready(Some(packet));
+        }
+        if shared.closed {
+            return Poll::Ready(None);
+        }
+
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (lock(&self.shared).queue.len(), None)
+    }
+}
+
+impl Drop for PacketStream {
+    fn drop(&mut self) {
+        lock(&self.shared).closed = true;
+    }
+}
+
+/// Receive into the queue until receiving fails or the stream is dropped
+fn receive(socket: &UdpSocket, shared: &Mutex<Shared>) {
+    let mut buffer = vec![0; MAX_DATAGRAM];
+
+    loop {
+        let received = socket.recv_from(&mut buffer);
+
+        let mut shared = lock(shared);
+        if shared.closed {
+            return;
+        }
+        match received {
+            Ok((num_bytes, source)) => match shared.queue.len() < QUEUE_CAPACITY {
+                true => {
+                    let payload = Bytes::copy_from_slice(&buffer[..num_bytes]);
+                    shared.queue.push_back((source, payload));
+                }
+                false => shared.dropped += 1,
+            },
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => {
+                shared.error = Some(e);
+                shared.closed = true;
+            }
+        }
+
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+        if shared.closed {
+            return;
+        }
+    }
+}
+
+/// Lock the shared state, recovering from a panic of the other side
+fn lock(shared: &Mutex<Shared>) -> std::sync::MutexGuard<'_, Shared> {
+    shared
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::mpsc, task::Wake};
+
+    use super::*;
+
+    /// Waker signalling a channel
+    struct ChannelWaker(mpsc::Sender<()>);
+
+    impl Wake for ChannelWaker {
+        fn wake(self: Arc<Self>) {
+            let _ = self.0.send(());
+        }
+    }
+
+    #[test]
+    fn received_packets_streamed() {
+        let listener_addr: SocketAddr = "127.0.0.1:4400".parse().unwrap();
+        let mut stream = PacketStream::new(ListenerSpec::Unicast(listener_addr)).unwrap();
+        let (sender, woken) = mpsc::channel();
+        let waker = Waker::from(Arc::new(ChannelWaker(sender)));
+        let mut cx = Context::from_waker(&waker);
+
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.send_to(b"hello", listener_addr).unwrap();
+        woken.recv_timeout(Duration::from_secs(1)).unwrap();
+
+        match Pin::new(&mut stream).poll_next(&mut cx) {
+            Poll::Ready(Some((source, payload))) => {
+                assert_eq!(socket.local_addr().unwrap(), source);
+                assert_eq!(&b"hello"[..], payload);
+            }
+            _ => panic!("packet not streamed"),
+        }
+        assert_eq!(0, stream.dropped());
+    }
+}
@@ -0,0 +1,414 @@
+//! Public address discovery with STUN
+//!
+//! Sends STUN binding requests (RFC 5389) from the sender sockets to learn the public
+//! address a NAT maps them to, i.e. what remote peers see and must send to.
+//! Asking more than one server also reveals whether the NAT keeps the mapping
+//! for all destinations or creates one per destination.
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crate::tools::is_timeout;
+
+/// Time to wait for the answer of each STUN server
+pub const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Interval between retransmissions of a request
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Magic cookie of STUN messages
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+/// Message type of a binding request
+const BINDING_REQUEST: u16 = 0x0001;
+/// Attribute with the mapped address in plain
+const MAPPED_ADDRESS: u16 = 0x0001;
+/// Attribute with the mapped address obfuscated by the magic cookie and transaction ID
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+/// Length of the message header
+const HEADER_LEN: usize = 20;
+
+/// Transaction ID matching responses to requests
+pub(crate) type TransactionId = [u8; 12];
+
+/// Behavior of the NAT in front of a sender
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NatType {
+    /// The public address is the local address
+    Open,
+    /// All servers see the same public address, so peers can reach it once it is open
+    Cone,
+    /// Each server sees a different public address, so hole punching is unlikely to work
+    Symmetric,
+    /// The address is translated, but only one server answered to tell more
+    Unknown,
+}
+
+impl fmt::Display for NatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Open => "no NAT",
+            Self::Cone => "cone NAT",
+            Self::Symmetric => "symmetric NAT",
+            Self::Unknown => "NAT of unknown type",
+        })
+    }
+}
+
+/// Public address of a sender as seen by STUN servers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mapping {
+    /// Local address of the sender
+    pub local: SocketAddr,
+    /// Address the first answering server saw packets of the sender coming from
+    pub public: SocketAddr,
+    /// Behavior of the NAT in between
+    pub nat: NatType,
+}
+
+/// Resolve STUN servers given as `host:port`
+pub fn resolve(servers: &[String]) -> Result<Vec<SocketAddr>, io::Error> {
+    let mut addrs = Vec::new();
+    for server in servers {
+        addrs.extend(server.to_socket_addrs()?);
+    }
+
+    Ok(addrs)
+}
+
+/// Ask each of `servers` for the public address of `socket`
+///
+/// `local` is the address the socket sends from, to tell if the address is translated.
+/// Fails if no server answered within `timeout`.
+pub(crate) fn discover(
+    socket: &UdpSocket,
+    local: SocketAddr,
+    servers: &[SocketAddr],
+    timeout: Duration,
+) -> Result<Mapping, io::Error> {
+    let mut mapped = Vec::new();
+    for server in servers {
+        match query(socket, *server, timeout) {
+            Ok(addr) => mapped.push(addr),
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let Some(public) = mapped.first().copied() else {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no STUN server answered",
+        ));
+    };
+
+    Ok(Mapping {
+        local,
+        public,
+        nat: classify(local, &mapped),
+    })
+}
+
+/// Tell the NAT behavior from the addresses mapped by different servers
+fn classify(local: SocketAddr, mapped: &[SocketAddr]) -> NatType {
+    match mapped {
+        [public, ..] if *public == local => NatType::Open,
+        [_] | [] => NatType::Unknown,
+        [public, others @ ..] if others.iter().all(|addr| addr == public) => NatType::Cone,
+        _ => NatType::Symmetric,
+    }
+}
+
+/// Send a binding request to `server` until it answers or `timeout` passes
+fn query(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<SocketAddr, io::Error> {
+    let transaction_id = transaction_id();
+    let request = encode_message(BINDING_REQUEST, &transaction_id, &[], 0);
+    let response = transact(socket, server, &request, &transaction_id, timeout)?;
+
+    Message::parse(&response)
+        .and_then(|response| response.mapped_address())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no mapped address in answer of STUN server {server}"),
+            )
+        })
+}
+
+/// Send `request` to `server` until the response to the transaction arrives or `timeout` passes
+///
+/// Other packets received meanwhile are dropped.
+pub(crate) fn transact(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    request: &[u8],
+    transaction_id: &TransactionId,
+    timeout: Duration,
+) -> Result<Vec<u8>, io::Error> {
+    socket.set_read_timeout(Some(RETRY_INTERVAL))?;
+    let deadline = Instant::now()
+        .checked_add(timeout)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "timeout too long"))?;
+    let mut buffer = [0; 1500];
+
+    while Instant::now() < deadline {
+        socket.send_to(request, server)?;
+
+        let (num_bytes, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Err(e),
+        };
+
+        let response = &buffer[..num_bytes];
+        if source == server
+            && Message::parse(response)
+                .is_some_and(|response| response.transaction_id == *transaction_id)
+        {
+            return Ok(response.to_vec());
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("no answer from STUN server {server}"),
+    ))
+}
+
+/// Random transaction ID to match responses to the request
+pub(crate) fn transaction_id() -> TransactionId {
+    let mut id = [0; 12];
+    for chunk in id.chunks_mut(8) {
+        let random = RandomState::new().build_hasher().finish().to_be_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+
+    id
+}
+
+/// Decoded STUN message, referring to the attributes in the received packet
+#[derive(Debug)]
+pub(crate) struct Message<'a> {
+    /// Message type, i.e. method and class
+    pub(crate) kind: u16,
+    /// Transaction the message belongs to
+    pub(crate) transaction_id: TransactionId,
+    /// Type and value of each attribute in order
+    pub(crate) attributes: Vec<(u16, &'a [u8])>,
+}
+
+impl<'a> Message<'a> {
+    /// Decode a received packet, `None` if it is no STUN message
+    pub(crate) fn parse(packet: &'a [u8]) -> Option<Self> {
+        let header = packet.get(..HEADER_LEN)?;
+        if header[4..8] != MAGIC_COOKIE.to_be_bytes() {
+            return None;
+        }
+
+        let mut attributes = Vec::new();
+        let mut rest = &packet[HEADER_LEN..];
+        while let [t0, t1, l0, l1, value @ ..] = rest {
+            let len = u16::from_be_bytes([*l0, *l1]) as usize;
+            attributes.push((u16::from_be_bytes([*t0, *t1]), value.get(..len)?));
+            // Attributes are padded to a multiple of four bytes
+            rest = value.get(len.next_multiple_of(4)..).unwrap_or_default();
+        }
+
+        Some(Self {
+            kind: u16::from_be_bytes([header[0], header[1]]),
+            transaction_id: header[8..].try_into().ok()?,
+            attributes,
+        })
+    }
+
+    /// Value of the first attribute of the given type
+    pub(crate) fn attribute(&self, kind: u16) -> Option<&'a [u8]> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| *attribute == kind)
+            .map(|(_, value)| *value)
+    }
+
+    /// Mapped address, preferring the XOR-ed variant
+    fn mapped_address(&self) -> Option<SocketAddr> {
+        match self.attribute(XOR_MAPPED_ADDRESS) {
+            Some(value) => parse_xor_address(value, &self.transaction_id),
+            None => parse_address(self.attribute(MAPPED_ADDRESS)?, &[0; 16]),
+        }
+    }
+}
+
+/// Encode a message with the given attributes
+///
+/// The length in the header covers `extra_len` more bytes, so that an integrity
+/// attribute appended afterwards can be computed over the message as encoded here.
+pub(crate) fn encode_message(
+    kind: u16,
+    transaction_id: &TransactionId,
+    attributes: &[(u16, &[u8])],
+    extra_len: usize,
+) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_LEN);
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    message.extend_from_slice(transaction_id);
+
+    for (kind, value) in attributes {
+        append_attribute(&mut message, *kind, value);
+    }
+
+    let len = (message.len() - HEADER_LEN + extra_len) as u16;
+    message[2..4].copy_from_slice(&len.to_be_bytes());
+    message
+}
+
+/// Append an attribute with padding to an encoded message
+pub(crate) fn append_attribute(message: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    message.extend_from_slice(value);
+    message.resize(message.len().next_multiple_of(4), 0);
+}
+
+/// Mask of XOR-ed addresses, the magic cookie followed by the transaction ID
+fn xor_mask(transaction_id: &TransactionId) -> [u8; 16] {
+    let mut mask = [0; 16];
+    mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    mask[4..].copy_from_slice(transaction_id);
+    mask
+}
+
+/// Encode the value of an XOR-ed address attribute
+pub(crate) fn encode_xor_address(addr: SocketAddr, transaction_id: &TransactionId) -> Vec<u8> {
+    let mask = xor_mask(transaction_id);
+    let (family, octets) = match addr.ip() {
+        IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+    };
+
+    let mut value = vec![0, family];
+    value.extend_from_slice(&addr.port().to_be_bytes());
+    value.extend(octets.iter().zip(mask).map(|(octet, mask)| octet ^ mask));
+    value[2] ^= mask[0];
+    value[3] ^= mask[1];
+    value
+}
+
+/// Parse the value of an XOR-ed address attribute
+pub(crate) fn parse_xor_address(
+    value: &[u8],
+    transaction_id: &TransactionId,
+) -> Option<SocketAddr> {
+    parse_address(value, &xor_mask(transaction_id))
+}
+
+/// Parse the value of an address attribute, XOR-ed with `mask`
+fn parse_address(value: &[u8], mask: &[u8; 16]) -> Option<SocketAddr> {
+    let [_, family, p0, p1, addr @ ..] = value else {
+        return None;
+    };
+    let port = u16::from_be_bytes([p0 ^ mask[0], p1 ^ mask[1]]);
+
+    let ip = match (family, addr.len()) {
+        (1, 4) => {
+            let octets: [u8; 4] = std::array::from_fn(|idx| addr[idx] ^ mask[idx]);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        (2, 16) => {
+            let octets: [u8; 16] = std::array::from_fn(|idx| addr[idx] ^ mask[idx]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+
+    /// Message type of a successful binding response
+    const BINDING_RESPONSE: u16 = 0x0101;
+
+    #[test]
+    fn xor_address_round_trip() {
+        let transaction_id = transaction_id();
+
+        for addr in ["203.0.113.7:40123", "[2001:db8::7]:40123"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let value = encode_xor_address(addr, &transaction_id);
+            assert_eq!(Some(addr), parse_xor_address(&value, &transaction_id));
+        }
+    }
+
+    #[test]
+    fn mapped_address_parsed() {
+        let transaction_id = transaction_id();
+        let addr: SocketAddr = "203.0.113.7:40123".parse().unwrap();
+        let value = encode_xor_address(addr, &transaction_id);
+        let response = encode_message(
+            BINDING_RESPONSE,
+            &transaction_id,
+            &[(0x8022, b"server"), (XOR_MAPPED_ADDRESS, &value)],
+            0,
+        );
+
+        let response = Message::parse(&response).unwrap();
+        assert_eq!(BINDING_RESPONSE, response.kind);
+        assert_eq!(Some(addr), response.mapped_address());
+        assert!(Message::parse(b"data").is_none());
+    }
+
+    #[test]
+    fn nat_type_classified() {
+        let local: SocketAddr = "192.168.1.10:4000".parse().unwrap();
+        let public: SocketAddr = "203.0.113.7:40123".parse().unwrap();
+        let other: SocketAddr = "203.0.113.7:40124".parse().unwrap();
+
+        assert_eq!(NatType::Open, classify(local, &[local, local]));
+        assert_eq!(NatType::Unknown, classify(local, &[public]));
+        assert_eq!(NatType::Cone, classify(local, &[public, public]));
+        assert_eq!(NatType::Symmetric, classify(local, &[public, other]));
+    }
+
+    #[test]
+    fn discover_from_local_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 1500];
+            loop {
+                let (num_bytes, source) = server.recv_from(&mut buffer).unwrap();
+                let request = Message::parse(&buffer[..num_bytes]).unwrap();
+                let value = encode_xor_address(source, &request.transaction_id);
+                let response = encode_message(
+                    BINDING_RESPONSE,
+                    &request.transaction_id,
+                    &[(XOR_MAPPED_ADDRESS, &value)],
+                    0,
+                );
+                server.send_to(&response, source).unwrap();
+            }
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let local = socket.local_addr().unwrap();
+        let mapping = discover(&socket, local, &[server_addr], TIMEOUT).unwrap();
+
+        assert_eq!(local, mapping.public);
+        assert_eq!(NatType::Open, mapping.nat);
+    }
+}
@@ -0,0 +1,245 @@
+//! Summary of a run
+//!
+//! Once forwarding stopped, the binary writes a single JSON object with the reason, the
+//! labels, the runtime, the counters of the listener and each target and the errors, e.g. for scripts
+//! evaluating a capture replayed in a batch job. Like the other documents of the crate, it
+//! is formatted by hand.
+
+use std::{fmt::Write, time::SystemTime};
+
+use crate::{
+    ForwardError,
+    json::{json_object, json_string},
+    shutdown::ShutdownHandle,
+    state::{ForwardStats, SharedState},
+};
+
+/// Why forwarding stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The source was exhausted
+    Finished,
+    /// Forwarding was shut down, e.g. on `SIGINT`
+    Stopped,
+    /// Receiving or sending failed
+    Failed,
+}
+
+impl ExitReason {
+    /// Name in the summary
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Finished => "finished",
+            Self::Stopped => "stopped",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// Outcome of a run of the forwarder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitSummary {
+    pub reason: ExitReason,
+    /// Labels of the forwarder like `tenant=teamA`
+    pub labels: Vec<(String, String)>,
+    /// Counters at the time forwarding stopped
+    pub stats: ForwardStats,
+    /// Error forwarding stopped with
+    pub error: Option<String>,
+    /// Errors recorded while forwarding, with the time they occurred
+    pub recent_errors: Vec<(SystemTime, String)>,
+    /// Time forwarding stopped
+    pub ended: SystemTime,
+}
+
+impl ExitSummary {
+    /// Summarize the result of [crate::Forwarder::run] of the forwarder with `state`
+    pub fn new(result: &Result<ForwardStats, ForwardError>, state: &SharedState) -> Self {
+        let (reason, stats, error) = match result {
+            Ok(stats) => (ExitReason::Finished, stats.clone(), None),
+            Err(e) => (
+                ExitReason::Failed,
+                e.stats.as_ref().clone(),
+                Some(e.error.to_string()),
+            ),
+        };
+        Self {
+            reason,
+            labels: state.labels(),
+            stats,
+            error,
+            recent_errors: state.recent_errors(),
+            ended: SystemTime::now(),
+        }
+    }
+
+    /// Report a finished run as stopped if it ended because `shutdown` was shut down
+    pub fn with_shutdown(mut self, shutdown: &ShutdownHandle) -> Self {
+        if self.reason == ExitReason::Finished && shutdown.is_shutdown() {
+            self.reason = ExitReason::Stopped;
+        }
+        self
+    }
+
+    /// Render as JSON object on a single line, times in seconds since the epoch
+    pub fn to_json(&self) -> String {
+        let stats = &self.stats;
+        let sent = stats.sent();
+        let mut json = format!(
+            "{{\"reason\":\"{}\",\"labels\":{},\"ended\":{:.3},\"runtime_secs\":{:.3},\"paused_secs\":{:.3},\
+             \"received_packets\":{},\"received_bytes\":{},\"sent_packets\":{},\"sent_bytes\":{},\
+             \"send_errors\":{},\"dropped_packets\":{},\"relayed_packets\":{},\"relayed_bytes\":{}",
+            self.reason.name(),
+            json_object(&self.labels),
+            epoch_secs(self.ended),
+            stats.runtime.as_secs_f64(),
+            stats.paused_time.as_secs_f64(),
+            stats.received.packets,
+            stats.received.bytes,
+            sent.packets,
+            sent.bytes,
+            stats.send_errors(),
+            stats.dropped_packets(),
+            stats.relayed.packets,
+            stats.relayed.bytes,
+        );
+
+        json.push_str(",\"targets\":[");
+        for (idx, target) in stats.targets.iter().enumerate() {
+            if idx > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"addr\":\"{}\",\"sent_packets\":{},\"sent_bytes\":{},\"send_errors\":{},\
+                 \"queue_dropped_packets\":{}}}",
+                target.addr,
+                target.sent.packets,
+                target.sent.bytes,
+                target.send_errors,
+                target.queue_dropped.packets
+            );
+        }
+        json.push(']');
+
+        match &self.error {
+            Some(error) => {
+                let _ = write!(json, ",\"error\":{}", json_string(error));
+            }
+            None => json.push_str(",\"error\":null"),
+        }
+        json.push_str(",\"recent_errors\":[");
+        for (idx, (time, error)) in self.recent_errors.iter().enumerate() {
+            if idx > 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "{{\"time\":{:.3},\"error\":{}}}",
+                epoch_secs(*time),
+                json_string(error)
+            );
+        }
+        json.push_str("]}\n");
+        json
+    }
+}
+
+/// Seconds since the epoch, `0` for times before it
+fn epoch_secs(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, time::Duration};
+
+    use super::*;
+    use crate::{
+        json::Json,
+        state::{TargetStats, Totals},
+    };
+
+    #[test]
+    fn summary_json_ok() {
+        let state = SharedState::new(None, &[]);
+        state.set_labels(vec![("tenant".to_owned(), "teamA".to_owned())]);
+        state.record_error("Failed to send to 10.1.1.2:4000: \"unreachable\"".to_owned());
+        let stats = ForwardStats {
+            runtime: Duration::from_millis(1500),
+            received: Totals {
+                packets: 3,
+                bytes: 300,
+            },
+            targets: vec![TargetStats {
+                addr: "10.1.1.2:4000".parse().unwrap(),
+                sent: Totals {
+                    packets: 2,
+                    bytes: 200,
+                },
+                send_errors: 1,
+                queue_dropped: Totals {
+                    packets: 4,
+                    bytes: 400,
+                },
+            }],
+            ..ForwardStats::default()
+        };
+        let result = Err(ForwardError {
+            error: io::Error::new(io::ErrorKind::ConnectionReset, "listener gone"),
+            stats: Box::new(stats),
+        });
+
+        let summary = ExitSummary::new(&result, &state);
+        assert_eq!(ExitReason::Failed, summary.reason);
+        let json = Json::parse(summary.to_json().trim_end()).unwrap();
+        assert_eq!(Some("failed"), json.get("reason").and_then(Json::as_str));
+        assert_eq!(
+            Some("teamA"),
+            json.get("labels")
+                .and_then(|labels| labels.get("tenant"))
+                .and_then(Json::as_str)
+        );
+        assert_eq!(Some(&Json::Number(1.5)), json.get("runtime_secs"));
+        assert_eq!(Some(3), json.get("received_packets").and_then(Json::as_u64));
+        assert_eq!(Some(0), json.get("dropped_packets").and_then(Json::as_u64));
+        assert_eq!(Some(1), json.get("send_errors").and_then(Json::as_u64));
+        let targets = json.get("targets").and_then(Json::as_array).unwrap();
+        assert_eq!(
+            Some("10.1.1.2:4000"),
+            targets[0].get("addr").and_then(Json::as_str)
+        );
+        assert_eq!(
+            Some(200),
+            targets[0].get("sent_bytes").and_then(Json::as_u64)
+        );
+        assert_eq!(
+            Some(4),
+            targets[0]
+                .get("queue_dropped_packets")
+                .and_then(Json::as_u64)
+        );
+        assert_eq!(
+            Some("listener gone"),
+            json.get("error").and_then(Json::as_str)
+        );
+        let recent_errors = json.get("recent_errors").and_then(Json::as_array).unwrap();
+        assert_eq!(
+            Some("Failed to send to 10.1.1.2:4000: \"unreachable\""),
+            recent_errors[0].get("error").and_then(Json::as_str)
+        );
+
+        let summary = ExitSummary::new(&Ok(ForwardStats::default()), &state);
+        let json = Json::parse(summary.to_json().trim_end()).unwrap();
+        assert_eq!(Some("finished"), json.get("reason").and_then(Json::as_str));
+        assert_eq!(Some(&Json::Null), json.get("error"));
+
+        let shutdown = ShutdownHandle::new();
+        shutdown.shutdown();
+        let summary =
+            ExitSummary::new(&Ok(ForwardStats::default()), &state).with_shutdown(&shutdown);
+        assert_eq!(ExitReason::Stopped, summary.reason);
+    }
+}
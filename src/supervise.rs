@@ -0,0 +1,139 @@
+//! Restarting after transient failures
+//!
+//! Receiving fails for good when the network goes away under the listener, e.g. with
+//! `ENETDOWN` while an interface is down or `EBADF` after a laptop resumed from sleep.
+//! Under a [RetryPolicy], the forwarder rebinds its sockets and resumes instead, waiting
+//! longer after each failure in a row.
+
+use std::{fmt, io, str::FromStr, time::Duration};
+
+use crate::args::parse_duration;
+
+/// Wait before the first restart by default
+pub const DEFAULT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between restarts by default
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Backoff between restarts after transient failures
+///
+/// The wait doubles with each failure in a row up to `max_backoff` and starts over at
+/// `min_backoff` once forwarding ran for `max_backoff` without failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RetryPolicy {
+    /// Wait before the first restart
+    pub min_backoff: Duration,
+    /// Longest wait between restarts
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            min_backoff: DEFAULT_MIN_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait after `backoff`, doubled up to the maximum
+    pub(crate) fn next_backoff(&self, backoff: Duration) -> Duration {
+        backoff.saturating_mul(2).min(self.max_backoff)
+    }
+}
+
+impl FromStr for RetryPolicy {
+    type Err = ParseRetryPolicyError;
+
+    /// Parse a range of backoffs like `1s..30s`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (min, max) = s.split_once("..").ok_or(ParseRetryPolicyError)?;
+        let min_backoff = parse_duration(min).map_err(|_| ParseRetryPolicyError)?;
+        let max_backoff = parse_duration(max).map_err(|_| ParseRetryPolicyError)?;
+        if min_backoff.is_zero() || min_backoff > max_backoff {
+            return Err(ParseRetryPolicyError);
+        }
+        Ok(Self {
+            min_backoff,
+            max_backoff,
+        })
+    }
+}
+
+impl fmt::Display for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}..{:?}", self.min_backoff, self.max_backoff)
+    }
+}
+
+/// Range of backoffs other than `min..max` with `0 < min <= max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseRetryPolicyError;
+
+impl fmt::Display for ParseRetryPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected a range of backoffs like 1s..30s")
+    }
+}
+
+impl std::error::Error for ParseRetryPolicyError {}
+
+/// Check if forwarding failed with `error` because the network went away for a while
+///
+/// Such failures are worth rebinding the sockets for, unlike e.g. lacking permissions.
+pub fn is_transient(error: &io::Error) -> bool {
+    /// Bad file descriptor on Unix, sockets closed under the process e.g. on resume
+    const EBADF: i32 = 9;
+    /// No such device, the interface the socket was bound to is gone
+    const ENODEV: i32 = 19;
+
+    matches!(
+        error.kind(),
+        io::ErrorKind::NetworkDown
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::AddrNotAvailable
+    ) || cfg!(unix) && matches!(error.raw_os_error(), Some(EBADF | ENODEV))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn policies_parsed() {
+        assert_eq!(
+            Ok(RetryPolicy {
+                min_backoff: Duration::from_millis(500),
+                max_backoff: Duration::from_secs(60),
+            }),
+            "500ms..1m".parse()
+        );
+        assert!("30s..1s".parse::<RetryPolicy>().is_err());
+        assert!("0s..1s".parse::<RetryPolicy>().is_err());
+        assert!("1s".parse::<RetryPolicy>().is_err());
+    }
+
+    #[test]
+    fn backoff_doubled_up_to_max() {
+        let policy = RetryPolicy::default();
+        let backoffs: Vec<_> = std::iter::successors(Some(policy.min_backoff), |backoff| {
+            Some(policy.next_backoff(*backoff))
+        })
+        .take(7)
+        .map(|backoff| backoff.as_secs())
+        .collect();
+        assert_eq!(vec![1, 2, 4, 8, 16, 30, 30], backoffs);
+    }
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient(&io::Error::from(io::ErrorKind::NetworkDown)));
+        #[cfg(unix)]
+        assert!(is_transient(&io::Error::from_raw_os_error(9)));
+        assert!(!is_transient(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+}
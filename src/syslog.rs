@@ -0,0 +1,230 @@
+//! Re-framing of relayed syslog messages
+//!
+//! Some senders batch several syslog messages into one datagram, separated by
+//! newlines or with octet-counting framing (RFC 6587). Many collectors only parse
+//! the first message of a datagram, so the others are split into datagrams of their own.
+//!
+//! Relaying also hides the sender from the collector, which sees the forwarder as
+//! source. Rewriting the hostname field of RFC 3164 and RFC 5424 messages to the
+//! address of the original source keeps that information.
+
+use std::{borrow::Cow, net::IpAddr};
+
+/// Months as they appear in RFC 3164 timestamps
+const MONTHS: [&[u8; 3]; 12] = [
+    b"Jan", b"Feb", b"Mar", b"Apr", b"May", b"Jun", b"Jul", b"Aug", b"Sep", b"Oct", b"Nov", b"Dec",
+];
+/// Length of an RFC 3164 timestamp like `Oct 11 22:14:15`
+const RFC3164_TIMESTAMP_LEN: usize = 15;
+
+/// Handling of syslog messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Syslog {
+    /// Replace the hostname field with the address of the source
+    pub rewrite_hostname: bool,
+}
+
+impl Syslog {
+    /// Split a received datagram into its messages, rewriting the hostnames if configured
+    pub(crate) fn reframe<'a>(&self, packet: &'a [u8], source: IpAddr) -> Vec<Cow<'a, [u8]>> {
+        let hostname = source.to_canonical().to_string();
+
+        split(packet)
+            .into_iter()
+            .map(|message| match self.rewrite_hostname {
+                true => match rewrite_hostname(message, hostname.as_bytes()) {
+                    Some(rewritten) => Cow::Owned(rewritten),
+                    None => Cow::Borrowed(message),
+                },
+                false => Cow::Borrowed(message),
+            })
+            .collect()
+    }
+}
+
+/// Split a datagram into the syslog messages it contains
+///
+/// Messages are either octet-counted (`<len> <message>`) or separated by newlines
+/// followed by the `<` of the next priority. Lines not starting with a priority
+/// continue the previous message.
+fn split(packet: &[u8]) -> Vec<&[u8]> {
+    let mut messages = Vec::new();
+    let mut rest = packet;
+
+    loop {
+        rest = trim_start(rest);
+        if rest.is_empty() {
+            break;
+        }
+
+        let (message, next) = match octet_counted(rest) {
+            Some((message, next)) => (message, next),
+            None => match rest.windows(2).position(|window| window == b"\n<") {
+                Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+                None => (rest, &[][..]),
+            },
+        };
+        let message = trim_end(message);
+        if !message.is_empty() {
+            messages.push(message);
+        }
+        rest = next;
+    }
+
+    messages
+}
+
+/// Split off an octet-counted message `<len> <message>` at the start of `data`
+fn octet_counted(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let space = data.iter().take(6).position(|byte| *byte == b' ')?;
+    let len: usize = std::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+    let message = data.get(space + 1..space + 1 + len)?;
+    if !message.starts_with(b"<") {
+        return None;
+    }
+
+    Some((message, &data[space + 1 + len..]))
+}
+
+/// Replace the hostname of an RFC 3164 or RFC 5424 message with `hostname`
+///
+/// RFC 3164 messages without hostname get one inserted after the timestamp.
+/// Returns `None` if the message has neither format.
+fn rewrite_hostname(message: &[u8], hostname: &[u8]) -> Option<Vec<u8>> {
+    // Priority like `<34>`
+    let pri_end = message.iter().take(5).position(|byte| *byte == b'>')?;
+    if message[0] != b'<' || pri_end < 2 {
+        return None;
+    }
+    let header = &message[pri_end + 1..];
+
+    let (start, end) = if header.first()?.is_ascii_digit() {
+        // RFC 5424: VERSION SP TIMESTAMP SP HOSTNAME SP ...
+        let mut fields = header.splitn(4, |byte| *byte == b' ');
+        let version = fields.next()?;
+        let timestamp = fields.next()?;
+        let host = fields.next()?;
+        fields.next()?;
+        let start = version.len() + timestamp.len() + 2;
+        (start, start + host.len())
+    } else if is_rfc3164_timestamp(header) {
+        // RFC 3164: TIMESTAMP SP HOSTNAME SP TAG, some senders omit the hostname
+        let start = RFC3164_TIMESTAMP_LEN + 1;
+        let host_len = header[start..]
+            .iter()
+            .position(|byte| *byte == b' ')
+            .unwrap_or(header.len() - start);
+        let host = &header[start..start + host_len];
+        match host.ends_with(b":") || host.contains(&b'[') {
+            true => (start, start),
+            false => (start, start + host_len),
+        }
+    } else {
+        return None;
+    };
+
+    let (start, end) = (pri_end + 1 + start, pri_end + 1 + end);
+    let mut rewritten = Vec::with_capacity(message.len() + hostname.len());
+    rewritten.extend_from_slice(&message[..start]);
+    rewritten.extend_from_slice(hostname);
+    if start == end {
+        rewritten.push(b' ');
+    }
+    rewritten.extend_from_slice(&message[end..]);
+    Some(rewritten)
+}
+
+/// Check if `header` starts with an RFC 3164 timestamp followed by a space
+fn is_rfc3164_timestamp(header: &[u8]) -> bool {
+    let Some(timestamp) = header.get(..=RFC3164_TIMESTAMP_LEN) else {
+        return false;
+    };
+    let digit_or_space = |byte: u8| byte.is_ascii_digit() || byte == b' ';
+
+    MONTHS.iter().any(|month| timestamp.starts_with(*month))
+        && timestamp[3] == b' '
+        && digit_or_space(timestamp[4])
+        && timestamp[5].is_ascii_digit()
+        && timestamp[6] == b' '
+        && timestamp[7..15]
+            .iter()
+            .enumerate()
+            .all(|(idx, byte)| match idx {
+                2 | 5 => *byte == b':',
+                _ => byte.is_ascii_digit(),
+            })
+        && timestamp[15] == b' '
+}
+
+fn trim_start(data: &[u8]) -> &[u8] {
+    let start = data
+        .iter()
+        .position(|byte| !matches!(byte, b'\r' | b'\n' | b'\0'))
+        .unwrap_or(data.len());
+    &data[start..]
+}
+
+fn trim_end(data: &[u8]) -> &[u8] {
+    let end = data
+        .iter()
+        .rposition(|byte| !matches!(byte, b'\r' | b'\n' | b'\0'))
+        .map_or(0, |idx| idx + 1);
+    &data[..end]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn newline_separated_messages_split() {
+        let packet =
+            b"<34>Oct 11 22:14:15 host su: first\n  continued\n<13>1 - - - - - - second\r\n";
+
+        assert_eq!(
+            vec![
+                &b"<34>Oct 11 22:14:15 host su: first\n  continued"[..],
+                &b"<13>1 - - - - - - second"[..]
+            ],
+            split(packet)
+        );
+    }
+
+    #[test]
+    fn octet_counted_messages_split() {
+        let packet = b"11 <13>1 first12 <13>1 second";
+
+        assert_eq!(
+            vec![&b"<13>1 first"[..], &b"<13>1 second"[..]],
+            split(packet)
+        );
+    }
+
+    #[test]
+    fn hostnames_rewritten() {
+        let syslog = Syslog {
+            rewrite_hostname: true,
+        };
+        let source: IpAddr = "10.1.1.10".parse().unwrap();
+        let packet = b"<165>1 2003-10-11T22:14:15.003Z mymachine evntslog - ID47 - event\n\
+            <34>Oct  1 22:14:15 mymachine su: 'su root' failed\n\
+            <34>Oct 11 22:14:15 su[42]: no hostname\n\
+            <34>unparseable";
+
+        let messages: Vec<_> = syslog
+            .reframe(packet, source)
+            .into_iter()
+            .map(|message| String::from_utf8(message.into_owned()).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "<165>1 2003-10-11T22:14:15.003Z 10.1.1.10 evntslog - ID47 - event",
+                "<34>Oct  1 22:14:15 10.1.1.10 su: 'su root' failed",
+                "<34>Oct 11 22:14:15 10.1.1.10 su[42]: no hostname",
+                "<34>unparseable",
+            ],
+            messages
+        );
+    }
+}
@@ -0,0 +1,386 @@
+//! Receive timestamps of the kernel and network card
+//!
+//! A [TimestampingSocket] has packets stamped when the kernel or the network card
+//! receives them, so that the jitter reflects the arrival on the wire rather than
+//! when the forwarding loop got to read the packet. The socket is only available
+//! on Linux.
+
+use std::{fmt, str::FromStr};
+
+/// Clock stamping received packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Timestamps {
+    /// Software timestamps of the kernel with `SO_TIMESTAMPNS`
+    Kernel,
+    /// Timestamps of the network card with `SO_TIMESTAMPING`
+    ///
+    /// Packets the card doesn't stamp get a software timestamp. If an interface is
+    /// given, timestamping of all received packets is enabled on it, which needs
+    /// `CAP_NET_ADMIN`. Otherwise it is left to e.g. `ptp4l` or `hwstamp_ctl`.
+    /// The card stamps with its own clock, keep it synchronized with `phc2sys`.
+    Hardware { interface: Option<String> },
+}
+
+impl FromStr for Timestamps {
+    type Err = ParseTimestampsError;
+
+    /// Parse `kernel`, `hardware` or `hardware:interface`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "kernel" => Ok(Self::Kernel),
+            None if s == "hardware" => Ok(Self::Hardware { interface: None }),
+            Some(("hardware", interface)) if !interface.is_empty() => Ok(Self::Hardware {
+                interface: Some(interface.to_owned()),
+            }),
+            _ => Err(ParseTimestampsError),
+        }
+    }
+}
+
+impl fmt::Display for Timestamps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kernel => write!(f, "kernel"),
+            Self::Hardware { interface: None } => write!(f, "hardware"),
+            Self::Hardware {
+                interface: Some(interface),
+            } => write!(f, "hardware:{interface}"),
+        }
+    }
+}
+
+/// Timestamps neither `kernel`, `hardware` nor `hardware:interface`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseTimestampsError;
+
+impl fmt::Display for ParseTimestampsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected kernel, hardware or hardware:interface")
+    }
+}
+
+impl std::error::Error for ParseTimestampsError {}
+
+#[cfg(target_os = "linux")]
+pub use self::socket::TimestampingSocket;
+
+#[cfg(target_os = "linux")]
+mod socket {
+    use std::{
+        ffi::{c_int, c_long, c_ulong, c_void},
+        io,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+        os::fd::AsRawFd,
+        ptr,
+        time::{Duration, SystemTime},
+    };
+
+    use super::Timestamps;
+    use crate::{capture::interface_index, sockopt, source::Source};
+
+    const SO_TIMESTAMPNS: c_int = 35;
+    const SO_TIMESTAMPING: c_int = 37;
+    const SOF_TIMESTAMPING_RX_HARDWARE: c_int = 1 << 2;
+    const SOF_TIMESTAMPING_RX_SOFTWARE: c_int = 1 << 3;
+    const SOF_TIMESTAMPING_SOFTWARE: c_int = 1 << 4;
+    const SOF_TIMESTAMPING_RAW_HARDWARE: c_int = 1 << 6;
+    const SIOCSHWTSTAMP: c_ulong = 0x89b0;
+    const HWTSTAMP_FILTER_ALL: c_int = 1;
+    const AF_INET: u16 = 2;
+    const AF_INET6: u16 = 10;
+
+    /// `struct timespec`
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Timespec {
+        tv_sec: c_long,
+        tv_nsec: c_long,
+    }
+
+    /// `struct iovec`
+    #[repr(C)]
+    struct Iovec {
+        base: *mut c_void,
+        len: usize,
+    }
+
+    /// `struct msghdr`
+    #[repr(C)]
+    struct Msghdr {
+        name: *mut c_void,
+        namelen: u32,
+        iov: *mut Iovec,
+        iovlen: usize,
+        control: *mut c_void,
+        controllen: usize,
+        flags: c_int,
+    }
+
+    /// `struct cmsghdr`, followed by the data
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Cmsghdr {
+        len: usize,
+        level: c_int,
+        kind: c_int,
+    }
+
+    /// `struct hwtstamp_config`
+    #[repr(C)]
+    struct HwtstampConfig {
+        flags: c_int,
+        tx_type: c_int,
+        rx_filter: c_int,
+    }
+
+    /// `struct ifreq` pointing to the timestamping configuration
+    #[repr(C)]
+    struct Ifreq {
+        name: [u8; 16],
+        data: *mut HwtstampConfig,
+        padding: [u8; 16],
+    }
+
+    unsafe extern "C" {
+        fn recvmsg(fd: c_int, msg: *mut Msghdr, flags: c_int) -> isize;
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+
+    /// Listener socket recording when the kernel or network card received each packet
+    ///
+    /// Packets received right after enabling hardware timestamps may lack a timestamp.
+    #[derive(Debug)]
+    pub struct TimestampingSocket {
+        socket: UdpSocket,
+        hardware: bool,
+        /// Timestamp of the last received packet
+        arrival: Option<SystemTime>,
+    }
+
+    impl TimestampingSocket {
+        /// Have the packets received on `socket` stamped by `timestamps`
+        pub fn new(socket: UdpSocket, timestamps: &Timestamps) -> Result<Self, io::Error> {
+            let hardware = match timestamps {
+                Timestamps::Kernel => {
+                    sockopt::set_int(&socket, sockopt::SOL_SOCKET, SO_TIMESTAMPNS, 1)?;
+                    false
+                }
+                Timestamps::Hardware { interface } => {
+                    if let Some(interface) = interface {
+                        enable_hardware_timestamps(&socket, interface)?;
+                    }
+                    let flags = SOF_TIMESTAMPING_RX_HARDWARE
+                        | SOF_TIMESTAMPING_RAW_HARDWARE
+                        | SOF_TIMESTAMPING_RX_SOFTWARE
+                        | SOF_TIMESTAMPING_SOFTWARE;
+                    sockopt::set_int(&socket, sockopt::SOL_SOCKET, SO_TIMESTAMPING, flags)?;
+                    true
+                }
+            };
+
+            Ok(Self {
+                socket,
+                hardware,
+                arrival: None,
+            })
+        }
+
+        /// Timestamp of the control message of `level` and `kind` with data `data`
+        fn timestamp(&self, level: c_int, kind: c_int, data: &[u8]) -> Option<SystemTime> {
+            if level != sockopt::SOL_SOCKET {
+                return None;
+            }
+            // Software, deprecated and raw hardware timestamp, preferring the latter
+            let timespecs: [Timespec; 3] = match kind {
+                SO_TIMESTAMPNS if !self.hardware => [read(data)?; 3],
+                SO_TIMESTAMPING if self.hardware => read(data)?,
+                _ => return None,
+            };
+            timespecs
+                .iter()
+                .rev()
+                .find(|timespec| timespec.tv_sec != 0 || timespec.tv_nsec != 0)
+                .map(|timespec| {
+                    SystemTime::UNIX_EPOCH
+                        + Duration::new(timespec.tv_sec as u64, timespec.tv_nsec as u32)
+                })
+        }
+    }
+
+    /// Let the card of `interface` stamp all received packets
+    fn enable_hardware_timestamps(socket: &UdpSocket, interface: &str) -> Result<(), io::Error> {
+        // Check the name fits and exists
+        interface_index(interface)?;
+        let mut config = HwtstampConfig {
+            flags: 0,
+            tx_type: 0,
+            rx_filter: HWTSTAMP_FILTER_ALL,
+        };
+        let mut request = Ifreq {
+            name: [0; 16],
+            data: &mut config,
+            padding: [0; 16],
+        };
+        request.name[..interface.len()].copy_from_slice(interface.as_bytes());
+
+        // SAFETY: Request and configuration are valid for the duration of the call
+        match unsafe { ioctl(socket.as_raw_fd(), SIOCSHWTSTAMP, &mut request) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read a `T` from the start of `data`, if long enough
+    fn read<T: Copy>(data: &[u8]) -> Option<T> {
+        if data.len() < size_of::<T>() {
+            return None;
+        }
+        // SAFETY: Long enough, `read_unaligned` needs no alignment
+        Some(unsafe { ptr::read_unaligned(data.as_ptr() as *const T) })
+    }
+
+    /// Address of a `struct sockaddr_in` or `struct sockaddr_in6`
+    fn socket_addr(addr: &[u8]) -> Option<SocketAddr> {
+        let family = u16::from_ne_bytes(addr.get(..2)?.try_into().ok()?);
+        let port = u16::from_be_bytes(addr.get(2..4)?.try_into().ok()?);
+        match family {
+            AF_INET => {
+                let ip: [u8; 4] = addr.get(4..8)?.try_into().ok()?;
+                Some(SocketAddrV4::new(Ipv4Addr::from(ip), port).into())
+            }
+            AF_INET6 => {
+                let flowinfo = u32::from_be_bytes(addr.get(4..8)?.try_into().ok()?);
+                let ip: [u8; 16] = addr.get(8..24)?.try_into().ok()?;
+                let scope_id = u32::from_ne_bytes(addr.get(24..28)?.try_into().ok()?);
+                Some(SocketAddrV6::new(Ipv6Addr::from(ip), port, flowinfo, scope_id).into())
+            }
+            _ => None,
+        }
+    }
+
+    impl Source for TimestampingSocket {
+        fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+            // Large enough for a `struct sockaddr_in6` and the timestamps
+            let mut addr = [0u64; 4];
+            let mut control = [0u64; 16];
+            let mut iov = Iovec {
+                base: buffer.as_mut_ptr() as *mut c_void,
+                len: buffer.len(),
+            };
+            let mut msg = Msghdr {
+                name: addr.as_mut_ptr() as *mut c_void,
+                namelen: size_of_val(&addr) as u32,
+                iov: &mut iov,
+                iovlen: 1,
+                control: control.as_mut_ptr() as *mut c_void,
+                controllen: size_of_val(&control),
+                flags: 0,
+            };
+            // SAFETY: All buffers are valid for their lengths
+            let len = unsafe { recvmsg(self.socket.as_raw_fd(), &mut msg, 0) };
+            let Ok(len) = usize::try_from(len) else {
+                return Err(io::Error::last_os_error());
+            };
+
+            // SAFETY: Plain bytes of the buffers, the kernel reports how many it filled
+            let (addr, control) = unsafe {
+                (
+                    std::slice::from_raw_parts(addr.as_ptr() as *const u8, msg.namelen as usize),
+                    std::slice::from_raw_parts(control.as_ptr() as *const u8, msg.controllen),
+                )
+            };
+            let Some(source) = socket_addr(addr) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "received from an address which is no IP address",
+                ));
+            };
+
+            // Control messages are aligned to the size of `usize`
+            self.arrival = None;
+            let mut offset = 0;
+            while let Some(header) = control.get(offset..).and_then(read::<Cmsghdr>) {
+                let Some(data) = control.get(offset + size_of::<Cmsghdr>()..offset + header.len)
+                else {
+                    break;
+                };
+                if let Some(arrival) = self.timestamp(header.level, header.kind, data) {
+                    self.arrival = Some(arrival);
+                }
+                offset += header.len.next_multiple_of(size_of::<usize>());
+            }
+
+            Ok(Some((len.min(buffer.len()), source)))
+        }
+
+        fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+            self.socket.set_read_timeout(timeout)
+        }
+
+        fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+            self.socket.set_nonblocking(nonblocking)
+        }
+
+        fn socket(&self) -> Option<&UdpSocket> {
+            Some(&self.socket)
+        }
+
+        fn arrival(&self) -> Option<SystemTime> {
+            self.arrival
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn kernel_stamps_packets() {
+            // Loopback has no hardware timestamps, so the kernel stamps in both modes
+            for timestamps in [Timestamps::Kernel, Timestamps::Hardware { interface: None }] {
+                let listener = UdpSocket::bind("[::1]:0").unwrap();
+                let listener_addr = listener.local_addr().unwrap();
+                let mut listener = TimestampingSocket::new(listener, &timestamps).unwrap();
+                listener
+                    .set_read_timeout(Some(Duration::from_secs(1)))
+                    .unwrap();
+
+                let sender = UdpSocket::bind("[::1]:0").unwrap();
+                let before = SystemTime::now();
+                sender.send_to(b"stamped", listener_addr).unwrap();
+                let mut buffer = [0; 16];
+                let (len, source) = listener.recv(&mut buffer).unwrap().unwrap();
+                let after = SystemTime::now();
+
+                assert_eq!(b"stamped", &buffer[..len]);
+                assert_eq!(sender.local_addr().unwrap(), source);
+                // Software timestamps of SO_TIMESTAMPING only start once the kernel enabled
+                // timestamping, which it defers, while SO_TIMESTAMPNS stamps late instead
+                let arrival = listener.arrival();
+                if timestamps == Timestamps::Kernel {
+                    assert!(arrival.is_some());
+                }
+                assert!(arrival.is_none_or(|arrival| before <= arrival && arrival <= after));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timestamps_parsed() {
+        assert_eq!(Ok(Timestamps::Kernel), "kernel".parse());
+        assert_eq!(
+            Ok(Timestamps::Hardware { interface: None }),
+            "hardware".parse()
+        );
+        let timestamps: Timestamps = "hardware:eth0".parse().unwrap();
+        assert_eq!("hardware:eth0", timestamps.to_string());
+        assert!("hardware:".parse::<Timestamps>().is_err());
+        assert!("software".parse::<Timestamps>().is_err());
+    }
+}
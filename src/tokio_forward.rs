@@ -0,0 +1,230 @@
+//! Forwarding within a tokio runtime
+//!
+//! [AsyncForwarder] forwards on sockets of the tokio runtime it is polled in, so that
+//! services built on tokio run it as a task instead of dedicating a blocking thread to
+//! [crate::Forwarder]. It keeps the counters, the targets and the pausing in a
+//! [SharedState] like the blocking forwarder, but none of its optional processing.
+//! Only available with the `tokio` feature.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, atomic::Ordering},
+    time::Instant,
+};
+
+use tokio::net::UdpSocket;
+
+use crate::{
+    ForwardError, ListenerBuilder, ListenerSpec,
+    socket::route_source_ip,
+    state::{ForwardStats, SharedState, Target},
+};
+
+/// Largest payload received, the MTU of Ethernet like the blocking forwarder
+const MTU: usize = 1500;
+
+/// Forward from a listener to a set of forward addresses within a tokio runtime
+///
+/// Like [crate::forward], but without blocking the thread polling the future.
+pub async fn async_forward(
+    listener_spec: ListenerSpec,
+    forward_addrs: &[SocketAddr],
+) -> Result<ForwardStats, ForwardError> {
+    AsyncForwarder::new(listener_spec, forward_addrs.to_vec())?
+        .run()
+        .await
+}
+
+/// Forwarder on the sockets of a tokio runtime
+pub struct AsyncForwarder {
+    listener: UdpSocket,
+    /// IPv4-bound socket, only bound for IPv4 targets
+    sender_v4: Option<UdpSocket>,
+    /// IPv6-bound socket, only bound for IPv6 targets
+    sender_v6: Option<UdpSocket>,
+    /// Source addresses which packets sent to the targets carry
+    own_addrs: Vec<SocketAddr>,
+    state: Arc<SharedState>,
+}
+
+impl AsyncForwarder {
+    /// Bind the listener and senders
+    ///
+    /// Must be called within a tokio runtime with I/O enabled.
+    pub fn new(
+        listener_spec: ListenerSpec,
+        forward_addrs: Vec<SocketAddr>,
+    ) -> Result<Self, io::Error> {
+        let listener = ListenerBuilder::new(&listener_spec)
+            .nonblocking(true)
+            .bind()?;
+        let state = SharedState::new(listener.local_addr().ok(), &forward_addrs);
+
+        let mut forwarder = Self {
+            listener: UdpSocket::from_std(listener)?,
+            sender_v4: None,
+            sender_v6: None,
+            own_addrs: Vec::new(),
+            state: Arc::new(state),
+        };
+        for addr in &forward_addrs {
+            forwarder.ensure_sender_for(addr)?;
+        }
+
+        Ok(forwarder)
+    }
+
+    /// Handle to the state shared with the forwarding task
+    ///
+    /// Changes to the targets are picked up with the next packet.
+    pub fn state(&self) -> Arc<SharedState> {
+        Arc::clone(&self.state)
+    }
+
+    /// Forward packets until receiving fails
+    ///
+    /// Failures to send to a target are counted per target and don't stop forwarding,
+    /// packets looping back to the listener are dropped. Dropping the future stops
+    /// forwarding, the counters remain available through [AsyncForwarder::state].
+    pub async fn run(mut self) -> Result<ForwardStats, ForwardError> {
+        let started = Instant::now();
+        let error = self.forward().await;
+        let stats = self.state.forward_stats(started.elapsed());
+
+        Err(ForwardError {
+            error,
+            stats: Box::new(stats),
+        })
+    }
+
+    async fn forward(&mut self) -> io::Error {
+        let mut buffer = vec![0; MTU];
+        let mut generation = self.state.generation();
+        let mut targets = self.state.active_targets();
+        loop {
+            let (num_bytes, source) = match self.listener.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                // Signals interrupt receiving like in the blocking forwarder
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return e,
+            };
+            self.state.received().add(num_bytes);
+            if self.own_addrs.contains(&source) {
+                self.state.looped().add(num_bytes);
+                continue;
+            }
+
+            // Pick up changes of the targets
+            let current_generation = self.state.generation();
+            if current_generation != generation {
+                generation = current_generation;
+                targets = self.state.active_targets();
+                for target in &targets {
+                    if let Err(e) = self.ensure_sender_for(&target.addr) {
+                        return e;
+                    }
+                }
+            }
+
+            if self.state.is_paused() {
+                self.state.paused_dropped().add(num_bytes);
+                continue;
+            }
+            for target in &targets {
+                self.send(target, &buffer[..num_bytes]).await;
+            }
+        }
+    }
+
+    /// Send data to a target, counting the result
+    async fn send(&self, target: &Target, data: &[u8]) {
+        let sender = match target.addr {
+            SocketAddr::V4(_) => self.sender_v4.as_ref(),
+            SocketAddr::V6(_) => self.sender_v6.as_ref(),
+        };
+        let result = match sender {
+            Some(sender) => sender.send_to(data, target.addr).await,
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no sender for the IP family",
+            )),
+        };
+        match result {
+            Ok(num_bytes) => target.sent.add(num_bytes),
+            Err(e) => {
+                target.send_errors.fetch_add(1, Ordering::Relaxed);
+                self.state
+                    .record_error(format!("failed to send to {}: {e}", target.addr));
+            }
+        }
+    }
+
+    /// Bind the sender for the IP family of the given address if not done yet
+    ///
+    /// Also remembers the source address of packets to `addr` to detect forwarding loops.
+    fn ensure_sender_for(&mut self, addr: &SocketAddr) -> Result<(), io::Error> {
+        let (sender, unspecified) = match addr {
+            SocketAddr::V4(_) => (
+                &mut self.sender_v4,
+                SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            ),
+            SocketAddr::V6(_) => (
+                &mut self.sender_v6,
+                SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            ),
+        };
+        let sender = match sender {
+            Some(sender) => sender,
+            None => {
+                let socket = std::net::UdpSocket::bind(unspecified)?;
+                socket.set_nonblocking(true)?;
+                sender.insert(UdpSocket::from_std(socket)?)
+            }
+        };
+
+        // Senders are bound to the unspecified address like those of the blocking forwarder
+        let port = sender.local_addr()?.port();
+        if let Some(ip) = route_source_ip(addr)
+            && !self.own_addrs.contains(&SocketAddr::new(ip, port))
+        {
+            self.own_addrs.push(SocketAddr::new(ip, port));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn forwarded_within_runtime() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forwarder = AsyncForwarder::new(
+            ListenerSpec::Unicast("127.0.0.1:0".parse().unwrap()),
+            vec![target.local_addr().unwrap()],
+        )
+        .unwrap();
+        let state = forwarder.state();
+        let listener_addr = state.listener_addr().unwrap();
+        let forwarding = tokio::spawn(forwarder.run());
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.send_to(b"hello", listener_addr).await.unwrap();
+        let mut buffer = [0; 64];
+        let (num_bytes, _) =
+            tokio::time::timeout(Duration::from_secs(1), target.recv_from(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(b"hello", &buffer[..num_bytes]);
+        assert_eq!(1, state.received().packets());
+        assert_eq!(1, state.active_targets()[0].sent.packets());
+
+        forwarding.abort();
+    }
+}
@@ -0,0 +1,297 @@
+//! Auxiliary tools
+//!
+//! Small helpers around the forwarding use case,
+//! for example to verify that a multicast group can be received
+//! or to measure the achievable send rate.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    Forwarder, ListenerBuilder, ListenerSpec,
+    jitter::{Arrivals, JitterStats},
+};
+
+/// Packet and byte counts received during one interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateSample {
+    /// Length of the interval
+    pub interval: Duration,
+    /// Number of packets received during the interval
+    pub packets: u64,
+    /// Number of bytes received during the interval
+    pub bytes: u64,
+    /// Gaps between the packets, if at least two were received
+    pub jitter: Option<JitterStats>,
+}
+
+/// Result of a send benchmark
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchReport {
+    /// Time it took to send all packets
+    pub elapsed: Duration,
+    /// Number of packets sent
+    pub packets: u64,
+    /// Number of bytes sent
+    pub bytes: u64,
+}
+
+/// Result of forwarding packets over loopback
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelftestReport {
+    /// Time from sending the first packet until the last one was received or given up on
+    pub elapsed: Duration,
+    /// Number of packets which made it through the forwarder
+    pub packets: u64,
+    /// Number of bytes which made it through the forwarder
+    pub bytes: u64,
+    /// Number of packets which did not arrive in time
+    pub lost: u64,
+    /// Median latency from sending to receiving a packet
+    pub latency_p50: Duration,
+    /// Latency 90% of the packets arrived within
+    pub latency_p90: Duration,
+    /// Latency 99% of the packets arrived within
+    pub latency_p99: Duration,
+    /// Highest latency of a packet
+    pub latency_max: Duration,
+}
+
+/// Receive on a listener without forwarding and report rates and jitter every `interval`
+pub fn stats(
+    listener_spec: ListenerSpec,
+    interval: Duration,
+    mut report: impl FnMut(RateSample),
+) -> Result<(), io::Error> {
+    let listener = ListenerBuilder::new(&listener_spec).bind()?;
+    listener.set_read_timeout(Some(interval))?;
+
+    let mut buffer = [0; 1500];
+    let mut sample_start = Instant::now();
+    let mut packets = 0;
+    let mut bytes = 0;
+    let mut arrivals = Arrivals::new();
+
+    loop {
+        match listener.recv(&mut buffer) {
+            Ok(num_bytes) => {
+                packets += 1;
+                bytes += num_bytes as u64;
+                arrivals.add(SystemTime::now());
+            }
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+
+        let elapsed = sample_start.elapsed();
+        if elapsed >= interval {
+            report(RateSample {
+                interval: elapsed,
+                packets,
+                bytes,
+                jitter: arrivals.take(),
+            });
+            sample_start = Instant::now();
+            packets = 0;
+            bytes = 0;
+        }
+    }
+}
+
+/// Send `count` packets of `size` bytes to `target` as fast as possible
+pub fn bench(target: SocketAddr, count: u64, size: usize) -> Result<BenchReport, io::Error> {
+    let sender = match target {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0")?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?,
+    };
+    let payload = vec![0; size];
+
+    let start = Instant::now();
+    for _ in 0..count {
+        sender.send_to(&payload, target)?;
+    }
+
+    Ok(BenchReport {
+        elapsed: start.elapsed(),
+        packets: count,
+        bytes: count * size as u64,
+    })
+}
+
+/// Forward `count` packets of `size` bytes from a sender through a forwarder to a receiver
+///
+/// All sockets are bound to loopback, so the report shows what the forwarding path
+/// achieves on this machine. At most [SELFTEST_WINDOW] packets are in flight, so that
+/// the latency reflects forwarding rather than queueing in full socket buffers. Packets
+/// carry their send time and need to be at least 8 bytes long.
+pub fn selftest(count: u64, size: usize) -> Result<SelftestReport, io::Error> {
+    /// Time to wait for packets in flight before considering them lost
+    const LOSS_TIMEOUT: Duration = Duration::from_millis(100);
+
+    if !(8..=MAX_SELFTEST_SIZE).contains(&size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("packet size must be between 8 and {MAX_SELFTEST_SIZE} bytes"),
+        ));
+    }
+
+    let loopback = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let receiver = UdpSocket::bind(loopback)?;
+    receiver.set_read_timeout(Some(LOSS_TIMEOUT))?;
+    let listener = UdpSocket::bind(loopback)?;
+    let listener_addr = listener.local_addr()?;
+    let forwarder = Forwarder::from_source(listener, vec![receiver.local_addr()?])?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let forwarding = {
+        let stop = Arc::clone(&stop);
+        thread::Builder::new()
+            .name("selftest".to_owned())
+            .spawn(move || forwarder.run_until(Some(&stop)))?
+    };
+
+    let result = measure(&receiver, listener_addr, count, size);
+
+    stop.store(true, Ordering::Release);
+    forwarding
+        .join()
+        .map_err(|_| io::Error::other("forwarding thread panicked"))??;
+
+    result
+}
+
+/// Packets in flight during [selftest] at most
+pub const SELFTEST_WINDOW: u64 = 64;
+
+/// Largest packet the forwarder passes on without truncating it
+const MAX_SELFTEST_SIZE: usize = 1500;
+
+/// Send packets to the forwarder at `listener_addr` and time their arrival at `receiver`
+fn measure(
+    receiver: &UdpSocket,
+    listener_addr: SocketAddr,
+    count: u64,
+    size: usize,
+) -> Result<SelftestReport, io::Error> {
+    let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let mut payload = vec![0; size];
+    let mut buffer = vec![0; size];
+    let mut latencies = Vec::with_capacity(count.min(1 << 20) as usize);
+    let (mut sent, mut in_flight) = (0, 0);
+
+    let start = Instant::now();
+    while sent < count || in_flight > 0 {
+        while sent < count && in_flight < SELFTEST_WINDOW {
+            let sent_at = start.elapsed().as_nanos() as u64;
+            payload[..8].copy_from_slice(&sent_at.to_be_bytes());
+            sender.send_to(&payload, listener_addr)?;
+            sent += 1;
+            in_flight += 1;
+        }
+
+        match receiver.recv(&mut buffer) {
+            Ok(num_bytes) => {
+                let Some(sent_at) = buffer[..num_bytes].first_chunk() else {
+                    continue;
+                };
+                let received_at = start.elapsed().as_nanos() as u64;
+                let latency = received_at.saturating_sub(u64::from_be_bytes(*sent_at));
+                latencies.push(Duration::from_nanos(latency));
+                // Packets given up on may still arrive
+                in_flight = in_flight.saturating_sub(1);
+            }
+            // Give up on the packets in flight
+            Err(e) if is_timeout(&e) => in_flight = 0,
+            Err(e) => return Err(e),
+        }
+    }
+    let elapsed = start.elapsed();
+
+    latencies.sort_unstable();
+    let percentile = |percent: usize| match latencies.len() {
+        0 => Duration::ZERO,
+        len => latencies[(len * percent / 100).min(len - 1)],
+    };
+    let packets = latencies.len() as u64;
+
+    Ok(SelftestReport {
+        elapsed,
+        packets,
+        bytes: packets * size as u64,
+        lost: count.saturating_sub(packets),
+        latency_p50: percentile(50),
+        latency_p90: percentile(90),
+        latency_p99: percentile(99),
+        latency_max: latencies.last().copied().unwrap_or_default(),
+    })
+}
+
+/// Send every received packet back to its source
+pub fn echo(listener_spec: ListenerSpec) -> Result<(), io::Error> {
+    let listener = ListenerBuilder::new(&listener_spec).bind()?;
+
+    let mut buffer = [0; 1500];
+
+    loop {
+        let (num_bytes, source) = listener.recv_from(&mut buffer)?;
+        listener.send_to(&buffer[..num_bytes], source)?;
+    }
+}
+
+/// Join the listener and wait up to `timeout` for the first packet
+///
+/// Returns the source and size of the first packet or `None` on timeout.
+pub fn probe(
+    listener_spec: ListenerSpec,
+    timeout: Duration,
+) -> Result<Option<(SocketAddr, usize)>, io::Error> {
+    let listener = ListenerBuilder::new(&listener_spec).bind()?;
+    listener.set_read_timeout(Some(timeout))?;
+
+    let mut buffer = [0; 1500];
+
+    match listener.recv_from(&mut buffer) {
+        Ok((num_bytes, source)) => Ok(Some((source, num_bytes))),
+        Err(e) if is_timeout(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check if an error is caused by a read timeout
+///
+/// Depending on the platform, this is reported as [io::ErrorKind::WouldBlock]
+/// or [io::ErrorKind::TimedOut].
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn selftest_forwards_over_loopback() {
+        let report = selftest(200, 100).unwrap();
+
+        assert_eq!(200, report.packets + report.lost);
+        assert_eq!(report.packets * 100, report.bytes);
+        assert!(report.latency_p50 <= report.latency_p99);
+        assert!(report.latency_p99 <= report.latency_max);
+
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            selftest(1, 4).unwrap_err().kind()
+        );
+    }
+}
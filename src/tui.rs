@@ -0,0 +1,204 @@
+//! Terminal status display
+//!
+//! Renders a live dashboard of listener and target rates, target health
+//! and recent errors, redrawn in place with ANSI escape sequences.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, Write},
+    net::SocketAddr,
+    sync::{Arc, atomic::Ordering},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::state::SharedState;
+
+/// Clear the screen and move the cursor to the top left corner
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Counters at one point in time, to compute rates from
+struct Snapshot {
+    /// Time the counters were read
+    taken: Instant,
+    /// Received packets and bytes
+    received: (u64, u64),
+    /// Sent packets, bytes and send errors per target
+    targets: HashMap<SocketAddr, (u64, u64, u64)>,
+}
+
+impl Snapshot {
+    fn take(state: &SharedState) -> Self {
+        Self {
+            taken: Instant::now(),
+            received: (state.received().packets(), state.received().bytes()),
+            targets: state
+                .targets()
+                .iter()
+                .map(|target| {
+                    (
+                        target.addr,
+                        (
+                            target.sent.packets(),
+                            target.sent.bytes(),
+                            target.send_errors.load(Ordering::Relaxed),
+                        ),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Redraw the dashboard on stdout every `interval` in a new thread
+pub fn spawn(state: Arc<SharedState>, interval: Duration) -> Result<JoinHandle<()>, io::Error> {
+    thread::Builder::new()
+        .name("tui".to_owned())
+        .spawn(move || {
+            let mut previous = Snapshot::take(&state);
+            loop {
+                thread::sleep(interval);
+                let current = Snapshot::take(&state);
+
+                let mut stdout = io::stdout().lock();
+                let _ = write!(
+                    stdout,
+                    "{CLEAR_SCREEN}{}",
+                    render(&state, &previous, &current)
+                );
+                let _ = stdout.flush();
+
+                previous = current;
+            }
+        })
+}
+
+/// Render the dashboard with rates between two snapshots
+fn render(state: &SharedState, previous: &Snapshot, current: &Snapshot) -> String {
+    let secs = current
+        .taken
+        .duration_since(previous.taken)
+        .as_secs_f64()
+        .max(f64::EPSILON);
+    let rate = |now: u64, before: u64| now.saturating_sub(before) as f64 / secs;
+
+    let mut out = String::new();
+
+    let listener = state
+        .listener_addr()
+        .map_or_else(|| "unknown".to_owned(), |addr| addr.to_string());
+    let _ = writeln!(
+        out,
+        "udpforwarder  listener {listener}  uptime {}s{}\n",
+        state.uptime().as_secs(),
+        if state.is_paused() { "  PAUSED" } else { "" }
+    );
+
+    let _ = writeln!(
+        out,
+        "{:<44} {:>12} {:>14} {:>10}",
+        "", "packets/s", "bytes/s", "total"
+    );
+    let _ = writeln!(
+        out,
+        "{:<44} {:>12.0} {:>14.0} {:>10}\n",
+        "received",
+        rate(current.received.0, previous.received.0),
+        rate(current.received.1, previous.received.1),
+        current.received.0
+    );
+
+    let _ = writeln!(
+        out,
+        "{:<44} {:>12} {:>14} {:>10} {:>8}",
+        "target", "packets/s", "bytes/s", "errors", "health"
+    );
+    for target in state.targets() {
+        let Some(&(packets, bytes, errors)) = current.targets.get(&target.addr) else {
+            continue;
+        };
+        let (prev_packets, prev_bytes, prev_errors) = previous
+            .targets
+            .get(&target.addr)
+            .copied()
+            .unwrap_or_default();
+
+        let health = if errors > prev_errors {
+            "failing"
+        } else if packets > prev_packets {
+            "ok"
+        } else {
+            "idle"
+        };
+
+        let _ = writeln!(
+            out,
+            "{:<44} {:>12.0} {:>14.0} {:>10} {:>8}",
+            target.addr,
+            rate(packets, prev_packets),
+            rate(bytes, prev_bytes),
+            errors,
+            health
+        );
+    }
+
+    let recent_errors = state.recent_errors();
+    if !recent_errors.is_empty() {
+        out.push_str("\nrecent errors\n");
+        for (time, error) in recent_errors.iter().rev() {
+            let _ = writeln!(out, "  {} {error}", format_time(*time));
+        }
+    }
+
+    out
+}
+
+/// Format the time of day as `HH:MM:SS` in UTC
+fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default();
+
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_rates_and_health() {
+        let ok: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let failing: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+        let state = SharedState::new(None, &[ok, failing]);
+
+        let previous = Snapshot::take(&state);
+        let mut current = Snapshot::take(&state);
+        current.taken = previous.taken + Duration::from_secs(2);
+        current.received = (20, 2000);
+        current.targets.insert(ok, (20, 2000, 0));
+        current.targets.insert(failing, (0, 0, 20));
+
+        let out = render(&state, &previous, &current);
+
+        assert!(out.contains("received"));
+        let ok_line = out.lines().find(|line| line.starts_with("127.0.0.1:4001"));
+        assert!(ok_line.is_some_and(|line| line.contains(" 10 ") && line.ends_with("ok")));
+        let failing_line = out.lines().find(|line| line.starts_with("127.0.0.1:4002"));
+        assert!(failing_line.is_some_and(|line| line.ends_with("failing")));
+    }
+
+    #[test]
+    fn format_time_ok() {
+        let time = UNIX_EPOCH + Duration::from_secs(86400 + 3600 + 2 * 60 + 3);
+
+        assert_eq!("01:02:03", format_time(time));
+    }
+}
@@ -0,0 +1,861 @@
+//! Listening with XDP
+//!
+//! An [XdpSource] loads an XDP program on a network interface which steers the UDP
+//! packets of the listener into an `AF_XDP` socket, bypassing the network stack of the
+//! kernel. Packets of other flows pass to the stack unchanged. The listener socket is
+//! still bound and receives the packets the program does not steer, e.g. those arriving
+//! on other queues of the interface or with IP options, so nothing is lost. Only
+//! available on Linux 5.9 and later, and requires `CAP_NET_ADMIN` and `CAP_BPF`.
+
+#![cfg(target_os = "linux")]
+
+use std::{
+    ffi::{c_int, c_long, c_void},
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr, slice,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    ListenerBuilder, ListenerSpec,
+    capture::{Flow, interface_index},
+    sockopt,
+    source::{Source, copy_truncated, udp_payload},
+};
+
+#[cfg(target_arch = "x86_64")]
+const SYS_BPF: c_long = 321;
+#[cfg(target_arch = "x86")]
+const SYS_BPF: c_long = 357;
+#[cfg(target_arch = "arm")]
+const SYS_BPF: c_long = 386;
+/// Number of the generic syscall table, e.g. of aarch64 and riscv64
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm")))]
+const SYS_BPF: c_long = 280;
+
+const BPF_MAP_CREATE: c_int = 0;
+const BPF_MAP_UPDATE_ELEM: c_int = 2;
+const BPF_PROG_LOAD: c_int = 5;
+const BPF_LINK_CREATE: c_int = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+
+const AF_XDP: c_int = 44;
+const SOCK_RAW: c_int = 3;
+const SOCK_CLOEXEC: c_int = 0o2000000;
+const SOL_XDP: c_int = 283;
+const XDP_MMAP_OFFSETS: c_int = 1;
+const XDP_RX_RING: c_int = 2;
+const XDP_UMEM_REG: c_int = 4;
+const XDP_UMEM_FILL_RING: c_int = 5;
+const XDP_UMEM_COMPLETION_RING: c_int = 6;
+const XDP_PGOFF_RX_RING: i64 = 0;
+const XDP_UMEM_PGOFF_FILL_RING: i64 = 0x100000000;
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_SHARED: c_int = 1;
+const MAP_PRIVATE: c_int = 2;
+const MAP_ANONYMOUS: c_int = 0x20;
+const MAP_POPULATE: c_int = 0x8000;
+const POLLIN: i16 = 1;
+const EINTR: i32 = 4;
+
+/// Size of a frame in the memory shared with the kernel, at most a page
+const FRAME_SIZE: u32 = 4096;
+/// Number of frames, each fill and RX ring entry refers to one
+const NUM_FRAMES: u32 = 2048;
+const ETH_HEADER_LEN: usize = 14;
+
+unsafe extern "C" {
+    fn syscall(number: c_long, ...) -> c_long;
+    fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+    fn bind(fd: c_int, addr: *const c_void, len: u32) -> c_int;
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    fn poll(fds: *mut PollFd, nfds: u64, timeout: c_int) -> c_int;
+}
+
+/// Attributes of `BPF_MAP_CREATE`
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+/// Attributes of `BPF_MAP_UPDATE_ELEM`
+#[repr(C)]
+struct MapUpdateAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+/// Attributes of `BPF_PROG_LOAD`
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+}
+
+/// Attributes of `BPF_LINK_CREATE`
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+/// `struct xdp_umem_reg`
+#[repr(C)]
+struct UmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+/// `struct xdp_ring_offset`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct RingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+/// `struct xdp_mmap_offsets`
+#[repr(C)]
+#[derive(Debug, Default)]
+struct MmapOffsets {
+    rx: RingOffset,
+    tx: RingOffset,
+    fill: RingOffset,
+    completion: RingOffset,
+}
+
+/// `struct sockaddr_xdp`
+#[repr(C)]
+struct SockaddrXdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+/// `struct xdp_desc`, a frame in the RX ring
+#[repr(C)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+/// `struct pollfd`
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: i16,
+    revents: i16,
+}
+
+/// Run the `bpf` syscall, returning the new file descriptor
+fn bpf<T>(cmd: c_int, attr: &T) -> Result<OwnedFd, io::Error> {
+    // SAFETY: The attributes are valid for their size
+    let fd = unsafe { syscall(SYS_BPF, cmd, attr as *const T, size_of::<T>() as u32) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a new file descriptor owned by nothing else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as c_int) })
+}
+
+/// `struct bpf_insn`, an instruction of eBPF
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl Insn {
+    fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        #[cfg(target_endian = "little")]
+        let regs = src << 4 | dst;
+        #[cfg(target_endian = "big")]
+        let regs = dst << 4 | src;
+        Self {
+            code,
+            regs,
+            off,
+            imm,
+        }
+    }
+}
+
+const LDX_W: u8 = 0x61;
+const LDX_H: u8 = 0x69;
+const LDX_B: u8 = 0x71;
+const LD_IMM64: u8 = 0x18;
+const MOV64_K: u8 = 0xb7;
+const MOV64_X: u8 = 0xbf;
+const ADD64_K: u8 = 0x07;
+const AND64_K: u8 = 0x57;
+const JGT_X: u8 = 0x2d;
+const JNE32_K: u8 = 0x56;
+const CALL: u8 = 0x85;
+const EXIT: u8 = 0x95;
+
+/// Registers of the program: context, packet start and end, scratch
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R2: u8 = 2;
+const R3: u8 = 3;
+const R4: u8 = 4;
+const R5: u8 = 5;
+const R6: u8 = 6;
+
+/// Builder of an XDP program whose checks all jump to a final `XDP_PASS`
+#[derive(Default)]
+struct Program {
+    insns: Vec<Insn>,
+    /// Indices of the jumps to the pass, to be resolved once its index is known
+    passes: Vec<usize>,
+}
+
+impl Program {
+    fn push(&mut self, code: u8, dst: u8, src: u8, off: i16, imm: i32) {
+        self.insns.push(Insn::new(code, dst, src, off, imm));
+    }
+
+    /// Load the packet at `offset` into the scratch register, `code` giving the size
+    fn load(&mut self, code: u8, offset: i16) {
+        self.push(code, R5, R2, offset, 0);
+    }
+
+    /// Continue if the scratch register equals `value` as loaded from the packet
+    fn expect(&mut self, value: u32) {
+        self.passes.push(self.insns.len());
+        self.push(JNE32_K, R5, 0, 0, value as i32);
+    }
+
+    /// Load the bytes at `offset` and expect them to be `bytes`, in network byte order
+    fn expect_bytes(&mut self, offset: i16, bytes: &[u8]) {
+        match *bytes {
+            [byte] => {
+                self.load(LDX_B, offset);
+                self.expect(byte.into());
+            }
+            [a, b] => {
+                self.load(LDX_H, offset);
+                self.expect(u16::from_ne_bytes([a, b]).into());
+            }
+            [a, b, c, d] => {
+                self.load(LDX_W, offset);
+                self.expect(u32::from_ne_bytes([a, b, c, d]));
+            }
+            _ => unreachable!("loads are 1, 2 or 4 bytes"),
+        }
+    }
+
+    /// Pass packets shorter than `len` to the network stack
+    fn expect_len(&mut self, len: i32) {
+        self.push(MOV64_X, R4, R2, 0, 0);
+        self.push(ADD64_K, R4, 0, 0, len);
+        self.passes.push(self.insns.len());
+        self.push(JGT_X, R4, R3, 0, 0);
+    }
+
+    fn finish(mut self, map_fd: c_int) -> Vec<Insn> {
+        // Redirect to the socket of the queue, passing if there is none
+        self.push(LDX_W, R2, R6, 16, 0);
+        self.push(LD_IMM64, R1, BPF_PSEUDO_MAP_FD, 0, map_fd);
+        self.push(0, 0, 0, 0, 0);
+        self.push(MOV64_K, R3, 0, 0, XDP_PASS);
+        self.push(CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP);
+        self.push(EXIT, 0, 0, 0, 0);
+        let pass = self.insns.len();
+        self.push(MOV64_K, R0, 0, 0, XDP_PASS);
+        self.push(EXIT, 0, 0, 0, 0);
+
+        for idx in self.passes {
+            self.insns[idx].off = (pass - idx - 1) as i16;
+        }
+        self.insns
+    }
+}
+
+/// XDP program redirecting the Ethernet frames of `flow` to the sockets in the map
+fn program(flow: &Flow, map_fd: c_int) -> Vec<Insn> {
+    let mut program = Program::default();
+    // Context, packet start and end
+    program.push(MOV64_X, R6, R1, 0, 0);
+    program.push(LDX_W, R2, R6, 0, 0);
+    program.push(LDX_W, R3, R6, 4, 0);
+
+    let source = flow.source;
+    match flow.destination.ip() {
+        IpAddr::V4(destination) => {
+            program.expect_len(ETH_HEADER_LEN as i32 + 28);
+            program.expect_bytes(12, &0x0800u16.to_be_bytes());
+            // Without options, so that the UDP header is at a fixed offset
+            program.expect_bytes(14, &[0x45]);
+            program.expect_bytes(23, &[17]);
+            program.load(LDX_H, 20);
+            program.push(AND64_K, R5, 0, 0, u16::from_ne_bytes([0x3f, 0xff]).into());
+            program.expect(0);
+            if !destination.is_unspecified() {
+                program.expect_bytes(30, &destination.octets());
+            }
+            if let Some(SocketAddr::V4(source)) = source
+                && !source.ip().is_unspecified()
+            {
+                program.expect_bytes(26, &source.ip().octets());
+            }
+            if flow.destination.port() != 0 {
+                program.expect_bytes(36, &flow.destination.port().to_be_bytes());
+            }
+            if let Some(source) = source
+                && source.port() != 0
+            {
+                program.expect_bytes(34, &source.port().to_be_bytes());
+            }
+        }
+        IpAddr::V6(destination) => {
+            program.expect_len(ETH_HEADER_LEN as i32 + 48);
+            program.expect_bytes(12, &0x86ddu16.to_be_bytes());
+            program.load(LDX_B, 14);
+            program.push(AND64_K, R5, 0, 0, 0xf0);
+            program.expect(0x60);
+            program.expect_bytes(20, &[17]);
+            if !destination.is_unspecified() {
+                for (idx, word) in destination.octets().as_chunks::<4>().0.iter().enumerate() {
+                    program.expect_bytes(38 + 4 * idx as i16, word);
+                }
+            }
+            if let Some(SocketAddr::V6(source)) = source
+                && !source.ip().is_unspecified()
+            {
+                for (idx, word) in source.ip().octets().as_chunks::<4>().0.iter().enumerate() {
+                    program.expect_bytes(22 + 4 * idx as i16, word);
+                }
+            }
+            if flow.destination.port() != 0 {
+                program.expect_bytes(56, &flow.destination.port().to_be_bytes());
+            }
+            if let Some(source) = source
+                && source.port() != 0
+            {
+                program.expect_bytes(54, &source.port().to_be_bytes());
+            }
+        }
+    }
+
+    program.finish(map_fd)
+}
+
+/// Memory mapping, unmapped when dropped
+#[derive(Debug)]
+struct Mmap {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(len: usize, flags: c_int, fd: c_int, offset: i64) -> Result<Self, io::Error> {
+        // SAFETY: Mapping new memory doesn't alias any existing memory
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                flags | MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: Mapped in `new` and not referenced beyond the lifetime of `self`
+        unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+/// Single-producer single-consumer ring shared with the kernel
+#[derive(Debug)]
+struct Ring {
+    area: Mmap,
+    offset: RingOffset,
+    size: u32,
+}
+
+impl Ring {
+    /// Map the ring of `size` entries of type `T` at page offset `pgoff` of `socket`
+    fn map<T>(
+        socket: &OwnedFd,
+        offset: RingOffset,
+        size: u32,
+        pgoff: i64,
+    ) -> Result<Self, io::Error> {
+        let len = offset.desc as usize + size as usize * size_of::<T>();
+        let area = Mmap::new(len, MAP_SHARED, socket.as_raw_fd(), pgoff)?;
+        Ok(Self { area, offset, size })
+    }
+
+    fn index(&self, offset: u64) -> &AtomicU32 {
+        // SAFETY: The kernel places the aligned index within the mapping
+        unsafe { &*(self.area.ptr.add(offset as usize) as *const AtomicU32) }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        self.index(self.offset.producer)
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        self.index(self.offset.consumer)
+    }
+
+    /// Entry at position `idx`, wrapping around
+    fn entry<T>(&self, idx: u32) -> *mut T {
+        let idx = (idx & (self.size - 1)) as usize;
+        // SAFETY: The entries follow the descriptor offset within the mapping
+        unsafe {
+            self.area
+                .ptr
+                .add(self.offset.desc as usize + idx * size_of::<T>()) as *mut T
+        }
+    }
+}
+
+/// Source receiving the packets of a listener through an `AF_XDP` socket
+///
+/// Dropping the source detaches the program from the interface.
+#[derive(Debug)]
+pub struct XdpSource {
+    _link: OwnedFd,
+    _program: OwnedFd,
+    _map: OwnedFd,
+    rx: Ring,
+    fill: Ring,
+    socket: OwnedFd,
+    /// Frames the kernel writes packets to
+    umem: Mmap,
+    listener: UdpSocket,
+    flow: Flow,
+    timeout: Option<Duration>,
+    nonblocking: bool,
+}
+
+// SAFETY: The rings and frames are only accessed through the source and the kernel
+unsafe impl Send for XdpSource {}
+
+impl XdpSource {
+    /// Bind the listener and steer its packets arriving at `queue` of `interface`
+    pub fn open(
+        listener_spec: ListenerSpec,
+        interface: &str,
+        queue: u32,
+    ) -> Result<Self, io::Error> {
+        let Some(mut flow) = Flow::to_listener(&listener_spec) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cannot steer packets of a socket passed by systemd",
+            ));
+        };
+        let ifindex = interface_index(interface)?;
+        let listener = ListenerBuilder::new(&listener_spec).bind()?;
+        listener.set_nonblocking(true)?;
+        if flow.destination.port() == 0 {
+            flow.destination.set_port(listener.local_addr()?.port());
+        }
+
+        // SAFETY: Plain syscall without memory arguments
+        let fd = unsafe { socket(AF_XDP, SOCK_RAW | SOCK_CLOEXEC, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a new socket owned by nothing else
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let umem_len = (NUM_FRAMES * FRAME_SIZE) as usize;
+        let umem = Mmap::new(umem_len, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)?;
+        let reg = UmemReg {
+            addr: umem.ptr as u64,
+            len: umem_len as u64,
+            chunk_size: FRAME_SIZE,
+            headroom: 0,
+            flags: 0,
+            tx_metadata_len: 0,
+        };
+        sockopt::set_struct(&socket, SOL_XDP, XDP_UMEM_REG, &reg)?;
+        sockopt::set_int(&socket, SOL_XDP, XDP_UMEM_FILL_RING, NUM_FRAMES as c_int)?;
+        // Required although nothing is sent
+        sockopt::set_int(&socket, SOL_XDP, XDP_UMEM_COMPLETION_RING, 1)?;
+        sockopt::set_int(&socket, SOL_XDP, XDP_RX_RING, NUM_FRAMES as c_int)?;
+
+        let offsets: MmapOffsets = sockopt::get_struct(&socket, SOL_XDP, XDP_MMAP_OFFSETS)?;
+        let rx = Ring::map::<XdpDesc>(&socket, offsets.rx, NUM_FRAMES, XDP_PGOFF_RX_RING)?;
+        let fill = Ring::map::<u64>(&socket, offsets.fill, NUM_FRAMES, XDP_UMEM_PGOFF_FILL_RING)?;
+
+        // Hand all frames to the kernel to receive into
+        for idx in 0..NUM_FRAMES {
+            // SAFETY: Entry of the fill ring, which the kernel doesn't read yet
+            unsafe { fill.entry::<u64>(idx).write((idx * FRAME_SIZE).into()) };
+        }
+        fill.producer().store(NUM_FRAMES, Ordering::Release);
+
+        let addr = SockaddrXdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: 0,
+            sxdp_ifindex: ifindex as u32,
+            sxdp_queue_id: queue,
+            sxdp_shared_umem_fd: 0,
+        };
+        // SAFETY: The address is valid for the given length
+        let result = unsafe {
+            bind(
+                socket.as_raw_fd(),
+                &addr as *const SockaddrXdp as *const c_void,
+                size_of::<SockaddrXdp>() as u32,
+            )
+        };
+        if result == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let map = bpf(
+            BPF_MAP_CREATE,
+            &MapCreateAttr {
+                map_type: BPF_MAP_TYPE_XSKMAP,
+                key_size: 4,
+                value_size: 4,
+                max_entries: queue + 1,
+            },
+        )?;
+        let (key, value) = (queue, socket.as_raw_fd() as u32);
+        bpf(
+            BPF_MAP_UPDATE_ELEM,
+            &MapUpdateAttr {
+                map_fd: map.as_raw_fd() as u32,
+                _pad: 0,
+                key: &key as *const u32 as u64,
+                value: &value as *const u32 as u64,
+                flags: 0,
+            },
+        )?;
+
+        let insns = program(&flow, map.as_raw_fd());
+        let program = bpf(
+            BPF_PROG_LOAD,
+            &ProgLoadAttr {
+                prog_type: BPF_PROG_TYPE_XDP,
+                insn_cnt: insns.len() as u32,
+                insns: insns.as_ptr() as u64,
+                license: c"Dual MIT/GPL".as_ptr() as u64,
+                log_level: 0,
+                log_size: 0,
+                log_buf: 0,
+                kern_version: 0,
+                prog_flags: 0,
+                prog_name: *b"udpforwarder\0\0\0\0",
+            },
+        )
+        .map_err(|e| io::Error::new(e.kind(), format!("XDP program rejected: {e}")))?;
+        let link = bpf(
+            BPF_LINK_CREATE,
+            &LinkCreateAttr {
+                prog_fd: program.as_raw_fd() as u32,
+                target_ifindex: ifindex as u32,
+                attach_type: BPF_XDP,
+                flags: 0,
+            },
+        )
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to attach XDP program: {e}")))?;
+
+        Ok(Self {
+            _link: link,
+            _program: program,
+            _map: map,
+            rx,
+            fill,
+            socket,
+            umem,
+            listener,
+            flow,
+            timeout: None,
+            nonblocking: false,
+        })
+    }
+
+    /// Flow the program steers to the source
+    pub fn flow(&self) -> &Flow {
+        &self.flow
+    }
+
+    /// Take the next UDP packet out of the RX ring, handing its frame back to the kernel
+    fn recv_frame(&mut self, buffer: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let available = self.rx.producer().load(Ordering::Acquire);
+        let mut consumer = self.rx.consumer().load(Ordering::Relaxed);
+
+        let mut received = None;
+        while received.is_none() && consumer != available {
+            // SAFETY: Entry the kernel produced before updating the producer index
+            let desc = unsafe { self.rx.entry::<XdpDesc>(consumer).read() };
+            consumer = consumer.wrapping_add(1);
+
+            let (addr, len) = (desc.addr as usize, desc.len as usize);
+            if addr.saturating_add(len) <= self.umem.len {
+                // SAFETY: Within the frames and owned by the source until handed back
+                let frame =
+                    unsafe { slice::from_raw_parts(self.umem.ptr.add(addr) as *const u8, len) };
+                received = frame
+                    .get(ETH_HEADER_LEN..)
+                    .and_then(udp_payload)
+                    .map(|(source, payload)| (copy_truncated(payload, buffer), source));
+            }
+
+            // The fill ring has room for every frame, so it can't be full
+            let producer = self.fill.producer().load(Ordering::Relaxed);
+            // SAFETY: Entry of the fill ring the kernel consumed before
+            unsafe { self.fill.entry::<u64>(producer).write(desc.addr) };
+            self.fill
+                .producer()
+                .store(producer.wrapping_add(1), Ordering::Release);
+        }
+        self.rx.consumer().store(consumer, Ordering::Release);
+
+        received
+    }
+
+    /// Wait for the socket or the listener to become readable
+    fn wait(&self) -> Result<(), io::Error> {
+        let mut fds = [self.socket.as_raw_fd(), self.listener.as_raw_fd()].map(|fd| PollFd {
+            fd,
+            events: POLLIN,
+            revents: 0,
+        });
+        let timeout = match self.timeout {
+            Some(timeout) => timeout.as_millis().clamp(1, c_int::MAX as u128) as c_int,
+            None => -1,
+        };
+        // SAFETY: The descriptors are valid for their number
+        match unsafe { poll(fds.as_mut_ptr(), fds.len() as u64, timeout) } {
+            // Like a socket whose read timeout passed
+            0 => Err(io::ErrorKind::WouldBlock.into()),
+            -1 => match io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(EINTR) => Ok(()),
+                e => Err(e),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Source for XdpSource {
+    fn recv(&mut self, buffer: &mut [u8]) -> Result<Option<(usize, SocketAddr)>, io::Error> {
+        loop {
+            if let Some(received) = self.recv_frame(buffer) {
+                return Ok(Some(received));
+            }
+            match self.listener.recv_from(buffer) {
+                Ok(received) => return Ok(Some(received)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+            if self.nonblocking {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.wait()?;
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), io::Error> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    fn socket(&self) -> Option<&UdpSocket> {
+        Some(&self.listener)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CTX: u64 = 1 << 32;
+    const PACKET: u64 = 2 << 32;
+
+    /// Run `program` on `frame` like the kernel, returning whether it is redirected
+    fn redirected(program: &[Insn], frame: &[u8]) -> bool {
+        let mut regs = [0u64; 11];
+        regs[R1 as usize] = CTX;
+        let ctx = |offset: u64| match offset {
+            0 => PACKET,
+            4 => PACKET + frame.len() as u64,
+            _ => 0,
+        };
+        let load = |addr: u64, len: usize| {
+            let offset = (addr - PACKET) as usize;
+            let mut bytes = [0; 8];
+            bytes[..len].copy_from_slice(&frame[offset..offset + len]);
+            match len {
+                1 => bytes[0].into(),
+                2 => u16::from_ne_bytes([bytes[0], bytes[1]]).into(),
+                _ => u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).into(),
+            }
+        };
+
+        let mut pc = 0;
+        loop {
+            let Insn {
+                code,
+                regs: reg,
+                off,
+                imm,
+            } = program[pc];
+            #[cfg(target_endian = "little")]
+            let (dst, src) = ((reg & 0xf) as usize, (reg >> 4) as usize);
+            #[cfg(target_endian = "big")]
+            let (dst, src) = ((reg >> 4) as usize, (reg & 0xf) as usize);
+            pc += 1;
+            match code {
+                LDX_W | LDX_H | LDX_B => {
+                    let addr = regs[src].wrapping_add_signed(off.into());
+                    regs[dst] = match (addr >= PACKET, code) {
+                        (false, _) => ctx(addr - CTX),
+                        (true, LDX_W) => load(addr, 4),
+                        (true, LDX_H) => load(addr, 2),
+                        (true, _) => load(addr, 1),
+                    };
+                }
+                LD_IMM64 => pc += 1,
+                MOV64_K => regs[dst] = imm as u64,
+                MOV64_X => regs[dst] = regs[src],
+                ADD64_K => regs[dst] = regs[dst].wrapping_add_signed(imm.into()),
+                AND64_K => regs[dst] &= imm as u64,
+                JGT_X if regs[dst] > regs[src] => pc += off as usize,
+                JNE32_K if regs[dst] as u32 != imm as u32 => pc += off as usize,
+                JGT_X | JNE32_K => (),
+                CALL => return true,
+                EXIT => return false,
+                _ => panic!("unexpected instruction {code:#x}"),
+            }
+        }
+    }
+
+    /// Ethernet frame with an IPv4 packet from `source` to `destination`
+    fn ipv4_frame(source: &str, destination: &str) -> Vec<u8> {
+        let (source, destination): (SocketAddr, SocketAddr) =
+            (source.parse().unwrap(), destination.parse().unwrap());
+        let (IpAddr::V4(src), IpAddr::V4(dst)) = (source.ip(), destination.ip()) else {
+            panic!("IPv4 addresses expected");
+        };
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0x45, 0, 0, 33, 0, 0, 0x40, 0, 64, 17, 0, 0]);
+        frame.extend_from_slice(&src.octets());
+        frame.extend_from_slice(&dst.octets());
+        frame.extend_from_slice(&source.port().to_be_bytes());
+        frame.extend_from_slice(&destination.port().to_be_bytes());
+        frame.extend_from_slice(&[0, 13, 0, 0]);
+        frame.extend_from_slice(b"hello");
+        frame
+    }
+
+    #[test]
+    fn program_redirects_flow() {
+        let flow = Flow::to_listener(&"10.1.1.10:4000".parse().unwrap()).unwrap();
+        let program = program(&flow, 3);
+
+        assert!(redirected(
+            &program,
+            &ipv4_frame("10.1.1.20:5000", "10.1.1.10:4000")
+        ));
+        for (source, destination) in [
+            ("10.1.1.20:5000", "10.1.1.10:4001"),
+            ("10.1.1.20:5000", "10.1.1.11:4000"),
+        ] {
+            assert!(!redirected(&program, &ipv4_frame(source, destination)));
+        }
+
+        // Fragments, IP options and truncated frames pass to the network stack
+        let mut fragment = ipv4_frame("10.1.1.20:5000", "10.1.1.10:4000");
+        fragment[20..22].copy_from_slice(&[0x20, 0x10]);
+        assert!(!redirected(&program, &fragment));
+        let mut options = ipv4_frame("10.1.1.20:5000", "10.1.1.10:4000");
+        options[14] = 0x46;
+        assert!(!redirected(&program, &options));
+        let frame = ipv4_frame("10.1.1.20:5000", "10.1.1.10:4000");
+        assert!(!redirected(&program, &frame[..40]));
+    }
+
+    #[test]
+    fn program_redirects_ipv6_flow() {
+        let flow = Flow::to_listener(&"[fd00::10]:4000".parse().unwrap())
+            .unwrap()
+            .from_source("[fd00::20]:5000".parse().unwrap());
+        let program = program(&flow, 3);
+
+        let frame = |source: &str, port: u16| {
+            let source: std::net::Ipv6Addr = source.parse().unwrap();
+            let mut frame = vec![0; 12];
+            frame.extend_from_slice(&[0x86, 0xdd, 0x60, 0, 0, 0, 0, 13, 17, 64]);
+            frame.extend_from_slice(&source.octets());
+            frame.extend_from_slice(&"fd00::10".parse::<std::net::Ipv6Addr>().unwrap().octets());
+            frame.extend_from_slice(&5000u16.to_be_bytes());
+            frame.extend_from_slice(&port.to_be_bytes());
+            frame.extend_from_slice(&[0, 13, 0, 0]);
+            frame.extend_from_slice(b"hello");
+            frame
+        };
+        assert!(redirected(&program, &frame("fd00::20", 4000)));
+        assert!(!redirected(&program, &frame("fd00::21", 4000)));
+        assert!(!redirected(&program, &frame("fd00::20", 4001)));
+    }
+}
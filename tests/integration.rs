@@ -4,10 +4,17 @@ use std::{
     io::ErrorKind,
     net::{SocketAddr, UdpSocket},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Child, Command},
+    sync::mpsc,
+    thread,
     time::Duration,
 };
 
+use udpforwarder::{
+    Action, Forwarder, ListenerSpec, Packet, ShutdownHandle, delay::OneWayDelay,
+    forward_with_shutdown, hooks::DropReason, sink::ChannelSink, source::ChannelSource,
+};
+
 /// Launch the pre-built binary and kill it again
 #[test]
 fn launch_kill_process() {
@@ -19,6 +26,7 @@ fn launch_kill_process() {
         .spawn()
         .expect("spawn process");
     handle.kill().expect("kill child process");
+    handle.wait().expect("wait for child process");
 }
 
 /// Receive packets through a simple forward from one localhost port to another
@@ -76,7 +84,7 @@ fn simple_ipv4_forward() {
         loop {
             match forwarded_listener.recv(&mut recv_buffer) {
                 Ok(num_received) => {
-                    assert_eq!(num_received, msg.bytes().len());
+                    assert_eq!(num_received, msg.len());
                     assert_eq!(&recv_buffer[..num_received], msg.as_bytes());
                     break;
                 }
@@ -89,6 +97,343 @@ fn simple_ipv4_forward() {
     }
 
     handle.kill().expect("kill child process");
+    handle.wait().expect("wait for child process");
+}
+
+/// Forward with the seccomp filter installed
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+#[test]
+fn seccomp_ipv4_forward() {
+    let mut handle = spawn_forwarder(&["--seccomp", "127.0.0.1:4100", "127.0.0.1:4101"]);
+    assert_forwards("127.0.0.1:4100", "127.0.0.1:4101");
+
+    assert!(
+        handle.try_wait().expect("query child process").is_none(),
+        "forwarder terminated"
+    );
+    handle.kill().expect("kill child process");
+    handle.wait().expect("wait for child process");
+}
+
+/// Forward to the listener itself without creating a packet storm
+#[test]
+fn loop_to_listener_dropped() {
+    let mut handle = spawn_forwarder(&["127.0.0.1:4200", "127.0.0.1:4201", "127.0.0.1:4200"]);
+    assert_forwards("127.0.0.1:4200", "127.0.0.1:4201");
+
+    handle.kill().expect("kill child process");
+    handle.wait().expect("wait for child process");
+}
+
+/// Forward two streams in one process according to rules
+#[test]
+fn rules_forwarded_concurrently() {
+    let mut handle = spawn_forwarder(&[
+        "127.0.0.1:4400=>127.0.0.1:4401",
+        "127.0.0.1:4402=>127.0.0.1:4403",
+    ]);
+    assert_forwards("127.0.0.1:4400", "127.0.0.1:4401");
+    assert_forwards("127.0.0.1:4402", "127.0.0.1:4403");
+
+    handle.kill().expect("kill child process");
+    handle.wait().expect("wait for child process");
+}
+
+/// Filter and transform packets with handlers of the library
+#[test]
+fn handlers_filter_and_transform() {
+    let forwarder = Forwarder::new(
+        ListenerSpec::Unicast("127.0.0.1:4300".parse().unwrap()),
+        vec!["127.0.0.1:4301".parse().unwrap()],
+    )
+    .expect("set up forwarder")
+    .with_handler(
+        |packet: &mut Packet| match packet.payload.starts_with(b"drop") {
+            true => Action::Drop,
+            false => Action::Forward,
+        },
+    )
+    .with_handler(|packet: &mut Packet| {
+        packet.payload.extend_from_slice(b" (handled)");
+        Action::Forward
+    });
+    let state = forwarder.state();
+    thread::spawn(move || forwarder.run());
+
+    let sender = UdpSocket::bind("127.0.0.1:0").expect("bind sender");
+    let receiver = UdpSocket::bind("127.0.0.1:4301").expect("bind receiver");
+    receiver
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("set read timeout");
+
+    let mut recv_buffer = [0; 1500];
+    for msg in [&b"drop this"[..], b"keep this"] {
+        sender.send_to(msg, "127.0.0.1:4300").expect("send");
+    }
+    let num_received = receiver
+        .recv(&mut recv_buffer)
+        .expect("receive forwarded packet");
+    assert_eq!(b"keep this (handled)", &recv_buffer[..num_received]);
+    assert_eq!(1, state.filtered().packets());
+}
+
+/// Drive forwarding from a channel into a sink, ending with the input
+#[test]
+fn channel_source_to_sink() {
+    let (input, source) = mpsc::channel();
+    let (sink, output) = mpsc::channel();
+    let forwarder = Forwarder::from_source(ChannelSource::new(source), Vec::new())
+        .expect("set up forwarder")
+        .with_sink(ChannelSink::new(sink));
+
+    let packet = Packet {
+        source: "10.1.1.10:5000".parse().unwrap(),
+        payload: b"hello".to_vec(),
+    };
+    input.send(packet.clone()).expect("send packet");
+    drop(input);
+
+    forwarder.run().expect("forward until the input ends");
+    assert_eq!(packet, output.recv().expect("receive forwarded packet"));
+}
+
+/// Poll a forwarder from the test thread instead of running it
+#[test]
+fn poll_without_blocking() {
+    let (input, source) = mpsc::channel();
+    let (sink, output) = mpsc::channel();
+    let mut forwarder = Forwarder::from_source(ChannelSource::new(source), Vec::new())
+        .expect("set up forwarder")
+        .with_sink(ChannelSink::new(sink));
+
+    assert_eq!(Some(0), forwarder.poll_once().expect("poll idle forwarder"));
+
+    let packet = Packet {
+        source: "10.1.1.10:5000".parse().unwrap(),
+        payload: b"hello".to_vec(),
+    };
+    for _ in 0..3 {
+        input.send(packet.clone()).expect("send packet");
+    }
+    assert_eq!(
+        Some(3),
+        forwarder.poll_once().expect("poll waiting packets")
+    );
+    assert_eq!(3, output.try_iter().count());
+
+    drop(input);
+    assert_eq!(
+        None,
+        forwarder.poll_once().expect("poll exhausted forwarder")
+    );
+}
+
+/// Stop forwarding from another thread and get the statistics of the run
+#[test]
+fn stopped_through_shutdown_handle() {
+    let shutdown = ShutdownHandle::new();
+    let forwarding = {
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            forward_with_shutdown(
+                ListenerSpec::Unicast("127.0.0.1:0".parse().unwrap()),
+                &["127.0.0.1:9".parse().unwrap()],
+                shutdown,
+            )
+        })
+    };
+
+    thread::sleep(Duration::from_millis(50));
+    shutdown.shutdown();
+    let stats = forwarding
+        .join()
+        .expect("join forwarding thread")
+        .expect("stopped without error");
+    assert_eq!(0, stats.received.packets);
+}
+
+/// Relay the answers of a target back to the clients asking it
+#[test]
+fn replies_relayed_to_clients() {
+    let resolver = UdpSocket::bind("127.0.0.1:0").expect("bind resolver");
+    resolver
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("set read timeout");
+    let mut forwarder = Forwarder::new(
+        ListenerSpec::Unicast("127.0.0.1:0".parse().unwrap()),
+        vec![resolver.local_addr().unwrap()],
+    )
+    .expect("set up forwarder")
+    .with_reply_path(Duration::from_secs(5))
+    .expect("enable reply path");
+    let listener_addr = forwarder.state().listener_addr().unwrap();
+
+    let mut buffer = [0; 64];
+    for query in [&b"first"[..], b"second"] {
+        let client = UdpSocket::bind("127.0.0.1:0").expect("bind client");
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .expect("set read timeout");
+        client.send_to(query, listener_addr).expect("send query");
+        while forwarder.poll_once().expect("poll forwarder") == Some(0) {}
+
+        let (num_bytes, session_addr) = resolver.recv_from(&mut buffer).expect("receive query");
+        assert_eq!(query, &buffer[..num_bytes]);
+        let answer = [query, b" answered"].concat();
+        resolver
+            .send_to(&answer, session_addr)
+            .expect("send answer");
+
+        let (num_bytes, source) = client.recv_from(&mut buffer).expect("receive answer");
+        assert_eq!(answer, &buffer[..num_bytes]);
+        assert_eq!(listener_addr, source);
+    }
+}
+
+/// Observe received and dropped packets through hooks
+#[test]
+fn hooks_observe_packets() {
+    let (input, source) = mpsc::channel();
+    let (packets, received) = mpsc::channel();
+    let (drops, dropped) = mpsc::channel();
+    let forwarder = Forwarder::from_source(ChannelSource::new(source), Vec::new())
+        .expect("set up forwarder")
+        .with_handler(
+            |packet: &mut Packet| match packet.payload.starts_with(b"drop") {
+                true => Action::Drop,
+                false => Action::Forward,
+            },
+        )
+        .on_packet(move |_, payload| {
+            let _ = packets.send(payload.to_vec());
+        })
+        .on_drop(move |_, num_bytes, reason| {
+            let _ = drops.send((num_bytes, reason));
+        });
+
+    let source: SocketAddr = "10.1.1.10:5000".parse().unwrap();
+    for payload in [&b"hello"[..], b"drop me"] {
+        let packet = Packet {
+            source,
+            payload: payload.to_vec(),
+        };
+        input.send(packet).expect("send packet");
+    }
+    drop(input);
+
+    forwarder.run().expect("forward until the input ends");
+    assert_eq!(2, received.try_iter().count());
+    assert_eq!(
+        vec![(7, DropReason::Filtered)],
+        dropped.try_iter().collect::<Vec<_>>()
+    );
+}
+
+/// Measure the one-way delay of packets stamped by another forwarder
+#[test]
+fn one_way_delay_measured() {
+    let listener = UdpSocket::bind("127.0.0.1:0").expect("bind listener");
+    let listener_addr = listener.local_addr().expect("listener address");
+    let (sink, output) = mpsc::channel();
+    let mut receiving = Forwarder::from_source(listener, Vec::new())
+        .expect("set up receiving forwarder")
+        .with_one_way_delay(OneWayDelay::Measure)
+        .with_stats_interval(Duration::ZERO)
+        .with_sink(ChannelSink::new(sink));
+    let state = receiving.state();
+
+    let (input, source) = mpsc::channel();
+    let sending = Forwarder::from_source(ChannelSource::new(source), vec![listener_addr])
+        .expect("set up sending forwarder")
+        .with_one_way_delay(OneWayDelay::Stamp);
+    let packet = Packet {
+        source: "10.1.1.10:5000".parse().unwrap(),
+        payload: b"hello".to_vec(),
+    };
+    input.send(packet).expect("send packet");
+    drop(input);
+    sending.run().expect("forward until the input ends");
+
+    let mut forwarded = None;
+    for _ in 0..100 {
+        receiving.poll_once().expect("poll receiving forwarder");
+        forwarded = output.try_recv().ok();
+        if forwarded.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(
+        b"hello",
+        forwarded.expect("packet forwarded").payload.as_slice()
+    );
+    let delay = state.delay().expect("delay measured");
+    assert_eq!(1, delay.packets);
+    assert!(delay.max < Duration::from_secs(1));
+}
+
+/// Spawn the forwarder binary with the given arguments
+fn spawn_forwarder(args: &[&str]) -> Child {
+    let binary_path = get_binary_path().expect("binary exists");
+    println!("Using binary {}", binary_path.display());
+
+    Command::new(binary_path)
+        .args(args)
+        .spawn()
+        .expect("spawn process")
+}
+
+/// Send packets to `incoming` and expect to receive them on `forwarded`
+fn assert_forwards(incoming: &str, forwarded: &str) {
+    let incoming_address: SocketAddr = incoming.parse().unwrap();
+    let forwarded_address: SocketAddr = forwarded.parse().unwrap();
+
+    let sender = UdpSocket::bind(match incoming_address {
+        SocketAddr::V4(_) => "127.0.0.1:0",
+        SocketAddr::V6(_) => "[::1]:0",
+    })
+    .expect("bind sender");
+    let forwarded_listener = UdpSocket::bind(forwarded_address).expect("bind listener");
+    forwarded_listener
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .expect("set read timeout");
+
+    let mut recv_buffer = [0; 1500];
+
+    // Fire packets until the forwarding is up
+    let msg = b"establish connection";
+    let mut attempts = 0;
+
+    loop {
+        sender.send_to(msg, incoming_address).expect("send");
+        attempts += 1;
+        assert!(attempts < 50, "forwarding not established");
+
+        match forwarded_listener.recv(&mut recv_buffer) {
+            Ok(num_received) if num_received == msg.len() => break,
+            Ok(_) => continue,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => panic!("failed to receive on socket: {e}"),
+        }
+    }
+
+    // Drain duplicates of the establishing message
+    while forwarded_listener.recv(&mut recv_buffer).is_ok() {}
+
+    for i in 0..10 {
+        let msg = format!("packet number {i}");
+        sender
+            .send_to(msg.as_bytes(), incoming_address)
+            .expect("send");
+
+        let num_received = forwarded_listener
+            .recv(&mut recv_buffer)
+            .expect("receive forwarded packet");
+        assert_eq!(&recv_buffer[..num_received], msg.as_bytes());
+    }
 }
 
 fn get_binary_path() -> Option<PathBuf> {